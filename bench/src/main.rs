@@ -0,0 +1,75 @@
+//! Standardized benchmarks for nakamoto.
+//!
+//! Runs a fixed set of scenarios and prints one JSON object per scenario on standard output,
+//! so that results can be compared across hardware and configurations, and attached to
+//! performance reports.
+#![deny(missing_docs, unsafe_code)]
+use std::process;
+
+use argh::FromArgs;
+
+mod scenario;
+
+use scenario::Scenario;
+
+#[derive(FromArgs)]
+/// Run nakamoto benchmarks, and print the results as JSON, one line per scenario.
+pub struct Options {
+    /// scenario to run, one of `headers`, `filter-headers`, `rescan`, `load-headers` or
+    /// `load-headers-mmap` (default: all)
+    #[argh(option)]
+    pub scenario: Vec<Scenario>,
+
+    /// number of block headers to sync in the `headers` scenario (default: 10000)
+    #[argh(option, default = "10_000")]
+    pub headers: usize,
+
+    /// number of filter headers to import in the `filter-headers` scenario (default: 700000)
+    #[argh(option, default = "700_000")]
+    pub filter_headers: usize,
+
+    /// number of heights to scan in the `rescan` scenario (default: 1000)
+    #[argh(option, default = "1000")]
+    pub heights: usize,
+
+    /// number of scripts to watch in the `rescan` scenario (default: 100)
+    #[argh(option, default = "100")]
+    pub scripts: usize,
+
+    /// number of block headers to load in the `load-headers` scenarios (default: 700000)
+    #[argh(option, default = "700_000")]
+    pub load_headers: usize,
+
+    /// random seed used to generate test data (default: 1)
+    #[argh(option, default = "1")]
+    pub seed: u64,
+}
+
+fn main() {
+    let opts: Options = argh::from_env();
+    let scenarios = if opts.scenario.is_empty() {
+        Scenario::all().to_vec()
+    } else {
+        opts.scenario.clone()
+    };
+
+    for scenario in scenarios {
+        let rng = fastrand::Rng::with_seed(opts.seed);
+        let result = match scenario {
+            Scenario::Headers => scenario::headers(opts.headers, rng),
+            Scenario::FilterHeaders => scenario::filter_headers(opts.filter_headers, rng),
+            Scenario::Rescan => scenario::rescan(opts.heights, opts.scripts, rng),
+            Scenario::LoadHeaders => scenario::load_headers(opts.load_headers),
+            #[cfg(feature = "mmap")]
+            Scenario::LoadHeadersMmap => scenario::load_headers_mmap(opts.load_headers),
+        };
+
+        match result {
+            Ok(report) => println!("{}", microserde::json::to_string(&report.to_json())),
+            Err(err) => {
+                eprintln!("Error: scenario `{}` failed: {}", scenario, err);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Benchmark scenarios.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{env, fmt, fs, net, process, thread, time};
+
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_chain::filter::cache::{FilterCache, StoredHeader};
+use nakamoto_client::client::{self, event, Client, Config, Event, Network};
+use nakamoto_client::handle::Handle as _;
+use nakamoto_common::block::filter::{FilterHeader, FilterMatcher, Filters as _};
+use nakamoto_common::block::store::{Genesis as _, Store as _};
+use nakamoto_common::block::{BlockHeader, Height};
+use nakamoto_p2p::protocol::{spvmgr, syncmgr};
+use nakamoto_test::block::gen;
+
+/// The network reactor used by clients.
+type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
+
+/// Error returned by a scenario.
+pub type Error = Box<dyn std::error::Error>;
+
+/// A benchmark scenario.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scenario {
+    /// Sync block headers from a peer on the local host.
+    Headers,
+    /// Import a mainnet-sized filter header chain, in batches of the size peers send.
+    FilterHeaders,
+    /// Match block filters against watched scripts.
+    Rescan,
+    /// Load block headers from an on-disk store, reading the file.
+    LoadHeaders,
+    /// Load block headers from an on-disk store, through a memory map of the file.
+    #[cfg(feature = "mmap")]
+    LoadHeadersMmap,
+}
+
+impl Scenario {
+    /// All scenarios, in the order they are run.
+    pub fn all() -> &'static [Scenario] {
+        &[
+            Self::Headers,
+            Self::FilterHeaders,
+            Self::Rescan,
+            Self::LoadHeaders,
+            #[cfg(feature = "mmap")]
+            Self::LoadHeadersMmap,
+        ]
+    }
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Headers => write!(f, "headers"),
+            Self::FilterHeaders => write!(f, "filter-headers"),
+            Self::Rescan => write!(f, "rescan"),
+            Self::LoadHeaders => write!(f, "load-headers"),
+            #[cfg(feature = "mmap")]
+            Self::LoadHeadersMmap => write!(f, "load-headers-mmap"),
+        }
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::all()
+            .iter()
+            .find(|scenario| scenario.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown scenario `{}`", s))
+    }
+}
+
+/// The result of running a scenario.
+#[derive(Debug)]
+pub struct Report {
+    /// Name of the scenario.
+    pub scenario: String,
+    /// Version of nakamoto the scenario ran with.
+    pub version: String,
+    /// Number of items processed, eg. headers.
+    pub items: u64,
+    /// Unit of the items processed.
+    pub unit: String,
+    /// Time it took to process all items, in milliseconds.
+    pub elapsed_ms: u64,
+    /// Items processed per second.
+    pub rate: f64,
+}
+
+impl Report {
+    fn new(scenario: Scenario, items: usize, unit: &str, elapsed: time::Duration) -> Self {
+        Self {
+            scenario: scenario.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            items: items as u64,
+            unit: unit.to_owned(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            rate: items as f64 / elapsed.as_secs_f64(),
+        }
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        obj.insert("scenario".to_owned(), Value::String(self.scenario.clone()));
+        obj.insert("version".to_owned(), Value::String(self.version.clone()));
+        obj.insert("items".to_owned(), Value::Number(Number::U64(self.items)));
+        obj.insert("unit".to_owned(), Value::String(self.unit.clone()));
+        obj.insert(
+            "elapsed_ms".to_owned(),
+            Value::Number(Number::U64(self.elapsed_ms)),
+        );
+        obj.insert("rate".to_owned(), Value::Number(Number::F64(self.rate)));
+
+        Value::Object(obj)
+    }
+}
+
+/// Sync `count` block headers from a client running on the local host. Regtest headers
+/// are used, since they can be generated quickly.
+pub fn headers(count: usize, mut rng: fastrand::Rng) -> Result<Report, Error> {
+    let network = Network::Regtest;
+    let mut prev = network.genesis();
+    let headers = (0..count)
+        .map(|_| {
+            prev = gen::header(&prev, Default::default(), &mut rng);
+            prev
+        })
+        .collect::<Vec<BlockHeader>>();
+
+    let (seed, addr, seed_thread) = spawn("seed", network)?;
+    seed.import_headers(headers)??;
+
+    let (node, _, node_thread) = spawn("node", network)?;
+    let start = time::Instant::now();
+
+    node.connect(addr)?;
+    node.wait_for_height(count as Height)?;
+
+    let elapsed = start.elapsed();
+
+    for (handle, thread) in [(seed, seed_thread), (node, node_thread)] {
+        handle.shutdown()?;
+        thread.join().ok();
+    }
+    Ok(Report::new(Scenario::Headers, count, "headers", elapsed))
+}
+
+/// Import `count` mainnet filter headers into an on-disk store, the way they are imported
+/// during sync.
+pub fn filter_headers(count: usize, rng: fastrand::Rng) -> Result<Report, Error> {
+    let network = Network::Mainnet;
+    let dir = env::temp_dir().join(format!("nakamoto-bench-{}", process::id()));
+    let path = dir.join("filters.db");
+
+    fs::create_dir_all(&dir)?;
+
+    let store = store::File::create(&path, StoredHeader::genesis(network))?;
+    let mut cache = FilterCache::from(store)?;
+    let headers = gen::cfheaders(FilterHeader::genesis(network), rng)
+        .take(count)
+        .collect::<Vec<_>>();
+
+    let start = time::Instant::now();
+    for batch in headers.chunks(spvmgr::MAX_MESSAGE_CFHEADERS) {
+        cache.import_headers(batch.to_vec())?;
+    }
+    let elapsed = start.elapsed();
+
+    fs::remove_dir_all(&dir)?;
+
+    Ok(Report::new(
+        Scenario::FilterHeaders,
+        count,
+        "filter headers",
+        elapsed,
+    ))
+}
+
+/// Match the filters of `heights` blocks against `scripts` watched scripts.
+pub fn rescan(heights: usize, scripts: usize, mut rng: fastrand::Rng) -> Result<Report, Error> {
+    let genesis = gen::genesis(&mut rng);
+    let blocks = gen::blockchain(genesis, heights..heights + 1, &mut rng);
+    let filters = blocks
+        .iter()
+        .skip(1)
+        .map(|blk| (blk.block_hash(), gen::cfilter(blk)))
+        .collect::<Vec<_>>();
+    let watch = (0..scripts)
+        .map(|_| gen::tx_out(&mut rng).script_pubkey)
+        .collect::<Vec<_>>();
+
+    let start = time::Instant::now();
+    for (hash, filter) in filters.iter() {
+        FilterMatcher::new(filter, hash)?.match_any(watch.iter().map(|s| s.as_bytes()));
+    }
+    let elapsed = start.elapsed();
+
+    Ok(Report::new(Scenario::Rescan, heights, "filters", elapsed))
+}
+
+/// Load `count` block headers from an on-disk store, the way they are loaded when the
+/// client starts.
+pub fn load_headers(count: usize) -> Result<Report, Error> {
+    let (dir, store) = header_store(count)?;
+
+    let start = time::Instant::now();
+    let loaded = load(store.read_iter()?)?;
+    let elapsed = start.elapsed();
+
+    fs::remove_dir_all(&dir)?;
+    assert_eq!(loaded, count);
+
+    Ok(Report::new(
+        Scenario::LoadHeaders,
+        count,
+        "headers",
+        elapsed,
+    ))
+}
+
+/// Like [`load_headers`], reading the store through a memory map of the file.
+#[cfg(feature = "mmap")]
+pub fn load_headers_mmap(count: usize) -> Result<Report, Error> {
+    let (dir, store) = header_store(count)?;
+
+    let start = time::Instant::now();
+    let loaded = load(store.mmap_iter()?)?;
+    let elapsed = start.elapsed();
+
+    fs::remove_dir_all(&dir)?;
+    assert_eq!(loaded, count);
+
+    Ok(Report::new(
+        Scenario::LoadHeadersMmap,
+        count,
+        "headers",
+        elapsed,
+    ))
+}
+
+/// Write `count` block headers to an on-disk store, in a new temporary directory.
+fn header_store(count: usize) -> Result<(PathBuf, store::File<BlockHeader>), Error> {
+    let genesis = Network::Regtest.genesis();
+    let dir = env::temp_dir().join(format!("nakamoto-bench-{}", process::id()));
+    let path = dir.join("headers.db");
+
+    fs::create_dir_all(&dir)?;
+    {
+        // The store doesn't validate headers, so there's no need to generate a real chain.
+        let mut store = store::File::create(&path, genesis)?;
+
+        store.put((0..count).map(|nonce| BlockHeader {
+            nonce: nonce as u32,
+            ..genesis
+        }))?;
+        store.sync()?;
+    }
+    let store = store::File::open(&path, genesis)?;
+
+    Ok((dir, store))
+}
+
+/// Consume an iterator over stored headers, and return the number of headers.
+fn load(
+    headers: impl Iterator<Item = Result<(Height, BlockHeader), store::Error>>,
+) -> Result<usize, Error> {
+    let mut count = 0;
+
+    for result in headers {
+        result?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Spawn a client with in-memory stores, listening on the local host.
+fn spawn(
+    name: &'static str,
+    network: Network,
+) -> Result<
+    (
+        client::Handle<Reactor>,
+        net::SocketAddr,
+        thread::JoinHandle<()>,
+    ),
+    Error,
+> {
+    let cfg = Config {
+        name,
+        network,
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        services: syncmgr::REQUIRED_SERVICES,
+        ..Config::default()
+    };
+    let mut client = Client::<Reactor>::new(cfg)?;
+    let mut handle = client.handle();
+    let events = handle.events();
+
+    let thread = thread::spawn(move || {
+        let checkpoints = network.checkpoints().collect::<Vec<_>>();
+        let store = store::Memory::new((network.genesis(), vec![]).into());
+        let cache = BlockCache::from(store, network.params(), &checkpoints).unwrap();
+        let filters = FilterCache::from(store::Memory::default()).unwrap();
+
+        if let Err(err) = client.run_with(cache, filters, HashMap::new()) {
+            eprintln!("Error: client `{}` failed: {}", name, err);
+        }
+    });
+
+    let addr = event::wait(
+        &events,
+        |e| match e {
+            Event::Listening(addr) => Some(addr),
+            _ => None,
+        },
+        time::Duration::from_secs(5),
+    )?;
+    handle.set_timeout(time::Duration::from_secs(600));
+
+    Ok((handle, addr, thread))
+}
@@ -8,7 +8,7 @@
 pub mod test;
 
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
@@ -32,6 +32,8 @@ struct CachedBlock {
     pub height: Height,
     pub hash: BlockHash,
     pub header: BlockHeader,
+    /// Total proof-of-work of the chain, up to and including this block.
+    pub chain_work: Work,
 }
 
 impl std::ops::Deref for CachedBlock {
@@ -57,6 +59,41 @@ struct Candidate {
     fork_hash: BlockHash,
 }
 
+/// Verify a chain of headers, starting with the genesis, by importing them into an in-memory
+/// block cache, which fully validates each header. Returns the height of the chain.
+///
+/// Fails if any header is invalid, or if the headers don't form a single chain.
+pub fn verify<I: IntoIterator<Item = BlockHeader>, C: Clock>(
+    headers: I,
+    params: Params,
+    checkpoints: &[(Height, BlockHash)],
+    clock: &C,
+) -> Result<Height, Error> {
+    let mut headers = headers.into_iter();
+    let genesis = match headers.next() {
+        Some(genesis) => genesis,
+        None => return Ok(0),
+    };
+    let store = crate::block::store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, checkpoints)?;
+    let mut height = 0;
+    let mut tip = genesis.block_hash();
+
+    for header in headers {
+        if header.prev_blockhash != tip {
+            return Err(Error::BlockMissing(header.prev_blockhash));
+        }
+        height += 1;
+        tip = header.block_hash();
+
+        cache.import_block(header, clock)?;
+    }
+    if cache.tip().0 != tip {
+        return Err(Error::InvalidBlockHash(tip, height));
+    }
+    Ok(height)
+}
+
 /// An implementation of [`BlockTree`] using a generic storage backend.
 /// Most of the functionality is accessible via the trait.
 ///
@@ -69,6 +106,8 @@ pub struct BlockCache<S: Store> {
     orphans: HashMap<BlockHash, BlockHeader>,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
+    /// Whether the BIP 94 difficulty rules are enforced.
+    bip94: bool,
     store: S,
 }
 
@@ -83,12 +122,17 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         let length = store.len()?;
         let orphans = HashMap::new();
         let checkpoints = checkpoints.iter().cloned().collect();
+        // Testnet4 shares its consensus parameters with testnet, so it is identified by
+        // its genesis block instead.
+        let bip94 =
+            genesis.block_hash() == nakamoto_common::network::Network::Testnet4.genesis_hash();
 
         let chain = NonEmpty::from((
             CachedBlock {
                 height: 0,
                 hash: genesis.block_hash(),
                 header: genesis,
+                chain_work: genesis.work(),
             },
             Vec::with_capacity(length - 1),
         ));
@@ -101,6 +145,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             headers,
             orphans,
             params,
+            bip94,
             checkpoints,
             store,
         };
@@ -118,6 +163,48 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Ok(cache)
     }
 
+    /// Import a chain of trusted headers, eg. a header dump from one's own full node, in
+    /// bulk. Returns the new chain height.
+    ///
+    /// Headers already in the active chain are skipped, and the remaining headers must
+    /// extend the active chain. Unlike [`BlockTree::import_blocks`], only the proof-of-work
+    /// of each header against its own target, and the checkpoints, are checked: difficulty
+    /// transitions and timestamps are not. Headers are written to the store at once.
+    pub fn import_trusted<I: IntoIterator<Item = BlockHeader>>(
+        &mut self,
+        headers: I,
+    ) -> Result<Height, Error> {
+        let mut imported = Vec::new();
+
+        for header in headers {
+            let hash = header.block_hash();
+
+            if imported.is_empty() && self.headers.contains_key(&hash) {
+                continue;
+            }
+            let tip = self.chain.last();
+
+            if header.prev_blockhash != tip.hash {
+                return Err(Error::BlockMissing(header.prev_blockhash));
+            }
+            let height = tip.height + 1;
+
+            if header.validate_pow(&header.target()).is_err() {
+                return Err(Error::InvalidBlockPoW);
+            }
+            if let Some(checkpoint) = self.checkpoints.get(&height) {
+                if &hash != checkpoint {
+                    return Err(Error::InvalidBlockHash(hash, height));
+                }
+            }
+            self.extend_chain(height, hash, header);
+            imported.push(header);
+        }
+        self.store.put(imported.into_iter())?;
+
+        Ok(self.height())
+    }
+
     /// Iterate over a range of blocks.
     ///
     /// # Errors
@@ -139,31 +226,6 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             .take((range.end - range.start) as usize)
     }
 
-    /// Get the median time past for the blocks leading up to the given height.
-    ///
-    /// # Errors
-    ///
-    /// Panics if height is `0`.
-    ///
-    pub fn median_time_past(&self, height: Height) -> BlockTime {
-        assert!(height != 0, "height must be > 0");
-
-        let mut times = [0; time::MEDIAN_TIME_SPAN as usize];
-
-        let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
-        let end = height;
-
-        for (i, blk) in self.range(start..end).enumerate() {
-            times[i] = blk.time;
-        }
-
-        // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`.
-        let available = &mut times[0..(end - start) as usize];
-
-        available.sort_unstable();
-        available[available.len() / 2]
-    }
-
     /// Import a block into the tree. Performs header validation. This function may trigger
     /// a chain re-org.
     fn import_block(
@@ -332,13 +394,15 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Validate a candidate branch. This function is useful for chain selection.
     fn validate_branch(&self, candidate: &Candidate, clock: &impl Clock) -> Result<(), Error> {
-        let fork_header = self
-            .get_block_by_height(candidate.fork_height)
+        let fork = self
+            .chain
+            .get(candidate.fork_height as usize)
             .expect("the given candidate must fork from a known block");
         let mut tip = CachedBlock {
             height: candidate.fork_height,
             hash: candidate.fork_hash,
-            header: *fork_header,
+            header: fork.header,
+            chain_work: fork.chain_work,
         };
 
         for header in candidate.headers.iter() {
@@ -348,6 +412,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: tip.height + 1,
                 hash: header.block_hash(),
                 header: *header,
+                chain_work: tip.chain_work + header.work(),
             };
         }
         Ok(())
@@ -370,6 +435,18 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             } else {
                 self.next_min_difficulty_target(&self.params)
             }
+        } else if self.bip94 && (tip.height + 1) % self.params.difficulty_adjustment_interval() == 0
+        {
+            // BIP 94: retarget based on the first block of the period, rather than the last,
+            // so that a min-difficulty block can't lower the difficulty of the next period.
+            let first = tip
+                .height
+                .saturating_sub(self.params.difficulty_adjustment_interval() - 1);
+            let target = self
+                .get_block_by_height(first)
+                .map_or_else(|| tip.target(), |h| h.target());
+
+            self.next_difficulty_target(tip.height, tip.time, target, &self.params)
         } else {
             self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
         };
@@ -407,6 +484,14 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         if header.time > clock.block_time() + time::MAX_FUTURE_BLOCK_TIME {
             return Err(Error::InvalidBlockTime(header.time, Ordering::Greater));
         }
+        // BIP 94: the first block of a difficulty period can't precede its parent by more
+        // than MAX_TIMEWARP, preventing the time warp attack.
+        if self.bip94
+            && height % self.params.difficulty_adjustment_interval() == 0
+            && header.time < tip.time.saturating_sub(time::MAX_TIMEWARP)
+        {
+            return Err(Error::InvalidBlockTime(header.time, Ordering::Less));
+        }
 
         Ok(())
     }
@@ -476,10 +561,14 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         self.headers.insert(hash, height);
         self.orphans.remove(&hash);
+
+        let chain_work = self.chain.last().chain_work + header.work();
+
         self.chain.push(CachedBlock {
             height,
             hash,
             header,
+            chain_work,
         });
     }
 
@@ -497,9 +586,34 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         context: &C,
     ) -> Result<ImportResult, Error> {
         let mut result = None;
+        // Blocks of the original active chain that were reverted, in height order.
+        let mut stale = Vec::new();
+        let mut imported = HashSet::new();
 
         for (i, header) in chain.enumerate() {
+            imported.insert(header.block_hash());
+
             match self.import_block(header, context) {
+                Ok(ImportResult::TipChanged(header, hash, height, reverted)) => {
+                    // The tip may change more than once while importing, eg. if the best
+                    // branch is only found after switching to another one. Blocks that
+                    // were imported and then reverted don't need to be reported. Since
+                    // each switch forks off below the previous one, the blocks it reverts
+                    // come before those already reverted.
+                    let mut reverted = reverted
+                        .into_iter()
+                        .filter(|h| !imported.contains(h))
+                        .collect::<Vec<_>>();
+                    reverted.append(&mut stale);
+                    stale = reverted;
+
+                    result = Some(ImportResult::TipChanged(
+                        header,
+                        hash,
+                        height,
+                        stale.clone(),
+                    ));
+                }
                 Ok(r) => result = Some(r),
                 Err(Error::DuplicateBlock(hash)) => log::trace!("Duplicate block {}", hash),
                 Err(Error::BlockMissing(hash)) => log::trace!("Missing block {}", hash),
@@ -559,11 +673,24 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         Box::new(Iter::new(&self.chain).map(|(i, h)| (i, h.header)))
     }
 
+    /// Iterate over a range of blocks.
+    fn range<'a>(
+        &'a self,
+        range: std::ops::Range<Height>,
+    ) -> Box<dyn Iterator<Item = BlockHeader> + 'a> {
+        Box::new(BlockCache::range(self, range).map(|blk| blk.header))
+    }
+
     /// Return the height of the longest chain.
     fn height(&self) -> Height {
         self.chain.tail.len() as Height
     }
 
+    /// Get the total proof-of-work of the active chain, up to the given height.
+    fn chain_work(&self, height: Height) -> Option<Work> {
+        self.chain.get(height as usize).map(|blk| blk.chain_work)
+    }
+
     /// Check whether this block hash is known.
     fn is_known(&self, hash: &BlockHash) -> bool {
         self.headers.contains_key(hash) || self.orphans.contains_key(hash)
@@ -624,6 +751,10 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
     ///
     /// *Panics* if the given starting height is out of bounds.
     ///
+    fn sync(&mut self) -> Result<(), block::store::Error> {
+        self.store.sync()
+    }
+
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash> {
         let mut hashes = Vec::new();
 
@@ -2,7 +2,7 @@ use super::BlockCache;
 
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
 use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::{BlockTime, Height, Target, Work};
 
 use nakamoto_test::block;
 use nakamoto_test::block::cache::model;
@@ -512,11 +512,23 @@ fn test_bitcoin_difficulty() {
     }
 }
 
+/// Open a store with the test headers. Opening the store converts the file to the current
+/// format, so a copy is opened.
+fn headers_store(genesis: BlockHeader) -> (tempfile::TempDir, store::File<BlockHeader>) {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("headers.db");
+
+    std::fs::copy(&*nakamoto_test::headers::PATH, &path).unwrap();
+    let store = store::File::open(&path, genesis).unwrap();
+
+    (tmp, store)
+}
+
 // Test that we're correctly loading headers from the header store.
 #[test]
 fn test_from_store() {
     let genesis = constants::genesis_block(bitcoin::Network::Bitcoin).header;
-    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+    let (_tmp, store) = headers_store(genesis);
     let store_headers = store.iter().collect::<Result<Vec<_>, _>>().unwrap();
 
     let network = bitcoin::Network::Bitcoin;
@@ -538,12 +550,98 @@ fn test_from_store() {
     }
 }
 
+#[test]
+fn test_import_trusted() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let (_tmp, store) = headers_store(genesis);
+    let headers = store
+        .iter()
+        .map(|r| r.map(|(_, h)| h))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, Params::new(network), &[]).unwrap();
+
+    // Importing a prefix, then overlapping headers, only imports what's missing.
+    assert_eq!(
+        cache.import_trusted(headers[..10].iter().cloned()).unwrap(),
+        9
+    );
+    assert_eq!(
+        cache.import_trusted(headers.iter().cloned()).unwrap(),
+        headers.len() as Height - 1
+    );
+    assert_eq!(cache.iter().map(|(_, h)| h).collect::<Vec<_>>(), headers);
+    assert_eq!(cache.store.len().unwrap(), headers.len());
+
+    // Headers that don't extend the chain are rejected.
+    assert_eq!(
+        cache.import_trusted(headers[3..4].iter().cloned()).unwrap(),
+        headers.len() as Height - 1
+    );
+    let mut header = headers[headers.len() - 1];
+    header.prev_blockhash = headers[1].block_hash();
+    assert!(matches!(
+        cache.import_trusted(iter::once(header)),
+        Err(Error::BlockMissing(_))
+    ));
+}
+
+#[test]
+fn test_verify() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let (_tmp, store) = headers_store(genesis);
+    let mut headers = store
+        .iter()
+        .map(|r| r.map(|(_, h)| h))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let tip = headers.len() as Height - 1;
+
+    assert_eq!(
+        super::verify(headers.iter().cloned(), Params::new(network), &[], &clock).unwrap(),
+        tip
+    );
+
+    // Tampering with the last header invalidates its proof-of-work.
+    headers.last_mut().unwrap().nonce += 1;
+    assert!(matches!(
+        super::verify(headers.iter().cloned(), Params::new(network), &[], &clock),
+        Err(Error::InvalidBlockPoW)
+    ));
+
+    // Headers that don't form a chain are rejected.
+    headers[2].prev_blockhash = headers[0].block_hash();
+    assert!(matches!(
+        super::verify(headers.iter().cloned(), Params::new(network), &[], &clock),
+        Err(Error::BlockMissing(_))
+    ));
+}
+
+#[test]
+fn test_bip94() {
+    use nakamoto_common::network::Network;
+
+    for network in [Network::Mainnet, Network::Testnet, Network::Testnet4].iter() {
+        let genesis = network.genesis_block().header;
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let cache = BlockCache::from(store, network.params(), &[]).unwrap();
+
+        assert_eq!(cache.bip94, network.is_bip94());
+        assert_eq!(cache.genesis().block_hash(), network.genesis_hash());
+    }
+}
+
 #[test]
 fn test_median_time_past() {
     let network = bitcoin::Network::Bitcoin;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
-    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+    let (_tmp, store) = headers_store(genesis);
 
     let cache = BlockCache::from(store, params, &[]).unwrap();
     let headers = cache.iter().map(|(_, h)| h).collect::<Vec<_>>();
@@ -556,6 +654,31 @@ fn test_median_time_past() {
     assert_eq!(cache.median_time_past(13), headers[7].time);
 }
 
+#[test]
+fn test_find_height_by_time() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let (_tmp, store) = headers_store(genesis);
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let tip = cache.median_time_past(cache.height() + 1);
+
+    assert_eq!(cache.find_height_by_time(0), Some(0));
+    assert_eq!(cache.find_height_by_time(genesis.time), Some(0));
+    assert_eq!(cache.find_height_by_time(tip + 1), None);
+
+    for height in 1..=cache.height() {
+        let time = cache.median_time_past(height + 1);
+        let found = cache.find_height_by_time(time).unwrap();
+
+        // The first block with that median time past.
+        assert!(found <= height);
+        assert_eq!(cache.median_time_past(found + 1), time);
+        assert!(found == 0 || cache.median_time_past(found) < time);
+    }
+}
+
 #[test]
 fn prop_cache_import_ordered() {
     fn prop(input: arbitrary::OrderedHeaders) -> bool {
@@ -849,6 +972,44 @@ fn test_cache_import_back_and_forth() {
     assert_eq!(cache.tip().0, b5.hash);
 }
 
+#[test]
+fn test_cache_chain_work() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut rand::thread_rng();
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    let work = |cache: &BlockCache<_>| {
+        cache
+            .chain()
+            .fold(Work::default(), |work, header| work + header.work())
+    };
+
+    assert_eq!(cache.chain_work(0), Some(genesis.work()));
+    assert_eq!(cache.chain_work(1), None);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert_eq!(cache.chain_work(2), Some(work(&cache)));
+
+    // Switching to a fork recomputes the work past the fork.
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+    assert_eq!(cache.chain_work(3), Some(work(&cache)));
+    assert_eq!(
+        cache.chain_work(1),
+        Some(genesis.work() + cache.get_block_by_height(1).unwrap().work())
+    );
+}
+
 #[test]
 fn test_cache_import_equal_difficulty_blocks() {
     let mut headers = vec![
@@ -0,0 +1,86 @@
+//! Bulk import of block headers from a file, eg. a header dump from one's own full node.
+//!
+//! Two formats are supported: raw headers, ie. consecutive 80-byte consensus-encoded
+//! headers, and hex-encoded headers, one per line, as output by
+//! `bitcoin-cli getblockheader <hash> false`. Headers are imported with
+//! [`BlockCache::import_trusted`](super::cache::BlockCache::import_trusted), which skips most
+//! validation, so the file should only ever come from a trusted source.
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use bitcoin::consensus::encode::{deserialize, Decodable};
+use bitcoin_hashes::hex::FromHex;
+
+use super::BlockHeader;
+
+/// Size of a consensus-encoded block header.
+pub const HEADER_SIZE: usize = 80;
+
+/// Read block headers from a file. See the module documentation for the supported formats.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Vec<BlockHeader>> {
+    read(fs::File::open(path)?)
+}
+
+/// Read block headers from a reader. See the module documentation for the supported formats.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Vec<BlockHeader>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let mut bytes = Vec::new();
+
+    reader.read_to_end(&mut bytes)?;
+
+    // A raw header starts with its version, which is never made of hex digits only.
+    let is_hex = bytes
+        .iter()
+        .take(HEADER_SIZE * 2)
+        .all(|b| b.is_ascii_hexdigit());
+
+    if is_hex {
+        let text = std::str::from_utf8(&bytes).map_err(|_| invalid("invalid header file"))?;
+
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let bytes =
+                    Vec::<u8>::from_hex(line).map_err(|_| invalid("invalid hex-encoded header"))?;
+
+                deserialize(&bytes).map_err(|_| invalid("invalid hex-encoded header"))
+            })
+            .collect()
+    } else {
+        if bytes.len() % HEADER_SIZE != 0 {
+            return Err(invalid(
+                "header file size is not a multiple of the header size",
+            ));
+        }
+        bytes
+            .chunks(HEADER_SIZE)
+            .map(|chunk| {
+                BlockHeader::consensus_decode(chunk).map_err(|_| invalid("invalid raw header"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin_hashes::hex::ToHex;
+    use nakamoto_test::BITCOIN_HEADERS;
+
+    #[test]
+    fn test_read() {
+        let headers = BITCOIN_HEADERS.iter().cloned().collect::<Vec<_>>();
+        let raw = headers.iter().flat_map(serialize).collect::<Vec<u8>>();
+        let hex = headers
+            .iter()
+            .map(|h| serialize(h).to_hex() + "\n")
+            .collect::<String>();
+
+        assert_eq!(read(raw.as_slice()).unwrap(), headers);
+        assert_eq!(read(hex.as_bytes()).unwrap(), headers);
+        assert!(read(&raw[1..]).is_err());
+    }
+}
@@ -4,6 +4,12 @@ pub use nakamoto_common::block::store::*;
 
 pub mod io;
 pub mod memory;
+#[cfg(all(unix, feature = "mmap"))]
+mod mmap;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 pub use io::File;
 pub use memory::Memory;
+#[cfg(feature = "sqlite")]
+pub use sqlite::Sqlite;
@@ -1,45 +1,133 @@
 //! Persistent storage backend for blocks.
+//!
+//! Headers are stored in a single file, as fixed-size records following a short file
+//! header. Each record holds an encoded header followed by its checksum, so that records
+//! which were only partially written, eg. because of a crash, are detected. These are
+//! truncated when the file is opened.
+//!
+//! With the `mmap` feature, headers are read through a memory map of the file when
+//! iterating over the store, which is much faster when loading a large store.
 use std::fs;
 use std::io::{self, Read, Seek, Write};
 use std::iter;
 use std::marker::PhantomData;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+#[cfg(all(unix, feature = "mmap"))]
+use std::sync::Arc;
 
 use bitcoin::consensus::encode::{Decodable, Encodable};
+use bitcoin_hashes::{sha256d, Hash};
 
 use nakamoto_common::block::store::{Error, Store};
 use nakamoto_common::block::Height;
 
-/// Append a block to the end of the stream.
-fn put<H: Sized + Encodable, S: Seek + Write, I: Iterator<Item = H>>(
-    mut stream: S,
-    headers: I,
-) -> Result<Height, Error> {
-    let mut pos = stream.seek(io::SeekFrom::End(0))?;
-    let size = std::mem::size_of::<H>();
+#[cfg(all(unix, feature = "mmap"))]
+use super::mmap::Mmap;
+
+/// Magic bytes at the start of a header file.
+const MAGIC: &[u8; 8] = b"NAKAMOTO";
+/// Version of the file format.
+const VERSION: u32 = 1;
+/// Size of the file header: the magic bytes, the format version and the number of
+/// headers pruned after the genesis.
+const FILE_HEADER_SIZE: u64 = 8 + 4 + 8;
+/// Size of a record checksum.
+const CHECKSUM_SIZE: usize = 4;
+
+/// Size of a record holding a header of type `H`.
+fn record_size<H>() -> u64 {
+    (mem::size_of::<H>() + CHECKSUM_SIZE) as u64
+}
 
-    for header in headers {
-        pos += header.consensus_encode(&mut stream)? as u64;
-    }
-    Ok(pos / size as u64)
+/// Checksum of an encoded header: the first bytes of its double-SHA256.
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = sha256d::Hash::hash(bytes);
+    let mut checksum = [0; CHECKSUM_SIZE];
+
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+/// Check a record against its checksum.
+fn is_valid(record: &[u8]) -> bool {
+    let (bytes, sum) = record.split_at(record.len() - CHECKSUM_SIZE);
+    checksum(bytes) == sum
+}
+
+/// Encode a header as a record, at the end of the buffer.
+fn encode<H: Encodable>(header: &H, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let start = buf.len();
+
+    header.consensus_encode(&mut *buf)?;
+
+    let sum = checksum(&buf[start..]);
+    buf.extend_from_slice(&sum);
+
+    Ok(())
 }
 
-/// Get a block from the stream.
-fn get<H: Decodable, S: Seek + Read>(mut stream: S, ix: u64) -> Result<H, Error> {
-    let size = std::mem::size_of::<H>();
-    let mut buf = vec![0; size]; // TODO: Use an array when rust has const-generics.
+/// Read the record at the given index from the stream.
+fn read<H, S: Seek + Read>(mut stream: S, ix: u64) -> Result<Vec<u8>, Error> {
+    let size = record_size::<H>();
+    let mut buf = vec![0; size as usize]; // TODO: Use an array when rust has const-generics.
 
-    stream.seek(io::SeekFrom::Start(ix * size as u64))?;
+    stream.seek(io::SeekFrom::Start(FILE_HEADER_SIZE + ix * size))?;
     stream.read_exact(&mut buf)?;
 
-    H::consensus_decode(&buf[..]).map_err(Error::from)
+    Ok(buf)
+}
+
+/// Get a block from the stream. Returns [`Error::Corruption`] if the record doesn't
+/// match its checksum.
+fn get<H: Decodable, S: Seek + Read>(stream: S, ix: u64) -> Result<H, Error> {
+    let record = read::<H, _>(stream, ix)?;
+
+    if !is_valid(&record) {
+        return Err(Error::Corruption);
+    }
+    H::consensus_decode(&record[..record.len() - CHECKSUM_SIZE]).map_err(Error::from)
+}
+
+/// Write a file header.
+fn write_file_header<W: Write>(mut stream: W, pruned: Height) -> io::Result<()> {
+    stream.write_all(MAGIC)?;
+    stream.write_all(&VERSION.to_le_bytes())?;
+    stream.write_all(&pruned.to_le_bytes())
+}
+
+/// Read the file header, and return the number of pruned headers it holds. Returns `None`
+/// if the stream doesn't start with a file header.
+fn read_file_header<S: Seek + Read>(mut stream: S) -> Result<Option<Height>, Error> {
+    let mut buf = [0; FILE_HEADER_SIZE as usize];
+
+    stream.seek(io::SeekFrom::Start(0))?;
+
+    match stream.read_exact(&mut buf) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    if &buf[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let mut version = [0; 4];
+    let mut pruned = [0; 8];
+
+    version.copy_from_slice(&buf[8..12]);
+    pruned.copy_from_slice(&buf[12..20]);
+
+    if u32::from_le_bytes(version) != VERSION {
+        return Err(Error::Corruption);
+    }
+    Ok(Some(u64::from_le_bytes(pruned)))
 }
 
 /// An iterator over block headers in a file.
 #[derive(Debug)]
 pub struct Iter<H> {
     height: Height,
+    ix: u64,
     file: fs::File,
 
     _phantom: PhantomData<H>,
@@ -53,7 +141,7 @@ impl<H: Decodable> Iterator for Iter<H> {
 
         assert!(height > 0);
 
-        match get(&mut self.file, height - 1) {
+        match get(&mut self.file, self.ix) {
             // If we hit this branch, it's because we're trying to read passed the end
             // of the file, which means there are no further headers remaining.
             Err(Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => None,
@@ -62,39 +150,279 @@ impl<H: Decodable> Iterator for Iter<H> {
             Err(err) => Some(Err(err)),
             Ok(header) => {
                 self.height = height + 1;
+                self.ix += 1;
                 Some(Ok((height, header)))
             }
         }
     }
 }
 
+/// An iterator over block headers in a memory-mapped file.
+///
+/// While the iterator is alive, the store refuses to truncate the file.
+#[cfg(all(unix, feature = "mmap"))]
+#[derive(Debug)]
+pub struct MmapIter<H> {
+    height: Height,
+    /// Offset of the next record in the map.
+    offset: usize,
+    map: Mmap,
+    /// Keeps the store from truncating the mapped file.
+    _guard: Arc<()>,
+
+    _phantom: PhantomData<H>,
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl<H: Decodable> Iterator for MmapIter<H> {
+    type Item = Result<(Height, H), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = record_size::<H>() as usize;
+        let record = self.map.get(self.offset..self.offset + size)?;
+
+        if !is_valid(record) {
+            // Stop at the first invalid record, like a reader hitting the end of the file.
+            self.offset = self.map.len();
+            return Some(Err(Error::Corruption));
+        }
+        let height = self.height;
+
+        self.height += 1;
+        self.offset += size;
+
+        Some(
+            H::consensus_decode(&record[..size - CHECKSUM_SIZE])
+                .map(|header| (height, header))
+                .map_err(Error::from),
+        )
+    }
+}
+
 /// A `Store` backed by a single file.
 #[derive(Debug)]
 pub struct File<H> {
     file: fs::File,
+    path: PathBuf,
     genesis: H,
+    /// Number of headers pruned after the genesis.
+    pruned: Height,
+    /// Shared with the memory-mapped iterators over the file.
+    #[cfg(all(unix, feature = "mmap"))]
+    maps: Arc<()>,
 }
 
 impl<H> File<H> {
     /// Open a new file store from the given path and genesis header.
-    pub fn open<P: AsRef<Path>>(path: P, genesis: H) -> io::Result<Self> {
-        fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .append(true)
-            .open(path)
-            .map(|file| Self { file, genesis })
+    ///
+    /// Records at the end of the file that weren't completely written are truncated.
+    /// Files written without checksums, by earlier versions, are converted.
+    pub fn open<P: AsRef<Path>>(path: P, genesis: H) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = Self::open_file(&path, true)?;
+        let pruned = match read_file_header(&mut file)? {
+            Some(pruned) => pruned,
+            None if file.metadata()?.len() == 0 => {
+                write_file_header(&mut file, 0)?;
+                0
+            }
+            None => {
+                Self::upgrade(&path)?;
+                file = Self::open_file(&path, false)?;
+                0
+            }
+        };
+        let store = Self {
+            file,
+            path,
+            genesis,
+            pruned,
+            #[cfg(all(unix, feature = "mmap"))]
+            maps: Arc::new(()),
+        };
+        store.recover()?;
+
+        Ok(store)
     }
 
     /// Create a new file store at the given path, with the provided genesis header.
     pub fn create<P: AsRef<Path>>(path: P, genesis: H) -> Result<Self, Error> {
-        let file = fs::OpenOptions::new()
+        let mut file = fs::OpenOptions::new()
             .create_new(true)
             .read(true)
             .append(true)
-            .open(path)?;
+            .open(&path)?;
+
+        write_file_header(&mut file, 0)?;
+
+        Ok(Self {
+            file,
+            path: path.as_ref().to_path_buf(),
+            genesis,
+            pruned: 0,
+            #[cfg(all(unix, feature = "mmap"))]
+            maps: Arc::new(()),
+        })
+    }
+
+    fn open_file(path: &Path, create: bool) -> io::Result<fs::File> {
+        fs::OpenOptions::new()
+            .create(create)
+            .read(true)
+            .append(true)
+            .open(path)
+    }
+
+    /// Convert a file without checksums, as written by earlier versions, in place.
+    /// An incomplete header at the end of the file is dropped.
+    fn upgrade(path: &Path) -> Result<(), Error> {
+        let mut legacy = io::BufReader::new(fs::File::open(path)?);
+        let tmp = path.with_extension("upgrade");
+        let mut buf = vec![0; mem::size_of::<H>()];
+        {
+            let mut out = io::BufWriter::new(fs::File::create(&tmp)?);
+
+            write_file_header(&mut out, 0)?;
+
+            loop {
+                match legacy.read_exact(&mut buf) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err.into()),
+                }
+                out.write_all(&buf)?;
+                out.write_all(&checksum(&buf))?;
+            }
+            out.into_inner().map_err(io::Error::from)?.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Truncate the records at the end of the file that weren't completely written,
+    /// eg. because of a crash.
+    fn recover(&self) -> Result<(), Error> {
+        let size = record_size::<H>();
+        let len = self.file.metadata()?.len();
+        let mut records = len.saturating_sub(FILE_HEADER_SIZE) / size;
+        let mut file = self.file.try_clone()?;
+
+        while records > 0 && !is_valid(&read::<H, _>(&mut file, records - 1)?) {
+            records -= 1;
+        }
+        let valid = FILE_HEADER_SIZE + records * size;
+
+        if valid < len {
+            log::warn!(
+                "Truncating {} byte(s) of incomplete records from {:?}",
+                len - valid,
+                self.path
+            );
+            self.file.set_len(valid)?;
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Index of the first record that doesn't match its checksum, if any.
+    fn first_invalid(&self) -> Result<Option<u64>, Error> {
+        let size = record_size::<H>();
+        let mut file = self.file.try_clone()?;
+
+        file.seek(io::SeekFrom::Start(FILE_HEADER_SIZE))?;
+
+        let mut reader = io::BufReader::new(file);
+        let mut record = vec![0; size as usize];
+        let mut ix = 0;
 
-        Ok(Self { file, genesis })
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) if is_valid(&record) => ix += 1,
+                Ok(()) => return Ok(Some(ix)),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Truncate the file to the given length. Fails if the file is memory-mapped, since
+    /// reading a mapping past the end of its file is fatal.
+    fn truncate(&self, len: u64) -> Result<(), Error> {
+        #[cfg(all(unix, feature = "mmap"))]
+        if Arc::strong_count(&self.maps) > 1 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot truncate a store while iterating over it",
+            )));
+        }
+        self.file.set_len(len).map_err(Error::from)
+    }
+
+    /// Record the number of pruned headers in the file header.
+    fn set_pruned(&mut self, pruned: Height) -> Result<(), Error> {
+        // The store file is opened for appending, so we need another handle to write
+        // at the start of the file.
+        let mut file = fs::OpenOptions::new().write(true).open(&self.path)?;
+
+        write_file_header(&mut file, pruned)?;
+        self.pruned = pruned;
+
+        Ok(())
+    }
+
+    /// Record index of the header at the given height. The height must not be pruned.
+    fn index(&self, height: Height) -> u64 {
+        height - self.pruned - 1
+    }
+
+    /// Number of header records in the file.
+    fn records(&self) -> Result<u64, Error> {
+        let len = self.file.metadata()?.len();
+        let size = record_size::<H>();
+
+        if len < FILE_HEADER_SIZE || (len - FILE_HEADER_SIZE) % size != 0 {
+            return Err(Error::Corruption);
+        }
+        Ok((len - FILE_HEADER_SIZE) / size)
+    }
+}
+
+impl<H: Decodable> File<H> {
+    /// Iterate over the stored headers, excluding the genesis, reading them from the file
+    /// one at a time.
+    pub fn read_iter(&self) -> Result<Iter<H>, Error> {
+        // Clone so this function doesn't have to take a `&mut self`.
+        Ok(Iter {
+            height: self.pruned + 1,
+            ix: 0,
+            file: self.file.try_clone()?,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Iterate over the stored headers, excluding the genesis, reading them through a
+    /// memory map of the file. Headers appended after the iterator is created aren't
+    /// yielded.
+    #[cfg(all(unix, feature = "mmap"))]
+    pub fn mmap_iter(&self) -> Result<MmapIter<H>, Error> {
+        let len = self.file.metadata()?.len();
+        let size = record_size::<H>();
+
+        if len < FILE_HEADER_SIZE {
+            return Err(Error::Corruption);
+        }
+        // Only map whole records. An incomplete record at the end is left out.
+        let records = (len - FILE_HEADER_SIZE) / size;
+        let map = Mmap::map(&self.file, (FILE_HEADER_SIZE + records * size) as usize)?;
+
+        Ok(MmapIter {
+            height: self.pruned + 1,
+            offset: FILE_HEADER_SIZE as usize,
+            map,
+            _guard: self.maps.clone(),
+            _phantom: PhantomData,
+        })
     }
 }
 
@@ -108,29 +436,79 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
     /// Append a block to the end of the file.
     fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
-        self::put(&mut self.file, headers)
+        let mut buf = Vec::new();
+
+        for header in headers {
+            encode(&header, &mut buf)?;
+        }
+        // Write the whole batch at once, to keep the window for partial writes small.
+        self.file.write_all(&buf)?;
+        self.height()
     }
 
     /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
     /// the height is not found.
     fn get(&self, height: Height) -> Result<H, Error> {
-        if let Some(ix) = height.checked_sub(1) {
-            // Clone so this function doesn't have to take a `&mut self`.
-            let mut file = self.file.try_clone()?;
-            get(&mut file, ix)
-        } else {
-            Ok(self.genesis)
+        if height == 0 {
+            return Ok(self.genesis);
+        }
+        if height <= self.pruned {
+            return Err(Error::Pruned(height));
         }
+        // Clone so this function doesn't have to take a `&mut self`.
+        let mut file = self.file.try_clone()?;
+        get(&mut file, self.index(height))
     }
 
     /// Rollback the chain to the given height. Behavior is undefined if the given
     /// height is not contained in the store.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
-        let size = mem::size_of::<H>();
+        let size = record_size::<H>();
 
-        self.file
-            .set_len((height) * size as u64)
-            .map_err(Error::from)
+        if height == 0 {
+            if self.pruned > 0 {
+                self.set_pruned(0)?;
+            }
+        } else if height <= self.pruned {
+            return Err(Error::Pruned(height));
+        }
+        let records = match height {
+            0 => 0,
+            h => self.index(h) + 1,
+        };
+        self.truncate(FILE_HEADER_SIZE + records * size)
+    }
+
+    /// Discard the headers below the given height, except for the genesis.
+    ///
+    /// The remaining headers are copied to a new file, which then replaces the old one.
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        let size = record_size::<H>();
+        let below = below.min(self.height()?);
+
+        if below <= self.pruned + 1 {
+            return Ok(());
+        }
+        let pruned = below - 1;
+        let tmp = self.path.with_extension("prune");
+        {
+            let mut file = self.file.try_clone()?;
+            let mut out = fs::File::create(&tmp)?;
+
+            write_file_header(&mut out, pruned)?;
+
+            file.seek(io::SeekFrom::Start(
+                FILE_HEADER_SIZE + self.index(below) * size,
+            ))?;
+            io::copy(&mut file, &mut out)?;
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = Self::open_file(&self.path, false)?;
+        self.pruned = pruned;
+
+        Ok(())
     }
 
     /// Flush changes to disk.
@@ -138,55 +516,92 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         self.file.sync_data().map_err(Error::from)
     }
 
-    /// Iterate over all headers in the store.
+    /// Iterate over all headers in the store. With the `mmap` feature, headers are read
+    /// through a memory map of the file.
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, H), Error>>> {
-        // Clone so this function doesn't have to take a `&mut self`.
-        match self.file.try_clone() {
-            Ok(file) => Box::new(iter::once(Ok((0, self.genesis))).chain(Iter {
-                height: 1,
-                file,
-                _phantom: PhantomData,
-            })),
-            Err(err) => Box::new(iter::once(Err(Error::Io(err)))),
+        #[cfg(all(unix, feature = "mmap"))]
+        let headers = self.mmap_iter();
+        #[cfg(not(all(unix, feature = "mmap")))]
+        let headers = self.read_iter();
+
+        match headers {
+            Ok(headers) => Box::new(iter::once(Ok((0, self.genesis))).chain(headers)),
+            Err(err) => Box::new(iter::once(Err(err))),
         }
     }
 
     /// Return the number of headers in the store.
     fn len(&self) -> Result<usize, Error> {
-        let meta = self.file.metadata()?;
-        let len = meta.len();
-        let size = mem::size_of::<H>();
+        let records = self.records()?;
 
-        assert!(len <= usize::MAX as u64);
+        assert!(records < usize::MAX as u64);
 
-        if len as usize % size != 0 {
-            return Err(Error::Corruption);
-        }
-        Ok(len as usize / size + 1)
+        Ok(records as usize + 1)
     }
 
     /// Return the block height of the store.
     fn height(&self) -> Result<Height, Error> {
-        self.len().map(|n| n as Height - 1)
+        self.records().map(|n| n + self.pruned)
     }
 
-    /// Check the file store integrity.
+    /// Check the file store integrity, including the checksum of every record.
     fn check(&self) -> Result<(), Error> {
-        self.len().map(|_| ())
+        self.records()?;
+
+        if self.first_invalid()?.is_some() {
+            return Err(Error::Corruption);
+        }
+        Ok(())
     }
 
-    /// Attempt to heal data corruption.
+    /// Attempt to heal data corruption, by truncating the file at the first record that
+    /// is incomplete or doesn't match its checksum.
     fn heal(&self) -> Result<(), Error> {
-        let meta = self.file.metadata()?;
-        let len = meta.len();
-        let size = mem::size_of::<H>();
+        let len = self.file.metadata()?.len();
+        let size = record_size::<H>();
+
+        if len < FILE_HEADER_SIZE {
+            return Err(Error::Corruption);
+        }
+        let records = match self.first_invalid()? {
+            Some(ix) => ix,
+            None => (len - FILE_HEADER_SIZE) / size,
+        };
+        let valid = FILE_HEADER_SIZE + records * size;
 
-        assert!(len <= usize::MAX as u64);
+        if valid < len {
+            self.truncate(valid)?;
+        }
+        Ok(())
+    }
 
-        let extraneous = len as usize % size;
-        if extraneous != 0 {
-            self.file.set_len(len - extraneous as u64)?;
+    /// Heal the file, and copy the valid records to a new file, which then replaces the old
+    /// one. Fails if the file is memory-mapped.
+    fn compact(&mut self) -> Result<(), Error> {
+        #[cfg(all(unix, feature = "mmap"))]
+        if Arc::strong_count(&self.maps) > 1 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot compact a store while iterating over it",
+            )));
         }
+        self.heal()?;
+
+        let records = self.records()?;
+        let tmp = self.path.with_extension("compact");
+        {
+            let mut file = self.file.try_clone()?;
+            let mut out = fs::File::create(&tmp)?;
+
+            write_file_header(&mut out, self.pruned)?;
+
+            file.seek(io::SeekFrom::Start(FILE_HEADER_SIZE))?;
+            io::copy(&mut file.take(records * record_size::<H>()), &mut out)?;
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = Self::open_file(&self.path, false)?;
 
         Ok(())
     }
@@ -194,25 +609,31 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
 #[cfg(test)]
 mod test {
-    use std::{io, iter};
+    use std::io::{Seek, Write};
+    use std::{fs, io, iter};
+
+    use bitcoin::consensus::encode::Encodable;
 
-    use super::{Error, File, Height, Store};
+    use super::{record_size, Error, File, Height, Store, FILE_HEADER_SIZE, MAGIC};
     use crate::block::BlockHeader;
 
     const HEADER_SIZE: usize = 80;
 
-    fn store(path: &str) -> File<BlockHeader> {
-        let tmp = tempfile::tempdir().unwrap();
-        let genesis = BlockHeader {
+    fn genesis() -> BlockHeader {
+        BlockHeader {
             version: 1,
             prev_blockhash: Default::default(),
             merkle_root: Default::default(),
             bits: 0x2ffffff,
             time: 39123818,
             nonce: 0,
-        };
+        }
+    }
+
+    fn store(path: &str) -> File<BlockHeader> {
+        let tmp = tempfile::tempdir().unwrap();
 
-        File::open(tmp.path().join(path), genesis).unwrap()
+        File::open(tmp.path().join(path), genesis()).unwrap()
     }
 
     #[test]
@@ -340,6 +761,63 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = genesis();
+        let mut store = File::open(&path, genesis).unwrap();
+
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: genesis.block_hash(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 1842918273,
+            nonce: 0,
+        };
+        let headers = (0..32)
+            .map(|i| BlockHeader { nonce: i, ..header })
+            .collect::<Vec<_>>();
+
+        store.put(headers.iter().cloned()).unwrap();
+        store.prune(11).unwrap();
+
+        assert!(matches!(store.get(10), Err(Error::Pruned(10))));
+        assert_eq!(store.get(0).unwrap(), genesis);
+        assert_eq!(store.get(11).unwrap(), headers[10]);
+        assert_eq!(store.height().unwrap(), 32);
+        assert_eq!(store.len().unwrap(), 23);
+
+        let heights = store.iter().map(|r| r.unwrap().0).collect::<Vec<Height>>();
+        assert_eq!(heights[0], 0);
+        assert_eq!(heights[1..], (11..=32).collect::<Vec<_>>()[..]);
+
+        // Pruning is persisted.
+        drop(store);
+        let mut store = File::open(&path, genesis).unwrap();
+
+        assert!(store.get(10).is_err());
+        assert_eq!(store.get(32).unwrap(), headers[31]);
+        assert_eq!(store.put(iter::once(header)).unwrap(), 33);
+        assert_eq!(store.get(33).unwrap(), header);
+
+        // Pruning again, and rolling back.
+        store.prune(21).unwrap();
+        assert!(store.get(20).is_err());
+        assert_eq!(store.get(21).unwrap(), headers[20]);
+
+        store.rollback(25).unwrap();
+        assert_eq!(store.height().unwrap(), 25);
+        assert!(matches!(store.rollback(20), Err(Error::Pruned(20))));
+
+        // The tip is always kept.
+        store.prune(100).unwrap();
+        assert_eq!(store.height().unwrap(), 25);
+        assert_eq!(store.get(25).unwrap(), headers[24]);
+        store.check().unwrap();
+    }
+
     #[test]
     fn test_corrupt_file() {
         let mut store = store("headers.db");
@@ -370,13 +848,13 @@ mod test {
 
         assert_eq!(store.len().unwrap(), 3);
 
-        let size = std::mem::size_of::<BlockHeader>();
-        assert_eq!(size, HEADER_SIZE);
+        let size = record_size::<BlockHeader>();
+        assert_eq!(std::mem::size_of::<BlockHeader>(), HEADER_SIZE);
 
         // Intentionally corrupt the file, by truncating it by 32 bytes.
         store
             .file
-            .set_len(headers.len() as u64 * size as u64 - 32)
+            .set_len(FILE_HEADER_SIZE + headers.len() as u64 * size - 32)
             .unwrap();
 
         assert_eq!(
@@ -404,4 +882,153 @@ mod test {
             "the last (corrupted) header was removed"
         );
     }
+
+    #[test]
+    fn test_compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = genesis();
+        let headers = (0..8)
+            .map(|nonce| BlockHeader { nonce, ..genesis })
+            .collect::<Vec<_>>();
+        let mut store = File::open(&path, genesis).unwrap();
+
+        store.put(headers.iter().cloned()).unwrap();
+        store.prune(3).unwrap();
+        store.file.write_all(&[1, 2, 3]).unwrap();
+        store.check().unwrap_err();
+
+        store.compact().unwrap();
+        store.check().unwrap();
+
+        assert_eq!(store.height().unwrap(), 8);
+        assert_eq!(store.get(3).unwrap(), headers[2]);
+        assert!(matches!(store.get(2), Err(Error::Pruned(2))));
+        assert!(!tmp.path().join("headers.compact").exists());
+
+        let store = File::open(&path, genesis).unwrap();
+        assert_eq!(
+            store
+                .iter()
+                .skip(1)
+                .map(|r| r.unwrap().1)
+                .collect::<Vec<_>>(),
+            headers[2..]
+        );
+    }
+
+    #[test]
+    fn test_recover() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = genesis();
+        let headers = (0..8)
+            .map(|nonce| BlockHeader { nonce, ..genesis })
+            .collect::<Vec<_>>();
+        let size = record_size::<BlockHeader>();
+
+        {
+            let mut store = File::open(&path, genesis).unwrap();
+            store.put(headers.iter().cloned()).unwrap();
+        }
+
+        // Simulate a crash while writing: a record whose content never made it to disk,
+        // followed by a partial record.
+        {
+            let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&vec![0; size as usize]).unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+        }
+        let store = File::open(&path, genesis).unwrap();
+
+        assert_eq!(store.height().unwrap(), 8);
+        assert_eq!(store.get(8).unwrap(), headers[7]);
+        store.check().unwrap();
+
+        // Corruption in the middle of the file is detected, and healed by truncating.
+        {
+            let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(io::SeekFrom::Start(FILE_HEADER_SIZE + 4 * size + 1))
+                .unwrap();
+            file.write_all(&[0xff]).unwrap();
+        }
+        assert!(matches!(store.get(5), Err(Error::Corruption)));
+        assert!(store.iter().any(|r| matches!(r, Err(Error::Corruption))));
+        store.check().expect_err("a record is corrupted");
+
+        store.heal().unwrap();
+        store.check().unwrap();
+        assert_eq!(store.height().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = genesis();
+        let headers = (0..8)
+            .map(|nonce| BlockHeader { nonce, ..genesis })
+            .collect::<Vec<_>>();
+
+        // Headers stored back to back, without checksums, and an incomplete header.
+        {
+            let mut file = fs::File::create(&path).unwrap();
+
+            for header in headers.iter() {
+                header.consensus_encode(&mut file).unwrap();
+            }
+            file.write_all(&[1, 2, 3]).unwrap();
+        }
+        let store = File::open(&path, genesis).unwrap();
+
+        assert_eq!(store.height().unwrap(), 8);
+        assert_eq!(
+            store
+                .iter()
+                .skip(1)
+                .map(|r| r.unwrap().1)
+                .collect::<Vec<_>>(),
+            headers
+        );
+        store.check().unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+    }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    #[test]
+    fn test_mmap_iter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let genesis = genesis();
+        let mut store = File::open(tmp.path().join("headers.db"), genesis).unwrap();
+
+        assert_eq!(store.mmap_iter().unwrap().count(), 0);
+
+        let headers = (0..64)
+            .map(|nonce| BlockHeader { nonce, ..genesis })
+            .collect::<Vec<_>>();
+        store.put(headers.iter().cloned()).unwrap();
+        store.prune(9).unwrap();
+
+        let read = store
+            .read_iter()
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        let mapped = store
+            .mmap_iter()
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(read, mapped);
+        assert_eq!(mapped.first(), Some(&(9, headers[8])));
+        assert_eq!(mapped.last(), Some(&(64, headers[63])));
+
+        // The file can't be truncated while it is mapped.
+        let iter = store.mmap_iter().unwrap();
+        assert!(store.rollback(32).is_err());
+        drop(iter);
+        store.rollback(32).unwrap();
+        assert_eq!(store.mmap_iter().unwrap().count(), 32 - 8);
+    }
 }
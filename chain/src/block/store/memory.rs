@@ -1,4 +1,6 @@
 //! Ephemeral storage backend for blocks.
+use std::iter;
+
 use nonempty::NonEmpty;
 
 use nakamoto_common::block::store::{Error, Genesis, Store};
@@ -7,25 +9,29 @@ use nakamoto_common::network::Network;
 
 /// In-memory block store.
 #[derive(Debug, Clone)]
-pub struct Memory<H>(NonEmpty<H>);
+pub struct Memory<H> {
+    chain: NonEmpty<H>,
+    /// Number of headers pruned after the genesis.
+    pruned: Height,
+}
 
 impl<H> Memory<H> {
     /// Create a new in-memory block store.
     pub fn new(chain: NonEmpty<H>) -> Self {
-        Self(chain)
+        Self { chain, pruned: 0 }
     }
 }
 
 impl<H: Default> Default for Memory<H> {
     fn default() -> Self {
-        Self(NonEmpty::new(H::default()))
+        Self::new(NonEmpty::new(H::default()))
     }
 }
 
 impl<H: Genesis> Memory<H> {
     /// Create a memory store with only the genesis.
     pub fn genesis(network: Network) -> Self {
-        Self(NonEmpty::new(H::genesis(network)))
+        Self::new(NonEmpty::new(H::genesis(network)))
     }
 }
 
@@ -34,18 +40,24 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
 
     /// Get the genesis block.
     fn genesis(&self) -> H {
-        *self.0.first()
+        *self.chain.first()
     }
 
     /// Append a batch of consecutive block headers to the end of the chain.
     fn put<I: Iterator<Item = H>>(&mut self, headers: I) -> Result<Height, Error> {
-        self.0.tail.extend(headers);
-        Ok(self.0.len() as Height - 1)
+        self.chain.tail.extend(headers);
+        Ok(self.pruned + self.chain.tail.len() as Height)
     }
 
     /// Get the block at the given height.
     fn get(&self, height: Height) -> Result<H, Error> {
-        match self.0.get(height as usize) {
+        if height == 0 {
+            return Ok(self.chain.head);
+        }
+        if height <= self.pruned {
+            return Err(Error::Pruned(height));
+        }
+        match self.chain.tail.get((height - self.pruned - 1) as usize) {
             Some(header) => Ok(*header),
             None => Err(Error::Io(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
@@ -57,8 +69,23 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
     /// Rollback the chain to the given height.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         match height {
-            0 => self.0.tail.clear(),
-            h => self.0.tail.truncate(h as usize),
+            0 => {
+                self.chain.tail.clear();
+                self.pruned = 0;
+            }
+            h if h <= self.pruned => return Err(Error::Pruned(h)),
+            h => self.chain.tail.truncate((h - self.pruned) as usize),
+        }
+        Ok(())
+    }
+
+    /// Discard the headers below the given height, except for the genesis.
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        let below = below.min(self.height()?);
+
+        if below > self.pruned + 1 {
+            self.chain.tail.drain(..(below - self.pruned - 1) as usize);
+            self.pruned = below - 1;
         }
         Ok(())
     }
@@ -70,23 +97,29 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
 
     /// Iterate over all headers in the store.
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, H), Error>>> {
+        let genesis = iter::once(Ok((0, self.chain.head)));
+        let pruned = self.pruned;
+
         Box::new(
-            self.0
-                .clone()
-                .into_iter()
-                .enumerate()
-                .map(|(i, h)| Ok((i as Height, h))),
+            genesis.chain(
+                self.chain
+                    .tail
+                    .clone()
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, h)| Ok((pruned + i as Height + 1, h))),
+            ),
         )
     }
 
     /// Return the number of headers in the store.
     fn len(&self) -> Result<usize, Error> {
-        Ok(self.0.len())
+        Ok(self.chain.len())
     }
 
     /// Return the height of the store.
     fn height(&self) -> Result<Height, Error> {
-        Ok(self.0.len() as Height - 1)
+        Ok(self.pruned + self.chain.tail.len() as Height)
     }
 
     /// Check data integrity.
@@ -98,4 +131,10 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
     fn heal(&self) -> Result<(), Error> {
         Ok(())
     }
+
+    /// Release the memory left unused by rollbacks and pruning.
+    fn compact(&mut self) -> Result<(), Error> {
+        self.chain.tail.shrink_to_fit();
+        Ok(())
+    }
 }
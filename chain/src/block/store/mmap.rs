@@ -0,0 +1,80 @@
+//! Read-only memory maps of store files.
+//!
+//! Reading a file through a memory map avoids a system call per header, which makes
+//! loading large stores considerably faster. Accessing a mapping past the end of the
+//! underlying file is an error at the OS level, so a mapped file must not be truncated
+//! while it is mapped. The file store ensures this for its own writes.
+#![allow(unsafe_code)]
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+/// A read-only memory map of the start of a file.
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only, and is only unmapped when dropped.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the first `len` bytes of a file. The file must be at least `len` bytes long.
+    pub fn map(file: &fs::File, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Ok(Self {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        if file.metadata()?.len() < len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "mapping past the end of the file",
+            ));
+        }
+        // SAFETY: We map a range that is backed by the file, and only ever read from it.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: The mapping is valid for `len` bytes until it is dropped.
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: The mapping was created by `mmap`, with the same length.
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! SQLite storage backend for blocks.
+//!
+//! Headers are stored in a table keyed by height, one row per header. Batches of headers
+//! are written in a single transaction, so that a crash never leaves a partial batch
+//! behind, and the database can be inspected with the usual SQLite tools.
+use std::collections::VecDeque;
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use nakamoto_common::block::store::{Error, Store};
+use nakamoto_common::block::Height;
+
+/// Number of headers read at once when iterating over the store.
+const PAGE_SIZE: i64 = 1000;
+
+/// Convert an SQLite error into a store error.
+pub(crate) fn error(err: rusqlite::Error) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::Other, err))
+}
+
+/// Open an SQLite database, creating it if necessary.
+pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Connection, Error> {
+    let conn = Connection::open(path).map_err(error)?;
+
+    // With a write-ahead log, readers don't block writers, and the database stays
+    // consistent if we crash in the middle of a write.
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+        .map_err(error)?;
+
+    Ok(conn)
+}
+
+/// Decode a header stored in a blob.
+fn decode<H: Decodable>(blob: Vec<u8>) -> Result<H, Error> {
+    H::consensus_decode(&blob[..]).map_err(Error::from)
+}
+
+/// An iterator over block headers in a table.
+#[derive(Debug)]
+pub struct Iter<H> {
+    conn: Arc<Mutex<Connection>>,
+    table: &'static str,
+    /// Height of the next page to read.
+    height: Height,
+    /// Headers read but not yet yielded.
+    page: VecDeque<(Height, Vec<u8>)>,
+    done: bool,
+
+    _phantom: PhantomData<H>,
+}
+
+impl<H> Iter<H> {
+    fn read_page(&mut self) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn
+            .prepare_cached(&format!(
+                "SELECT height, header FROM {} WHERE height >= ?1 ORDER BY height LIMIT ?2",
+                self.table
+            ))
+            .map_err(error)?;
+        let rows = stmt
+            .query_map(params![self.height as i64, PAGE_SIZE], |row| {
+                Ok((row.get::<_, i64>(0)? as Height, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(error)?;
+
+        for row in rows {
+            self.page.push_back(row.map_err(error)?);
+        }
+        if self.page.len() < PAGE_SIZE as usize {
+            self.done = true;
+        }
+        if let Some((height, _)) = self.page.back() {
+            self.height = height + 1;
+        }
+        Ok(())
+    }
+}
+
+impl<H: Decodable> Iterator for Iter<H> {
+    type Item = Result<(Height, H), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(err) = self.read_page() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.page
+            .pop_front()
+            .map(|(height, blob)| decode(blob).map(|header| (height, header)))
+    }
+}
+
+/// A `Store` backed by an SQLite table.
+///
+/// Like the file store, the genesis isn't stored, and is supplied when opening the store.
+#[derive(Debug)]
+pub struct Sqlite<H> {
+    conn: Arc<Mutex<Connection>>,
+    table: &'static str,
+    genesis: H,
+}
+
+impl<H> Sqlite<H> {
+    /// Open a store backed by the given table of an SQLite database, creating the database
+    /// and table if necessary. Several stores can share a database, using different tables.
+    pub fn open<P: AsRef<Path>>(path: P, table: &'static str, genesis: H) -> Result<Self, Error> {
+        let conn = self::open(path)?;
+
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    height INTEGER PRIMARY KEY,
+                    header BLOB NOT NULL
+                )",
+                table
+            ),
+            [],
+        )
+        .map_err(error)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            table,
+            genesis,
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lowest and highest stored heights, if any header other than the genesis is stored.
+    fn bounds(&self) -> Result<Option<(Height, Height)>, Error> {
+        let (min, max) = self
+            .conn()
+            .query_row(
+                &format!("SELECT MIN(height), MAX(height) FROM {}", self.table),
+                [],
+                |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?)),
+            )
+            .map_err(error)?;
+
+        Ok(min
+            .zip(max)
+            .map(|(min, max)| (min as Height, max as Height)))
+    }
+
+    /// Number of headers pruned after the genesis.
+    fn pruned(&self) -> Result<Height, Error> {
+        Ok(self.bounds()?.map_or(0, |(min, _)| min - 1))
+    }
+}
+
+impl<H: 'static + Copy + Encodable + Decodable> Store for Sqlite<H> {
+    type Header = H;
+
+    /// Get the genesis block.
+    fn genesis(&self) -> H {
+        self.genesis
+    }
+
+    /// Append a batch of block headers to the end of the table, in a single transaction.
+    fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
+        let mut height = self.height()?;
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(error)?;
+        {
+            let mut stmt = tx
+                .prepare_cached(&format!(
+                    "INSERT INTO {} (height, header) VALUES (?1, ?2)",
+                    self.table
+                ))
+                .map_err(error)?;
+
+            for header in headers {
+                height += 1;
+                stmt.execute(params![height as i64, encode::serialize(&header)])
+                    .map_err(error)?;
+            }
+        }
+        tx.commit().map_err(error)?;
+
+        Ok(height)
+    }
+
+    /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
+    /// the height is not found.
+    fn get(&self, height: Height) -> Result<H, Error> {
+        if height == 0 {
+            return Ok(self.genesis);
+        }
+        let blob = self
+            .conn()
+            .query_row(
+                &format!("SELECT header FROM {} WHERE height = ?1", self.table),
+                params![height as i64],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(error)?;
+
+        match blob {
+            Some(blob) => decode(blob),
+            None if height <= self.pruned()? => Err(Error::Pruned(height)),
+            None => Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of table",
+            ))),
+        }
+    }
+
+    /// Rollback the chain to the given height.
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        if height > 0 && height <= self.pruned()? {
+            return Err(Error::Pruned(height));
+        }
+        self.conn()
+            .execute(
+                &format!("DELETE FROM {} WHERE height > ?1", self.table),
+                params![height as i64],
+            )
+            .map_err(error)?;
+
+        Ok(())
+    }
+
+    /// Discard the headers below the given height, except for the genesis.
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        let below = below.min(self.height()?);
+
+        self.conn()
+            .execute(
+                &format!("DELETE FROM {} WHERE height < ?1", self.table),
+                params![below as i64],
+            )
+            .map_err(error)?;
+
+        Ok(())
+    }
+
+    /// Synchronize the changes to disk. Since every write is committed as it is made,
+    /// there is nothing left to do.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Iterate over all headers in the store.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, H), Error>>> {
+        Box::new(std::iter::once(Ok((0, self.genesis))).chain(Iter {
+            conn: self.conn.clone(),
+            table: self.table,
+            height: 1,
+            page: VecDeque::new(),
+            done: false,
+            _phantom: PhantomData,
+        }))
+    }
+
+    /// Return the number of headers in the store.
+    fn len(&self) -> Result<usize, Error> {
+        let count = self
+            .conn()
+            .query_row(&format!("SELECT COUNT(*) FROM {}", self.table), [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map_err(error)?;
+
+        Ok(count as usize + 1)
+    }
+
+    /// Return the block height of the store.
+    fn height(&self) -> Result<Height, Error> {
+        Ok(self.bounds()?.map_or(0, |(_, max)| max))
+    }
+
+    /// Check the database integrity, and that there are no gaps between the headers.
+    fn check(&self) -> Result<(), Error> {
+        let result = self
+            .conn()
+            .query_row("PRAGMA integrity_check", [], |row| row.get::<_, String>(0))
+            .map_err(error)?;
+
+        if result != "ok" {
+            return Err(Error::Corruption);
+        }
+        if let Some((min, max)) = self.bounds()? {
+            if max - min + 1 != self.len()? as Height - 1 {
+                return Err(Error::Corruption);
+            }
+        }
+        Ok(())
+    }
+
+    /// Attempt to heal data corruption. Since writes are atomic, there is nothing to
+    /// heal from a crash, and corruption of the database itself can't be healed.
+    fn heal(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Rebuild the database file, which also holds the other tables of the database.
+    fn compact(&mut self) -> Result<(), Error> {
+        self.conn().execute_batch("VACUUM").map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+
+    use super::{Error, Height, Sqlite, Store};
+    use crate::block::BlockHeader;
+
+    fn header(prev: &BlockHeader, nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 1842918273,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_put_get() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("nakamoto.sqlite");
+        let genesis = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 39123818,
+            nonce: 0,
+        };
+        let headers = (0..2500).map(|i| header(&genesis, i)).collect::<Vec<_>>();
+
+        {
+            let mut store = Sqlite::open(&path, "headers", genesis).unwrap();
+
+            assert_eq!(store.get(0).unwrap(), genesis);
+            assert!(store.get(1).is_err());
+            assert_eq!(store.put(headers.iter().cloned()).unwrap(), 2500);
+            assert_eq!(store.len().unwrap(), 2501);
+            assert_eq!(store.get(2500).unwrap(), headers[2499]);
+            store.check().unwrap();
+
+            // Another table in the same database is independent.
+            let other = Sqlite::open(&path, "other", genesis).unwrap();
+            assert_eq!(other.height().unwrap(), 0);
+        }
+
+        // Headers are there once the database is opened again.
+        let mut store = Sqlite::open(&path, "headers", genesis).unwrap();
+        let stored = store
+            .iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<(Height, BlockHeader)>>();
+
+        assert_eq!(stored.len(), 2501);
+        assert_eq!(stored[0], (0, genesis));
+        for (height, header) in stored.iter().skip(1) {
+            assert_eq!(*header, headers[*height as usize - 1]);
+        }
+
+        // Rollback and overwrite the history.
+        store.rollback(1000).unwrap();
+        assert_eq!(store.height().unwrap(), 1000);
+        assert!(store.get(1001).is_err());
+
+        let fork = header(&headers[999], 99999);
+        assert_eq!(store.put(iter::once(fork)).unwrap(), 1001);
+        assert_eq!(store.get(1001).unwrap(), fork);
+
+        // Prune, keeping the genesis and the tip.
+        store.prune(500).unwrap();
+        assert!(matches!(store.get(499), Err(Error::Pruned(499))));
+        assert_eq!(store.get(500).unwrap(), headers[499]);
+        assert_eq!(store.get(0).unwrap(), genesis);
+        assert_eq!(store.height().unwrap(), 1001);
+        assert_eq!(store.iter().nth(1).unwrap().unwrap().0, 500);
+        assert!(matches!(store.rollback(400), Err(Error::Pruned(400))));
+        store.check().unwrap();
+    }
+}
@@ -1,11 +1,10 @@
 #![allow(dead_code)]
 //! Compact block filter cache.
 
+use std::collections::VecDeque;
 use std::io;
 use std::ops::Range;
 
-use nonempty::NonEmpty;
-
 use bitcoin::consensus::{encode, Decodable, Encodable};
 
 pub use nakamoto_common::block::filter::{
@@ -18,6 +17,9 @@ use nakamoto_common::block::Height;
 use nakamoto_common::network::Network;
 
 use crate::filter::store;
+use crate::filter::store::bodies::Bodies;
+
+use bitcoin_hashes::Hash as _;
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct StoredHeader {
@@ -54,37 +56,121 @@ impl Genesis for StoredHeader {
     }
 }
 
+/// Default number of filter headers kept in memory.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Filter header cache. Keeps a bounded window of the most recent headers in memory,
+/// and reads older headers from the underlying store on demand.
 pub struct FilterCache<S> {
-    headers: NonEmpty<StoredHeader>,
+    /// The most recent headers. Always contains at least the tip.
+    headers: VecDeque<StoredHeader>,
+    /// Height of the first header in the in-memory window.
+    offset: Height,
+    /// Maximum number of headers kept in memory.
+    capacity: usize,
+    /// Number of headers pruned after the genesis.
+    pruned: Height,
     header_store: S,
+    /// Filter store. If not set, filters aren't stored.
+    bodies: Option<Box<dyn Bodies + Send>>,
 }
 
 impl<S: Store<Header = StoredHeader>> FilterCache<S> {
+    /// Create a new cache from a store, keeping up to [`DEFAULT_CAPACITY`] headers
+    /// in memory.
     pub fn from(header_store: S) -> Result<Self, nakamoto_common::block::store::Error> {
-        let mut headers = NonEmpty::new(header_store.genesis());
+        Self::with_capacity(header_store, DEFAULT_CAPACITY)
+    }
+
+    /// Create a new cache from a store, keeping up to `capacity` headers in memory.
+    /// The tip is always kept in memory, so a capacity of zero is treated as one.
+    pub fn with_capacity(
+        header_store: S,
+        capacity: usize,
+    ) -> Result<Self, nakamoto_common::block::store::Error> {
+        let mut cache = Self {
+            headers: VecDeque::new(),
+            offset: 0,
+            capacity: capacity.max(1),
+            pruned: 0,
+            header_store,
+            bodies: None,
+        };
+        cache.headers.push_back(cache.header_store.genesis());
 
-        for result in header_store.iter().skip(1) {
-            let (_, header) = result?;
-            headers.push(header);
+        for result in cache.header_store.iter().skip(1) {
+            let (height, header) = result?;
+
+            if height != cache.height() + 1 {
+                // The headers before this one were pruned.
+                cache.pruned = height - 1;
+                cache.headers.clear();
+                cache.offset = height;
+            }
+            cache.push(header);
         }
+        Ok(cache)
+    }
 
-        Ok(Self {
-            header_store,
-            headers,
-        })
+    /// Reload the in-memory window from the store, ending at the given height.
+    fn reload(&mut self, height: Height) -> Result<(), Error> {
+        let mut start = (height + 1).saturating_sub(self.capacity as Height);
+
+        if self.pruned > 0 && height > 0 {
+            start = start.max(self.pruned + 1);
+        }
+        self.headers.clear();
+        self.offset = start;
+
+        for h in start..=height {
+            let header = self.header_store.get(h)?;
+            self.headers.push_back(header);
+        }
+        Ok(())
     }
 }
 
 impl<S> FilterCache<S> {
+    /// Store validated filters in the given store, so that they don't have to be fetched
+    /// from the network again.
+    pub fn with_bodies(mut self, bodies: impl Bodies + Send + 'static) -> Self {
+        self.bodies = Some(Box::new(bodies));
+        self
+    }
+
+    /// Maximum number of headers kept in memory.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Add a header to the in-memory window, evicting the oldest header if necessary.
+    fn push(&mut self, header: StoredHeader) {
+        self.headers.push_back(header);
+
+        if self.headers.len() > self.capacity {
+            self.headers.pop_front();
+            self.offset += 1;
+        }
+    }
+}
+
+impl<S: Store<Header = StoredHeader>> FilterCache<S> {
     /// Verify the filter header chain. Returns `true` if the chain is valid.
     pub fn verify(&self, network: Network) -> Result<(), store::Error> {
         let mut prev_header = FilterHeader::default();
 
-        if self.headers.first().header != FilterHeader::genesis(network) {
+        if self.header_store.genesis().header != FilterHeader::genesis(network) {
             return Err(store::Error::Integrity);
         }
 
-        for stored_header in self.headers.iter() {
+        for result in self.header_store.iter() {
+            let (height, stored_header) = result.map_err(|_| store::Error::Integrity)?;
+
+            // The first header after the pruned ones can't be checked against its parent.
+            if self.pruned > 0 && height == self.pruned + 1 {
+                prev_header = stored_header.header;
+                continue;
+            }
             let expected = stored_header.hash.filter_header(&prev_header);
             let actual = stored_header.header;
 
@@ -97,20 +183,26 @@ impl<S> FilterCache<S> {
     }
 }
 
-#[allow(unused_variables)]
 impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
     fn get_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)> {
-        self.headers
-            .get(height as usize)
-            .map(|s| (s.hash, s.header))
+        if height > self.height() {
+            return None;
+        }
+        let stored = if height >= self.offset {
+            self.headers.get((height - self.offset) as usize).copied()
+        } else {
+            self.header_store.get(height).ok()
+        };
+        stored.map(|s| (s.hash, s.header))
     }
 
     fn get_headers(&self, range: Range<Height>) -> Vec<(FilterHash, FilterHeader)> {
-        self.headers
-            .iter()
-            .skip(range.start as usize)
-            .take(range.end as usize - range.start as usize)
-            .map(|h| (h.hash, h.header))
+        let end = range.end.min(self.height() + 1);
+
+        (range.start..end)
+            .map(|height| self.get_header(height))
+            .take_while(Option::is_some)
+            .flatten()
             .collect()
     }
 
@@ -122,17 +214,22 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
             .into_iter()
             .map(|(hash, header)| StoredHeader { hash, header });
 
-        self.headers.tail.extend(iter.clone());
+        for header in iter.clone() {
+            self.push(header);
+        }
         self.header_store.put(iter).map_err(Error::from)
     }
 
     fn tip(&self) -> (&FilterHash, &FilterHeader) {
-        let StoredHeader { hash, header } = self.headers.last();
+        let StoredHeader { hash, header } = self
+            .headers
+            .back()
+            .expect("FilterCache::tip: the tip is always in memory");
         (hash, header)
     }
 
     fn height(&self) -> Height {
-        self.headers.tail.len() as Height
+        self.offset + self.headers.len() as Height - 1
     }
 
     fn rollback(&mut self, n: usize) -> Result<(), Error> {
@@ -140,8 +237,183 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
         let height = self.height() - n as Height;
 
         self.header_store.rollback(height)?;
-        self.headers.tail.truncate(height as usize);
 
+        if height == 0 {
+            self.pruned = 0;
+        }
+        if let Some(bodies) = &mut self.bodies {
+            bodies.rollback(height)?;
+        }
+        if height >= self.offset {
+            self.headers.truncate((height - self.offset) as usize + 1);
+        } else {
+            self.reload(height)?;
+        }
+        Ok(())
+    }
+
+    fn get_filter(&self, height: Height) -> Option<BlockFilter> {
+        let bodies = self.bodies.as_ref()?;
+        let (hash, _) = self.get_header(height)?;
+        let filter = bodies.get(height).ok()??;
+
+        // Stored filters may be stale, eg. after a re-org, so we only return filters
+        // that match our header chain.
+        if FilterHash::hash(&filter.content) == hash {
+            Some(filter)
+        } else {
+            None
+        }
+    }
+
+    fn has_filter(&self, height: Height) -> bool {
+        // Nb. This doesn't check the filter against its header.
+        height <= self.height() && matches!(&self.bodies, Some(bodies) if bodies.contains(height))
+    }
+
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), Error> {
+        if let Some(bodies) = &mut self.bodies {
+            bodies.put(height, &filter)?;
+        }
         Ok(())
     }
+
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        let below = below.min(self.height());
+
+        if below <= self.pruned + 1 {
+            return Ok(());
+        }
+        self.header_store.prune(below)?;
+
+        if let Some(bodies) = &mut self.bodies {
+            bodies.prune(below)?;
+        }
+        self.pruned = below - 1;
+
+        while self.offset < below && self.headers.len() > 1 {
+            self.headers.pop_front();
+            self.offset += 1;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.header_store.sync()?;
+
+        if let Some(bodies) = &mut self.bodies {
+            bodies.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::store::io::File;
+    use crate::block::store::memory::Memory;
+    use crate::filter::store::bodies;
+    use bitcoin_hashes::Hash;
+
+    fn headers(count: usize) -> Vec<(FilterHash, FilterHeader)> {
+        let mut prev = FilterHeader::genesis(Network::Regtest);
+        let mut headers = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let hash = FilterHash::hash(&i.to_le_bytes());
+            prev = hash.filter_header(&prev);
+            headers.push((hash, prev));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_bounded_window() {
+        let network = Network::Regtest;
+        let store = Memory::genesis(network);
+        let mut cache = FilterCache::with_capacity(store, 8).unwrap();
+        let imported = headers(32);
+
+        cache.import_headers(imported.clone()).unwrap();
+
+        assert_eq!(cache.height(), 32);
+        assert_eq!(cache.headers.len(), 8);
+        assert_eq!(cache.tip(), (&imported[31].0, &imported[31].1));
+
+        // Headers outside of the in-memory window are read from the store.
+        for (i, header) in imported.iter().enumerate() {
+            assert_eq!(cache.get_header(i as Height + 1), Some(*header));
+        }
+        assert_eq!(cache.get_headers(4..12), imported[3..11].to_vec());
+        assert_eq!(cache.get_header(33), None);
+        cache.verify(network).unwrap();
+
+        // Rolling back past the window reloads it from the store.
+        cache.rollback(30).unwrap();
+
+        assert_eq!(cache.height(), 2);
+        assert_eq!(cache.headers.len(), 3);
+        assert_eq!(cache.tip(), (&imported[1].0, &imported[1].1));
+        assert_eq!(cache.get_header(1), Some(imported[0]));
+
+        cache.import_headers(imported[2..].to_vec()).unwrap();
+        assert_eq!(cache.height(), 32);
+        assert_eq!(cache.headers.len(), 8);
+        cache.verify(network).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let network = Network::Regtest;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let genesis = StoredHeader::genesis(network);
+        let store = File::create(&path, genesis).unwrap();
+        let mut cache = FilterCache::with_capacity(store, 8)
+            .unwrap()
+            .with_bodies(bodies::Memory::default());
+        let imported = headers(32);
+
+        cache.import_headers(imported.clone()).unwrap();
+        for height in 1..=32 {
+            let filter = BlockFilter::new(&[height as u8]);
+            cache.import_filter(height, filter).unwrap();
+        }
+
+        // Pruning below the in-memory window.
+        cache.prune(11).unwrap();
+
+        assert_eq!(cache.height(), 32);
+        assert_eq!(cache.get_header(10), None);
+        assert_eq!(cache.get_header(11), Some(imported[10]));
+        assert_eq!(cache.get_header(0), Some((genesis.hash, genesis.header)));
+        assert!(!cache.has_filter(10));
+        assert!(cache.has_filter(11));
+        cache.verify(network).unwrap();
+
+        // Pruning within the in-memory window.
+        cache.prune(28).unwrap();
+
+        assert_eq!(cache.get_header(27), None);
+        assert_eq!(cache.headers.len(), 5);
+        assert_eq!(cache.tip(), (&imported[31].0, &imported[31].1));
+
+        // Pruned headers stay pruned once the store is opened again.
+        drop(cache);
+
+        let store = File::open(&path, genesis).unwrap();
+        let mut cache = FilterCache::with_capacity(store, 8).unwrap();
+
+        assert_eq!(cache.height(), 32);
+        assert_eq!(cache.get_header(27), None);
+        assert_eq!(cache.get_header(28), Some(imported[27]));
+        cache.verify(network).unwrap();
+
+        // We can't roll back past the pruned headers.
+        cache.rollback(2).unwrap();
+        assert_eq!(cache.tip(), (&imported[29].0, &imported[29].1));
+        assert!(cache.rollback(10).is_err());
+        assert_eq!(cache.height(), 30);
+    }
 }
@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+pub mod bodies;
+
 pub use nakamoto_common::block::filter::{BlockFilter, FilterHash, FilterHeader, Filters};
 pub use nakamoto_common::block::store::Store;
 
@@ -0,0 +1,497 @@
+//! Block filter body storage.
+//!
+//! Unlike filter headers, filters are variable in size, so they can't be stored in a
+//! fixed-size header [`Store`](nakamoto_common::block::store::Store).
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::store::Error;
+use nakamoto_common::block::Height;
+
+#[cfg(feature = "sqlite")]
+use crate::block::store::sqlite;
+
+/// Size of a record header: a height followed by a content length.
+const RECORD_HEADER_SIZE: usize = 8 + 4;
+
+/// Represents objects that can store block filters.
+pub trait Bodies {
+    /// Get the filter at the given height, if stored.
+    fn get(&self, height: Height) -> Result<Option<BlockFilter>, Error>;
+    /// Check whether a filter is stored for the given height.
+    fn contains(&self, height: Height) -> bool;
+    /// Store the filter for the given height, replacing any filter stored for that height.
+    fn put(&mut self, height: Height, filter: &BlockFilter) -> Result<(), Error>;
+    /// Discard all filters above the given height.
+    fn rollback(&mut self, height: Height) -> Result<(), Error>;
+    /// Discard all filters below the given height.
+    fn prune(&mut self, below: Height) -> Result<(), Error>;
+    /// Make sure all stored filters are durable.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// In-memory filter store.
+#[derive(Debug, Default, Clone)]
+pub struct Memory(BTreeMap<Height, BlockFilter>);
+
+impl Bodies for Memory {
+    fn get(&self, height: Height) -> Result<Option<BlockFilter>, Error> {
+        Ok(self.0.get(&height).cloned())
+    }
+
+    fn contains(&self, height: Height) -> bool {
+        self.0.contains_key(&height)
+    }
+
+    fn put(&mut self, height: Height, filter: &BlockFilter) -> Result<(), Error> {
+        self.0.insert(height, filter.clone());
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        self.0.split_off(&(height + 1));
+        Ok(())
+    }
+
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        self.0 = self.0.split_off(&below);
+        Ok(())
+    }
+}
+
+/// A filter store backed by a single append-only file.
+///
+/// Each record consists of the filter height, the length of the filter and the filter
+/// content. When a height appears more than once, the last record wins. Rolled back
+/// records remain on disk, which is why filters read from this store should be checked
+/// against their filter header. Pruning compacts the file, dropping these records too,
+/// and so does opening the file once more than half of it is taken by replaced records,
+/// so that the file doesn't keep growing when filters are fetched again, eg. after reorgs.
+#[derive(Debug)]
+pub struct File {
+    file: fs::File,
+    path: PathBuf,
+    /// Offset and length of the filter content, by height.
+    index: HashMap<Height, (u64, u32)>,
+    /// Length of the valid part of the file.
+    len: u64,
+}
+
+impl File {
+    /// Open a filter store at the given path, creating it if it doesn't exist.
+    ///
+    /// If the file ends with an incomplete record, eg. due to a crash while writing,
+    /// the incomplete record is discarded.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut index = HashMap::new();
+        let mut len = 0;
+        let total = file.metadata()?.len();
+
+        file.seek(io::SeekFrom::Start(0))?;
+        let mut reader = io::BufReader::new(&mut file);
+        let mut header = [0; RECORD_HEADER_SIZE];
+
+        while len + RECORD_HEADER_SIZE as u64 <= total {
+            reader.read_exact(&mut header)?;
+
+            let mut height = [0; 8];
+            let mut size = [0; 4];
+
+            height.copy_from_slice(&header[..8]);
+            size.copy_from_slice(&header[8..]);
+
+            let height = u64::from_le_bytes(height);
+            let size = u32::from_le_bytes(size);
+            let offset = len + RECORD_HEADER_SIZE as u64;
+
+            if offset + size as u64 > total {
+                break;
+            }
+            reader.seek_relative(size as i64)?;
+            index.insert(height, (offset, size));
+            len = offset + size as u64;
+        }
+        if len < total {
+            file.set_len(len)?;
+        }
+        let mut store = Self {
+            file,
+            path: path.as_ref().to_path_buf(),
+            index,
+            len,
+        };
+        if store.len > store.live() * 2 {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Rewrite the file with the current record of each height only, dropping the records
+    /// that were replaced.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let mut heights = self.index.keys().copied().collect::<Vec<_>>();
+        heights.sort_unstable();
+
+        self.rewrite(heights)
+    }
+
+    /// Size of the current records, ie. the size of the file once compacted.
+    fn live(&self) -> u64 {
+        self.index
+            .values()
+            .map(|(_, size)| RECORD_HEADER_SIZE as u64 + *size as u64)
+            .sum()
+    }
+
+    /// Replace the file with one holding only the records at the given heights, in order.
+    fn rewrite(&mut self, heights: Vec<Height>) -> Result<(), Error> {
+        // Copy the records we keep to a new file, which then replaces the old one.
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut out = io::BufWriter::new(fs::File::create(&tmp)?);
+
+            for height in heights {
+                if let Some(filter) = self.get(height)? {
+                    let size = filter.content.len() as u32;
+
+                    out.write_all(&height.to_le_bytes())?;
+                    out.write_all(&size.to_le_bytes())?;
+                    out.write_all(&filter.content)?;
+                }
+            }
+            out.into_inner().map_err(io::Error::from)?.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        *self = Self::open(&self.path)?;
+
+        Ok(())
+    }
+
+    /// Number of filters in the store.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Check whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+impl Bodies for File {
+    fn get(&self, height: Height) -> Result<Option<BlockFilter>, Error> {
+        if let Some((offset, size)) = self.index.get(&height) {
+            // Clone so this function doesn't have to take a `&mut self`.
+            let mut file = self.file.try_clone()?;
+            let mut content = vec![0; *size as usize];
+
+            file.seek(io::SeekFrom::Start(*offset))?;
+            file.read_exact(&mut content)?;
+
+            return Ok(Some(BlockFilter::new(&content)));
+        }
+        Ok(None)
+    }
+
+    fn contains(&self, height: Height) -> bool {
+        self.index.contains_key(&height)
+    }
+
+    fn put(&mut self, height: Height, filter: &BlockFilter) -> Result<(), Error> {
+        let size = filter.content.len() as u32;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + filter.content.len());
+
+        record.extend_from_slice(&height.to_le_bytes());
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&filter.content);
+
+        self.file.write_all(&record)?;
+        self.index
+            .insert(height, (self.len + RECORD_HEADER_SIZE as u64, size));
+        self.len += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        self.index.retain(|h, _| *h <= height);
+        Ok(())
+    }
+
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        if self.index.keys().all(|h| *h >= below) {
+            return Ok(());
+        }
+        let mut heights = self
+            .index
+            .keys()
+            .copied()
+            .filter(|h| *h >= below)
+            .collect::<Vec<_>>();
+        heights.sort_unstable();
+
+        self.rewrite(heights)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data().map_err(Error::from)
+    }
+}
+
+/// A filter store backed by an SQLite table. Filters are replaced and removed in place,
+/// so unlike with the [`File`] store, nothing stale is kept around.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct Sqlite {
+    conn: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl Sqlite {
+    /// Open a filter store in the given SQLite database, creating it if necessary. The
+    /// database may be shared with header stores.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let conn = sqlite::open(path)?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS filters (
+                height INTEGER PRIMARY KEY,
+                filter BLOB NOT NULL
+            )",
+            [],
+        )
+        .map_err(sqlite::error)?;
+
+        Ok(Self { conn })
+    }
+
+    /// Number of filters in the store.
+    pub fn len(&self) -> Result<usize, Error> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM filters", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|n| n as usize)
+            .map_err(sqlite::error)
+    }
+
+    /// Check whether the store is empty.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        self.len().map(|n| n == 0)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Bodies for Sqlite {
+    fn get(&self, height: Height) -> Result<Option<BlockFilter>, Error> {
+        use rusqlite::OptionalExtension;
+
+        self.conn
+            .query_row(
+                "SELECT filter FROM filters WHERE height = ?1",
+                [height as i64],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map(|content| content.map(|c| BlockFilter::new(&c)))
+            .map_err(sqlite::error)
+    }
+
+    fn contains(&self, height: Height) -> bool {
+        matches!(
+            self.conn.query_row(
+                "SELECT 1 FROM filters WHERE height = ?1",
+                [height as i64],
+                |_| Ok(()),
+            ),
+            Ok(())
+        )
+    }
+
+    fn put(&mut self, height: Height, filter: &BlockFilter) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO filters (height, filter) VALUES (?1, ?2)",
+                rusqlite::params![height as i64, filter.content],
+            )
+            .map(|_| ())
+            .map_err(sqlite::error)
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM filters WHERE height > ?1", [height as i64])
+            .map(|_| ())
+            .map_err(sqlite::error)
+    }
+
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM filters WHERE height < ?1", [below as i64])
+            .map(|_| ())
+            .map_err(sqlite::error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_put_get() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let filters = (0..8u8)
+            .map(|i| BlockFilter::new(&vec![i; i as usize + 1]))
+            .collect::<Vec<_>>();
+
+        {
+            let mut store = File::open(&path).unwrap();
+
+            for (height, filter) in filters.iter().enumerate() {
+                store.put(height as Height, filter).unwrap();
+            }
+            assert_eq!(store.len(), filters.len());
+            assert_eq!(store.get(3).unwrap().unwrap().content, filters[3].content);
+            assert!(store.get(8).unwrap().is_none());
+
+            store.rollback(5).unwrap();
+            assert!(store.get(6).unwrap().is_none());
+
+            // Overwrite a filter.
+            store.put(5, &filters[0]).unwrap();
+            assert_eq!(store.get(5).unwrap().unwrap().content, filters[0].content);
+        }
+
+        // Simulate a partial write.
+        {
+            let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+        }
+
+        let store = File::open(&path).unwrap();
+
+        assert_eq!(store.get(5).unwrap().unwrap().content, filters[0].content);
+        assert_eq!(store.get(2).unwrap().unwrap().content, filters[2].content);
+    }
+
+    #[test]
+    fn test_file_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let filters = (0..8u8)
+            .map(|i| BlockFilter::new(&vec![i; i as usize + 1]))
+            .collect::<Vec<_>>();
+
+        {
+            let mut store = File::open(&path).unwrap();
+
+            for (height, filter) in filters.iter().enumerate() {
+                store.put(height as Height, filter).unwrap();
+            }
+            store.put(6, &filters[0]).unwrap();
+            store.prune(4).unwrap();
+
+            assert_eq!(store.len(), 4);
+            assert!(store.get(3).unwrap().is_none());
+            assert_eq!(store.get(4).unwrap().unwrap().content, filters[4].content);
+            assert_eq!(store.get(6).unwrap().unwrap().content, filters[0].content);
+
+            store.put(8, &filters[1]).unwrap();
+        }
+        let store = File::open(&path).unwrap();
+
+        // Only the records that were kept remain on disk.
+        assert_eq!(store.len(), 5);
+        assert!(store.get(2).unwrap().is_none());
+        assert_eq!(store.get(6).unwrap().unwrap().content, filters[0].content);
+        assert_eq!(store.get(8).unwrap().unwrap().content, filters[1].content);
+    }
+
+    #[test]
+    fn test_file_compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let filters = (0..8u8)
+            .map(|i| BlockFilter::new(&vec![i; i as usize + 1]))
+            .collect::<Vec<_>>();
+
+        {
+            let mut store = File::open(&path).unwrap();
+
+            for (height, filter) in filters.iter().enumerate() {
+                store.put(height as Height, filter).unwrap();
+            }
+        }
+        let size = fs::metadata(&path).unwrap().len();
+
+        // Replacing a few filters doesn't trigger a compaction.
+        {
+            let mut store = File::open(&path).unwrap();
+
+            store.put(7, &filters[6]).unwrap();
+        }
+        let store = File::open(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > size);
+        drop(store);
+
+        // Replacing more filters than are current does.
+        {
+            let mut store = File::open(&path).unwrap();
+
+            for _ in 0..8 {
+                store.put(7, &filters[7]).unwrap();
+            }
+        }
+        let store = File::open(&path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(store.len(), filters.len());
+        assert_eq!(store.get(7).unwrap().unwrap().content, filters[7].content);
+        assert_eq!(store.get(3).unwrap().unwrap().content, filters[3].content);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_put_get() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("nakamoto.sqlite");
+        let filters = (0..8u8)
+            .map(|i| BlockFilter::new(&vec![i; i as usize + 1]))
+            .collect::<Vec<_>>();
+
+        {
+            let mut store = Sqlite::open(&path).unwrap();
+
+            for (height, filter) in filters.iter().enumerate() {
+                store.put(height as Height, filter).unwrap();
+            }
+            assert_eq!(store.len().unwrap(), filters.len());
+            assert!(store.contains(3));
+            assert_eq!(store.get(3).unwrap().unwrap().content, filters[3].content);
+            assert!(store.get(8).unwrap().is_none());
+
+            store.rollback(5).unwrap();
+            assert!(!store.contains(6));
+
+            store.put(5, &filters[0]).unwrap();
+            store.prune(2).unwrap();
+        }
+        let store = Sqlite::open(&path).unwrap();
+
+        assert_eq!(store.len().unwrap(), 4);
+        assert!(store.get(1).unwrap().is_none());
+        assert_eq!(store.get(5).unwrap().unwrap().content, filters[0].content);
+    }
+}
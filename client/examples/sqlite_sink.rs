@@ -0,0 +1,107 @@
+//! An event sink persisting header and filter events to SQLite.
+//!
+//! Run with:
+//!
+//!     cargo run -p nakamoto-client --example sqlite_sink -- <database path>
+//!
+use std::net;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{env, thread, time};
+
+use rusqlite::{params, Connection};
+
+use nakamoto_client::client::{Client, Config, Network};
+use nakamoto_client::handle::Handle as _;
+use nakamoto_client::sink::{self, EventSink};
+use nakamoto_common::block::tree::ImportResult;
+use nakamoto_p2p::event::Event;
+use nakamoto_p2p::protocol::{spvmgr, syncmgr};
+
+type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, nakamoto_client::client::Publisher>;
+
+/// Keeps track of the active chain and of processed filters.
+struct Indexer {
+    db: Mutex<Connection>,
+}
+
+impl Indexer {
+    fn open(path: PathBuf) -> rusqlite::Result<Self> {
+        let db = Connection::open(path)?;
+
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                height  INTEGER PRIMARY KEY,
+                hash    TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS matches (
+                height  INTEGER NOT NULL,
+                hash    TEXT NOT NULL
+             );",
+        )?;
+
+        Ok(Self { db: Mutex::new(db) })
+    }
+}
+
+impl EventSink for Indexer {
+    fn receive(&self, event: &Event, _deadline: time::Instant) -> Result<(), sink::Error> {
+        let mut db = self.db.lock().unwrap();
+
+        match event {
+            Event::SyncManager(syncmgr::Event::BlockDisconnected { height, .. }) => {
+                let tx = db.transaction()?;
+
+                tx.execute("DELETE FROM blocks WHERE height >= ?1", params![*height])?;
+                tx.execute("DELETE FROM matches WHERE height >= ?1", params![*height])?;
+                tx.commit()?;
+            }
+            Event::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
+                _,
+                hash,
+                height,
+                _,
+            ))) => {
+                db.execute(
+                    "INSERT OR REPLACE INTO blocks (height, hash) VALUES (?1, ?2)",
+                    params![*height, hash.to_string()],
+                )?;
+            }
+            Event::SpvManager(spvmgr::Event::FilterProcessed {
+                block,
+                height,
+                matched: true,
+                ..
+            }) => {
+                db.execute(
+                    "INSERT INTO matches (height, hash) VALUES (?1, ?2)",
+                    params![*height, block.to_string()],
+                )?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("events.sqlite"));
+    let mut cfg = Config {
+        network: Network::Testnet,
+        ..Config::default()
+    };
+    cfg.sinks.register(Indexer::open(path)?);
+
+    let mut client = Client::<Reactor>::new(cfg)?;
+    let handle = client.handle();
+
+    thread::spawn(move || client.run().unwrap());
+
+    handle.wait_for_ready()?;
+    handle.shutdown()?;
+
+    Ok(())
+}
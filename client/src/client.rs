@@ -4,10 +4,13 @@ use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io;
+use std::mem;
 use std::net;
-use std::net::SocketAddr;
-use std::ops::Range;
-use std::path::PathBuf;
+use std::net::{SocketAddr, ToSocketAddrs as _};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
 use std::time::{self, SystemTime};
 
 use crossbeam_channel as chan;
@@ -18,60 +21,195 @@ use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_chain::{block::cache::BlockCache, filter::BlockFilter};
 
 use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::proof::InclusionProof;
 use nakamoto_common::block::store::{Genesis as _, Store as _};
-use nakamoto_common::block::time::AdjustedTime;
+use nakamoto_common::block::time::{AdjustedTime, LocalDuration};
 use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height, Transaction, Work};
 use nakamoto_common::p2p::peer::{Source, Store as _};
 
 pub use nakamoto_common::network::Network;
-pub use nakamoto_common::p2p::Domain;
-pub use nakamoto_p2p::protocol::Peer;
+pub use nakamoto_common::p2p::{Domain, Subnet};
+pub use nakamoto_p2p::protocol::{Birthday, Peer};
 
 use nakamoto_p2p as p2p;
+use nakamoto_p2p::bitcoin::consensus::encode::{Decodable, Encodable};
 use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
 use nakamoto_p2p::bitcoin::network::message::NetworkMessage;
 use nakamoto_p2p::bitcoin::network::Address;
+use nakamoto_p2p::bitcoin::util::bip32::ExtendedPubKey;
+use nakamoto_p2p::bitcoin::{Script, Txid};
+use nakamoto_p2p::protocol::feemgr::FeeRate;
+use nakamoto_p2p::protocol::replay::Recorder;
+use nakamoto_p2p::protocol::EffectiveConfig;
+use nakamoto_p2p::protocol::PeerInfo;
+use nakamoto_p2p::protocol::RescanStatus;
+use nakamoto_p2p::protocol::TxStatus;
 use nakamoto_p2p::protocol::{self, Link};
-use nakamoto_p2p::protocol::{connmgr, peermgr, spvmgr, syncmgr};
-use nakamoto_p2p::protocol::{Command, GetBlockError, Protocol};
+use nakamoto_p2p::protocol::{addrmgr, blockmgr, connmgr, peermgr, ratemgr, spvmgr, syncmgr};
+use nakamoto_p2p::protocol::{Blocks, ChainInfo, Command, Epoch, GetBlockError, Protocol};
+use nakamoto_p2p::protocol::{ChainSnapshot, DerivationScheme, SnapshotReader, WatchlistId};
 
 pub use nakamoto_p2p::event::{self, Event};
 pub use nakamoto_p2p::reactor::Reactor;
 
+use crate::delivery::{self, Deliveries};
 use crate::error::Error;
-use crate::handle;
+use crate::handle::{self, RescanHandle};
+use crate::metrics::{self, Metrics};
 use crate::peer;
+use crate::rescan;
+use crate::sink::Sinks;
+
+/// Time to wait for a block fetched with [`handle::Handle::fetch_block`], before it's
+/// requested again, from another peer. Longer than the block request timeout, so that
+/// the first request has timed out when the block is requested again.
+pub const BLOCK_RETRY_TIMEOUT: time::Duration =
+    time::Duration::from_secs(blockmgr::REQUEST_TIMEOUT.as_secs() + 5);
+
+/// Storage backend for block headers, filter headers and filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// A flat file per store.
+    File,
+    /// A single SQLite database, with a table per store. Requires the `sqlite` feature.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::File
+    }
+}
 
 /// Client configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
-    /// Client listen addresses.
+    /// Client listen addresses. Inbound peers can connect on these, up to
+    /// `max_inbound_peers` at a time, and are served block and filter headers. An empty list
+    /// disables inbound connections.
     pub listen: Vec<net::SocketAddr>,
     /// Bitcoin network.
     pub network: Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Only ever connect to the peers in `connect`, eg. one's own full node. Neither the
+    /// address book nor DNS seeds are used, and the peers are reconnected to with backoff
+    /// when they disconnect.
+    pub connect_only: bool,
     /// Network domains to connect to.
     pub domains: Vec<Domain>,
     /// Target number of outbound peers to connect to.
     pub target_outbound_peers: usize,
-    /// Maximum number of inbound peers supported.
+    /// Maximum number of inbound peers supported. Once reached, new inbound peers may
+    /// take the place of existing ones, see [`connmgr`].
     pub max_inbound_peers: usize,
     /// Timeout duration for client commands.
     pub timeout: time::Duration,
+    /// How long submitted transactions are served to the peers they were announced to.
+    pub tx_ttl: time::Duration,
+    /// Limits on the messages received from each peer.
+    pub limits: ratemgr::Config,
+    /// Minimum fee rate of the transactions we want announced to us. Sent to peers in a
+    /// `feefilter` message. Set to `None` to not send one.
+    pub feefilter: Option<FeeRate>,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
+    /// Data is kept under `<root>/.nakamoto/<network>`, so that clients on different
+    /// networks can share a home path, unless `datadir` is set.
     pub root: PathBuf,
+    /// Directory runtime data is stored in, overriding the one derived from `root`.
+    /// Clients running at the same time must not share a data directory.
+    pub datadir: Option<PathBuf>,
+    /// Storage backend used for block headers and filters, when the client is run with
+    /// [`Client::run`].
+    pub backend: Backend,
     /// Client name. Used for logging only.
     pub name: &'static str,
     /// Services offered by this node.
     pub services: ServiceFlags,
+    /// Services required of outbound peers. Peers signaling `NETWORK_LIMITED`, which only
+    /// serve recent blocks, satisfy `NETWORK`.
+    pub required_services: ServiceFlags,
+    /// Services required of the peers compact filters are downloaded from.
+    pub filter_services: ServiceFlags,
+    /// Minimum number of outbound peers to download compact filters from, out of
+    /// `target_outbound_peers`, eg. at least 2 filter peers out of 8. Outbound peers without
+    /// the filter services are replaced until this is reached.
+    pub min_filter_peers: usize,
+    /// Time without any outbound filter peer after which
+    /// [`connmgr::Event::NoFilterPeers`] is emitted, eg. for wallets to warn their users.
+    pub filter_peer_timeout: time::Duration,
+    /// Time to wait for a new connection to a peer before giving up on it.
+    pub connect_timeout: time::Duration,
+    /// Whether to serve compact block filters to peers. Filters are then downloaded and
+    /// stored as the chain grows, and `COMPACT_FILTERS` is added to the services offered.
+    pub serve_filters: bool,
+    /// Number of blocks below the tip to keep compact filters and filter headers for.
+    /// Older ones are pruned from the stores. If not set, nothing is pruned.
+    pub filter_window: Option<Height>,
+    /// Number of recent filter headers kept in memory. Older ones are read from the
+    /// filter header store when needed.
+    pub filter_cache_capacity: usize,
+    /// Wallet birthday, as a height or a time, eg. when the wallet seed was created.
+    /// Rescans start no earlier than the birthday, and the filters below it are pruned.
+    /// A time is converted to a height using the block header timestamps.
+    pub wallet_birthday: Option<Birthday>,
+    /// Whether to request the filter of the newest block from two peers at once, so that
+    /// new blocks are matched against watched scripts with minimal latency.
+    pub redundant_filters: bool,
+    /// Peer addresses that are trusted, always allowed to connect, and never banned.
+    pub whitelist: Vec<net::IpAddr>,
+    /// Addresses and subnets that are never connected to, and whose inbound connections are
+    /// rejected, unless whitelisted.
+    pub blacklist: Vec<Subnet>,
+    /// SOCKS5 proxy to connect to peers through, eg. a Tor client listening on
+    /// `127.0.0.1:9050`. DNS seeds are not used when a proxy is set, see
+    /// [`Config::dns_seeds`].
+    pub proxy: Option<net::SocketAddr>,
+    /// Whether to connect to each peer through the proxy with its own credentials, so that
+    /// Tor uses a separate circuit for each peer.
+    pub isolate_streams: bool,
+    /// Number of random blocks to download as decoys along with every block requested with
+    /// [`handle::Handle::get_block`], on average, so that peers can't tell which blocks our
+    /// wallet is interested in. Zero disables decoys.
+    pub decoy_blocks: f64,
     /// Protocol hooks.
     pub hooks: protocol::Hooks,
+    /// Event sinks, receiving events before they are published to subscribers.
+    pub sinks: Sinks,
+    /// File of trusted block headers to import into the block header store on startup, eg.
+    /// a header dump from one's own full node, when the client is run with [`Client::run`]
+    /// or [`Client::run_with_stores`].
+    /// See [`nakamoto_chain::block::import`] for the supported formats.
+    pub import_headers: Option<PathBuf>,
+    /// File to record protocol inputs to, so that a run can be replayed deterministically.
+    /// See [`p2p::protocol::replay`].
+    pub record: Option<PathBuf>,
 }
 
 impl Config {
-    /// Add seeds to connect to.
+    /// Get the directory runtime data is stored in. See [`Config::datadir`].
+    pub fn dir(&self) -> PathBuf {
+        match &self.datadir {
+            Some(dir) => dir.clone(),
+            None => self.root.join(".nakamoto").join(self.network.as_str()),
+        }
+    }
+
+    /// DNS seeds used to bootstrap the address book. None are used when connecting through
+    /// a proxy, since they would be resolved by the system resolver, revealing that we're
+    /// looking for Bitcoin peers to anyone watching our DNS queries.
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        if self.proxy.is_some() {
+            &[]
+        } else {
+            self.network.seeds()
+        }
+    }
+
+    /// Add seeds to connect to. Host names are resolved by the system resolver, even when
+    /// connecting through a proxy.
     pub fn seed<T: net::ToSocketAddrs + std::fmt::Debug>(&mut self, seeds: &[T]) -> io::Result<()> {
         let connect = seeds
             .iter()
@@ -93,8 +231,26 @@ impl From<Config> for p2p::protocol::Config {
             network: cfg.network,
             target: cfg.name,
             connect: cfg.connect,
+            connect_only: cfg.connect_only,
             target_outbound_peers: cfg.target_outbound_peers,
             max_inbound_peers: cfg.max_inbound_peers,
+            tx_ttl: LocalDuration::from_millis(cfg.tx_ttl.as_millis()),
+            limits: cfg.limits,
+            feefilter: cfg.feefilter,
+            serve_filters: cfg.serve_filters,
+            filter_window: cfg.filter_window,
+            birthday: cfg.wallet_birthday,
+            redundant_filters: cfg.redundant_filters,
+            required_services: cfg.required_services,
+            filter_services: cfg.filter_services,
+            min_filter_peers: cfg.min_filter_peers,
+            filter_peer_timeout: LocalDuration::from_millis(cfg.filter_peer_timeout.as_millis()),
+            connect_timeout: LocalDuration::from_millis(cfg.connect_timeout.as_millis()),
+            whitelist: protocol::Whitelist::new(cfg.whitelist, vec![]),
+            blacklist: cfg.blacklist,
+            proxy: cfg.proxy,
+            isolate_streams: cfg.isolate_streams,
+            decoy_blocks: cfg.decoy_blocks,
             ..Self::default()
         }
     }
@@ -106,26 +262,101 @@ impl Default for Config {
             listen: vec![([0, 0, 0, 0], 0).into()],
             network: Network::default(),
             connect: Vec::new(),
+            connect_only: false,
             domains: Domain::all(),
             timeout: time::Duration::from_secs(60),
+            tx_ttl: p2p::protocol::invmgr::DEFAULT_TTL.into(),
+            limits: ratemgr::Config::default(),
+            feefilter: Some(p2p::protocol::feemgr::DEFAULT_FEEFILTER),
             root: PathBuf::from(env::var("HOME").unwrap_or_default()),
+            datadir: None,
+            backend: Backend::default(),
             target_outbound_peers: p2p::protocol::connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: p2p::protocol::connmgr::MAX_INBOUND_PEERS,
             services: ServiceFlags::NONE,
+            required_services: ServiceFlags::NETWORK,
+            filter_services: spvmgr::REQUIRED_SERVICES,
+            min_filter_peers: spvmgr::MIN_PEERS,
+            filter_peer_timeout: connmgr::FILTER_PEER_TIMEOUT.into(),
+            connect_timeout: connmgr::CONNECTION_TIMEOUT.into(),
+            serve_filters: false,
+            filter_window: None,
+            filter_cache_capacity: filter::cache::DEFAULT_CAPACITY,
+            wallet_birthday: None,
+            redundant_filters: false,
+            whitelist: Vec::new(),
+            blacklist: Vec::new(),
+            proxy: None,
+            isolate_streams: true,
+            decoy_blocks: 0.,
             name: "self",
             hooks: protocol::Hooks::default(),
+            sinks: Sinks::default(),
+            import_headers: None,
+            record: None,
         }
     }
 }
 
+/// Import the trusted block headers found in a file into the block cache.
+fn import_headers<S: store::Store<Header = BlockHeader>>(
+    cache: &mut BlockCache<S>,
+    path: &Path,
+) -> Result<(), Error> {
+    log::info!("Importing block headers from {:?}..", path);
+
+    let headers = nakamoto_chain::block::import::open(path)?;
+    let height = cache.height();
+    let tip = cache.import_trusted(headers)?;
+
+    log::info!(
+        "Imported {} block header(s) (height = {})",
+        tip - height,
+        tip
+    );
+
+    Ok(())
+}
+
+/// Open a header file store, creating it if it doesn't exist, and healing it if it's
+/// corrupted.
+fn open_file<H: 'static + Copy + Encodable + Decodable>(
+    path: PathBuf,
+    genesis: H,
+    kind: &str,
+) -> Result<store::File<H>, Error> {
+    match store::File::create(&path, genesis) {
+        Ok(store) => {
+            log::info!("Initializing new {} store {:?}", kind, path);
+            Ok(store)
+        }
+        Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+            log::info!("Found existing store {:?}", path);
+            let mut store = store::File::open(&path, genesis)?;
+
+            if store.check().is_err() {
+                log::warn!("Corruption detected in {} store, healing..", kind);
+                // Rollback store to the last valid header, and rebuild the file.
+                store.compact()?;
+            }
+            log::info!("Height of {} store = {}", kind, store.height()?);
+
+            Ok(store)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
 /// The client's event publisher.
 pub struct Publisher {
+    sinks: Sinks,
     publishers: Vec<Box<dyn event::Publisher>>,
 }
 
 impl Publisher {
-    fn new() -> Self {
+    fn new(sinks: Sinks) -> Self {
         Self {
+            sinks,
             publishers: Vec::new(),
         }
     }
@@ -138,6 +369,9 @@ impl Publisher {
 
 impl event::Publisher for Publisher {
     fn publish(&self, e: Event) {
+        // Sinks must see the event before any subscriber does.
+        self.sinks.receive(&e);
+
         for p in self.publishers.iter() {
             p.publish(e.clone());
         }
@@ -150,14 +384,105 @@ pub struct Client<R: Reactor<Publisher>> {
     pub config: Config,
 
     handle: chan::Sender<Command>,
+    epoch: Arc<AtomicU64>,
+    started: bool,
     events: event::Subscriber<Event>,
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
+    deliveries: Deliveries,
+    anchors: peer::Anchors,
+    bans: peer::Bans,
+    rescans: rescan::Rescans,
+    metrics: Metrics,
 
     reactor: R,
 }
 
+/// Builds a [`Client`], starting from the default configuration.
+///
+/// ```
+/// use nakamoto_client::{Client, Network};
+///
+/// type Reactor = nakamoto_net_poll::Reactor<std::net::TcpStream, nakamoto_client::client::Publisher>;
+///
+/// let dir = std::env::temp_dir();
+/// let mainnet = Client::<Reactor>::builder()
+///     .network(Network::Mainnet)
+///     .datadir(dir.join("mainnet"))
+///     .build()
+///     .unwrap();
+/// let testnet = Client::<Reactor>::builder()
+///     .network(Network::Testnet)
+///     .datadir(dir.join("testnet"))
+///     .build()
+///     .unwrap();
+///
+/// assert_ne!(mainnet.config.dir(), testnet.config.dir());
+/// ```
+pub struct ClientBuilder<R> {
+    config: Config,
+    reactor: std::marker::PhantomData<R>,
+}
+
+impl<R: Reactor<Publisher>> ClientBuilder<R> {
+    /// Set the Bitcoin network.
+    pub fn network(mut self, network: Network) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Set the client home path. See [`Config::root`].
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.config.root = root.into();
+        self
+    }
+
+    /// Set the directory runtime data is stored in. See [`Config::datadir`].
+    pub fn datadir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.datadir = Some(dir.into());
+        self
+    }
+
+    /// Set the client name, used for logging.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.config.name = name;
+        self
+    }
+
+    /// Set the listen addresses. See [`Config::listen`].
+    pub fn listen(mut self, addrs: Vec<net::SocketAddr>) -> Self {
+        self.config.listen = addrs;
+        self
+    }
+
+    /// Set the peers to connect to. See [`Config::connect`].
+    pub fn connect(mut self, addrs: Vec<net::SocketAddr>) -> Self {
+        self.config.connect = addrs;
+        self
+    }
+
+    /// Modify any other part of the configuration.
+    pub fn configure(mut self, f: impl FnOnce(&mut Config)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Build the client. Each client has its own reactor and waker, so clients can be
+    /// run side by side in the same process.
+    pub fn build(self) -> Result<Client<R>, Error> {
+        Client::new(self.config)
+    }
+}
+
 impl<R: Reactor<Publisher>> Client<R> {
+    /// Create a client builder, starting from the default configuration.
+    pub fn builder() -> ClientBuilder<R> {
+        ClientBuilder {
+            config: Config::default(),
+            reactor: std::marker::PhantomData,
+        }
+    }
+
     /// Create a new client.
     pub fn new(config: Config) -> Result<Self, Error> {
         let (handle, commands) = chan::unbounded::<Command>();
@@ -169,11 +494,10 @@ impl<R: Reactor<Publisher>> Client<R> {
             None
         });
         let (filters_pub, filters) = event::broadcast(|e| {
-            if let Event::SpvManager(spvmgr::Event::FilterReceived {
+            if let Event::SpvManager(spvmgr::Event::FilterFetched {
                 filter,
                 block_hash,
                 height,
-                ..
             }) = e
             {
                 return Some((filter, block_hash, height));
@@ -181,20 +505,38 @@ impl<R: Reactor<Publisher>> Client<R> {
             None
         });
 
-        let publisher = Publisher::new()
+        let deliveries = Deliveries::new();
+        let anchors = peer::Anchors::new();
+        let bans = peer::Bans::new();
+        let rescans = rescan::Rescans::new();
+        let metrics = Metrics::new();
+
+        let publisher = Publisher::new(config.sinks.clone())
             .register(event_pub)
             .register(blocks_pub)
-            .register(filters_pub);
+            .register(filters_pub)
+            .register(deliveries.clone())
+            .register(anchors.clone())
+            .register(bans.clone())
+            .register(rescans.clone())
+            .register(metrics.clone());
 
         let reactor = R::new(publisher, commands)?;
 
         Ok(Self {
             events,
             handle,
+            epoch: Arc::new(AtomicU64::new(0)),
+            started: false,
             reactor,
             config,
             blocks,
             filters,
+            deliveries,
+            anchors,
+            bans,
+            rescans,
+            metrics,
         })
     }
 
@@ -208,78 +550,110 @@ impl<R: Reactor<Publisher>> Client<R> {
     }
 
     /// Start the client process. This function is meant to be run in its own thread.
-    pub fn run(mut self) -> Result<(), Error> {
-        let home = self.config.root.join(".nakamoto");
-        let dir = home.join(self.config.network.as_str());
-        let listen = self.config.listen.clone();
+    ///
+    /// Once it returns, eg. after a shutdown, the client can be started again. Existing
+    /// handles stay connected to it, but commands they issued against the previous run of the
+    /// node fail with [`handle::Error::Stale`].
+    pub fn run(&mut self) -> Result<(), Error> {
+        let dir = self.config.dir();
 
         fs::create_dir_all(&dir)?;
 
-        let genesis = self.config.network.genesis();
-        let params = self.config.network.params();
+        let network = self.config.network;
+        let genesis = network.genesis();
+        let cfheaders_genesis = filter::cache::StoredHeader::genesis(network);
 
-        log::info!("Initializing client ({:?})..", self.config.network);
-        log::info!(
-            "Genesis block hash is {}",
-            self.config.network.genesis_hash()
-        );
+        match self.config.backend {
+            Backend::File => {
+                let store = open_file(dir.join("headers.db"), genesis, "block header")?;
+                let cfheaders_store =
+                    open_file(dir.join("filters.db"), cfheaders_genesis, "filter header")?;
+                let filters_path = dir.join("cfilters.db");
+                let bodies = filter::store::bodies::File::open(&filters_path)?;
+                log::info!("{} filter(s) found in {:?}", bodies.len(), filters_path);
 
-        let path = dir.join("headers.db");
-        let store = match store::File::create(&path, genesis) {
-            Ok(store) => {
-                log::info!("Initializing new block store {:?}", path);
-                store
+                self.run_with_stores(store, cfheaders_store, bodies)
             }
-            Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
-                log::info!("Found existing store {:?}", path);
-                let store = store::File::open(path, genesis)?;
-
-                if store.check().is_err() {
-                    log::warn!("Corruption detected in header store, healing..");
-                    store.heal()?; // Rollback store to the last valid header.
-                }
-                log::info!("Store height = {}", store.height()?);
-                log::info!("Loading block headers from store..");
-
-                store
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => {
+                let path = dir.join("nakamoto.sqlite");
+                log::info!("Opening database {:?}", path);
+
+                let store = store::Sqlite::open(&path, "headers", genesis)?;
+                store.check()?;
+                log::info!("Block header store height = {}", store.height()?);
+
+                let cfheaders_store =
+                    store::Sqlite::open(&path, "filter_headers", cfheaders_genesis)?;
+                cfheaders_store.check()?;
+                log::info!("Filter header store height = {}", cfheaders_store.height()?);
+                let bodies = filter::store::bodies::Sqlite::open(&path)?;
+                log::info!("{} filter(s) found in {:?}", bodies.len()?, path);
+
+                self.run_with_stores(store, cfheaders_store, bodies)
             }
-            Err(err) => return Err(err.into()),
-        };
+        }
+    }
 
-        let local_time = SystemTime::now().into();
-        let checkpoints = self.config.network.checkpoints().collect::<Vec<_>>();
-        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let cache = BlockCache::from(store, params, &checkpoints)?;
-        let rng = fastrand::Rng::new();
+    /// Start the client process with the given block header, filter header and filter
+    /// stores, instead of the ones [`Client::run`] opens in the data directory. The rest
+    /// of the runtime data, eg. the address book, is still kept in the data directory.
+    /// This function is meant to be run in its own thread.
+    ///
+    /// Unlike [`Client::run_with`], the block and filter header caches are built from the
+    /// stores, with the network's checkpoints, and the filter headers are verified.
+    pub fn run_with_stores<H, FH, B>(
+        &mut self,
+        headers: H,
+        filter_headers: FH,
+        bodies: B,
+    ) -> Result<(), Error>
+    where
+        H: store::Store<Header = BlockHeader>,
+        FH: store::Store<Header = filter::cache::StoredHeader>,
+        B: filter::store::bodies::Bodies + Send + 'static,
+    {
+        let dir = self.config.dir();
 
-        log::info!("Initializing block filters..");
+        fs::create_dir_all(&dir)?;
+        self.deliveries.persist(dir.join(delivery::FILE_NAME))?;
 
-        let cfheaders_genesis = filter::cache::StoredHeader::genesis(self.config.network);
-        let cfheaders_path = dir.join("filters.db");
-        let cfheaders_store = match store::File::create(&cfheaders_path, cfheaders_genesis) {
-            Ok(store) => {
-                log::info!("Initializing new filter header store {:?}", cfheaders_path);
-                store
-            }
-            Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
-                log::info!("Found existing store {:?}", cfheaders_path);
-                let store = store::File::open(cfheaders_path, cfheaders_genesis)?;
+        let network = self.config.network;
+        let params = network.params();
+        let checkpoints = network.checkpoints().collect::<Vec<_>>();
 
-                if store.check().is_err() {
-                    log::warn!("Corruption detected in filter store, healing..");
-                    store.heal()?; // Rollback store to the last valid header.
-                }
-                log::info!("Filters height = {}", store.height()?);
-                log::info!("Loading filter headers from store..");
+        log::info!("Initializing client ({:?})..", network);
+        log::info!("Genesis block hash is {}", network.genesis_hash());
 
-                store
-            }
-            Err(err) => return Err(err.into()),
-        };
+        log::info!("Loading block headers from store..");
+        let mut cache = BlockCache::from(headers, params, &checkpoints)?;
+        if let Some(path) = &self.config.import_headers {
+            import_headers(&mut cache, path)?;
+        }
 
-        let filters = FilterCache::from(cfheaders_store)?;
+        log::info!("Loading filter headers from store..");
+        let filters =
+            FilterCache::with_capacity(filter_headers, self.config.filter_cache_capacity)?
+                .with_bodies(bodies);
         log::info!("Verifying filter headers..");
-        filters.verify(self.config.network)?; // Verify store integrity.
+        filters.verify(network)?; // Verify store integrity.
+
+        self.start(cache, filters, dir)
+    }
+
+    /// Start the client process with the given stores, keeping runtime data in `dir`.
+    fn start<T: BlockTree, F: Filters>(
+        &mut self,
+        cache: T,
+        filters: F,
+        dir: PathBuf,
+    ) -> Result<(), Error> {
+        let config = self.config.clone();
+        let epoch = self.next_epoch();
+        let listen = config.listen.clone();
+        let local_time = SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let (rng, recorder) = self.rng()?;
 
         log::info!("Loading peer addresses..");
 
@@ -303,36 +677,83 @@ impl<R: Reactor<Publisher>> Client<R> {
 
         log::trace!("{:#?}", peers);
 
-        if self.config.connect.is_empty() && peers.is_empty() {
-            log::info!("Address book is empty. Trying DNS seeds..");
-            peers.seed(
-                self.config
-                    .network
-                    .seeds()
-                    .iter()
-                    .map(|s| (*s, self.config.network.port())),
-                Source::Dns,
-            )?;
-            peers.flush()?;
-
-            log::info!("{} seeds added to address book", peers.len());
+        let anchors = self
+            .anchors
+            .load(dir.join(peer::ANCHORS_FILE_NAME))
+            .map_err(Error::PeerStore)?;
+        log::info!("{} anchor peer(s) found..", anchors.len());
+
+        let banned = self
+            .bans
+            .load(dir.join(peer::BANS_FILE_NAME))
+            .map_err(Error::PeerStore)?;
+        log::info!("{} banned peer(s) found..", banned.len());
+
+        let rescan = self.rescans.load(dir.join(rescan::FILE_NAME))?;
+        if let Some(state) = &rescan {
+            log::info!("Found rescan state at height {}..", state.current);
+        }
+
+        if !config.connect_only && config.connect.is_empty() && peers.is_empty() {
+            let seeds = config.dns_seeds();
+
+            if seeds.is_empty() {
+                log::warn!("Address book is empty, and there are no DNS seeds to try..");
+            } else {
+                log::info!("Address book is empty. Trying DNS seeds..");
+                peers.seed(
+                    seeds.iter().map(|s| (*s, config.network.port())),
+                    Source::Dns,
+                )?;
+                peers.flush()?;
+
+                log::info!("{} seeds added to address book", peers.len());
+            }
         }
 
         let cfg = p2p::protocol::Config {
-            network: self.config.network,
-            params: self.config.network.params(),
-            target: self.config.name,
-            connect: self.config.connect,
-            domains: self.config.domains,
-            target_outbound_peers: self.config.target_outbound_peers,
-            max_inbound_peers: self.config.max_inbound_peers,
-            services: self.config.services,
-            hooks: self.config.hooks,
+            network: config.network,
+            params: config.network.params(),
+            target: config.name,
+            connect: config.connect,
+            connect_only: config.connect_only,
+            anchors,
+            rescan,
+            banned,
+            domains: config.domains,
+            target_outbound_peers: config.target_outbound_peers,
+            max_inbound_peers: config.max_inbound_peers,
+            services: config.services,
+            required_services: config.required_services,
+            filter_services: config.filter_services,
+            min_filter_peers: config.min_filter_peers,
+            filter_peer_timeout: LocalDuration::from_millis(config.filter_peer_timeout.as_millis()),
+            connect_timeout: LocalDuration::from_millis(config.connect_timeout.as_millis()),
+            tx_ttl: LocalDuration::from_millis(config.tx_ttl.as_millis()),
+            limits: config.limits,
+            feefilter: config.feefilter,
+            serve_filters: config.serve_filters,
+            filter_window: config.filter_window,
+            birthday: config.wallet_birthday,
+            redundant_filters: config.redundant_filters,
+            whitelist: protocol::Whitelist::new(config.whitelist, vec![]),
+            blacklist: config.blacklist,
+            proxy: config.proxy,
+            isolate_streams: config.isolate_streams,
+            decoy_blocks: config.decoy_blocks,
+            hooks: self.metrics.hooks(config.hooks),
+            epoch,
+            storage: Some(dir),
             ..p2p::protocol::Config::default()
         };
 
         self.reactor.run(&listen, move |upstream| {
-            Protocol::new(cache, filters, peers, clock, rng, cfg, upstream)
+            let protocol = Protocol::new(cache, filters, peers, clock, rng, cfg, upstream);
+
+            match recorder {
+                Some(recorder) => protocol.with_recorder(recorder),
+                None => protocol,
+            }
         })?;
 
         Ok(())
@@ -341,20 +762,40 @@ impl<R: Reactor<Publisher>> Client<R> {
     /// Start the client process, supplying the block cache. This function is meant to be run in
     /// its own thread.
     pub fn run_with<T: BlockTree, F: Filters, P: peer::Store>(
-        mut self,
+        &mut self,
         cache: T,
         filters: F,
         peers: P,
     ) -> Result<(), Error> {
+        let config = self.config.clone();
+        let epoch = self.next_epoch();
+        let (rng, recorder) = self.rng()?;
         let cfg = p2p::protocol::Config {
-            services: self.config.services,
-            hooks: self.config.hooks,
-            domains: self.config.domains,
-            ..p2p::protocol::Config::from(
-                self.config.name,
-                self.config.network,
-                self.config.connect,
-            )
+            services: config.services,
+            required_services: config.required_services,
+            filter_services: config.filter_services,
+            min_filter_peers: config.min_filter_peers,
+            filter_peer_timeout: LocalDuration::from_millis(config.filter_peer_timeout.as_millis()),
+            connect_timeout: LocalDuration::from_millis(config.connect_timeout.as_millis()),
+            tx_ttl: LocalDuration::from_millis(config.tx_ttl.as_millis()),
+            limits: config.limits,
+            feefilter: config.feefilter,
+            serve_filters: config.serve_filters,
+            filter_window: config.filter_window,
+            birthday: config.wallet_birthday,
+            redundant_filters: config.redundant_filters,
+            whitelist: protocol::Whitelist::new(config.whitelist, vec![]),
+            blacklist: config.blacklist,
+            proxy: config.proxy,
+            isolate_streams: config.isolate_streams,
+            decoy_blocks: config.decoy_blocks,
+            hooks: self.metrics.hooks(config.hooks),
+            domains: config.domains,
+            target_outbound_peers: config.target_outbound_peers,
+            max_inbound_peers: config.max_inbound_peers,
+            epoch,
+            connect_only: config.connect_only,
+            ..p2p::protocol::Config::from(config.name, config.network, config.connect)
         };
 
         log::info!("Initializing client ({:?})..", cfg.network);
@@ -363,26 +804,59 @@ impl<R: Reactor<Publisher>> Client<R> {
 
         let local_time = SystemTime::now().into();
         let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let rng = fastrand::Rng::new();
 
         log::info!("{} peer(s) found..", peers.len());
 
-        self.reactor.run(&self.config.listen, |upstream| {
-            Protocol::new(cache, filters, peers, clock, rng, cfg, upstream)
+        self.reactor.run(&config.listen, |upstream| {
+            let protocol = Protocol::new(cache, filters, peers, clock, rng, cfg, upstream);
+
+            match recorder {
+                Some(recorder) => protocol.with_recorder(recorder),
+                None => protocol,
+            }
         })?;
 
         Ok(())
     }
 
+    /// Get the epoch to start the node with. Every start after the first bumps the epoch,
+    /// so that commands issued against the previous incarnation of the node are rejected.
+    fn next_epoch(&mut self) -> Epoch {
+        if mem::replace(&mut self.started, true) {
+            self.epoch.fetch_add(1, atomic::Ordering::SeqCst) + 1
+        } else {
+            self.epoch.load(atomic::Ordering::SeqCst)
+        }
+    }
+
+    /// Create the protocol's random number generator, along with a recorder for its inputs,
+    /// if recording.
+    fn rng(&self) -> io::Result<(fastrand::Rng, Option<Recorder>)> {
+        let seed = fastrand::u64(..);
+        let recorder = match &self.config.record {
+            Some(path) => {
+                log::info!("Recording protocol inputs to {:?}..", path);
+                Some(Recorder::create(path, seed)?)
+            }
+            None => None,
+        };
+        Ok((fastrand::Rng::with_seed(seed), recorder))
+    }
+
     /// Create a new handle to communicate with the client.
     pub fn handle(&self) -> Handle<R> {
         Handle {
             events: self.events.clone(),
             waker: self.reactor.waker(),
             commands: self.handle.clone(),
+            epoch: self.epoch.clone(),
             timeout: self.config.timeout,
+            network: self.config.network,
+            seeds: self.config.dns_seeds(),
             blocks: self.blocks.clone(),
             filters: self.filters.clone(),
+            deliveries: self.deliveries.clone(),
+            metrics: self.metrics.clone(),
         }
     }
 }
@@ -390,11 +864,16 @@ impl<R: Reactor<Publisher>> Client<R> {
 /// An instance of [`handle::Handle`] for [`Client`].
 pub struct Handle<R: Reactor<Publisher>> {
     commands: chan::Sender<Command>,
+    epoch: Arc<AtomicU64>,
     events: event::Subscriber<Event>,
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
+    deliveries: Deliveries,
+    metrics: Metrics,
     waker: R::Waker,
     timeout: time::Duration,
+    network: Network,
+    seeds: &'static [&'static str],
 }
 
 impl<R: Reactor<Publisher>> Clone for Handle<R>
@@ -405,9 +884,14 @@ where
         Self {
             blocks: self.blocks.clone(),
             commands: self.commands.clone(),
+            epoch: self.epoch.clone(),
             events: self.events.clone(),
             filters: self.filters.clone(),
+            deliveries: self.deliveries.clone(),
+            metrics: self.metrics.clone(),
             timeout: self.timeout,
+            network: self.network,
+            seeds: self.seeds,
             waker: self.waker.clone(),
         }
     }
@@ -422,15 +906,19 @@ where
         self.timeout = timeout;
     }
 
+    /// Get the current client metrics, eg. the number of connected peers and the traffic
+    /// exchanged with them. See [`metrics::Snapshot::to_prometheus`] to export them.
+    pub fn metrics(&self) -> metrics::Snapshot {
+        self.metrics.snapshot()
+    }
+
     /// Get connected peers.
     pub fn get_peers(
         &self,
         services: impl Into<ServiceFlags>,
     ) -> Result<HashSet<SocketAddr>, handle::Error> {
         let (sender, recvr) = chan::bounded(1);
-        self._command(Command::GetPeers(services.into(), sender))?;
-
-        Ok(recvr.recv()?)
+        self.request(Command::GetPeers(services.into(), sender), &recvr)
     }
 
     /// Get block by height.
@@ -439,17 +927,33 @@ where
         height: Height,
     ) -> Result<Option<BlockHeader>, handle::Error> {
         let (sender, recvr) = chan::bounded(1);
-        self._command(Command::GetBlockByHeight(height, sender))?;
-
-        Ok(recvr.recv()?)
+        self.request(Command::GetBlockByHeight(height, sender), &recvr)
     }
 
-    /// Send a command to the command channel, and wake up the event loop.
+    /// Send a command to the command channel, tagged with the current node epoch, wake up
+    /// the event loop, and wait for the node to pick it up.
+    ///
+    /// Commands are dropped by the node if it was restarted since they were sent. When that
+    /// happens, a [`handle::Error::Stale`] error is returned.
     fn _command(&self, cmd: Command) -> Result<(), handle::Error> {
-        self.commands.send(cmd)?;
+        let epoch = self.epoch.load(atomic::Ordering::SeqCst);
+        let (ack, acked) = chan::bounded(1);
+
+        self.commands
+            .send(Command::Tagged(epoch, Box::new(cmd), ack))?;
         R::wake(&self.waker)?;
 
-        Ok(())
+        match acked.recv_timeout(self.timeout)? {
+            current if current == epoch => Ok(()),
+            current => Err(handle::Error::Stale { epoch, current }),
+        }
+    }
+
+    /// Send a command and wait for its reply.
+    fn request<T>(&self, cmd: Command, reply: &chan::Receiver<T>) -> Result<T, handle::Error> {
+        self._command(cmd)?;
+
+        Ok(reply.recv()?)
     }
 }
 
@@ -459,33 +963,221 @@ where
 {
     fn get_tip(&self) -> Result<(Height, BlockHeader), handle::Error> {
         let (transmit, receive) = chan::bounded::<(Height, BlockHeader)>(1);
-        self.command(Command::GetTip(transmit))?;
+        self.request(Command::GetTip(transmit), &receive)
+    }
 
-        Ok(receive.recv()?)
+    fn get_block_header_by_height(
+        &self,
+        height: Height,
+    ) -> Result<Option<BlockHeader>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetBlockByHeight(height, transmit), &receive)
+    }
+
+    fn get_block_header(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<(Height, BlockHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetBlockHeader(*hash, transmit), &receive)
+    }
+
+    fn get_chain_info(&self) -> Result<ChainInfo, handle::Error> {
+        let (transmit, receive) = chan::bounded::<ChainInfo>(1);
+        self.request(Command::GetChainInfo(transmit), &receive)
+    }
+
+    fn get_chain_work(&self) -> Result<Work, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Work>(1);
+        self.request(Command::GetChainWork(transmit), &receive)
+    }
+
+    fn get_median_time_past(&self, height: Height) -> Result<Option<BlockTime>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetMedianTimePast(height, transmit), &receive)
+    }
+
+    fn get_height_by_time(&self, time: BlockTime) -> Result<Option<Height>, handle::Error> {
+        self.with_chain_snapshot(move |snapshot| snapshot.get_height_by_time(time))
+    }
+
+    fn estimate_feerate(&self, target_blocks: Height) -> Result<Option<FeeRate>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::EstimateFeeRate(target_blocks, transmit), &receive)
+    }
+
+    fn effective_config(&self) -> Result<EffectiveConfig, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetConfig(transmit), &receive)
+    }
+
+    fn peer_info(&self) -> Result<Vec<PeerInfo>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::PeerInfo(transmit), &receive)
+    }
+
+    fn with_chain_snapshot<F, T>(&self, f: F) -> Result<T, handle::Error>
+    where
+        F: FnOnce(&dyn ChainSnapshot) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (transmit, receive) = chan::bounded::<T>(1);
+        let f = Mutex::new(Some(f));
+
+        self.request(
+            Command::ReadSnapshot(SnapshotReader::new(move |snapshot| {
+                if let Some(f) = f.lock().unwrap().take() {
+                    transmit.send(f(snapshot)).ok();
+                }
+            })),
+            &receive,
+        )
     }
 
     fn get_block(&self, hash: &BlockHash) -> Result<net::SocketAddr, handle::Error> {
         let (transmit, receive) = chan::bounded::<Result<net::SocketAddr, GetBlockError>>(1);
-        self.command(Command::GetBlock(*hash, transmit))?;
-
-        receive
-            .recv()?
+        self.request(Command::GetBlock(*hash, transmit), &receive)?
             .map_err(|e| handle::Error::Command(Box::new(e)))
     }
 
-    fn get_filters(&self, range: Range<Height>) -> Result<(), handle::Error> {
+    fn get_filters(&self, ranges: Vec<Range<Height>>) -> Result<(), handle::Error> {
         assert!(
-            !range.is_empty(),
-            "client::Handle::get_filters: range cannot be empty"
+            ranges.iter().all(|r| !r.is_empty()),
+            "client::Handle::get_filters: ranges cannot be empty"
         );
         let (transmit, receive) = chan::bounded(1);
-        self.command(Command::GetFilters(range, transmit))?;
+        self.request(Command::GetFilters(ranges, transmit), &receive)?
+            .map_err(|e| handle::Error::Command(Box::new(e)))
+    }
 
-        receive
-            .recv()?
+    fn rescan_status(&self) -> Result<Option<RescanStatus>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetRescanStatus(transmit), &receive)
+    }
+
+    fn rescan(
+        &self,
+        range: impl RangeBounds<Height>,
+        watch: Vec<Script>,
+    ) -> Result<RescanHandle<Self>, handle::Error> {
+        let events = RescanHandle::events(self);
+        let (transmit, receive) = chan::bounded(1);
+
+        self.request(
+            Command::Rescan {
+                from: cloned(range.start_bound()),
+                to: cloned(range.end_bound()),
+                watch,
+                reply: transmit,
+            },
+            &receive,
+        )?
+        .map_err(|e| handle::Error::Command(Box::new(e)))?;
+
+        Ok(RescanHandle::new(self.clone(), events))
+    }
+
+    fn fetch_block(&self, hash: &BlockHash) -> Result<(Block, Height), handle::Error> {
+        // Subscribe before requesting, so that the block can't be missed.
+        let blocks = self.blocks.subscribe();
+        let deadline = time::Instant::now() + self.timeout;
+
+        loop {
+            // A retry goes to another peer than the one the request timed out with, if
+            // there is one.
+            let result = match self.get_block(hash) {
+                Err(err @ handle::Error::Disconnected) | Err(err @ handle::Error::Stale { .. }) => {
+                    return Err(err);
+                }
+                result => result,
+            };
+            let retry = (time::Instant::now() + BLOCK_RETRY_TIMEOUT).min(deadline);
+
+            loop {
+                match blocks.recv_timeout(retry.saturating_duration_since(time::Instant::now())) {
+                    Ok((block, height)) if block.block_hash() == *hash => {
+                        return Ok((block, height));
+                    }
+                    Ok(_) => {}
+                    Err(chan::RecvTimeoutError::Timeout) => break,
+                    Err(chan::RecvTimeoutError::Disconnected) => {
+                        return Err(handle::Error::Disconnected);
+                    }
+                }
+            }
+            if time::Instant::now() >= deadline {
+                // Report why the block couldn't be requested, if it couldn't.
+                return Err(result.err().unwrap_or(handle::Error::Timeout));
+            }
+            log::debug!("Block {} not received, requesting it again..", hash);
+        }
+    }
+
+    fn get_blocks(
+        &self,
+        blocks: impl Into<Blocks>,
+    ) -> Result<chan::Receiver<(Block, Height)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetBlocks(blocks.into(), transmit), &receive)?
+            .map_err(|e| handle::Error::Command(Box::new(e)))
+    }
+
+    fn prove_inclusion(
+        &self,
+        block: &Block,
+        txid: &Txid,
+    ) -> Result<Option<InclusionProof>, handle::Error> {
+        let proof = match InclusionProof::new(block, txid) {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+        Ok(self.verify_inclusion(&proof)?.map(|_| proof))
+    }
+
+    fn verify_inclusion(&self, proof: &InclusionProof) -> Result<Option<Height>, handle::Error> {
+        let (height, header) = match self.get_block_header(&proof.block_hash())? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        proof
+            .verify(&header)
+            .map_err(|e| handle::Error::Command(Box::new(e)))?;
+
+        Ok(Some(height))
+    }
+
+    fn verify_chain(&self) -> Result<Height, handle::Error> {
+        let headers =
+            self.with_chain_snapshot(|snapshot| snapshot.get_blocks(0..snapshot.height() + 1))?;
+        let checkpoints = self.network.checkpoints().collect::<Vec<_>>();
+        let clock = AdjustedTime::<net::SocketAddr>::new(SystemTime::now().into());
+
+        nakamoto_chain::block::cache::verify(headers, self.network.params(), &checkpoints, &clock)
             .map_err(|e| handle::Error::Command(Box::new(e)))
     }
 
+    fn watch_xpub(
+        &self,
+        xpub: ExtendedPubKey,
+        scheme: DerivationScheme,
+        gap_limit: u32,
+    ) -> Result<[WatchlistId; 2], handle::Error> {
+        let chains =
+            spvmgr::xpub::chains(&xpub).map_err(|e| handle::Error::Command(Box::new(e)))?;
+
+        for xpub in chains.iter() {
+            self.command(Command::WatchXpub {
+                xpub: *xpub,
+                scheme,
+                gap_limit,
+            })?;
+        }
+        Ok([
+            spvmgr::xpub::watchlist(&chains[0]),
+            spvmgr::xpub::watchlist(&chains[1]),
+        ])
+    }
+
     fn blocks(&self) -> chan::Receiver<(Block, Height)> {
         self.blocks.subscribe()
     }
@@ -494,6 +1186,18 @@ where
         self.filters.subscribe()
     }
 
+    fn subscribe_blocks(&self, id: &str, redeliver: bool) -> chan::Receiver<(Block, Height)> {
+        self.deliveries.subscribe_blocks(id, redeliver)
+    }
+
+    fn subscribe_filters(
+        &self,
+        id: &str,
+        redeliver: bool,
+    ) -> chan::Receiver<(BlockFilter, BlockHash, Height)> {
+        self.deliveries.subscribe_filters(id, redeliver)
+    }
+
     fn command(&self, cmd: Command) -> Result<(), handle::Error> {
         self._command(cmd)
     }
@@ -504,16 +1208,12 @@ where
         predicate: fn(Peer) -> bool,
     ) -> Result<Vec<net::SocketAddr>, handle::Error> {
         let (transmit, receive) = chan::bounded(1);
-        self.command(Command::Broadcast(msg, predicate, transmit))?;
-
-        Ok(receive.recv()?)
+        self.request(Command::Broadcast(msg, predicate, transmit), &receive)
     }
 
     fn query(&self, msg: NetworkMessage) -> Result<Option<net::SocketAddr>, handle::Error> {
         let (transmit, receive) = chan::bounded::<Option<net::SocketAddr>>(1);
-        self.command(Command::Query(msg, transmit))?;
-
-        Ok(receive.recv()?)
+        self.request(Command::Query(msg, transmit), &receive)
     }
 
     fn connect(&self, addr: net::SocketAddr) -> Result<Link, handle::Error> {
@@ -555,14 +1255,37 @@ where
         Ok(())
     }
 
+    fn ban(&self, addr: net::IpAddr, duration: time::Duration) -> Result<(), handle::Error> {
+        let duration = LocalDuration::from_millis(duration.as_millis());
+
+        self.command(Command::Ban(addr, duration))
+    }
+
+    fn unban(&self, addr: net::IpAddr) -> Result<(), handle::Error> {
+        self.command(Command::Unban(addr))
+    }
+
+    fn list_banned(&self) -> Result<Vec<(net::IpAddr, protocol::Ban)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetBanned(transmit), &receive)
+    }
+
+    fn list_addresses(&self) -> Result<Vec<peer::KnownAddress>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetAddresses(transmit), &receive)
+    }
+
+    fn mark_address(&self, addr: net::IpAddr, good: bool) -> Result<bool, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::MarkAddress(addr, good, transmit), &receive)
+    }
+
     fn import_headers(
         &self,
         headers: Vec<BlockHeader>,
     ) -> Result<Result<ImportResult, tree::Error>, handle::Error> {
         let (transmit, receive) = chan::bounded::<Result<ImportResult, tree::Error>>(1);
-        self.command(Command::ImportHeaders(headers, transmit))?;
-
-        Ok(receive.recv()?)
+        self.request(Command::ImportHeaders(headers, transmit), &receive)
     }
 
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), handle::Error> {
@@ -571,12 +1294,73 @@ where
         Ok(())
     }
 
-    fn submit_transaction(&self, tx: Transaction) -> Result<(), handle::Error> {
-        self.command(Command::SubmitTransaction(tx))?;
+    fn submit_transaction(&self, tx: Transaction, private: bool) -> Result<(), handle::Error> {
+        self.command(Command::SubmitTransaction(tx, None, private))?;
+
+        Ok(())
+    }
+
+    fn submit_transaction_with_fee(&self, tx: Transaction, fee: u64) -> Result<(), handle::Error> {
+        self.command(Command::SubmitTransaction(tx, Some(fee), false))?;
 
         Ok(())
     }
 
+    fn get_tx_status(&self, txid: &Txid) -> Result<TxStatus, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.request(Command::GetTxStatus(*txid, transmit), &receive)
+    }
+
+    fn find_peers(&self) -> Result<usize, handle::Error> {
+        let events = self.events();
+        let seeds = self
+            .seeds
+            .iter()
+            .filter_map(
+                |seed| match (*seed, self.network.port()).to_socket_addrs() {
+                    Ok(addrs) => Some(addrs),
+                    Err(err) => {
+                        log::warn!("Failed to resolve DNS seed {}: {}", seed, err);
+                        None
+                    }
+                },
+            )
+            .flatten()
+            .collect();
+
+        let (transmit, receive) = chan::bounded(1);
+        let (mut discovered, peers) =
+            self.request(Command::QueryAddresses(seeds, transmit), &receive)?;
+        let mut pending = peers.into_iter().collect::<HashSet<_>>();
+
+        if pending.is_empty() {
+            return Ok(discovered);
+        }
+        // Addresses received from a peer are discovered before its `AddressesReceived`
+        // event is emitted.
+        let result = event::wait(
+            &events,
+            |e| match e {
+                Event::AddrManager(addrmgr::Event::AddressDiscovered(_, Source::Peer(_))) => {
+                    discovered += 1;
+                    None
+                }
+                Event::AddrManager(addrmgr::Event::AddressesReceived {
+                    source: Source::Peer(peer),
+                    ..
+                }) if pending.remove(&peer) && pending.is_empty() => Some(()),
+                _ => None,
+            },
+            self.timeout,
+        );
+
+        match result {
+            // Peers that didn't respond in time are not waited on.
+            Ok(()) | Err(event::RecvTimeoutError::Timeout) => Ok(discovered),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     fn wait<F, T>(&self, f: F) -> Result<T, handle::Error>
     where
         F: FnMut(Event) -> Option<T>,
@@ -661,9 +1445,42 @@ where
         self.events.subscribe()
     }
 
+    fn events_filtered(&self, filter: impl event::EventFilter<Event>) -> chan::Receiver<Event> {
+        self.events.subscribe_filtered(filter)
+    }
+
+    fn events_bounded(&self, backpressure: event::Backpressure) -> event::BoundedReceiver<Event> {
+        self.events
+            .subscribe_bounded(backpressure, Event::supersedes)
+    }
+
+    fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    fn sequenced_events(
+        &self,
+        backpressure: event::Backpressure,
+    ) -> event::BoundedReceiver<event::Sequenced<Event>> {
+        self.events
+            .subscribe_sequenced(backpressure, Event::supersedes)
+    }
+
     fn shutdown(self) -> Result<(), handle::Error> {
+        let events = self.events_filtered(event::EventMask::NODE_STOPPED);
+
         self.command(Command::Shutdown)?;
+        event::wait(&events, |_| Some(()), self.timeout)?;
 
         Ok(())
     }
 }
+
+/// Clone the height of a range bound.
+fn cloned(bound: Bound<&Height>) -> Bound<Height> {
+    match bound {
+        Bound::Included(h) => Bound::Included(*h),
+        Bound::Excluded(h) => Bound::Excluded(*h),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
@@ -0,0 +1,617 @@
+//! Deduplicated delivery of blocks and filters.
+//!
+//! Applications processing blocks or filters as they are fetched usually keep track of how
+//! far they got, but the client may fetch the same items again after a restart, eg. when a
+//! rescan is resumed from an earlier height following a crash. To spare applications from
+//! processing thousands of items twice, subscriptions can be given an identity, under which
+//! the heights of the items delivered are recorded, and persisted by the client. Items at
+//! heights that were already delivered under the same identity are skipped, unless
+//! re-delivery is explicitly requested.
+//!
+//! An item counts as delivered once it is sent on the subscription channel. When blocks are
+//! disconnected from the active chain, the heights above the fork are forgotten, so that the
+//! items of the new chain are delivered.
+//!
+//! Delivered heights are written to disk at most once every [`FLUSH_INTERVAL`], and when the
+//! node stops. Items delivered shortly before a crash may thus be delivered again.
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use crossbeam_channel as chan;
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::{Block, BlockHash, Height};
+use nakamoto_p2p::event::{self, Event};
+use nakamoto_p2p::protocol::{spvmgr, syncmgr};
+
+/// Name of the file delivered heights are persisted in.
+pub const FILE_NAME: &str = "deliveries.json";
+
+/// Minimum time between two writes of the delivered heights to disk.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A compact set of heights, stored as disjoint ranges.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Heights {
+    /// Inclusive ranges, keyed by their start.
+    ranges: BTreeMap<Height, Height>,
+}
+
+impl Heights {
+    /// Check whether the set contains the given height.
+    pub fn contains(&self, height: Height) -> bool {
+        matches!(self.ranges.range(..=height).next_back(), Some((_, end)) if *end >= height)
+    }
+
+    /// Add a height to the set, merging adjacent ranges. Returns `false` if the height was
+    /// already in the set.
+    pub fn insert(&mut self, height: Height) -> bool {
+        if self.contains(height) {
+            return false;
+        }
+        let start = match self.ranges.range(..height).next_back() {
+            Some((start, end)) if end + 1 == height => *start,
+            _ => height,
+        };
+        let end = self.ranges.remove(&(height + 1)).unwrap_or(height);
+
+        self.ranges.insert(start, end);
+
+        true
+    }
+
+    /// Remove all heights above the given height. Returns `false` if there were none.
+    pub fn truncate(&mut self, height: Height) -> bool {
+        let removed = self.ranges.split_off(&(height + 1));
+        let mut changed = !removed.is_empty();
+
+        if let Some((_, end)) = self.ranges.range_mut(..=height).next_back() {
+            if *end > height {
+                *end = height;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Iterate over the ranges of the set, in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = RangeInclusive<Height>> + '_ {
+        self.ranges.iter().map(|(start, end)| *start..=*end)
+    }
+
+    /// Convert to a JSON value.
+    fn to_json(&self) -> Value {
+        Value::Array(
+            self.ranges
+                .iter()
+                .map(|(start, end)| {
+                    Value::Array(
+                        [*start, *end]
+                            .iter()
+                            .map(|h| Value::Number(Number::U64(*h)))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Convert from a JSON value.
+    fn from_json(value: Value) -> Option<Self> {
+        let mut ranges = BTreeMap::new();
+
+        match value {
+            Value::Array(ary) => {
+                for range in ary {
+                    match range {
+                        Value::Array(bounds) => match bounds.as_slice() {
+                            [Value::Number(Number::U64(start)), Value::Number(Number::U64(end))]
+                                if start <= end =>
+                            {
+                                ranges.insert(*start, *end);
+                            }
+                            _ => return None,
+                        },
+                        _ => return None,
+                    }
+                }
+            }
+            _ => return None,
+        }
+        Some(Self { ranges })
+    }
+}
+
+/// The kind of items a subscription receives.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Kind {
+    Blocks,
+    Filters,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Blocks => "blocks",
+            Self::Filters => "filters",
+        }
+    }
+}
+
+/// The channel of a subscription.
+#[derive(Debug)]
+enum Channel {
+    Blocks(chan::Sender<(Block, Height)>),
+    Filters(chan::Sender<(BlockFilter, BlockHash, Height)>),
+}
+
+/// A subscription with an identity.
+#[derive(Debug)]
+struct Subscription {
+    id: String,
+    redeliver: bool,
+    channel: Channel,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Delivered heights, by kind and subscription identity.
+    delivered: HashMap<(Kind, String), Heights>,
+    subscriptions: Vec<Subscription>,
+    /// File delivered heights are persisted in, if any.
+    file: Option<fs::File>,
+    /// Whether there are changes that weren't persisted yet.
+    dirty: bool,
+    /// Time of the last write to the file.
+    flushed_at: Option<Instant>,
+}
+
+impl Inner {
+    /// Deliver an item at the given height to the subscriptions of the given kind.
+    fn deliver<F>(&mut self, kind: Kind, height: Height, mut send: F)
+    where
+        F: FnMut(&Channel) -> bool,
+    {
+        let delivered = &mut self.delivered;
+        let mut changed = false;
+
+        self.subscriptions.retain(|sub| {
+            let matches = matches!(
+                (&sub.channel, kind),
+                (Channel::Blocks(_), Kind::Blocks) | (Channel::Filters(_), Kind::Filters)
+            );
+            if !matches {
+                return true;
+            }
+            let heights = delivered.entry((kind, sub.id.clone())).or_default();
+
+            if heights.contains(height) && !sub.redeliver {
+                return true;
+            }
+            // Subscriptions whose receiver was dropped are removed.
+            if !send(&sub.channel) {
+                return false;
+            }
+            changed |= heights.insert(height);
+
+            true
+        });
+
+        if changed {
+            self.changed();
+        }
+    }
+
+    /// Forget the heights above the given height, for all kinds and identities.
+    fn rollback(&mut self, height: Height) {
+        let mut changed = false;
+
+        for heights in self.delivered.values_mut() {
+            changed |= heights.truncate(height);
+        }
+        if changed {
+            self.changed();
+        }
+    }
+
+    /// Record a change, and persist it unless the file was written too recently.
+    fn changed(&mut self) {
+        self.dirty = true;
+
+        if matches!(self.flushed_at, Some(t) if t.elapsed() < FLUSH_INTERVAL) {
+            return;
+        }
+        self.persist();
+    }
+
+    /// Persist pending changes, if any.
+    fn persist(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Err(err) = self.flush() {
+            log::error!("Error persisting delivered heights: {}", err);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        use io::{Seek, Write};
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut obj = Object::new();
+
+        for kind in [Kind::Blocks, Kind::Filters] {
+            let subs: Object = self
+                .delivered
+                .iter()
+                .filter(|((k, _), _)| *k == kind)
+                .map(|((_, id), heights)| (id.clone(), heights.to_json()))
+                .collect();
+
+            obj.insert(kind.as_str().to_owned(), Value::Object(subs));
+        }
+        let s = microserde::json::to_string(&Value::Object(obj));
+
+        file.set_len(0)?;
+        file.seek(io::SeekFrom::Start(0))?;
+        file.write_all(s.as_bytes())?;
+        file.write_all(b"\n")?;
+        file.sync_data()?;
+
+        self.dirty = false;
+        self.flushed_at = Some(Instant::now());
+
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.persist();
+    }
+}
+
+/// Keeps track of the items delivered to subscriptions with an identity.
+#[derive(Debug, Clone, Default)]
+pub struct Deliveries {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Deliveries {
+    /// Create a new, in-memory, delivery tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Persist delivered heights in the given file, creating it if necessary. Heights
+    /// already in the file are loaded, and merged with the heights delivered so far.
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        use io::Read;
+
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut s = String::new();
+
+        file.read_to_string(&mut s)?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if !s.trim().is_empty() {
+            let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+            let obj = match microserde::json::from_str(&s).map_err(|_| invalid())? {
+                Value::Object(obj) => obj,
+                _ => return Err(invalid()),
+            };
+            for kind in [Kind::Blocks, Kind::Filters] {
+                let subs = match obj.get(kind.as_str()) {
+                    Some(Value::Object(subs)) => subs.clone(),
+                    Some(_) => return Err(invalid()),
+                    None => continue,
+                };
+                for (id, heights) in subs {
+                    let loaded = Heights::from_json(heights).ok_or_else(invalid)?;
+                    let heights = inner.delivered.entry((kind, id)).or_default();
+
+                    for range in loaded.ranges() {
+                        for height in range {
+                            heights.insert(height);
+                        }
+                    }
+                }
+            }
+        }
+        inner.file = Some(file);
+        inner.flush()
+    }
+
+    /// Subscribe to blocks under the given identity. Blocks at heights already delivered
+    /// under this identity are skipped, unless `redeliver` is set.
+    pub fn subscribe_blocks(&self, id: &str, redeliver: bool) -> chan::Receiver<(Block, Height)> {
+        let (sender, receiver) = chan::unbounded();
+
+        self.subscribe(id, redeliver, Channel::Blocks(sender));
+
+        receiver
+    }
+
+    /// Subscribe to filters under the given identity. Filters at heights already delivered
+    /// under this identity are skipped, unless `redeliver` is set.
+    pub fn subscribe_filters(
+        &self,
+        id: &str,
+        redeliver: bool,
+    ) -> chan::Receiver<(BlockFilter, BlockHash, Height)> {
+        let (sender, receiver) = chan::unbounded();
+
+        self.subscribe(id, redeliver, Channel::Filters(sender));
+
+        receiver
+    }
+
+    /// Get the heights of the blocks delivered under the given identity.
+    pub fn delivered_blocks(&self, id: &str) -> Heights {
+        self.delivered(Kind::Blocks, id)
+    }
+
+    /// Get the heights of the filters delivered under the given identity.
+    pub fn delivered_filters(&self, id: &str) -> Heights {
+        self.delivered(Kind::Filters, id)
+    }
+
+    fn delivered(&self, kind: Kind, id: &str) -> Heights {
+        self.inner
+            .lock()
+            .unwrap()
+            .delivered
+            .get(&(kind, id.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn subscribe(&self, id: &str, redeliver: bool, channel: Channel) {
+        self.inner.lock().unwrap().subscriptions.push(Subscription {
+            id: id.to_owned(),
+            redeliver,
+            channel,
+        });
+    }
+}
+
+impl event::Publisher for Deliveries {
+    fn publish(&self, e: Event) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match e {
+            Event::SyncManager(syncmgr::Event::BlockReceived(_, block, height)) => {
+                inner.deliver(Kind::Blocks, height, |channel| match channel {
+                    Channel::Blocks(c) => c.send((block.clone(), height)).is_ok(),
+                    Channel::Filters(_) => true,
+                });
+            }
+            Event::SpvManager(spvmgr::Event::FilterFetched {
+                filter,
+                block_hash,
+                height,
+            }) => {
+                inner.deliver(Kind::Filters, height, |channel| match channel {
+                    Channel::Filters(c) => c.send((filter.clone(), block_hash, height)).is_ok(),
+                    Channel::Blocks(_) => true,
+                });
+            }
+            Event::SyncManager(syncmgr::Event::BlockDisconnected { height, .. }) => {
+                inner.rollback(height.saturating_sub(1));
+            }
+            Event::SyncManager(syncmgr::Event::ChainReorged { disconnected, .. }) => {
+                if let Some(height) = disconnected.iter().map(|(h, _)| *h).min() {
+                    inner.rollback(height.saturating_sub(1));
+                }
+            }
+            Event::SpvManager(spvmgr::Event::RollbackDetected(height)) => {
+                inner.rollback(height);
+            }
+            Event::NodeStopped => {
+                inner.persist();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_test::block::gen;
+
+    fn block_received(block: &Block, height: Height) -> Event {
+        Event::SyncManager(syncmgr::Event::BlockReceived(
+            ([88, 88, 88, 88], 8333).into(),
+            block.clone(),
+            height,
+        ))
+    }
+
+    #[test]
+    fn test_heights() {
+        let mut heights = Heights::default();
+
+        for h in [5, 7, 1, 6, 2, 9] {
+            assert!(heights.insert(h));
+        }
+        assert!(!heights.insert(6));
+        assert_eq!(
+            heights.ranges().collect::<Vec<_>>(),
+            vec![1..=2, 5..=7, 9..=9]
+        );
+
+        assert!(heights.contains(6));
+        assert!(!heights.contains(3));
+        assert!(!heights.contains(8));
+
+        heights.insert(8);
+        assert_eq!(heights.ranges().collect::<Vec<_>>(), vec![1..=2, 5..=9]);
+        assert_eq!(Heights::from_json(heights.to_json()), Some(heights.clone()));
+
+        assert!(heights.truncate(6));
+        assert_eq!(heights.ranges().collect::<Vec<_>>(), vec![1..=2, 5..=6]);
+        assert!(!heights.truncate(6));
+        assert!(heights.truncate(3));
+        assert_eq!(heights.ranges().collect::<Vec<_>>(), vec![1..=2]);
+    }
+
+    #[test]
+    fn test_reorg() {
+        use event::Publisher as _;
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let block = gen::genesis(&mut rng);
+        let deliveries = Deliveries::new();
+        let alice = deliveries.subscribe_blocks("alice", false);
+
+        for height in 1..=5 {
+            deliveries.publish(block_received(&block, height));
+        }
+        assert_eq!(alice.try_iter().count(), 5);
+
+        // Blocks 4 and 5 are replaced by the blocks of a longer chain.
+        for height in [5, 4] {
+            deliveries.publish(Event::SyncManager(syncmgr::Event::BlockDisconnected {
+                hash: block.block_hash(),
+                height,
+            }));
+        }
+        assert_eq!(
+            deliveries
+                .delivered_blocks("alice")
+                .ranges()
+                .collect::<Vec<_>>(),
+            vec![1..=3]
+        );
+        for height in 1..=6 {
+            deliveries.publish(block_received(&block, height));
+        }
+        assert_eq!(
+            alice.try_iter().map(|(_, h)| h).collect::<Vec<_>>(),
+            vec![4, 5, 6]
+        );
+
+        // Filters above a filter header rollback are delivered again.
+        let filters = deliveries.subscribe_filters("alice", false);
+        let filter_fetched = |height| {
+            Event::SpvManager(spvmgr::Event::FilterFetched {
+                filter: BlockFilter::new(&[]),
+                block_hash: block.block_hash(),
+                height,
+            })
+        };
+        for height in 1..=4 {
+            deliveries.publish(filter_fetched(height));
+        }
+        deliveries.publish(Event::SpvManager(spvmgr::Event::RollbackDetected(2)));
+        for height in 1..=4 {
+            deliveries.publish(filter_fetched(height));
+        }
+        assert_eq!(
+            filters.try_iter().map(|(_, _, h)| h).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 3, 4]
+        );
+        assert_eq!(
+            deliveries
+                .delivered_blocks("alice")
+                .ranges()
+                .collect::<Vec<_>>(),
+            vec![1..=2]
+        );
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        use event::Publisher as _;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(FILE_NAME);
+        let mut rng = fastrand::Rng::with_seed(1);
+        let block = gen::genesis(&mut rng);
+        let load = || {
+            let deliveries = Deliveries::new();
+            deliveries.persist(&path).unwrap();
+            deliveries.delivered_blocks("alice")
+        };
+
+        let deliveries = Deliveries::new();
+        deliveries.persist(&path).unwrap();
+
+        let _alice = deliveries.subscribe_blocks("alice", false);
+        for height in 1..=3 {
+            deliveries.publish(block_received(&block, height));
+        }
+        // The file was written when the heights were loaded, so the deliveries that followed
+        // are not on disk yet.
+        assert!(load().ranges().next().is_none());
+
+        // Pending changes are written when the node stops.
+        deliveries.publish(Event::NodeStopped);
+        assert_eq!(load().ranges().collect::<Vec<_>>(), vec![1..=3]);
+    }
+
+    #[test]
+    fn test_dedup_across_restarts() {
+        use event::Publisher as _;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(FILE_NAME);
+        let mut rng = fastrand::Rng::with_seed(1);
+        let block = gen::genesis(&mut rng);
+
+        let deliveries = Deliveries::new();
+        deliveries.persist(&path).unwrap();
+
+        let alice = deliveries.subscribe_blocks("alice", false);
+        for height in 1..=3 {
+            deliveries.publish(block_received(&block, height));
+        }
+        assert_eq!(alice.try_iter().count(), 3);
+        drop(deliveries);
+
+        // After a restart, already delivered blocks are skipped.
+        let deliveries = Deliveries::new();
+        deliveries.persist(&path).unwrap();
+
+        let alice = deliveries.subscribe_blocks("alice", false);
+        let bob = deliveries.subscribe_blocks("bob", false);
+        let again = deliveries.subscribe_blocks("alice", true);
+        for height in 2..=4 {
+            deliveries.publish(block_received(&block, height));
+        }
+        assert_eq!(
+            alice.try_iter().map(|(_, h)| h).collect::<Vec<_>>(),
+            vec![4]
+        );
+        assert_eq!(bob.try_iter().count(), 3);
+        assert_eq!(again.try_iter().count(), 3);
+        assert_eq!(
+            deliveries
+                .delivered_blocks("alice")
+                .ranges()
+                .collect::<Vec<_>>(),
+            vec![1..=4]
+        );
+        assert!(deliveries
+            .delivered_filters("alice")
+            .ranges()
+            .next()
+            .is_none());
+    }
+}
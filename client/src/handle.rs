@@ -1,19 +1,31 @@
 //! Node handles are created from nodes by users of the library, to communicate with the underlying
 //! protocol instance.
 use std::net;
-use std::ops::Range;
+use std::ops::{Range, RangeBounds};
+use std::time;
 
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::Address;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::Script;
 use crossbeam_channel as chan;
 use thiserror::Error;
 
 use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::proof::InclusionProof;
 use nakamoto_common::block::tree::ImportResult;
-use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, Height, Transaction};
-use nakamoto_p2p::protocol::Command;
-use nakamoto_p2p::protocol::Peer;
-use nakamoto_p2p::{bitcoin::network::message::NetworkMessage, event::Event, protocol::Link};
+use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, BlockTime, Height};
+use nakamoto_common::block::{Transaction, Work};
+use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_p2p::bitcoin::Txid;
+use nakamoto_p2p::event::{self, Event};
+use nakamoto_p2p::protocol::feemgr::FeeRate;
+use nakamoto_p2p::protocol::spvmgr;
+use nakamoto_p2p::protocol::EffectiveConfig;
+use nakamoto_p2p::protocol::{Ban, Blocks, Peer, PeerInfo};
+use nakamoto_p2p::protocol::{ChainInfo, ChainSnapshot, Command, DerivationScheme, Epoch};
+use nakamoto_p2p::protocol::{RescanStatus, TxStatus, WatchlistId};
+use nakamoto_p2p::{bitcoin::network::message::NetworkMessage, protocol::Link};
 
 /// An error resulting from a handle method.
 #[derive(Error, Debug)]
@@ -27,6 +39,15 @@ pub enum Error {
     /// The operation timed out.
     #[error("the operation timed out")]
     Timeout,
+    /// The command was issued against a previous incarnation of the node, ie. before the
+    /// client was restarted, and was dropped without being executed.
+    #[error("command issued against node epoch {epoch} was dropped (current epoch is {current})")]
+    Stale {
+        /// Epoch the command was issued against.
+        epoch: Epoch,
+        /// Current epoch of the node.
+        current: Epoch,
+    },
     /// An I/O error occured.
     #[error(transparent)]
     Io(#[from] std::io::Error),
@@ -53,19 +74,232 @@ impl<T> From<chan::SendError<T>> for Error {
     }
 }
 
+/// How a rescan ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescanOutcome {
+    /// The rescan completed.
+    Completed {
+        /// Last height scanned.
+        height: Height,
+    },
+    /// The rescan was aborted before completing.
+    Aborted {
+        /// Last height scanned.
+        height: Height,
+    },
+}
+
+/// Handle to a rescan started with [`Handle::rescan`].
+#[derive(Debug)]
+pub struct RescanHandle<H> {
+    handle: H,
+    /// Rescan events, subscribed to before the rescan was started.
+    events: chan::Receiver<Event>,
+    /// Whether the start of this rescan was seen. Events of an earlier rescan may be
+    /// received before that.
+    started: bool,
+    outcome: Option<RescanOutcome>,
+}
+
+impl<H: Handle> RescanHandle<H> {
+    /// Create a handle to a rescan. The events must have been subscribed to, eg. with
+    /// [`RescanHandle::events`], before the rescan was started.
+    pub fn new(handle: H, events: chan::Receiver<Event>) -> Self {
+        Self {
+            handle,
+            events,
+            started: false,
+            outcome: None,
+        }
+    }
+
+    /// Subscribe to the events needed to follow a rescan.
+    pub fn events(handle: &H) -> chan::Receiver<Event> {
+        handle.events_filtered(|e: &Event| {
+            matches!(
+                e,
+                Event::SpvManager(spvmgr::Event::RescanStarted { .. })
+                    | Event::SpvManager(spvmgr::Event::RescanCompleted { .. })
+                    | Event::SpvManager(spvmgr::Event::RescanAborted { .. })
+            )
+        })
+    }
+
+    /// Get the progress of the rescan, or `None` if it's no longer active.
+    pub fn status(&self) -> Result<Option<RescanStatus>, Error> {
+        if self.outcome.is_some() {
+            return Ok(None);
+        }
+        self.handle.rescan_status()
+    }
+
+    /// Abort the rescan. Use [`RescanHandle::wait`] to wait for it to stop.
+    pub fn abort(&self) -> Result<(), Error> {
+        if self.outcome.is_some() {
+            return Ok(());
+        }
+        self.handle.command(Command::AbortRescan)
+    }
+
+    /// Wait for the rescan to complete, or be aborted. Rescans with no end height only
+    /// stop when aborted.
+    pub fn wait(&mut self) -> Result<RescanOutcome, Error> {
+        self.wait_until(None)
+    }
+
+    /// Like [`RescanHandle::wait`], but gives up after the given timeout.
+    pub fn wait_timeout(&mut self, timeout: time::Duration) -> Result<RescanOutcome, Error> {
+        self.wait_until(Some(time::Instant::now() + timeout))
+    }
+
+    fn wait_until(&mut self, deadline: Option<time::Instant>) -> Result<RescanOutcome, Error> {
+        while self.outcome.is_none() {
+            let event = match deadline {
+                Some(deadline) => self
+                    .events
+                    .recv_timeout(deadline.saturating_duration_since(time::Instant::now()))?,
+                None => self.events.recv()?,
+            };
+            match event {
+                Event::SpvManager(spvmgr::Event::RescanStarted { .. }) => {
+                    self.started = true;
+                }
+                Event::SpvManager(spvmgr::Event::RescanCompleted { height }) if self.started => {
+                    self.outcome = Some(RescanOutcome::Completed { height });
+                }
+                Event::SpvManager(spvmgr::Event::RescanAborted { height }) if self.started => {
+                    self.outcome = Some(RescanOutcome::Aborted { height });
+                }
+                _ => {}
+            }
+        }
+        Ok(self.outcome.unwrap())
+    }
+}
+
 /// A handle for communicating with a node process.
 pub trait Handle: Sized + Send + Sync {
     /// Get the tip of the chain.
     fn get_tip(&self) -> Result<(Height, BlockHeader), Error>;
-    /// Get a full block from the network.
+    /// Get the block header of the active chain at the given height.
+    fn get_block_header_by_height(&self, height: Height) -> Result<Option<BlockHeader>, Error>;
+    /// Get a block header of the active chain by hash, along with its height. Returns `None`
+    /// if the block isn't part of the active chain.
+    fn get_block_header(&self, hash: &BlockHash) -> Result<Option<(Height, BlockHeader)>, Error>;
+    /// Get information about the active chain, eg. its difficulty and median time past.
+    fn get_chain_info(&self) -> Result<ChainInfo, Error>;
+    /// Get the total proof-of-work of the active chain, as used to pick the best chain.
+    fn get_chain_work(&self) -> Result<Work, Error>;
+    /// Get the median time past of the block at the given height, ie. the median timestamp
+    /// of the eleven blocks up to and including it. This is the time lock-times are checked
+    /// against, as per BIP 113. Returns `None` if the height is past the tip.
+    fn get_median_time_past(&self, height: Height) -> Result<Option<BlockTime>, Error>;
+    /// Get the height of the first block of the active chain with a median time past at
+    /// or after the given time, eg. to find where to start scanning for the transactions of
+    /// a wallet created at that time. Since the median time past lags behind the block
+    /// timestamps by about an hour, it's best to look up a time a little earlier. Returns
+    /// `None` if the chain hasn't reached that time yet.
+    fn get_height_by_time(&self, time: BlockTime) -> Result<Option<Height>, Error>;
+    /// Estimate the fee rate, in satoshis per 1000 virtual bytes, needed for a transaction to
+    /// confirm within the given number of blocks. Estimates are based on the blocks we've
+    /// downloaded and the fee filters of our peers, and are rough at best. Returns `None` if
+    /// there is nothing to estimate from yet.
+    fn estimate_feerate(&self, target_blocks: Height) -> Result<Option<FeeRate>, Error>;
+    /// Get the configuration the node is running with. Peer addresses are redacted, so the
+    /// result, converted with [`EffectiveConfig::to_json`], can be attached to bug reports.
+    fn effective_config(&self) -> Result<EffectiveConfig, Error>;
+    /// Get information about the peers we're negotiated with, eg. their latency and the
+    /// number of messages exchanged with them.
+    fn peer_info(&self) -> Result<Vec<PeerInfo>, Error>;
+    /// Run a function over a consistent snapshot of the block header chain and filter header
+    /// chain. Queries made from the function all see the same state, eg. the filter tip can't
+    /// move past the block tip in between two queries.
+    ///
+    /// The function runs on the node's thread, and blocks it until it returns, so it should
+    /// only be used for reads.
+    fn with_chain_snapshot<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&dyn ChainSnapshot) -> T + Send + 'static,
+        T: Send + 'static;
+    /// Get a full block from the network. Returns the peer the block was requested from,
+    /// which is never the peer that served us the block's filter, unless it's the only
+    /// peer able to serve the block.
     fn get_block(&self, hash: &BlockHash) -> Result<net::SocketAddr, Error>;
-    /// Get compact filters from the network.
-    fn get_filters(&self, range: Range<Height>) -> Result<(), Error>;
+    /// Get a full block from the network, and wait for it to be received, along with its
+    /// height. Unlike with [`Handle::get_block`], the block doesn't need to be picked out
+    /// of [`Handle::blocks`]. If the block isn't received in time, it's requested again,
+    /// until the handle's timeout elapses.
+    fn fetch_block(&self, hash: &BlockHash) -> Result<(Block, Height), Error>;
+    /// Get full blocks from the network, eg. all the blocks matched during a rescan, given
+    /// either a range of heights or block hashes. The blocks are delivered in height order
+    /// on the returned channel, which is closed once all blocks are delivered. Blocks
+    /// already being fetched aren't requested twice, and requests that time out are retried
+    /// with other peers. If the blocks can't be fetched, the channel is closed early.
+    /// Blocks are only downloaded a few at a time ahead of the receiver, so a receiver that
+    /// stops reading for too long also gets the channel closed.
+    fn get_blocks(
+        &self,
+        blocks: impl Into<Blocks>,
+    ) -> Result<chan::Receiver<(Block, Height)>, Error>;
+    /// Create a proof of inclusion of a transaction in a block, eg. one received via
+    /// [`Handle::blocks`], verified against the stored block header. Returns `None` if the
+    /// transaction isn't part of the block, or the block isn't part of the active chain.
+    fn prove_inclusion(&self, block: &Block, txid: &Txid) -> Result<Option<InclusionProof>, Error>;
+    /// Verify a proof of inclusion against the stored block header. Returns the height of
+    /// the block the transaction is included in, or `None` if the block isn't part of the
+    /// active chain. Fails with a [`nakamoto_common::block::proof::Error`] if the proof is invalid.
+    fn verify_inclusion(&self, proof: &InclusionProof) -> Result<Option<Height>, Error>;
+    /// Get compact filters in the given ranges, from the network or the filter store.
+    /// Overlapping ranges are merged, and filters are delivered via [`Handle::filters`]
+    /// in ascending height order within each range.
+    fn get_filters(&self, ranges: Vec<Range<Height>>) -> Result<(), Error>;
+    /// Get the status of the active rescan, or `None` if no rescan is active.
+    fn rescan_status(&self) -> Result<Option<RescanStatus>, Error>;
+    /// Rescan the chain for the given scripts, within the given range of heights. If the
+    /// range has no start, the rescan starts at the current tip, and if it has no end, it
+    /// keeps scanning new blocks until aborted. Fails if a rescan is already active.
+    ///
+    /// Matching blocks are reported via [`Handle::events`], and the rescan can be followed
+    /// and aborted with the returned [`RescanHandle`].
+    fn rescan(
+        &self,
+        range: impl RangeBounds<Height>,
+        watch: Vec<Script>,
+    ) -> Result<RescanHandle<Self>, Error>
+    where
+        Self: Clone;
+    /// Verify the integrity of the active chain, by fully validating every block header,
+    /// from the genesis to the tip. The headers are copied from the node, and validated
+    /// on the calling thread. Returns the height of the verified chain.
+    fn verify_chain(&self) -> Result<Height, Error>;
+    /// Watch the receive and change addresses of a wallet account, given the account's
+    /// extended public key, eg. `m/84'/0'/0'` for the [`DerivationScheme::Bip84`] scheme.
+    ///
+    /// Addresses are derived up to the gap limit, and more are derived as matching filters
+    /// are processed, which is signaled by `GapLimitExtended` events. Returns the watchlists
+    /// of the receive and change chains, in that order.
+    fn watch_xpub(
+        &self,
+        xpub: ExtendedPubKey,
+        scheme: DerivationScheme,
+        gap_limit: u32,
+    ) -> Result<[WatchlistId; 2], Error>;
     /// Subscribe to blocks received.
     fn blocks(&self) -> chan::Receiver<(Block, Height)>;
-    /// Subscribe to compact filters received.
+    /// Subscribe to compact filters requested via [`Handle::get_filters`].
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)>;
-    /// Send a command to the client.
+    /// Subscribe to blocks received, under a persistent identity. Blocks at heights that were
+    /// already delivered under the same identity, including before a restart, are skipped,
+    /// unless `redeliver` is set.
+    fn subscribe_blocks(&self, id: &str, redeliver: bool) -> chan::Receiver<(Block, Height)>;
+    /// Subscribe to compact filters, under a persistent identity. Works like
+    /// [`Handle::subscribe_blocks`].
+    fn subscribe_filters(
+        &self,
+        id: &str,
+        redeliver: bool,
+    ) -> chan::Receiver<(BlockFilter, BlockHash, Height)>;
+    /// Send a command to the client. Returns once the command was picked up by the node.
     fn command(&self, cmd: Command) -> Result<(), Error>;
     /// Broadcast a message to peers matching the predicate.
     ///
@@ -85,8 +319,35 @@ pub trait Handle: Sized + Send + Sync {
     fn connect(&self, addr: net::SocketAddr) -> Result<Link, Error>;
     /// Disconnect from the designated peer address.
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error>;
-    /// Submit a transaction to the network.
-    fn submit_transaction(&self, tx: Transaction) -> Result<(), Error>;
+    /// Ban the designated peer address for the given duration, disconnecting any peer
+    /// connected from it. Bans are persisted, and survive restarts. Whitelisted addresses
+    /// can't be banned.
+    fn ban(&self, addr: net::IpAddr, duration: time::Duration) -> Result<(), Error>;
+    /// Lift the ban on the designated peer address.
+    fn unban(&self, addr: net::IpAddr) -> Result<(), Error>;
+    /// Get the banned peer addresses, along with the time until which they are banned,
+    /// and the reason for the ban.
+    fn list_banned(&self) -> Result<Vec<(net::IpAddr, Ban)>, Error>;
+    /// Get the addresses in the address book, including where each was learned from, and
+    /// when the peer was last seen.
+    fn list_addresses(&self) -> Result<Vec<KnownAddress>, Error>;
+    /// Mark an address in the address book as good or bad. Good addresses are treated as
+    /// if we had just connected to them successfully, while bad addresses are removed from
+    /// the address book. Returns `false` if the address isn't in the address book.
+    fn mark_address(&self, addr: net::IpAddr, good: bool) -> Result<bool, Error>;
+    /// Submit a transaction to the network. If `private` is set, the transaction isn't
+    /// announced to our peers, but to peers outside of our peer set, over dedicated
+    /// connections that are closed once the transaction was served. Combined with a proxy,
+    /// this makes it harder to link the transaction to this node.
+    fn submit_transaction(&self, tx: Transaction, private: bool) -> Result<(), Error>;
+    /// Submit a transaction paying the given fee, in satoshis, to the network. Unlike with
+    /// [`Handle::submit_transaction`], the transaction isn't announced to peers that asked
+    /// not to be sent transactions below the fee rate it pays.
+    fn submit_transaction_with_fee(&self, tx: Transaction, fee: u64) -> Result<(), Error>;
+    /// Get the status of a transaction submitted with [`Handle::submit_transaction`], eg.
+    /// whether peers have it in their mempool, or the height it was confirmed at.
+    /// Transactions that weren't submitted through this node are reported as unknown.
+    fn get_tx_status(&self, txid: &Txid) -> Result<TxStatus, Error>;
     /// Import block headers into the node.
     /// This may cause the node to broadcast header or inventory messages to its peers.
     fn import_headers(
@@ -95,6 +356,10 @@ pub trait Handle: Sized + Send + Sync {
     ) -> Result<Result<ImportResult, block::tree::Error>, Error>;
     /// Import peer addresses into the node's address book.
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), Error>;
+    /// Look for new peers, eg. when the address book is stale. DNS seeds are resolved again,
+    /// unless connecting through a proxy, and our outbound peers are asked for addresses. Waits for the peers to respond, or
+    /// for the timeout to elapse, and returns the number of new addresses learnt.
+    fn find_peers(&self) -> Result<usize, Error>;
     /// Wait for the given predicate to be fulfilled.
     fn wait<F: FnMut(Event) -> Option<T>, T>(&self, f: F) -> Result<T, Error>;
     /// Wait for a given number of peers to be connected with the given services.
@@ -110,6 +375,24 @@ pub trait Handle: Sized + Send + Sync {
     fn wait_for_height(&self, h: Height) -> Result<BlockHash, Error>;
     /// Listen on events.
     fn events(&self) -> chan::Receiver<Event>;
-    /// Shutdown the node process.
+    /// Listen on the events matching a filter, eg. an [`event::EventMask`] or a predicate
+    /// over [`Event`]. Unlike with [`Handle::events`], events that don't match aren't queued.
+    fn events_filtered(&self, filter: impl event::EventFilter<Event>) -> chan::Receiver<Event>;
+    /// Listen on events, on a bounded channel. When the channel is full, events are handled
+    /// according to the overflow policy, eg. the oldest ones are dropped, so that a stalled
+    /// consumer doesn't make the channel grow without bound.
+    fn events_bounded(&self, backpressure: event::Backpressure) -> event::BoundedReceiver<Event>;
+    /// Get the number of events dropped or coalesced so far on bounded event channels.
+    /// See [`Handle::events_bounded`].
+    fn dropped_events(&self) -> u64;
+    /// Listen on events, stamped with sequence numbers for gap detection, on a bounded
+    /// channel. Events dropped or coalesced when the channel is full leave a gap in the
+    /// sequence. See [`Handle::events_bounded`] and [`event::GapDetector`].
+    fn sequenced_events(
+        &self,
+        backpressure: event::Backpressure,
+    ) -> event::BoundedReceiver<event::Sequenced<Event>>;
+    /// Shutdown the node process. Returns once pending block header, filter and address
+    /// store writes are flushed, and all peer connections are closed.
     fn shutdown(self) -> Result<(), Error>;
 }
@@ -3,9 +3,19 @@
 #![allow(clippy::type_complexity)]
 #![deny(missing_docs, unsafe_code)]
 pub mod client;
+pub mod delivery;
 pub mod error;
 pub mod handle;
+pub mod metrics;
 pub mod peer;
+#[cfg(feature = "regtest")]
+pub mod regtest;
+pub mod rescan;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod sink;
+#[cfg(feature = "zmq")]
+pub mod zmq;
 
 pub use client::*;
 
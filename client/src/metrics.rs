@@ -0,0 +1,360 @@
+//! Client metrics.
+//!
+//! The client keeps a set of counters and gauges, eg. the number of connected peers, the
+//! header and filter heights, and the traffic exchanged with peers. These can be pulled
+//! with [`Handle::metrics`](crate::client::Handle::metrics), and rendered in the Prometheus
+//! text format with [`Snapshot::to_prometheus`]. With the `metrics-http` feature, they can
+//! also be served over HTTP with [`serve`].
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use nakamoto_common::block::tree::ImportResult;
+use nakamoto_common::block::Height;
+use nakamoto_p2p::event::{self, Event};
+use nakamoto_p2p::protocol::{connmgr, spvmgr, syncmgr, Direction, Hooks, PeerId};
+
+/// Progress of the active rescan.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Rescan {
+    /// Height of the last filter processed.
+    pub current: Height,
+    /// Height the rescan stops at, if any.
+    pub end: Option<Height>,
+}
+
+/// A point-in-time view of the client metrics.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of connected peers.
+    pub peers: usize,
+    /// Height of the block header chain.
+    pub header_height: Height,
+    /// Height of the filter header chain.
+    pub filter_header_height: Height,
+    /// Height of the last filter processed.
+    pub filter_height: Height,
+    /// Progress of the active rescan, if any.
+    pub rescan: Option<Rescan>,
+    /// Number of messages sent to peers.
+    pub messages_sent: u64,
+    /// Number of messages received from peers.
+    pub messages_received: u64,
+    /// Number of bytes sent to peers, including message headers.
+    pub bytes_sent: u64,
+    /// Number of bytes received from peers, including message headers.
+    pub bytes_received: u64,
+}
+
+impl Snapshot {
+    /// Render the metrics in the Prometheus text exposition format. Metric names are
+    /// prefixed with `nakamoto_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP nakamoto_{} {}", name, help).ok();
+            writeln!(out, "# TYPE nakamoto_{} {}", name, kind).ok();
+            writeln!(out, "nakamoto_{} {}", name, value).ok();
+        };
+
+        metric(
+            "peers",
+            "gauge",
+            "Number of connected peers.",
+            self.peers as u64,
+        );
+        metric(
+            "header_height",
+            "gauge",
+            "Height of the block header chain.",
+            self.header_height,
+        );
+        metric(
+            "filter_header_height",
+            "gauge",
+            "Height of the filter header chain.",
+            self.filter_header_height,
+        );
+        metric(
+            "filter_height",
+            "gauge",
+            "Height of the last filter processed.",
+            self.filter_height,
+        );
+        metric(
+            "rescan_active",
+            "gauge",
+            "Whether a rescan is active.",
+            self.rescan.is_some() as u64,
+        );
+        if let Some(rescan) = self.rescan {
+            metric(
+                "rescan_height",
+                "gauge",
+                "Height of the last filter processed by the active rescan.",
+                rescan.current,
+            );
+        }
+        metric(
+            "messages_sent_total",
+            "counter",
+            "Number of messages sent to peers.",
+            self.messages_sent,
+        );
+        metric(
+            "messages_received_total",
+            "counter",
+            "Number of messages received from peers.",
+            self.messages_received,
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Number of bytes sent to peers.",
+            self.bytes_sent,
+        );
+        metric(
+            "bytes_received_total",
+            "counter",
+            "Number of bytes received from peers.",
+            self.bytes_received,
+        );
+
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    snapshot: Snapshot,
+    peers: HashSet<PeerId>,
+}
+
+/// Client metrics, updated from protocol events and wire traffic.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    state: Arc<Mutex<State>>,
+}
+
+impl Metrics {
+    /// Create a new, empty set of metrics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current value of the metrics.
+    pub fn snapshot(&self) -> Snapshot {
+        self.state.lock().unwrap().snapshot.clone()
+    }
+
+    /// Wrap the given protocol hooks, so that wire traffic is counted before the hooks are
+    /// called.
+    pub fn hooks(&self, hooks: Hooks) -> Hooks {
+        let on_wire = hooks.on_wire.clone();
+        let state = self.state.clone();
+
+        Hooks {
+            on_wire: Arc::new(move |addr, direction, msg, size| {
+                {
+                    let snapshot = &mut state.lock().unwrap().snapshot;
+
+                    match direction {
+                        Direction::Sent => {
+                            snapshot.messages_sent += 1;
+                            snapshot.bytes_sent += size as u64;
+                        }
+                        Direction::Received => {
+                            snapshot.messages_received += 1;
+                            snapshot.bytes_received += size as u64;
+                        }
+                    }
+                }
+                on_wire(addr, direction, msg, size);
+            }),
+            ..hooks
+        }
+    }
+}
+
+impl event::Publisher for Metrics {
+    fn publish(&self, e: Event) {
+        let mut state = self.state.lock().unwrap();
+
+        match e {
+            Event::ConnManager(connmgr::Event::Connected(addr, _)) => {
+                state.peers.insert(addr);
+                state.snapshot.peers = state.peers.len();
+            }
+            Event::ConnManager(connmgr::Event::Disconnected(addr)) => {
+                state.peers.remove(&addr);
+                state.snapshot.peers = state.peers.len();
+            }
+            Event::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
+                _,
+                _,
+                height,
+                _,
+            )))
+            | Event::SyncManager(syncmgr::Event::Synced(_, height)) => {
+                state.snapshot.header_height = height;
+            }
+            Event::SpvManager(spvmgr::Event::FilterHeadersImported { height })
+            | Event::SpvManager(spvmgr::Event::Synced(height))
+            | Event::SpvManager(spvmgr::Event::RollbackDetected(height)) => {
+                state.snapshot.filter_header_height = height;
+            }
+            Event::SpvManager(spvmgr::Event::FilterProcessed { height, .. }) => {
+                state.snapshot.filter_height = height;
+
+                if let Some(rescan) = &mut state.snapshot.rescan {
+                    rescan.current = height;
+                }
+            }
+            Event::SpvManager(spvmgr::Event::RescanStarted { start, end }) => {
+                state.snapshot.rescan = Some(Rescan {
+                    current: start.saturating_sub(1),
+                    end,
+                });
+            }
+            Event::SpvManager(spvmgr::Event::RescanProgress { current, end, .. }) => {
+                state.snapshot.rescan = Some(Rescan { current, end });
+            }
+            Event::SpvManager(spvmgr::Event::RescanCompleted { .. })
+            | Event::SpvManager(spvmgr::Event::RescanAborted { .. }) => {
+                state.snapshot.rescan = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serve the metrics over HTTP, in the Prometheus text format, on the given address. Every
+/// request is answered with the current metrics, whatever its path. Requests are handled
+/// on a background thread, until the listener fails. Returns the address listened on.
+#[cfg(feature = "metrics-http")]
+pub fn serve(
+    addr: impl std::net::ToSocketAddrs,
+    metrics: Metrics,
+) -> std::io::Result<std::net::SocketAddr> {
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+
+    log::info!("Serving metrics on {}", local_addr);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("Metrics server error: {}", err);
+                    return;
+                }
+            };
+            // Consume the request headers, up to the empty line.
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+
+            while matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+                if line.trim().is_empty() {
+                    break;
+                }
+                line.clear();
+            }
+            let body = metrics.snapshot().to_prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).ok();
+        }
+    });
+
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::Publisher as _;
+    use nakamoto_p2p::protocol::Link;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        let alice = ([88, 88, 88, 88], 8333).into();
+        let bob = ([99, 99, 99, 99], 8333).into();
+
+        metrics.publish(Event::ConnManager(connmgr::Event::Connected(
+            alice,
+            Link::Outbound,
+        )));
+        metrics.publish(Event::ConnManager(connmgr::Event::Connected(
+            bob,
+            Link::Inbound,
+        )));
+        metrics.publish(Event::ConnManager(connmgr::Event::Disconnected(bob)));
+        metrics.publish(Event::SpvManager(spvmgr::Event::RescanStarted {
+            start: 10,
+            end: Some(20),
+        }));
+        metrics.publish(Event::SpvManager(spvmgr::Event::FilterProcessed {
+            block: Default::default(),
+            height: 12,
+            matched: false,
+            watchlists: vec![],
+            spends: false,
+        }));
+
+        let hooks = metrics.hooks(Hooks::default());
+        let msg = nakamoto_p2p::bitcoin::network::message::RawNetworkMessage {
+            magic: 0,
+            payload: nakamoto_p2p::bitcoin::network::message::NetworkMessage::Verack,
+        };
+        (hooks.on_wire)(alice, Direction::Sent, &msg, 24);
+        (hooks.on_wire)(alice, Direction::Received, &msg, 24);
+        (hooks.on_wire)(alice, Direction::Received, &msg, 24);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.peers, 1);
+        assert_eq!(snapshot.filter_height, 12);
+        assert_eq!(
+            snapshot.rescan,
+            Some(Rescan {
+                current: 12,
+                end: Some(20)
+            })
+        );
+        assert_eq!(snapshot.bytes_sent, 24);
+        assert_eq!(snapshot.bytes_received, 48);
+        assert_eq!(snapshot.messages_received, 2);
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("# TYPE nakamoto_bytes_received_total counter\n"));
+        assert!(text.contains("\nnakamoto_bytes_received_total 48\n"));
+        assert!(text.contains("\nnakamoto_rescan_height 12\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics-http")]
+    fn test_serve() {
+        use std::io::{Read as _, Write as _};
+
+        let metrics = Metrics::new();
+        let addr = serve("127.0.0.1:0", metrics.clone()).unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.snapshot().to_prometheus()));
+    }
+}
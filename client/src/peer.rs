@@ -1,10 +1,20 @@
 //! Client-related peer functionality.
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::{fs, io, net};
 
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_p2p::event::{self, Event};
+use nakamoto_p2p::protocol::connmgr::{self, Ban};
+
 pub use nakamoto_common::p2p::peer::*;
 
+/// Name of the file anchor peers are persisted in.
+pub const ANCHORS_FILE_NAME: &str = "anchors.json";
+/// Name of the file peer bans are persisted in.
+pub const BANS_FILE_NAME: &str = "bans.json";
+
 /// A file-backed implementation of [`Store`].
 #[derive(Debug)]
 pub struct Cache {
@@ -119,12 +129,200 @@ impl Store for Cache {
     }
 }
 
+/// Anchor peers, persisted across restarts. See [`connmgr::Event::Anchors`].
+///
+/// Anchors are stored as a JSON array of socket addresses, written when the client shuts down.
+#[derive(Debug, Clone, Default)]
+pub struct Anchors {
+    path: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Anchors {
+    /// Create a new anchor store, which doesn't persist anything until [`Anchors::load`]
+    /// is called.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the anchors persisted in the given file, and persist anchors to it on shutdown.
+    /// The file is cleared once read, so that the same anchors aren't used again if the
+    /// client doesn't shut down cleanly.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<net::SocketAddr>> {
+        use microserde::json::Value;
+        use std::str::FromStr;
+
+        let path = path.as_ref();
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut anchors = Vec::new();
+
+        if !s.trim().is_empty() {
+            let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+            match microserde::json::from_str(&s).map_err(|_| invalid())? {
+                Value::Array(ary) => {
+                    for v in ary {
+                        match v {
+                            Value::String(addr) => anchors
+                                .push(net::SocketAddr::from_str(&addr).map_err(|_| invalid())?),
+                            _ => return Err(invalid()),
+                        }
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Self::write(path, &[])?;
+        *self.path.lock().unwrap() = Some(path.to_owned());
+
+        Ok(anchors)
+    }
+
+    fn write(path: &Path, anchors: &[net::SocketAddr]) -> io::Result<()> {
+        use microserde::json::Value;
+
+        let ary = anchors
+            .iter()
+            .map(|addr| Value::String(addr.to_string()))
+            .collect();
+        let s = microserde::json::to_string(&Value::Array(ary));
+
+        fs::write(path, s + "\n")
+    }
+}
+
+impl event::Publisher for Anchors {
+    fn publish(&self, e: Event) {
+        if let Event::ConnManager(connmgr::Event::Anchors(anchors)) = e {
+            if let Some(path) = &*self.path.lock().unwrap() {
+                if let Err(err) = Self::write(path, &anchors) {
+                    log::error!("Error persisting anchor peers: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Peer bans, persisted across restarts. See [`connmgr::Event::Banned`].
+///
+/// Bans are stored as a JSON object mapping banned IP addresses to the time until which they
+/// are banned, in seconds since Epoch, and the reason for the ban. The file is rewritten every
+/// time an address is banned or unbanned, and expired bans are dropped from it.
+#[derive(Debug, Clone, Default)]
+pub struct Bans {
+    path: Arc<Mutex<Option<PathBuf>>>,
+    bans: Arc<Mutex<HashMap<net::IpAddr, Ban>>>,
+}
+
+impl Bans {
+    /// Create a new ban store, which doesn't persist anything until [`Bans::load`]
+    /// is called.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the bans persisted in the given file, and persist bans to it from now on.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<(net::IpAddr, Ban)>> {
+        use microserde::json::{Number, Value};
+        use std::str::FromStr;
+
+        let path = path.as_ref();
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut bans = HashMap::new();
+
+        if !s.trim().is_empty() {
+            let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+            match microserde::json::from_str(&s).map_err(|_| invalid())? {
+                Value::Object(obj) => {
+                    for (addr, v) in obj {
+                        let addr = net::IpAddr::from_str(&addr).map_err(|_| invalid())?;
+                        let ban = match v {
+                            Value::Object(ban) => match (ban.get("until"), ban.get("reason")) {
+                                (
+                                    Some(Value::Number(Number::U64(until))),
+                                    Some(Value::String(reason)),
+                                ) => Ban {
+                                    until: LocalTime::from_secs(*until),
+                                    reason: reason.clone(),
+                                },
+                                _ => return Err(invalid()),
+                            },
+                            _ => return Err(invalid()),
+                        };
+                        bans.insert(addr, ban);
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        let loaded = bans.iter().map(|(a, b)| (*a, b.clone())).collect();
+
+        *self.bans.lock().unwrap() = bans;
+        *self.path.lock().unwrap() = Some(path.to_owned());
+
+        Ok(loaded)
+    }
+
+    fn write(path: &Path, bans: &HashMap<net::IpAddr, Ban>) -> io::Result<()> {
+        use microserde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        for (addr, ban) in bans {
+            let mut val = Object::new();
+
+            val.insert(
+                "until".to_owned(),
+                Value::Number(Number::U64(ban.until.block_time() as u64)),
+            );
+            val.insert("reason".to_owned(), Value::String(ban.reason.clone()));
+            obj.insert(addr.to_string(), Value::Object(val));
+        }
+        let s = microserde::json::to_string(&Value::Object(obj));
+
+        fs::write(path, s + "\n")
+    }
+}
+
+impl event::Publisher for Bans {
+    fn publish(&self, e: Event) {
+        let mut bans = self.bans.lock().unwrap();
+
+        match e {
+            Event::ConnManager(connmgr::Event::Banned(addr, ban)) => {
+                bans.insert(addr, ban);
+            }
+            Event::ConnManager(connmgr::Event::Unbanned(addr)) => {
+                bans.remove(&addr);
+            }
+            _ => return,
+        }
+        let now = LocalTime::now();
+        bans.retain(|_, ban| now < ban.until);
+
+        if let Some(path) = &*self.path.lock().unwrap() {
+            if let Err(err) = Self::write(path, &bans) {
+                log::error!("Error persisting peer bans: {}", err);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use bitcoin::network::address::Address;
     use bitcoin::network::constants::ServiceFlags;
-    use nakamoto_common::block::time::LocalTime;
+    use nakamoto_common::block::time::LocalDuration;
+    use nakamoto_p2p::event::Publisher as _;
 
     #[test]
     fn test_empty() {
@@ -157,6 +355,7 @@ mod test {
                     last_sampled: Some(LocalTime::from_secs((i + 1) as u64)),
                     last_attempt: None,
                     last_active: None,
+                    filters: Default::default(),
                 };
                 cache.insert(ip, ka);
             }
@@ -180,4 +379,48 @@ mod test {
             assert_eq!(actual, expected);
         }
     }
+
+    #[test]
+    fn test_anchors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(ANCHORS_FILE_NAME);
+        let addrs: Vec<net::SocketAddr> = vec![
+            ([88, 88, 88, 88], 8333).into(),
+            "[2001:db8::1]:8333".parse().unwrap(),
+        ];
+
+        let anchors = Anchors::new();
+        assert!(anchors.load(&path).unwrap().is_empty());
+
+        anchors.publish(Event::ConnManager(connmgr::Event::Anchors(addrs.clone())));
+        assert_eq!(Anchors::new().load(&path).unwrap(), addrs);
+
+        // Anchors are only used once.
+        assert!(Anchors::new().load(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bans() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(BANS_FILE_NAME);
+        let alice: net::IpAddr = [88, 88, 88, 88].into();
+        let bob: net::IpAddr = "2001:db8::1".parse().unwrap();
+        let ban = Ban {
+            until: LocalTime::from_block_time(LocalTime::now().block_time())
+                + LocalDuration::from_mins(60),
+            reason: String::from("sent invalid headers"),
+        };
+
+        let bans = Bans::new();
+        assert!(bans.load(&path).unwrap().is_empty());
+
+        bans.publish(Event::ConnManager(connmgr::Event::Banned(
+            alice,
+            ban.clone(),
+        )));
+        bans.publish(Event::ConnManager(connmgr::Event::Banned(bob, ban.clone())));
+        bans.publish(Event::ConnManager(connmgr::Event::Unbanned(alice)));
+
+        assert_eq!(Bans::new().load(&path).unwrap(), vec![(bob, ban)]);
+    }
 }
@@ -0,0 +1,392 @@
+//! Utilities for driving a client against a local `bitcoind` in regtest mode.
+//!
+//! Meant for end-to-end tests, eg. of wallets built on the client, which need a full node
+//! to generate blocks and serve compact filters. Requires the `regtest` feature, and a
+//! `bitcoind` executable, either in the `PATH` or at the location given by the
+//! `BITCOIND_EXE` environment variable.
+//!
+//! ```no_run
+//! use nakamoto_client::handle::Handle as _;
+//! use nakamoto_client::regtest::{Bitcoind, Node};
+//!
+//! let bitcoind = Bitcoind::spawn().unwrap();
+//! let node = Node::spawn(Node::config(&bitcoind)).unwrap();
+//!
+//! bitcoind.generate(10).unwrap();
+//! node.wait_for_sync(&bitcoind).unwrap();
+//!
+//! let filters = node.wait_for_filters(1..11).unwrap();
+//! assert_eq!(filters.len(), 10);
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, Read as _, Write as _};
+use std::net;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time;
+
+use microserde::json::{self, Value};
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_chain::filter::cache::FilterCache;
+use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_p2p::bitcoin::blockdata::script::Script;
+use nakamoto_p2p::bitcoin::hashes::hex::FromHex;
+use nakamoto_p2p::bitcoin::util::address::Address;
+
+use crate::client::{self, Client, Config, Network, Publisher};
+use crate::error::Error;
+use crate::handle::{self, Handle as _};
+
+/// The reactor nodes are run with.
+pub type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, Publisher>;
+
+/// RPC credentials `bitcoind` is started with.
+const RPC_USER: &str = "nakamoto";
+/// RPC password `bitcoind` is started with.
+const RPC_PASSWORD: &str = "nakamoto";
+/// How long to wait for `bitcoind` to start serving RPC requests.
+const STARTUP_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// A `bitcoind` process running in regtest mode, serving compact filters. The process is
+/// stopped, and its data directory removed, when this is dropped.
+#[derive(Debug)]
+pub struct Bitcoind {
+    process: process::Child,
+    datadir: PathBuf,
+    p2p_port: u16,
+    rpc_port: u16,
+}
+
+impl Bitcoind {
+    /// Start a `bitcoind` process with a fresh data directory, and wait for it to be ready.
+    pub fn spawn() -> io::Result<Self> {
+        Self::spawn_with_args(&[])
+    }
+
+    /// Start a `bitcoind` process with additional command-line arguments, eg. `-txindex`.
+    pub fn spawn_with_args(args: &[&str]) -> io::Result<Self> {
+        let exe = env::var("BITCOIND_EXE").unwrap_or_else(|_| String::from("bitcoind"));
+        let p2p_port = unused_port()?;
+        let rpc_port = unused_port()?;
+        let datadir =
+            env::temp_dir().join(format!("nakamoto-regtest-{}-{}", process::id(), p2p_port));
+        fs::create_dir_all(&datadir)?;
+
+        let process = process::Command::new(exe)
+            .arg("-regtest")
+            .arg("-server")
+            .arg("-listen")
+            .arg("-blockfilterindex")
+            .arg("-peerblockfilters")
+            .arg("-fallbackfee=0.0001")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-port={}", p2p_port))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+            .args(args)
+            .stdout(process::Stdio::null())
+            .spawn()?;
+
+        let bitcoind = Self {
+            process,
+            datadir,
+            p2p_port,
+            rpc_port,
+        };
+        let start = time::Instant::now();
+
+        // The RPC server returns errors until it is done warming up.
+        while let Err(err) = bitcoind.rpc("getblockchaininfo", &[]) {
+            if start.elapsed() >= STARTUP_TIMEOUT {
+                return Err(err);
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        Ok(bitcoind)
+    }
+
+    /// The address `bitcoind` accepts peer connections on.
+    pub fn addr(&self) -> net::SocketAddr {
+        ([127, 0, 0, 1], self.p2p_port).into()
+    }
+
+    /// Call an RPC method, with the given JSON-encoded parameters, and return the result.
+    pub fn rpc(&self, method: &str, params: &[Value]) -> io::Result<Value> {
+        let mut request = json::Object::new();
+        request.insert(String::from("jsonrpc"), Value::String("1.0".to_owned()));
+        request.insert(String::from("id"), Value::String("nakamoto".to_owned()));
+        request.insert(String::from("method"), Value::String(method.to_owned()));
+        request.insert(
+            String::from("params"),
+            Value::Array(params.iter().cloned().collect()),
+        );
+
+        let body = json::to_string(&Value::Object(request));
+        let mut stream =
+            net::TcpStream::connect(net::SocketAddr::from(([127, 0, 0, 1], self.rpc_port)))?;
+
+        write!(
+            stream,
+            "POST / HTTP/1.0\r\n\
+             Host: 127.0.0.1\r\n\
+             Authorization: Basic {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            base64(format!("{}:{}", RPC_USER, RPC_PASSWORD).as_bytes()),
+            body.len(),
+            body
+        )?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        let mut response = match json::from_str(body) {
+            Ok(Value::Object(obj)) => obj,
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+
+        match response.remove("error") {
+            Some(Value::Null) | None => {}
+            Some(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{}: {}", method, json::to_string(&err)),
+                ))
+            }
+        }
+        Ok(response.remove("result").unwrap_or(Value::Null))
+    }
+
+    /// Get the height of the `bitcoind` chain.
+    pub fn height(&self) -> io::Result<Height> {
+        match self.rpc("getblockcount", &[])? {
+            Value::Number(n) => Ok(number(n)),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    /// Mine blocks, paying to an anyone-can-spend address. Returns the block hashes.
+    pub fn generate(&self, blocks: u64) -> io::Result<Vec<BlockHash>> {
+        let address = Address::p2wsh(&Script::from(vec![0x51]), Network::Regtest.into());
+
+        self.generate_to_address(blocks, &address)
+    }
+
+    /// Mine blocks, paying to the given address. Returns the block hashes.
+    pub fn generate_to_address(
+        &self,
+        blocks: u64,
+        address: &Address,
+    ) -> io::Result<Vec<BlockHash>> {
+        let result = self.rpc(
+            "generatetoaddress",
+            &[
+                Value::Number(json::Number::U64(blocks)),
+                Value::String(address.to_string()),
+            ],
+        )?;
+
+        match result {
+            Value::Array(hashes) => hashes
+                .into_iter()
+                .map(|h| match h {
+                    Value::String(h) => BlockHash::from_hex(&h)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData)),
+                    _ => Err(io::ErrorKind::InvalidData.into()),
+                })
+                .collect(),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        if self.rpc("stop", &[]).is_ok() {
+            self.process.wait().ok();
+        } else {
+            self.process.kill().ok();
+        }
+        fs::remove_dir_all(&self.datadir).ok();
+    }
+}
+
+/// A client node running in its own thread, with in-memory stores. The node is shut down
+/// when this is dropped.
+pub struct Node {
+    handle: client::Handle<Reactor>,
+    timeout: time::Duration,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Node {
+    /// A regtest configuration connecting only to the given `bitcoind`.
+    pub fn config(bitcoind: &Bitcoind) -> Config {
+        Config {
+            network: Network::Regtest,
+            connect: vec![bitcoind.addr()],
+            connect_only: true,
+            listen: vec![],
+            timeout: time::Duration::from_secs(30),
+            ..Config::default()
+        }
+    }
+
+    /// Start a node with the given configuration.
+    pub fn spawn(config: Config) -> Result<Self, Error> {
+        let network = config.network;
+        let timeout = config.timeout;
+        let mut client = Client::<Reactor>::new(config)?;
+        let handle = client.handle();
+
+        let thread = thread::spawn(move || {
+            let mut run = || -> Result<(), Error> {
+                let genesis = network.genesis();
+                let checkpoints = network.checkpoints().collect::<Vec<_>>();
+                let store = store::Memory::new((genesis, vec![]).into());
+                let cache = BlockCache::from(store, network.params(), &checkpoints)?;
+                let filters = FilterCache::from(store::Memory::default())?;
+
+                client.run_with(cache, filters, HashMap::new())
+            };
+            if let Err(err) = run() {
+                log::error!("Regtest node exited with error: {}", err);
+            }
+        });
+
+        Ok(Self {
+            handle,
+            timeout,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get a handle to the node.
+    pub fn handle(&self) -> &client::Handle<Reactor> {
+        &self.handle
+    }
+
+    /// Wait for the node's active chain to catch up with `bitcoind`'s. Returns the tip.
+    pub fn wait_for_sync(&self, bitcoind: &Bitcoind) -> Result<(Height, BlockHash), handle::Error> {
+        let height = bitcoind.height()?;
+        let hash = self.handle.wait_for_height(height)?;
+
+        Ok((height, hash))
+    }
+
+    /// Fetch the compact filters in the given range, and wait for all of them to be
+    /// received. Returns the filters in ascending height order.
+    pub fn wait_for_filters(
+        &self,
+        range: Range<Height>,
+    ) -> Result<Vec<(BlockFilter, BlockHash, Height)>, handle::Error> {
+        let filters = self.handle.filters();
+        let mut received = BTreeMap::new();
+
+        self.handle.get_filters(vec![range.clone()])?;
+
+        while received.len() < (range.end - range.start) as usize {
+            let (filter, hash, height) = filters.recv_timeout(self.timeout)?;
+
+            if range.contains(&height) {
+                received.insert(height, (filter, hash, height));
+            }
+        }
+        Ok(received.into_iter().map(|(_, v)| v).collect())
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.handle.clone().shutdown().ok();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Find a local port that's not in use.
+fn unused_port() -> io::Result<u16> {
+    let listener = net::TcpListener::bind(net::SocketAddr::from(([127, 0, 0, 1], 0)))?;
+
+    Ok(listener.local_addr()?.port())
+}
+
+/// Convert a JSON number to a height.
+fn number(n: json::Number) -> Height {
+    match n {
+        json::Number::U64(n) => n,
+        json::Number::I64(n) => n as Height,
+        json::Number::F64(n) => n as Height,
+    }
+}
+
+/// Encode bytes in base64, for HTTP basic authentication.
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::new();
+
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"nakamoto:nakamoto"), "bmFrYW1vdG86bmFrYW1vdG8=");
+    }
+
+    #[test]
+    #[ignore] // Requires `bitcoind`.
+    fn test_regtest() {
+        let bitcoind = Bitcoind::spawn().unwrap();
+        let node = Node::spawn(Node::config(&bitcoind)).unwrap();
+        let hashes = bitcoind.generate(16).unwrap();
+
+        let (height, hash) = node.wait_for_sync(&bitcoind).unwrap();
+        assert_eq!(height, 16);
+        assert_eq!(Some(&hash), hashes.last());
+
+        let filters = node.wait_for_filters(1..17).unwrap();
+        assert_eq!(
+            filters.iter().map(|(_, h, _)| *h).collect::<Vec<_>>(),
+            hashes
+        );
+    }
+}
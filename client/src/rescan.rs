@@ -0,0 +1,354 @@
+//! Rescan state, persisted across restarts.
+//!
+//! The state of the active rescan is saved periodically, and when the client shuts down,
+//! see [`spvmgr::Event::RescanCheckpoint`]. When the client is restarted, a rescan with the
+//! same bounds and watched scripts resumes from the saved state, instead of starting over
+//! from its start height. The state is discarded once the rescan completes, or is aborted.
+//!
+//! The state is written to a temporary file first, which then replaces the state file, so
+//! that a crash while writing never leaves a partial state behind. A state file that can't
+//! be read anyway is ignored, and the next rescan starts over.
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use bitcoin::consensus::encode;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::sha256;
+use bitcoin::{OutPoint, Script, TxOut};
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_p2p::event::{self, Event};
+use nakamoto_p2p::protocol::spvmgr::{self, RescanState};
+
+/// Name of the file the rescan state is persisted in.
+pub const FILE_NAME: &str = "rescan.json";
+
+/// Persists the state of the active rescan. See the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct Rescans {
+    path: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Rescans {
+    /// Create a new rescan store, which doesn't persist anything until [`Rescans::load`]
+    /// is called.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the rescan state persisted in the given file, if any, and persist the state of
+    /// rescans to it from now on. A corrupt state is treated as absent.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<Option<RescanState>> {
+        let path = path.as_ref();
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Ignoring invalid rescan state in {:?}", path);
+                String::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let state = if s.trim().is_empty() {
+            None
+        } else {
+            let state = microserde::json::from_str(&s).ok().and_then(from_json);
+
+            if state.is_none() {
+                log::warn!("Ignoring invalid rescan state in {:?}", path);
+            }
+            state
+        };
+        *self.path.lock().unwrap() = Some(path.to_owned());
+
+        Ok(state)
+    }
+
+    fn write(path: &Path, state: &RescanState) -> io::Result<()> {
+        let s = microserde::json::to_string(&to_json(state));
+        let tmp = path.with_extension("json.tmp");
+
+        fs::write(&tmp, s + "\n")?;
+        fs::rename(&tmp, path)
+    }
+
+    fn clear(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl event::Publisher for Rescans {
+    fn publish(&self, e: Event) {
+        let path = self.path.lock().unwrap();
+        let path = match &*path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = match e {
+            Event::SpvManager(spvmgr::Event::RescanCheckpoint(state)) => Self::write(path, &state),
+            Event::SpvManager(spvmgr::Event::RescanCompleted { .. })
+            | Event::SpvManager(spvmgr::Event::RescanAborted { .. }) => Self::clear(path),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            log::error!("Error persisting rescan state: {}", err);
+        }
+    }
+}
+
+fn to_json(state: &RescanState) -> Value {
+    let height = |h: Height| Value::Number(Number::U64(h));
+    let array = |vals: Vec<Value>| Value::Array(vals.into_iter().collect());
+    let mut obj = Object::new();
+
+    obj.insert("id".to_owned(), Value::String(state.id.to_string()));
+    obj.insert("current".to_owned(), height(state.current));
+    obj.insert("end".to_owned(), state.end.map_or(Value::Null, height));
+    obj.insert(
+        "matched".to_owned(),
+        Value::Array(
+            state
+                .matched
+                .iter()
+                .map(|(h, hash)| {
+                    Value::Array(
+                        vec![height(*h), Value::String(hash.to_string())]
+                            .into_iter()
+                            .collect(),
+                    )
+                })
+                .collect(),
+        ),
+    );
+    obj.insert(
+        "outpoints".to_owned(),
+        Value::Array(
+            state
+                .outpoints
+                .iter()
+                .map(|(outpoint, script)| {
+                    array(vec![
+                        Value::String(outpoint.to_string()),
+                        Value::String(encode::serialize_hex(script)),
+                    ])
+                })
+                .collect(),
+        ),
+    );
+    obj.insert(
+        "utxos".to_owned(),
+        Value::Array(
+            state
+                .utxos
+                .iter()
+                .map(|(id, outpoint, output, h)| {
+                    array(vec![
+                        Value::Number(Number::U64(*id)),
+                        Value::String(outpoint.to_string()),
+                        Value::String(encode::serialize_hex(output)),
+                        height(*h),
+                    ])
+                })
+                .collect(),
+        ),
+    );
+    obj.insert(
+        "xpubs".to_owned(),
+        Value::Array(
+            state
+                .xpubs
+                .iter()
+                .map(|(id, used)| {
+                    array(vec![
+                        Value::Number(Number::U64(*id)),
+                        Value::Number(Number::U64(*used as u64)),
+                    ])
+                })
+                .collect(),
+        ),
+    );
+    Value::Object(obj)
+}
+
+fn from_json(val: Value) -> Option<RescanState> {
+    let height = |val: &Value| match val {
+        Value::Number(Number::U64(h)) => Some(*h),
+        _ => None,
+    };
+    let decode = |val: &Value| match val {
+        Value::String(s) => Vec::<u8>::from_hex(s).ok(),
+        _ => None,
+    };
+    let outpoint = |val: &Value| match val {
+        Value::String(s) => OutPoint::from_str(s).ok(),
+        _ => None,
+    };
+    let obj = match val {
+        Value::Object(obj) => obj,
+        _ => return None,
+    };
+    let id = match obj.get("id")? {
+        Value::String(s) => sha256::Hash::from_str(s).ok()?,
+        _ => return None,
+    };
+    let current = height(obj.get("current")?)?;
+    let end = match obj.get("end")? {
+        Value::Null => None,
+        val => Some(height(val)?),
+    };
+    let matched = match obj.get("matched")? {
+        Value::Array(ary) => ary
+            .iter()
+            .map(|val| match val {
+                Value::Array(pair) => match pair.as_slice() {
+                    [h, Value::String(hash)] => Some((height(h)?, BlockHash::from_str(hash).ok()?)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+
+    // Lists added after the first version of the state are optional.
+    let list = |key: &str| match obj.get(key) {
+        None => Some(Vec::new()),
+        Some(Value::Array(ary)) => Some(ary.iter().collect::<Vec<_>>()),
+        Some(_) => None,
+    };
+    let outpoints = list("outpoints")?
+        .into_iter()
+        .map(|val| match val {
+            Value::Array(pair) => match pair.as_slice() {
+                [o, script] => Some((
+                    outpoint(o)?,
+                    encode::deserialize::<Script>(&decode(script)?).ok()?,
+                )),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let utxos = list("utxos")?
+        .into_iter()
+        .map(|val| match val {
+            Value::Array(tuple) => match tuple.as_slice() {
+                [id, o, output, h] => Some((
+                    height(id)?,
+                    outpoint(o)?,
+                    encode::deserialize::<TxOut>(&decode(output)?).ok()?,
+                    height(h)?,
+                )),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let xpubs = list("xpubs")?
+        .into_iter()
+        .map(|val| match val {
+            Value::Array(pair) => match pair.as_slice() {
+                [id, used] => Some((height(id)?, height(used)? as u32)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(RescanState {
+        id,
+        current,
+        end,
+        matched,
+        outpoints,
+        utxos,
+        xpubs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use nakamoto_p2p::event::Publisher as _;
+
+    #[test]
+    fn test_persist() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(FILE_NAME);
+        let state = RescanState {
+            id: sha256::Hash::hash(b"rescan"),
+            current: 1024,
+            end: None,
+            matched: vec![(1000, BlockHash::hash(b"block"))],
+            outpoints: vec![(
+                OutPoint::new(bitcoin::Txid::hash(b"watched"), 1),
+                Script::from(vec![0x51]),
+            )],
+            utxos: vec![(
+                7,
+                OutPoint::new(bitcoin::Txid::hash(b"utxo"), 0),
+                TxOut {
+                    value: 1000,
+                    script_pubkey: Script::from(vec![0x6a]),
+                },
+                999,
+            )],
+            xpubs: vec![(7, 5)],
+        };
+
+        let rescans = Rescans::new();
+        assert_eq!(rescans.load(&path).unwrap(), None);
+
+        rescans.publish(Event::SpvManager(spvmgr::Event::RescanCheckpoint(
+            state.clone(),
+        )));
+        assert_eq!(Rescans::new().load(&path).unwrap(), Some(state.clone()));
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // The state is discarded once the rescan completes.
+        rescans.publish(Event::SpvManager(spvmgr::Event::RescanCompleted {
+            height: 2048,
+        }));
+        assert_eq!(Rescans::new().load(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(FILE_NAME);
+
+        // A state file truncated by a crash is ignored.
+        fs::write(&path, "{\"id\":\"00").unwrap();
+        assert_eq!(Rescans::new().load(&path).unwrap(), None);
+
+        // The state written by an earlier version, without outputs, is still loaded.
+        let id = sha256::Hash::hash(b"rescan");
+        fs::write(
+            &path,
+            format!(
+                "{{\"id\":\"{}\",\"current\":5,\"end\":null,\"matched\":[]}}",
+                id
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            Rescans::new().load(&path).unwrap(),
+            Some(RescanState {
+                id,
+                current: 5,
+                end: None,
+                matched: vec![],
+                outpoints: vec![],
+                utxos: vec![],
+                xpubs: vec![],
+            })
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! JSON-RPC control interface.
+//!
+//! Serves a subset of the [`Handle`] operations over JSON-RPC 2.0, on HTTP `POST` requests,
+//! so that the client can be run as a standalone light node, and controlled from other
+//! languages. The supported methods are:
+//!
+//! * `gettip`: get the tip of the active chain, as `{"hash": <hex>, "height": <n>}`.
+//! * `getpeers`: get the negotiated peers, as an array of objects with the `addr`, `link`,
+//!   `services`, `user_agent`, `height` and `version` fields.
+//! * `watchaddress [<address>]`: watch an address. Returns `null`.
+//! * `rescan [<from>, <to>]`: rescan the chain for the watched addresses, starting at
+//!   height `from`, up to and including height `to`, or indefinitely if `to` is omitted.
+//!   Returns `null`.
+//! * `sendrawtransaction [<hex>]`: submit a serialized transaction to the network. Returns
+//!   the transaction id, in hex.
+//!
+//! There is no authentication: the server should only listen on a local address.
+use std::io::{self, BufRead as _, BufReader, Read as _, Write as _};
+use std::net;
+use std::ops::Bound;
+use std::str::FromStr;
+use std::thread;
+
+use bitcoin::consensus::encode;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{Address, Script, Transaction};
+use crossbeam_channel as chan;
+use microserde::json::{self, Array, Number, Object, Value};
+
+use nakamoto_common::block::Height;
+use nakamoto_p2p::protocol::spvmgr::DEFAULT_WATCHLIST;
+use nakamoto_p2p::protocol::{Command, Link};
+
+use crate::handle::Handle;
+
+/// Invalid JSON was received.
+pub const PARSE_ERROR: i64 = -32700;
+/// The request is not a valid JSON-RPC request.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The operation failed.
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// A JSON-RPC error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Error code.
+    pub code: i64,
+    /// Error message.
+    pub message: String,
+}
+
+impl Error {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn params(message: impl ToString) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn internal(err: impl std::fmt::Display) -> Self {
+        Self::new(INTERNAL_ERROR, err)
+    }
+}
+
+/// Serve the JSON-RPC interface on the given address. Requests are handled one at a time,
+/// on a background thread, until the listener fails. Returns the address listened on.
+pub fn serve<H>(addr: impl net::ToSocketAddrs, handle: H) -> io::Result<net::SocketAddr>
+where
+    H: Handle + Send + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let mut server = Server {
+        handle,
+        watching: Vec::new(),
+    };
+
+    log::info!("Serving JSON-RPC on {}", local_addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("JSON-RPC server error: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = server.respond(stream) {
+                log::debug!("JSON-RPC request failed: {}", err);
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// JSON-RPC server state.
+struct Server<H> {
+    handle: H,
+    /// Scripts watched via `watchaddress`, rescanned for on `rescan`.
+    watching: Vec<Script>,
+}
+
+impl<H: Handle> Server<H> {
+    /// Read an HTTP request from the stream, and write the response.
+    fn respond(&mut self, mut stream: net::TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        let mut length = 0;
+
+        reader.read_line(&mut line)?;
+        let post = line.starts_with("POST ");
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                }
+            }
+        }
+        if !post {
+            return stream.write_all(
+                b"HTTP/1.1 405 Method Not Allowed\r\nAllow: POST\r\n\
+                  Content-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+
+        let response = json::to_string(&self.process(&String::from_utf8_lossy(&body)));
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            response.len(),
+            response
+        );
+        stream.write_all(response.as_bytes())
+    }
+
+    /// Process a JSON-RPC request, and return the response object.
+    fn process(&mut self, request: &str) -> Value {
+        let mut response = Object::new();
+        let mut id = Value::Null;
+
+        let result = match json::from_str::<Value>(request) {
+            Ok(Value::Object(mut req)) => {
+                id = req.remove("id").unwrap_or(Value::Null);
+
+                match (req.remove("method"), req.remove("params")) {
+                    (Some(Value::String(method)), Some(Value::Array(params))) => {
+                        self.call(&method, &params)
+                    }
+                    (Some(Value::String(method)), None) => self.call(&method, &[]),
+                    _ => Err(Error::new(INVALID_REQUEST, "invalid request")),
+                }
+            }
+            Ok(_) => Err(Error::new(INVALID_REQUEST, "invalid request")),
+            Err(_) => Err(Error::new(PARSE_ERROR, "parse error")),
+        };
+
+        response.insert("jsonrpc".to_owned(), Value::String("2.0".to_owned()));
+        response.insert("id".to_owned(), id);
+
+        match result {
+            Ok(result) => {
+                response.insert("result".to_owned(), result);
+            }
+            Err(err) => {
+                let mut error = Object::new();
+
+                error.insert("code".to_owned(), Value::Number(Number::I64(err.code)));
+                error.insert("message".to_owned(), Value::String(err.message));
+                response.insert("error".to_owned(), Value::Object(error));
+            }
+        }
+        Value::Object(response)
+    }
+
+    /// Call a method with the given parameters.
+    fn call(&mut self, method: &str, params: &[Value]) -> Result<Value, Error> {
+        log::debug!("Received JSON-RPC call: {}", method);
+
+        match method {
+            "gettip" => {
+                let (height, header) = self.handle.get_tip().map_err(Error::internal)?;
+                let mut tip = Object::new();
+
+                tip.insert(
+                    "hash".to_owned(),
+                    Value::String(header.block_hash().to_string()),
+                );
+                tip.insert("height".to_owned(), Value::Number(Number::U64(height)));
+
+                Ok(Value::Object(tip))
+            }
+            "getpeers" => {
+                let peers = self.handle.peer_info().map_err(Error::internal)?;
+                let peers = peers
+                    .into_iter()
+                    .map(|peer| {
+                        let mut obj = Object::new();
+                        let link = match peer.link {
+                            Link::Inbound => "inbound",
+                            Link::Outbound => "outbound",
+                        };
+
+                        obj.insert("addr".to_owned(), Value::String(peer.addr.to_string()));
+                        obj.insert("link".to_owned(), Value::String(link.to_owned()));
+                        obj.insert(
+                            "services".to_owned(),
+                            Value::Number(Number::U64(peer.services.as_u64())),
+                        );
+                        obj.insert("user_agent".to_owned(), Value::String(peer.user_agent));
+                        obj.insert("height".to_owned(), Value::Number(Number::U64(peer.height)));
+                        obj.insert(
+                            "version".to_owned(),
+                            Value::Number(Number::U64(peer.version as u64)),
+                        );
+
+                        Value::Object(obj)
+                    })
+                    .collect::<Array>();
+
+                Ok(Value::Array(peers))
+            }
+            "watchaddress" => {
+                let addr = match params {
+                    [Value::String(addr)] => Address::from_str(addr).map_err(Error::params)?,
+                    _ => return Err(Error::params("expected an address")),
+                };
+                let script = addr.script_pubkey();
+
+                self.handle
+                    .command(Command::WatchScripts(
+                        DEFAULT_WATCHLIST,
+                        vec![script.clone()],
+                    ))
+                    .map_err(Error::internal)?;
+
+                if !self.watching.contains(&script) {
+                    self.watching.push(script);
+                }
+                Ok(Value::Null)
+            }
+            "rescan" => {
+                let (from, to) = match params {
+                    [from] => (height(from)?, Bound::Unbounded),
+                    [from, to] => (height(from)?, Bound::Included(height(to)?)),
+                    _ => return Err(Error::params("expected a start and optional end height")),
+                };
+                let (reply, result) = chan::bounded(1);
+
+                self.handle
+                    .command(Command::Rescan {
+                        from: Bound::Included(from),
+                        to,
+                        watch: self.watching.clone(),
+                        reply,
+                    })
+                    .map_err(Error::internal)?;
+
+                result
+                    .recv()
+                    .map_err(Error::internal)?
+                    .map_err(Error::internal)?;
+
+                Ok(Value::Null)
+            }
+            "sendrawtransaction" => {
+                let tx: Transaction = match params {
+                    [Value::String(hex)] => Vec::<u8>::from_hex(hex)
+                        .map_err(Error::params)
+                        .and_then(|bytes| encode::deserialize(&bytes).map_err(Error::params))?,
+                    _ => return Err(Error::params("expected a hex-encoded transaction")),
+                };
+                let txid = tx.txid();
+
+                self.handle
+                    .submit_transaction(tx, false)
+                    .map_err(Error::internal)?;
+
+                Ok(Value::String(txid.to_string()))
+            }
+            _ => Err(Error::new(
+                METHOD_NOT_FOUND,
+                format!("method `{}` not found", method),
+            )),
+        }
+    }
+}
+
+/// Get a block height from a JSON value.
+fn height(value: &Value) -> Result<Height, Error> {
+    match value {
+        Value::Number(Number::U64(height)) => Ok(*height),
+        _ => Err(Error::params("expected a block height")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_chain::block::store;
+    use nakamoto_chain::filter::store::bodies;
+    use nakamoto_common::network::Network;
+    use nakamoto_p2p::event::Event;
+
+    use crate::client::{event, Client, Config, Publisher};
+
+    type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, Publisher>;
+
+    fn call(addr: net::SocketAddr, request: &str) -> String {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            request.len(),
+            request
+        )
+        .unwrap();
+        stream.read_to_string(&mut response).unwrap();
+
+        response.split("\r\n\r\n").nth(1).unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_serve() {
+        let tmp = tempfile::tempdir().unwrap();
+        let network = Network::Regtest;
+        let mut client: Client<Reactor> = Client::new(Config {
+            datadir: Some(tmp.path().to_path_buf()),
+            network,
+            connect_only: true,
+            ..Config::default()
+        })
+        .unwrap();
+        let handle = client.handle();
+        let events = handle.events();
+
+        thread::spawn(move || {
+            client
+                .run_with_stores(
+                    store::Memory::genesis(network),
+                    store::Memory::genesis(network),
+                    bodies::Memory::default(),
+                )
+                .unwrap();
+        });
+        event::wait(
+            &events,
+            |e| match e {
+                Event::Listening(_) => Some(()),
+                _ => None,
+            },
+            std::time::Duration::from_secs(2),
+        )
+        .unwrap();
+
+        let addr = serve("127.0.0.1:0", handle).unwrap();
+
+        assert_eq!(
+            call(
+                addr,
+                r#"{"jsonrpc":"2.0","id":1,"method":"gettip","params":[]}"#
+            ),
+            format!(
+                r#"{{"id":1,"jsonrpc":"2.0","result":{{"hash":"{}","height":0}}}}"#,
+                network.genesis_hash()
+            )
+        );
+        assert_eq!(
+            call(addr, r#"{"jsonrpc":"2.0","id":2,"method":"getpeers"}"#),
+            r#"{"id":2,"jsonrpc":"2.0","result":[]}"#
+        );
+        assert_eq!(
+            call(
+                addr,
+                r#"{"jsonrpc":"2.0","id":3,"method":"watchaddress","params":["bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"]}"#
+            ),
+            r#"{"id":3,"jsonrpc":"2.0","result":null}"#
+        );
+        assert_eq!(
+            call(
+                addr,
+                r#"{"jsonrpc":"2.0","id":4,"method":"rescan","params":[0]}"#
+            ),
+            r#"{"id":4,"jsonrpc":"2.0","result":null}"#
+        );
+        assert_eq!(
+            call(
+                addr,
+                r#"{"jsonrpc":"2.0","id":5,"method":"rescan","params":[0]}"#
+            ),
+            r#"{"error":{"code":-32603,"message":"a rescan is already active"},"id":5,"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(
+            call(addr, r#"{"jsonrpc":"2.0","id":6,"method":"getblock"}"#),
+            r#"{"error":{"code":-32601,"message":"method `getblock` not found"},"id":6,"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(
+            call(addr, "{"),
+            r#"{"error":{"code":-32700,"message":"parse error"},"id":null,"jsonrpc":"2.0"}"#
+        );
+    }
+}
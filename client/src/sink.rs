@@ -0,0 +1,164 @@
+//! Event sinks.
+//!
+//! An event sink receives every protocol event synchronously, before the event is published
+//! to subscribers. This is meant for embedded indexers that persist events transactionally
+//! with their own data: by the time a subscriber sees an event, the indexer has stored it.
+//!
+//! Sinks run on the client thread, and hold up the protocol while they do. Each call is
+//! therefore given a deadline, and a sink that overruns it is dropped: it doesn't receive
+//! any further events.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time;
+
+use nakamoto_p2p::event::Event;
+
+/// Default time budget, per event, for all sinks combined.
+pub const DEFAULT_BUDGET: time::Duration = time::Duration::from_millis(10);
+
+/// An error returned by an event sink.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A synchronous consumer of protocol events.
+pub trait EventSink: Send + Sync {
+    /// Receive an event. Implementations should return before the deadline, eg. by keeping
+    /// transactions small, or be dropped. An error returned here is logged, and doesn't stop
+    /// the client.
+    fn receive(&self, event: &Event, deadline: time::Instant) -> Result<(), Error>;
+}
+
+/// A registered sink.
+struct Registered {
+    sink: Box<dyn EventSink>,
+    /// Whether the sink was dropped for overrunning its deadline.
+    dropped: AtomicBool,
+}
+
+/// The event sinks registered with a client.
+#[derive(Clone)]
+pub struct Sinks {
+    /// Time budget, per event, for all sinks combined.
+    pub budget: time::Duration,
+
+    sinks: Vec<Arc<Registered>>,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Self {
+            budget: DEFAULT_BUDGET,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Sinks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sinks")
+            .field("budget", &self.budget)
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+impl Sinks {
+    /// Register a sink. Sinks receive events in the order they are registered.
+    pub fn register(&mut self, sink: impl EventSink + 'static) {
+        self.sinks.push(Arc::new(Registered {
+            sink: Box::new(sink),
+            dropped: AtomicBool::new(false),
+        }));
+    }
+
+    /// Check whether there are no sinks registered.
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Pass an event to all sinks. Returns `false` if the time budget was exceeded, in
+    /// which case the sink that overran the deadline is dropped.
+    pub fn receive(&self, event: &Event) -> bool {
+        if self.sinks.is_empty() {
+            return true;
+        }
+        let start = time::Instant::now();
+        let deadline = start + self.budget;
+        let mut exceeded = false;
+
+        for registered in self.sinks.iter() {
+            if registered.dropped.load(Ordering::Relaxed) {
+                continue;
+            }
+            let called = time::Instant::now();
+
+            if let Err(err) = registered.sink.receive(event, deadline) {
+                log::error!("Event sink error: {}", err);
+            }
+            let now = time::Instant::now();
+
+            // Only the sink that overran the deadline is dropped, not the ones after it.
+            if now > deadline && called <= deadline {
+                log::error!(
+                    "Event sink overran the time budget of {:?} ({:?} elapsed), dropping it",
+                    self.budget,
+                    now - start
+                );
+                registered.dropped.store(true, Ordering::Relaxed);
+            }
+            exceeded |= now > deadline;
+        }
+        !exceeded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use nakamoto_p2p::protocol::syncmgr;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+        delay: time::Duration,
+    }
+
+    impl EventSink for Recorder {
+        fn receive(&self, event: &Event, _deadline: time::Instant) -> Result<(), Error> {
+            std::thread::sleep(self.delay);
+            self.events.lock().unwrap().push(format!("{:?}", event));
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sinks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let event = Event::SyncManager(syncmgr::Event::Synced(Default::default(), 1));
+        let mut sinks = Sinks::default();
+
+        assert!(sinks.receive(&event));
+
+        sinks.register(Recorder {
+            events: events.clone(),
+            ..Recorder::default()
+        });
+        assert!(sinks.receive(&event));
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        sinks.register(Recorder {
+            events: events.clone(),
+            delay: sinks.budget * 2,
+        });
+        assert!(!sinks.receive(&event), "the budget is exceeded");
+        assert_eq!(events.lock().unwrap().len(), 3);
+
+        // The slow sink was dropped, the other one still receives events.
+        assert!(sinks.receive(&event));
+        assert_eq!(events.lock().unwrap().len(), 4);
+    }
+}
@@ -4,30 +4,30 @@ use std::thread;
 use std::time;
 
 use bitcoin::network::constants::ServiceFlags;
+use crossbeam_channel as chan;
 use nakamoto_chain::block::cache::BlockCache;
 use nakamoto_chain::block::store;
 use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_common::block::Height;
 use nakamoto_common::network::Services;
-use nakamoto_p2p::protocol::syncmgr;
+use nakamoto_net_poll::transport;
+use nakamoto_p2p::protocol::{connmgr, syncmgr, Command};
 use nakamoto_test::{logger, BITCOIN_HEADERS};
 
 use crate::client::{self, event, Client, Config, Event};
 use crate::error;
-use crate::handle::Handle as _;
+use crate::handle::{self, Handle as _};
 
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
+type MemoryReactor = nakamoto_net_poll::Reactor<transport::Memory, client::Publisher>;
 
-fn network(
+fn network<R>(
     cfgs: &[Config],
-) -> Result<
-    Vec<(
-        client::Handle<Reactor>,
-        net::SocketAddr,
-        thread::JoinHandle<()>,
-    )>,
-    error::Error,
-> {
+) -> Result<Vec<(client::Handle<R>, net::SocketAddr, thread::JoinHandle<()>)>, error::Error>
+where
+    R: nakamoto_p2p::reactor::Reactor<client::Publisher> + Send + 'static,
+    R::Waker: Sync,
+{
     let mut handles = Vec::new();
 
     for cfg in cfgs.iter().cloned() {
@@ -35,7 +35,7 @@ fn network(
         let genesis = cfg.network.genesis();
         let params = cfg.network.params();
 
-        let node = Client::new(cfg)?;
+        let mut node = Client::new(cfg)?;
         let handle = node.handle();
         let events = handle.events();
 
@@ -87,7 +87,7 @@ fn test_full_sync() {
         }
     }
 
-    let nodes = network(&[config("olive"), config("alice"), config("misha")]).unwrap();
+    let nodes = network::<Reactor>(&[config("olive"), config("alice"), config("misha")]).unwrap();
     let (handle, _, _) = nodes.last().unwrap();
     let headers = BITCOIN_HEADERS.tail.clone();
     let height = headers.len() as Height;
@@ -124,7 +124,7 @@ fn test_wait_for_peers() {
         5
     ];
 
-    let nodes = network(&cfgs).unwrap();
+    let nodes = network::<Reactor>(&cfgs).unwrap();
     let (handle, _, _) = nodes.first().unwrap();
 
     handle
@@ -132,6 +132,366 @@ fn test_wait_for_peers() {
         .unwrap();
 }
 
+#[test]
+fn test_memory_transport() {
+    logger::init(log::Level::Debug);
+
+    let cfgs = vec![
+        Config {
+            services: ServiceFlags::NETWORK,
+            listen: vec![([127, 0, 0, 1], 0).into()],
+            ..Default::default()
+        };
+        3
+    ];
+
+    let nodes = network::<MemoryReactor>(&cfgs).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+
+    handle
+        .wait_for_peers(nodes.len() - 1, Services::Chain)
+        .unwrap();
+}
+
+#[test]
+fn test_fetch_block_not_connected() {
+    logger::init(log::Level::Debug);
+
+    let cfg = Config {
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        ..Default::default()
+    };
+    let genesis = cfg.network.genesis_hash();
+    let nodes = network::<Reactor>(&[cfg]).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let mut handle = handle.clone();
+
+    handle.set_timeout(time::Duration::from_millis(100));
+
+    // With no peers to request the block from, the reason is reported once we give up.
+    match handle.fetch_block(&genesis) {
+        Err(handle::Error::Command(err)) => {
+            assert_eq!(
+                err.to_string(),
+                "not connected to any peer with the required services"
+            );
+        }
+        result => panic!("unexpected result {:?}", result.map(|(_, h)| h)),
+    }
+}
+
+/// A peer that completes the handshake, and replies to requests for the given block,
+/// unless `serve` returns `false`. The blocks it's asked for are sent on the returned
+/// channel, along with the peer's address.
+fn block_peer(
+    cfg: &Config,
+    block: bitcoin::Block,
+    serve: impl Fn() -> bool + Send + 'static,
+) -> (
+    net::SocketAddr,
+    chan::Receiver<(net::SocketAddr, bitcoin::BlockHash)>,
+) {
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::address::Address;
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use bitcoin::network::message_blockdata::Inventory;
+    use bitcoin::network::message_network::VersionMessage;
+    use bitcoin::network::stream_reader::StreamReader;
+    use std::io::Write as _;
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let magic = cfg.network.magic();
+    let (requests, receive) = chan::unbounded();
+
+    thread::spawn(move || {
+        let (conn, remote) = listener.accept().unwrap();
+        let mut writer = conn.try_clone().unwrap();
+        let mut reader = StreamReader::new(conn, None);
+        let mut send = |payload| {
+            let msg = RawNetworkMessage { magic, payload };
+            writer.write_all(&serialize(&msg))
+        };
+
+        while let Ok(msg) = reader.read_next::<RawNetworkMessage>() {
+            let result = match msg.payload {
+                NetworkMessage::Version(_) => {
+                    let timestamp = time::SystemTime::now()
+                        .duration_since(time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs() as i64;
+                    let version = VersionMessage {
+                        version: nakamoto_p2p::protocol::PROTOCOL_VERSION,
+                        ..VersionMessage::new(
+                            ServiceFlags::NETWORK,
+                            timestamp,
+                            Address::new(&remote, ServiceFlags::NONE),
+                            Address::new(&addr, ServiceFlags::NETWORK),
+                            fastrand::u64(..),
+                            "/block-peer/".to_owned(),
+                            0,
+                        )
+                    };
+                    send(NetworkMessage::Version(version)).and(send(NetworkMessage::Verack))
+                }
+                NetworkMessage::Ping(nonce) => send(NetworkMessage::Pong(nonce)),
+                NetworkMessage::GetData(inv) => {
+                    for hash in inv.iter().filter_map(|i| match i {
+                        Inventory::Block(hash) => Some(*hash),
+                        _ => None,
+                    }) {
+                        requests.send((addr, hash)).ok();
+
+                        if hash == block.block_hash() && serve() {
+                            send(NetworkMessage::Block(block.clone())).ok();
+                        }
+                    }
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+    (addr, receive)
+}
+
+#[test]
+fn test_fetch_block() {
+    logger::init(log::Level::Debug);
+
+    let cfg = Config {
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        ..Default::default()
+    };
+    let block = cfg.network.genesis_block();
+    let nodes = network::<Reactor>(std::slice::from_ref(&cfg)).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let (peer, requests) = block_peer(&cfg, block.clone(), || true);
+
+    handle.connect(peer).unwrap();
+    handle.wait_for_peers(1, Services::Chain).unwrap();
+
+    let (received, height) = handle.fetch_block(&block.block_hash()).unwrap();
+    assert_eq!(received, block);
+    assert_eq!(height, 0);
+    assert_eq!(requests.try_recv().unwrap(), (peer, block.block_hash()));
+}
+
+/// Test that a block that isn't received is requested again, from another peer.
+#[test]
+fn test_fetch_block_retry() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    logger::init(log::Level::Debug);
+
+    let cfg = Config {
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        ..Default::default()
+    };
+    let block = cfg.network.genesis_block();
+    let nodes = network::<Reactor>(std::slice::from_ref(&cfg)).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let mut handle = handle.clone();
+    // Whichever peer is asked first doesn't reply.
+    let ignored = Arc::new(AtomicBool::new(false));
+    let peers = (0..2)
+        .map(|_| {
+            let ignored = ignored.clone();
+            block_peer(&cfg, block.clone(), move || {
+                ignored.swap(true, Ordering::SeqCst)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (peer, _) in &peers {
+        handle.connect(*peer).unwrap();
+    }
+    handle.wait_for_peers(2, Services::Chain).unwrap();
+    handle.set_timeout(client::BLOCK_RETRY_TIMEOUT * 2);
+
+    let (received, _) = handle.fetch_block(&block.block_hash()).unwrap();
+    assert_eq!(received, block);
+
+    let requests = peers
+        .iter()
+        .flat_map(|(_, requests)| requests.try_iter())
+        .collect::<Vec<_>>();
+    assert_eq!(requests.len(), 2);
+    assert_ne!(
+        requests[0].0, requests[1].0,
+        "the block is asked from both peers"
+    );
+}
+
+#[test]
+fn test_socks5_proxy() {
+    use std::io::{self, Read as _, Write as _};
+
+    logger::init(log::Level::Debug);
+
+    // A SOCKS5 proxy that relays a single connection, reporting the user name it was
+    // authenticated with.
+    fn proxy(listener: net::TcpListener) -> io::Result<String> {
+        let (mut conn, _) = listener.accept()?;
+        let mut buf = [0; 255];
+
+        conn.read_exact(&mut buf[..3])?;
+        assert_eq!(&buf[..3], &[5, 1, 2], "authentication is required");
+        conn.write_all(&[5, 2])?;
+
+        conn.read_exact(&mut buf[..2])?;
+        let mut username = vec![0; buf[1] as usize];
+        conn.read_exact(&mut username)?;
+        conn.read_exact(&mut buf[..1])?;
+        let len = buf[0] as usize;
+        conn.read_exact(&mut buf[..len])?;
+        conn.write_all(&[1, 0])?;
+
+        conn.read_exact(&mut buf[..10])?;
+        assert_eq!(&buf[..4], &[5, 1, 0, 1], "connecting to an IPv4 address");
+        let port = u16::from_be_bytes([buf[8], buf[9]]);
+        let remote = net::TcpStream::connect(("127.0.0.1", port))?;
+        conn.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+        let (mut rx, mut tx) = (conn.try_clone()?, remote.try_clone()?);
+        thread::spawn(move || io::copy(&mut rx, &mut tx));
+        thread::spawn(move || io::copy(&mut { remote }, &mut { conn }));
+
+        Ok(String::from_utf8(username).unwrap())
+    }
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let proxy = thread::spawn(move || proxy(listener).unwrap());
+
+    let cfgs = [
+        Config {
+            listen: vec![([127, 0, 0, 1], 0).into()],
+            ..Config::default()
+        },
+        Config {
+            listen: vec![],
+            proxy: Some(proxy_addr),
+            ..Config::default()
+        },
+    ];
+    let mut nodes = network::<Reactor>(&cfgs[..1]).unwrap();
+    let (_, bob, _) = nodes.pop().unwrap();
+
+    let mut alice = Client::<Reactor>::new(cfgs[1].clone()).unwrap();
+    let handle = alice.handle();
+    let genesis = cfgs[1].network.genesis();
+    let params = cfgs[1].network.params();
+
+    thread::spawn(move || {
+        let store = store::Memory::new((genesis, vec![]).into());
+        let cache = BlockCache::from(store, params, &[]).unwrap();
+        let filters = FilterCache::from(store::Memory::default()).unwrap();
+
+        alice.run_with(cache, filters, HashMap::new()).unwrap();
+    });
+
+    // We're connected to the peer, though our connection is to the proxy.
+    handle.connect(bob).unwrap();
+    assert!(!proxy.join().unwrap().is_empty());
+}
+
+#[test]
+fn test_connect_timeout() {
+    logger::init(log::Level::Debug);
+
+    // A proxy that accepts connections, but never completes the handshake, so that
+    // connection attempts through it hang.
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let conns = listener.incoming().collect::<Vec<_>>();
+        drop(conns);
+    });
+
+    let cfg = Config {
+        listen: vec![],
+        proxy: Some(proxy),
+        connect_timeout: time::Duration::from_secs(1),
+        ..Config::default()
+    };
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let mut client = Client::<Reactor>::new(cfg).unwrap();
+    let handle = client.handle();
+    let events = handle.events();
+
+    thread::spawn(move || {
+        let store = store::Memory::new((genesis, vec![]).into());
+        let cache = BlockCache::from(store, params, &[]).unwrap();
+        let filters = FilterCache::from(store::Memory::default()).unwrap();
+
+        client.run_with(cache, filters, HashMap::new()).unwrap();
+    });
+
+    // The attempt is given up on by the reactor, well before the connection manager's
+    // next idle check.
+    let peer: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+    handle.command(Command::Connect(peer)).unwrap();
+
+    event::wait(
+        &events,
+        |e| match e {
+            Event::ConnManager(connmgr::Event::Disconnected(addr)) if addr == peer => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(3),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_proxy_no_dns_seeds() {
+    use nakamoto_chain::filter::store::bodies;
+    use nakamoto_common::p2p::peer::Store as _;
+
+    let tmp = tempfile::tempdir().unwrap();
+    // Nothing listens on the proxy address: we don't connect to anyone.
+    let proxy = net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let cfg = Config {
+        datadir: Some(tmp.path().to_path_buf()),
+        network: nakamoto_common::network::Network::Mainnet,
+        listen: vec![],
+        proxy: Some(proxy),
+        ..Config::default()
+    };
+    assert!(cfg.dns_seeds().is_empty());
+
+    let network = cfg.network;
+    let mut client: Client<Reactor> = Client::new(cfg).unwrap();
+    let handle = client.handle();
+
+    let node = thread::spawn(move || {
+        client
+            .run_with_stores(
+                store::Memory::genesis(network),
+                store::Memory::genesis(network),
+                bodies::Memory::default(),
+            )
+            .unwrap();
+    });
+
+    // The address book is empty, yet DNS seeds aren't resolved, on startup or later.
+    assert_eq!(handle.find_peers().unwrap(), 0);
+    handle.shutdown().unwrap();
+    node.join().unwrap();
+
+    let peers = crate::peer::Cache::open(tmp.path().join("peers.json")).unwrap();
+    assert!(peers.is_empty());
+}
+
 #[test]
 fn test_send_handle() {
     let cfg = Config::default();
@@ -144,6 +504,24 @@ fn test_send_handle() {
     });
 }
 
+#[test]
+fn test_protocol_config() {
+    let cfg = Config {
+        required_services: ServiceFlags::NETWORK_LIMITED,
+        filter_services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
+        min_filter_peers: 3,
+        ..Config::default()
+    };
+    let protocol: nakamoto_p2p::protocol::Config = cfg.into();
+
+    assert_eq!(protocol.required_services, ServiceFlags::NETWORK_LIMITED);
+    assert_eq!(
+        protocol.filter_services,
+        ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK
+    );
+    assert_eq!(protocol.min_filter_peers, 3);
+}
+
 #[test]
 fn test_multiple_handle_events() {
     use std::time;
@@ -151,7 +529,7 @@ fn test_multiple_handle_events() {
     let cfg = Config::default();
     let genesis = cfg.network.genesis();
     let params = cfg.network.params();
-    let client: Client<Reactor> = Client::new(cfg).unwrap();
+    let mut client: Client<Reactor> = Client::new(cfg).unwrap();
     let store = store::Memory::new((genesis, vec![]).into());
     let cache = BlockCache::from(store, params, &[]).unwrap();
     let filters = FilterCache::from(store::Memory::default()).unwrap();
@@ -162,7 +540,7 @@ fn test_multiple_handle_events() {
     let alice_events = alice.events();
     let bob_events = bob.events();
 
-    thread::spawn(|| {
+    thread::spawn(move || {
         client.run_with(cache, filters, peers).unwrap();
     });
 
@@ -186,3 +564,215 @@ fn test_multiple_handle_events() {
     )
     .unwrap();
 }
+
+#[test]
+fn test_run_with_stores() {
+    use nakamoto_chain::filter::store::bodies;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        datadir: Some(tmp.path().to_path_buf()),
+        network: nakamoto_common::network::Network::Regtest,
+        connect_only: true,
+        ..Config::default()
+    };
+    let network = cfg.network;
+    let mut client: Client<Reactor> = Client::new(cfg).unwrap();
+    let handle = client.handle();
+    let events = handle.events();
+
+    thread::spawn(move || {
+        client
+            .run_with_stores(
+                store::Memory::genesis(network),
+                store::Memory::genesis(network),
+                bodies::Memory::default(),
+            )
+            .unwrap();
+    });
+
+    event::wait(
+        &events,
+        |e| match e {
+            Event::Listening(_) => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(2),
+    )
+    .unwrap();
+
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+}
+
+#[test]
+fn test_file_stores() {
+    use bitcoin::hashes::Hash as _;
+    use nakamoto_chain::filter::cache::StoredHeader;
+    use nakamoto_chain::filter::store::bodies::{self, Bodies as _};
+    use nakamoto_common::block::filter::{FilterHash, FilterHeader, Filters as _};
+    use nakamoto_common::block::store::{Genesis as _, Store as _};
+    use nakamoto_test::block::gen;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        datadir: Some(tmp.path().to_path_buf()),
+        network: nakamoto_common::network::Network::Regtest,
+        listen: vec![],
+        connect_only: true,
+        ..Config::default()
+    };
+    let network = cfg.network;
+    let dir = cfg.dir();
+    let mut client: Client<Reactor> = Client::new(cfg).unwrap();
+    let handle = client.handle();
+    let (restart, restarted) = crossbeam_channel::bounded(1);
+
+    let node = thread::spawn(move || {
+        client.run().unwrap();
+        restarted.recv().unwrap();
+        client.run().unwrap();
+    });
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+    handle.clone().shutdown().unwrap();
+
+    // Store a block, its filter header and its filter, and check that they are all still
+    // there once the client was run again, ie. that each store is kept in its own file.
+    let block = gen::block(&network.genesis(), &mut fastrand::Rng::with_seed(1));
+    store::File::open(dir.join("headers.db"), network.genesis())
+        .unwrap()
+        .put(std::iter::once(block.header))
+        .unwrap();
+
+    let genesis = StoredHeader::genesis(network);
+    let filter = gen::cfilter(&block);
+    let hash = FilterHash::hash(&filter.content);
+    let header = hash.filter_header(&FilterHeader::genesis(network));
+    let mut cache =
+        FilterCache::from(store::File::open(dir.join("filters.db"), genesis).unwrap()).unwrap();
+    cache.import_headers(vec![(hash, header)]).unwrap();
+    bodies::File::open(dir.join("cfilters.db"))
+        .unwrap()
+        .put(1, &filter)
+        .unwrap();
+
+    let events = handle.events();
+    restart.send(()).unwrap();
+    event::wait(
+        &events,
+        |e| match e {
+            Event::NodeStarted(_) => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(2),
+    )
+    .unwrap();
+    handle.shutdown().unwrap();
+    node.join().unwrap();
+
+    let headers = store::File::open(dir.join("headers.db"), network.genesis()).unwrap();
+    assert_eq!(headers.get(1).unwrap(), block.header);
+
+    let cache =
+        FilterCache::from(store::File::open(dir.join("filters.db"), genesis).unwrap()).unwrap();
+    assert_eq!(cache.get_header(1), Some((hash, header)));
+
+    let bodies = bodies::File::open(dir.join("cfilters.db")).unwrap();
+    assert_eq!(bodies.get(1).unwrap().unwrap().content, filter.content);
+}
+
+#[test]
+fn test_restart_stale_commands() {
+    use nakamoto_chain::filter::store::bodies;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        datadir: Some(tmp.path().to_path_buf()),
+        network: nakamoto_common::network::Network::Regtest,
+        listen: vec![],
+        connect_only: true,
+        ..Config::default()
+    };
+    let network = cfg.network;
+    let mut client: Client<Reactor> = Client::new(cfg).unwrap();
+    let handle = client.handle();
+    let (restart, restarted) = crossbeam_channel::bounded(1);
+
+    let node = thread::spawn(move || {
+        let mut run = || {
+            client
+                .run_with_stores(
+                    store::Memory::genesis(network),
+                    store::Memory::genesis(network),
+                    bodies::Memory::default(),
+                )
+                .unwrap()
+        };
+        run();
+        restarted.recv().unwrap();
+        run();
+    });
+
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+    handle.clone().shutdown().unwrap();
+
+    // Commands issued while the node is stopped are issued against its first run, and are
+    // rejected once it is started again, whether they expect a reply or not.
+    let is_stale = |result: Result<(), handle::Error>| {
+        matches!(
+            result,
+            Err(handle::Error::Stale {
+                epoch: 0,
+                current: 1
+            })
+        )
+    };
+    let get_tip = {
+        let handle = handle.clone();
+        thread::spawn(move || is_stale(handle.get_tip().map(|_| ())))
+    };
+    let unban = {
+        let handle = handle.clone();
+        thread::spawn(move || is_stale(handle.unban([88, 88, 88, 88].into())))
+    };
+    thread::sleep(time::Duration::from_millis(100));
+    restart.send(()).unwrap();
+
+    assert!(get_tip.join().unwrap());
+    assert!(unban.join().unwrap());
+
+    // Commands issued after the restart are executed.
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+    handle.shutdown().unwrap();
+    node.join().unwrap();
+}
+
+#[test]
+fn test_rescan_handle() {
+    let cfg = Config {
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        services: ServiceFlags::NETWORK,
+        ..Config::default()
+    };
+    let nodes = network::<MemoryReactor>(&[cfg]).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+
+    // With no end height, the rescan keeps going until aborted.
+    let mut rescan = handle.rescan(0.., vec![]).unwrap();
+    assert!(matches!(
+        rescan.wait_timeout(time::Duration::from_millis(100)),
+        Err(handle::Error::Timeout)
+    ));
+    assert_eq!(rescan.status().unwrap().map(|s| s.start), Some(0));
+    assert!(
+        handle.rescan(0.., vec![]).is_err(),
+        "a rescan is already active"
+    );
+
+    rescan.abort().unwrap();
+    assert_eq!(
+        rescan.wait().unwrap(),
+        handle::RescanOutcome::Aborted { height: 0 }
+    );
+    assert_eq!(rescan.status().unwrap(), None);
+    assert_eq!(handle.rescan_status().unwrap(), None);
+}
@@ -0,0 +1,579 @@
+//! ZeroMQ event publisher.
+//!
+//! Publishes a subset of client events on a ZeroMQ `PUB` socket, so that programs written in
+//! other languages, eg. GUIs and scripts, can follow the client without linking this crate.
+//! Any ZeroMQ `SUB` socket can connect to the publisher and subscribe to one or more topics.
+//!
+//! Every notification is a three-part message, in the style of Bitcoin Core:
+//!
+//! 1. The topic, eg. `tip`.
+//! 2. A JSON object describing the event, encoded in UTF-8.
+//! 3. A sequence number, as a 4-byte little-endian integer, incremented with every message
+//!    published on the topic. A gap in the sequence means messages were missed.
+//!
+//! The topics, and the fields of their JSON objects, are:
+//!
+//! * `tip`: the active chain has a new tip.
+//!   - `hash`: hash of the new tip, in hex.
+//!   - `height`: height of the new tip.
+//!   - `reverted`: hashes of the blocks reverted by a re-org, if any, in hex.
+//! * `filtermatch`: a compact filter matched a watched script or outpoint.
+//!   - `block`: hash of the matching block, in hex.
+//!   - `height`: height of the matching block.
+//!   - `watchlists`: identifiers of the watchlists with a matching script.
+//! * `txconfirmed`: a transaction paying to a watched script, or spending a watched output,
+//!   was found in a block. This may be published more than once for the same transaction,
+//!   eg. if it pays to several watched scripts.
+//!   - `txid`: the transaction id, in hex.
+//!   - `height`: height of the block the transaction was found in.
+//!
+//! The publisher speaks ZMTP 3.0, with the `NULL` security mechanism. Messages are queued
+//! for each subscriber and written on a thread of their own, so that the client never waits
+//! on a subscriber. Subscribers that fall too far behind are disconnected.
+//!
+//! The publisher is registered with the client as an event sink:
+//!
+//! ```no_run
+//! use nakamoto_client::{zmq, Config};
+//!
+//! let mut cfg = Config::default();
+//! let publisher = zmq::Publisher::bind("127.0.0.1:28332").unwrap();
+//!
+//! cfg.sinks.register(publisher);
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crossbeam_channel as chan;
+use microserde::json::{self, Array, Number, Object, Value};
+
+use nakamoto_common::block::tree::ImportResult;
+use nakamoto_common::block::Height;
+use nakamoto_p2p::event::Event;
+use nakamoto_p2p::protocol::{spvmgr, syncmgr};
+
+use crate::sink::{self, EventSink};
+
+/// Topic of new tip notifications.
+pub const TOPIC_TIP: &str = "tip";
+/// Topic of filter match notifications.
+pub const TOPIC_FILTER_MATCH: &str = "filtermatch";
+/// Topic of transaction confirmation notifications.
+pub const TOPIC_TX_CONFIRMED: &str = "txconfirmed";
+
+/// Time after which a subscriber that isn't reading its messages is dropped.
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+/// Maximum number of messages queued for a subscriber. Subscribers with a full queue are
+/// dropped.
+const QUEUE_CAPACITY: usize = 256;
+/// Maximum size of a frame received from a subscriber.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// More frames follow in the same message.
+const FLAG_MORE: u8 = 0x1;
+/// The frame size is encoded on eight bytes.
+const FLAG_LONG: u8 = 0x2;
+/// The frame is a command, not a message.
+const FLAG_COMMAND: u8 = 0x4;
+
+/// A connected subscriber.
+struct Subscriber {
+    addr: net::SocketAddr,
+    stream: net::TcpStream,
+    /// Messages waiting to be written to the subscriber.
+    queue: chan::Sender<Arc<Vec<u8>>>,
+    /// Topic prefixes subscribed to.
+    topics: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Subscriber {
+    /// Check whether the subscriber is interested in the given topic.
+    fn is_subscribed(&self, topic: &[u8]) -> bool {
+        self.topics
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|prefix| topic.starts_with(prefix))
+    }
+}
+
+/// Publishes client events on a ZeroMQ `PUB` socket. See the module documentation for the
+/// message format.
+#[derive(Clone)]
+pub struct Publisher {
+    local_addr: net::SocketAddr,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    sequences: Arc<Mutex<HashMap<&'static str, u32>>>,
+}
+
+impl fmt::Debug for Publisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Publisher")
+            .field("local_addr", &self.local_addr)
+            .field("subscribers", &self.subscribers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Publisher {
+    /// Listen for subscribers on the given address. Subscribers are accepted on a background
+    /// thread, until the listener fails.
+    pub fn bind(addr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        log::info!("Publishing events over ZeroMQ on {}", local_addr);
+
+        thread::spawn({
+            let subscribers = subscribers.clone();
+            move || accept(listener, subscribers)
+        });
+
+        Ok(Self {
+            local_addr,
+            subscribers,
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Get the address subscribers can connect to.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Publish a notification to all subscribers of the topic, without waiting for them.
+    /// Subscribers that can't keep up are dropped.
+    fn publish(&self, topic: &'static str, body: &Value) {
+        let sequence = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let next = sequences.entry(topic).or_insert(0);
+            let sequence = *next;
+
+            *next = next.wrapping_add(1);
+            sequence
+        };
+        let body = json::to_string(body);
+        let message = Arc::new(encode_message(&[
+            topic.as_bytes(),
+            body.as_bytes(),
+            &sequence.to_le_bytes()[..],
+        ]));
+
+        self.subscribers.lock().unwrap().retain(|sub| {
+            if !sub.is_subscribed(topic.as_bytes()) {
+                return true;
+            }
+            match sub.queue.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(chan::TrySendError::Full(_)) => {
+                    log::warn!("Dropping ZeroMQ subscriber {}: too slow", sub.addr);
+                    sub.stream.shutdown(net::Shutdown::Both).ok();
+
+                    false
+                }
+                Err(chan::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl EventSink for Publisher {
+    fn receive(&self, event: &Event, _deadline: time::Instant) -> Result<(), sink::Error> {
+        if let Some((topic, body)) = notification(event) {
+            self.publish(topic, &body);
+        }
+        Ok(())
+    }
+}
+
+/// Get the notification to publish for an event, if any.
+fn notification(event: &Event) -> Option<(&'static str, Value)> {
+    let mut obj = Object::new();
+
+    let topic = match event {
+        Event::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
+            _,
+            hash,
+            height,
+            reverted,
+        ))) => {
+            let reverted = reverted
+                .iter()
+                .map(|h| Value::String(h.to_string()))
+                .collect::<Array>();
+
+            obj.insert("hash".to_owned(), Value::String(hash.to_string()));
+            obj.insert("height".to_owned(), height_value(*height));
+            obj.insert("reverted".to_owned(), Value::Array(reverted));
+
+            TOPIC_TIP
+        }
+        Event::SpvManager(spvmgr::Event::FilterProcessed {
+            block,
+            height,
+            matched: true,
+            watchlists,
+            ..
+        }) => {
+            let watchlists = watchlists
+                .iter()
+                .map(|id| Value::Number(Number::U64(*id)))
+                .collect::<Array>();
+
+            obj.insert("block".to_owned(), Value::String(block.to_string()));
+            obj.insert("height".to_owned(), height_value(*height));
+            obj.insert("watchlists".to_owned(), Value::Array(watchlists));
+
+            TOPIC_FILTER_MATCH
+        }
+        Event::SpvManager(spvmgr::Event::UtxoAdded {
+            outpoint, height, ..
+        }) => {
+            obj.insert("txid".to_owned(), Value::String(outpoint.txid.to_string()));
+            obj.insert("height".to_owned(), height_value(*height));
+
+            TOPIC_TX_CONFIRMED
+        }
+        Event::SpvManager(spvmgr::Event::OutpointSpent { txid, height, .. })
+        | Event::SpvManager(spvmgr::Event::UtxoSpent { txid, height, .. }) => {
+            obj.insert("txid".to_owned(), Value::String(txid.to_string()));
+            obj.insert("height".to_owned(), height_value(*height));
+
+            TOPIC_TX_CONFIRMED
+        }
+        _ => return None,
+    };
+    Some((topic, Value::Object(obj)))
+}
+
+fn height_value(height: Height) -> Value {
+    Value::Number(Number::U64(height))
+}
+
+/// Accept subscribers until the listener fails.
+fn accept(listener: net::TcpListener, subscribers: Arc<Mutex<Vec<Subscriber>>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("ZeroMQ publisher error: {}", err);
+                return;
+            }
+        };
+        let subscribers = subscribers.clone();
+
+        thread::spawn(move || {
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+            if let Err(err) = serve(stream, addr, &subscribers) {
+                log::debug!("ZeroMQ subscriber {} disconnected: {}", addr, err);
+            }
+            subscribers.lock().unwrap().retain(|sub| sub.addr != addr);
+        });
+    }
+}
+
+/// Perform the handshake with a subscriber, and process its subscriptions until it
+/// disconnects.
+fn serve(
+    mut stream: net::TcpStream,
+    addr: net::SocketAddr,
+    subscribers: &Mutex<Vec<Subscriber>>,
+) -> io::Result<()> {
+    handshake(&mut stream)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let (queue, messages) = chan::bounded(QUEUE_CAPACITY);
+
+    thread::spawn({
+        let mut stream = stream.try_clone()?;
+
+        move || write(&mut stream, addr, messages)
+    });
+    subscribers.lock().unwrap().push(Subscriber {
+        addr,
+        stream: stream.try_clone()?,
+        queue,
+        topics: topics.clone(),
+    });
+
+    loop {
+        let (flags, body) = read_frame(&mut stream)?;
+
+        // Subscriptions are sent as messages in ZMTP 3.0, and as commands in ZMTP 3.1.
+        let (subscribe, topic) = if flags & FLAG_COMMAND != 0 {
+            if let Some(topic) = body.strip_prefix(b"\x09SUBSCRIBE") {
+                (true, topic)
+            } else if let Some(topic) = body.strip_prefix(b"\x06CANCEL") {
+                (false, topic)
+            } else {
+                continue;
+            }
+        } else {
+            match body.split_first() {
+                Some((1, topic)) => (true, topic),
+                Some((0, topic)) => (false, topic),
+                _ => continue,
+            }
+        };
+
+        let mut topics = topics.lock().unwrap();
+        if subscribe {
+            topics.push(topic.to_vec());
+        } else if let Some(ix) = topics.iter().position(|t| t == topic) {
+            topics.remove(ix);
+        }
+    }
+}
+
+/// Exchange greetings and `READY` commands with a subscriber.
+fn handshake(stream: &mut net::TcpStream) -> io::Result<()> {
+    let mut greeting = [0; 64];
+
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3; // Major version.
+    greeting[11] = 0; // Minor version.
+    greeting[12..16].copy_from_slice(b"NULL");
+
+    stream.write_all(&greeting)?;
+    stream.read_exact(&mut greeting)?;
+
+    if greeting[0] != 0xff || greeting[9] != 0x7f {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid greeting",
+        ));
+    }
+    if greeting[10] < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported ZMTP version {}", greeting[10]),
+        ));
+    }
+    if &greeting[12..32] != b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported security mechanism",
+        ));
+    }
+
+    let mut ready = b"\x05READY\x0bSocket-Type".to_vec();
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"PUB");
+
+    write_frame(stream, FLAG_COMMAND, &ready)?;
+
+    let (flags, body) = read_frame(stream)?;
+    if flags & FLAG_COMMAND == 0 || !body.starts_with(b"\x05READY") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected READY command",
+        ));
+    }
+    Ok(())
+}
+
+/// Write the messages queued for a subscriber, until it is dropped or disconnects.
+fn write(
+    stream: &mut net::TcpStream,
+    addr: net::SocketAddr,
+    messages: chan::Receiver<Arc<Vec<u8>>>,
+) {
+    for message in messages {
+        if let Err(err) = stream.write_all(&message) {
+            log::warn!("Dropping ZeroMQ subscriber {}: {}", addr, err);
+            stream.shutdown(net::Shutdown::Both).ok();
+
+            return;
+        }
+    }
+}
+
+/// Encode a multi-part message, so that it is written in a single write.
+fn encode_message(parts: &[&[u8]]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    for (i, part) in parts.iter().enumerate() {
+        let flags = if i + 1 < parts.len() { FLAG_MORE } else { 0 };
+        write_frame(&mut buf, flags, part).expect("writing to a vector never fails");
+    }
+    buf
+}
+
+fn write_frame<W: Write>(w: &mut W, flags: u8, body: &[u8]) -> io::Result<()> {
+    if body.len() > u8::MAX as usize {
+        w.write_all(&[flags | FLAG_LONG])?;
+        w.write_all(&(body.len() as u64).to_be_bytes())?;
+    } else {
+        w.write_all(&[flags, body.len() as u8])?;
+    }
+    w.write_all(body)
+}
+
+fn read_frame<R: Read>(r: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0; 1];
+    r.read_exact(&mut flags)?;
+
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0; 8];
+        r.read_exact(&mut size)?;
+
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0; 1];
+        r.read_exact(&mut size)?;
+
+        size[0] as u64
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", size),
+        ));
+    }
+    let mut body = vec![0; size as usize];
+    r.read_exact(&mut body)?;
+
+    Ok((flags, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_common::block::BlockHash;
+    use nakamoto_test::block::gen;
+
+    /// Connect a `SUB` socket to the publisher, and subscribe to the given topic.
+    fn subscribe(publisher: &Publisher, topic: &[u8]) -> net::TcpStream {
+        let mut stream = net::TcpStream::connect(publisher.local_addr()).unwrap();
+        let mut greeting = [0; 64];
+
+        greeting[0] = 0xff;
+        greeting[9] = 0x7f;
+        greeting[10] = 3;
+        greeting[12..16].copy_from_slice(b"NULL");
+
+        stream.write_all(&greeting).unwrap();
+        stream.read_exact(&mut greeting).unwrap();
+
+        let mut ready = b"\x05READY\x0bSocket-Type".to_vec();
+        ready.extend_from_slice(&3u32.to_be_bytes());
+        ready.extend_from_slice(b"SUB");
+
+        write_frame(&mut stream, FLAG_COMMAND, &ready).unwrap();
+        let (flags, body) = read_frame(&mut stream).unwrap();
+        assert_eq!(flags, FLAG_COMMAND);
+        assert!(body.ends_with(b"PUB"));
+
+        write_frame(&mut stream, 0, &[&[1], topic].concat()).unwrap();
+
+        // Wait for the subscription to be processed.
+        let deadline = time::Instant::now() + time::Duration::from_secs(2);
+        while !publisher
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.is_subscribed(topic))
+        {
+            assert!(time::Instant::now() < deadline, "subscription timed out");
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        stream
+    }
+
+    #[test]
+    fn test_publish() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let publisher = Publisher::bind("127.0.0.1:0").unwrap();
+        let mut tips = subscribe(&publisher, TOPIC_TIP.as_bytes());
+        let mut txs = subscribe(&publisher, b"tx");
+        let header = gen::genesis(&mut rng).header;
+        let hash = header.block_hash();
+        let txid = gen::transaction(&mut rng).txid();
+
+        for height in 1..=2 {
+            publisher.publish_event(Event::SyncManager(syncmgr::Event::HeadersImported(
+                ImportResult::TipChanged(header, hash, height, vec![BlockHash::default()]),
+            )));
+        }
+        publisher.publish_event(Event::SpvManager(spvmgr::Event::OutpointSpent {
+            outpoint: Default::default(),
+            txid,
+            height: 7,
+        }));
+
+        for sequence in 0..2u32 {
+            let (flags, topic) = read_frame(&mut tips).unwrap();
+            assert_eq!(flags, FLAG_MORE);
+            assert_eq!(topic, b"tip");
+
+            let (flags, body) = read_frame(&mut tips).unwrap();
+            assert_eq!(flags, FLAG_MORE);
+            assert_eq!(
+                String::from_utf8(body).unwrap(),
+                format!(
+                    r#"{{"hash":"{}","height":{},"reverted":["{}"]}}"#,
+                    hash,
+                    sequence + 1,
+                    BlockHash::default()
+                )
+            );
+
+            let (flags, seq) = read_frame(&mut tips).unwrap();
+            assert_eq!(flags, 0);
+            assert_eq!(seq, sequence.to_le_bytes());
+        }
+
+        let (_, topic) = read_frame(&mut txs).unwrap();
+        let (_, body) = read_frame(&mut txs).unwrap();
+        assert_eq!(topic, b"txconfirmed");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!(r#"{{"height":7,"txid":"{}"}}"#, txid)
+        );
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let publisher = Publisher::bind("127.0.0.1:0").unwrap();
+        // A subscriber that never reads its messages.
+        let _stalled = subscribe(&publisher, TOPIC_TIP.as_bytes());
+        let header = gen::genesis(&mut rng).header;
+        let hash = header.block_hash();
+        let reverted = vec![BlockHash::default(); 64];
+        let mut slowest = time::Duration::default();
+
+        // Publish until the subscriber's socket buffers and queue are full.
+        for height in 0..4096 {
+            let start = time::Instant::now();
+
+            publisher.publish_event(Event::SyncManager(syncmgr::Event::HeadersImported(
+                ImportResult::TipChanged(header, hash, height, reverted.clone()),
+            )));
+            slowest = slowest.max(start.elapsed());
+        }
+        // Publishing never waited on the subscriber, which was dropped instead.
+        assert!(slowest < WRITE_TIMEOUT, "publishing took {:?}", slowest);
+        assert!(publisher.subscribers.lock().unwrap().is_empty());
+    }
+
+    impl Publisher {
+        fn publish_event(&self, event: Event) {
+            self.receive(&event, time::Instant::now()).unwrap();
+        }
+    }
+}
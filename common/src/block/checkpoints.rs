@@ -24,5 +24,43 @@ pub const TESTNET: &[(u64, &str)] = &[
     (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
 ];
 
+/// Testnet4 checkpoints.
+pub const TESTNET4: &[(u64, &str)] = &[];
+
 /// Regtest checkpoints.
 pub const REGTEST: &[(u64, &str)] = &[];
+
+/// Compact filter header checkpoints, ie. the basic filter header of the block at certain
+/// heights, as returned in the `header` field of Bitcoin Core's `getblockfilter` RPC. These
+/// must only be taken from a trusted, fully validating node.
+pub mod filter {
+    /// Mainnet filter header checkpoints.
+    ///
+    /// None yet: until some are taken from a trusted node, filter headers are only checked
+    /// against the `cfcheckpt` checkpoints that peers agree on.
+    pub const MAINNET: &[(u64, &str)] = &[];
+
+    /// Testnet filter header checkpoints. Taken from the BIP 158 test vectors, which Bitcoin
+    /// Core checks its filter index against.
+    #[rustfmt::skip]
+    pub const TESTNET: &[(u64, &str)] = &[
+        (2,       "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0"),
+        (3,       "8d63aadf5ab7257cb6d2316a57b16f517bff1c6388f124ec4c04af1212729d2a"),
+        (15007,   "07384b01311867949e0c046607c66b7a766d338474bb67f66c8ae9dbd454b20e"),
+        (49291,   "b6d98692cec5145f67585f3434ec3c2b3030182e1cb3ec58b855c5c164dfaaa3"),
+        (180480,  "c582d51c0ca365e3fcf36c51cb646d7f83a67e867cb4743fd2128e3e022b700c"),
+        (926485,  "546c574a0472144bcaf9b6aeabf26372ad87c7af7d1ee0dbfae5e099abeae49c"),
+        (987876,  "0965a544743bbfa36f254446e75630c09404b3d164a261892372977538928ed5"),
+        (1263442, "4e6d564c2a2452065c205dd7eb2791124e0c4e0dbb064c410c24968572589dec"),
+        (1414221, "021e8882ef5a0ed932edeebbecfeda1d7ce528ec7b3daa27641acf1189d7b5dc"),
+    ];
+
+    /// Testnet4 filter header checkpoints.
+    ///
+    /// None yet: until some are taken from a trusted node, filter headers are only checked
+    /// against the `cfcheckpt` checkpoints that peers agree on.
+    pub const TESTNET4: &[(u64, &str)] = &[];
+
+    /// Regtest filter header checkpoints.
+    pub const REGTEST: &[(u64, &str)] = &[];
+}
@@ -1,14 +1,20 @@
 //! Compact block filter core types and traits.
 #![warn(missing_docs)]
 
+use std::convert::TryInto;
+use std::io;
 use std::ops::Range;
 
 use thiserror::Error;
 
+use bitcoin::consensus::encode::{Decodable, VarInt};
+use bitcoin::hashes::{siphash24, Hash};
+use bitcoin::util::bip158::{self, BitStreamReader};
+
 pub use bitcoin::hash_types::{FilterHash, FilterHeader};
 pub use bitcoin::util::bip158::BlockFilter;
 
-use super::Height;
+use super::{BlockHash, Height};
 use crate::block::store::{self, Genesis};
 use crate::network::Network;
 use crate::source;
@@ -42,6 +48,84 @@ impl Genesis for FilterHeader {
     }
 }
 
+/// Golomb-Rice coding parameter of basic block filters. See BIP 158.
+const FILTER_P: u8 = 19;
+/// Inverse of the false positive rate of basic block filters. See BIP 158.
+const FILTER_M: u64 = 784931;
+
+/// A block filter decoded for matching, so that any number of scripts can be matched
+/// against it without decoding it again.
+///
+/// [`BlockFilter::match_any`] decodes the filter, and hashes all the scripts, every time
+/// it's called. This adds up when a filter is matched against many watchlists, or against
+/// scripts one at a time. Here, the filter is decoded once, with its sip-hash keys derived
+/// from the block hash, and each script is hashed once and looked up.
+///
+/// The default matcher is empty, and doesn't match anything.
+#[derive(Debug, Clone, Default)]
+pub struct FilterMatcher {
+    k0: u64,
+    k1: u64,
+    /// Range filter values are mapped to, ie. the number of elements times [`FILTER_M`].
+    range: u64,
+    /// Filter values, in ascending order.
+    values: Vec<u64>,
+}
+
+impl FilterMatcher {
+    /// Decode the filter of the given block.
+    pub fn new(filter: &BlockFilter, block_hash: &BlockHash) -> Result<Self, bip158::Error> {
+        let key = block_hash.into_inner();
+        let k0 = u64::from_le_bytes(key[0..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(key[8..16].try_into().unwrap());
+
+        let mut reader = io::Cursor::new(&filter.content);
+        let n = VarInt::consensus_decode(&mut reader).map_or(0, |n| n.0);
+        // Each element takes at least `FILTER_P + 1` bits, which bounds the allocation for
+        // filters claiming more elements than they have.
+        let max = filter.content.len() as u64 * 8 / (FILTER_P as u64 + 1);
+        let mut values = Vec::with_capacity(n.min(max) as usize);
+        let mut bits = BitStreamReader::new(&mut reader);
+        let mut value = 0u64;
+
+        for _ in 0..n {
+            let mut q = 0u64;
+            while bits.read(1)? == 1 {
+                q += 1;
+            }
+            let r = bits.read(FILTER_P)?;
+
+            value = value.wrapping_add((q << FILTER_P) + r);
+            values.push(value);
+        }
+
+        Ok(Self {
+            k0,
+            k1,
+            range: n.wrapping_mul(FILTER_M),
+            values,
+        })
+    }
+
+    /// Check whether the filter matches the given script.
+    pub fn contains(&self, script: &[u8]) -> bool {
+        let hash = siphash24::Hash::hash_to_u64_with_keys(self.k0, self.k1, script);
+        let mapped = ((hash as u128 * self.range as u128) >> 64) as u64;
+
+        self.values.binary_search(&mapped).is_ok()
+    }
+
+    /// Check whether the filter matches any of the given scripts.
+    pub fn match_any<'a>(&self, query: impl IntoIterator<Item = &'a [u8]>) -> bool {
+        query.into_iter().any(|script| self.contains(script))
+    }
+
+    /// Check whether the filter matches all the given scripts.
+    pub fn match_all<'a>(&self, query: impl IntoIterator<Item = &'a [u8]>) -> bool {
+        query.into_iter().all(|script| self.contains(script))
+    }
+}
+
 /// An error related to the filters access.
 #[derive(Debug, Error)]
 pub enum Error {
@@ -77,4 +161,90 @@ pub trait Filters {
     }
     /// Rollback chain by the given number of headers.
     fn rollback(&mut self, n: usize) -> Result<(), Error>;
+    /// Get the block filter at the given height, if it was stored.
+    fn get_filter(&self, height: Height) -> Option<BlockFilter>;
+    /// Check whether the block filter at the given height is stored. This may be cheaper
+    /// than getting the filter.
+    fn has_filter(&self, height: Height) -> bool {
+        self.get_filter(height).is_some()
+    }
+    /// Store a block filter that was validated against its header. Implementations that
+    /// don't store filters may ignore it.
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), Error>;
+    /// Discard the filter headers and filters below the given height, eg. once blocks
+    /// below the wallet birth height no longer need to be scanned. The genesis and the
+    /// tip are always kept.
+    fn prune(&mut self, below: Height) -> Result<(), Error>;
+    /// Make sure all imported filter headers and filters are durably stored.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Script;
+
+    #[test]
+    fn test_filter_matcher() {
+        let genesis = Network::Mainnet.genesis_block();
+        let block_hash = genesis.block_hash();
+        let filter = BlockFilter::new_script_filter(&genesis, |_| unreachable!()).unwrap();
+        let matcher = FilterMatcher::new(&filter, &block_hash).unwrap();
+
+        let coinbase = genesis.txdata[0].output[0].script_pubkey.clone();
+        let other = Script::from(vec![0x6a, 0x01, 0x42]);
+
+        for scripts in [
+            vec![],
+            vec![&coinbase],
+            vec![&other],
+            vec![&other, &coinbase],
+        ] {
+            let mut query = scripts.iter().map(|s| s.as_bytes());
+
+            // Unlike the matcher, `BlockFilter::match_any` matches an empty query.
+            assert_eq!(
+                matcher.match_any(scripts.iter().map(|s| s.as_bytes())),
+                !scripts.is_empty() && filter.match_any(&block_hash, &mut query).unwrap()
+            );
+        }
+        assert!(matcher.contains(coinbase.as_bytes()));
+        assert!(!matcher.contains(other.as_bytes()));
+        assert!(!FilterMatcher::default().contains(coinbase.as_bytes()));
+    }
+
+    #[test]
+    fn test_filter_checkpoints() {
+        use bitcoin::consensus::deserialize;
+        use bitcoin::hashes::hex::FromHex;
+        use bitcoin::Block;
+
+        // Testnet block #3.
+        let block: Block = deserialize(
+            &Vec::<u8>::from_hex(
+                "0100000020782a005255b657696ea057d5b98f34defcf75196f64f6eeac8026c00000000\
+                 41ba5afc532aae03151b8aa87b65e1594f97504a768e010c98c0add79216247186e7494d\
+                 ffff001d058dc2b60101000000010000000000000000000000000000000000000000000000\
+                 000000000000000000ffffffff0e0486e7494d0151062f503253482fffffffff0100f2052a\
+                 01000000232103f6d9ff4c12959445ca5549c811683bf9c88e637b222dd2e0311154c4c85c\
+                 f423ac00000000",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            block.block_hash(),
+            BlockHash::from_hex("000000008b896e272758da5297bcd98fdc6d97c9b765ecec401e286dc1fdbe10")
+                .unwrap()
+        );
+
+        let checkpoints = Network::Testnet.filter_checkpoints().collect::<Vec<_>>();
+        let filter = BlockFilter::new_script_filter(&block, |_| unreachable!()).unwrap();
+
+        assert_eq!(checkpoints[0].0, 2);
+        assert_eq!(checkpoints[1].0, 3);
+        assert_eq!(filter.filter_header(&checkpoints[0].1), checkpoints[1].1);
+    }
 }
@@ -18,6 +18,15 @@ pub const TESTNET: &[u8; 32] = &[
     0x01, 0xea, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00,
 ];
 
+#[rustfmt::skip]
+/// Bitcoin testnet4 genesis hash.
+pub const TESTNET4: &[u8; 32] = &[
+    0x43, 0xf0, 0x8b, 0xda, 0xb0, 0x50, 0xe3, 0x5b,
+    0x56, 0x7c, 0x86, 0x4b, 0x91, 0xf4, 0x7f, 0x50,
+    0xae, 0x72, 0x5a, 0xe2, 0xde, 0x53, 0xbc, 0xfb,
+    0xba, 0xf2, 0x84, 0xda, 0x00, 0x00, 0x00, 0x00,
+];
+
 #[rustfmt::skip]
 /// Bitcoin regtest genesis hash.
 pub const REGTEST: &[u8; 32] = &[
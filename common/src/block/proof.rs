@@ -0,0 +1,209 @@
+//! Merkle proofs of transaction inclusion.
+//!
+//! A proof shows that a transaction is part of a block, using only the block header and a
+//! partial merkle tree, which is much smaller than the block itself. Proofs are serialized
+//! as BIP 37 `merkleblock` messages, which is also the format of Bitcoin Core's
+//! `gettxoutproof` and `verifytxoutproof` RPCs.
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+use bitcoin::hash_types::Txid;
+use bitcoin::util::merkleblock::{MerkleBlock, MerkleBlockError};
+
+use super::{Block, BlockHash, BlockHeader};
+
+/// An error verifying an inclusion proof.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    /// The proof is for a different block than the header it was verified against.
+    #[error("proof is for block {proof}, not {header}")]
+    BlockMismatch {
+        /// Block the proof is for.
+        proof: BlockHash,
+        /// Block of the header the proof was verified against.
+        header: BlockHash,
+    },
+    /// The partial merkle tree is malformed, or doesn't match the merkle root of the header.
+    #[error("invalid partial merkle tree: {0:?}")]
+    InvalidTree(MerkleBlockError),
+    /// The transaction isn't proven to be part of the block.
+    #[error("transaction {0} is not part of the proof")]
+    NotIncluded(Txid),
+}
+
+/// Proof that a transaction is included in a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// The transaction proven to be included.
+    pub txid: Txid,
+    /// The block header, and the partial merkle tree leading to the transaction.
+    pub merkle_block: MerkleBlock,
+}
+
+impl InclusionProof {
+    /// Create a proof of inclusion of a transaction in a block. Returns `None` if the
+    /// transaction isn't part of the block.
+    pub fn new(block: &Block, txid: &Txid) -> Option<Self> {
+        if !block.txdata.iter().any(|tx| tx.txid() == *txid) {
+            return None;
+        }
+        let matches = [*txid].iter().copied().collect::<HashSet<_>>();
+        let merkle_block = MerkleBlock::from_block(block, &matches);
+
+        Some(Self {
+            txid: *txid,
+            merkle_block,
+        })
+    }
+
+    /// The hash of the block the transaction is included in.
+    pub fn block_hash(&self) -> BlockHash {
+        self.merkle_block.header.block_hash()
+    }
+
+    /// Verify the proof against a trusted block header, eg. one from our header chain.
+    /// The header must be that of the block the proof is for.
+    pub fn verify(&self, header: &BlockHeader) -> Result<(), Error> {
+        if self.merkle_block.header != *header {
+            return Err(Error::BlockMismatch {
+                proof: self.block_hash(),
+                header: header.block_hash(),
+            });
+        }
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+
+        self.merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(Error::InvalidTree)?;
+
+        if !matches.contains(&self.txid) {
+            return Err(Error::NotIncluded(self.txid));
+        }
+        Ok(())
+    }
+
+    /// Serialize the proof, eg. to persist it. The transaction id is not part of the
+    /// serialized proof, so that it can be passed to `verifytxoutproof`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode::serialize(&self.merkle_block)
+    }
+
+    /// Deserialize a proof of inclusion of the given transaction, eg. one returned by
+    /// `gettxoutproof`.
+    pub fn from_bytes(txid: Txid, bytes: &[u8]) -> Result<Self, encode::Error> {
+        let merkle_block = encode::deserialize(bytes)?;
+
+        Ok(Self { txid, merkle_block })
+    }
+}
+
+impl Encodable for InclusionProof {
+    fn consensus_encode<W: std::io::Write>(&self, mut e: W) -> Result<usize, std::io::Error> {
+        let mut len = self.txid.consensus_encode(&mut e)?;
+        len += self.merkle_block.consensus_encode(&mut e)?;
+
+        Ok(len)
+    }
+}
+
+impl Decodable for InclusionProof {
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let txid = Txid::consensus_decode(&mut d)?;
+        let merkle_block = MerkleBlock::consensus_decode(&mut d)?;
+
+        Ok(Self { txid, merkle_block })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+    use bitcoin::hash_types::TxMerkleNode;
+    use bitcoin::hashes::Hash;
+
+    use crate::network::Network;
+
+    fn block(n: u32) -> Block {
+        let mut block = Network::Regtest.genesis_block();
+        let coinbase = block.txdata[0].clone();
+
+        block.txdata = (0..n)
+            .map(|i| {
+                let mut tx = coinbase.clone();
+                tx.input = vec![TxIn {
+                    previous_output: OutPoint::new(Txid::hash(&i.to_le_bytes()), i),
+                    ..coinbase.input[0].clone()
+                }];
+                tx.output = vec![TxOut {
+                    value: i as u64,
+                    ..coinbase.output[0].clone()
+                }];
+                tx
+            })
+            .collect();
+        block.header.merkle_root =
+            TxMerkleNode::from_hash(bitcoin::util::hash::bitcoin_merkle_root(
+                block.txdata.iter().map(|tx| tx.txid().as_hash()),
+            ));
+        block
+    }
+
+    #[test]
+    fn test_inclusion_proof() {
+        let block = block(7);
+        let txid = block.txdata[5].txid();
+        let proof = InclusionProof::new(&block, &txid).unwrap();
+
+        assert_eq!(proof.block_hash(), block.block_hash());
+        assert_eq!(proof.verify(&block.header), Ok(()));
+        assert!(proof.to_bytes().len() < encode::serialize(&block).len());
+
+        // Proofs survive a round-trip, with or without the transaction id. Decoded flag
+        // bits are padded to a whole byte, so compare the encodings.
+        let decoded = InclusionProof::from_bytes(txid, &proof.to_bytes()).unwrap();
+        assert_eq!(decoded.to_bytes(), proof.to_bytes());
+        assert_eq!(decoded.verify(&block.header), Ok(()));
+        let decoded: InclusionProof = encode::deserialize(&encode::serialize(&proof)).unwrap();
+        assert_eq!(decoded.txid, txid);
+        assert_eq!(decoded.to_bytes(), proof.to_bytes());
+
+        // Transactions not in the block can't be proven.
+        let other = Txid::hash(b"other");
+        assert!(InclusionProof::new(&block, &other).is_none());
+        assert_eq!(
+            InclusionProof {
+                txid: other,
+                ..proof.clone()
+            }
+            .verify(&block.header),
+            Err(Error::NotIncluded(other))
+        );
+    }
+
+    #[test]
+    fn test_inclusion_proof_invalid() {
+        let block = block(4);
+        let txid = block.txdata[1].txid();
+        let proof = InclusionProof::new(&block, &txid).unwrap();
+
+        // Proofs only verify against the header of their block.
+        let genesis = Network::Regtest.genesis_block();
+        assert!(matches!(
+            proof.verify(&genesis.header),
+            Err(Error::BlockMismatch { .. })
+        ));
+
+        // The partial merkle tree must match the header's merkle root.
+        let mut forged = proof;
+        forged.merkle_block.header.merkle_root = TxMerkleNode::hash(b"forged");
+        assert_eq!(
+            forged.verify(&forged.merkle_block.header),
+            Err(Error::InvalidTree(MerkleBlockError::MerkleRootMismatch))
+        );
+    }
+}
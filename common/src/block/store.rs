@@ -23,6 +23,9 @@ pub enum Error {
     /// A data-corruption error.
     #[error("error: the store data is corrupt")]
     Corruption,
+    /// The header at the given height was pruned from the store.
+    #[error("header at height {0} was pruned")]
+    Pruned(Height),
 }
 
 /// Represents an object (such as a header), that has a genesis.
@@ -64,8 +67,12 @@ pub trait Store {
     fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error>;
     /// Get the block at the given height.
     fn get(&self, height: Height) -> Result<Self::Header, Error>;
-    /// Rollback the chain to the given height.
+    /// Rollback the chain to the given height. Rolling back to a pruned height, other
+    /// than the genesis, fails with [`Error::Pruned`].
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
+    /// Discard the headers below the given height, except for the genesis. The tip is
+    /// always kept. Discarded headers can no longer be read from the store.
+    fn prune(&mut self, below: Height) -> Result<(), Error>;
     /// Synchronize the changes to disk.
     fn sync(&mut self) -> Result<(), Error>;
     /// Iterate over all headers in the store.
@@ -78,4 +85,7 @@ pub trait Store {
     fn check(&self) -> Result<(), Error>;
     /// Heal data corruption.
     fn heal(&self) -> Result<(), Error>;
+    /// Rebuild the store, reclaiming the space left unused by rollbacks, pruning and
+    /// healing. The stored headers are unchanged.
+    fn compact(&mut self) -> Result<(), Error>;
 }
@@ -12,6 +12,10 @@ pub const MAX_TIME_ADJUSTMENT: TimeOffset = 70 * 60;
 /// it is considered invalid (2 hours).
 pub const MAX_FUTURE_BLOCK_TIME: BlockTime = 60 * 60 * 2;
 
+/// Maximum a block timestamp can precede its parent's, for the first block of a
+/// difficulty period, on networks enforcing BIP 94 (10 minutes).
+pub const MAX_TIMEWARP: BlockTime = 60 * 10;
+
 /// Number of previous blocks to look at when determining the median
 /// block time.
 pub const MEDIAN_TIME_SPAN: Height = 11;
@@ -54,6 +58,10 @@ impl Default for LocalTime {
 
 impl LocalTime {
     /// Construct a local time from the current system time.
+    ///
+    /// Not available on `wasm32-unknown-unknown`, which has no system clock: the time
+    /// must be supplied by the host instead.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
     pub fn now() -> Self {
         Self::from(SystemTime::now())
     }
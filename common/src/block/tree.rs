@@ -7,7 +7,7 @@ use bitcoin::hash_types::BlockHash;
 use thiserror::Error;
 
 use crate::block::store;
-use crate::block::time::Clock;
+use crate::block::time::{self, Clock};
 use crate::block::{Bits, BlockTime, Height, Target, Work};
 
 /// An error related to the block tree.
@@ -131,6 +131,17 @@ pub trait BlockTree {
     }
     /// Return the height of the longest chain.
     fn height(&self) -> Height;
+    /// Get the total proof-of-work of the longest chain, from genesis up to and including the
+    /// block at the given height. Returns `None` if the height is past the tip.
+    fn chain_work(&self, height: Height) -> Option<Work> {
+        if height > self.height() {
+            return None;
+        }
+        Some(
+            self.range(0..height + 1)
+                .fold(Work::default(), |work, header| work + header.work()),
+        )
+    }
     /// Get the tip of the longest chain.
     fn tip(&self) -> (BlockHash, BlockHeader);
     /// Get the last block of the longest chain.
@@ -160,6 +171,59 @@ pub trait BlockTree {
     ) -> Vec<BlockHeader>;
     /// Get the locator hashes starting from the given height and going backwards.
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash>;
+    /// Make sure all imported headers are durably stored.
+    fn sync(&mut self) -> Result<(), store::Error> {
+        Ok(())
+    }
+    /// Get the median time past for the blocks leading up to the given height.
+    ///
+    /// # Errors
+    ///
+    /// Panics if height is `0`.
+    ///
+    fn median_time_past(&self, height: Height) -> BlockTime {
+        assert!(height != 0, "height must be > 0");
+
+        let mut times = [0; time::MEDIAN_TIME_SPAN as usize];
+
+        let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
+        let end = height;
+
+        for (i, blk) in self.range(start..end).enumerate() {
+            times[i] = blk.time;
+        }
+
+        // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`.
+        let available = &mut times[0..(end - start) as usize];
+
+        available.sort_unstable();
+        available[available.len() / 2]
+    }
+    /// Get the height of the first block of the active chain whose median time past, ie.
+    /// the median timestamp of the eleven blocks up to and including it, is at or after the
+    /// given time. Returns `None` if the median time past of the tip is before that.
+    ///
+    /// Unlike block timestamps, which can go back in time by up to an hour or so, the median
+    /// time past never decreases, so it can be binary searched. It lags behind the block
+    /// timestamps by about an hour though, so the block returned is usually a few blocks
+    /// after the first one timestamped at the given time.
+    fn find_height_by_time(&self, time: BlockTime) -> Option<Height> {
+        if self.median_time_past(self.height() + 1) < time {
+            return None;
+        }
+        let (mut low, mut high) = (0, self.height());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            if self.median_time_past(mid + 1) < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Some(low)
+    }
     /// Get the next difficulty given a block height, time and bits.
     fn next_difficulty_target(
         &self,
@@ -8,6 +8,7 @@ use bitcoin_hashes::hex::FromHex;
 
 use bitcoin_hashes::sha256d;
 
+use crate::block::filter::FilterHeader;
 use crate::block::Height;
 
 /// Peer services supported by nakamoto.
@@ -41,6 +42,8 @@ pub enum Network {
     Mainnet,
     /// Bitcoin Testnet.
     Testnet,
+    /// Bitcoin Testnet4 (BIP 94).
+    Testnet4,
     /// Bitcoin regression test net.
     Regtest,
 }
@@ -55,7 +58,7 @@ impl From<Network> for bitcoin::Network {
     fn from(value: Network) -> Self {
         match value {
             Network::Mainnet => Self::Bitcoin,
-            Network::Testnet => Self::Testnet,
+            Network::Testnet | Network::Testnet4 => Self::Testnet,
             Network::Regtest => Self::Regtest,
         }
     }
@@ -67,6 +70,7 @@ impl Network {
         match self {
             Network::Mainnet => 8333,
             Network::Testnet => 18333,
+            Network::Testnet4 => 48333,
             Network::Regtest => 18334,
         }
     }
@@ -78,6 +82,7 @@ impl Network {
         let iter = match self {
             Network::Mainnet => &checkpoints::MAINNET,
             Network::Testnet => &checkpoints::TESTNET,
+            Network::Testnet4 => &checkpoints::TESTNET4,
             Network::Regtest => &checkpoints::REGTEST,
         }
         .iter()
@@ -90,17 +95,39 @@ impl Network {
         Box::new(iter)
     }
 
+    /// Compact filter header checkpoints. Filter headers that don't match them are
+    /// rejected, along with the peers that served them.
+    pub fn filter_checkpoints(&self) -> Box<dyn Iterator<Item = (Height, FilterHeader)>> {
+        use crate::block::checkpoints::filter;
+
+        let iter = match self {
+            Network::Mainnet => filter::MAINNET,
+            Network::Testnet => filter::TESTNET,
+            Network::Testnet4 => filter::TESTNET4,
+            Network::Regtest => filter::REGTEST,
+        }
+        .iter()
+        .cloned()
+        .map(|(height, header)| {
+            let header = FilterHeader::from_hex(header).unwrap();
+            (height, header)
+        });
+
+        Box::new(iter)
+    }
+
     /// Return the short string representation of this network.
     pub fn as_str(&self) -> &'static str {
         match self {
             Network::Mainnet => "mainnet",
             Network::Testnet => "testnet",
+            Network::Testnet4 => "testnet4",
             Network::Regtest => "regtest",
         }
     }
 
     /// DNS seeds. Used to bootstrap the client's address book.
-    pub fn seeds(&self) -> &[&str] {
+    pub fn seeds(&self) -> &'static [&'static str] {
         match self {
             Network::Mainnet => &[
                 "seed.bitcoin.sipa.be",          // Pieter Wuille
@@ -119,6 +146,10 @@ impl Network {
                 "seed.testnet.bitcoin.sprovoost.nl",
                 "testnet-seed.bluematt.me",
             ],
+            Network::Testnet4 => &[
+                "seed.testnet4.bitcoin.sprovoost.nl", // Sjors Provoost
+                "seed.testnet4.wiz.biz",              // Jason Maurice
+            ],
             Network::Regtest => &[], // No seeds
         }
     }
@@ -140,10 +171,23 @@ impl Network {
     }
 
     /// Get the genesis block.
+    ///
+    /// ```
+    /// use nakamoto_common::network::Network;
+    ///
+    /// let network = Network::Testnet4;
+    /// let genesis = network.genesis_block();
+    ///
+    /// assert_eq!(network.genesis_hash(), genesis.block_hash());
+    /// assert_eq!(genesis.header.merkle_root, genesis.merkle_root());
+    /// ```
     pub fn genesis_block(&self) -> Block {
         use bitcoin::blockdata::constants;
 
-        constants::genesis_block((*self).into())
+        match self {
+            Self::Testnet4 => testnet4_genesis_block(),
+            _ => constants::genesis_block((*self).into()),
+        }
     }
 
     /// Get the hash of the genesis block of this network.
@@ -154,6 +198,7 @@ impl Network {
         let hash = match self {
             Self::Mainnet => genesis::MAINNET,
             Self::Testnet => genesis::TESTNET,
+            Self::Testnet4 => genesis::TESTNET4,
             Self::Regtest => genesis::REGTEST,
         };
         BlockHash::from(
@@ -164,11 +209,74 @@ impl Network {
 
     /// Get the consensus parameters for this network.
     pub fn params(&self) -> Params {
-        Params::new((*self).into())
+        match self {
+            // Testnet4 shares the testnet rules, except that soft forks are active from the
+            // start. Its stricter difficulty rules (BIP 94) are enforced by the block tree.
+            Self::Testnet4 => Params {
+                bip34_height: 1,
+                bip65_height: 1,
+                bip66_height: 1,
+                ..Params::new(bitcoin::Network::Testnet)
+            },
+            _ => Params::new((*self).into()),
+        }
     }
 
     /// Get the network magic number for this network.
     pub fn magic(&self) -> u32 {
-        bitcoin::Network::from(*self).magic()
+        match self {
+            Self::Testnet4 => 0x283f161c,
+            _ => bitcoin::Network::from(*self).magic(),
+        }
+    }
+
+    /// Whether the network enforces the difficulty adjustment and timestamp rules of
+    /// BIP 94.
+    pub fn is_bip94(&self) -> bool {
+        matches!(self, Self::Testnet4)
+    }
+}
+
+/// Construct the testnet4 genesis block, which isn't known to the `bitcoin` crate.
+fn testnet4_genesis_block() -> Block {
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+    let script_sig = script::Builder::new()
+        .push_scriptint(486604799)
+        .push_scriptint(4)
+        .push_slice(b"03/May/2024 000000000000000000001ebd58c244970b3aa9d783bb001011fbe8ea8e98e00e")
+        .into_script();
+    let script_pubkey = script::Builder::new()
+        .push_slice(&[0; 33])
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script();
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: 0xffffffff,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value: 50 * 100_000_000,
+            script_pubkey,
+        }],
+    };
+    let merkle_root: sha256d::Hash = coinbase.txid().into();
+
+    Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: merkle_root.into(),
+            time: 1714777860,
+            bits: 0x1d00ffff,
+            nonce: 393743547,
+        },
+        txdata: vec![coinbase],
     }
 }
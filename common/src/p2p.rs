@@ -1,5 +1,7 @@
 //! P2P-related types
-use std::net;
+use std::{fmt, net, str::FromStr};
+
+use thiserror::Error;
 
 pub mod peer;
 
@@ -26,3 +28,94 @@ impl Domain {
         }
     }
 }
+
+/// Error parsing a [`Subnet`].
+#[derive(Debug, Error)]
+pub enum SubnetError {
+    /// The address part is invalid.
+    #[error("invalid subnet address: {0}")]
+    Address(#[from] net::AddrParseError),
+    /// The prefix length is invalid.
+    #[error("invalid subnet prefix length: {0}")]
+    Prefix(String),
+}
+
+/// An IP subnet, eg. `10.0.0.0/8`. A single address is a subnet with a full-length prefix.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Subnet {
+    addr: net::IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Create a new subnet from an address and a prefix length. The prefix length is capped
+    /// to the length of the address.
+    pub fn new(addr: net::IpAddr, prefix: u8) -> Self {
+        let prefix = prefix.min(Self::max_prefix(&addr));
+
+        Self { addr, prefix }
+    }
+
+    /// Check whether the subnet contains the given address.
+    ///
+    /// ```
+    /// use nakamoto_common::p2p::Subnet;
+    ///
+    /// let subnet: Subnet = "10.0.0.0/8".parse().unwrap();
+    ///
+    /// assert!(subnet.contains(&[10, 1, 2, 3].into()));
+    /// assert!(!subnet.contains(&[11, 1, 2, 3].into()));
+    /// ```
+    pub fn contains(&self, ip: &net::IpAddr) -> bool {
+        match (self.addr, ip) {
+            (net::IpAddr::V4(a), net::IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(a) & mask == u32::from(*b) & mask
+            }
+            (net::IpAddr::V6(a), net::IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(a) & mask == u128::from(*b) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix(addr: &net::IpAddr) -> u8 {
+        match addr {
+            net::IpAddr::V4(_) => 32,
+            net::IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl From<net::IpAddr> for Subnet {
+    fn from(addr: net::IpAddr) -> Self {
+        Self::new(addr, Self::max_prefix(&addr))
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse::<net::IpAddr>()?;
+                let prefix = prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|p| *p <= Self::max_prefix(&addr))
+                    .ok_or_else(|| SubnetError::Prefix(prefix.to_owned()))?;
+
+                Ok(Self::new(addr, prefix))
+            }
+            None => Ok(Self::from(s.parse::<net::IpAddr>()?)),
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
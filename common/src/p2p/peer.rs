@@ -8,7 +8,7 @@ use microserde as serde;
 use bitcoin::network::address::Address;
 use bitcoin::network::constants::ServiceFlags;
 
-use crate::block::time::LocalTime;
+use crate::block::time::{LocalDuration, LocalTime};
 
 /// Peer store.
 ///
@@ -194,6 +194,98 @@ impl std::fmt::Display for Source {
     }
 }
 
+/// Response time above which a peer's filter service isn't considered good.
+pub const MAX_FILTER_LATENCY: LocalDuration = LocalDuration::from_secs(5);
+
+/// Quality of the compact filter service we got from a peer, across connections.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterService {
+    /// Number of valid filter or filter header responses served.
+    pub served: u32,
+    /// Number of responses that didn't match our filter header chain.
+    pub mismatches: u32,
+    /// Moving average of the time taken to respond to filter header requests.
+    pub latency: Option<LocalDuration>,
+}
+
+impl FilterService {
+    /// Record a valid response, and the time it took, if known.
+    pub fn served(&mut self, latency: Option<LocalDuration>) {
+        self.served = self.served.saturating_add(1);
+
+        if let Some(latency) = latency {
+            self.latency = Some(match self.latency {
+                // Give recent responses a weight of one quarter.
+                Some(avg) => (avg * 3 + latency) / 4,
+                None => latency,
+            });
+        }
+    }
+
+    /// Record a response that didn't match our filter header chain.
+    pub fn mismatched(&mut self) {
+        self.mismatches = self.mismatches.saturating_add(1);
+    }
+
+    /// Check whether the peer served us filters reliably and in a timely fashion.
+    pub fn is_good(&self) -> bool {
+        self.served > 0
+            && self.mismatches == 0
+            && matches!(self.latency, Some(l) if l <= MAX_FILTER_LATENCY)
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> serde::json::Value {
+        use serde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert(
+            "served".to_owned(),
+            Value::Number(Number::U64(self.served as u64)),
+        );
+        obj.insert(
+            "mismatches".to_owned(),
+            Value::Number(Number::U64(self.mismatches as u64)),
+        );
+        obj.insert(
+            "latency".to_owned(),
+            match self.latency {
+                Some(l) => Value::Number(Number::U64(l.as_millis() as u64)),
+                None => Value::Null,
+            },
+        );
+
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(v: &serde::json::Value) -> Result<Self, serde::Error> {
+        use serde::json::{Number, Value};
+
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(serde::Error),
+        };
+        let count = |key: &str| match obj.get(key) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n as u32),
+            None => Ok(0),
+            _ => Err(serde::Error),
+        };
+        let latency = match obj.get("latency") {
+            Some(Value::Null) | None => None,
+            Some(Value::Number(Number::U64(n))) => Some(LocalDuration::from_millis(*n as u128)),
+            _ => return Err(serde::Error),
+        };
+
+        Ok(Self {
+            served: count("served")?,
+            mismatches: count("mismatches")?,
+            latency,
+        })
+    }
+}
+
 /// A known address.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KnownAddress {
@@ -209,6 +301,8 @@ pub struct KnownAddress {
     pub last_attempt: Option<LocalTime>,
     /// Last time this peer was seen alive.
     pub last_active: Option<LocalTime>,
+    /// How well this peer served us compact filters.
+    pub filters: FilterService,
 }
 
 impl KnownAddress {
@@ -221,6 +315,7 @@ impl KnownAddress {
             last_attempt: None,
             last_sampled: None,
             last_active,
+            filters: FilterService::default(),
         }
     }
 
@@ -265,6 +360,7 @@ impl KnownAddress {
                 None => Value::Null,
             },
         );
+        obj.insert("filters".to_owned(), self.filters.to_json());
         obj.insert(
             "source".to_owned(),
             match self.source {
@@ -333,6 +429,11 @@ impl KnownAddress {
             }
             _ => return Err(serde::Error),
         };
+        // Addresses stored before filter service was recorded don't have this field.
+        let filters = match obj.get("filters") {
+            Some(v) => FilterService::from_json(v)?,
+            None => FilterService::default(),
+        };
 
         Ok(Self {
             addr: Address::new(&addr, services),
@@ -341,6 +442,7 @@ impl KnownAddress {
             last_sampled,
             last_attempt,
             last_active,
+            filters,
         })
     }
 }
@@ -351,6 +453,11 @@ pub trait AddressSource {
     fn sample(&mut self, services: ServiceFlags) -> Option<(Address, Source)>;
     /// Return an iterator over random peer addresses.
     fn iter(&mut self, services: ServiceFlags) -> Box<dyn Iterator<Item = (Address, Source)> + '_>;
+    /// Sample a random peer address with a good history of serving compact filters. Returns
+    /// `None` if there is no such address.
+    fn sample_filter_server(&mut self, _services: ServiceFlags) -> Option<(Address, Source)> {
+        None
+    }
 }
 
 /// Functions and traits useful for testing.
@@ -386,6 +493,11 @@ mod tests {
             last_sampled: Some(LocalTime::from_secs(144)),
             last_attempt: None,
             last_active: None,
+            filters: FilterService {
+                served: 12,
+                mismatches: 1,
+                latency: Some(LocalDuration::from_millis(320)),
+            },
         };
 
         let value = ka.to_json();
@@ -393,4 +505,20 @@ mod tests {
 
         assert_eq!(ka, deserialized);
     }
+
+    #[test]
+    fn test_filter_service() {
+        let mut service = FilterService::default();
+        assert!(!service.is_good());
+
+        service.served(Some(LocalDuration::from_millis(800)));
+        service.served(Some(LocalDuration::from_millis(400)));
+        service.served(None);
+        assert_eq!(service.served, 3);
+        assert_eq!(service.latency, Some(LocalDuration::from_millis(700)));
+        assert!(service.is_good());
+
+        service.mismatched();
+        assert!(!service.is_good());
+    }
 }
@@ -30,14 +30,16 @@
 #[cfg(unix)]
 pub mod reactor;
 pub mod socket;
+pub mod socks5;
 pub mod time;
+#[cfg(unix)]
+pub mod transport;
 
 pub use reactor::Reactor;
 
 #[cfg(test)]
 mod fallible;
 
-#[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
 
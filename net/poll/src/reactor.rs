@@ -20,19 +20,14 @@ use std::fmt::Debug;
 use std::io;
 use std::io::prelude::*;
 use std::net;
-use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use std::time;
 use std::time::SystemTime;
 
-use crate::fallible;
 use crate::socket::Socket;
+use crate::socks5;
 use crate::time::TimeoutManager;
+use crate::transport::{Listener as _, Transport, WRITE_TIMEOUT};
 
-/// Maximum time to wait when reading from a socket.
-const READ_TIMEOUT: time::Duration = time::Duration::from_secs(6);
-/// Maximum time to wait when writing to a socket.
-const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 /// Maximum amount of time to wait for i/o.
 const WAIT_TIMEOUT: LocalDuration = LocalDuration::from_mins(60);
 
@@ -50,20 +45,24 @@ enum Source {
     Waker,
 }
 
-/// A single-threaded non-blocking reactor.
+/// A single-threaded non-blocking reactor, running peer connections over the transport `R`,
+/// eg. `net::TcpStream`. See [`crate::transport`].
 pub struct Reactor<R: Write + Read, E> {
     peers: HashMap<net::SocketAddr, Socket<R, RawNetworkMessage>>,
     connecting: HashSet<net::SocketAddr>,
+    /// Proxy handshakes of peers being connected to through a proxy, and whether the
+    /// handshake was started.
+    handshakes: HashMap<net::SocketAddr, (socks5::Handshake, bool)>,
     inputs: VecDeque<Input>,
     commands: chan::Receiver<Command>,
     publisher: E,
     sources: popol::Sources<Source>,
     waker: Arc<popol::Waker>,
     timeouts: TimeoutManager<()>,
+    dials: TimeoutManager<net::SocketAddr>,
 }
 
-/// The `R` parameter represents the underlying stream type, eg. `net::TcpStream`.
-impl<R: Write + Read + AsRawFd, E> Reactor<R, E> {
+impl<R: Transport, E> Reactor<R, E> {
     /// Register a peer with the reactor.
     fn register_peer(&mut self, addr: net::SocketAddr, stream: R, link: Link) {
         self.sources
@@ -74,13 +73,28 @@ impl<R: Write + Read + AsRawFd, E> Reactor<R, E> {
     /// Unregister a peer from the reactor.
     fn unregister_peer(&mut self, addr: net::SocketAddr, reason: DisconnectReason) {
         self.connecting.remove(&addr);
+        self.dials.unregister(&addr);
+        self.handshakes.remove(&addr);
         self.inputs.push_back(Input::Disconnected(addr, reason));
         self.sources.unregister(&Source::Peer(addr));
         self.peers.remove(&addr);
     }
+
+    /// Clear the state left over from a previous run, so that the reactor can be run again.
+    fn reset(&mut self) {
+        for addr in self.peers.keys() {
+            self.sources.unregister(&Source::Peer(*addr));
+        }
+        self.sources.unregister(&Source::Listener);
+        self.peers.clear();
+        self.connecting.clear();
+        self.dials = TimeoutManager::new(LocalDuration::from_secs(0));
+        self.handshakes.clear();
+        self.inputs.clear();
+    }
 }
 
-impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::TcpStream, E> {
+impl<R: Transport, E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<R, E> {
     type Waker = Arc<popol::Waker>;
 
     /// Construct a new reactor, given a channel to send events on.
@@ -91,17 +105,21 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
         let mut sources = popol::Sources::new();
         let waker = Arc::new(popol::Waker::new(&mut sources, Source::Waker)?);
         let timeouts = TimeoutManager::new(LocalDuration::from_secs(1));
+        let dials = TimeoutManager::new(LocalDuration::from_secs(0));
         let connecting = HashSet::new();
+        let handshakes = HashMap::new();
 
         Ok(Self {
             peers,
             connecting,
+            handshakes,
             sources,
             inputs,
             commands,
             publisher,
             waker,
             timeouts,
+            dials,
         })
     }
 
@@ -114,10 +132,12 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
     where
         B: FnOnce(chan::Sender<Out>) -> Protocol<T, F, P>,
     {
+        self.reset();
+
         let listener = if listen_addrs.is_empty() {
             None
         } else {
-            let listener = self::listen(listen_addrs)?;
+            let listener = R::Listener::bind(listen_addrs)?;
             let local_addr = listener.local_addr()?;
 
             self.sources
@@ -154,6 +174,8 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
         let mut events = popol::Events::new();
         // Timeouts populated by `TimeoutManager::wake`.
         let mut timeouts = Vec::with_capacity(32);
+        // Connection attempts that timed out.
+        let mut dials = Vec::with_capacity(8);
 
         loop {
             trace!(
@@ -162,7 +184,14 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
                 self.timeouts.len()
             );
 
-            let timeout = self.timeouts.next().unwrap_or(WAIT_TIMEOUT).into();
+            let timeout = self
+                .timeouts
+                .next()
+                .into_iter()
+                .chain(self.dials.next())
+                .min()
+                .unwrap_or(WAIT_TIMEOUT)
+                .into();
             let result = self.sources.wait_timeout(&mut events, timeout); // Blocking.
             let local_time = SystemTime::now().into();
 
@@ -206,8 +235,6 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
                                     };
                                     trace!("{}: Accepting peer connection", addr);
 
-                                    conn.set_nonblocking(true)?;
-
                                     let local_addr = conn.local_addr()?;
                                     let link = Link::Inbound;
 
@@ -242,9 +269,33 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
                         self.inputs.push_back(Input::Tick);
                     }
                 }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                    // The wait was interrupted by a signal, eg. one the process is handling
+                    // to shut down cleanly. Keep going.
+                }
                 Err(err) => return Err(err.into()),
             }
 
+            // Give up on connection attempts that are taking too long, eg. because the
+            // remote address is unreachable.
+            self.dials.wake(local_time, &mut dials);
+
+            for addr in dials.drain(..) {
+                if self.connecting.contains(&addr) {
+                    if let Some(peer) = self.peers.get(&addr) {
+                        debug!("{}: Connection attempt timed out", addr);
+
+                        peer.disconnect().ok();
+                        self.unregister_peer(
+                            addr,
+                            DisconnectReason::ConnectionError(
+                                io::Error::from(io::ErrorKind::TimedOut).to_string(),
+                            ),
+                        );
+                    }
+                }
+            }
+
             while let Some(event) = self.inputs.pop_front() {
                 protocol.step(event, local_time);
 
@@ -268,7 +319,7 @@ impl<E: event::Publisher> nakamoto_p2p::reactor::Reactor<E> for Reactor<net::Tcp
     }
 }
 
-impl<E: event::Publisher> Reactor<net::TcpStream, E> {
+impl<R: Transport, E: event::Publisher> Reactor<R, E> {
     /// Process protocol state machine outputs.
     fn process(&mut self, outputs: &chan::Receiver<Out>, local_time: LocalTime) -> Control {
         // Note that there may be messages destined for a peer that has since been
@@ -302,19 +353,27 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
                         }
                     }
                 }
-                // TODO: Use connection timeout, or handle timeouts in connection manager.
-                Out::Connect(addr, _timeout) => {
+                Out::Connect(addr, timeout, proxy) => {
                     trace!("Connecting to {}...", &addr);
 
-                    match self::dial(&addr) {
-                        Ok(stream) => {
-                            trace!("{:#?}", stream);
+                    // When connecting through a proxy, the peer is registered under its
+                    // own address, though the stream is connected to the proxy.
+                    let remote = proxy.as_ref().map_or(addr, |p| p.addr);
 
+                    match R::dial(&remote) {
+                        Ok(stream) => {
+                            if let Some(proxy) = proxy {
+                                self.handshakes.insert(
+                                    addr,
+                                    (socks5::Handshake::new(addr, proxy.credentials), false),
+                                );
+                            }
                             self.register_peer(addr, stream, Link::Outbound);
                             self.connecting.insert(addr);
+                            self.dials.register(addr, local_time + timeout);
                             self.inputs.push_back(Input::Connecting { addr });
                         }
-                        Err(Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
                             // Ignore. We are already establishing a connection through
                             // this socket.
                         }
@@ -352,6 +411,14 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
                 Out::Shutdown => {
                     info!("Shutdown received");
 
+                    // Deliver any outstanding messages and close all connections.
+                    for (addr, peer) in self.peers.iter_mut() {
+                        if let Err(err) = peer.close(WRITE_TIMEOUT) {
+                            debug!("{}: Error closing connection: {}", addr, err);
+                        }
+                    }
+                    self.publisher.publish(Event::NodeStopped);
+
                     return Control::Shutdown;
                 }
             }
@@ -360,6 +427,10 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
     }
 
     fn handle_readable(&mut self, addr: &net::SocketAddr) {
+        if self.handshakes.contains_key(addr) {
+            self.handle_handshake(addr);
+            return;
+        }
         let socket = self.peers.get_mut(&addr).unwrap();
 
         trace!("{}: Socket is readable", addr);
@@ -396,6 +467,67 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
         }
     }
 
+    /// Read from a peer's proxy during the proxy handshake. Once the handshake is done, the
+    /// peer is connected.
+    fn handle_handshake(&mut self, addr: &net::SocketAddr) {
+        let socket = self.peers.get_mut(addr).unwrap();
+        let (handshake, _) = self.handshakes.get_mut(addr).unwrap();
+
+        let result = loop {
+            let mut buf = vec![0; handshake.remaining()];
+
+            let n = match socket.read_raw(&mut buf) {
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(err) => break Err(err),
+            };
+            match handshake.received(&buf[..n]) {
+                Ok(socks5::Step::Read) => {}
+                // Requests are small enough to fit in the socket's send buffer.
+                Ok(socks5::Step::Send(bytes)) => {
+                    if let Err(err) = socket.write_raw(&bytes) {
+                        break Err(err);
+                    }
+                }
+                Ok(socks5::Step::Done) => break Ok(true),
+                Err(err) => break Err(err),
+            }
+        };
+
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                trace!("{}: Connected through proxy", addr);
+
+                self.handshakes.remove(addr);
+                self.connecting.remove(addr);
+                self.dials.unregister(addr);
+
+                match socket.local_address() {
+                    Ok(local_addr) => self.inputs.push_back(Input::Connected {
+                        addr: *addr,
+                        local_addr,
+                        link: socket.link,
+                    }),
+                    Err(err) => {
+                        socket.disconnect().ok();
+                        self.unregister_peer(
+                            *addr,
+                            DisconnectReason::ConnectionError(err.to_string()),
+                        );
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("{}: Proxy error: {}", addr, err);
+
+                socket.disconnect().ok();
+                self.unregister_peer(*addr, DisconnectReason::ConnectionError(err.to_string()));
+            }
+        }
+    }
+
     fn handle_writable(&mut self, addr: &net::SocketAddr, source: &Source) -> io::Result<()> {
         trace!("{}: Socket is writable", addr);
 
@@ -406,8 +538,30 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
         // that it is ready for writing, once a connection has been established."
         //
         // Since we perform a non-blocking connect, we're only really connected once the socket
-        // is writable.
+        // is writable. When connecting through a proxy, we're then connected to the proxy,
+        // and the handshake with it starts.
+        if let Some((handshake, started)) = self.handshakes.get_mut(addr) {
+            if !*started {
+                *started = true;
+
+                // The greeting is a few bytes long, and fits in the socket's send buffer.
+                if let Err(err) = socket.write_raw(&handshake.greeting()) {
+                    error!("{}: Proxy write error: {}", addr, err.to_string());
+
+                    socket.disconnect().ok();
+                    self.unregister_peer(*addr, DisconnectReason::ConnectionError(err.to_string()));
+
+                    return Ok(());
+                }
+            }
+            // Nothing else is written until the handshake is done.
+            src.unset(popol::interest::WRITE);
+
+            return Ok(());
+        }
         if self.connecting.remove(addr) {
+            self.dials.unregister(addr);
+
             let local_addr = socket.local_address()?;
 
             self.inputs.push_back(Input::Connected {
@@ -426,40 +580,3 @@ impl<E: event::Publisher> Reactor<net::TcpStream, E> {
         Ok(())
     }
 }
-
-/// Connect to a peer given a remote address.
-fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, Error> {
-    use socket2::{Domain, Socket, Type};
-    fallible! { Error::Io(io::ErrorKind::Other.into()) };
-
-    let domain = if addr.is_ipv4() {
-        Domain::IPV4
-    } else {
-        Domain::IPV6
-    };
-    let sock = Socket::new(domain, Type::STREAM, None)?;
-
-    sock.set_read_timeout(Some(READ_TIMEOUT))?;
-    sock.set_write_timeout(Some(WRITE_TIMEOUT))?;
-    sock.set_nonblocking(true)?;
-
-    match sock.connect(&(*addr).into()) {
-        Ok(()) => {}
-        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
-        Err(e) if e.raw_os_error() == Some(libc::EALREADY) => {
-            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into())
-        }
-        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-        Err(e) => return Err(e.into()),
-    }
-    Ok(sock.into())
-}
-
-// Listen for connections on the given address.
-fn listen<A: net::ToSocketAddrs>(addr: A) -> Result<net::TcpListener, Error> {
-    let sock = net::TcpListener::bind(addr)?;
-
-    sock.set_nonblocking(true)?;
-
-    Ok(sock)
-}
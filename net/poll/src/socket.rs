@@ -3,6 +3,7 @@ use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::io::{self, Read, Write};
 use std::net;
+use std::time;
 
 use bitcoin::consensus::encode::Decodable;
 use bitcoin::consensus::encode::{self, Encodable};
@@ -13,6 +14,7 @@ use log::*;
 use nakamoto_p2p::protocol::{Input, Link};
 
 use crate::fallible;
+use crate::transport::Transport;
 
 /// Maximum peer-to-peer message size.
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
@@ -27,7 +29,7 @@ pub struct Socket<R: Read + Write, M> {
     queue: VecDeque<M>,
 }
 
-impl<M> Socket<net::TcpStream, M> {
+impl<R: Transport, M> Socket<R, M> {
     pub fn queue(&mut self, msg: M) {
         self.queue.push_back(msg);
     }
@@ -37,9 +39,23 @@ impl<M> Socket<net::TcpStream, M> {
     }
 }
 
-impl<M: Encodable + Decodable + Debug> Socket<net::TcpStream, M> {
+impl<R: Transport, M: Encodable + Decodable + Debug> Socket<R, M> {
     pub fn disconnect(&self) -> io::Result<()> {
-        self.raw.stream.shutdown(net::Shutdown::Both)
+        self.raw.stream.shutdown()
+    }
+
+    /// Write any queued messages and close the connection. Blocks for at most `timeout` on
+    /// each write.
+    pub fn close(&mut self, timeout: time::Duration) -> io::Result<()> {
+        if !self.queue.is_empty() {
+            self.raw.stream.set_nonblocking(false)?;
+            self.raw.stream.set_write_timeout(Some(timeout))?;
+
+            while let Some(msg) = self.queue.pop_front() {
+                self.write(&msg)?;
+            }
+        }
+        self.disconnect()
     }
 }
 
@@ -57,6 +73,18 @@ impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
         }
     }
 
+    /// Read bytes from the underlying stream, bypassing message decoding. Only safe to
+    /// call before any message was read, eg. during a proxy handshake.
+    pub fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.raw.stream.read(buf)
+    }
+
+    /// Write bytes to the underlying stream, bypassing message encoding.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.raw.stream.write_all(bytes)?;
+        self.raw.stream.flush()
+    }
+
     pub fn read(&mut self) -> Result<M, encode::Error> {
         fallible! { io::Error::from(io::ErrorKind::Other) };
 
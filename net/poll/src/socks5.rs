@@ -0,0 +1,274 @@
+//! SOCKS5 client handshake (RFC 1928), for connecting to peers through a proxy.
+//!
+//! The handshake doesn't perform any I/O: the reactor sends the bytes it returns to the
+//! proxy, and feeds it the bytes it receives, reading no more than [`Handshake::remaining`]
+//! bytes at a time, so that nothing past the end of the handshake is consumed. Once the
+//! handshake is done, the connection is relayed to the peer by the proxy.
+use std::io;
+use std::net;
+
+use nakamoto_p2p::protocol::connmgr::Credentials;
+
+/// Protocol version.
+const VERSION: u8 = 0x05;
+/// Authentication method: none.
+const METHOD_NONE: u8 = 0x00;
+/// Authentication method: username and password (RFC 1929).
+const METHOD_PASSWORD: u8 = 0x02;
+/// Version of the username and password authentication sub-negotiation.
+const PASSWORD_VERSION: u8 = 0x01;
+/// Command: establish a TCP connection.
+const CMD_CONNECT: u8 = 0x01;
+/// Address type: IPv4.
+const ATYP_IPV4: u8 = 0x01;
+/// Address type: domain name.
+const ATYP_DOMAIN: u8 = 0x03;
+/// Address type: IPv6.
+const ATYP_IPV6: u8 = 0x04;
+
+/// What to do after feeding bytes to the handshake.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Send these bytes to the proxy, and keep reading.
+    Send(Vec<u8>),
+    /// Keep reading.
+    Read,
+    /// The handshake is done, and the connection to the peer is established.
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the authentication method chosen by the proxy.
+    Method,
+    /// Waiting for the authentication result.
+    Auth,
+    /// Waiting for the reply to the connect request.
+    Connect,
+    /// The handshake is done.
+    Done,
+}
+
+/// A SOCKS5 client handshake, connecting to a peer through a proxy.
+#[derive(Debug)]
+pub struct Handshake {
+    /// Peer address to connect to.
+    target: net::SocketAddr,
+    /// Credentials to authenticate with. If set, the proxy must accept them.
+    credentials: Option<Credentials>,
+    state: State,
+    /// Bytes received of the current reply.
+    buf: Vec<u8>,
+}
+
+impl Handshake {
+    /// Create a new handshake, connecting to the given peer.
+    pub fn new(target: net::SocketAddr, credentials: Option<Credentials>) -> Self {
+        Self {
+            target,
+            credentials,
+            state: State::Method,
+            buf: Vec::new(),
+        }
+    }
+
+    /// The bytes to send to the proxy first, once connected to it.
+    pub fn greeting(&self) -> Vec<u8> {
+        let method = if self.credentials.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        vec![VERSION, 1, method]
+    }
+
+    /// Check whether the handshake is done.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Number of bytes to read from the proxy before the handshake can move on.
+    pub fn remaining(&self) -> usize {
+        let len = match self.state {
+            State::Method | State::Auth => 2,
+            // The reply to a connect request is of variable length, depending on the
+            // type of the bound address. Read up to its first byte before deciding.
+            State::Connect => match self.buf.get(3..5) {
+                Some([ATYP_IPV4, _]) => 4 + 4 + 2,
+                Some([ATYP_IPV6, _]) => 4 + 16 + 2,
+                Some([ATYP_DOMAIN, len]) => 4 + 1 + *len as usize + 2,
+                _ => 5,
+            },
+            State::Done => return 0,
+        };
+        len - self.buf.len()
+    }
+
+    /// Feed bytes received from the proxy. At most [`Handshake::remaining`] bytes should
+    /// be fed at once.
+    pub fn received(&mut self, bytes: &[u8]) -> io::Result<Step> {
+        if bytes.len() > self.remaining() {
+            return Err(error("unexpected data"));
+        }
+        self.buf.extend_from_slice(bytes);
+
+        // Don't wait for the rest of the reply to report a failed connection.
+        if self.state == State::Connect && self.buf.len() >= 2 {
+            match self.buf[..2] {
+                [VERSION, 0] => {}
+                [VERSION, code] => return Err(error(reply_error(code))),
+                _ => return Err(error("invalid version")),
+            }
+        }
+        if self.remaining() > 0 {
+            return Ok(Step::Read);
+        }
+        let reply = std::mem::take(&mut self.buf);
+
+        match self.state {
+            State::Method => match (reply[0], reply[1], &self.credentials) {
+                (VERSION, METHOD_PASSWORD, Some(credentials)) => {
+                    self.state = State::Auth;
+
+                    Ok(Step::Send(auth(credentials)?))
+                }
+                (VERSION, METHOD_NONE, None) => {
+                    self.state = State::Connect;
+
+                    Ok(Step::Send(connect(&self.target)))
+                }
+                (VERSION, _, _) => Err(error("no acceptable authentication method")),
+                _ => Err(error("invalid version")),
+            },
+            State::Auth => match reply[..] {
+                [PASSWORD_VERSION, 0] => {
+                    self.state = State::Connect;
+
+                    Ok(Step::Send(connect(&self.target)))
+                }
+                _ => Err(error("authentication failed")),
+            },
+            State::Connect => {
+                self.state = State::Done;
+
+                Ok(Step::Done)
+            }
+            State::Done => Err(error("unexpected data")),
+        }
+    }
+}
+
+/// Encode a username and password authentication request.
+fn auth(credentials: &Credentials) -> io::Result<Vec<u8>> {
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+
+    if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socks5: credentials must be between 1 and 255 bytes long",
+        ));
+    }
+    let mut buf = vec![PASSWORD_VERSION, username.len() as u8];
+    buf.extend_from_slice(username);
+    buf.push(password.len() as u8);
+    buf.extend_from_slice(password);
+
+    Ok(buf)
+}
+
+/// Encode a connect request.
+fn connect(target: &net::SocketAddr) -> Vec<u8> {
+    let mut buf = vec![VERSION, CMD_CONNECT, 0];
+
+    match target.ip() {
+        net::IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        net::IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    buf
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("socks5: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let target: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let credentials = Credentials {
+            username: String::from("alice"),
+            password: String::from("p4ss"),
+        };
+        let mut hs = Handshake::new(target, Some(credentials));
+
+        assert_eq!(hs.greeting(), vec![5, 1, METHOD_PASSWORD]);
+        assert_eq!(hs.remaining(), 2);
+
+        // Bytes may arrive one at a time.
+        assert_eq!(hs.received(&[5]).unwrap(), Step::Read);
+        assert_eq!(
+            hs.received(&[METHOD_PASSWORD]).unwrap(),
+            Step::Send(b"\x01\x05alice\x04p4ss".to_vec())
+        );
+        assert_eq!(
+            hs.received(&[1, 0]).unwrap(),
+            Step::Send(vec![5, 1, 0, ATYP_IPV4, 88, 88, 88, 88, 0x20, 0x8d])
+        );
+
+        // The length of the reply depends on the type of the bound address.
+        assert_eq!(hs.remaining(), 5);
+        assert_eq!(hs.received(&[5, 0, 0, ATYP_DOMAIN, 3]).unwrap(), Step::Read);
+        assert_eq!(hs.remaining(), 5);
+        assert_eq!(hs.received(b"tor\x00\x00").unwrap(), Step::Done);
+        assert!(hs.is_done());
+        assert_eq!(hs.remaining(), 0);
+    }
+
+    #[test]
+    fn test_handshake_errors() {
+        let target: net::SocketAddr = "[2001:db8::1]:8333".parse().unwrap();
+        let credentials = Credentials {
+            username: String::from("alice"),
+            password: String::from("p4ss"),
+        };
+
+        // Isolating streams requires authentication.
+        let mut hs = Handshake::new(target, Some(credentials));
+        assert!(hs.received(&[5, METHOD_NONE]).is_err());
+
+        // The proxy failed to reach the peer.
+        let mut hs = Handshake::new(target, None);
+        assert_eq!(hs.greeting(), vec![5, 1, METHOD_NONE]);
+        match hs.received(&[5, METHOD_NONE]).unwrap() {
+            Step::Send(req) => assert_eq!(req[3], ATYP_IPV6),
+            step => panic!("unexpected step {:?}", step),
+        }
+        let err = hs.received(&[5, 5, 0, ATYP_IPV4, 0]).unwrap_err();
+        assert!(err.to_string().contains("connection refused"));
+    }
+}
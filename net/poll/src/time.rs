@@ -69,6 +69,33 @@ impl<K> TimeoutManager<K> {
         true
     }
 
+    /// Unregister the timeouts associated with a key, eg. once the event they were waiting
+    /// for has happened.
+    ///
+    /// ```
+    /// use nakamoto_net_poll::time::{LocalTime, LocalDuration, TimeoutManager};
+    ///
+    /// let mut tm = TimeoutManager::new(LocalDuration::from_secs(0));
+    /// let now = LocalTime::now();
+    ///
+    /// tm.register(0xA, now + LocalDuration::from_millis(8));
+    /// tm.register(0xB, now + LocalDuration::from_millis(16));
+    ///
+    /// tm.unregister(&0xA);
+    /// assert_eq!(tm.len(), 1);
+    ///
+    /// let mut timeouts = Vec::new();
+    ///
+    /// tm.wake(now + LocalDuration::from_millis(16), &mut timeouts);
+    /// assert_eq!(timeouts, vec![0xB]);
+    /// ```
+    pub fn unregister(&mut self, key: &K)
+    where
+        K: PartialEq,
+    {
+        self.timeouts.retain(|(k, _)| k != key);
+    }
+
     /// Get the minimum time duration we should wait for at least one timeout
     /// to be reached.  Returns `None` if there are no timeouts.
     ///
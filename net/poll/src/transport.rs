@@ -0,0 +1,333 @@
+//! Byte transports the reactor can run peer connections over.
+//!
+//! A transport carries the bytes of a peer connection, eg. over plain TCP, through a proxy,
+//! or over an encrypted tunnel. The reactor is generic over the transport, so that custom
+//! transports can be used without changing the reactor. The only requirement is that
+//! transports are backed by a file descriptor that can be polled for readiness.
+//!
+//! Two transports are provided: [`net::TcpStream`], and [`Memory`], which connects
+//! reactors running in the same process, and is meant for testing.
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use crate::fallible;
+
+/// Maximum time to wait when reading from a socket.
+pub const READ_TIMEOUT: time::Duration = time::Duration::from_secs(6);
+/// Maximum time to wait when writing to a socket.
+pub const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+/// A byte transport for peer connections.
+pub trait Transport: Read + Write + AsRawFd + Sized {
+    /// Listener accepting inbound connections.
+    type Listener: Listener<Stream = Self>;
+
+    /// Connect to a peer, without blocking. The connection is established once the stream
+    /// is writable. Returns an [`io::ErrorKind::AlreadyExists`] error if a connection to the
+    /// peer is already being established.
+    fn dial(addr: &net::SocketAddr) -> io::Result<Self>;
+    /// Get the local address of the connection.
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+    /// Shut the connection down.
+    fn shutdown(&self) -> io::Result<()>;
+    /// Set the stream in blocking or non-blocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    /// Set the timeout of blocking writes.
+    fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()>;
+}
+
+/// A listener for inbound connections of a transport.
+pub trait Listener: AsRawFd + Sized {
+    /// Stream of accepted connections.
+    type Stream;
+
+    /// Listen on the first of the given addresses that can be bound to. The listener must
+    /// not block on [`Listener::accept`].
+    fn bind(addrs: &[net::SocketAddr]) -> io::Result<Self>;
+    /// Accept an inbound connection, along with the remote address. Returns an
+    /// [`io::ErrorKind::WouldBlock`] error if there is no pending connection.
+    fn accept(&self) -> io::Result<(Self::Stream, net::SocketAddr)>;
+    /// Get the address the listener is bound to.
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+}
+
+impl Transport for net::TcpStream {
+    type Listener = net::TcpListener;
+
+    fn dial(addr: &net::SocketAddr) -> io::Result<Self> {
+        use socket2::{Domain, Socket, Type};
+        fallible! { io::Error::from(io::ErrorKind::Other) };
+
+        let domain = if addr.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        };
+        let sock = Socket::new(domain, Type::STREAM, None)?;
+
+        sock.set_read_timeout(Some(READ_TIMEOUT))?;
+        sock.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        sock.set_nonblocking(true)?;
+
+        match sock.connect(&(*addr).into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EALREADY) => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        Ok(sock.into())
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        net::TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        net::TcpStream::shutdown(self, net::Shutdown::Both)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        net::TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        net::TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Listener for net::TcpListener {
+    type Stream = net::TcpStream;
+
+    fn bind(addrs: &[net::SocketAddr]) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(addrs)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(listener)
+    }
+
+    fn accept(&self) -> io::Result<(net::TcpStream, net::SocketAddr)> {
+        let (stream, addr) = net::TcpListener::accept(self)?;
+        stream.set_nonblocking(true)?;
+
+        Ok((stream, addr))
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        net::TcpListener::local_addr(self)
+    }
+}
+
+/// Connections waiting to be accepted by a [`MemoryListener`].
+struct Backlog {
+    pending: VecDeque<(Memory, net::SocketAddr)>,
+    /// Written to once per pending connection, to make the listener readable.
+    notify: UnixStream,
+}
+
+lazy_static! {
+    /// Listeners of the in-memory network, by address.
+    static ref LISTENERS: Mutex<BTreeMap<net::SocketAddr, Arc<Mutex<Backlog>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Next port handed out to in-memory connections and listeners bound to port zero.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(49152);
+
+fn ephemeral(ip: net::IpAddr) -> net::SocketAddr {
+    net::SocketAddr::new(ip, NEXT_PORT.fetch_add(1, Ordering::SeqCst))
+}
+
+/// An in-memory transport, connecting reactors running in the same process. Addresses
+/// are only meaningful within the process: dialing an address connects to the
+/// [`MemoryListener`] bound to it, if any.
+#[derive(Debug)]
+pub struct Memory {
+    stream: UnixStream,
+    local_addr: net::SocketAddr,
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Memory {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsRawFd for Memory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Transport for Memory {
+    type Listener = MemoryListener;
+
+    fn dial(addr: &net::SocketAddr) -> io::Result<Self> {
+        let backlog = LISTENERS
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        let (local, remote) = UnixStream::pair()?;
+        let local_addr = ephemeral(net::Ipv4Addr::LOCALHOST.into());
+
+        local.set_nonblocking(true)?;
+        remote.set_nonblocking(true)?;
+
+        let mut backlog = backlog.lock().unwrap();
+
+        backlog.notify.write_all(&[0])?;
+        backlog.pending.push_back((
+            Memory {
+                stream: remote,
+                local_addr: *addr,
+            },
+            local_addr,
+        ));
+
+        Ok(Memory {
+            stream: local,
+            local_addr,
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(net::Shutdown::Both)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+}
+
+/// Listener of the in-memory transport. Stops listening when dropped.
+#[derive(Debug)]
+pub struct MemoryListener {
+    addr: net::SocketAddr,
+    /// Readable when there are pending connections.
+    ready: UnixStream,
+}
+
+impl AsRawFd for MemoryListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+}
+
+impl Listener for MemoryListener {
+    type Stream = Memory;
+
+    fn bind(addrs: &[net::SocketAddr]) -> io::Result<Self> {
+        let mut listeners = LISTENERS.lock().unwrap();
+
+        for addr in addrs {
+            let addr = if addr.port() == 0 {
+                ephemeral(addr.ip())
+            } else {
+                *addr
+            };
+            if listeners.contains_key(&addr) {
+                continue;
+            }
+            let (ready, notify) = UnixStream::pair()?;
+            ready.set_nonblocking(true)?;
+
+            listeners.insert(
+                addr,
+                Arc::new(Mutex::new(Backlog {
+                    pending: VecDeque::new(),
+                    notify,
+                })),
+            );
+            return Ok(Self { addr, ready });
+        }
+        Err(io::Error::from(io::ErrorKind::AddrInUse))
+    }
+
+    fn accept(&self) -> io::Result<(Memory, net::SocketAddr)> {
+        let backlog = LISTENERS
+            .lock()
+            .unwrap()
+            .get(&self.addr)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let mut backlog = backlog.lock().unwrap();
+
+        (&self.ready).read_exact(&mut [0])?;
+        backlog
+            .pending
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        let listener = MemoryListener::bind(&[([127, 0, 0, 1], 0).into()]).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let mut alice = Memory::dial(&addr).unwrap();
+        let (mut bob, remote) = listener.accept().unwrap();
+
+        assert_eq!(remote, alice.local_addr().unwrap());
+        assert_eq!(bob.local_addr().unwrap(), addr);
+
+        alice.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        bob.set_nonblocking(false).unwrap();
+        bob.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(listener);
+        assert_eq!(
+            Memory::dial(&addr).unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+}
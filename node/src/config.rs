@@ -0,0 +1,361 @@
+//! Daemon configuration file.
+//!
+//! The configuration is read from a TOML file, of which only top-level keys with string and
+//! array values are supported. For example:
+//!
+//! ```toml
+//! network = "testnet"
+//! datadir = "/var/lib/nakamoto"
+//! connect = ["127.0.0.1:18333"]
+//! listen = ["0.0.0.0:18333"]
+//! log = "debug"
+//!
+//! # Addresses, or hex-encoded output scripts, to watch.
+//! watch = [
+//!     "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+//! ]
+//! ```
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io, net};
+
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{Address, Script};
+use thiserror::Error;
+
+use nakamoto_client::client::Network;
+
+/// An error reading the configuration file.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The file couldn't be read.
+    #[error("error reading configuration: {0}")]
+    Io(#[from] io::Error),
+    /// The file is not valid.
+    #[error("invalid configuration on line {line}: {message}")]
+    Parse {
+        /// Line of the file the error was found on.
+        line: usize,
+        /// Error message.
+        message: String,
+    },
+}
+
+/// Daemon configuration.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigFile {
+    /// Bitcoin network.
+    pub network: Option<Network>,
+    /// Root directory for nakamoto files.
+    pub root: Option<PathBuf>,
+    /// Directory runtime data is stored in, overriding the one derived from `root`.
+    pub datadir: Option<PathBuf>,
+    /// Peers to connect to.
+    pub connect: Vec<net::SocketAddr>,
+    /// Addresses to listen on for peer connections.
+    pub listen: Vec<net::SocketAddr>,
+    /// File of trusted block headers to import on startup.
+    pub import_headers: Option<PathBuf>,
+    /// SOCKS5 proxy to connect to peers through, eg. Tor.
+    pub proxy: Option<net::SocketAddr>,
+    /// Log level.
+    pub log: Option<log::Level>,
+    /// Output scripts to watch, from the tip onwards.
+    pub watch: Vec<Script>,
+}
+
+impl ConfigFile {
+    /// Read the configuration from a file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let s = fs::read_to_string(path)?;
+
+        Self::from_str(&s)
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cfg = Self::default();
+
+        for (line, key, value) in parse(s)? {
+            let err = |message: String| Error::Parse { line, message };
+
+            match key.as_str() {
+                "network" => {
+                    let name = value.string().map_err(err)?;
+                    let network = [
+                        Network::Mainnet,
+                        Network::Testnet,
+                        Network::Testnet4,
+                        Network::Regtest,
+                    ]
+                    .iter()
+                    .find(|n| n.as_str() == name)
+                    .ok_or_else(|| err(format!("unknown network `{}`", name)))?;
+
+                    cfg.network = Some(*network);
+                }
+                "root" => cfg.root = Some(value.string().map_err(err)?.into()),
+                "datadir" => cfg.datadir = Some(value.string().map_err(err)?.into()),
+                "import_headers" => {
+                    cfg.import_headers = Some(value.string().map_err(err)?.into());
+                }
+                "connect" => cfg.connect = value.parse_all().map_err(err)?,
+                "listen" => cfg.listen = value.parse_all().map_err(err)?,
+                "proxy" => cfg.proxy = Some(value.parse().map_err(err)?),
+                "log" => cfg.log = Some(value.parse().map_err(err)?),
+                "watch" => {
+                    cfg.watch = value
+                        .strings()
+                        .map_err(err)?
+                        .into_iter()
+                        .map(|s| match Address::from_str(&s) {
+                            Ok(addr) => Ok(addr.script_pubkey()),
+                            Err(_) => Script::from_hex(&s).map_err(|_| {
+                                format!("`{}` is neither an address nor a hex script", s)
+                            }),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                }
+                _ => return Err(err(format!("unknown key `{}`", key))),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// A configuration value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn string(self) -> Result<String, String> {
+        match self {
+            Self::String(s) => Ok(s),
+            _ => Err(String::from("expected a string")),
+        }
+    }
+
+    fn strings(self) -> Result<Vec<String>, String> {
+        match self {
+            Self::Array(values) => values.into_iter().map(Value::string).collect(),
+            _ => Err(String::from("expected an array of strings")),
+        }
+    }
+
+    fn parse<T: FromStr>(self) -> Result<T, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let s = self.string()?;
+        s.parse()
+            .map_err(|e| format!("invalid value `{}`: {}", s, e))
+    }
+
+    fn parse_all<T: FromStr>(self) -> Result<Vec<T>, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.strings()?
+            .into_iter()
+            .map(|s| Value::String(s).parse())
+            .collect()
+    }
+}
+
+/// Parse the top-level key-value pairs of a TOML document. Returns the line of each pair,
+/// along with the pair.
+fn parse(s: &str) -> Result<Vec<(usize, String, Value)>, Error> {
+    let mut pairs = Vec::new();
+    let mut keys = HashSet::new();
+    let mut lines = s.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+    while let Some((line, text)) = lines.next() {
+        let err = |message: &str| Error::Parse {
+            line,
+            message: message.to_owned(),
+        };
+        let mut text = strip_comment(text).trim().to_owned();
+
+        if text.is_empty() {
+            continue;
+        }
+        if text.starts_with('[') {
+            return Err(err("tables are not supported"));
+        }
+        // Arrays may span multiple lines.
+        while depth(&text) > 0 {
+            match lines.next() {
+                Some((_, more)) => {
+                    text.push(' ');
+                    text.push_str(strip_comment(more).trim());
+                }
+                None => return Err(err("unterminated array")),
+            }
+        }
+        let (key, value) = text
+            .split_once('=')
+            .ok_or_else(|| err("expected `key = value`"))?;
+        let key = key.trim().to_owned();
+        let (value, rest) = value_prefix(value.trim()).map_err(|e| err(&e))?;
+
+        if !rest.trim().is_empty() {
+            return Err(err("unexpected characters after value"));
+        }
+        if !keys.insert(key.clone()) {
+            return Err(err(&format!("duplicate key `{}`", key)));
+        }
+        pairs.push((line, key, value));
+    }
+    Ok(pairs)
+}
+
+/// Strip a comment from a line, ignoring `#` characters within strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if quoted && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Parse a value at the start of the input. Returns the value and the remaining input.
+fn value_prefix(s: &str) -> Result<(Value, &str), String> {
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+
+        loop {
+            rest = rest.trim_start();
+
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), rest));
+            }
+            let (value, r) = value_prefix(rest)?;
+            values.push(value);
+            rest = r.trim_start();
+
+            if let Some(r) = rest.strip_prefix(',') {
+                rest = r;
+            } else if !rest.starts_with(']') {
+                return Err(String::from("expected `,` or `]` in array"));
+            }
+        }
+    } else if let Some(rest) = s.strip_prefix('"') {
+        let mut string = String::new();
+        let mut chars = rest.char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(string), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, '"')) => string.push('"'),
+                    Some((_, '\\')) => string.push('\\'),
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    _ => return Err(String::from("invalid escape sequence")),
+                },
+                c => string.push(c),
+            }
+        }
+        Err(String::from("unterminated string"))
+    } else {
+        Err(format!("invalid value `{}`", s))
+    }
+}
+
+/// Get the array nesting depth at the end of a line, ignoring brackets within strings.
+fn depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        match c {
+            '\\' if quoted && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            '[' if !quoted => depth += 1,
+            ']' if !quoted => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cfg = ConfigFile::from_str(
+            r#"
+            # Connect to our own node.
+            network = "regtest" # Not mainnet.
+            datadir = "/tmp/nakamoto#1"
+            connect = ["127.0.0.1:18444", "[::1]:18444"]
+            proxy = "127.0.0.1:9050"
+            log = "debug"
+            watch = [
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080", # Address.
+                "6a",
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(cfg.network, Some(Network::Regtest)));
+        assert_eq!(cfg.datadir, Some(PathBuf::from("/tmp/nakamoto#1")));
+        assert_eq!(cfg.proxy, Some(([127, 0, 0, 1], 9050).into()));
+        assert_eq!(
+            cfg.connect,
+            vec![
+                ([127, 0, 0, 1], 18444).into(),
+                "[::1]:18444".parse().unwrap()
+            ]
+        );
+        assert!(cfg.listen.is_empty());
+        assert_eq!(cfg.log, Some(log::Level::Debug));
+        assert_eq!(cfg.watch.len(), 2);
+        assert_eq!(cfg.watch[1], Script::from(vec![0x6a]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (input, line) in [
+            ("network = \"simnet\"", 1),
+            ("\n\nconnect = [\"127.0.0.1\"]", 3),
+            ("log = \"info\"\nlog = \"debug\"", 2),
+            ("[node]\nnetwork = \"testnet\"", 1),
+            ("watch = [\n\"00\",\n", 1),
+            ("unknown = \"\"", 1),
+            ("datadir = 1", 1),
+        ] {
+            match ConfigFile::from_str(input) {
+                Err(Error::Parse { line: l, .. }) => assert_eq!(l, line, "{}", input),
+                other => panic!("unexpected result for {:?}: {:?}", input, other),
+            }
+        }
+    }
+}
@@ -2,45 +2,122 @@
 #![deny(missing_docs, unsafe_code)]
 
 use std::net;
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::thread;
 use std::time;
 
+use nakamoto_client::handle::Handle as _;
+use nakamoto_client::Event;
+use nakamoto_p2p::protocol::{syncmgr, Command};
+
 pub use nakamoto_client::client::{self, Client, Config, Network};
 pub use nakamoto_client::error::Error;
 pub use nakamoto_client::Domain;
 
+pub mod config;
 pub mod logger;
+#[cfg(unix)]
+pub mod signals;
+
+use config::ConfigFile;
 
 /// The network reactor we're going to use.
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
 
 /// Run the light-client. Takes an initial list of peers to connect to, a list of listen addresses,
-/// the client root and the Bitcoin network to connect to.
+/// the client root, an optional file of trusted block headers to import and the Bitcoin network
+/// to connect to.
 pub fn run(
     connect: &[net::SocketAddr],
     listen: &[net::SocketAddr],
     root: Option<PathBuf>,
+    import_headers: Option<PathBuf>,
     domains: &[Domain],
     network: Network,
 ) -> Result<(), Error> {
+    let cfg = ConfigFile {
+        network: Some(network),
+        root,
+        connect: connect.to_vec(),
+        listen: listen.to_vec(),
+        import_headers,
+        ..ConfigFile::default()
+    };
+    run_daemon(cfg, domains)
+}
+
+/// Run the light-client with the given configuration, until it is shut down, or a termination
+/// signal is received. Once the client is synced, the configured scripts are watched from the
+/// tip onwards.
+pub fn run_daemon(file: ConfigFile, domains: &[Domain]) -> Result<(), Error> {
     let mut cfg = Config {
-        network,
-        listen: if listen.is_empty() {
+        network: file.network.unwrap_or_default(),
+        listen: if file.listen.is_empty() {
             vec![([0, 0, 0, 0], 0).into()]
         } else {
-            listen.to_vec()
+            file.listen
         },
-        connect: connect.to_vec(),
         domains: domains.to_vec(),
         timeout: time::Duration::from_secs(30),
+        import_headers: file.import_headers,
+        datadir: file.datadir,
+        proxy: file.proxy,
         ..Config::default()
     };
-    if let Some(path) = root {
+    if let Some(path) = file.root {
         cfg.root = path;
     }
-    if !connect.is_empty() {
-        cfg.target_outbound_peers = connect.len();
+    if !file.connect.is_empty() {
+        cfg.target_outbound_peers = file.connect.len();
+        cfg.connect = file.connect;
+    }
+
+    let mut client = Client::<Reactor>::new(cfg)?;
+    let handle = client.handle();
+
+    #[cfg(unix)]
+    {
+        signals::install()?;
+
+        let handle = handle.clone();
+        thread::spawn(move || {
+            signals::wait();
+            log::info!("Received termination signal, shutting down..");
+
+            if let Err(err) = handle.shutdown() {
+                log::error!("Error shutting down: {}", err);
+            }
+        });
+    }
+
+    if !file.watch.is_empty() {
+        let watch = file.watch;
+        let events = handle.events();
+
+        thread::spawn(move || {
+            let synced = events
+                .iter()
+                .any(|e| matches!(e, Event::SyncManager(syncmgr::Event::Synced(_, _))));
+            if !synced {
+                return;
+            }
+            let (reply, result) = crossbeam_channel::bounded(1);
+
+            log::info!("Watching {} script(s) from the tip..", watch.len());
+
+            if let Err(err) = handle.command(Command::Rescan {
+                from: Bound::Unbounded,
+                to: Bound::Unbounded,
+                watch,
+                reply,
+            }) {
+                log::error!("Error watching scripts: {}", err);
+            } else if let Ok(Err(err)) = result.recv() {
+                log::error!("Error watching scripts: {}", err);
+            }
+        });
     }
 
-    Client::<Reactor>::new(cfg)?.run()
+    client.run()
 }
@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use argh::FromArgs;
 
 use nakamoto_client::client::Network;
+use nakamoto_node::config::ConfigFile;
 use nakamoto_node::{logger, Domain};
 
 #[derive(FromArgs)]
@@ -21,6 +22,10 @@ pub struct Options {
     #[argh(switch)]
     pub testnet: bool,
 
+    /// use the bitcoin test network, version 4 (default: false)
+    #[argh(switch)]
+    pub testnet4: bool,
+
     /// only connect to IPv4 addresses (default: false)
     #[argh(switch, short = '4')]
     pub ipv4: bool,
@@ -30,12 +35,26 @@ pub struct Options {
     pub ipv6: bool,
 
     /// log level (default: info)
-    #[argh(option, default = "log::Level::Info")]
-    pub log: log::Level,
+    #[argh(option)]
+    pub log: Option<log::Level>,
 
     /// root directory for nakamoto files (default: ~)
     #[argh(option)]
     pub root: Option<PathBuf>,
+
+    /// import trusted block headers from the given file on startup
+    #[argh(option)]
+    pub import_headers: Option<PathBuf>,
+
+    /// connect to peers through the given SOCKS5 proxy, eg. Tor, using a separate
+    /// circuit for each peer
+    #[argh(option)]
+    pub proxy: Option<net::SocketAddr>,
+
+    /// read the configuration from the given TOML file. Command-line options take
+    /// precedence over the file
+    #[argh(option)]
+    pub config: Option<PathBuf>,
 }
 
 impl Options {
@@ -46,14 +65,42 @@ impl Options {
 
 fn main() {
     let opts = Options::from_env();
+    let mut cfg = match &opts.config {
+        Some(path) => match ConfigFile::load(path) {
+            Ok(cfg) => cfg,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => ConfigFile::default(),
+    };
 
-    logger::init(opts.log).expect("initializing logger for the first time");
+    logger::init(opts.log.or(cfg.log).unwrap_or(log::Level::Info))
+        .expect("initializing logger for the first time");
 
-    let network = if opts.testnet {
-        Network::Testnet
-    } else {
-        Network::Mainnet
-    };
+    if opts.testnet4 {
+        cfg.network = Some(Network::Testnet4);
+    } else if opts.testnet {
+        cfg.network = Some(Network::Testnet);
+    } else if cfg.network.is_none() {
+        cfg.network = Some(Network::Mainnet);
+    }
+    if !opts.connect.is_empty() {
+        cfg.connect = opts.connect;
+    }
+    if !opts.listen.is_empty() {
+        cfg.listen = opts.listen;
+    }
+    if opts.root.is_some() {
+        cfg.root = opts.root;
+    }
+    if opts.import_headers.is_some() {
+        cfg.import_headers = opts.import_headers;
+    }
+    if opts.proxy.is_some() {
+        cfg.proxy = opts.proxy;
+    }
 
     let domains = if opts.ipv4 && opts.ipv6 {
         vec![Domain::IPV4, Domain::IPV6]
@@ -65,7 +112,7 @@ fn main() {
         vec![Domain::IPV4, Domain::IPV6]
     };
 
-    if let Err(e) = nakamoto_node::run(&opts.connect, &opts.listen, opts.root, &domains, network) {
+    if let Err(e) = nakamoto_node::run_daemon(cfg, &domains) {
         log::error!("Exiting: {}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,39 @@
+//! Termination signal handling.
+//!
+//! `SIGINT` and `SIGTERM` are caught, so that the daemon can shut the client down cleanly,
+//! flushing its state to disk, instead of being killed.
+#![allow(unsafe_code)]
+use std::io;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+/// Set when a termination signal is received.
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+/// Install the signal handlers. Signals received before this is called terminate the
+/// process.
+pub fn install() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        let result =
+            unsafe { libc::signal(signal, handle as extern "C" fn(c_int) as libc::sighandler_t) };
+
+        if result == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Block until a termination signal is received.
+pub fn wait() {
+    while !TERMINATE.load(Ordering::SeqCst) {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
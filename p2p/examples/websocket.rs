@@ -0,0 +1,228 @@
+//! A minimal reactor running the protocol over WebSockets, eg. in a browser-based wallet.
+//!
+//! Browsers can't open TCP connections, so peers are reached through a WebSocket-to-TCP
+//! proxy, eg. `websockify`, which forwards the bytes received on a WebSocket to the peer's
+//! TCP socket, and back. Since the bytes of a message may be split across WebSocket
+//! messages, the reactor buffers them until a full message can be decoded.
+//!
+//! The reactor doesn't perform any I/O, nor does it read the clock. The host, eg. JavaScript
+//! glue generated with `wasm-bindgen` when compiling to `wasm32-unknown-unknown`, opens the
+//! WebSockets, and calls into the reactor when a socket opens, closes or receives data, and
+//! when a timer fires, passing the current time. In return, the reactor returns the actions
+//! the host should perform: which sockets to open, what to send, and when to call back.
+//!
+//! Run natively with:
+//!
+//!     cargo run -p nakamoto-p2p --example websocket
+//!
+//! This prints the actions the reactor asks for, against a peer that never answers.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net;
+
+use crossbeam_channel as chan;
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_chain::filter::cache::FilterCache;
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::peer;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::network::message::RawNetworkMessage;
+use nakamoto_p2p::protocol::{self, DisconnectReason, Input, Link, Out, PeerId, Protocol};
+
+/// An action for the host to perform.
+enum Action {
+    /// Open a WebSocket to the peer, via the proxy.
+    Open(PeerId),
+    /// Send bytes on the peer's WebSocket.
+    Send(PeerId, Vec<u8>),
+    /// Close the peer's WebSocket.
+    Close(PeerId),
+    /// Call [`Reactor::tick`] after the given number of milliseconds.
+    Wake(u128),
+}
+
+/// A reactor driven by the host, see the module documentation.
+struct Reactor<T, F, P> {
+    protocol: Protocol<T, F, P>,
+    outputs: chan::Receiver<Out>,
+    /// Bytes received from each peer, that don't yet form a full message.
+    buffers: HashMap<PeerId, Vec<u8>>,
+    /// Inputs generated while processing outputs.
+    inputs: VecDeque<Input>,
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Reactor<T, F, P> {
+    /// Create a reactor, and initialize the protocol.
+    fn new<B>(builder: B, now: LocalTime) -> (Self, Vec<Action>)
+    where
+        B: FnOnce(chan::Sender<Out>) -> Protocol<T, F, P>,
+    {
+        let (tx, outputs) = chan::unbounded();
+        let mut reactor = Self {
+            protocol: builder(tx),
+            outputs,
+            buffers: HashMap::new(),
+            inputs: VecDeque::new(),
+        };
+        reactor.protocol.initialize(now);
+
+        let actions = reactor.actions(now);
+        (reactor, actions)
+    }
+
+    /// The peer's WebSocket was opened.
+    fn opened(&mut self, addr: PeerId, now: LocalTime) -> Vec<Action> {
+        self.buffers.insert(addr, Vec::new());
+        self.step(
+            Input::Connected {
+                addr,
+                // Not known to the browser.
+                local_addr: ([0, 0, 0, 0], 0).into(),
+                link: Link::Outbound,
+            },
+            now,
+        )
+    }
+
+    /// Data was received on the peer's WebSocket.
+    fn received(&mut self, addr: PeerId, bytes: &[u8], now: LocalTime) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let buffer = match self.buffers.get_mut(&addr) {
+            Some(buffer) => buffer,
+            None => return actions,
+        };
+        buffer.extend_from_slice(bytes);
+
+        while let Some(buffer) = self.buffers.get_mut(&addr) {
+            match encode::deserialize_partial::<RawNetworkMessage>(buffer) {
+                Ok((msg, n)) => {
+                    buffer.drain(..n);
+                    actions.extend(self.step(Input::Received(addr, msg), now));
+                }
+                Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(err) => {
+                    actions.push(Action::Close(addr));
+                    actions.extend(self.closed(
+                        addr,
+                        DisconnectReason::ConnectionError(err.to_string()),
+                        now,
+                    ));
+                    break;
+                }
+            }
+        }
+        actions
+    }
+
+    /// The peer's WebSocket was closed.
+    fn closed(&mut self, addr: PeerId, reason: DisconnectReason, now: LocalTime) -> Vec<Action> {
+        self.buffers.remove(&addr);
+        self.step(Input::Disconnected(addr, reason), now)
+    }
+
+    /// A timer requested with [`Action::Wake`] fired.
+    fn tick(&mut self, now: LocalTime) -> Vec<Action> {
+        self.step(Input::Tick, now)
+    }
+
+    fn step(&mut self, input: Input, now: LocalTime) -> Vec<Action> {
+        self.protocol.step(input, now);
+        self.actions(now)
+    }
+
+    /// Turn the protocol outputs into actions for the host.
+    fn actions(&mut self, now: LocalTime) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        loop {
+            for out in self.outputs.try_iter() {
+                match out {
+                    Out::Message(addr, msg) => {
+                        let bytes = encode::serialize(&msg);
+
+                        self.inputs.push_back(Input::Sent(addr, bytes.len()));
+                        actions.push(Action::Send(addr, bytes));
+                    }
+                    Out::Connect(addr, _timeout, _proxy) => {
+                        self.inputs.push_back(Input::Connecting { addr });
+                        actions.push(Action::Open(addr));
+                    }
+                    Out::Disconnect(addr, reason) => {
+                        if self.buffers.remove(&addr).is_some() {
+                            self.inputs.push_back(Input::Disconnected(addr, reason));
+                            actions.push(Action::Close(addr));
+                        }
+                    }
+                    Out::SetTimeout(timeout) => actions.push(Action::Wake(timeout.as_millis())),
+                    Out::Event(event) => println!("event: {:?}", event),
+                    Out::Shutdown => {}
+                }
+            }
+            match self.inputs.pop_front() {
+                Some(input) => self.protocol.step(input, now),
+                None => break,
+            }
+        }
+        actions
+    }
+}
+
+fn main() {
+    let network = Network::Regtest;
+    let peer: net::SocketAddr = ([127, 0, 0, 1], 18444).into();
+    // In the browser, the time would come from `Date.now()`, and the seed from
+    // `crypto.getRandomValues()`.
+    let now = LocalTime::from_secs(1_600_000_000);
+    let rng = fastrand::Rng::with_seed(42);
+
+    let cache = BlockCache::from(store::Memory::genesis(network), network.params(), &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::genesis(network)).unwrap();
+    let peers = HashMap::new();
+    let cfg = protocol::Config {
+        network,
+        connect: vec![peer],
+        target_outbound_peers: 1,
+        ..protocol::Config::default()
+    };
+
+    let (mut reactor, actions) = Reactor::new(
+        |upstream| {
+            Protocol::new(
+                cache,
+                filters,
+                peers,
+                AdjustedTime::new(now),
+                rng,
+                cfg,
+                upstream,
+            )
+        },
+        now,
+    );
+    print(&actions);
+
+    print(&reactor.opened(peer, now));
+    print(&reactor.received(peer, &[], now));
+
+    // The peer never answers our `version` message.
+    let later = LocalTime::from_secs(1_600_000_000 + 60);
+    print(&reactor.tick(later));
+}
+
+fn print(actions: &[Action]) {
+    for action in actions {
+        match action {
+            Action::Open(addr) => println!("action: open {}", addr),
+            Action::Send(addr, bytes) => println!("action: send {} bytes to {}", bytes.len(), addr),
+            Action::Close(addr) => println!("action: close {}", addr),
+            Action::Wake(ms) => println!("action: wake in {}ms", ms),
+        }
+    }
+}
@@ -1,21 +1,33 @@
 //! Events generated by the peer-to-peer system.
+use std::collections::VecDeque;
+use std::iter;
 use std::net;
-use std::sync::{Arc, Mutex};
+use std::ops::Deref;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex, Weak};
 use std::time;
 
 use bitcoin::network::message::NetworkMessage;
 use crossbeam_channel as chan;
 
-use crate::protocol::PeerId;
 use crate::protocol::{addrmgr, connmgr, peermgr, spvmgr, syncmgr};
+use crate::protocol::{EffectiveConfig, PeerId};
 
 pub use chan::RecvTimeoutError;
 
+/// How often a publisher waiting for room on a subscription channel checks whether the
+/// subscriber is still there.
+const BLOCKED_SEND_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
 /// A peer-to-peer event.
 #[derive(Debug, Clone)]
 pub enum Event {
     /// The node is now listening for incoming connections.
     Listening(net::SocketAddr),
+    /// The node started, with the given configuration.
+    NodeStarted(EffectiveConfig),
+    /// The node stopped. All state was flushed to disk and all connections were closed.
+    NodeStopped,
     /// Received a message from a peer.
     Received(PeerId, NetworkMessage),
     /// An address manager event.
@@ -30,25 +42,511 @@ pub enum Event {
     SpvManager(spvmgr::Event),
 }
 
+impl Event {
+    /// Check whether this event makes an earlier one redundant. This is the case for events
+    /// that only report progress, eg. filters processed without a match, and is used to
+    /// coalesce events on a congested channel, see [`Overflow::Coalesce`].
+    pub fn supersedes(&self, earlier: &Event) -> bool {
+        use spvmgr::Event::*;
+
+        match (self, earlier) {
+            (Event::SpvManager(new), Event::SpvManager(old)) => matches!(
+                (new, old),
+                (
+                    FilterProcessed { matched: false, .. },
+                    FilterProcessed { matched: false, .. }
+                ) | (RescanProgress { .. }, RescanProgress { .. })
+                    | (RescanCheckpoint(_), RescanCheckpoint(_))
+                    | (FilterHeadersImported { .. }, FilterHeadersImported { .. })
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// A set of event kinds, for subscribing to a subset of events. Event kinds correspond to
+/// the variants of [`Event`], and can be combined with `|`.
+///
+/// ```
+/// use nakamoto_p2p::event::{Event, EventMask};
+///
+/// let mask = EventMask::LISTENING | EventMask::SPV_MANAGER;
+///
+/// assert!(mask.matches(&Event::Listening(([0, 0, 0, 0], 8333).into())));
+/// assert!(!EventMask::PEERS.matches(&Event::Listening(([0, 0, 0, 0], 8333).into())));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMask(u32);
+
+impl EventMask {
+    /// No events.
+    pub const NONE: Self = Self(0);
+    /// [`Event::Listening`] events.
+    pub const LISTENING: Self = Self(1 << 0);
+    /// [`Event::NodeStarted`] events.
+    pub const NODE_STARTED: Self = Self(1 << 1);
+    /// [`Event::NodeStopped`] events.
+    pub const NODE_STOPPED: Self = Self(1 << 8);
+    /// [`Event::Received`] events.
+    pub const RECEIVED: Self = Self(1 << 2);
+    /// [`Event::AddrManager`] events.
+    pub const ADDR_MANAGER: Self = Self(1 << 3);
+    /// [`Event::SyncManager`] events.
+    pub const SYNC_MANAGER: Self = Self(1 << 4);
+    /// [`Event::ConnManager`] events.
+    pub const CONN_MANAGER: Self = Self(1 << 5);
+    /// [`Event::PeerManager`] events.
+    pub const PEER_MANAGER: Self = Self(1 << 6);
+    /// [`Event::SpvManager`] events, ie. filter sync and rescan events.
+    pub const SPV_MANAGER: Self = Self(1 << 7);
+    /// Peer lifecycle events, ie. connections, handshakes and disconnections.
+    pub const PEERS: Self = Self(Self::CONN_MANAGER.0 | Self::PEER_MANAGER.0);
+    /// All events.
+    pub const ALL: Self = Self(u32::MAX);
+
+    /// The kind of the given event.
+    pub fn of(event: &Event) -> Self {
+        match event {
+            Event::Listening(_) => Self::LISTENING,
+            Event::NodeStarted(_) => Self::NODE_STARTED,
+            Event::NodeStopped => Self::NODE_STOPPED,
+            Event::Received(_, _) => Self::RECEIVED,
+            Event::AddrManager(_) => Self::ADDR_MANAGER,
+            Event::SyncManager(_) => Self::SYNC_MANAGER,
+            Event::ConnManager(_) => Self::CONN_MANAGER,
+            Event::PeerManager(_) => Self::PEER_MANAGER,
+            Event::SpvManager(_) => Self::SPV_MANAGER,
+        }
+    }
+
+    /// Check whether the event is of a kind included in the mask.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.0 & Self::of(event).0 != 0
+    }
+}
+
+impl std::ops::BitOr for EventMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Selects the events delivered to a filtered subscription.
+/// See [`Subscriber::subscribe_filtered`].
+pub trait EventFilter<T>: Send + 'static {
+    /// Check whether the event should be delivered.
+    fn matches(&self, event: &T) -> bool;
+}
+
+impl EventFilter<Event> for EventMask {
+    fn matches(&self, event: &Event) -> bool {
+        EventMask::matches(self, event)
+    }
+}
+
+impl<T, F: Fn(&T) -> bool + Send + 'static> EventFilter<T> for F {
+    fn matches(&self, event: &T) -> bool {
+        self(event)
+    }
+}
+
+/// What to do when a bounded subscription channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the subscriber to make room. This stalls the node until it does, or until
+    /// the receiver is dropped. Other subscriptions aren't locked while waiting, so the
+    /// consumer may keep subscribing from its own thread.
+    Block,
+    /// Drop the oldest event on the channel to make room.
+    DropOldest,
+    /// Hold back events made redundant by newer ones, eg. rescan progress reports, keeping
+    /// only the latest, and deliver it once the channel is drained. Other events wait for
+    /// the subscriber to make room, like with [`Overflow::Block`].
+    Coalesce,
+}
+
+/// Capacity and overflow policy of bounded subscription channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    /// Maximum number of events queued on a channel. At least one.
+    pub capacity: usize,
+    /// What to do when the channel is full.
+    pub overflow: Overflow,
+}
+
+/// The receiving end of a bounded subscription. Dereferences to a channel receiver, but
+/// events should be received with its own methods, which also deliver the events held back
+/// when coalescing. See [`Subscriber::subscribe_bounded`].
+#[derive(Debug)]
+pub struct BoundedReceiver<T> {
+    receiver: chan::Receiver<T>,
+    /// Events held back by the publisher, received once the channel is drained.
+    pending: Arc<Mutex<VecDeque<T>>>,
+    _alive: Arc<()>,
+}
+
+impl<T> BoundedReceiver<T> {
+    /// Receive an event, if one is available.
+    pub fn try_recv(&self) -> Result<T, chan::TryRecvError> {
+        // Nb. The publisher only holds back events when the channel is full, and does so
+        // with this lock held, so events are received in the order they were published.
+        let mut pending = self.pending.lock().unwrap();
+
+        match self.receiver.try_recv() {
+            Err(chan::TryRecvError::Empty) => pending.pop_front().ok_or(chan::TryRecvError::Empty),
+            result => result,
+        }
+    }
+
+    /// Receive an event, waiting until one is available.
+    pub fn recv(&self) -> Result<T, chan::RecvError> {
+        match self.try_recv() {
+            Ok(event) => Ok(event),
+            Err(chan::TryRecvError::Empty) => self.receiver.recv(),
+            Err(chan::TryRecvError::Disconnected) => Err(chan::RecvError),
+        }
+    }
+
+    /// Receive an event, waiting until one is available, or the timeout elapses.
+    pub fn recv_timeout(&self, timeout: time::Duration) -> Result<T, chan::RecvTimeoutError> {
+        match self.try_recv() {
+            Ok(event) => Ok(event),
+            Err(chan::TryRecvError::Empty) => self.receiver.recv_timeout(timeout),
+            Err(chan::TryRecvError::Disconnected) => Err(chan::RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Iterate over the events available, without waiting.
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        iter::from_fn(move || self.try_recv().ok())
+    }
+
+    /// Iterate over the events, waiting for each one.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        iter::from_fn(move || self.recv().ok())
+    }
+}
+
+impl<T> Deref for BoundedReceiver<T> {
+    type Target = chan::Receiver<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
 /// Any type that is able to publish events.
 pub trait Publisher: Send + Sync {
     /// Publish an event.
     fn publish(&self, event: Event);
 }
 
+/// An event, stamped with its position in a subscription's event stream.
+///
+/// Sequence numbers start at zero for every subscription and increase by one for each event
+/// published to it, whether it was delivered, or dropped or coalesced on overflow. A consumer that sees a sequence
+/// number other than the one it expected has missed events, and should resynchronize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequenced<T> {
+    /// Sequence number of the event.
+    pub seq: u64,
+    /// The event itself.
+    pub event: T,
+}
+
+/// Events were skipped in a sequenced event stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// The sequence number that was expected.
+    pub expected: u64,
+    /// The sequence number that was received.
+    pub received: u64,
+}
+
+impl Gap {
+    /// Number of events missed.
+    pub fn missed(&self) -> u64 {
+        self.received.saturating_sub(self.expected)
+    }
+}
+
+impl std::fmt::Display for Gap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "expected event #{}, received event #{}",
+            self.expected, self.received
+        )
+    }
+}
+
+/// Tracks the sequence numbers of a sequenced event stream, to detect missed events.
+#[derive(Debug, Default, Clone)]
+pub struct GapDetector {
+    next: u64,
+}
+
+impl GapDetector {
+    /// Create a new gap detector, expecting the stream to start at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number of the next expected event.
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    /// Check the given sequence number against the expected one. Returns a [`Gap`] if events
+    /// were missed. In any case, the detector then expects the event following `seq`.
+    ///
+    /// ```
+    /// use nakamoto_p2p::event::{Gap, GapDetector};
+    ///
+    /// let mut detector = GapDetector::new();
+    ///
+    /// assert_eq!(detector.check(0), Ok(()));
+    /// assert_eq!(detector.check(1), Ok(()));
+    /// assert_eq!(detector.check(4), Err(Gap { expected: 2, received: 4 }));
+    /// assert_eq!(detector.check(5), Ok(()));
+    /// ```
+    pub fn check(&mut self, seq: u64) -> Result<(), Gap> {
+        let expected = self.next;
+        self.next = seq + 1;
+
+        if seq == expected {
+            Ok(())
+        } else {
+            Err(Gap {
+                expected,
+                received: seq,
+            })
+        }
+    }
+}
+
+/// A subscriber's end of a broadcast channel.
+enum Subscription<T> {
+    /// Receives plain events.
+    Plain(chan::Sender<T>),
+    /// Receives events stamped with a sequence number, on a bounded channel.
+    Sequenced {
+        seq: u64,
+        channel: Bounded<Sequenced<T>>,
+        /// Checks whether an event makes an earlier one redundant.
+        supersedes: fn(&T, &T) -> bool,
+    },
+    /// Receives the events matching a filter.
+    Filtered {
+        filter: Box<dyn EventFilter<T>>,
+        sender: chan::Sender<T>,
+    },
+    /// Receives events on a bounded channel.
+    Bounded {
+        channel: Bounded<T>,
+        /// Checks whether an event makes an earlier one redundant.
+        supersedes: fn(&T, &T) -> bool,
+    },
+}
+
+/// The publisher's end of a bounded subscription channel.
+struct Bounded<T> {
+    sender: chan::Sender<T>,
+    /// Used to drop the oldest events, when the channel is full.
+    receiver: chan::Receiver<T>,
+    overflow: Overflow,
+    /// Events held back until the channel is drained, when coalescing. Shared with the
+    /// subscriber's [`BoundedReceiver`].
+    pending: Arc<Mutex<VecDeque<T>>>,
+    /// Count of events dropped, shared between all subscriptions.
+    dropped: Arc<AtomicU64>,
+    /// Gone once the subscriber's [`BoundedReceiver`] is dropped. Since we hold on to
+    /// a receiver, the channel never disconnects on its own.
+    alive: Weak<()>,
+}
+
+/// A send that has to wait for the subscriber to make room. It is made once the lock on the
+/// subscriptions is released.
+type BlockedSend = Box<dyn FnOnce() + Send>;
+
+/// Send an event on a bounded channel, waiting for room for as long as the subscriber is
+/// there. Returns `false` if the subscriber is gone.
+fn send_blocking<T>(sender: &chan::Sender<T>, mut event: T, alive: &Weak<()>) -> bool {
+    loop {
+        match sender.send_timeout(event, BLOCKED_SEND_INTERVAL) {
+            Ok(()) => return true,
+            Err(chan::SendTimeoutError::Timeout(e)) if alive.strong_count() > 0 => event = e,
+            Err(_) => return false,
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Bounded<T> {
+    /// Send an event according to the overflow policy. Returns `false` if the subscriber is
+    /// gone. Sends that have to wait for the subscriber are added to `blocked` instead.
+    fn send(
+        &mut self,
+        event: T,
+        supersedes: impl Fn(&T, &T) -> bool,
+        blocked: &mut Vec<BlockedSend>,
+    ) -> bool {
+        if self.alive.strong_count() == 0 {
+            return false;
+        }
+        match self.overflow {
+            Overflow::Block => match self.sender.try_send(event) {
+                Ok(()) => true,
+                Err(chan::TrySendError::Full(event)) => {
+                    let (sender, alive) = (self.sender.clone(), self.alive.clone());
+
+                    blocked.push(Box::new(move || {
+                        send_blocking(&sender, event, &alive);
+                    }));
+                    true
+                }
+                Err(chan::TrySendError::Disconnected(_)) => false,
+            },
+            Overflow::DropOldest => {
+                let mut event = event;
+
+                loop {
+                    match self.sender.try_send(event) {
+                        Ok(()) => return true,
+                        Err(chan::TrySendError::Full(e)) => {
+                            if self.receiver.try_recv().is_ok() {
+                                self.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+                            }
+                            event = e;
+                        }
+                        Err(chan::TrySendError::Disconnected(_)) => return false,
+                    }
+                }
+            }
+            Overflow::Coalesce => {
+                let mut pending = self.pending.lock().unwrap();
+
+                // Deliver held back events first, to preserve ordering.
+                while let Some(e) = pending.front() {
+                    match self.sender.try_send(e.clone()) {
+                        Ok(()) => {
+                            pending.pop_front();
+                        }
+                        Err(chan::TrySendError::Full(_)) => break,
+                        Err(chan::TrySendError::Disconnected(_)) => return false,
+                    }
+                }
+                let event = if pending.is_empty() {
+                    match self.sender.try_send(event) {
+                        Ok(()) => return true,
+                        Err(chan::TrySendError::Full(e)) => e,
+                        Err(chan::TrySendError::Disconnected(_)) => return false,
+                    }
+                } else {
+                    event
+                };
+
+                // Events that can be made redundant by a newer one are held back, in place
+                // of the event they make redundant.
+                if supersedes(&event, &event) {
+                    if let Some(i) = pending.iter().position(|e| supersedes(&event, e)) {
+                        pending.remove(i);
+                        self.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+                    }
+                    pending.push_back(event);
+
+                    return true;
+                }
+                // Other events wait for room, after the held back ones.
+                let events = pending
+                    .drain(..)
+                    .chain(iter::once(event))
+                    .collect::<Vec<_>>();
+                let (sender, alive) = (self.sender.clone(), self.alive.clone());
+
+                blocked.push(Box::new(move || {
+                    for e in events {
+                        if !send_blocking(&sender, e, &alive) {
+                            break;
+                        }
+                    }
+                }));
+                true
+            }
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Subscription<T> {
+    /// Send an event to the subscriber. Returns `false` if the subscriber is gone. Sends that
+    /// have to wait for the subscriber are added to `blocked` instead.
+    fn send(&mut self, event: T, blocked: &mut Vec<BlockedSend>) -> bool {
+        match self {
+            Self::Plain(sender) => !matches!(
+                sender.try_send(event),
+                Err(chan::TrySendError::Disconnected(_))
+            ),
+            Self::Sequenced {
+                seq,
+                channel,
+                supersedes,
+            } => {
+                let event = Sequenced { seq: *seq, event };
+                let supersedes = *supersedes;
+                // Nb. The sequence number is incremented even if the event is dropped or
+                // coalesced, so that the subscriber is able to detect the gap.
+                *seq += 1;
+
+                channel.send(event, |a, b| supersedes(&a.event, &b.event), blocked)
+            }
+            Self::Filtered { filter, sender } => {
+                if !filter.matches(&event) {
+                    // Nb. A subscriber that is gone is only dropped on the next matching event.
+                    return true;
+                }
+                !matches!(
+                    sender.try_send(event),
+                    Err(chan::TrySendError::Disconnected(_))
+                )
+            }
+            Self::Bounded {
+                channel,
+                supersedes,
+            } => channel.send(event, *supersedes, blocked),
+        }
+    }
+}
+
 /// An event publisher.
 pub struct Broadcast<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
     filter: Box<dyn Fn(Event) -> Option<T> + Send + Sync>,
 }
 
-impl<T: Clone + Send + Sync> Publisher for Broadcast<T> {
+impl<T: Clone + Send + Sync + 'static> Publisher for Broadcast<T> {
     /// Publish a message to all subscribers.
     fn publish(&self, event: Event) {
-        let mut subs = self.subscribers.lock().unwrap();
-
         if let Some(msg) = (self.filter)(event) {
-            subs.retain(|s| s.try_send(msg.clone()).is_ok());
+            let mut blocked = Vec::new();
+            let mut subscribers = self.subscribers.lock().unwrap();
+
+            let mut i = 0;
+
+            while i < subscribers.len() {
+                if subscribers[i].send(msg.clone(), &mut blocked) {
+                    i += 1;
+                } else {
+                    subscribers.remove(i);
+                }
+            }
+            drop(subscribers);
+
+            // Nb. Sends that wait for a subscriber are made without holding the lock, so
+            // that a subscriber waited on is still able to subscribe.
+            for send in blocked {
+                send();
+            }
         }
     }
 }
@@ -56,7 +554,8 @@ impl<T: Clone + Send + Sync> Publisher for Broadcast<T> {
 /// An event subscriber.
 #[derive(Clone)]
 pub struct Subscriber<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
+    dropped: Arc<AtomicU64>,
 }
 
 impl<T> Subscriber<T> {
@@ -64,10 +563,93 @@ impl<T> Subscriber<T> {
     pub fn subscribe(&self) -> chan::Receiver<T> {
         let (sender, receiver) = chan::unbounded();
         let mut subs = self.subscribers.lock().unwrap();
-        subs.push(sender);
+        subs.push(Subscription::Plain(sender));
+
+        receiver
+    }
+
+    /// Add a subscription to receive broadcast events, stamped with sequence numbers, on a
+    /// bounded channel. Events dropped or coalesced according to the overflow policy leave
+    /// a gap in the sequence, which a [`GapDetector`] finds. See
+    /// [`Subscriber::subscribe_bounded`].
+    pub fn subscribe_sequenced(
+        &self,
+        backpressure: Backpressure,
+        supersedes: fn(&T, &T) -> bool,
+    ) -> BoundedReceiver<Sequenced<T>> {
+        let (channel, receiver) = self.bounded(backpressure);
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.push(Subscription::Sequenced {
+            seq: 0,
+            channel,
+            supersedes,
+        });
+
+        receiver
+    }
+
+    /// Add a subscription to receive only the broadcast events matching the filter, eg. an
+    /// [`EventMask`] or a predicate. Events that don't match are never queued on the channel.
+    pub fn subscribe_filtered(&self, filter: impl EventFilter<T>) -> chan::Receiver<T>
+    where
+        T: 'static,
+    {
+        let (sender, receiver) = chan::unbounded();
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.push(Subscription::Filtered {
+            filter: Box::new(filter),
+            sender,
+        });
 
         receiver
     }
+
+    /// Add a subscription to receive broadcast events on a bounded channel. When the channel
+    /// is full, events are handled according to the given overflow policy. When coalescing,
+    /// `supersedes` tells whether an event makes an earlier one redundant, eg.
+    /// [`Event::supersedes`].
+    pub fn subscribe_bounded(
+        &self,
+        backpressure: Backpressure,
+        supersedes: fn(&T, &T) -> bool,
+    ) -> BoundedReceiver<T> {
+        let (channel, receiver) = self.bounded(backpressure);
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.push(Subscription::Bounded {
+            channel,
+            supersedes,
+        });
+
+        receiver
+    }
+
+    /// Create both ends of a bounded subscription channel.
+    fn bounded<U>(&self, backpressure: Backpressure) -> (Bounded<U>, BoundedReceiver<U>) {
+        let (sender, receiver) = chan::bounded(backpressure.capacity.max(1));
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        let alive = Arc::new(());
+
+        (
+            Bounded {
+                sender,
+                receiver: receiver.clone(),
+                overflow: backpressure.overflow,
+                pending: pending.clone(),
+                dropped: self.dropped.clone(),
+                alive: Arc::downgrade(&alive),
+            },
+            BoundedReceiver {
+                receiver,
+                pending,
+                _alive: alive,
+            },
+        )
+    }
+
+    /// Number of events dropped or coalesced so far on bounded subscriptions.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(atomic::Ordering::Relaxed)
+    }
 }
 
 /// Create a new broadcast channel.
@@ -80,7 +662,10 @@ pub fn broadcast<T>(
             subscribers: subscribers.clone(),
             filter: Box::new(filter),
         },
-        Subscriber { subscribers },
+        Subscriber {
+            subscribers,
+            dropped: Arc::new(AtomicU64::new(0)),
+        },
     )
 }
 
@@ -117,3 +702,169 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequenced_subscription() {
+        let (publisher, subscriber) = broadcast(Some);
+        let listening = |port| Event::Listening(([0, 0, 0, 0], port).into());
+        let backpressure = |capacity| Backpressure {
+            capacity,
+            overflow: Overflow::DropOldest,
+        };
+        let first = subscriber.subscribe_sequenced(backpressure(8), Event::supersedes);
+
+        publisher.publish(listening(0));
+        publisher.publish(listening(1));
+
+        // A late subscription starts its own sequence at zero.
+        let second = subscriber.subscribe_sequenced(backpressure(8), Event::supersedes);
+        publisher.publish(listening(2));
+
+        let mut detector = GapDetector::new();
+        for e in first.try_iter() {
+            detector.check(e.seq).unwrap();
+        }
+        assert_eq!(detector.next(), 3);
+        assert_eq!(
+            second.try_iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![0]
+        );
+
+        // Events dropped when the channel overflows leave a gap.
+        let slow = subscriber.subscribe_sequenced(backpressure(2), Event::supersedes);
+        for port in 0..5 {
+            publisher.publish(listening(port));
+        }
+        let mut detector = GapDetector::new();
+        let gaps = slow
+            .try_iter()
+            .filter_map(|e| detector.check(e.seq).err())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            gaps,
+            vec![Gap {
+                expected: 0,
+                received: 3
+            }]
+        );
+        assert_eq!(gaps[0].missed(), 3);
+        assert_eq!(detector.next(), 5);
+    }
+
+    #[test]
+    fn test_filtered_subscription() {
+        let (publisher, subscriber) = broadcast(Some);
+        let listening = subscriber.subscribe_filtered(EventMask::LISTENING);
+        let peers = subscriber.subscribe_filtered(EventMask::PEERS);
+        let port = subscriber
+            .subscribe_filtered(|e: &Event| matches!(e, Event::Listening(a) if a.port() == 1));
+
+        publisher.publish(Event::Listening(([0, 0, 0, 0], 0).into()));
+        publisher.publish(Event::Listening(([0, 0, 0, 0], 1).into()));
+
+        assert_eq!(listening.try_iter().count(), 2);
+        assert_eq!(peers.try_iter().count(), 0);
+        assert_eq!(port.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_bounded_subscription() {
+        let (publisher, subscriber) = broadcast(Some);
+        let progress = |height| Event::SpvManager(spvmgr::Event::FilterHeadersImported { height });
+        let listening = |port| Event::Listening(([0, 0, 0, 0], port).into());
+
+        let oldest = subscriber.subscribe_bounded(
+            Backpressure {
+                capacity: 2,
+                overflow: Overflow::DropOldest,
+            },
+            Event::supersedes,
+        );
+        for port in 0..4 {
+            publisher.publish(listening(port));
+        }
+        assert_eq!(
+            oldest
+                .try_iter()
+                .filter_map(|e| match e {
+                    Event::Listening(addr) => Some(addr.port()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(subscriber.dropped(), 2);
+        drop(oldest);
+
+        let coalesced = subscriber.subscribe_bounded(
+            Backpressure {
+                capacity: 1,
+                overflow: Overflow::Coalesce,
+            },
+            Event::supersedes,
+        );
+        publisher.publish(listening(0));
+        for height in 1..=3 {
+            publisher.publish(progress(height));
+        }
+        assert_eq!(subscriber.dropped(), 4);
+
+        // Held back events are delivered once there's room.
+        coalesced.recv().unwrap();
+        publisher.publish(progress(4));
+        assert!(matches!(
+            coalesced.try_recv().unwrap(),
+            Event::SpvManager(spvmgr::Event::FilterHeadersImported { height: 3 })
+        ));
+        // The latest held back event is delivered once the channel is drained, even if
+        // nothing else is published.
+        assert!(matches!(
+            coalesced.try_recv().unwrap(),
+            Event::SpvManager(spvmgr::Event::FilterHeadersImported { height: 4 })
+        ));
+        assert!(coalesced.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_bounded_subscription_block() {
+        let (publisher, subscriber) = broadcast(Some);
+        let listening = |port| Event::Listening(([0, 0, 0, 0], port).into());
+        let blocking = subscriber.subscribe_bounded(
+            Backpressure {
+                capacity: 1,
+                overflow: Overflow::Block,
+            },
+            Event::supersedes,
+        );
+        publisher.publish(listening(0));
+
+        // The publisher waits for room on the channel.
+        let thread = std::thread::spawn(move || {
+            publisher.publish(listening(1));
+            publisher
+        });
+        std::thread::sleep(BLOCKED_SEND_INTERVAL);
+
+        // Meanwhile, the consumer is able to subscribe.
+        let other = subscriber.subscribe();
+
+        for port in 0..2 {
+            assert!(matches!(
+                blocking.recv_timeout(time::Duration::from_secs(1)),
+                Ok(Event::Listening(addr)) if addr.port() == port
+            ));
+        }
+        let publisher = thread.join().unwrap();
+
+        // The publisher doesn't wait on subscribers that are gone.
+        publisher.publish(listening(2));
+        drop(blocking);
+        publisher.publish(listening(3));
+        assert_eq!(other.try_iter().count(), 2);
+    }
+}
@@ -30,12 +30,28 @@
 //! To achieve this, handling of network I/O is cleanly separated into a network
 //! *reactor*. See the `nakamoto-net-poll` crate for an example of a reactor.
 //!
+//! Since the protocol neither reads the system clock nor seeds its own random number
+//! generator, both being passed in by the reactor, this crate also compiles to
+//! `wasm32-unknown-unknown`. See the `websocket` example for a reactor that can be driven
+//! from a browser, over WebSockets.
+//!
+//! Peers are still identified by their socket address, see [`protocol::PeerId`]. The
+//! `std::net` address types are available on that target, only the sockets aren't: a
+//! reactor that reaches peers some other way, eg. through a WebSocket proxy, maps each
+//! address to a connection of its own.
+//!
+//! With the `tracing` feature, the connection, sync and compact filter managers enter
+//! `tracing` spans for the peers and requests they handle, and record their events in them.
+//!
 #![allow(clippy::type_complexity)]
 #![allow(clippy::new_without_default)]
 #![allow(clippy::single_match)]
 #![allow(clippy::comparison_chain)]
 #![allow(clippy::inconsistent_struct_constructor)]
 #![deny(missing_docs, unsafe_code)]
+#[macro_use]
+mod trace;
+
 pub mod error;
 pub mod event;
 pub mod protocol;
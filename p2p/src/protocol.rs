@@ -4,21 +4,38 @@ use crossbeam_channel as chan;
 use log::*;
 
 pub mod addrmgr;
+pub mod blockmgr;
 pub mod channel;
 pub mod connmgr;
+pub mod decoy;
+pub mod feemgr;
+pub mod invmgr;
 pub mod peermgr;
 pub mod pingmgr;
+pub mod power;
+pub mod ratemgr;
+pub mod replay;
+pub mod selector;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
 pub mod spvmgr;
 pub mod syncmgr;
+pub mod tracker;
 
 #[cfg(test)]
 mod tests;
 
 use addrmgr::AddressManager;
+use blockmgr::BlockManager;
 use channel::Channel;
 use connmgr::ConnectionManager;
+use decoy::Decoys;
+use feemgr::{FeeManager, FeeRate};
+use invmgr::InventoryManager;
 use peermgr::PeerManager;
 use pingmgr::PingManager;
+use power::PowerPolicy;
+use ratemgr::RateManager;
 use spvmgr::SpvManager;
 use syncmgr::SyncManager;
 
@@ -26,32 +43,37 @@ use crate::event::Event;
 
 use std::fmt::{self, Debug};
 use std::net;
-use std::ops::Range;
+use std::ops::{Bound, Range};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{collections::HashSet, net::SocketAddr};
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
+use bitcoin::consensus::Encodable;
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
 use bitcoin::network::message_filter::GetCFilters;
 use bitcoin::network::message_network::VersionMessage;
 use bitcoin::network::Address;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{OutPoint, Script, Txid};
 
-use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::filter::{FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::time::{AdjustedTime, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Bits, Block, BlockHash, Height, Target, Work};
 use nakamoto_common::block::{BlockTime, Transaction};
 use nakamoto_common::network::{self, Network};
-use nakamoto_common::p2p::{peer, Domain};
+use nakamoto_common::p2p::{peer, Domain, Subnet};
 
 use thiserror::Error;
 
 /// Peer-to-peer protocol version.
-/// For now, we only support `70012`, due to lacking `sendcmpct` support.
-pub const PROTOCOL_VERSION: u32 = 70012;
+/// We use `70013`, the first version with `feefilter` (BIP 133). The next version, `70014`,
+/// adds compact blocks (BIP 152), which we don't support.
+pub const PROTOCOL_VERSION: u32 = 70013;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.2.0/";
 
@@ -61,12 +83,17 @@ type Locators = (Vec<BlockHash>, BlockHash);
 /// Upstream communication channel. The protocol interacts with the peer network via this channel.
 type Upstream = Channel;
 
-/// Identifies a peer.
+/// Identifies a peer. This is the peer's socket address, even when the reactor doesn't
+/// connect to it directly, eg. when going through a proxy.
 pub type PeerId = net::SocketAddr;
 
 /// A timeout.
 pub type Timeout = LocalDuration;
 
+/// A node incarnation. Every time the node is restarted, it runs with a new epoch, and
+/// commands issued against earlier epochs are no longer executed.
+pub type Epoch = u64;
+
 /// Link direction of the peer connection.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Link {
@@ -88,19 +115,104 @@ impl Link {
     }
 }
 
+/// Direction of a message on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Message sent to a peer.
+    Sent,
+    /// Message received from a peer.
+    Received,
+}
+
+/// Hook called with every message sent or received, and its encoded size in bytes,
+/// including the message header. See [`Hooks::on_wire`].
+pub type WireHook = Arc<dyn Fn(PeerId, Direction, &RawNetworkMessage, usize) + Send + Sync>;
+
 /// A command or request that can be sent to the protocol.
 #[derive(Debug, Clone)]
 pub enum Command {
     /// Get block header at height.
     GetBlockByHeight(Height, chan::Sender<Option<BlockHeader>>),
+    /// Get a block header of the active chain by hash, along with its height.
+    GetBlockHeader(BlockHash, chan::Sender<Option<(Height, BlockHeader)>>),
     /// Get connected peers.
     GetPeers(ServiceFlags, chan::Sender<HashSet<SocketAddr>>),
+    /// Get information about negotiated peers, including latency and traffic statistics.
+    PeerInfo(chan::Sender<Vec<PeerInfo>>),
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get information about the active chain.
+    GetChainInfo(chan::Sender<ChainInfo>),
+    /// Get the total proof-of-work of the active chain, including the genesis block.
+    GetChainWork(chan::Sender<Work>),
+    /// Get the median time past of the active chain block at the given height, ie. the
+    /// median timestamp of the eleven blocks up to and including it.
+    GetMedianTimePast(Height, chan::Sender<Option<BlockTime>>),
+    /// Estimate the fee rate needed for a transaction to confirm within the given number
+    /// of blocks.
+    EstimateFeeRate(Height, chan::Sender<Option<FeeRate>>),
+    /// Get the configuration the protocol is running with.
+    GetConfig(chan::Sender<EffectiveConfig>),
+    /// Read from a consistent snapshot of the block header and filter header chains.
+    ReadSnapshot(SnapshotReader),
     /// Get a block from the active chain.
     GetBlock(BlockHash, chan::Sender<Result<PeerId, GetBlockError>>),
-    /// Get block filters.
-    GetFilters(Range<Height>, chan::Sender<Result<(), GetFiltersError>>),
+    /// Get blocks from the active chain. The blocks are delivered in height order on the
+    /// channel replied with, which is closed once all blocks are delivered, or if the
+    /// blocks can't be fetched, see [`blockmgr::BATCH_TIMEOUT`].
+    GetBlocks(
+        Blocks,
+        chan::Sender<Result<chan::Receiver<(Block, Height)>, GetBlockError>>,
+    ),
+    /// Get block filters in the given ranges.
+    GetFilters(
+        Vec<Range<Height>>,
+        chan::Sender<Result<(), GetFiltersError>>,
+    ),
+    /// Rescan the chain for matching scripts, between the given heights.
+    Rescan {
+        /// Start scanning from this height. If unbounded, start at the current tip.
+        from: Bound<Height>,
+        /// Stop scanning at this height. If unbounded, keep scanning new blocks.
+        to: Bound<Height>,
+        /// Scripts to match on. These replace the scripts of the default watchlist.
+        watch: Vec<Script>,
+        /// Reply channel. Fails if a rescan is already active.
+        reply: chan::Sender<Result<(), GetFiltersError>>,
+    },
+    /// Rescan the chain for matching scripts, until every matching block has at least
+    /// the given number of confirmations.
+    RescanUntilConfirmed {
+        /// Start scanning from this height. If unbounded, start at the current tip.
+        from: Bound<Height>,
+        /// Confirmations required on matching blocks before the rescan ends.
+        confirmations: Height,
+        /// Scripts to match on. These replace the scripts of the default watchlist.
+        watch: Vec<Script>,
+        /// Reply channel. Fails if a rescan is already active.
+        reply: chan::Sender<Result<(), GetFiltersError>>,
+    },
+    /// Abort the active rescan, if any.
+    AbortRescan,
+    /// Get the status of the active rescan, if any.
+    GetRescanStatus(chan::Sender<Option<RescanStatus>>),
+    /// Add scripts to a watchlist, creating it if necessary.
+    WatchScripts(WatchlistId, Vec<Script>),
+    /// Remove scripts from a watchlist.
+    UnwatchScripts(WatchlistId, Vec<Script>),
+    /// Watch outpoints for spends, given the script of each output, which filters are
+    /// matched on.
+    WatchOutpoints(Vec<(OutPoint, Script)>),
+    /// Watch the scripts derived from an extended public key, up to a gap limit. More
+    /// scripts are derived as matches are found.
+    WatchXpub {
+        /// Extended public key to derive scripts from.
+        xpub: ExtendedPubKey,
+        /// How scripts are derived from the child keys.
+        scheme: DerivationScheme,
+        /// Number of unused scripts to watch past the last used one.
+        gap_limit: u32,
+    },
     /// Broadcast to peers matching the predicate.
     Broadcast(NetworkMessage, fn(Peer) -> bool, chan::Sender<Vec<PeerId>>),
     /// Send a message to a random peer.
@@ -109,6 +221,18 @@ pub enum Command {
     Connect(net::SocketAddr),
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr),
+    /// Ban a peer address for the given duration, disconnecting it if connected.
+    Ban(net::IpAddr, LocalDuration),
+    /// Lift the ban on a peer address.
+    Unban(net::IpAddr),
+    /// Get the banned peer addresses, along with their bans.
+    GetBanned(chan::Sender<Vec<(net::IpAddr, Ban)>>),
+    /// Get the addresses in the address book.
+    GetAddresses(chan::Sender<Vec<peer::KnownAddress>>),
+    /// Mark an address in the address book as good or bad. Good addresses are treated as
+    /// if we had just connected to them successfully, and bad addresses are removed from
+    /// the address book. Replies with whether the address was known.
+    MarkAddress(net::IpAddr, bool, chan::Sender<bool>),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
@@ -116,22 +240,211 @@ pub enum Command {
     ),
     /// Import addresses into the address book.
     ImportAddresses(Vec<Address>),
-    /// Submit a transaction to the network.
-    SubmitTransaction(Transaction),
+    /// Ask our outbound peers for new addresses, after adding the given addresses, resolved
+    /// from DNS seeds, to the address book. Replies with the number of new addresses from
+    /// the seeds, and the peers asked.
+    QueryAddresses(Vec<net::SocketAddr>, chan::Sender<(usize, Vec<PeerId>)>),
+    /// Submit a transaction to the network, along with the fee it pays, if known. Peers
+    /// with a fee filter above the transaction's fee rate aren't announced the transaction.
+    ///
+    /// If the flag is set, the transaction is instead broadcast privately, over short-lived
+    /// connections to peers outside of our outbound peer set, see
+    /// [`connmgr::ConnectionManager::connect_broadcast`].
+    SubmitTransaction(Transaction, Option<u64>, bool),
+    /// Get the status of a submitted transaction.
+    GetTxStatus(Txid, chan::Sender<TxStatus>),
     /// Shutdown the protocol.
     Shutdown,
+    /// A command tagged with the epoch it was issued against. The epoch the protocol is
+    /// running with is sent back on the given channel. If it isn't the epoch the command
+    /// was issued against, the command is dropped without being executed, along with its
+    /// reply channel.
+    Tagged(Epoch, Box<Command>, chan::Sender<Epoch>),
 }
 
-/// An error resulting from the [`Command::GetBlock`].
+/// An error resulting from the [`Command::GetBlock`] or [`Command::GetBlocks`].
 #[derive(Error, Debug)]
 pub enum GetBlockError {
     /// Not connected to any peer with the required services.
     #[error("not connected to any peer with the required services")]
     NotConnected,
+    /// The block isn't part of the active chain.
+    #[error("block {0} not found in the active chain")]
+    NotFound(BlockHash),
+    /// The range of heights goes past the tip of the active chain.
+    #[error("range {0:?} is past the tip of the active chain")]
+    InvalidRange(Range<Height>),
+}
+
+/// A read-only view of the block header chain and the filter header chain.
+///
+/// Since commands are processed one at a time, the view can't change while it's being
+/// read from: no headers are imported in between two reads.
+pub trait ChainSnapshot {
+    /// Get the tip of the active chain.
+    fn tip(&self) -> (BlockHash, BlockHeader);
+    /// Get the height of the active chain.
+    fn height(&self) -> Height;
+    /// Get a block header of the active chain by height.
+    fn get_block_by_height(&self, height: Height) -> Option<BlockHeader>;
+    /// Get a block header of the active chain by hash, along with its height.
+    fn get_block(&self, hash: &BlockHash) -> Option<(Height, BlockHeader)>;
+    /// Get the block headers of the active chain in the given range.
+    fn get_blocks(&self, range: Range<Height>) -> Vec<BlockHeader>;
+    /// Get the height of the first block of the active chain with a median time past at
+    /// or after the given time. See [`BlockTree::find_height_by_time`].
+    fn get_height_by_time(&self, time: BlockTime) -> Option<Height>;
+    /// Get the tip of the filter header chain.
+    fn filter_tip(&self) -> (FilterHash, FilterHeader);
+    /// Get the height of the filter header chain.
+    fn filter_height(&self) -> Height;
+    /// Get a filter header by height, along with the hash of the filter.
+    fn get_filter_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)>;
+}
+
+/// A snapshot of the protocol's chain state, borrowed for the duration of a command.
+struct Snapshot<'a, T, F> {
+    tree: &'a T,
+    filters: &'a F,
+}
+
+impl<'a, T: BlockTree, F: Filters> ChainSnapshot for Snapshot<'a, T, F> {
+    fn tip(&self) -> (BlockHash, BlockHeader) {
+        self.tree.tip()
+    }
+
+    fn height(&self) -> Height {
+        self.tree.height()
+    }
+
+    fn get_block_by_height(&self, height: Height) -> Option<BlockHeader> {
+        self.tree.get_block_by_height(height).copied()
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Option<(Height, BlockHeader)> {
+        self.tree.get_block(hash).map(|(h, header)| (h, *header))
+    }
+
+    fn get_blocks(&self, range: Range<Height>) -> Vec<BlockHeader> {
+        self.tree.range(range).collect()
+    }
+
+    fn get_height_by_time(&self, time: BlockTime) -> Option<Height> {
+        self.tree.find_height_by_time(time)
+    }
+
+    fn filter_tip(&self) -> (FilterHash, FilterHeader) {
+        let (hash, header) = self.filters.tip();
+        (*hash, *header)
+    }
+
+    fn filter_height(&self) -> Height {
+        self.filters.height()
+    }
+
+    fn get_filter_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)> {
+        self.filters.get_header(height)
+    }
+}
+
+/// A function reading from a [`ChainSnapshot`], sent with [`Command::ReadSnapshot`].
+#[derive(Clone)]
+pub struct SnapshotReader(Arc<dyn Fn(&dyn ChainSnapshot) + Send + Sync>);
+
+impl SnapshotReader {
+    /// Create a new snapshot reader.
+    pub fn new(f: impl Fn(&dyn ChainSnapshot) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Read from a snapshot.
+    pub fn read(&self, snapshot: &dyn ChainSnapshot) {
+        (self.0)(snapshot)
+    }
 }
 
+impl Debug for SnapshotReader {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "SnapshotReader(..)")
+    }
+}
+
+/// Information about a negotiated peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The peer's address.
+    pub addr: PeerId,
+    /// Whether this is an inbound or outbound connection.
+    pub link: Link,
+    /// Services offered by the peer.
+    pub services: ServiceFlags,
+    /// The peer's user agent.
+    pub user_agent: String,
+    /// The peer's best height, as of the handshake.
+    pub height: Height,
+    /// The protocol version negotiated with the peer.
+    pub version: u32,
+    /// Time at which we connected to the peer.
+    pub since: LocalTime,
+    /// Latency and traffic statistics.
+    pub stats: pingmgr::Stats,
+}
+
+/// Information about the state of the active chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainInfo {
+    /// Hash of the chain tip.
+    pub tip: BlockHash,
+    /// Height of the chain tip.
+    pub height: Height,
+    /// Timestamp of the chain tip.
+    pub time: BlockTime,
+    /// Median time past of the chain tip, ie. the time the next block must be
+    /// greater than.
+    pub median_time_past: BlockTime,
+    /// Compact difficulty target of the chain tip.
+    pub bits: Bits,
+    /// Difficulty target of the chain tip.
+    pub target: Target,
+    /// Difficulty of the chain tip, relative to the minimum difficulty.
+    pub difficulty: u64,
+    /// Height of the next difficulty adjustment.
+    pub next_retarget: Height,
+    /// Estimated time of the next difficulty adjustment, assuming blocks are found
+    /// at the target spacing.
+    pub next_retarget_time: BlockTime,
+}
+
+impl ChainInfo {
+    /// Compute chain information from a block tree.
+    pub fn new<T: BlockTree>(tree: &T, params: &Params) -> Self {
+        let (tip, header) = tree.tip();
+        let height = tree.height();
+        let interval = params.difficulty_adjustment_interval();
+        let next_retarget = (height / interval + 1) * interval;
+        let next_retarget_time =
+            header.time as u64 + (next_retarget - height) * params.pow_target_spacing;
+
+        Self {
+            tip,
+            height,
+            time: header.time,
+            median_time_past: tree.median_time_past(height + 1),
+            bits: header.bits,
+            target: header.target(),
+            difficulty: header.difficulty(params.network),
+            next_retarget,
+            next_retarget_time: next_retarget_time.min(BlockTime::MAX as u64) as BlockTime,
+        }
+    }
+}
+
+pub use blockmgr::Blocks;
+pub use connmgr::Ban;
+pub use invmgr::TxStatus;
 pub use peermgr::Peer;
-pub use spvmgr::GetFiltersError;
+pub use spvmgr::xpub::DerivationScheme;
+pub use spvmgr::{Birthday, GetFiltersError, RescanStatus, WatchlistId};
 
 /// A protocol input event, parametrized over the network message type.
 /// These are input events generated outside of the protocol.
@@ -176,8 +489,8 @@ pub enum Input {
 pub enum Out {
     /// Send a message to a peer.
     Message(PeerId, RawNetworkMessage),
-    /// Connect to a peer.
-    Connect(PeerId, Timeout),
+    /// Connect to a peer, through the given proxy, if any.
+    Connect(PeerId, Timeout, Option<connmgr::Proxy>),
     /// Disconnect from a peer.
     Disconnect(PeerId, DisconnectReason),
     /// Set a timeout.
@@ -209,10 +522,20 @@ pub enum DisconnectReason {
     PeerMagic(u32),
     /// Peer timed out.
     PeerTimeout(&'static str),
+    /// Peer is temporarily banned, due to earlier misbehavior.
+    PeerBanned,
+    /// Peer address is blacklisted.
+    PeerBlacklisted,
+    /// Peer is on a branch with less work than our active chain.
+    PeerStaleBranch,
+    /// Peer kept sending messages over the given rate limit.
+    PeerFlooding(&'static str),
     /// Connection to self was detected.
     SelfConnection,
     /// Inbound connection limit reached.
     ConnectionLimit,
+    /// Peer was evicted to make room for another peer.
+    PeerEvicted,
     /// Error with the underlying connection.
     ConnectionError(String),
     /// Peer was forced to disconnect by external command.
@@ -228,6 +551,7 @@ impl DisconnectReason {
         matches!(
             self,
             Self::ConnectionLimit
+                | Self::PeerEvicted
                 | Self::PeerTimeout(_)
                 | Self::PeerHeight(_)
                 | Self::ConnectionError(_)
@@ -244,8 +568,13 @@ impl fmt::Display for DisconnectReason {
             Self::PeerHeight(_) => write!(f, "peer is too far behind"),
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout(s) => write!(f, "peer timed out: {:?}", s),
+            Self::PeerBanned => write!(f, "peer is banned"),
+            Self::PeerBlacklisted => write!(f, "peer is blacklisted"),
+            Self::PeerStaleBranch => write!(f, "peer is on a stale branch"),
+            Self::PeerFlooding(limit) => write!(f, "peer exceeded the {} rate limit", limit),
             Self::SelfConnection => write!(f, "detected self-connection"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
+            Self::PeerEvicted => write!(f, "peer evicted to make room for another"),
             Self::ConnectionError(err) => write!(f, "connection error: {}", err),
             Self::Command => write!(f, "received external command"),
             Self::Other(reason) => write!(f, "{}", reason),
@@ -253,6 +582,65 @@ impl fmt::Display for DisconnectReason {
     }
 }
 
+/// Peer misbehavior. Each kind of misbehavior adds to the peer's misbehavior score,
+/// and peers whose score reaches [`connmgr::BAN_THRESHOLD`] are disconnected and banned.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Misbehavior {
+    /// Peer sent invalid block headers.
+    InvalidHeaders,
+    /// Peer sent invalid compact filter headers or filters.
+    InvalidFilters(&'static str),
+    /// Peer sent a message that violates the protocol.
+    InvalidMessage(&'static str),
+    /// Peer sent a message we didn't ask for.
+    UnsolicitedMessage(&'static str),
+    /// Peer didn't respond to one of our requests in time.
+    Stalling,
+    /// Peer kept sending messages over the given rate limit.
+    Flooding(&'static str),
+}
+
+impl Misbehavior {
+    /// The score added to a peer's misbehavior score.
+    pub fn score(&self) -> u32 {
+        match self {
+            Self::InvalidHeaders => 100,
+            Self::InvalidFilters(_) => 100,
+            Self::InvalidMessage(_) => 50,
+            Self::Stalling => 20,
+            Self::Flooding(_) => 20,
+            Self::UnsolicitedMessage(_) => 10,
+        }
+    }
+
+    /// A short description of the misbehavior.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::InvalidHeaders => "invalid headers",
+            Self::InvalidFilters(reason) => reason,
+            Self::InvalidMessage(reason) => reason,
+            Self::UnsolicitedMessage(reason) => reason,
+            Self::Stalling => "stalling",
+            Self::Flooding(_) => "flooding",
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHeaders => write!(f, "sent invalid headers"),
+            Self::InvalidFilters(reason) => write!(f, "sent invalid filters ({})", reason),
+            Self::InvalidMessage(reason) => write!(f, "sent invalid message ({})", reason),
+            Self::UnsolicitedMessage(reason) => {
+                write!(f, "sent unsolicited message ({})", reason)
+            }
+            Self::Stalling => write!(f, "stalled"),
+            Self::Flooding(limit) => write!(f, "exceeded the {} rate limit", limit),
+        }
+    }
+}
+
 mod message {
     use super::*;
 
@@ -291,10 +679,20 @@ pub struct Hooks {
     /// Called when a `version` message is received.
     /// If an error is returned, the peer is dropped, and the error is logged.
     pub on_version: Arc<dyn Fn(PeerId, VersionMessage) -> Result<(), &'static str> + Send + Sync>,
-    /// Called when a `getcfilters` message is received.
+    /// Called when a `getcfilters` message is received, unless we serve filters ourselves,
+    /// see [`Config::serve_filters`].
     pub on_getcfilters: Arc<dyn Fn(PeerId, GetCFilters, &Upstream) + Send + Sync>,
-    /// Called when a `getdata` message is received.
+    /// Called when a `getdata` message is received. Requests for transactions are handled
+    /// by the protocol, and aren't passed to this hook.
     pub on_getdata: Arc<dyn Fn(PeerId, Vec<Inventory>, &Upstream) + Send + Sync>,
+    /// Called for every negotiated peer when shutting down. Messages sent to the peer from
+    /// this hook are delivered before the connection is closed.
+    pub on_shutdown: Arc<dyn Fn(PeerId, &Upstream) + Send + Sync>,
+    /// Called with every message sent to or received from a peer, along with its encoded
+    /// size, eg. to log wire traffic or export bandwidth metrics. Messages are passed to the
+    /// hook as they are queued for sending, and before they are processed when received,
+    /// including messages dropped by the rate limiter or by [`Hooks::on_message`].
+    pub on_wire: WireHook,
 }
 
 impl Default for Hooks {
@@ -304,6 +702,8 @@ impl Default for Hooks {
             on_version: Arc::new(|_, _| Ok(())),
             on_getcfilters: Arc::new(|_, _, _| {}),
             on_getdata: Arc::new(|_, _, _| {}),
+            on_shutdown: Arc::new(|_, _| {}),
+            on_wire: Arc::new(|_, _, _, _| {}),
         }
     }
 }
@@ -340,6 +740,18 @@ pub struct Protocol<T, F, P> {
     spvmgr: SpvManager<F, Upstream>,
     /// Peer manager.
     peermgr: PeerManager<Upstream>,
+    /// Fee rate estimator.
+    feemgr: FeeManager,
+    /// Transaction inventory manager.
+    invmgr: InventoryManager<Upstream>,
+    /// Per-peer message rate limiter.
+    ratemgr: RateManager,
+    /// Block download manager.
+    blockmgr: BlockManager,
+    /// Decoy block downloads.
+    decoys: Decoys,
+    /// Power policy, deciding the cadence of periodic network activity.
+    power: PowerPolicy,
     /// Network-adjusted clock.
     clock: AdjustedTime<PeerId>,
     /// Informational name of this protocol instance. Used for logging purposes only.
@@ -352,6 +764,12 @@ pub struct Protocol<T, F, P> {
     upstream: Upstream,
     /// Protocol event hooks.
     hooks: Hooks,
+    /// Node incarnation this protocol instance is running as.
+    epoch: Epoch,
+    /// Configuration we're running with, for diagnostics.
+    effective_config: EffectiveConfig,
+    /// Records inputs for later replay, if set.
+    recorder: Option<replay::Recorder>,
 }
 
 /// Protocol configuration.
@@ -361,14 +779,57 @@ pub struct Config {
     pub network: network::Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Only ever connect to the peers in `connect`, reconnecting to them with backoff when
+    /// they disconnect. The address manager isn't used to find other peers.
+    pub connect_only: bool,
+    /// Anchor peers persisted on the last shutdown. See [`connmgr::Event::Anchors`].
+    pub anchors: Vec<net::SocketAddr>,
+    /// Rescan state persisted on the last shutdown, to resume the next rescan from if it
+    /// has the same parameters. See [`spvmgr::Event::RescanCheckpoint`].
+    pub rescan: Option<spvmgr::RescanState>,
+    /// Peer bans persisted on the last run. See [`connmgr::Event::Banned`].
+    pub banned: Vec<(net::IpAddr, Ban)>,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
     /// Services offered by our peer.
     pub services: ServiceFlags,
-    /// Required peer services.
+    /// Required peer services. Peers signaling `NETWORK_LIMITED` satisfy `NETWORK`.
     pub required_services: ServiceFlags,
-    /// Peer whitelist. Peers in this list are trusted by default.
+    /// Services required of the peers compact filters are downloaded from.
+    pub filter_services: ServiceFlags,
+    /// Minimum number of outbound peers to download compact filters from, out of
+    /// `target_outbound_peers`. Other outbound peers are replaced until this is reached.
+    pub min_filter_peers: usize,
+    /// Time without any outbound filter peer after which [`connmgr::Event::NoFilterPeers`]
+    /// is emitted.
+    pub filter_peer_timeout: LocalDuration,
+    /// Time to wait for a new connection to a peer before giving up on it.
+    pub connect_timeout: LocalDuration,
+    /// Whether to serve compact block filters to peers (BIP 157). If set,
+    /// [`ServiceFlags::COMPACT_FILTERS`] is added to the services we offer.
+    pub serve_filters: bool,
+    /// Number of blocks below the tip to keep compact filters for. Older filters and
+    /// filter headers are pruned. If not set, nothing is pruned.
+    pub filter_window: Option<Height>,
+    /// Wallet birthday. Filters below it are neither scanned nor kept.
+    pub birthday: Option<Birthday>,
+    /// Whether to request the filter of the newest block from two peers at once, and use
+    /// whichever arrives first. Lowers the latency of matching new blocks, if a peer is slow.
+    pub redundant_filters: bool,
+    /// Peer whitelist. Peers in this list are trusted by default, are always allowed to
+    /// connect, and are never banned.
     pub whitelist: Whitelist,
+    /// Peer blacklist. Addresses in these subnets are never connected to, and their inbound
+    /// connections are rejected, unless they are whitelisted.
+    pub blacklist: Vec<Subnet>,
+    /// SOCKS5 proxy to connect to peers through, eg. a Tor client.
+    pub proxy: Option<net::SocketAddr>,
+    /// Whether each peer connection authenticates with the proxy using its own credentials,
+    /// so that Tor uses a separate circuit for each peer. See [`connmgr::Credentials`].
+    pub isolate_streams: bool,
+    /// Number of random blocks to download as decoys along with every block requested,
+    /// on average. Zero disables decoys. See [`decoy`].
+    pub decoy_blocks: f64,
     /// Consensus parameters.
     pub params: Params,
     /// Our protocol version.
@@ -381,10 +842,22 @@ pub struct Config {
     pub max_inbound_peers: usize,
     /// Ping timeout, after which remotes are disconnected.
     pub ping_timeout: LocalDuration,
+    /// Time after which submitted transactions are no longer served to peers.
+    pub tx_ttl: LocalDuration,
+    /// Limits on the messages received from each peer.
+    pub limits: ratemgr::Config,
+    /// Minimum fee rate of the transactions we want peers to announce to us (BIP 133).
+    pub feefilter: Option<FeeRate>,
+    /// Power policy configuration.
+    pub power: power::Config,
     /// Log target.
     pub target: &'static str,
     /// Protocol event hooks.
     pub hooks: Hooks,
+    /// Node incarnation. Tagged commands from other epochs are dropped.
+    pub epoch: Epoch,
+    /// Directory runtime data is stored in, if any. Only used for diagnostics.
+    pub storage: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -393,17 +866,39 @@ impl Default for Config {
             network: network::Network::Mainnet,
             params: Params::new(network::Network::Mainnet.into()),
             connect: Vec::new(),
+            connect_only: false,
+            anchors: Vec::new(),
+            rescan: None,
+            banned: Vec::new(),
             domains: Domain::all(),
             services: ServiceFlags::NONE,
             required_services: ServiceFlags::NETWORK,
+            filter_services: spvmgr::REQUIRED_SERVICES,
+            min_filter_peers: spvmgr::MIN_PEERS,
+            filter_peer_timeout: connmgr::FILTER_PEER_TIMEOUT,
+            connect_timeout: connmgr::CONNECTION_TIMEOUT,
+            serve_filters: false,
+            filter_window: None,
+            birthday: None,
+            redundant_filters: false,
             whitelist: Whitelist::default(),
+            blacklist: Vec::new(),
+            proxy: None,
+            isolate_streams: true,
+            decoy_blocks: 0.,
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: connmgr::MAX_INBOUND_PEERS,
             ping_timeout: pingmgr::PING_TIMEOUT,
+            tx_ttl: invmgr::DEFAULT_TTL,
+            limits: ratemgr::Config::default(),
+            feefilter: Some(feemgr::DEFAULT_FEEFILTER),
+            power: power::Config::default(),
             user_agent: USER_AGENT,
             target: "self",
             hooks: Hooks::default(),
+            epoch: 0,
+            storage: None,
         }
     }
 }
@@ -430,6 +925,197 @@ impl Config {
     pub fn port(&self) -> u16 {
         self.network.port()
     }
+
+    /// Services offered by our peer, including the ones implied by other settings.
+    fn offered_services(&self) -> ServiceFlags {
+        if self.serve_filters {
+            self.services | ServiceFlags::COMPACT_FILTERS
+        } else {
+            self.services
+        }
+    }
+}
+
+/// The configuration a node is running with, as reported for diagnostics.
+///
+/// This is redacted: peer addresses are replaced by their number, so that the configuration
+/// can be shared in bug reports without revealing who the node talks to. Hooks are left out.
+#[derive(Debug, Clone)]
+pub struct EffectiveConfig {
+    /// Bitcoin network.
+    pub network: Network,
+    /// Services offered by our peer.
+    pub services: ServiceFlags,
+    /// Required peer services.
+    pub required_services: ServiceFlags,
+    /// Services required of filter peers.
+    pub filter_services: ServiceFlags,
+    /// Minimum number of outbound filter peers.
+    pub min_filter_peers: usize,
+    /// Our protocol version.
+    pub protocol_version: u32,
+    /// Our user agent.
+    pub user_agent: &'static str,
+    /// Supported communication domains.
+    pub domains: Vec<Domain>,
+    /// Target outbound peer connections.
+    pub target_outbound_peers: usize,
+    /// Maximum inbound peer connections.
+    pub max_inbound_peers: usize,
+    /// Ping timeout.
+    pub ping_timeout: LocalDuration,
+    /// Time after which submitted transactions are no longer served to peers.
+    pub tx_ttl: LocalDuration,
+    /// Minimum fee rate of the transactions announced to us.
+    pub feefilter: Option<FeeRate>,
+    /// Number of peers configured to connect to.
+    pub connect: usize,
+    /// Whether we only connect to the configured peers.
+    pub connect_only: bool,
+    /// Number of anchor peers.
+    pub anchors: usize,
+    /// Number of whitelisted addresses and user agents.
+    pub whitelist: usize,
+    /// Number of blacklisted subnets.
+    pub blacklist: usize,
+    /// SOCKS5 proxy peers are connected to through.
+    pub proxy: Option<net::SocketAddr>,
+    /// Whether peer connections are isolated from each other on the proxy.
+    pub isolate_streams: bool,
+    /// Number of decoy blocks downloaded for every block requested.
+    pub decoy_blocks: f64,
+    /// Node incarnation.
+    pub epoch: Epoch,
+    /// Directory runtime data is stored in, if any.
+    pub storage: Option<PathBuf>,
+}
+
+impl EffectiveConfig {
+    /// Convert to a JSON value. Keys are sorted, and durations are in milliseconds.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+
+        let number = |n: u64| Value::Number(Number::U64(n));
+        let mut obj = Object::new();
+
+        obj.insert(
+            "network".to_owned(),
+            Value::String(self.network.as_str().to_owned()),
+        );
+        obj.insert("services".to_owned(), number(self.services.as_u64()));
+        obj.insert(
+            "required_services".to_owned(),
+            number(self.required_services.as_u64()),
+        );
+        obj.insert(
+            "filter_services".to_owned(),
+            number(self.filter_services.as_u64()),
+        );
+        obj.insert(
+            "min_filter_peers".to_owned(),
+            number(self.min_filter_peers as u64),
+        );
+        obj.insert(
+            "protocol_version".to_owned(),
+            number(self.protocol_version as u64),
+        );
+        obj.insert(
+            "user_agent".to_owned(),
+            Value::String(self.user_agent.to_owned()),
+        );
+        obj.insert(
+            "domains".to_owned(),
+            Value::Array(
+                self.domains
+                    .iter()
+                    .map(|d| {
+                        Value::String(match d {
+                            Domain::IPV4 => "ipv4".to_owned(),
+                            Domain::IPV6 => "ipv6".to_owned(),
+                        })
+                    })
+                    .collect(),
+            ),
+        );
+        obj.insert(
+            "target_outbound_peers".to_owned(),
+            number(self.target_outbound_peers as u64),
+        );
+        obj.insert(
+            "max_inbound_peers".to_owned(),
+            number(self.max_inbound_peers as u64),
+        );
+        obj.insert(
+            "ping_timeout_ms".to_owned(),
+            number(self.ping_timeout.as_millis() as u64),
+        );
+        obj.insert(
+            "tx_ttl_ms".to_owned(),
+            number(self.tx_ttl.as_millis() as u64),
+        );
+        obj.insert(
+            "feefilter".to_owned(),
+            self.feefilter.map_or(Value::Null, number),
+        );
+        obj.insert("connect".to_owned(), number(self.connect as u64));
+        obj.insert("connect_only".to_owned(), Value::Bool(self.connect_only));
+        obj.insert("anchors".to_owned(), number(self.anchors as u64));
+        obj.insert("whitelist".to_owned(), number(self.whitelist as u64));
+        obj.insert("blacklist".to_owned(), number(self.blacklist as u64));
+        obj.insert(
+            "proxy".to_owned(),
+            self.proxy
+                .map_or(Value::Null, |addr| Value::String(addr.to_string())),
+        );
+        obj.insert(
+            "isolate_streams".to_owned(),
+            Value::Bool(self.isolate_streams),
+        );
+        obj.insert(
+            "decoy_blocks".to_owned(),
+            Value::Number(Number::F64(self.decoy_blocks)),
+        );
+        obj.insert("epoch".to_owned(), number(self.epoch));
+        obj.insert(
+            "storage".to_owned(),
+            match &self.storage {
+                Some(path) => Value::String(path.display().to_string()),
+                None => Value::Null,
+            },
+        );
+
+        Value::Object(obj)
+    }
+}
+
+impl From<&Config> for EffectiveConfig {
+    fn from(cfg: &Config) -> Self {
+        Self {
+            network: cfg.network,
+            services: cfg.offered_services(),
+            required_services: cfg.required_services,
+            filter_services: cfg.filter_services,
+            min_filter_peers: cfg.min_filter_peers,
+            protocol_version: cfg.protocol_version,
+            user_agent: cfg.user_agent,
+            domains: cfg.domains.clone(),
+            target_outbound_peers: cfg.target_outbound_peers,
+            max_inbound_peers: cfg.max_inbound_peers,
+            ping_timeout: cfg.ping_timeout,
+            tx_ttl: cfg.tx_ttl,
+            feefilter: cfg.feefilter,
+            connect: cfg.connect.len(),
+            connect_only: cfg.connect_only,
+            anchors: cfg.anchors.len(),
+            whitelist: cfg.whitelist.addr.len() + cfg.whitelist.user_agent.len(),
+            blacklist: cfg.blacklist.len(),
+            proxy: cfg.proxy,
+            isolate_streams: cfg.isolate_streams,
+            decoy_blocks: cfg.decoy_blocks,
+            epoch: cfg.epoch,
+            storage: cfg.storage.clone(),
+        }
+    }
 }
 
 /// Peer whitelist.
@@ -451,6 +1137,17 @@ impl Default for Whitelist {
 }
 
 impl Whitelist {
+    /// Create a new whitelist from trusted addresses and user-agents.
+    pub fn new(
+        addrs: impl IntoIterator<Item = net::IpAddr>,
+        user_agents: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Self {
+            addr: addrs.into_iter().collect(),
+            user_agent: user_agents.into_iter().collect(),
+        }
+    }
+
     fn contains(&self, addr: &net::IpAddr, user_agent: &str) -> bool {
         self.addr.contains(addr) || self.user_agent.contains(user_agent)
     }
@@ -467,30 +1164,56 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         config: Config,
         upstream: chan::Sender<Out>,
     ) -> Self {
+        let effective_config = EffectiveConfig::from(&config);
+        let services = config.offered_services();
         let Config {
             network,
             connect,
+            connect_only,
+            anchors,
             domains,
-            services,
+            services: _,
+            serve_filters,
+            filter_window,
+            birthday,
+            redundant_filters,
             whitelist,
+            blacklist,
+            proxy,
+            isolate_streams,
+            decoy_blocks,
             protocol_version,
             target_outbound_peers,
             max_inbound_peers,
             ping_timeout,
+            tx_ttl,
+            limits,
+            feefilter,
+            power,
             user_agent,
             required_services,
+            filter_services,
+            min_filter_peers,
+            filter_peer_timeout,
+            connect_timeout,
             target,
             params,
             hooks,
+            epoch,
+            rescan,
+            banned,
+            storage: _,
         } = config;
 
-        let upstream = Upstream::new(network, protocol_version, target, upstream);
+        let upstream = Upstream::new(network, protocol_version, target, upstream)
+            .on_wire(hooks.on_wire.clone());
 
         let syncmgr = SyncManager::new(
             syncmgr::Config {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
                 params: params.clone(),
+                checkpoints: network.checkpoints().collect(),
             },
             rng.clone(),
             upstream.clone(),
@@ -501,16 +1224,36 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 target_outbound_peers,
                 max_inbound_peers,
                 retry: connect,
+                connect_only,
+                anchors,
                 domains: domains.clone(),
                 required_services,
                 // Include services required by all sub-protocols.
-                preferred_services: syncmgr::REQUIRED_SERVICES | spvmgr::REQUIRED_SERVICES,
+                preferred_services: syncmgr::REQUIRED_SERVICES | filter_services,
+                filter_services,
+                filter_peer_timeout,
+                connect_timeout,
+                whitelist: whitelist.addr.iter().copied().collect(),
+                blacklist: blacklist.clone(),
+                banned,
+                proxy,
+                isolate_streams,
             },
             rng.clone(),
         );
         let pingmgr = PingManager::new(ping_timeout, rng.clone(), upstream.clone());
         let spvmgr = SpvManager::new(
-            spvmgr::Config::default(),
+            spvmgr::Config {
+                serve_filters,
+                filter_window,
+                birthday,
+                filter_checkpoints: network.filter_checkpoints().collect(),
+                redundant_filters,
+                required_services: filter_services,
+                min_peers: min_filter_peers,
+                resume: rescan,
+                ..spvmgr::Config::default()
+            },
             rng.clone(),
             filters,
             upstream.clone(),
@@ -522,6 +1265,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 required_services,
                 services,
                 user_agent,
+                feefilter,
             },
             rng.clone(),
             hooks.clone(),
@@ -531,11 +1275,22 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             addrmgr::Config {
                 required_services,
                 domains,
+                blacklist,
             },
             rng.clone(),
             peers,
             upstream.clone(),
         );
+        let feemgr = FeeManager::new(network, rng.clone());
+        let invmgr = InventoryManager::new(
+            invmgr::Config { ttl: tx_ttl },
+            rng.clone(),
+            upstream.clone(),
+        );
+        let ratemgr = RateManager::new(limits, rng.clone());
+        let blockmgr = BlockManager::new(rng.clone());
+        let decoys = Decoys::new(decoy_blocks, rng.clone());
+        let power = PowerPolicy::new(power, clock.local_time());
 
         Self {
             tree,
@@ -550,10 +1305,39 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             pingmgr,
             spvmgr,
             peermgr,
+            feemgr,
+            invmgr,
+            ratemgr,
+            blockmgr,
+            decoys,
+            power,
             last_tick: LocalTime::default(),
             rng,
             upstream,
             hooks,
+            epoch,
+            effective_config,
+            recorder: None,
+        }
+    }
+
+    /// Record every input fed to the protocol from now on, see [`replay`].
+    pub fn with_recorder(mut self, recorder: replay::Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Record an input, if recording. Recording stops on the first error.
+    fn record(&mut self, input: Option<&Input>, time: LocalTime) {
+        if let Some(recorder) = &mut self.recorder {
+            let result = match input {
+                Some(input) => recorder.step(input, time),
+                None => recorder.initialize(time),
+            };
+            if let Err(err) = result {
+                error!(target: self.target, "Stopping input recording: {}", err);
+                self.recorder = None;
+            }
         }
     }
 
@@ -565,6 +1349,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         let peers = self
             .peermgr
             .outbound()
+            .filter(|p| !self.connmgr.is_broadcast(&p.address()))
             .filter(|p| f(*p))
             .collect::<Vec<_>>();
 
@@ -581,6 +1366,52 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Request a block, along with decoys if enabled. Returns the peer the block was
+    /// requested from.
+    fn get_block(&mut self, hash: BlockHash, now: LocalTime) -> Option<PeerId> {
+        // Blocks we don't know about yet are assumed to be at the tip.
+        let height = self
+            .tree
+            .get_block(&hash)
+            .map_or(self.tree.height(), |(height, _)| height);
+
+        self.decoys.requested(&hash);
+
+        let peer = self.request_block(hash, height, now)?;
+
+        if !self.decoys.is_enabled() {
+            return Some(peer);
+        }
+        for decoy in self.decoys.sample(height, &self.tree, now) {
+            if let Some((height, _)) = self.tree.get_block(&decoy) {
+                self.request_block(decoy, height, now);
+            }
+        }
+        Some(peer)
+    }
+
+    /// Request a block from one of the peers able to serve it, see [`blockmgr`]. Blocks
+    /// already in flight aren't requested again.
+    fn request_block(&mut self, hash: BlockHash, height: Height, now: LocalTime) -> Option<PeerId> {
+        if let Some(peer) = self.blockmgr.in_flight(&hash, now) {
+            return Some(peer);
+        }
+        let depth = self.tree.height().saturating_sub(height);
+        let peers = self
+            .peermgr
+            .outbound()
+            .filter(|p| !self.connmgr.is_broadcast(&p.address()))
+            .filter(|p| p.can_serve_block(depth))
+            .map(|p| p.address())
+            .collect::<Vec<_>>();
+        let peer = self.blockmgr.select(&hash, peers, now)?;
+
+        self.upstream
+            .message(peer, NetworkMessage::GetData(vec![Inventory::Block(hash)]));
+
+        Some(peer)
+    }
+
     fn tick(&mut self, local_time: LocalTime) {
         // The local time is set from outside the protocol.
         self.clock.set_local_time(local_time);
@@ -625,6 +1456,21 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Check whether a message is a reply to one of our requests to the peer.
+    fn is_solicited(&self, addr: &PeerId, msg: &NetworkMessage, now: LocalTime) -> bool {
+        match msg {
+            NetworkMessage::Block(block) => {
+                self.blockmgr.in_flight(&block.block_hash(), now) == Some(*addr)
+                    || self.spvmgr.is_awaiting(addr, msg, &self.tree)
+            }
+            NetworkMessage::Headers(headers) => self.syncmgr.is_awaiting(addr, headers),
+            NetworkMessage::CFHeaders(_)
+            | NetworkMessage::CFilter(_)
+            | NetworkMessage::CFCheckpt(_) => self.spvmgr.is_awaiting(addr, msg, &self.tree),
+            _ => false,
+        }
+    }
+
     fn receive(&mut self, addr: PeerId, msg: RawNetworkMessage) {
         let now = self.clock.local_time();
         let cmd = msg.cmd();
@@ -638,6 +1484,25 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             return;
         }
 
+        let size = msg.consensus_encode(std::io::sink()).unwrap_or_default();
+        (self.hooks.on_wire)(addr, Direction::Received, &msg, size);
+
+        let solicited = self.is_solicited(&addr, &msg.payload, now);
+        match self.ratemgr.received(addr, &msg, size, solicited, now) {
+            ratemgr::Verdict::Accept => {}
+            ratemgr::Verdict::Drop(limit) => {
+                debug!(
+                    target: self.target,
+                    "{}: Message {:?} dropped: over the {} limit", addr, cmd, limit
+                );
+                return;
+            }
+            ratemgr::Verdict::Disconnect(limit) => {
+                self.misbehaving(addr, Misbehavior::Flooding(limit));
+                return self.disconnect(addr, DisconnectReason::PeerFlooding(limit));
+            }
+        }
+
         debug!(
             target: self.target, "{}: Received {:?}",
             addr, cmd
@@ -650,6 +1515,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             );
             return;
         }
+        self.pingmgr.peer_active(addr, size, now);
 
         match msg.payload {
             NetworkMessage::Version(msg) => {
@@ -658,12 +1524,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 self.peermgr
                     .received_version(&addr, msg, height, now, &mut self.addrmgr);
             }
-            NetworkMessage::Verack => {
-                if let Some(peer) = self.peermgr.received_verack(&addr, now) {
+            NetworkMessage::Verack => match self.peermgr.received_verack(&addr, now) {
+                // Peers we're connected to only to broadcast a transaction to aren't used
+                // for anything else.
+                Ok(peer) if self.connmgr.is_broadcast(&addr) => {
+                    self.connmgr.peer_negotiated(peer.address(), peer.services);
+                    self.invmgr.peer_negotiated(peer.address());
+                }
+                Ok(peer) => {
                     self.clock.record_offset(peer.address(), peer.time_offset);
                     self.addrmgr
                         .peer_negotiated(&addr, peer.services, peer.conn.link, now);
-                    self.pingmgr.peer_negotiated(peer.address(), now);
+                    self.pingmgr
+                        .peer_negotiated(peer.address(), peer.conn.link, now);
                     self.connmgr.peer_negotiated(peer.address(), peer.services);
                     self.spvmgr.peer_negotiated(
                         peer.address(),
@@ -673,6 +1546,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                         &self.clock,
                         &self.tree,
                     );
+                    self.connmgr
+                        .set_filter_shortage(self.spvmgr.is_short_of_peers());
                     self.syncmgr.peer_negotiated(
                         peer.address(),
                         peer.height,
@@ -682,35 +1557,59 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                         &self.tree,
                     );
                 }
-            }
+                Err(misbehavior) => self.misbehaving(addr, misbehavior),
+            },
             NetworkMessage::Ping(nonce) => {
                 self.pingmgr.received_ping(addr, nonce);
             }
             NetworkMessage::Pong(nonce) => {
                 if self.pingmgr.received_pong(addr, nonce, now) {
                     self.addrmgr.peer_active(addr, now);
+
+                    if let Some(latency) = self.pingmgr.stats(&addr).and_then(|s| s.latency) {
+                        self.connmgr.peer_latency(&addr, latency.avg);
+                    }
                 }
             }
             NetworkMessage::Headers(headers) => {
-                match self
-                    .syncmgr
-                    .received_headers(&addr, headers, &self.clock, &mut self.tree)
-                {
+                let height = self.tree.height();
+                let announced = self.syncmgr.is_announcement(&addr, &headers);
+
+                let result =
+                    self.syncmgr
+                        .received_headers(&addr, headers, &self.clock, &mut self.tree);
+
+                // Filters can be requested from the peer up to its new height.
+                if let Some(height) = self.syncmgr.peer_height(&addr) {
+                    self.spvmgr.peer_height(&addr, height);
+                }
+                if let Ok(ImportResult::TipChanged(..)) = &result {
+                    self.invmgr.tip_changed(&self.tree);
+                }
+                match result {
+                    Err(syncmgr::Error::Misbehaving { misbehavior, .. }) => {
+                        self.misbehaving(addr, misbehavior)
+                    }
                     Err(e) => log::error!("Error receiving headers: {}", e),
                     Ok(ImportResult::TipChanged(_, _, _, reverted)) if !reverted.is_empty() => {
                         // By rolling back the filter headers, we will trigger
                         // a re-download of the missing headers, which should result
                         // in us having the new headers.
-                        self.spvmgr.rollback(reverted.len()).unwrap();
+                        let fork = height.saturating_sub(reverted.len() as Height);
+
+                        if let Err(err) = self.spvmgr.rollback(fork) {
+                            error!(target: self.target, "Error rolling back filters: {}", err);
+                        }
                         self.spvmgr.sync(&self.tree, now);
                     }
-                    Ok(ImportResult::TipChanged(_, _, _, _)) => {
-                        if !self.syncmgr.is_syncing() {
-                            // Trigger a filter sync, since we're going to have to catch up on the
-                            // new block header(s). This is not required, but reduces latency.
-                            // We only do this at the tip of the header chain.
-                            self.spvmgr.sync(&self.tree, now);
-                        }
+                    Ok(ImportResult::TipChanged(_, _, _, _))
+                        if !self.syncmgr.is_syncing() || announced =>
+                    {
+                        // Trigger a filter sync, since we're going to have to catch up on the
+                        // new block header(s). This is not required, but reduces latency.
+                        // We only do this at the tip of the header chain, which is where
+                        // header announcements are made.
+                        self.spvmgr.sync(&self.tree, now);
                     }
                     _ => {}
                 }
@@ -724,19 +1623,83 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                     .received_getheaders(&addr, (locator_hashes, stop_hash), &self.tree);
             }
             NetworkMessage::Block(block) => {
-                self.syncmgr.received_block(&addr, block, &self.tree);
+                if let Some((height, _)) = self.tree.get_block(&block.block_hash()) {
+                    self.feemgr.received_block(height, &block);
+                }
+                self.invmgr.received_block(&block);
+                self.blockmgr.received_block(&block, now);
+                for (_, hash) in self.blockmgr.missing() {
+                    self.get_block(hash, now);
+                }
+                if let Ok(peers) = self.spvmgr.received_block(&block, &self.tree, now) {
+                    for peer in peers {
+                        self.addrmgr.filters_mismatched(&peer);
+                        self.misbehaving(
+                            peer,
+                            Misbehavior::InvalidFilters("cfilter: filter doesn't match block"),
+                        );
+                    }
+                }
+                // Decoys aren't passed on to the user.
+                if self.decoys.received_block(&block.block_hash()) {
+                    debug!(target: self.target, "{}: Received decoy block", addr);
+                } else {
+                    self.syncmgr.received_block(&addr, block, &self.tree);
+                }
+            }
+            NetworkMessage::Tx(tx) => {
+                self.invmgr.received_tx(addr, &tx.txid());
+            }
+            NetworkMessage::NotFound(inventory) => {
+                // Transactions the peer no longer has are announced to our other peers.
+                for tx in self.invmgr.received_notfound(addr, &inventory) {
+                    let connmgr = &self.connmgr;
+                    let peers = self
+                        .peermgr
+                        .outbound()
+                        .filter(|p| p.relay && p.address() != addr)
+                        .filter(|p| !connmgr.is_broadcast(&p.address()))
+                        .map(|p| p.address())
+                        .collect::<Vec<_>>();
+                    self.invmgr.announce(tx, peers, now);
+                }
+                // Blocks the peer doesn't have are requested from another peer, right away.
+                for inv in inventory {
+                    let hash = match inv {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => hash,
+                        _ => continue,
+                    };
+                    if self.decoys.received_notfound(&hash) {
+                        // Decoys aren't worth requesting again.
+                        self.blockmgr.received_notfound(addr, &hash, now);
+                    } else if self.blockmgr.received_notfound(addr, &hash, now) {
+                        debug!(target: self.target, "{}: Block {} not found", addr, hash);
+
+                        if let Some((height, _)) = self.tree.get_block(&hash) {
+                            self.request_block(hash, height, now);
+                        }
+                    }
+                }
             }
             NetworkMessage::Inv(inventory) => {
                 // Receive an `inv` message. This will happen if we are out of sync with a
                 // peer. And blocks are being announced. Otherwise, we expect to receive a
                 // `headers` message.
+                self.invmgr.received_inv(addr, &inventory);
                 self.syncmgr
                     .received_inv(addr, inventory, &self.clock, &self.tree);
             }
             NetworkMessage::CFHeaders(msg) => {
+                let requested = self.spvmgr.requested_at(&msg.stop_hash);
+
                 match self.spvmgr.received_cfheaders(&addr, msg, &self.tree, now) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
-                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                        self.addrmgr.filters_mismatched(&addr);
+                        self.misbehaving(addr, Misbehavior::InvalidFilters(reason))
+                    }
+                    Ok(_) if requested.is_some() => {
+                        let latency = requested.map(|t| now - t);
+                        self.addrmgr.filters_served(&addr, latency);
                     }
                     _ => {}
                 }
@@ -744,15 +1707,44 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             NetworkMessage::GetCFHeaders(msg) => {
                 match self.spvmgr.received_getcfheaders(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
-                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                        self.misbehaving(addr, Misbehavior::InvalidMessage(reason))
+                    }
+                    _ => {}
+                }
+            }
+            NetworkMessage::GetCFCheckpt(msg) => {
+                match self.spvmgr.received_getcfcheckpt(&addr, msg, &self.tree) {
+                    Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.misbehaving(addr, Misbehavior::InvalidMessage(reason))
+                    }
+                    _ => {}
+                }
+            }
+            NetworkMessage::CFCheckpt(msg) => {
+                match self.spvmgr.received_cfcheckpt(&addr, msg, &self.tree, now) {
+                    Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.addrmgr.filters_mismatched(&addr);
+                        self.misbehaving(addr, Misbehavior::InvalidFilters(reason))
                     }
                     _ => {}
                 }
             }
             NetworkMessage::CFilter(msg) => {
-                match self.spvmgr.received_cfilter(&addr, msg, &self.tree) {
+                self.blockmgr.received_cfilter(addr, msg.block_hash);
+
+                match self.spvmgr.received_cfilter(&addr, msg, &self.tree, now) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
-                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                        self.addrmgr.filters_mismatched(&addr);
+                        self.misbehaving(addr, Misbehavior::InvalidFilters(reason))
+                    }
+                    Ok(()) => self.addrmgr.filters_served(&addr, None),
+                    _ => {}
+                }
+            }
+            NetworkMessage::GetCFilters(msg) if self.spvmgr.is_serving() => {
+                match self.spvmgr.received_getcfilters(&addr, msg, &self.tree) {
+                    Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.misbehaving(addr, Misbehavior::InvalidMessage(reason))
                     }
                     _ => {}
                 }
@@ -767,7 +1759,21 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 self.addrmgr.received_getaddr(&addr);
             }
             NetworkMessage::GetData(inv) => {
-                (*self.hooks.on_getdata)(addr, inv, &self.upstream);
+                let inv = self.invmgr.received_getdata(addr, inv);
+
+                if self.connmgr.is_broadcast(&addr) {
+                    self.connmgr.broadcast_done(&addr, now);
+                }
+
+                if !inv.is_empty() {
+                    (*self.hooks.on_getdata)(addr, inv, &self.upstream);
+                }
+            }
+            NetworkMessage::FeeFilter(rate) => {
+                self.feemgr.received_feefilter(addr, rate);
+            }
+            NetworkMessage::SendHeaders => {
+                self.syncmgr.received_sendheaders(&addr);
             }
             _ => {
                 debug!(target: self.target, "{}: Ignoring {:?}", addr, cmd);
@@ -775,6 +1781,29 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Re-evaluate the power profile, and adjust the timers of the affected managers.
+    fn update_power_profile(&mut self, now: LocalTime) {
+        let synced = !self.syncmgr.is_syncing();
+        let busy = self.spvmgr.is_rescanning();
+
+        if let Some(profile) = self.power.update(now, synced, busy) {
+            debug!(target: self.target, "Switching to {} power profile", profile);
+
+            self.pingmgr
+                .set_ping_interval(self.power.interval(pingmgr::PING_INTERVAL));
+            self.addrmgr.set_intervals(
+                self.power.interval(addrmgr::REQUEST_TIMEOUT),
+                self.power.interval(addrmgr::IDLE_TIMEOUT),
+            );
+        }
+    }
+
+    fn misbehaving(&mut self, addr: PeerId, misbehavior: Misbehavior) {
+        let now = self.clock.local_time();
+
+        self.connmgr.misbehaving(addr, misbehavior, now);
+    }
+
     fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         // TODO: Trigger disconnection everywhere, as if peer disconnected. This
         // avoids being in a state where we know a peer is about to get disconnected,
@@ -787,17 +1816,49 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
     /// Initialize the protocol. Called once before any event is sent to the state machine.
     pub fn initialize(&mut self, time: LocalTime) {
+        self.record(None, time);
         self.clock.set_local_time(time);
         self.addrmgr.initialize(time);
         self.syncmgr.initialize(time, &self.tree);
+        self.connmgr
+            .set_filter_shortage(self.spvmgr.is_short_of_peers());
         self.connmgr.initialize(time, &mut self.addrmgr);
         self.spvmgr.initialize(time, &self.tree);
+        self.upstream
+            .event(Event::NodeStarted(self.effective_config.clone()));
     }
 
     /// Process the next input and advance the state machine by one step.
     pub fn step(&mut self, input: Input, local_time: LocalTime) {
+        self.record(Some(&input), local_time);
+        self.process(input, local_time);
+    }
+
+    fn process(&mut self, input: Input, local_time: LocalTime) {
+        let input = match input {
+            Input::Command(Command::Tagged(epoch, cmd, ack)) => {
+                ack.send(self.epoch).ok();
+
+                if epoch == self.epoch {
+                    return self.process(Input::Command(*cmd), local_time);
+                }
+                warn!(
+                    target: self.target,
+                    "Dropping stale command {:?} from epoch {} (current epoch is {})",
+                    cmd,
+                    epoch,
+                    self.epoch
+                );
+                return;
+            }
+            input => input,
+        };
         self.tick(local_time);
 
+        if let Input::Command(_) = input {
+            self.power.user_activity(local_time);
+        }
+
         match input {
             Input::Connecting { addr } => {
                 self.addrmgr.peer_attempted(&addr, local_time);
@@ -822,19 +1883,31 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 info!(target: self.target, "[conn] {}: Disconnected: {}", addr, reason);
 
                 self.spvmgr.peer_disconnected(&addr);
-                self.syncmgr.peer_disconnected(&addr);
-                self.addrmgr.peer_disconnected(&addr, reason);
                 self.connmgr
-                    .peer_disconnected(&addr, &mut self.addrmgr, local_time);
+                    .set_filter_shortage(self.spvmgr.is_short_of_peers());
+                self.syncmgr.peer_disconnected(&addr);
+                self.addrmgr.peer_disconnected(&addr, reason.clone());
+                self.connmgr.peer_disconnected(
+                    &addr,
+                    reason.clone(),
+                    &mut self.addrmgr,
+                    local_time,
+                );
                 self.pingmgr.peer_disconnected(&addr);
-                self.peermgr.peer_disconnected(&addr);
+                self.peermgr.peer_disconnected(&addr, reason);
+                self.feemgr.peer_disconnected(&addr);
+                self.invmgr.peer_disconnected(&addr);
+                self.ratemgr.peer_disconnected(&addr);
+                self.blockmgr.peer_disconnected(&addr);
             }
             Input::Received(addr, msg) => {
                 self.upstream
                     .event(Event::Received(addr, msg.payload.clone()));
                 self.receive(addr, msg);
             }
-            Input::Sent(_addr, _msg) => {}
+            Input::Sent(addr, size) => {
+                self.pingmgr.message_sent(addr, size);
+            }
             Input::Command(cmd) => match cmd {
                 Command::GetBlockByHeight(height, reply) => {
                     debug!(target: self.target, "Received command: GetBlockByHeight");
@@ -843,6 +1916,13 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     reply.send(header).ok();
                 }
+                Command::GetBlockHeader(hash, reply) => {
+                    debug!(target: self.target, "Received command: GetBlockHeader({})", hash);
+
+                    let header = self.tree.get_block(&hash).map(|(h, header)| (h, *header));
+
+                    reply.send(header).ok();
+                }
                 Command::GetPeers(services, reply) => {
                     debug!(target: self.target, "Received command: GetPeers");
 
@@ -856,10 +1936,36 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     reply.send(peers).ok();
                 }
+                Command::PeerInfo(reply) => {
+                    debug!(target: self.target, "Received command: PeerInfo");
+
+                    let peers = self
+                        .peermgr
+                        .peers()
+                        .filter(|p| p.is_negotiated())
+                        .filter_map(|p| {
+                            let stats = self.pingmgr.stats(&p.address())?;
+
+                            Some(PeerInfo {
+                                addr: p.address(),
+                                link: p.conn.link,
+                                services: p.services,
+                                user_agent: p.user_agent.clone(),
+                                height: p.height,
+                                version: p.version.min(self.protocol_version),
+                                since: p.conn.since,
+                                stats,
+                            })
+                        })
+                        .collect();
+
+                    reply.send(peers).ok();
+                }
                 Command::Connect(addr) => {
                     debug!(target: self.target, "Received command: Connect({})", addr);
 
                     self.peermgr.whitelist(addr);
+                    self.connmgr.whitelist(addr.ip());
                     self.connmgr.connect(&addr, local_time);
                 }
                 Command::Disconnect(addr) => {
@@ -867,6 +1973,46 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     self.disconnect(addr, DisconnectReason::Command);
                 }
+                Command::Ban(addr, duration) => {
+                    debug!(target: self.target, "Received command: Ban({}, {})", addr, duration);
+
+                    self.connmgr
+                        .ban(addr, local_time + duration, String::from("banned by user"));
+                }
+                Command::Unban(addr) => {
+                    debug!(target: self.target, "Received command: Unban({})", addr);
+
+                    self.connmgr.unban(addr);
+                }
+                Command::GetBanned(reply) => {
+                    debug!(target: self.target, "Received command: GetBanned");
+
+                    let banned = self
+                        .connmgr
+                        .banned(local_time)
+                        .map(|(addr, ban)| (*addr, ban.clone()))
+                        .collect();
+
+                    reply.send(banned).ok();
+                }
+                Command::GetAddresses(reply) => {
+                    debug!(target: self.target, "Received command: GetAddresses");
+
+                    reply.send(self.addrmgr.addresses().cloned().collect()).ok();
+                }
+                Command::MarkAddress(addr, good, reply) => {
+                    debug!(
+                        target: self.target,
+                        "Received command: MarkAddress({}, {})", addr, good
+                    );
+
+                    let known = if good {
+                        self.addrmgr.mark_good(&addr, local_time)
+                    } else {
+                        self.addrmgr.mark_bad(&addr)
+                    };
+                    reply.send(known).ok();
+                }
                 Command::Query(msg, reply) => {
                     debug!(target: self.target, "Received command: Query({:?})", msg);
 
@@ -887,12 +2033,26 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 Command::ImportHeaders(headers, reply) => {
                     debug!(target: self.target, "Received command: ImportHeaders(..)");
 
+                    let height = self.tree.height();
                     let result = self.syncmgr.import_blocks(
                         headers.into_iter(),
                         &self.clock,
                         &mut self.tree,
                     );
 
+                    if let Ok(ImportResult::TipChanged(_, _, _, reverted)) = &result {
+                        self.invmgr.tip_changed(&self.tree);
+
+                        if !reverted.is_empty() {
+                            let fork = height.saturating_sub(reverted.len() as Height);
+
+                            if let Err(err) = self.spvmgr.rollback(fork) {
+                                error!(target: self.target, "Error rolling back filters: {}", err);
+                            }
+                            self.spvmgr.sync(&self.tree, local_time);
+                        }
+                    }
+
                     match result {
                         Ok(import_result) => {
                             reply.send(Ok(import_result)).ok();
@@ -911,24 +2071,130 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                         peer::Source::Imported,
                     );
                 }
+                Command::QueryAddresses(seeds, reply) => {
+                    debug!(
+                        target: self.target,
+                        "Received command: QueryAddresses({} seed(s))",
+                        seeds.len()
+                    );
+
+                    let count = self.addrmgr.seed(seeds, local_time);
+                    let peers = self.addrmgr.query(local_time);
+
+                    reply.send((count, peers)).ok();
+                }
                 Command::GetTip(reply) => {
                     let (_, header) = self.tree.tip();
                     let height = self.tree.height();
 
                     reply.send((height, header)).ok();
                 }
-                Command::GetFilters(range, reply) => {
-                    debug!(target: self.target,
-                        "Received command: GetFilters({}..{})", range.start, range.end);
+                Command::GetChainInfo(reply) => {
+                    reply.send(ChainInfo::new(&self.tree, &self.params)).ok();
+                }
+                Command::GetChainWork(reply) => {
+                    let work = self.tree.chain_work(self.tree.height()).unwrap_or_default();
+
+                    reply.send(work).ok();
+                }
+                Command::GetMedianTimePast(height, reply) => {
+                    let time = if height <= self.tree.height() {
+                        Some(self.tree.median_time_past(height + 1))
+                    } else {
+                        None
+                    };
+                    reply.send(time).ok();
+                }
+                Command::EstimateFeeRate(target, reply) => {
+                    reply.send(self.feemgr.estimate(target)).ok();
+                }
+                Command::GetConfig(reply) => {
+                    reply.send(self.effective_config.clone()).ok();
+                }
+                Command::ReadSnapshot(reader) => {
+                    reader.read(&Snapshot {
+                        tree: &self.tree,
+                        filters: self.spvmgr.filters(),
+                    });
+                }
+                Command::GetFilters(ranges, reply) => {
+                    debug!(target: self.target, "Received command: GetFilters({:?})", ranges);
+
+                    let result = self.spvmgr.get_filters(ranges, &self.tree, local_time);
+                    reply.send(result).ok();
+                }
+                Command::Rescan {
+                    from,
+                    to,
+                    watch,
+                    reply,
+                } => {
+                    debug!(target: self.target, "Received command: Rescan({:?}..{:?})", from, to);
+
+                    let result = self.spvmgr.rescan(from, to, watch, &self.tree, local_time);
+                    reply.send(result).ok();
+                }
+                Command::RescanUntilConfirmed {
+                    from,
+                    confirmations,
+                    watch,
+                    reply,
+                } => {
+                    debug!(
+                        target: self.target,
+                        "Received command: RescanUntilConfirmed({:?}, {})", from, confirmations
+                    );
 
-                    let result = self.spvmgr.get_cfilters(range, &self.tree);
+                    let result = self.spvmgr.rescan_until_confirmed(
+                        from,
+                        confirmations,
+                        watch,
+                        &self.tree,
+                        local_time,
+                    );
                     reply.send(result).ok();
                 }
+                Command::AbortRescan => {
+                    debug!(target: self.target, "Received command: AbortRescan");
+
+                    self.spvmgr.abort_rescan();
+                }
+                Command::GetRescanStatus(reply) => {
+                    reply.send(self.spvmgr.rescan_status()).ok();
+                }
+                Command::WatchScripts(id, scripts) => {
+                    debug!(target: self.target, "Received command: WatchScripts({}, ..)", id);
+
+                    self.spvmgr.watch(id, scripts);
+                }
+                Command::UnwatchScripts(id, scripts) => {
+                    debug!(target: self.target, "Received command: UnwatchScripts({}, ..)", id);
+
+                    self.spvmgr.unwatch(id, &scripts);
+                }
+                Command::WatchOutpoints(outpoints) => {
+                    debug!(
+                        target: self.target,
+                        "Received command: WatchOutpoints({})",
+                        outpoints.len()
+                    );
+
+                    self.spvmgr.watch_outpoints(outpoints);
+                }
+                Command::WatchXpub {
+                    xpub,
+                    scheme,
+                    gap_limit,
+                } => {
+                    debug!(
+                        target: self.target,
+                        "Received command: WatchXpub({}, {}, {})", xpub, scheme, gap_limit
+                    );
+
+                    self.spvmgr.watch_xpub(xpub, scheme, gap_limit);
+                }
                 Command::GetBlock(hash, reply) => {
-                    let peer = self
-                        .query(NetworkMessage::GetData(vec![Inventory::Block(hash)]), |p| {
-                            p.services.has(ServiceFlags::NETWORK)
-                        });
+                    let peer = self.get_block(hash, local_time);
 
                     if let Some(peer) = peer {
                         reply.send(Ok(peer)).ok();
@@ -936,22 +2202,112 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                         reply.send(Err(GetBlockError::NotConnected)).ok();
                     }
                 }
-                Command::SubmitTransaction(tx) => {
+                Command::GetBlocks(blocks, reply) => {
+                    debug!(target: self.target, "Received command: GetBlocks({:?})", blocks);
+
+                    match blocks.resolve(&self.tree) {
+                        Ok(blocks) => {
+                            let receive = self.blockmgr.batch(&blocks, local_time);
+
+                            // Blocks that can't be requested now are requested again on the
+                            // next tick. The rest of the batch is requested as blocks are
+                            // delivered.
+                            for (_, hash) in self.blockmgr.missing() {
+                                self.get_block(hash, local_time);
+                            }
+                            reply.send(Ok(receive)).ok();
+                        }
+                        Err(err) => {
+                            reply.send(Err(err)).ok();
+                        }
+                    }
+                }
+                Command::SubmitTransaction(tx, _, true) => {
+                    debug!(target: self.target, "Received command: SubmitTransaction(.., private)");
+
+                    self.power.transaction_submitted(local_time);
+
+                    let peers = self.connmgr.connect_broadcast(
+                        connmgr::BROADCAST_PEERS,
+                        &mut self.addrmgr,
+                        local_time,
+                    );
+                    if peers.is_empty() {
+                        warn!(
+                            target: self.target,
+                            "No peers to broadcast transaction {} to", tx.txid()
+                        );
+                    }
+                    self.invmgr.broadcast(tx, peers, local_time);
+                }
+                Command::SubmitTransaction(tx, fee, false) => {
                     debug!(target: self.target, "Received command: SubmitTransaction(..)");
 
-                    self.query(NetworkMessage::Tx(tx), |p| p.relay);
+                    self.power.transaction_submitted(local_time);
+
+                    let rate = fee.map(|fee| feemgr::feerate(&tx, fee));
+                    let feemgr = &self.feemgr;
+                    let connmgr = &self.connmgr;
+                    let peers = self
+                        .peermgr
+                        .outbound()
+                        .filter(|p| p.relay)
+                        .filter(|p| !connmgr.is_broadcast(&p.address()))
+                        .filter(|p| match (rate, feemgr.feefilter(&p.address())) {
+                            (Some(rate), Some(filter)) => rate >= filter,
+                            _ => true,
+                        })
+                        .map(|p| p.address())
+                        .collect::<Vec<_>>();
+                    self.invmgr.announce(tx, peers, local_time);
+                }
+                Command::GetTxStatus(txid, reply) => {
+                    debug!(target: self.target, "Received command: GetTxStatus({})", txid);
+
+                    reply.send(self.invmgr.status(&txid, &self.tree)).ok();
                 }
                 Command::Shutdown => {
+                    debug!(target: self.target, "Received command: Shutdown");
+
+                    // Stop waiting on responses from peers.
+                    self.syncmgr.shutdown();
+
+                    if let Err(err) = self.spvmgr.shutdown() {
+                        log::error!(target: self.target, "Error syncing filters: {}", err);
+                    }
+                    if let Err(err) = self.tree.sync() {
+                        log::error!(target: self.target, "Error syncing block headers: {}", err);
+                    }
+                    self.addrmgr.shutdown();
+
+                    for peer in self.peermgr.peers().filter(|p| p.is_negotiated()) {
+                        (self.hooks.on_shutdown)(peer.address(), &self.upstream);
+                    }
+                    self.connmgr.shutdown();
+                    // Messages queued up to this point are delivered by the reactor, before
+                    // it closes all connections.
                     self.upstream.push(Out::Shutdown);
                 }
+                Command::Tagged(..) => {
+                    unreachable!("tagged commands are unwrapped before being processed")
+                }
             },
             Input::Tick => {
                 trace!(target: self.target, "Received tick");
 
+                self.update_power_profile(local_time);
                 self.connmgr.received_tick(local_time, &mut self.addrmgr);
                 self.syncmgr.received_tick(local_time, &self.tree);
                 self.pingmgr.received_tick(local_time);
-                self.addrmgr.received_tick(local_time);
+                self.invmgr.received_tick(local_time);
+                self.decoys.received_tick(local_time);
+                self.blockmgr.received_tick(local_time);
+                for (height, hash) in self.blockmgr.missing() {
+                    self.request_block(hash, height, local_time);
+                }
+                if !self.connmgr.config.connect_only {
+                    self.addrmgr.received_tick(local_time);
+                }
                 self.peermgr.received_tick(local_time);
                 self.spvmgr.received_tick(local_time, &self.tree);
             }
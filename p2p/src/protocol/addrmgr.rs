@@ -11,7 +11,7 @@ use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::BlockTime;
 use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::p2p::peer::{AddressSource, KnownAddress, Source, Store};
-use nakamoto_common::p2p::Domain;
+use nakamoto_common::p2p::{Domain, Subnet};
 
 use super::channel::SetTimeout;
 use super::{DisconnectReason, Link, PeerId};
@@ -25,10 +25,19 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 /// Sample timeout. How long before a sampled address can be returned again.
 pub const SAMPLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(3);
 
+/// Time between address relays to our peers.
+pub const RELAY_INTERVAL: LocalDuration = LocalDuration::from_secs(30);
+
 /// Maximum number of addresses expected in a `addr` message.
 const MAX_ADDR_ADDRESSES: usize = 1000;
 /// Maximum number of addresses we store for a given address range.
 const MAX_RANGE_SIZE: usize = 256;
+/// Maximum number of addresses relayed to a peer at once.
+const MAX_RELAY_ADDRESSES: usize = 10;
+/// How recently an address must have been active for it to be relayed.
+const MAX_RELAY_AGE: LocalDuration = LocalDuration::from_mins(10);
+/// Maximum number of addresses remembered as known to a peer.
+const MAX_KNOWN_ADDRESSES: usize = 5000;
 
 /// Address manager event emission.
 pub trait Events {
@@ -141,6 +150,8 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Communication domains we're interested in.
     pub domains: Vec<Domain>,
+    /// Addresses and subnets we never connect to.
+    pub blacklist: Vec<Subnet>,
 }
 
 impl Default for Config {
@@ -148,6 +159,7 @@ impl Default for Config {
         Self {
             required_services: ServiceFlags::NONE,
             domains: Domain::all(),
+            blacklist: Vec::new(),
         }
     }
 }
@@ -165,6 +177,17 @@ pub struct AddressManager<P, U> {
     last_request: Option<LocalTime>,
     /// The last time we idled.
     last_idle: Option<LocalTime>,
+    /// The last time we relayed addresses to our peers.
+    last_relay: Option<LocalTime>,
+    /// Recently discovered addresses, to be relayed to our peers.
+    relay_queue: Vec<(LocalTime, Address)>,
+    /// Addresses known to each of our peers, either because they sent them to us, or we
+    /// sent them to them.
+    known: HashMap<net::SocketAddr, HashSet<net::SocketAddr>>,
+    /// Minimum time between address requests. Defaults to [`REQUEST_TIMEOUT`].
+    request_interval: LocalDuration,
+    /// Time between idle runs. Defaults to [`IDLE_TIMEOUT`].
+    idle_interval: LocalDuration,
     cfg: Config,
     upstream: U,
     rng: fastrand::Rng,
@@ -188,6 +211,33 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         }
     }
 
+    /// Ask all our address sources, ie. outbound peers, for new addresses, regardless of
+    /// when we last did. Returns the peers asked.
+    pub fn query(&mut self, local_time: LocalTime) -> Vec<PeerId> {
+        self.get_addresses();
+        self.last_request = Some(local_time);
+
+        self.sources.iter().copied().collect()
+    }
+
+    /// Add addresses resolved from DNS seeds to the address book. Returns the number of
+    /// new addresses.
+    pub fn seed(
+        &mut self,
+        addrs: impl IntoIterator<Item = net::SocketAddr>,
+        time: LocalTime,
+    ) -> usize {
+        let len = self.peers.len();
+
+        self.insert(
+            addrs
+                .into_iter()
+                .map(|a| (time.block_time(), Address::new(&a, ServiceFlags::NONE))),
+            Source::Dns,
+        );
+        self.peers.len() - len
+    }
+
     /// Called when we receive a `getaddr` message.
     pub fn received_getaddr(&mut self, from: &net::SocketAddr) {
         // TODO: We should only respond with peers who were last active within
@@ -205,23 +255,28 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
                 ka.addr.clone(),
             ));
         }
+        self.learn(from, addrs.iter().map(|(_, a)| a));
         self.upstream.send_addresses(*from, addrs);
     }
 
     /// Called when a tick is received.
     pub fn received_tick(&mut self, local_time: LocalTime) {
         // If we're already using all the addresses we have available, we should fetch more.
-        if local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
+        if local_time - self.last_request.unwrap_or_default() >= self.request_interval
             && self.is_exhausted()
         {
             Events::event(&self.upstream, Event::AddressBookExhausted);
 
             self.get_addresses();
             self.last_request = Some(local_time);
-            self.upstream.set_timeout(REQUEST_TIMEOUT);
+            self.upstream.set_timeout(self.request_interval);
         }
 
-        if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+        if local_time - self.last_relay.unwrap_or_default() >= RELAY_INTERVAL {
+            self.relay(local_time);
+        }
+
+        if local_time - self.last_idle.unwrap_or_default() >= self.idle_interval {
             self.idle(local_time);
         }
     }
@@ -233,6 +288,22 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         }
     }
 
+    /// Called when a peer served us valid compact filters or filter headers, with the time
+    /// it took to respond, if known.
+    pub fn filters_served(&mut self, addr: &net::SocketAddr, latency: Option<LocalDuration>) {
+        if let Some(ka) = self.peers.get_mut(&addr.ip()) {
+            ka.filters.served(latency);
+        }
+    }
+
+    /// Called when a peer served us compact filters or filter headers that don't match
+    /// our filter header chain.
+    pub fn filters_mismatched(&mut self, addr: &net::SocketAddr) {
+        if let Some(ka) = self.peers.get_mut(&addr.ip()) {
+            ka.filters.mismatched();
+        }
+    }
+
     /// Called when a peer connection is attempted.
     pub fn peer_attempted(&mut self, addr: &net::SocketAddr, time: LocalTime) {
         // We're only interested in connection attempts for addresses we keep track of.
@@ -263,6 +334,8 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         if link.is_outbound() {
             self.sources.insert(*addr);
         }
+        self.known
+            .insert(*addr, HashSet::with_hasher(self.rng.clone().into()));
 
         // We're only interested in peers we already know, eg. from DNS or peer
         // exchange. Peers should only be added to our address book if they are DNS seeds
@@ -284,6 +357,7 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         if self.connected.remove(&addr.ip()) {
             // Disconnected peers cannot be used as a source for new addresses.
             self.sources.remove(&addr);
+            self.known.remove(addr);
 
             // If the reason for disconnecting the peer suggests that we shouldn't try to
             // connect to this peer again, then remove the peer from the address book.
@@ -295,8 +369,48 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         }
     }
 
+    /// Called when we're shutting down. Saves addresses to the store.
+    pub fn shutdown(&mut self) {
+        if let Err(err) = self.peers.flush() {
+            self.upstream
+                .event(Event::Error(format!("flush to disk failed: {}", err)));
+        }
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Relay a random sample of recently discovered addresses to the peers that don't
+    /// know about them.
+    fn relay(&mut self, local_time: LocalTime) {
+        // Only addresses that were recently active are worth relaying.
+        self.relay_queue
+            .retain(|(last_active, _)| local_time - *last_active <= MAX_RELAY_AGE);
+        self.rng.shuffle(&mut self.relay_queue);
+
+        let sample = self
+            .relay_queue
+            .split_off(self.relay_queue.len().saturating_sub(MAX_RELAY_ADDRESSES));
+        let peers = self.known.keys().copied().collect::<Vec<_>>();
+
+        for peer in peers {
+            let addrs = sample
+                .iter()
+                .filter(|(_, addr)| match addr.socket_addr() {
+                    Ok(a) => a != peer && !self.known[&peer].contains(&a),
+                    Err(_) => false,
+                })
+                .map(|(last_active, addr)| (last_active.block_time(), addr.clone()))
+                .collect::<Vec<_>>();
+
+            if !addrs.is_empty() {
+                self.learn(&peer, addrs.iter().map(|(_, a)| a));
+                self.upstream.send_addresses(peer, addrs);
+            }
+        }
+        self.last_relay = Some(local_time);
+        self.upstream.set_timeout(RELAY_INTERVAL);
+    }
+
     fn idle(&mut self, local_time: LocalTime) {
         // If it's been a while, save addresses to store.
         if let Err(err) = self.peers.flush() {
@@ -304,11 +418,17 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
                 .event(Event::Error(format!("flush to disk failed: {}", err)));
         }
         self.last_idle = Some(local_time);
-        self.upstream.set_timeout(IDLE_TIMEOUT);
+        self.upstream.set_timeout(self.idle_interval);
     }
 }
 
 impl<P, U> AddressManager<P, U> {
+    /// Set the address request and idle intervals, eg. based on the current power profile.
+    pub fn set_intervals(&mut self, request: LocalDuration, idle: LocalDuration) {
+        self.request_interval = request;
+        self.idle_interval = idle;
+    }
+
     /// Record an address of ours as seen by a remote peer.
     /// This helps avoid self-connections.
     pub fn record_local_addr(&mut self, addr: net::SocketAddr) {
@@ -329,6 +449,11 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             local_addrs: HashSet::with_hasher(rng.clone().into()),
             last_request: None,
             last_idle: None,
+            last_relay: None,
+            relay_queue: Vec::new(),
+            known: HashMap::with_hasher(rng.clone().into()),
+            request_interval: REQUEST_TIMEOUT,
+            idle_interval: IDLE_TIMEOUT,
             upstream,
             rng,
         };
@@ -349,6 +474,61 @@ impl<P: Store, U: Events> AddressManager<P, U> {
         self.peers.is_empty() || self.address_ranges.is_empty()
     }
 
+    /// Remember that a peer knows the given addresses, so that we don't relay them to it.
+    fn learn<'a>(&mut self, peer: &net::SocketAddr, addrs: impl Iterator<Item = &'a Address>) {
+        if let Some(known) = self.known.get_mut(peer) {
+            if known.len() >= MAX_KNOWN_ADDRESSES {
+                known.clear();
+            }
+            known.extend(addrs.filter_map(|a| a.socket_addr().ok()));
+        }
+    }
+
+    /// Check whether an address is blacklisted.
+    pub fn is_blacklisted(&self, ip: &net::IpAddr) -> bool {
+        self.cfg.blacklist.iter().any(|s| s.contains(ip))
+    }
+
+    /// Iterate over the known addresses.
+    pub fn addresses(&self) -> impl Iterator<Item = &KnownAddress> + '_ {
+        self.peers.iter().map(|(_, ka)| ka)
+    }
+
+    /// Mark a known address as good, as if we had just connected to it successfully. This
+    /// makes it eligible for sampling again, if it was discarded. Returns `false` if the
+    /// address isn't known.
+    pub fn mark_good(&mut self, ip: &net::IpAddr, time: LocalTime) -> bool {
+        match self.peers.get_mut(ip) {
+            Some(ka) => {
+                ka.last_success = Some(time);
+                ka.last_active = Some(time);
+            }
+            None => return false,
+        }
+        self.populate_address_ranges(ip);
+
+        true
+    }
+
+    /// Mark a known address as bad, removing it from the address book. Returns `false` if
+    /// the address isn't known. The address may be learned again from peers: to keep from
+    /// connecting to it, it should be banned instead.
+    pub fn mark_bad(&mut self, ip: &net::IpAddr) -> bool {
+        if self.peers.remove(ip).is_none() {
+            return false;
+        }
+        let key = self::addr_key(ip);
+
+        if let Some(range) = self.address_ranges.get_mut(&key) {
+            range.remove(ip);
+
+            if range.is_empty() {
+                self.address_ranges.remove(&key);
+            }
+        }
+        true
+    }
+
     /// Clear the address manager of all peers.
     pub fn clear(&mut self) {
         self.peers.clear();
@@ -362,12 +542,12 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             return;
         }
         let source = Source::Peer(peer);
+        let count = addrs.len();
 
-        self.upstream.event(Event::AddressesReceived {
-            count: addrs.len(),
-            source,
-        });
+        self.learn(&peer, addrs.iter().map(|(_, a)| a));
         self.insert(addrs.into_iter(), source);
+        self.upstream
+            .event(Event::AddressesReceived { count, source });
     }
 
     /// Add addresses to the address manager. The input matches that of the `addr` message
@@ -419,8 +599,9 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             .expect("AddressManager::insert: manager must be initialized before inserting");
 
         for (last_active, addr) in addrs {
-            // Ignore addresses that don't have the required services.
-            if !addr.services.has(self.cfg.required_services) {
+            // Ignore addresses that don't have the required services. DNS seeds don't tell
+            // us the services of the addresses they return: we learn them on handshake.
+            if source != Source::Dns && !addr.services.has(self.cfg.required_services) {
                 continue;
             }
             // Ignore addresses that don't have a "last active" time.
@@ -453,6 +634,11 @@ impl<P: Store, U: Events> AddressManager<P, U> {
                 continue;
             }
 
+            // Ignore blacklisted addresses.
+            if self.is_blacklisted(&ip) {
+                continue;
+            }
+
             let last_active = if last_active == 0 {
                 None
             } else {
@@ -469,6 +655,13 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             }
 
             self.populate_address_ranges(&net_addr.ip());
+
+            // Addresses learned from peers are relayed to our other peers.
+            if let (Source::Peer(_), Some(last_active)) = (source, last_active) {
+                if self.relay_queue.len() < MAX_ADDR_ADDRESSES {
+                    self.relay_queue.push((last_active, addr.clone()));
+                }
+            }
             self.upstream.event(Event::AddressDiscovered(addr, source));
         }
     }
@@ -543,32 +736,18 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     ///
     /// ```
     pub fn sample(&mut self, services: ServiceFlags) -> Option<(Address, Source)> {
+        self.sample_with(|ka: &KnownAddress| self::may_have_services(ka, services))
+    }
+
+    /// Sample an address that served us compact filters well in the past. See
+    /// [`nakamoto_common::p2p::peer::FilterService::is_good`].
+    pub fn sample_filter_server(&mut self, services: ServiceFlags) -> Option<(Address, Source)> {
+        // Most addresses have no filter-serving history, so avoid sampling in vain.
+        if !self.peers.iter().any(|(_, ka)| ka.filters.is_good()) {
+            return None;
+        }
         self.sample_with(|ka: &KnownAddress| {
-            if !ka.addr.services.has(services) {
-                match ka.source {
-                    Source::Dns => {
-                        // If we've negotiated with this peer and it hasn't signaled the
-                        // required services, we know not to return it.
-                        // The reason we check this is that DNS-sourced addresses don't include
-                        // service information, so we can only know once negotiated.
-                        if ka.last_success.is_some() {
-                            return false;
-                        }
-                    }
-                    Source::Imported => {
-                        // We expect that imported addresses will always include the correct
-                        // service information. Hence, if this one doesn't have the necessary
-                        // services, it's safe to skip.
-                        return false;
-                    }
-                    Source::Peer(_) => {
-                        // Peer-sourced addresses come with service information. It's safe to
-                        // skip this address if it doesn't have the required services.
-                        return false;
-                    }
-                }
-            }
-            true
+            ka.filters.is_good() && self::may_have_services(ka, services)
         })
     }
 
@@ -625,6 +804,10 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             if self.connected.contains(&ip) {
                 continue;
             }
+            // If the address was blacklisted after it was added, skip it.
+            if self.cfg.blacklist.iter().any(|s| s.contains(ip)) {
+                continue;
+            }
             // If the provided filter doesn't pass, keep looking.
             if !predicate(&ka) {
                 continue;
@@ -695,6 +878,10 @@ impl<P: Store, U: Events + SyncAddresses + SetTimeout> AddressSource for Address
     fn iter(&mut self, services: ServiceFlags) -> Box<dyn Iterator<Item = (Address, Source)> + '_> {
         Box::new(AddressManager::iter(self, services))
     }
+
+    fn sample_filter_server(&mut self, services: ServiceFlags) -> Option<(Address, Source)> {
+        AddressManager::sample_filter_server(self, services)
+    }
 }
 
 /// Check whether an IP address is globally routable.
@@ -715,6 +902,36 @@ pub fn is_local(addr: &net::IpAddr) -> bool {
     }
 }
 
+/// Check whether an address may offer the given services. For addresses we haven't
+/// negotiated with, this isn't always known.
+fn may_have_services(ka: &KnownAddress, services: ServiceFlags) -> bool {
+    if !ka.addr.services.has(services) {
+        match ka.source {
+            Source::Dns => {
+                // If we've negotiated with this peer and it hasn't signaled the
+                // required services, we know not to return it.
+                // The reason we check this is that DNS-sourced addresses don't include
+                // service information, so we can only know once negotiated.
+                if ka.last_success.is_some() {
+                    return false;
+                }
+            }
+            Source::Imported => {
+                // We expect that imported addresses will always include the correct
+                // service information. Hence, if this one doesn't have the necessary
+                // services, it's safe to skip.
+                return false;
+            }
+            Source::Peer(_) => {
+                // Peer-sourced addresses come with service information. It's safe to
+                // skip this address if it doesn't have the required services.
+                return false;
+            }
+        }
+    }
+    true
+}
+
 /// Get the 8-bit key of an IP address. This key is based on the IP address's
 /// range, and is used as a key to group IP addresses by range.
 fn addr_key(ip: &net::IpAddr) -> u8 {
@@ -861,6 +1078,77 @@ mod tests {
         assert!(ka.last_sampled.is_some());
     }
 
+    #[test]
+    fn test_mark_addresses() {
+        let mut addrmgr =
+            AddressManager::new(Config::default(), fastrand::Rng::new(), HashMap::new(), ());
+        let time = LocalTime::now();
+        let services = ServiceFlags::NETWORK;
+        let good: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let bad: net::SocketAddr = ([44, 44, 44, 44], 8333).into();
+        let unknown: net::IpAddr = [55, 55, 55, 55].into();
+
+        addrmgr.initialize(time);
+        addrmgr.insert(
+            [
+                (time.block_time(), Address::new(&good, services)),
+                (time.block_time(), Address::new(&bad, services)),
+            ],
+            Source::Dns,
+        );
+        assert_eq!(addrmgr.addresses().count(), 2);
+
+        // A failed attempt keeps an address from being sampled, until it's marked as good.
+        addrmgr.peer_attempted(&good, time);
+        assert!(addrmgr.mark_good(&good.ip(), time));
+        assert_eq!(
+            addrmgr.peers.get(&good.ip()).unwrap().last_success,
+            Some(time)
+        );
+
+        // Bad addresses are forgotten.
+        assert!(addrmgr.mark_bad(&bad.ip()));
+        assert!(!addrmgr.mark_bad(&bad.ip()));
+        assert_eq!(addrmgr.addresses().count(), 1);
+
+        assert!(!addrmgr.mark_good(&unknown, time));
+        assert!(!addrmgr.mark_bad(&unknown));
+
+        let (sampled, _) = addrmgr.sample(services).unwrap();
+        assert_eq!(sampled.socket_addr().ok(), Some(good));
+        assert!(addrmgr.sample(services).is_none());
+    }
+
+    #[test]
+    fn test_sample_filter_server() {
+        let mut addrmgr =
+            AddressManager::new(Config::default(), fastrand::Rng::new(), HashMap::new(), ());
+        let time = LocalTime::now();
+        let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let good: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let bad: net::SocketAddr = ([44, 44, 44, 44], 8333).into();
+
+        addrmgr.initialize(time);
+        addrmgr.insert(
+            [
+                (time.block_time(), Address::new(&good, services)),
+                (time.block_time(), Address::new(&bad, services)),
+            ],
+            Source::Dns,
+        );
+        assert!(addrmgr.sample_filter_server(services).is_none());
+
+        addrmgr.filters_served(&good, Some(LocalDuration::from_millis(200)));
+        addrmgr.filters_served(&bad, Some(LocalDuration::from_millis(200)));
+        addrmgr.filters_mismatched(&bad);
+
+        let (sampled, _) = addrmgr.sample_filter_server(services).unwrap();
+        assert_eq!(sampled.socket_addr().ok(), Some(good));
+
+        // The address was just sampled, and the other one has a poor history.
+        assert!(addrmgr.sample_filter_server(services).is_none());
+    }
+
     #[test]
     fn test_is_exhausted() {
         let mut addrmgr =
@@ -1019,6 +1307,38 @@ mod tests {
         TestResult::passed()
     }
 
+    #[test]
+    fn test_blacklist() {
+        let cfg = Config {
+            blacklist: vec!["183.8.0.0/16".parse().unwrap()],
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), ());
+        let time = LocalTime::now();
+
+        addrmgr.initialize(time);
+        addrmgr.insert(
+            vec![
+                Address::new(&([183, 8, 55, 2], 8333).into(), ServiceFlags::NETWORK),
+                Address::new(&([211, 48, 99, 4], 8333).into(), ServiceFlags::NETWORK),
+            ]
+            .into_iter()
+            .map(|a| (time.block_time(), a)),
+            Source::Dns,
+        );
+
+        assert_eq!(addrmgr.len(), 1, "blacklisted addresses are ignored");
+        assert_eq!(
+            addrmgr
+                .sample(ServiceFlags::NONE)
+                .unwrap()
+                .0
+                .socket_addr()
+                .unwrap(),
+            ([211, 48, 99, 4], 8333).into()
+        );
+    }
+
     #[test]
     fn test_max_range_size() {
         let services = ServiceFlags::NONE;
@@ -0,0 +1,580 @@
+//! Block download management.
+//!
+//! Blocks are requested from the outbound peer we requested the fewest blocks from, so that
+//! requests are spread across peers, and no single peer sees all of them.
+//!
+//! The peer that served us the compact filter of a block is never asked for the block
+//! itself, since that peer would then learn that the filter matched our wallet. Peers
+//! serving filters are remembered for the last [`MAX_FILTER_PEERS`] filters received. If no
+//! other peer can serve the block, eg. when we only have one peer, the block is requested
+//! from the filter peer anyway.
+//!
+//! Blocks already requested aren't requested again until the request times out, or the
+//! peer disconnects. Peers that reply with `notfound` aren't asked for the block again.
+//! Blocks can also be requested in batches, which are delivered in height order over a
+//! dedicated channel, see [`Blocks`]. Only the lowest [`MAX_BATCH_IN_FLIGHT`] blocks of a
+//! batch that weren't delivered yet are requested, and the channel is bounded by the same
+//! number, so that a slow receiver holds back the download instead of filling up memory.
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+
+use crossbeam_channel as chan;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{Block, BlockHash, Height};
+use nakamoto_common::collections::HashMap;
+
+use super::{GetBlockError, PeerId};
+
+/// Number of filters we remember the serving peer of.
+pub const MAX_FILTER_PEERS: usize = 1 << 14;
+/// Time after which a block request is considered lost, and the block can be requested again.
+pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
+/// Time after which a batch that made no progress is abandoned.
+pub const BATCH_TIMEOUT: LocalDuration = LocalDuration::from_mins(5);
+/// Maximum number of blocks of a batch that are requested or waiting to be delivered at once.
+/// Also the capacity of the channel batches are delivered on.
+pub const MAX_BATCH_IN_FLIGHT: usize = 16;
+
+/// Blocks to fetch in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocks {
+    /// The blocks of the active chain in the given range of heights.
+    Range(Range<Height>),
+    /// The given blocks of the active chain, eg. the blocks matched during a rescan.
+    Hashes(Vec<BlockHash>),
+}
+
+impl Blocks {
+    /// Get the heights and hashes of the blocks, in height order, without duplicates.
+    pub fn resolve<T: BlockTree>(
+        &self,
+        tree: &T,
+    ) -> Result<Vec<(Height, BlockHash)>, GetBlockError> {
+        let mut blocks = match self {
+            Self::Range(range) => {
+                if range.end > tree.height() + 1 {
+                    return Err(GetBlockError::InvalidRange(range.clone()));
+                }
+                range
+                    .clone()
+                    .filter_map(|h| tree.get_block_by_height(h).map(|b| (h, b.block_hash())))
+                    .collect::<Vec<_>>()
+            }
+            Self::Hashes(hashes) => hashes
+                .iter()
+                .map(|hash| {
+                    tree.get_block(hash)
+                        .map(|(height, _)| (height, *hash))
+                        .ok_or(GetBlockError::NotFound(*hash))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        Ok(blocks)
+    }
+}
+
+impl From<Range<Height>> for Blocks {
+    fn from(range: Range<Height>) -> Self {
+        Self::Range(range)
+    }
+}
+
+impl From<Vec<BlockHash>> for Blocks {
+    fn from(hashes: Vec<BlockHash>) -> Self {
+        Self::Hashes(hashes)
+    }
+}
+
+/// A batch of blocks, delivered in height order.
+#[derive(Debug)]
+struct Batch {
+    /// Blocks of the batch not yet delivered, and the block itself once received.
+    blocks: BTreeMap<Height, (BlockHash, Option<Block>)>,
+    /// Channel the blocks are delivered on. Dropped once all blocks are delivered, or the
+    /// batch is abandoned.
+    reply: chan::Sender<(Block, Height)>,
+    /// Last time a block of the batch was received.
+    last_active: LocalTime,
+}
+
+impl Batch {
+    /// Deliver the blocks received that aren't waiting on a lower block, until the channel
+    /// is full. Returns `false` if the batch is done, or the receiver is gone.
+    fn deliver(&mut self, now: LocalTime) -> bool {
+        while let Some((&height, (_, slot))) = self.blocks.iter_mut().next() {
+            let block = match slot.take() {
+                Some(block) => block,
+                None => break,
+            };
+            match self.reply.try_send((block, height)) {
+                Ok(()) => {
+                    self.blocks.remove(&height);
+                    self.last_active = now;
+                }
+                // Delivered on a later tick, once the receiver caught up.
+                Err(chan::TrySendError::Full((block, _))) => {
+                    *slot = Some(block);
+                    break;
+                }
+                Err(chan::TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        !self.blocks.is_empty()
+    }
+
+    /// Blocks that may be requested: the lowest ones not yet delivered.
+    fn window(&self) -> impl Iterator<Item = (&Height, &(BlockHash, Option<Block>))> {
+        self.blocks.iter().take(MAX_BATCH_IN_FLIGHT)
+    }
+}
+
+/// Picks the peers to download blocks from.
+#[derive(Debug)]
+pub struct BlockManager {
+    /// Peers that served us the filters of recent blocks.
+    filter_peers: HashMap<BlockHash, PeerId>,
+    /// Blocks in `filter_peers`, oldest first.
+    filter_blocks: VecDeque<BlockHash>,
+    /// Number of blocks requested from each peer.
+    requests: HashMap<PeerId, usize>,
+    /// Blocks requested and not yet received, with the peer they were requested from.
+    in_flight: HashMap<BlockHash, (PeerId, LocalTime)>,
+    /// Peers that don't have a block, according to their `notfound` reply, and when the
+    /// last of them replied.
+    unavailable: HashMap<BlockHash, (Vec<PeerId>, LocalTime)>,
+    /// Peers a block request timed out with, and when the last of them timed out. These
+    /// are only asked for the block again if no other peer can serve it.
+    timed_out: HashMap<BlockHash, (Vec<PeerId>, LocalTime)>,
+    /// Batches not fully delivered yet.
+    batches: Vec<Batch>,
+    rng: fastrand::Rng,
+}
+
+impl BlockManager {
+    /// Create a new block manager.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            filter_peers: HashMap::with_hasher(rng.clone().into()),
+            filter_blocks: VecDeque::new(),
+            requests: HashMap::with_hasher(rng.clone().into()),
+            in_flight: HashMap::with_hasher(rng.clone().into()),
+            unavailable: HashMap::with_hasher(rng.clone().into()),
+            timed_out: HashMap::with_hasher(rng.clone().into()),
+            batches: Vec::new(),
+            rng,
+        }
+    }
+
+    /// Get the peer that served us the filter of a block, if known.
+    pub fn filter_peer(&self, block: &BlockHash) -> Option<PeerId> {
+        self.filter_peers.get(block).copied()
+    }
+
+    /// Get the peer a block was requested from, if the request is still in flight.
+    pub fn in_flight(&self, block: &BlockHash, now: LocalTime) -> Option<PeerId> {
+        self.in_flight
+            .get(block)
+            .filter(|(_, since)| now - *since < REQUEST_TIMEOUT)
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Pick the peer to request a block from, out of the peers able to serve it. Returns
+    /// `None` if there are no such peers, or they all replied that they don't have the
+    /// block. The block is considered in flight until it's received, or the request times out.
+    pub fn select(
+        &mut self,
+        block: &BlockHash,
+        peers: impl IntoIterator<Item = PeerId>,
+        now: LocalTime,
+    ) -> Option<PeerId> {
+        let mut peers = peers.into_iter().collect::<Vec<_>>();
+
+        if let Some((unavailable, _)) = self.unavailable.get(block) {
+            peers.retain(|p| !unavailable.contains(p));
+        }
+        // A request that timed out is being retried: try another peer if there is one.
+        self.expire(now);
+        if let Some((timed_out, _)) = self.timed_out.get(block) {
+            if peers.iter().any(|p| !timed_out.contains(p)) {
+                peers.retain(|p| !timed_out.contains(p));
+            }
+        }
+
+        // Fall back to the filter peer if it's the only candidate.
+        if let Some(filter_peer) = self.filter_peer(block) {
+            if peers.iter().any(|p| *p != filter_peer) {
+                peers.retain(|p| *p != filter_peer);
+            }
+        }
+        // Ties are broken at random.
+        self.rng.shuffle(&mut peers);
+
+        let peer = peers
+            .into_iter()
+            .min_by_key(|p| self.requests.get(p).copied().unwrap_or_default())?;
+        *self.requests.entry(peer).or_default() += 1;
+        self.in_flight.insert(*block, (peer, now));
+
+        Some(peer)
+    }
+
+    /// Add a batch of blocks to deliver in height order, once received. The blocks returned
+    /// by [`BlockManager::missing`] should be requested separately. Returns the receiving end
+    /// of the channel the blocks are delivered on.
+    pub fn batch(
+        &mut self,
+        blocks: &[(Height, BlockHash)],
+        now: LocalTime,
+    ) -> chan::Receiver<(Block, Height)> {
+        let (reply, receive) = chan::bounded(MAX_BATCH_IN_FLIGHT);
+        let blocks = blocks
+            .iter()
+            .map(|(height, hash)| (*height, (*hash, None)))
+            .collect::<BTreeMap<_, _>>();
+
+        if !blocks.is_empty() {
+            self.batches.push(Batch {
+                blocks,
+                reply,
+                last_active: now,
+            });
+        }
+        receive
+    }
+
+    /// Get the blocks of active batches that were neither received, nor are in flight.
+    /// These should be requested. Blocks too far above the lowest block of their batch
+    /// that wasn't delivered are left for later.
+    pub fn missing(&self) -> Vec<(Height, BlockHash)> {
+        let mut missing = self
+            .batches
+            .iter()
+            .flat_map(|b| b.window())
+            .filter(|(_, (hash, block))| block.is_none() && !self.in_flight.contains_key(hash))
+            .map(|(height, (hash, _))| (*height, *hash))
+            .collect::<Vec<_>>();
+
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+
+    /// Called when a block was received. Delivers the batches waiting on it.
+    pub fn received_block(&mut self, block: &Block, now: LocalTime) {
+        let hash = block.block_hash();
+
+        self.in_flight.remove(&hash);
+        self.unavailable.remove(&hash);
+        self.timed_out.remove(&hash);
+        for batch in self.batches.iter_mut() {
+            for (h, b) in batch.blocks.values_mut() {
+                if *h == hash && b.is_none() {
+                    *b = Some(block.clone());
+                    batch.last_active = now;
+                }
+            }
+        }
+        self.deliver(now);
+    }
+
+    /// Called when a peer replied with `notfound` to our request for a block. Returns
+    /// whether the block was requested from that peer, in which case it should be requested
+    /// again, from another peer.
+    pub fn received_notfound(&mut self, from: PeerId, block: &BlockHash, now: LocalTime) -> bool {
+        match self.in_flight.get(block) {
+            Some((peer, _)) if *peer == from => {
+                self.in_flight.remove(block);
+            }
+            _ => return false,
+        }
+        let (peers, since) = self
+            .unavailable
+            .entry(*block)
+            .or_insert_with(|| (Vec::new(), now));
+
+        peers.push(from);
+        *since = now;
+
+        true
+    }
+
+    /// Forget the requests that timed out, remembering the peers they were sent to.
+    fn expire(&mut self, now: LocalTime) {
+        let timed_out = &mut self.timed_out;
+
+        self.in_flight.retain(|block, (peer, since)| {
+            if now - *since < REQUEST_TIMEOUT {
+                return true;
+            }
+            let (peers, last) = timed_out.entry(*block).or_insert_with(|| (Vec::new(), now));
+
+            peers.push(*peer);
+            *last = now;
+
+            false
+        });
+    }
+
+    /// Called when a tick is received. Forgets requests that timed out.
+    pub fn received_tick(&mut self, now: LocalTime) {
+        self.expire(now);
+        // Peers may have the block, or be responsive, by now.
+        self.unavailable
+            .retain(|_, (_, since)| now - *since < BATCH_TIMEOUT);
+        self.timed_out
+            .retain(|_, (_, since)| now - *since < BATCH_TIMEOUT);
+        // Dropping the batch closes its channel, which tells the receiver it's incomplete.
+        self.deliver(now);
+        self.batches
+            .retain(|batch| now - batch.last_active < BATCH_TIMEOUT);
+    }
+
+    /// Deliver the blocks received to the batches waiting on them, and drop the batches that
+    /// are done.
+    fn deliver(&mut self, now: LocalTime) {
+        let mut i = 0;
+
+        while i < self.batches.len() {
+            if self.batches[i].deliver(now) {
+                i += 1;
+            } else {
+                self.batches.remove(i);
+            }
+        }
+    }
+
+    /// Called when a peer sent us the filter of a block.
+    pub fn received_cfilter(&mut self, from: PeerId, block: BlockHash) {
+        if self.filter_peers.insert(block, from).is_none() {
+            self.filter_blocks.push_back(block);
+        }
+        while self.filter_blocks.len() > MAX_FILTER_PEERS {
+            if let Some(oldest) = self.filter_blocks.pop_front() {
+                self.filter_peers.remove(&oldest);
+            }
+        }
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.requests.remove(addr);
+        self.in_flight.retain(|_, (peer, _)| peer != addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::hashes::Hash;
+
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_select() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut blockmgr = BlockManager::new(rng);
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let block = BlockHash::hash(b"block");
+        let now = LocalTime::now();
+
+        // Requests are spread across peers.
+        let first = blockmgr.select(&block, vec![alice, bob], now).unwrap();
+        let second = blockmgr.select(&block, vec![alice, bob], now).unwrap();
+        assert_ne!(first, second);
+
+        blockmgr.peer_disconnected(&alice);
+        assert_eq!(blockmgr.select(&block, vec![alice, bob], now), Some(alice));
+        assert_eq!(blockmgr.select(&block, vec![], now), None);
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut blockmgr = BlockManager::new(rng);
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let block = BlockHash::hash(b"block");
+        let mut now = LocalTime::now();
+
+        // Whichever peer the request timed out with, the block is requested from the other,
+        for _ in 0..4 {
+            let first = blockmgr.select(&block, vec![alice, bob], now).unwrap();
+            let other = if first == alice { bob } else { alice };
+            now = now + REQUEST_TIMEOUT;
+
+            // even if it's busier.
+            blockmgr.requests.insert(other, 8);
+
+            assert_eq!(blockmgr.in_flight(&block, now), None);
+            assert_eq!(blockmgr.select(&block, vec![alice, bob], now), Some(other));
+
+            blockmgr.peer_disconnected(&alice);
+            blockmgr.peer_disconnected(&bob);
+            blockmgr.timed_out.clear();
+        }
+        // ... unless it's the only peer.
+        let first = blockmgr.select(&block, vec![alice], now).unwrap();
+        assert_eq!(
+            blockmgr.select(&block, vec![alice], now + REQUEST_TIMEOUT),
+            Some(first)
+        );
+    }
+
+    #[test]
+    fn test_filter_peer() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut blockmgr = BlockManager::new(rng);
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let block = BlockHash::hash(b"block");
+        let now = LocalTime::now();
+
+        blockmgr.received_cfilter(alice, block);
+
+        // The filter peer isn't asked for the block, even if it's the least used.
+        for _ in 0..4 {
+            assert_eq!(blockmgr.select(&block, vec![alice, bob], now), Some(bob));
+        }
+        // ... unless it's the only peer.
+        assert_eq!(blockmgr.select(&block, vec![alice], now), Some(alice));
+
+        // Only the most recent filter peers are remembered.
+        for i in 0..MAX_FILTER_PEERS {
+            blockmgr.received_cfilter(bob, BlockHash::hash(&i.to_le_bytes()));
+        }
+        assert_eq!(blockmgr.filter_peer(&block), None);
+        assert_eq!(blockmgr.filter_blocks.len(), MAX_FILTER_PEERS);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 4..5, &mut rng);
+        let blocks = chain.iter().cloned().collect::<Vec<_>>();
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let now = LocalTime::now();
+
+        let mut blockmgr = BlockManager::new(rng);
+        let batch = (1..4)
+            .map(|h| (h as Height, blocks[h].block_hash()))
+            .collect::<Vec<_>>();
+
+        let receive = blockmgr.batch(&batch, now);
+        assert_eq!(blockmgr.missing(), batch);
+
+        // Blocks in flight aren't missing.
+        blockmgr.select(&batch[1].1, vec![alice], now);
+        assert_eq!(blockmgr.in_flight(&batch[1].1, now), Some(alice));
+        assert_eq!(blockmgr.missing(), vec![batch[0], batch[2]]);
+
+        // Blocks are delivered in height order.
+        blockmgr.received_block(&blocks[3], now);
+        assert!(receive.try_recv().is_err());
+        blockmgr.received_block(&blocks[1], now);
+        assert_eq!(
+            receive.try_iter().map(|(_, h)| h).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        // Requests that time out are missing again.
+        blockmgr.received_tick(now + REQUEST_TIMEOUT);
+        assert_eq!(blockmgr.in_flight(&batch[1].1, now + REQUEST_TIMEOUT), None);
+        assert_eq!(blockmgr.missing(), vec![batch[1]]);
+
+        // The channel is closed once all blocks are delivered.
+        blockmgr.received_block(&blocks[2], now);
+        assert_eq!(
+            receive.try_iter().map(|(_, h)| h).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(receive.recv().is_err());
+
+        // ... or when the batch is abandoned.
+        let receive = blockmgr.batch(&batch, now);
+        blockmgr.received_tick(now + BATCH_TIMEOUT);
+        assert!(receive.recv().is_err());
+        assert!(blockmgr.missing().is_empty());
+    }
+
+    #[test]
+    fn test_batch_window() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let count = MAX_BATCH_IN_FLIGHT * 3;
+        let chain = gen::blockchain(genesis, count..count + 1, &mut rng);
+        let blocks = chain.iter().cloned().collect::<Vec<_>>();
+        let now = LocalTime::now();
+
+        let mut blockmgr = BlockManager::new(rng);
+        let batch = (1..=count)
+            .map(|h| (h as Height, blocks[h].block_hash()))
+            .collect::<Vec<_>>();
+        let receive = blockmgr.batch(&batch, now);
+
+        // Only the lowest blocks are requested at first.
+        assert_eq!(blockmgr.missing(), batch[..MAX_BATCH_IN_FLIGHT]);
+
+        // Delivering the lowest block lets the next one be requested.
+        blockmgr.received_block(&blocks[1], now);
+        assert_eq!(receive.len(), 1);
+        assert_eq!(blockmgr.missing(), batch[1..MAX_BATCH_IN_FLIGHT + 1]);
+
+        // Blocks the receiver isn't ready for are held back, and nothing more is requested
+        // until they are delivered.
+        for block in &blocks[2..=MAX_BATCH_IN_FLIGHT * 2] {
+            blockmgr.received_block(block, now);
+        }
+        assert_eq!(receive.len(), MAX_BATCH_IN_FLIGHT);
+        assert!(blockmgr.missing().is_empty());
+
+        let received = receive.try_iter().map(|(_, h)| h).collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            (1..=MAX_BATCH_IN_FLIGHT as Height).collect::<Vec<_>>()
+        );
+
+        // Once the receiver caught up, the held back blocks are delivered, and the next
+        // blocks are requested.
+        blockmgr.received_tick(now);
+        assert_eq!(receive.len(), MAX_BATCH_IN_FLIGHT);
+        assert_eq!(
+            blockmgr.missing(),
+            batch[MAX_BATCH_IN_FLIGHT * 2..MAX_BATCH_IN_FLIGHT * 3]
+        );
+    }
+
+    #[test]
+    fn test_notfound() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut blockmgr = BlockManager::new(rng);
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let block = BlockHash::hash(b"block");
+        let now = LocalTime::now();
+
+        let peer = blockmgr.select(&block, vec![alice, bob], now).unwrap();
+        let other = if peer == alice { bob } else { alice };
+
+        // Only replies from the peer the block was requested from count.
+        assert!(!blockmgr.received_notfound(other, &block, now));
+        assert!(blockmgr.received_notfound(peer, &block, now));
+        assert_eq!(blockmgr.in_flight(&block, now), None);
+
+        // The block is requested from another peer.
+        assert_eq!(blockmgr.select(&block, vec![alice, bob], now), Some(other));
+        assert!(blockmgr.received_notfound(other, &block, now));
+        assert_eq!(blockmgr.select(&block, vec![alice, bob], now), None);
+
+        // Eventually, peers are asked again.
+        blockmgr.received_tick(now + BATCH_TIMEOUT);
+        assert!(blockmgr.select(&block, vec![alice, bob], now).is_some());
+    }
+}
@@ -4,27 +4,35 @@
 //! with specific capabilities, eg. peer disconnection, message sending etc. to
 //! communicate with the main protocol and network.
 use log::*;
+use std::fmt;
 use std::net;
+use std::sync::Arc;
 
 use crossbeam_channel as chan;
 
+use bitcoin::consensus::Encodable;
 use bitcoin::network::address::Address;
 use bitcoin::network::message::NetworkMessage;
-use bitcoin::network::message_blockdata::GetHeadersMessage;
-use bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
 use bitcoin::network::message_network::VersionMessage;
+use bitcoin::Transaction;
 
 use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::tree::ImportResult;
 use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height};
 
-use crate::protocol::{DisconnectReason, Event, Out, PeerId};
+use crate::protocol::{Direction, DisconnectReason, Event, Out, PeerId, WireHook};
 
+use super::feemgr::FeeRate;
 use super::network::Network;
-use super::{addrmgr, connmgr, message, peermgr, pingmgr, spvmgr, syncmgr, Link, Locators};
+use super::{addrmgr, connmgr, invmgr, message, peermgr, pingmgr, spvmgr, syncmgr};
+use super::{Link, Locators};
 
 /// Used to construct a protocol output.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Channel {
     /// Protocol version.
     version: u32,
@@ -34,6 +42,18 @@ pub struct Channel {
     builder: message::Builder,
     /// Log target.
     target: &'static str,
+    /// Called with every message sent.
+    on_wire: WireHook,
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("version", &self.version)
+            .field("builder", &self.builder)
+            .field("target", &self.target)
+            .finish()
+    }
 }
 
 impl Channel {
@@ -49,9 +69,16 @@ impl Channel {
             outbound,
             builder: message::Builder::new(network),
             target,
+            on_wire: Arc::new(|_, _, _, _| {}),
         }
     }
 
+    /// Set the hook called with every message sent. See [`super::Hooks::on_wire`].
+    pub fn on_wire(mut self, hook: WireHook) -> Self {
+        self.on_wire = hook;
+        self
+    }
+
     /// Push an output to the channel.
     pub fn push(&self, output: Out) {
         self.outbound.send(output).unwrap();
@@ -61,7 +88,13 @@ impl Channel {
     pub fn message(&self, addr: PeerId, message: NetworkMessage) -> &Self {
         debug!(target: self.target, "{}: Sending {:?}", addr, message.cmd());
 
-        self.push(self.builder.message(addr, message));
+        let message = self.builder.raw(message);
+        let size = message
+            .consensus_encode(std::io::sink())
+            .unwrap_or_default();
+
+        (self.on_wire)(addr, Direction::Sent, &message, size);
+        self.push(Out::Message(addr, message));
         self
     }
 
@@ -118,20 +151,41 @@ impl addrmgr::SyncAddresses for Channel {
 }
 
 impl connmgr::Connect for Channel {
-    fn connect(&self, addr: net::SocketAddr, timeout: LocalDuration) {
-        info!(target: self.target, "[conn] {}: Connecting..", addr);
-        self.push(Out::Connect(addr, timeout));
+    fn connect(
+        &self,
+        addr: net::SocketAddr,
+        timeout: LocalDuration,
+        proxy: Option<connmgr::Proxy>,
+    ) {
+        match &proxy {
+            Some(proxy) => info!(
+                target: self.target,
+                "[conn] {}: Connecting via {}..", addr, proxy.addr
+            ),
+            None => info!(target: self.target, "[conn] {}: Connecting..", addr),
+        }
+        self.push(Out::Connect(addr, timeout, proxy));
     }
 }
 
 impl connmgr::Connect for () {
-    fn connect(&self, _addr: net::SocketAddr, _timeout: LocalDuration) {}
+    fn connect(
+        &self,
+        _addr: net::SocketAddr,
+        _timeout: LocalDuration,
+        _proxy: Option<connmgr::Proxy>,
+    ) {
+    }
 }
 
 impl connmgr::Events for Channel {
     fn event(&self, event: connmgr::Event) {
+        record!("{}", event);
+
         match event {
-            connmgr::Event::Connected(_, Link::Outbound) => {
+            connmgr::Event::Connected(_, Link::Outbound)
+            | connmgr::Event::Banned(_, _)
+            | connmgr::Event::Unbanned(_) => {
                 info!(target: self.target, "[conn] {}", &event)
             }
             _ => {
@@ -180,6 +234,24 @@ impl pingmgr::Ping for Channel {
     }
 }
 
+impl invmgr::Inventories for Channel {
+    fn inv(&self, addr: PeerId, inventory: Vec<Inventory>) {
+        self.message(addr, NetworkMessage::Inv(inventory));
+    }
+
+    fn tx(&self, addr: PeerId, tx: Transaction) {
+        self.message(addr, NetworkMessage::Tx(tx));
+    }
+
+    fn not_found(&self, addr: PeerId, inventory: Vec<Inventory>) {
+        self.message(addr, NetworkMessage::NotFound(inventory));
+    }
+
+    fn get_data(&self, addr: PeerId, inventory: Vec<Inventory>) {
+        self.message(addr, NetworkMessage::GetData(inventory));
+    }
+}
+
 impl syncmgr::SyncHeaders for Channel {
     fn get_headers(&self, addr: PeerId, (locator_hashes, stop_hash): Locators) {
         let msg = NetworkMessage::GetHeaders(GetHeadersMessage {
@@ -199,17 +271,28 @@ impl syncmgr::SyncHeaders for Channel {
         self.push(msg);
     }
 
+    fn send_inv(&self, addr: PeerId, blocks: Vec<BlockHash>) {
+        self.message(
+            addr,
+            NetworkMessage::Inv(blocks.into_iter().map(Inventory::Block).collect()),
+        );
+    }
+
     fn negotiate(&self, addr: PeerId) {
         self.message(addr, NetworkMessage::SendHeaders);
     }
 
     fn event(&self, event: syncmgr::Event) {
         debug!(target: self.target, "[sync] {}", &event);
+        record!("{}", event);
 
         match &event {
             syncmgr::Event::HeadersImported(ImportResult::TipChanged(_, tip, height, _)) => {
                 info!(target: self.target, "Block height = {}, tip = {}", height, tip);
             }
+            syncmgr::Event::BlockDisconnected { .. } | syncmgr::Event::ChainReorged { .. } => {
+                info!(target: self.target, "[sync] {}", &event);
+            }
             _ => {}
         }
         self.event(Event::SyncManager(event));
@@ -226,6 +309,11 @@ impl peermgr::Handshake for Channel {
         self.message(addr, NetworkMessage::Verack);
         self
     }
+
+    fn feefilter(&self, addr: PeerId, rate: FeeRate) -> &Self {
+        self.message(addr, NetworkMessage::FeeFilter(rate as i64));
+        self
+    }
 }
 
 #[allow(unused_variables)]
@@ -251,6 +339,20 @@ impl spvmgr::SyncFilters for Channel {
         self.message(addr, NetworkMessage::CFHeaders(headers));
     }
 
+    fn get_cfcheckpt(&self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration) {
+        self.message(
+            addr,
+            NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                filter_type: 0x0,
+                stop_hash,
+            }),
+        );
+    }
+
+    fn send_cfcheckpt(&self, addr: PeerId, checkpoints: CFCheckpt) {
+        self.message(addr, NetworkMessage::CFCheckpt(checkpoints));
+    }
+
     fn get_cfilters(
         &self,
         addr: PeerId,
@@ -271,16 +373,33 @@ impl spvmgr::SyncFilters for Channel {
     fn send_cfilter(&self, addr: PeerId, cfilter: CFilter) {
         self.message(addr, NetworkMessage::CFilter(cfilter));
     }
+
+    fn get_block(&self, addr: PeerId, hash: BlockHash) {
+        self.message(addr, NetworkMessage::GetData(vec![Inventory::Block(hash)]));
+    }
 }
 
 impl spvmgr::Events for Channel {
     fn event(&self, event: spvmgr::Event) {
         debug!(target: self.target, "[spv] {}", &event);
+        record!("{}", event);
 
         match event {
             spvmgr::Event::FilterHeadersImported { height, .. } => {
                 info!(target: self.target, "Filter height = {}", height);
             }
+            spvmgr::Event::RescanStarted { .. }
+            | spvmgr::Event::RescanCompleted { .. }
+            | spvmgr::Event::RescanAborted { .. }
+            | spvmgr::Event::RescanProgress { .. }
+            | spvmgr::Event::GapLimitExtended { .. }
+            | spvmgr::Event::RollbackDetected(_)
+            | spvmgr::Event::OutpointSpent { .. }
+            | spvmgr::Event::UtxoAdded { .. }
+            | spvmgr::Event::UtxoSpent { .. }
+            | spvmgr::Event::FilterProcessed { matched: true, .. } => {
+                info!(target: self.target, "[spv] {}", &event);
+            }
             _ => {}
         }
 
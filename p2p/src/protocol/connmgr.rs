@@ -1,4 +1,15 @@
 //! Peer connection manager.
+//!
+//! Outbound connections are maintained at a target number, and inbound connections are
+//! accepted up to a limit. When inbound slots are full, a new inbound peer takes the place
+//! of an existing one if one can be evicted, which keeps the node reachable by honest peers
+//! even when an attacker fills up its slots. Slow peers are evicted first, since they are
+//! the least useful to us.
+//!
+//! Short-lived connections can also be opened to broadcast a transaction to peers outside of
+//! our outbound peer set, so that the transaction can't be linked to our long-lived peers.
+//! These connections don't count towards the outbound target, and are closed once the
+//! transaction was served, or after [`BROADCAST_TIMEOUT`].
 
 use std::marker::PhantomData;
 use std::net;
@@ -8,13 +19,14 @@ use bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::p2p::peer::{AddressSource, Source};
-use nakamoto_common::p2p::Domain;
+use nakamoto_common::p2p::{Domain, Subnet};
 
 use super::channel::{Disconnect, SetTimeout};
-use crate::protocol::{DisconnectReason, Link, PeerId, Timeout};
+use super::syncmgr::fork::Netgroup;
+use crate::protocol::{DisconnectReason, Link, Misbehavior, PeerId, Timeout};
 
-/// Time to wait for a new connection.
-/// TODO: Should be in config.
+/// Default time to wait for a new connection. This is passed on to the reactor when
+/// dialing, so that unreachable addresses fail fast, without waiting for the next idle tick.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 /// Time to wait until idle.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -22,11 +34,64 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Misbehavior score at which a peer is banned.
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a misbehaving peer stays banned.
+pub const BAN_DURATION: LocalDuration = LocalDuration::from_mins(60 * 24);
+/// Number of outbound connections kept as anchors across restarts.
+pub const MAX_ANCHORS: usize = 2;
+/// Delay before the first reconnection attempt to a peer, in connect-only mode.
+/// Doubles on every failed attempt.
+pub const MIN_RECONNECT_DELAY: LocalDuration = LocalDuration::from_secs(1);
+/// Maximum delay between reconnection attempts to a peer, in connect-only mode.
+pub const MAX_RECONNECT_DELAY: LocalDuration = LocalDuration::from_mins(10);
+/// Time without any outbound peer serving compact filters after which
+/// [`Event::NoFilterPeers`] is emitted.
+pub const FILTER_PEER_TIMEOUT: LocalDuration = LocalDuration::from_mins(10);
+/// Number of short-lived connections opened to broadcast a transaction.
+pub const BROADCAST_PEERS: usize = 2;
+/// Time after which a short-lived broadcast connection is closed.
+pub const BROADCAST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
+/// Time a broadcast connection is kept open after the transaction was served, so that it
+/// has time to be delivered.
+pub const BROADCAST_LINGER: LocalDuration = LocalDuration::from_secs(5);
 
 /// Ability to connect to peers.
 pub trait Connect {
-    /// Connect to peer.
-    fn connect(&self, addr: net::SocketAddr, timeout: Timeout);
+    /// Connect to peer, optionally through a proxy.
+    fn connect(&self, addr: net::SocketAddr, timeout: Timeout, proxy: Option<Proxy>);
+}
+
+/// A SOCKS5 proxy to connect to a peer through, eg. a Tor client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    /// Address of the proxy.
+    pub addr: net::SocketAddr,
+    /// Credentials to authenticate with, if any.
+    pub credentials: Option<Credentials>,
+}
+
+/// SOCKS5 username and password credentials (RFC 1929).
+///
+/// Tor doesn't check these, but by default uses a separate circuit for each set of
+/// credentials. Connecting to each peer with its own credentials thus keeps a single exit
+/// from seeing, and correlating, all of our peer connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// User name.
+    pub username: String,
+    /// Password.
+    pub password: String,
+}
+
+impl Credentials {
+    /// Generate random credentials, unique to a connection.
+    pub fn random(rng: &fastrand::Rng) -> Self {
+        Self {
+            username: format!("{:016x}", rng.u64(..)),
+            password: format!("{:016x}", rng.u64(..)),
+        }
+    }
 }
 
 /// Ability to emit events.
@@ -46,6 +111,17 @@ pub enum Event {
     Connected(PeerId, Link),
     /// A peer has been disconnected.
     Disconnected(PeerId),
+    /// A peer misbehaved, bringing its misbehavior score to the given value.
+    Misbehaving(PeerId, Misbehavior, u32),
+    /// A peer address was banned, either due to misbehavior, or on request.
+    Banned(net::IpAddr, Ban),
+    /// A peer address was unbanned on request.
+    Unbanned(net::IpAddr),
+    /// We're shutting down, and these peers should be connected to first on the next start.
+    Anchors(Vec<PeerId>),
+    /// No outbound peer serving compact filters was found for the given duration. Without
+    /// such peers, filters can't be fetched, and matching transactions go unnoticed.
+    NoFilterPeers(LocalDuration),
 }
 
 impl std::fmt::Display for Event {
@@ -56,10 +132,38 @@ impl std::fmt::Display for Event {
             }
             Event::Connected(addr, link) => write!(fmt, "{}: Peer connected ({:?})", &addr, link),
             Event::Disconnected(addr) => write!(fmt, "Disconnected from {}", &addr),
+            Event::Misbehaving(addr, misbehavior, score) => write!(
+                fmt,
+                "{}: Peer {} (misbehavior score = {})",
+                &addr, misbehavior, score
+            ),
+            Event::Banned(addr, ban) => write!(
+                fmt,
+                "{}: Peer banned until {} ({})",
+                &addr,
+                ban.until.block_time(),
+                ban.reason
+            ),
+            Event::Unbanned(addr) => write!(fmt, "{}: Peer unbanned", &addr),
+            Event::Anchors(addrs) => write!(fmt, "Anchoring {} peer(s)", addrs.len()),
+            Event::NoFilterPeers(duration) => write!(
+                fmt,
+                "No peer serving compact filters found for {} minute(s)",
+                duration.as_mins()
+            ),
         }
     }
 }
 
+/// A ban on a peer address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// Time until which the address is banned.
+    pub until: LocalTime,
+    /// Why the address was banned.
+    pub reason: String,
+}
+
 /// Connection manager configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -69,6 +173,8 @@ pub struct Config {
     pub max_inbound_peers: usize,
     /// Peer addresses that should always be retried.
     pub retry: Vec<net::SocketAddr>,
+    /// Anchor peers from the previous run. These are connected to before any other peer.
+    pub anchors: Vec<net::SocketAddr>,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
     /// Peer services required.
@@ -76,6 +182,29 @@ pub struct Config {
     /// Peer services preferred. We try to maintain as many
     /// connections to peers with these services.
     pub preferred_services: ServiceFlags,
+    /// Services of the peers compact filters are downloaded from. When we're short of
+    /// such peers, outbound peers without them are replaced, one at a time.
+    pub filter_services: ServiceFlags,
+    /// Time without any outbound peer with the filter services after which
+    /// [`Event::NoFilterPeers`] is emitted.
+    pub filter_peer_timeout: LocalDuration,
+    /// Time to wait for a new connection before giving up on it.
+    pub connect_timeout: LocalDuration,
+    /// Only ever connect to the peers in `retry`, never to addresses from the address
+    /// manager. Peers are reconnected to with exponential backoff when they disconnect.
+    pub connect_only: bool,
+    /// Peer addresses that are always allowed to connect, and are never banned.
+    pub whitelist: Vec<net::IpAddr>,
+    /// Addresses and subnets that are never connected to, and whose inbound connections
+    /// are rejected. Whitelisted addresses take precedence.
+    pub blacklist: Vec<Subnet>,
+    /// Banned peer addresses, eg. from the previous run. Expired bans are ignored.
+    pub banned: Vec<(net::IpAddr, Ban)>,
+    /// SOCKS5 proxy to connect to peers through, eg. a Tor client.
+    pub proxy: Option<net::SocketAddr>,
+    /// Whether to authenticate with the proxy using different credentials for each peer
+    /// connection. See [`Credentials`].
+    pub isolate_streams: bool,
 }
 
 impl Default for Config {
@@ -84,9 +213,19 @@ impl Default for Config {
             target_outbound_peers: TARGET_OUTBOUND_PEERS,
             max_inbound_peers: MAX_INBOUND_PEERS,
             retry: vec![],
+            anchors: vec![],
             domains: Domain::all(),
             required_services: ServiceFlags::NONE,
             preferred_services: ServiceFlags::NONE,
+            filter_services: ServiceFlags::COMPACT_FILTERS,
+            filter_peer_timeout: FILTER_PEER_TIMEOUT,
+            connect_timeout: CONNECTION_TIMEOUT,
+            connect_only: false,
+            whitelist: vec![],
+            blacklist: vec![],
+            banned: vec![],
+            proxy: None,
+            isolate_streams: true,
         }
     }
 }
@@ -109,6 +248,8 @@ enum Peer {
         services: ServiceFlags,
         /// Time connected.
         time: LocalTime,
+        /// Average round-trip latency, if known.
+        latency: Option<LocalDuration>,
     },
     Disconnecting,
     Disconnected, // TODO: Keep track of when the peer was disconnected, so we can prune.
@@ -121,8 +262,24 @@ pub struct ConnectionManager<U, A> {
     pub config: Config,
     /// Set of outbound peers being connected to.
     peers: HashMap<PeerId, Peer>,
+    /// Misbehavior scores of peers, by IP address.
+    scores: HashMap<net::IpAddr, u32>,
+    /// Banned peer IP addresses.
+    banned: HashMap<net::IpAddr, Ban>,
+    /// Failed connection attempts to the peers we were asked to connect to, and the time
+    /// until which we wait before reconnecting. Only used in connect-only mode.
+    reconnects: HashMap<PeerId, (u32, LocalTime)>,
     /// Last time we were idle.
     last_idle: Option<LocalTime>,
+    /// Whether we're short of peers serving compact filters. When set, addresses with a
+    /// good filter-serving history are dialed first.
+    filter_shortage: bool,
+    /// Since when we've had no outbound peer with the filter services, and whether this
+    /// was reported with [`Event::NoFilterPeers`].
+    no_filter_peers: Option<(LocalTime, bool)>,
+    /// Short-lived connections opened to broadcast a transaction, and the time at which
+    /// they are closed.
+    broadcasts: HashMap<PeerId, LocalTime>,
     /// Channel to the network.
     upstream: U,
     /// Type witness for address source.
@@ -134,9 +291,24 @@ pub struct ConnectionManager<U, A> {
 impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> ConnectionManager<U, A> {
     /// Create a new connection manager.
     pub fn new(upstream: U, config: Config, rng: fastrand::Rng) -> Self {
+        let mut banned = HashMap::with_hasher(rng.clone().into());
+        banned.extend(
+            config
+                .banned
+                .iter()
+                .filter(|(addr, _)| !config.whitelist.contains(addr))
+                .cloned(),
+        );
+
         Self {
             peers: HashMap::with_hasher(rng.clone().into()),
+            scores: HashMap::with_hasher(rng.clone().into()),
+            banned,
+            reconnects: HashMap::with_hasher(rng.clone().into()),
+            broadcasts: HashMap::with_hasher(rng.clone().into()),
             last_idle: None,
+            filter_shortage: false,
+            no_filter_peers: None,
             config,
             upstream,
             addresses: PhantomData,
@@ -146,15 +318,28 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
 
     /// Initialize the connection manager. Must be called once.
     pub fn initialize(&mut self, time: LocalTime, addrs: &mut A) {
-        let retry = self
+        self.no_filter_peers = Some((time, false));
+        self.upstream.set_timeout(self.config.filter_peer_timeout);
+
+        if self.config.connect_only {
+            self.upstream.set_timeout(IDLE_TIMEOUT);
+            self.reconnect(time);
+
+            return;
+        }
+        let initial = self
             .config
-            .retry
+            .anchors
             .iter()
-            .take(self.config.target_outbound_peers)
+            .take(MAX_ANCHORS)
+            .chain(self.config.retry.iter())
             .cloned()
             .collect::<Vec<_>>();
 
-        for addr in retry {
+        for addr in initial {
+            if self.connecting_peers().count() >= self.config.target_outbound_peers {
+                break;
+            }
             self.connect(&addr, time);
         }
         self.upstream.set_timeout(IDLE_TIMEOUT);
@@ -190,21 +375,163 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         )
     }
 
+    /// Check whether a peer is connected, or being connected to, only to broadcast a
+    /// transaction.
+    pub fn is_broadcast(&self, addr: &PeerId) -> bool {
+        self.broadcasts.contains_key(addr)
+    }
+
+    /// Check whether a peer is banned.
+    pub fn is_banned(&self, addr: &net::IpAddr, now: LocalTime) -> bool {
+        matches!(self.banned.get(addr), Some(ban) if now < ban.until)
+    }
+
+    /// Get the active bans.
+    pub fn banned(&self, now: LocalTime) -> impl Iterator<Item = (&net::IpAddr, &Ban)> + '_ {
+        self.banned.iter().filter(move |(_, ban)| now < ban.until)
+    }
+
+    /// Ban a peer address until the given time, disconnecting any peer connected from it.
+    /// An existing ban is replaced. Whitelisted addresses can't be banned, in which case
+    /// `false` is returned.
+    pub fn ban(&mut self, addr: net::IpAddr, until: LocalTime, reason: String) -> bool {
+        if self.is_whitelisted(&addr) {
+            return false;
+        }
+        let ban = Ban { until, reason };
+
+        self.scores.remove(&addr);
+        self.banned.insert(addr, ban.clone());
+        self.upstream.event(Event::Banned(addr, ban));
+
+        let peers = self
+            .peers
+            .iter()
+            .filter(|(peer, p)| peer.ip() == addr && matches!(p, Peer::Connected { .. }))
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+
+        for peer in peers {
+            self._disconnect(peer, DisconnectReason::PeerBanned);
+        }
+        true
+    }
+
+    /// Lift the ban on a peer address, and reset its misbehavior score. Returns `false`
+    /// if the address wasn't banned.
+    pub fn unban(&mut self, addr: net::IpAddr) -> bool {
+        self.scores.remove(&addr);
+
+        if self.banned.remove(&addr).is_none() {
+            return false;
+        }
+        self.upstream.event(Event::Unbanned(addr));
+
+        true
+    }
+
+    /// Check whether a peer is whitelisted.
+    pub fn is_whitelisted(&self, addr: &net::IpAddr) -> bool {
+        self.config.whitelist.contains(addr)
+    }
+
+    /// Check whether a peer is blacklisted, and isn't whitelisted.
+    pub fn is_blacklisted(&self, addr: &net::IpAddr) -> bool {
+        !self.is_whitelisted(addr) && self.config.blacklist.iter().any(|s| s.contains(addr))
+    }
+
+    /// Whitelist a peer address. Returns `false` if it was already whitelisted.
+    pub fn whitelist(&mut self, addr: net::IpAddr) -> bool {
+        if self.is_whitelisted(&addr) {
+            return false;
+        }
+        self.config.whitelist.push(addr);
+        self.unban(addr);
+
+        true
+    }
+
     /// Connect to a peer.
     pub fn connect(&mut self, addr: &PeerId, time: LocalTime) -> bool {
+        span!("connect", peer = %addr);
+
         if !self.is_disconnected(addr) {
             return false;
         }
+        // Don't dial blacklisted peers.
+        if self.is_blacklisted(&addr.ip()) {
+            return false;
+        }
+        // Don't re-dial peers we've banned.
+        if self.is_banned(&addr.ip(), time) {
+            return false;
+        }
         // Don't allow connections to unsupported domains.
         if !self.config.domains.contains(&Domain::for_address(addr)) {
             return false;
         }
+        let proxy = self.config.proxy.map(|proxy| Proxy {
+            addr: proxy,
+            credentials: if self.config.isolate_streams {
+                Some(Credentials::random(&self.rng))
+            } else {
+                None
+            },
+        });
+
+        record!(proxied = proxy.is_some(), "connecting");
+
         self.peers.insert(*addr, Peer::Connecting { time });
-        self.upstream.connect(*addr, CONNECTION_TIMEOUT);
+        self.upstream
+            .connect(*addr, self.config.connect_timeout, proxy);
 
         true
     }
 
+    /// Open short-lived connections to up to `count` peers we aren't connected to, to
+    /// broadcast a transaction. Returns the peers being connected to. Nothing is done in
+    /// connect-only mode, since we aren't allowed to connect to other peers.
+    pub fn connect_broadcast(
+        &mut self,
+        count: usize,
+        addrs: &mut A,
+        local_time: LocalTime,
+    ) -> Vec<PeerId> {
+        let mut connecting = Vec::new();
+
+        if self.config.connect_only {
+            return connecting;
+        }
+        for (addr, source) in addrs.iter(self.config.required_services) {
+            if connecting.len() >= count {
+                break;
+            }
+            if let Ok(sockaddr) = addr.socket_addr() {
+                if self.connect(&sockaddr, local_time) {
+                    self.broadcasts
+                        .insert(sockaddr, local_time + BROADCAST_TIMEOUT);
+                    self.upstream.event(Event::Connecting(sockaddr, source));
+
+                    connecting.push(sockaddr);
+                }
+            }
+        }
+        if !connecting.is_empty() {
+            self.upstream.set_timeout(BROADCAST_TIMEOUT);
+        }
+        connecting
+    }
+
+    /// Called when the transaction was served to a broadcast peer. The connection is closed
+    /// shortly after.
+    pub fn broadcast_done(&mut self, addr: &PeerId, local_time: LocalTime) {
+        if let Some(until) = self.broadcasts.get_mut(addr) {
+            *until = (*until).min(local_time + BROADCAST_LINGER);
+
+            self.upstream.set_timeout(BROADCAST_LINGER);
+        }
+    }
+
     /// Disconnect from a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         if self.is_connected(&addr) {
@@ -212,6 +539,50 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         }
     }
 
+    /// Record peer misbehavior. Once a peer's misbehavior score reaches [`BAN_THRESHOLD`],
+    /// it is disconnected and banned for [`BAN_DURATION`]. Returns whether the peer was banned.
+    ///
+    /// Scores are kept by IP address, and survive reconnections. A peer's score is reset
+    /// once it is banned. Whitelisted peers are never banned.
+    pub fn misbehaving(&mut self, addr: PeerId, misbehavior: Misbehavior, now: LocalTime) -> bool {
+        span!("peer", peer = %addr);
+
+        if self.is_whitelisted(&addr.ip()) {
+            self.upstream
+                .event(Event::Misbehaving(addr, misbehavior, 0));
+
+            return false;
+        }
+        let score = self.scores.entry(addr.ip()).or_default();
+        *score += misbehavior.score();
+
+        self.upstream
+            .event(Event::Misbehaving(addr, misbehavior, *score));
+
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+        let ban = Ban {
+            until: now + BAN_DURATION,
+            reason: misbehavior.to_string(),
+        };
+
+        self.scores.remove(&addr.ip());
+        self.banned.insert(addr.ip(), ban.clone());
+        self.upstream.event(Event::Banned(addr.ip(), ban));
+        self.disconnect(
+            addr,
+            DisconnectReason::PeerMisbehaving(misbehavior.reason()),
+        );
+
+        true
+    }
+
+    /// Set whether we're short of peers serving compact filters.
+    pub fn set_filter_shortage(&mut self, shortage: bool) {
+        self.filter_shortage = shortage;
+    }
+
     /// Called when a peer is being connected to.
     pub fn peer_attempted(&mut self, addr: &net::SocketAddr) {
         // Since all "attempts" are made from this module, we expect that when a peer is
@@ -231,6 +602,7 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         link: Link,
         time: LocalTime,
     ) {
+        span!("peer", peer = %address);
         debug_assert!(!self.is_connected(&address));
 
         Events::event(&self.upstream, Event::Connected(address, link));
@@ -240,29 +612,60 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         // inbound. To prevent this, we could look at IPs when receiving inbound connections,
         // to check whether we are already connected to the peer.
 
-        match link {
-            Link::Inbound if self.inbound_peers().count() >= self.config.max_inbound_peers => {
-                // TODO: Test this branch.
-                // Don't allow inbound connections beyond the configured limit.
-                self._disconnect(address, DisconnectReason::ConnectionLimit);
+        let whitelisted = self.is_whitelisted(&address.ip());
+        let accept = match link {
+            Link::Inbound if self.is_blacklisted(&address.ip()) => {
+                self._disconnect(address, DisconnectReason::PeerBlacklisted);
+                false
             }
-            _ => {
-                self.peers.insert(
-                    address,
-                    Peer::Connected {
-                        address,
-                        local_address,
-                        services: ServiceFlags::NONE,
-                        link,
-                        time,
-                    },
-                );
+            Link::Inbound if self.is_banned(&address.ip(), time) => {
+                self._disconnect(address, DisconnectReason::PeerBanned);
+                false
+            }
+            // Whitelisted peers are allowed in beyond the inbound connection limit.
+            Link::Inbound
+                if !whitelisted
+                    && self.inbound_peers().count() >= self.config.max_inbound_peers =>
+            {
+                // Don't allow inbound connections beyond the configured limit, unless we
+                // can make room for them.
+                if let Some(evicted) = self.eviction_candidate() {
+                    self._disconnect(evicted, DisconnectReason::PeerEvicted);
+                    true
+                } else {
+                    self._disconnect(address, DisconnectReason::ConnectionLimit);
+                    false
+                }
             }
+            _ => true,
+        };
+
+        if accept {
+            self.peers.insert(
+                address,
+                Peer::Connected {
+                    address,
+                    local_address,
+                    services: ServiceFlags::NONE,
+                    link,
+                    time,
+                    latency: None,
+                },
+            );
+        }
+    }
+
+    /// Call when the average round-trip latency of a peer was updated.
+    pub fn peer_latency(&mut self, address: &net::SocketAddr, avg: LocalDuration) {
+        if let Some(Peer::Connected { latency, .. }) = self.peers.get_mut(address) {
+            *latency = Some(avg);
         }
     }
 
     /// Call when a peer negotiated.
     pub fn peer_negotiated(&mut self, address: net::SocketAddr, flags: ServiceFlags) {
+        self.reconnects.remove(&address);
+
         if let Some(Peer::Connected {
             ref mut services, ..
         }) = self.peers.get_mut(&address)
@@ -279,9 +682,11 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
     pub fn peer_disconnected(
         &mut self,
         addr: &net::SocketAddr,
+        reason: DisconnectReason,
         addrs: &mut A,
         local_time: LocalTime,
     ) {
+        span!("peer", peer = %addr, reason = %reason);
         debug_assert!(self.peers.contains_key(addr));
         debug_assert!(!self.is_disconnected(&addr));
 
@@ -290,6 +695,30 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         // If an outbound peer disconnected, we should make sure to maintain
         // our target outbound connection count.
         let previous = self.peers.insert(*addr, Peer::Disconnected);
+
+        // Broadcast connections don't count towards our outbound peers.
+        if self.broadcasts.remove(addr).is_some() {
+            return;
+        }
+
+        // A connected peer that timed out was stalling one of the sub-protocols. Note that
+        // peers we disconnect ourselves in this module are in the `Disconnecting` state.
+        if let (Some(Peer::Connected { .. }), DisconnectReason::PeerTimeout(_)) =
+            (&previous, &reason)
+        {
+            self.misbehaving(*addr, Misbehavior::Stalling, local_time);
+        }
+
+        if self.config.connect_only && self.config.retry.contains(addr) {
+            let (attempts, retry_at) = self.reconnects.entry(*addr).or_default();
+            let delay = (MIN_RECONNECT_DELAY * (1 << (*attempts).min(16))).min(MAX_RECONNECT_DELAY);
+
+            *attempts += 1;
+            *retry_at = local_time + delay;
+
+            self.upstream.set_timeout(delay);
+        }
+
         match previous {
             Some(Peer::Connected { link, .. }) if link.is_outbound() => {
                 self.maintain_connections(addrs, local_time);
@@ -303,10 +732,29 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
 
     /// Call when we recevied a tick.
     pub fn received_tick(&mut self, now: LocalTime, addrs: &mut A) {
+        // Lift expired bans.
+        self.banned.retain(|_, ban| now < ban.until);
+
         // Disconnect all peers that have been idle for too long.
         for addr in self.idle_peers(now).collect::<Vec<_>>() {
             self._disconnect(addr, DisconnectReason::PeerTimeout("connection"));
         }
+        // Close the broadcast connections we're done with.
+        let broadcasts = self
+            .broadcasts
+            .iter()
+            .filter(|(addr, until)| now >= **until && self.is_connected(addr))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in broadcasts {
+            self._disconnect(addr, DisconnectReason::Other("broadcast done"));
+        }
+
+        if self.config.connect_only {
+            self.reconnect(now);
+        }
+        self.check_filter_peers(now);
 
         if now - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
             self.maintain_connections(addrs, now);
@@ -315,11 +763,45 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         }
     }
 
-    /// Returns outbound peer addresses.
+    /// Returns the peers to anchor to across restarts: our oldest outbound connections. Since
+    /// we never ask peers to relay transactions to us, these are all block-relay connections.
+    pub fn anchors(&self) -> Vec<PeerId> {
+        let mut outbound = self
+            .peers
+            .iter()
+            .filter_map(|(addr, p)| match p {
+                Peer::Connected { link, time, .. }
+                    if link.is_outbound() && !self.is_broadcast(addr) =>
+                {
+                    Some((*time, *addr))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        outbound.sort_unstable();
+
+        outbound
+            .into_iter()
+            .take(MAX_ANCHORS)
+            .map(|(_, addr)| addr)
+            .collect()
+    }
+
+    /// Called when we're shutting down.
+    pub fn shutdown(&mut self) {
+        let anchors = self.anchors();
+
+        self.upstream.event(Event::Anchors(anchors));
+    }
+
+    /// Returns outbound peer addresses. Broadcast connections aren't included.
     pub fn outbound_peers(&self) -> impl Iterator<Item = &PeerId> {
         self.peers
             .iter()
-            .filter(|(_, p)| matches!(p, Peer::Connected { link, .. } if link.is_outbound()))
+            .filter(move |(addr, p)| {
+                matches!(p, Peer::Connected { link, .. } if link.is_outbound())
+                    && !self.is_broadcast(addr)
+            })
             .map(|(addr, _)| addr)
     }
 
@@ -331,27 +813,43 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
             .map(|(addr, _)| addr)
     }
 
-    /// Returns connecting peers.
+    /// Returns connecting peers. Broadcast connections aren't included.
     pub fn connecting_peers(&self) -> impl Iterator<Item = &PeerId> {
         self.peers
             .iter()
-            .filter(|(_, p)| matches!(p, Peer::Connecting { .. }))
+            .filter(move |(addr, p)| {
+                matches!(p, Peer::Connecting { .. }) && !self.is_broadcast(addr)
+            })
             .map(|(addr, _)| addr)
     }
 
     /// Attempt to maintain a certain number of outbound peers.
     fn maintain_connections(&mut self, addrs: &mut A, local_time: LocalTime) {
+        if self.config.connect_only {
+            return self.reconnect(local_time);
+        }
+        if self.filter_shortage {
+            self.make_room_for_filter_peer();
+        }
         let current = self.outbound().count() + self.connecting_peers().count();
         let target = self.config.target_outbound_peers;
-        let delta = target - current;
+        let delta = target.saturating_sub(current);
 
         // Keep track of new addresses we're connecting to, and loop until
         // we've connected to enough addresses.
         let mut connecting = HashSet::with_hasher(self.rng.clone().into());
 
         while connecting.len() < delta {
-            if let Some((addr, source)) = addrs
-                .sample(self.config.preferred_services)
+            let filter_servers = if self.filter_shortage {
+                addrs.sample_filter_server(
+                    self.config.required_services | self.config.filter_services,
+                )
+            } else {
+                None
+            };
+
+            if let Some((addr, source)) = filter_servers
+                .or_else(|| addrs.sample(self.config.preferred_services))
                 .or_else(|| addrs.sample(self.config.required_services))
             {
                 if let Ok(sockaddr) = addr.socket_addr() {
@@ -371,24 +869,157 @@ impl<U: Connect + Disconnect + Events + SetTimeout, A: AddressSource> Connection
         }
     }
 
-    /// Get outbound peers.
+    /// Report when we've been without outbound filter peers for too long.
+    fn check_filter_peers(&mut self, now: LocalTime) {
+        let filter_services = self.config.filter_services;
+        let connected = self.outbound().any(
+            |p| matches!(p, Peer::Connected { services, .. } if services.has(filter_services)),
+        );
+
+        match &mut self.no_filter_peers {
+            _ if connected => self.no_filter_peers = None,
+            None => {
+                self.no_filter_peers = Some((now, false));
+                self.upstream.set_timeout(self.config.filter_peer_timeout);
+            }
+            Some((since, reported)) => {
+                let elapsed = now - *since;
+
+                if !*reported && elapsed >= self.config.filter_peer_timeout {
+                    *reported = true;
+                    self.upstream.event(Event::NoFilterPeers(elapsed));
+                }
+            }
+        }
+    }
+
+    /// If all our outbound slots are taken, disconnect the most recent outbound peer that
+    /// doesn't have the filter services, so that a filter peer can take its place. Nothing
+    /// is done while connections are being established, so that peers are replaced one at
+    /// a time.
+    fn make_room_for_filter_peer(&mut self) {
+        if self.outbound().count() < self.config.target_outbound_peers
+            || self.connecting_peers().next().is_some()
+        {
+            return;
+        }
+        let candidate = self
+            .peers
+            .iter()
+            .filter_map(|(addr, p)| match p {
+                Peer::Connected {
+                    link,
+                    services,
+                    time,
+                    ..
+                } if link.is_outbound()
+                    && !self.is_broadcast(addr)
+                    // Services are unknown until the peer is negotiated.
+                    && *services != ServiceFlags::NONE
+                    && !services.has(self.config.filter_services)
+                    && !self.config.retry.contains(addr)
+                    && !self.is_whitelisted(&addr.ip()) =>
+                {
+                    Some((*time, *addr))
+                }
+                _ => None,
+            })
+            .max();
+
+        if let Some((_, addr)) = candidate {
+            self._disconnect(addr, DisconnectReason::PeerEvicted);
+        }
+    }
+
+    /// Connect to the peers we were asked to connect to, that aren't connected and are not
+    /// waiting to be reconnected to.
+    fn reconnect(&mut self, local_time: LocalTime) {
+        for addr in self.config.retry.clone() {
+            if matches!(self.reconnects.get(&addr), Some((_, retry_at)) if local_time < *retry_at) {
+                continue;
+            }
+            if self.connect(&addr, local_time) {
+                self.upstream
+                    .event(Event::Connecting(addr, Source::Imported));
+            }
+        }
+    }
+
+    /// Get outbound peers, not including broadcast connections.
     fn outbound(&self) -> impl Iterator<Item = &Peer> + Clone {
         self.peers
-            .values()
-            .filter(|p| matches!(p, Peer::Connected { link, .. } if link.is_outbound()))
+            .iter()
+            .filter(move |(addr, p)| {
+                matches!(p, Peer::Connected { link, .. } if link.is_outbound())
+                    && !self.is_broadcast(addr)
+            })
+            .map(|(_, p)| p)
+    }
+
+    /// Pick an inbound peer to evict, to make room for a new inbound connection.
+    ///
+    /// The half of our inbound peers that have been connected the longest are protected,
+    /// since an attacker can't easily displace them. Of the others, we evict from the network
+    /// range we have the most connections from, as these are the likeliest to be controlled
+    /// by a single entity. Within a range, the slowest peer is evicted, or the most recent
+    /// connection if latencies are unknown.
+    fn eviction_candidate(&self) -> Option<PeerId> {
+        let mut inbound = self
+            .peers
+            .iter()
+            .filter_map(|(addr, p)| match p {
+                Peer::Connected {
+                    link,
+                    time,
+                    latency,
+                    ..
+                } if link.is_inbound() && !self.is_whitelisted(&addr.ip()) => {
+                    Some((*time, *addr, *latency))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        inbound.sort_unstable();
+
+        let protected = (inbound.len() + 1) / 2;
+        let mut groups: HashMap<Netgroup, Vec<(LocalTime, PeerId, Option<LocalDuration>)>> =
+            HashMap::with_hasher(self.rng.clone().into());
+
+        for (time, addr, latency) in inbound.into_iter().skip(protected) {
+            groups
+                .entry(Netgroup::from(&addr.ip()))
+                .or_default()
+                .push((time, addr, latency));
+        }
+        // Unknown latencies sort before known ones, so peers known to be slow go first.
+        groups
+            .into_iter()
+            .filter_map(|(_, peers)| {
+                let (time, addr, latency) = peers
+                    .iter()
+                    .max_by_key(|(time, _, latency)| (*latency, *time))
+                    .copied()?;
+
+                Some((peers.len(), latency, time, addr))
+            })
+            .max()
+            .map(|(_, _, _, addr)| addr)
     }
 
     /// Disconnect a peer (internal).
     fn _disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
+        span!("peer", peer = %addr);
+        record!(reason = %reason, "disconnecting");
+
         self.peers.insert(addr, Peer::Disconnecting);
         self.upstream.disconnect(addr, reason);
     }
 
-    /// Peers that have been idle longer than [`CONNECTION_TIMEOUT`].
+    /// Peers that have been connecting for longer than [`Config::connect_timeout`].
     fn idle_peers(&self, now: LocalTime) -> impl Iterator<Item = PeerId> + '_ {
         self.peers.iter().filter_map(move |(addr, p)| {
             if let Peer::Connecting { time } = p {
-                if now - *time >= CONNECTION_TIMEOUT {
+                if now - *time >= self.config.connect_timeout {
                     return Some(*addr);
                 }
             }
@@ -464,7 +1095,7 @@ mod tests {
 
         // Disconnect remote#1 after it has connected.
         addrs.push_back((Address::new(&remote2, services), Source::Dns));
-        connmgr.peer_disconnected(&remote1, &mut addrs, time);
+        connmgr.peer_disconnected(&remote1, DisconnectReason::Command, &mut addrs, time);
 
         assert!(connmgr.is_disconnected(&remote1));
         assert_eq!(connmgr.outbound_peers().next(), None);
@@ -476,7 +1107,7 @@ mod tests {
 
         // Disconnect remote#2 while still connecting.
         addrs.push_back((Address::new(&remote3, services), Source::Dns));
-        connmgr.peer_disconnected(&remote2, &mut addrs, time);
+        connmgr.peer_disconnected(&remote2, DisconnectReason::Command, &mut addrs, time);
 
         assert!(connmgr.is_disconnected(&remote2));
         assert_eq!(
@@ -485,4 +1116,424 @@ mod tests {
             "Disconnection triggers a new connection to remote#3"
         );
     }
+
+    #[test]
+    fn test_misbehavior_ban() {
+        let cfg = Config::default();
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+        connmgr.connect(&remote, time);
+        connmgr.peer_connected(remote, local, Link::Outbound, time);
+
+        // Misbehavior accumulates until the threshold is reached.
+        assert!(!connmgr.misbehaving(remote, Misbehavior::InvalidMessage("ping"), time));
+        assert!(connmgr.is_connected(&remote));
+        assert!(connmgr.misbehaving(remote, Misbehavior::InvalidMessage("ping"), time));
+        assert!(connmgr.is_banned(&remote.ip(), time));
+        assert!(matches!(
+            connmgr.peers.get(&remote),
+            Some(Peer::Disconnecting)
+        ));
+
+        let reason = DisconnectReason::PeerMisbehaving("ping");
+        connmgr.peer_disconnected(&remote, reason, &mut addrs, time);
+
+        // Banned peers are not re-dialed, and are disconnected if they connect to us.
+        assert!(!connmgr.connect(&remote, time));
+        connmgr.peer_connected(remote, local, Link::Inbound, time);
+        assert!(!connmgr.is_connected(&remote));
+
+        let reason = DisconnectReason::PeerBanned;
+        connmgr.peer_disconnected(&remote, reason, &mut addrs, time);
+
+        // Once the ban expires, we're free to connect again.
+        time.elapse(BAN_DURATION);
+        connmgr.received_tick(time, &mut addrs);
+
+        assert!(!connmgr.is_banned(&remote.ip(), time));
+        assert!(connmgr.connect(&remote, time));
+    }
+
+    #[test]
+    fn test_ban_unban() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+        let previous: PeerId = ([124, 43, 110, 2], 8333).into();
+        let expired: PeerId = ([124, 43, 110, 3], 8333).into();
+
+        let ban = |until| Ban {
+            until,
+            reason: String::from("bad peer"),
+        };
+        let cfg = Config {
+            banned: vec![
+                (previous.ip(), ban(time + BAN_DURATION)),
+                (
+                    expired.ip(),
+                    ban(LocalTime::from_block_time(time.block_time() - 1)),
+                ),
+            ],
+            ..Config::default()
+        };
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        // Bans from the previous run are kept, unless they expired.
+        assert!(connmgr.is_banned(&previous.ip(), time));
+        assert!(!connmgr.is_banned(&expired.ip(), time));
+        assert!(!connmgr.connect(&previous, time));
+        assert!(connmgr.connect(&expired, time));
+
+        // Banning a connected peer disconnects it.
+        connmgr.connect(&remote, time);
+        connmgr.peer_connected(remote, local, Link::Outbound, time);
+        assert!(connmgr.ban(remote.ip(), time + BAN_DURATION, String::from("spam")));
+        assert!(connmgr.is_banned(&remote.ip(), time));
+        assert!(matches!(
+            connmgr.peers.get(&remote),
+            Some(Peer::Disconnecting)
+        ));
+        connmgr.peer_disconnected(&remote, DisconnectReason::PeerBanned, &mut addrs, time);
+
+        let mut banned = connmgr.banned(time).map(|(ip, _)| *ip).collect::<Vec<_>>();
+        banned.sort();
+        assert_eq!(banned, vec![remote.ip(), previous.ip()]);
+
+        // Unbanned peers can be connected to again.
+        assert!(connmgr.unban(remote.ip()));
+        assert!(!connmgr.unban(remote.ip()));
+        assert!(connmgr.connect(&remote, time));
+
+        // Whitelisted peers can't be banned, and whitelisting lifts bans.
+        assert!(connmgr.whitelist(previous.ip()));
+        assert!(!connmgr.is_banned(&previous.ip(), time));
+        assert!(!connmgr.ban(previous.ip(), time + BAN_DURATION, String::from("spam")));
+    }
+
+    #[test]
+    fn test_whitelist_blacklist() {
+        let trusted: PeerId = ([124, 43, 110, 1], 8333).into();
+        let blocked: PeerId = ([124, 43, 110, 2], 8333).into();
+        let cfg = Config {
+            max_inbound_peers: 0,
+            whitelist: vec![trusted.ip()],
+            blacklist: vec!["124.43.0.0/16".parse().unwrap()],
+            ..Config::default()
+        };
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let local = ([99, 99, 99, 99], 9999).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        // Blacklisted peers are never dialed, and their inbound connections are rejected.
+        assert!(connmgr.is_blacklisted(&blocked.ip()));
+        assert!(!connmgr.connect(&blocked, time));
+        connmgr.peer_connected(blocked, local, Link::Inbound, time);
+        assert!(!connmgr.is_connected(&blocked));
+
+        // Whitelisted peers take precedence over the blacklist, are allowed in beyond
+        // the inbound limit, and are never banned.
+        assert!(!connmgr.is_blacklisted(&trusted.ip()));
+        connmgr.peer_connected(trusted, local, Link::Inbound, time);
+        assert!(connmgr.is_connected(&trusted));
+
+        for _ in 0..3 {
+            assert!(!connmgr.misbehaving(trusted, Misbehavior::InvalidMessage("ping"), time));
+        }
+        assert!(!connmgr.is_banned(&trusted.ip(), time));
+        assert!(connmgr.is_connected(&trusted));
+    }
+
+    #[test]
+    fn test_connect_only() {
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+        let other: PeerId = ([124, 43, 110, 2], 8333).into();
+        let cfg = Config {
+            retry: vec![remote],
+            connect_only: true,
+            ..Config::default()
+        };
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+        let local = ([99, 99, 99, 99], 9999).into();
+
+        let mut addrs = VecDeque::new();
+        addrs.push_back((Address::new(&other, ServiceFlags::NETWORK), Source::Dns));
+
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        // Only the configured peer is connected to, never addresses from the address book.
+        connmgr.initialize(time, &mut addrs);
+        assert_eq!(
+            connmgr.connecting_peers().collect::<Vec<_>>(),
+            vec![&remote]
+        );
+
+        connmgr.peer_connected(remote, local, Link::Outbound, time);
+        connmgr.peer_disconnected(&remote, DisconnectReason::Command, &mut addrs, time);
+        assert!(connmgr.is_disconnected(&other));
+        assert!(connmgr.is_disconnected(&remote));
+
+        // The peer is reconnected to after a delay, which doubles on every failed attempt.
+        time.elapse(MIN_RECONNECT_DELAY);
+        connmgr.received_tick(time, &mut addrs);
+        assert!(connmgr.is_connecting(&remote));
+
+        let reason = DisconnectReason::ConnectionError(String::from("oops"));
+        connmgr.peer_disconnected(&remote, reason, &mut addrs, time);
+
+        time.elapse(MIN_RECONNECT_DELAY);
+        connmgr.received_tick(time, &mut addrs);
+        assert!(connmgr.is_disconnected(&remote));
+
+        time.elapse(MIN_RECONNECT_DELAY);
+        connmgr.received_tick(time, &mut addrs);
+        assert!(connmgr.is_connecting(&remote));
+        assert!(connmgr.is_disconnected(&other));
+    }
+
+    #[test]
+    fn test_stalling_peer() {
+        let cfg = Config::default();
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        // A peer that keeps timing out eventually gets banned.
+        for _ in 0..BAN_THRESHOLD / Misbehavior::Stalling.score() {
+            assert!(!connmgr.is_banned(&remote.ip(), time));
+
+            connmgr.connect(&remote, time);
+            connmgr.peer_connected(remote, local, Link::Outbound, time);
+
+            let reason = DisconnectReason::PeerTimeout("sync");
+            connmgr.peer_disconnected(&remote, reason, &mut addrs, time);
+        }
+        assert!(connmgr.is_banned(&remote.ip(), time));
+    }
+
+    #[test]
+    fn test_inbound_eviction() {
+        let cfg = Config {
+            max_inbound_peers: 4,
+            ..Config::default()
+        };
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let oldest: Vec<PeerId> = vec![
+            ([124, 43, 110, 1], 8333).into(),
+            ([124, 43, 110, 2], 8333).into(),
+        ];
+        let lone: PeerId = ([88, 88, 88, 88], 8333).into();
+        let crowded: PeerId = ([124, 43, 111, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        for addr in oldest.iter().chain([crowded, lone].iter()) {
+            time.elapse(LocalDuration::from_secs(1));
+            connmgr.peer_connected(*addr, local, Link::Inbound, time);
+        }
+        assert_eq!(connmgr.inbound_peers().count(), 4);
+
+        // The oldest peers are protected, even though they share a network range with
+        // `crowded`. Of the others, each is alone in its range, so the youngest is evicted.
+        let newcomer: PeerId = ([77, 77, 77, 77], 8333).into();
+        time.elapse(LocalDuration::from_secs(1));
+        connmgr.peer_connected(newcomer, local, Link::Inbound, time);
+
+        assert!(connmgr.is_connected(&newcomer));
+        assert!(matches!(
+            connmgr.peers.get(&lone),
+            Some(Peer::Disconnecting)
+        ));
+        assert!(oldest.iter().all(|p| connmgr.is_connected(p)));
+        assert!(connmgr.is_connected(&crowded));
+        assert_eq!(connmgr.inbound_peers().count(), 4);
+
+        // Slow peers are evicted before younger ones.
+        connmgr.peer_latency(&crowded, LocalDuration::from_millis(900));
+        connmgr.peer_latency(&newcomer, LocalDuration::from_millis(100));
+
+        let latecomer: PeerId = ([66, 66, 66, 66], 8333).into();
+        time.elapse(LocalDuration::from_secs(1));
+        connmgr.peer_connected(latecomer, local, Link::Inbound, time);
+
+        assert!(connmgr.is_connected(&latecomer));
+        assert!(connmgr.is_connected(&newcomer));
+        assert!(!connmgr.is_connected(&crowded));
+
+        // Without room to make, inbound connections are refused.
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new(
+            (),
+            Config {
+                max_inbound_peers: 1,
+                ..Config::default()
+            },
+            fastrand::Rng::with_seed(1),
+        );
+        connmgr.initialize(time, &mut addrs);
+        connmgr.peer_connected(lone, local, Link::Inbound, time);
+        connmgr.peer_connected(newcomer, local, Link::Inbound, time);
+
+        assert!(connmgr.is_connected(&lone));
+        assert!(!connmgr.is_connected(&newcomer));
+    }
+
+    #[test]
+    fn test_anchors() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remotes: Vec<PeerId> = (1..=4).map(|i| ([124, 43, 110, i], 8333).into()).collect();
+        let inbound: PeerId = ([124, 43, 110, 9], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> =
+            ConnectionManager::new((), Config::default(), rng.clone());
+
+        connmgr.initialize(time, &mut addrs);
+        connmgr.peer_connected(inbound, local, Link::Inbound, time);
+
+        // Peers are connected to in reverse order, so the last are the oldest.
+        for remote in remotes.iter().rev() {
+            time.elapse(LocalDuration::from_secs(1));
+            connmgr.connect(remote, time);
+            connmgr.peer_connected(*remote, local, Link::Outbound, time);
+        }
+        assert_eq!(connmgr.anchors(), vec![remotes[3], remotes[2]]);
+
+        // Anchors are connected to first on the next start.
+        let cfg = Config {
+            target_outbound_peers: 2,
+            retry: vec![remotes[0]],
+            anchors: connmgr.anchors(),
+            ..Config::default()
+        };
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        assert!(connmgr.is_connecting(&remotes[3]));
+        assert!(connmgr.is_connecting(&remotes[2]));
+        assert!(!connmgr.is_connecting(&remotes[0]));
+    }
+
+    #[test]
+    fn test_filter_peer_quota() {
+        let cfg = Config {
+            target_outbound_peers: 2,
+            ..Config::default()
+        };
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote1 = ([124, 43, 110, 1], 8333).into();
+        let remote2 = ([124, 43, 110, 2], 8333).into();
+        let remote3 = ([124, 43, 110, 3], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+
+        for remote in [remote1, remote2] {
+            time.elapse(LocalDuration::from_secs(1));
+            connmgr.connect(&remote, time);
+            connmgr.peer_connected(remote, local, Link::Outbound, time);
+            connmgr.peer_negotiated(remote, ServiceFlags::NETWORK);
+        }
+
+        // Without a shortage of filter peers, nothing happens.
+        addrs.push_back((
+            Address::new(&remote3, ServiceFlags::COMPACT_FILTERS),
+            Source::Dns,
+        ));
+        time.elapse(IDLE_TIMEOUT);
+        connmgr.received_tick(time, &mut addrs);
+        assert_eq!(connmgr.outbound_peers().count(), 2);
+        assert_eq!(connmgr.connecting_peers().count(), 0);
+
+        // With a shortage, the most recent peer makes room for a filter peer.
+        connmgr.set_filter_shortage(true);
+        time.elapse(IDLE_TIMEOUT);
+        connmgr.received_tick(time, &mut addrs);
+
+        assert!(connmgr.is_connected(&remote1));
+        assert!(matches!(
+            connmgr.peers.get(&remote2),
+            Some(Peer::Disconnecting)
+        ));
+        assert!(connmgr.is_connecting(&remote3));
+
+        // While the filter peer is connecting, no other peer is replaced.
+        time.elapse(IDLE_TIMEOUT);
+        connmgr.received_tick(time, &mut addrs);
+        assert!(connmgr.is_connected(&remote1));
+    }
+
+    #[test]
+    fn test_no_filter_peers() {
+        let cfg = Config::default();
+        let rng = fastrand::Rng::with_seed(1);
+        let mut time = LocalTime::now();
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote1 = ([124, 43, 110, 1], 8333).into();
+        let remote2 = ([124, 43, 110, 2], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut connmgr: ConnectionManager<_, VecDeque<_>> = ConnectionManager::new((), cfg, rng);
+
+        connmgr.initialize(time, &mut addrs);
+        connmgr.connect(&remote1, time);
+        connmgr.peer_connected(remote1, local, Link::Outbound, time);
+        connmgr.peer_negotiated(remote1, ServiceFlags::NETWORK);
+
+        time.elapse(LocalDuration::from_secs(FILTER_PEER_TIMEOUT.as_secs() - 1));
+        connmgr.received_tick(time, &mut addrs);
+        assert!(matches!(connmgr.no_filter_peers, Some((_, false))));
+
+        // Reported once the timeout elapses.
+        time.elapse(LocalDuration::from_secs(1));
+        connmgr.received_tick(time, &mut addrs);
+        assert!(matches!(connmgr.no_filter_peers, Some((_, true))));
+
+        // Reset once a filter peer is found.
+        connmgr.connect(&remote2, time);
+        connmgr.peer_connected(remote2, local, Link::Outbound, time);
+        connmgr.peer_negotiated(remote2, ServiceFlags::COMPACT_FILTERS);
+        connmgr.received_tick(time, &mut addrs);
+        assert!(connmgr.no_filter_peers.is_none());
+    }
 }
@@ -0,0 +1,146 @@
+//! Decoy block downloads.
+//!
+//! The blocks we download after a filter match tell the peers we download them from which
+//! blocks pay to our wallet. To make this harder to learn, random blocks near the requested
+//! block can be downloaded along with it as decoys. Decoy blocks are discarded once received.
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::collections::HashMap;
+
+/// Decoys are picked from the blocks within this distance of the requested block.
+pub const DECOY_WINDOW: Height = 1024;
+/// Time after which decoys that weren't received are forgotten.
+pub const DECOY_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
+
+/// Picks decoy blocks, and recognizes them when they're received.
+#[derive(Debug)]
+pub struct Decoys {
+    /// Number of decoys requested for every block, on average. Zero disables decoys.
+    ratio: f64,
+    /// Decoys requested, and when.
+    pending: HashMap<BlockHash, LocalTime>,
+    rng: fastrand::Rng,
+}
+
+impl Decoys {
+    /// Create a new decoy manager, requesting `ratio` decoys for every block, on average.
+    pub fn new(ratio: f64, rng: fastrand::Rng) -> Self {
+        Self {
+            ratio: ratio.max(0.),
+            pending: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// Check whether decoys are requested.
+    pub fn is_enabled(&self) -> bool {
+        self.ratio > 0.
+    }
+
+    /// Pick the decoys to request along with the block at the given height. The fractional
+    /// part of the ratio is the probability of an extra decoy, so that the requested ratio
+    /// is met on average.
+    pub fn sample<T: BlockTree>(
+        &mut self,
+        height: Height,
+        tree: &T,
+        now: LocalTime,
+    ) -> Vec<BlockHash> {
+        let mut count = self.ratio.trunc() as usize;
+        if self.rng.f64() < self.ratio.fract() {
+            count += 1;
+        }
+        let start = height.saturating_sub(DECOY_WINDOW);
+        let end = height.saturating_add(DECOY_WINDOW).min(tree.height());
+        // There aren't always enough other blocks around.
+        let count = count.min(end.saturating_sub(start) as usize);
+        let mut decoys = Vec::with_capacity(count);
+
+        while decoys.len() < count {
+            let h = self.rng.u64(start..=end);
+            if h == height {
+                continue;
+            }
+            if let Some(header) = tree.get_block_by_height(h) {
+                let hash = header.block_hash();
+
+                if !decoys.contains(&hash) {
+                    decoys.push(hash);
+                }
+            }
+        }
+        for hash in &decoys {
+            self.pending.insert(*hash, now);
+        }
+        decoys
+    }
+
+    /// Called when a block is requested by the user. It's no longer a decoy, if it was one.
+    pub fn requested(&mut self, hash: &BlockHash) {
+        self.pending.remove(hash);
+    }
+
+    /// Called when a block is received. Returns whether it was a decoy, in which case it
+    /// should be discarded.
+    pub fn received_block(&mut self, hash: &BlockHash) -> bool {
+        self.pending.remove(hash).is_some()
+    }
+
+    /// Called when a peer replied with `notfound` to our request for a block. Returns
+    /// whether it was a decoy, in which case it shouldn't be requested again.
+    pub fn received_notfound(&mut self, hash: &BlockHash) -> bool {
+        self.pending.remove(hash).is_some()
+    }
+
+    /// Called when a tick is received. Forgets decoys that were never received.
+    pub fn received_tick(&mut self, now: LocalTime) {
+        self.pending.retain(|_, since| now - *since < DECOY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_decoys() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 32..33, &mut rng);
+        let tree = model::Cache::from(chain.map(|b| b.header));
+        let now = LocalTime::now();
+
+        let mut decoys = Decoys::new(2.5, rng.clone());
+        let mut total = 0;
+
+        for _ in 0..100 {
+            let sample = decoys.sample(16, &tree, now);
+            let real = tree.get_block_by_height(16).unwrap().block_hash();
+
+            assert!(sample.len() == 2 || sample.len() == 3);
+            assert!(!sample.contains(&real));
+            total += sample.len();
+        }
+        assert!((200..300).contains(&total));
+
+        // Decoys are discarded when received, unless requested in the meantime.
+        let sample = decoys.sample(16, &tree, now);
+        decoys.requested(&sample[0]);
+        assert!(!decoys.received_block(&sample[0]));
+        assert!(decoys.received_block(&sample[1]));
+        assert!(!decoys.received_block(&sample[1]));
+
+        // Decoys that never arrive are forgotten.
+        let sample = decoys.sample(16, &tree, now);
+        decoys.received_tick(now + DECOY_TIMEOUT);
+        assert!(!decoys.received_block(&sample[0]));
+
+        // Not enough blocks for the decoys.
+        let mut decoys = Decoys::new(100., rng);
+        assert_eq!(decoys.sample(16, &tree, now).len(), 32);
+    }
+}
@@ -0,0 +1,273 @@
+//! Fee rate estimation.
+//!
+//! Light clients don't see the mempool, nor the fee paid by an individual transaction, since
+//! computing it requires the outputs being spent. What we can learn is the total fee paid in
+//! a block, from the value claimed by its coinbase, as well as the minimum fee rate our peers
+//! are willing to relay, from their BIP 133 `feefilter` messages. Together, these give a rough
+//! idea of the fee rate needed for a transaction to confirm within a number of blocks.
+use std::collections::VecDeque;
+
+use bitcoin::{Block, Transaction};
+
+use nakamoto_common::block::Height;
+use nakamoto_common::collections::HashMap;
+use nakamoto_common::network::Network;
+
+use crate::protocol::PeerId;
+
+/// Fee rate, in satoshis per 1000 virtual bytes. This is the unit used by `feefilter`.
+pub type FeeRate = u64;
+
+/// Default fee rate sent in our `feefilter` messages. This is the minimum relay fee rate
+/// of most nodes, so transactions paying less wouldn't make it to us anyway.
+pub const DEFAULT_FEEFILTER: FeeRate = 1000;
+
+/// Number of recent blocks to estimate fee rates from.
+pub const MAX_BLOCK_SAMPLES: usize = 144;
+
+/// Initial block subsidy, in satoshis.
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+/// Get the virtual size of a transaction, in bytes.
+fn vsize(tx: &Transaction) -> u64 {
+    (tx.get_weight() as u64 + 3) / 4
+}
+
+/// Compute the fee rate of a transaction paying the given fee, in satoshis.
+pub fn feerate(tx: &Transaction, fee: u64) -> FeeRate {
+    fee * 1000 / vsize(tx).max(1)
+}
+
+/// Compute the average fee rate paid by the transactions of a block at the given height.
+/// Returns `None` if the block has no transactions besides the coinbase.
+pub fn block_feerate(block: &Block, height: Height, network: Network) -> Option<FeeRate> {
+    let (coinbase, txs) = block.txdata.split_first()?;
+    let vsize = txs.iter().map(vsize).sum::<u64>();
+
+    if vsize == 0 {
+        return None;
+    }
+    let reward = coinbase.output.iter().map(|o| o.value).sum::<u64>();
+    // Miners may claim less than they are allowed to.
+    let fees = reward.saturating_sub(subsidy(height, network));
+
+    Some(fees * 1000 / vsize)
+}
+
+/// Get the block subsidy at the given height, in satoshis.
+fn subsidy(height: Height, network: Network) -> u64 {
+    let interval = match network {
+        Network::Regtest => 150,
+        Network::Mainnet | Network::Testnet | Network::Testnet4 => 210_000,
+    };
+    let halvings = height / interval;
+
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+/// Estimates fee rates from peer fee filters and recent blocks.
+#[derive(Debug)]
+pub struct FeeManager {
+    /// Fee rates of recent blocks, by height.
+    blocks: VecDeque<(Height, FeeRate)>,
+    /// Minimum fee rates relayed by our peers.
+    filters: HashMap<PeerId, FeeRate>,
+    /// Network we're on.
+    network: Network,
+}
+
+impl FeeManager {
+    /// Create a new fee manager.
+    pub fn new(network: Network, rng: fastrand::Rng) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(MAX_BLOCK_SAMPLES),
+            filters: HashMap::with_hasher(rng.into()),
+            network,
+        }
+    }
+
+    /// Called when a peer sent us a `feefilter` message.
+    pub fn received_feefilter(&mut self, peer: PeerId, rate: i64) {
+        if rate < 0 {
+            return;
+        }
+        self.filters.insert(peer, rate as FeeRate);
+    }
+
+    /// Get the minimum fee rate of the transactions a peer wants announced to it, if
+    /// it sent us a fee filter.
+    pub fn feefilter(&self, peer: &PeerId) -> Option<FeeRate> {
+        self.filters.get(peer).copied()
+    }
+
+    /// Called when a block of the active chain was received. Blocks replace earlier blocks
+    /// at the same height, and only the most recent blocks are kept.
+    pub fn received_block(&mut self, height: Height, block: &Block) {
+        let rate = match block_feerate(block, height, self.network) {
+            Some(rate) => rate,
+            None => return,
+        };
+        self.blocks.retain(|(h, _)| *h != height);
+
+        let ix = self
+            .blocks
+            .iter()
+            .position(|(h, _)| *h > height)
+            .unwrap_or(self.blocks.len());
+        self.blocks.insert(ix, (height, rate));
+
+        while self.blocks.len() > MAX_BLOCK_SAMPLES {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.filters.remove(peer);
+    }
+
+    /// Estimate the fee rate needed for a transaction to confirm within the given number
+    /// of blocks. The estimate is never below the median fee filter of our peers, since a
+    /// transaction paying less is unlikely to be relayed. Returns `None` if we have nothing
+    /// to estimate from.
+    pub fn estimate(&self, target: Height) -> Option<FeeRate> {
+        let target = target.max(1) as usize;
+        let relay = {
+            let mut filters = self.filters.values().copied().collect::<Vec<_>>();
+            filters.sort_unstable();
+            filters.get(filters.len() / 2).copied()
+        };
+        // The more blocks we can wait, the lower the fee rate we can get away with.
+        let blocks = {
+            let mut rates = self.blocks.iter().map(|(_, r)| *r).collect::<Vec<_>>();
+            rates.sort_unstable();
+            rates.get(rates.len() / (target + 1)).copied()
+        };
+
+        match (blocks, relay) {
+            (Some(b), Some(r)) => Some(b.max(r)),
+            (b, r) => b.or(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{Script, Transaction, TxIn, TxOut};
+    use nakamoto_test::block::gen;
+
+    /// Build a block paying the given fees, with one transaction per element of `sizes`.
+    fn block(height: Height, fees: u64, sizes: &[usize], rng: &mut fastrand::Rng) -> Block {
+        let mut block = gen::genesis(rng);
+        let tx = |script: Vec<u8>, value| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(script),
+            }],
+        };
+        block.txdata = vec![tx(vec![], subsidy(height, Network::Mainnet) + fees)];
+        block
+            .txdata
+            .extend(sizes.iter().map(|size| tx(vec![0; *size], 1)));
+        block
+    }
+
+    #[test]
+    fn test_subsidy() {
+        assert_eq!(subsidy(0, Network::Mainnet), INITIAL_SUBSIDY);
+        assert_eq!(subsidy(209_999, Network::Mainnet), INITIAL_SUBSIDY);
+        assert_eq!(subsidy(210_000, Network::Mainnet), INITIAL_SUBSIDY / 2);
+        assert_eq!(subsidy(300, Network::Regtest), INITIAL_SUBSIDY / 4);
+        assert_eq!(subsidy(64 * 210_000, Network::Mainnet), 0);
+    }
+
+    #[test]
+    fn test_block_feerate() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let empty = block(1, 0, &[], &mut rng);
+        assert_eq!(block_feerate(&empty, 1, Network::Mainnet), None);
+
+        let blk = block(1, 1000, &[100, 200], &mut rng);
+        let vsize = blk.txdata[1..]
+            .iter()
+            .map(|tx| tx.get_weight() as u64 / 4)
+            .sum::<u64>();
+        assert_eq!(
+            block_feerate(&blk, 1, Network::Mainnet),
+            Some(1000 * 1000 / vsize)
+        );
+
+        // Miners claiming less than the subsidy.
+        let mut blk = block(1, 0, &[100], &mut rng);
+        blk.txdata[0].output[0].value = 1;
+        assert_eq!(block_feerate(&blk, 1, Network::Mainnet), Some(0));
+    }
+
+    #[test]
+    fn test_feerate() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let blk = block(1, 0, &[100], &mut rng);
+        let tx = &blk.txdata[1];
+
+        assert_eq!(feerate(tx, 0), 0);
+        assert_eq!(feerate(tx, vsize(tx)), 1000);
+        assert_eq!(feerate(tx, vsize(tx) * 3), 3000);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mut feemgr = FeeManager::new(Network::Mainnet, rng.clone());
+
+        assert_eq!(feemgr.estimate(1), None);
+
+        for (height, fees) in (1..=4).zip([4000, 1000, 3000, 2000]) {
+            feemgr.received_block(height, &block(height, fees * 100, &[96], &mut rng));
+        }
+        let rates = {
+            let mut rates = feemgr.blocks.iter().map(|(_, r)| *r).collect::<Vec<_>>();
+            rates.sort_unstable();
+            rates
+        };
+        assert_eq!(feemgr.estimate(1), Some(rates[2]));
+        assert_eq!(feemgr.estimate(3), Some(rates[1]));
+        assert_eq!(feemgr.estimate(6), Some(rates[0]));
+        assert_eq!(feemgr.estimate(0), feemgr.estimate(1));
+
+        // Peer fee filters set a floor.
+        let peer = ([88, 88, 88, 88], 8333).into();
+        feemgr.received_feefilter(peer, rates[3] as i64 + 1);
+        assert_eq!(feemgr.estimate(1), Some(rates[3] + 1));
+        assert_eq!(feemgr.feefilter(&peer), Some(rates[3] + 1));
+
+        // Negative fee filters are ignored.
+        feemgr.received_feefilter(peer, -1);
+        assert_eq!(feemgr.feefilter(&peer), Some(rates[3] + 1));
+
+        feemgr.peer_disconnected(&peer);
+        assert_eq!(feemgr.estimate(1), Some(rates[2]));
+
+        // A block replacing another at the same height.
+        feemgr.received_block(4, &block(4, 0, &[96], &mut rng));
+        assert_eq!(feemgr.blocks.len(), 4);
+        assert_eq!(feemgr.estimate(6), Some(0));
+
+        for height in 5..MAX_BLOCK_SAMPLES as Height + 10 {
+            feemgr.received_block(height, &block(height, 100, &[96], &mut rng));
+        }
+        assert_eq!(feemgr.blocks.len(), MAX_BLOCK_SAMPLES);
+        assert_eq!(
+            feemgr.blocks.back().unwrap().0,
+            MAX_BLOCK_SAMPLES as Height + 9
+        );
+    }
+}
@@ -0,0 +1,700 @@
+//! Transaction inventory management.
+//!
+//! Transactions submitted by the user aren't pushed to peers directly. Instead, they are
+//! announced with an `inv` message, and served to the peers we announced them to when those
+//! peers ask for them with `getdata`. This matches how transactions are relayed on the network,
+//! and lets peers that already have the transaction skip downloading it.
+//!
+//! Announced transactions are kept until their time-to-live runs out, so that peers asking for
+//! them more than once, eg. after a reorg, are still answered.
+//!
+//! Transactions can also be broadcast to peers that are still being connected to, eg. over
+//! short-lived connections opened for this purpose only. They are announced to these peers
+//! once the handshake with them is complete.
+//!
+//! The status of announced transactions is tracked as well: peers that request a transaction
+//! or announce it back to us are assumed to have it in their mempool, and transactions found
+//! in downloaded blocks are considered confirmed for as long as the block is in the active
+//! chain. Confirmations deeper than [`MAX_REORG_DEPTH`] are forgotten.
+use std::collections::HashSet;
+
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::{Block, Transaction, Txid};
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::collections::HashMap;
+
+use super::channel::SetTimeout;
+use super::PeerId;
+
+/// Default time after which announced transactions are forgotten.
+pub const DEFAULT_TTL: LocalDuration = LocalDuration::from_mins(60);
+/// Depth past which a block isn't expected to be reorganized out of the active chain. The
+/// confirmations of our transactions are only kept up to this depth.
+pub const MAX_REORG_DEPTH: Height = 100;
+
+/// The ability to announce and serve transactions.
+pub trait Inventories {
+    /// Announce inventory to a peer.
+    fn inv(&self, addr: PeerId, inventory: Vec<Inventory>);
+    /// Send a transaction to a peer.
+    fn tx(&self, addr: PeerId, tx: Transaction);
+    /// Tell a peer we don't have the requested inventory.
+    fn not_found(&self, addr: PeerId, inventory: Vec<Inventory>);
+    /// Request inventory from a peer.
+    fn get_data(&self, addr: PeerId, inventory: Vec<Inventory>);
+}
+
+/// Inventory manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Time after which announced transactions are forgotten.
+    pub ttl: LocalDuration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { ttl: DEFAULT_TTL }
+    }
+}
+
+/// The status of a transaction, as far as we know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// The transaction wasn't submitted by us, or has expired.
+    Unknown,
+    /// The transaction was announced to the given number of peers, none of which are known
+    /// to have it yet.
+    Announced {
+        /// Number of peers the transaction was announced to.
+        peers: usize,
+    },
+    /// The transaction is in the mempool of the given number of peers, ie. they requested
+    /// it from us or announced it back to us.
+    InMempool {
+        /// Number of peers known to have the transaction.
+        peers: usize,
+    },
+    /// The transaction is included in a block of the active chain.
+    Confirmed {
+        /// Height of the block.
+        height: Height,
+        /// Hash of the block.
+        block: BlockHash,
+    },
+}
+
+/// A transaction we announced.
+#[derive(Debug)]
+struct Entry {
+    /// The transaction.
+    tx: Transaction,
+    /// Peers we announced the transaction to.
+    peers: HashSet<PeerId>,
+    /// Peers known to have the transaction in their mempool.
+    mempool: HashSet<PeerId>,
+    /// Peers we requested the transaction back from, and haven't replied yet.
+    requested: HashSet<PeerId>,
+    /// Whether the transaction was broadcast privately. Private transactions are never
+    /// announced to peers other than the ones they were broadcast to.
+    private: bool,
+    /// Time at which the transaction was first announced.
+    since: LocalTime,
+}
+
+impl Entry {
+    fn new(tx: Transaction, private: bool, since: LocalTime) -> Self {
+        Self {
+            tx,
+            peers: HashSet::new(),
+            mempool: HashSet::new(),
+            requested: HashSet::new(),
+            private,
+            since,
+        }
+    }
+}
+
+/// Keeps track of the transactions we announced, and to whom.
+#[derive(Debug)]
+pub struct InventoryManager<U> {
+    config: Config,
+    txs: HashMap<Txid, Entry>,
+    /// Blocks our transactions were found in. Kept past the transactions' time-to-live.
+    confirmed: HashMap<Txid, BlockHash>,
+    /// Transactions to announce to peers once they are negotiated.
+    pending: HashMap<PeerId, Txid>,
+    upstream: U,
+}
+
+impl<U: Inventories + SetTimeout> InventoryManager<U> {
+    /// Create a new inventory manager.
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U) -> Self {
+        Self {
+            config,
+            txs: HashMap::with_hasher(rng.clone().into()),
+            confirmed: HashMap::with_hasher(rng.clone().into()),
+            pending: HashMap::with_hasher(rng.into()),
+            upstream,
+        }
+    }
+
+    /// Announce a transaction to the given peers. Announcing a transaction again adds to
+    /// the set of peers it is served to, but doesn't extend its lifetime.
+    pub fn announce(
+        &mut self,
+        tx: Transaction,
+        peers: impl IntoIterator<Item = PeerId>,
+        now: LocalTime,
+    ) -> Vec<PeerId> {
+        let txid = tx.txid();
+        let entry = self
+            .txs
+            .entry(txid)
+            .or_insert_with(|| Entry::new(tx, false, now));
+        let mut announced = Vec::new();
+
+        for peer in peers {
+            if entry.peers.insert(peer) {
+                self.upstream.inv(peer, vec![Inventory::Transaction(txid)]);
+                announced.push(peer);
+            }
+        }
+        self.upstream.set_timeout(self.config.ttl);
+
+        announced
+    }
+
+    /// Broadcast a transaction to the given peers, which are being connected to. The
+    /// transaction is announced to each peer once it is negotiated, and isn't announced to
+    /// any other peer.
+    pub fn broadcast(
+        &mut self,
+        tx: Transaction,
+        peers: impl IntoIterator<Item = PeerId>,
+        now: LocalTime,
+    ) {
+        let txid = tx.txid();
+
+        self.txs
+            .entry(txid)
+            .or_insert_with(|| Entry::new(tx, true, now))
+            .private = true;
+        self.pending
+            .extend(peers.into_iter().map(|peer| (peer, txid)));
+        self.upstream.set_timeout(self.config.ttl);
+    }
+
+    /// Check whether a transaction is being announced.
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.txs.contains_key(txid)
+    }
+
+    /// Get the status of a transaction. Confirmations are only reported if the block the
+    /// transaction was found in is still part of the active chain.
+    pub fn status<T: BlockTree>(&self, txid: &Txid, tree: &T) -> TxStatus {
+        if let Some(block) = self.confirmed.get(txid) {
+            if let Some((height, _)) = tree.get_block(block) {
+                return TxStatus::Confirmed {
+                    height,
+                    block: *block,
+                };
+            }
+        }
+        match self.txs.get(txid) {
+            Some(entry) if !entry.mempool.is_empty() => TxStatus::InMempool {
+                peers: entry.mempool.len(),
+            },
+            Some(entry) => TxStatus::Announced {
+                peers: entry.peers.len(),
+            },
+            None => TxStatus::Unknown,
+        }
+    }
+
+    /// Called when a peer sent us an `inv` message. Peers announcing our transactions have
+    /// them in their mempool.
+    pub fn received_inv(&mut self, addr: PeerId, inventory: &[Inventory]) {
+        for inv in inventory {
+            if let Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) = inv {
+                if let Some(entry) = self.txs.get_mut(txid) {
+                    entry.mempool.insert(addr);
+                }
+            }
+        }
+    }
+
+    /// Request a transaction back from the peers we announced it to, to check whether it's
+    /// still in their mempool. Returns the peers the transaction was requested from.
+    pub fn query(&mut self, txid: &Txid) -> Vec<PeerId> {
+        let entry = match self.txs.get_mut(txid) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let mut requested = Vec::new();
+
+        for peer in &entry.peers {
+            if entry.requested.insert(*peer) {
+                self.upstream
+                    .get_data(*peer, vec![Inventory::Transaction(*txid)]);
+                requested.push(*peer);
+            }
+        }
+        requested
+    }
+
+    /// Called when a peer sent us a `notfound` message. Peers reporting our transactions as
+    /// not found don't have them in their mempool, eg. because they were evicted. Returns
+    /// these transactions, which should be announced to other peers.
+    ///
+    /// Only replies to our own requests are taken into account, and transactions that were
+    /// broadcast privately are never returned.
+    pub fn received_notfound(&mut self, addr: PeerId, inventory: &[Inventory]) -> Vec<Transaction> {
+        let mut txs = Vec::new();
+
+        for inv in inventory {
+            if let Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) = inv {
+                if let Some(entry) = self.txs.get_mut(txid) {
+                    if entry.requested.remove(&addr) {
+                        entry.mempool.remove(&addr);
+
+                        if !entry.private {
+                            txs.push(entry.tx.clone());
+                        }
+                    }
+                }
+            }
+        }
+        txs
+    }
+
+    /// Called when a peer sent us a transaction. Peers replying to our request for one of
+    /// our transactions have it in their mempool.
+    pub fn received_tx(&mut self, addr: PeerId, txid: &Txid) {
+        if let Some(entry) = self.txs.get_mut(txid) {
+            if entry.requested.remove(&addr) {
+                entry.mempool.insert(addr);
+            }
+        }
+    }
+
+    /// Called when a block was received. Our transactions included in it are marked as
+    /// confirmed by it.
+    pub fn received_block(&mut self, block: &Block) {
+        let hash = block.block_hash();
+
+        for tx in &block.txdata {
+            let txid = tx.txid();
+
+            if self.txs.contains_key(&txid) {
+                self.confirmed.insert(txid, hash);
+            }
+        }
+    }
+
+    /// Called when the tip of the active chain changed. Confirmations by blocks buried deeper
+    /// than [`MAX_REORG_DEPTH`], or no longer in the active chain, are forgotten.
+    pub fn tip_changed<T: BlockTree>(&mut self, tree: &T) {
+        let tip = tree.height();
+
+        self.confirmed
+            .retain(|_, block| match tree.get_block(block) {
+                Some((height, _)) => tip.saturating_sub(height) < MAX_REORG_DEPTH,
+                None => false,
+            });
+    }
+
+    /// Called when a peer sent us a `getdata` message. Requested transactions are sent to
+    /// the peer if we announced them to it, and reported as not found otherwise. Returns the
+    /// requested inventory that isn't a transaction, eg. blocks.
+    pub fn received_getdata(&mut self, addr: PeerId, inventory: Vec<Inventory>) -> Vec<Inventory> {
+        let mut other = Vec::new();
+        let mut not_found = Vec::new();
+
+        for inv in inventory {
+            let txid = match inv {
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => txid,
+                _ => {
+                    other.push(inv);
+                    continue;
+                }
+            };
+            match self.txs.get_mut(&txid) {
+                Some(entry) if entry.peers.contains(&addr) => {
+                    self.upstream.tx(addr, entry.tx.clone());
+                    entry.mempool.insert(addr);
+                }
+                _ => not_found.push(inv),
+            }
+        }
+        if !not_found.is_empty() {
+            self.upstream.not_found(addr, not_found);
+        }
+        other
+    }
+
+    /// Called when a peer is negotiated. Announces the transaction broadcast to it, if any.
+    /// Returns whether a transaction was announced.
+    pub fn peer_negotiated(&mut self, addr: PeerId) -> bool {
+        let entry = match self.pending.remove(&addr) {
+            Some(txid) => match self.txs.get_mut(&txid) {
+                Some(entry) => entry,
+                None => return false,
+            },
+            None => return false,
+        };
+        if entry.peers.insert(addr) {
+            self.upstream
+                .inv(addr, vec![Inventory::Transaction(entry.tx.txid())]);
+        }
+        true
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.pending.remove(addr);
+
+        for entry in self.txs.values_mut() {
+            entry.peers.remove(addr);
+            entry.mempool.remove(addr);
+            entry.requested.remove(addr);
+        }
+    }
+
+    /// Called when a tick is received. Forgets transactions past their time-to-live.
+    pub fn received_tick(&mut self, now: LocalTime) {
+        let ttl = self.config.ttl;
+
+        self.txs.retain(|_, entry| now - entry.since < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    use bitcoin::{TxIn, TxOut};
+    use nonempty::NonEmpty;
+
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        invs: RefCell<Vec<(PeerId, Vec<Inventory>)>>,
+        txs: RefCell<Vec<(PeerId, Txid)>>,
+        not_found: RefCell<Vec<(PeerId, Vec<Inventory>)>>,
+        get_data: RefCell<Vec<(PeerId, Vec<Inventory>)>>,
+    }
+
+    impl Inventories for Recorder {
+        fn inv(&self, addr: PeerId, inventory: Vec<Inventory>) {
+            self.invs.borrow_mut().push((addr, inventory));
+        }
+
+        fn tx(&self, addr: PeerId, tx: Transaction) {
+            self.txs.borrow_mut().push((addr, tx.txid()));
+        }
+
+        fn not_found(&self, addr: PeerId, inventory: Vec<Inventory>) {
+            self.not_found.borrow_mut().push((addr, inventory));
+        }
+
+        fn get_data(&self, addr: PeerId, inventory: Vec<Inventory>) {
+            self.get_data.borrow_mut().push((addr, inventory));
+        }
+    }
+
+    impl SetTimeout for Recorder {
+        fn set_timeout(&self, _timeout: LocalDuration) -> &Self {
+            self
+        }
+    }
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_getdata() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+        let unknown = transaction(2).txid();
+        let block = Inventory::Block(Default::default());
+        let now = LocalTime::now();
+
+        assert_eq!(invmgr.announce(tx.clone(), vec![alice], now), vec![alice]);
+        assert_eq!(invmgr.announce(tx, vec![alice], now), vec![]);
+        assert_eq!(
+            invmgr.upstream.invs.borrow().as_slice(),
+            &[(alice, vec![Inventory::Transaction(txid)])]
+        );
+
+        // Repeated requests are answered.
+        for _ in 0..2 {
+            let rest = invmgr.received_getdata(
+                alice,
+                vec![
+                    Inventory::WitnessTransaction(txid),
+                    Inventory::Transaction(unknown),
+                    block,
+                ],
+            );
+            assert_eq!(rest, vec![block]);
+        }
+        assert_eq!(invmgr.upstream.txs.borrow().as_slice(), &[(alice, txid); 2]);
+        assert_eq!(
+            invmgr.upstream.not_found.borrow().last(),
+            Some(&(alice, vec![Inventory::Transaction(unknown)]))
+        );
+
+        // The transaction wasn't announced to this peer.
+        invmgr.received_getdata(bob, vec![Inventory::Transaction(txid)]);
+        assert_eq!(
+            invmgr.upstream.not_found.borrow().last(),
+            Some(&(bob, vec![Inventory::Transaction(txid)]))
+        );
+        assert_eq!(invmgr.upstream.txs.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+
+        invmgr.broadcast(tx, vec![alice, bob], LocalTime::now());
+        assert!(invmgr.contains(&txid));
+        assert!(invmgr.upstream.invs.borrow().is_empty());
+
+        // The transaction is announced once the peer is negotiated.
+        assert!(invmgr.peer_negotiated(alice));
+        assert!(!invmgr.peer_negotiated(alice));
+        assert_eq!(
+            invmgr.upstream.invs.borrow().as_slice(),
+            &[(alice, vec![Inventory::Transaction(txid)])]
+        );
+
+        // Peers that disconnected before being negotiated aren't announced anything.
+        invmgr.peer_disconnected(&bob);
+        assert!(!invmgr.peer_negotiated(bob));
+        assert_eq!(invmgr.upstream.invs.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let rng = fastrand::Rng::with_seed(1);
+        let ttl = LocalDuration::from_mins(10);
+        let mut invmgr = InventoryManager::new(Config { ttl }, rng, Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+        let mut now = LocalTime::now();
+
+        invmgr.announce(tx, vec![alice], now);
+
+        now = now + LocalDuration::from_mins(9);
+        invmgr.received_tick(now);
+        assert!(invmgr.contains(&txid));
+
+        now = now + LocalDuration::from_mins(1);
+        invmgr.received_tick(now);
+        assert!(!invmgr.contains(&txid));
+
+        invmgr.received_getdata(alice, vec![Inventory::Transaction(txid)]);
+        assert!(invmgr.upstream.txs.borrow().is_empty());
+        assert_eq!(invmgr.upstream.not_found.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+        let now = LocalTime::now();
+
+        let genesis = gen::genesis(&mut rng);
+        let mut block = gen::block(&genesis.header, &mut rng);
+        block.txdata.push(tx.clone());
+
+        let mut tree = model::Cache::from(NonEmpty::from((genesis.header, vec![block.header])));
+
+        assert_eq!(invmgr.status(&txid, &tree), TxStatus::Unknown);
+
+        invmgr.announce(tx, vec![alice, bob], now);
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::Announced { peers: 2 }
+        );
+
+        invmgr.received_getdata(alice, vec![Inventory::Transaction(txid)]);
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 1 }
+        );
+        invmgr.received_inv(bob, &[Inventory::Transaction(txid)]);
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 2 }
+        );
+
+        invmgr.received_block(&block);
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::Confirmed {
+                height: 1,
+                block: block.block_hash()
+            }
+        );
+
+        // The block is no longer part of the active chain.
+        tree.rollback(0).unwrap();
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 2 }
+        );
+    }
+
+    #[test]
+    fn test_confirmed_pruned() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+
+        let genesis = gen::genesis(&mut rng);
+        let mut block = gen::block(&genesis.header, &mut rng);
+        block.txdata.push(tx.clone());
+
+        let mut headers = NonEmpty::from((genesis.header, vec![block.header]));
+        for _ in 1..MAX_REORG_DEPTH {
+            let header = gen::block(headers.last(), &mut rng).header;
+            headers.push(header);
+        }
+        let tree = model::Cache::from(headers.clone());
+
+        invmgr.announce(tx, vec![alice], LocalTime::now());
+        invmgr.received_block(&block);
+
+        // The block isn't buried deep enough yet.
+        invmgr.tip_changed(&tree);
+        assert!(matches!(
+            invmgr.status(&txid, &tree),
+            TxStatus::Confirmed { height: 1, .. }
+        ));
+
+        headers.push(gen::block(headers.last(), &mut rng).header);
+        let tree = model::Cache::from(headers);
+
+        invmgr.tip_changed(&tree);
+        assert!(invmgr.confirmed.is_empty());
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::Announced { peers: 1 }
+        );
+    }
+
+    #[test]
+    fn test_notfound() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, Recorder::default());
+        let tree = model::Cache::from(NonEmpty::new(
+            gen::genesis(&mut fastrand::Rng::new()).header,
+        ));
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let carol: PeerId = ([77, 77, 77, 77], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+
+        invmgr.announce(tx.clone(), vec![alice, bob], LocalTime::now());
+        invmgr.received_getdata(alice, vec![Inventory::Transaction(txid)]);
+        invmgr.received_getdata(bob, vec![Inventory::Transaction(txid)]);
+
+        // Replies we didn't ask for are ignored.
+        assert!(invmgr
+            .received_notfound(alice, &[Inventory::Transaction(txid)])
+            .is_empty());
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 2 }
+        );
+
+        let mut requested = invmgr.query(&txid);
+        requested.sort();
+        assert_eq!(requested, {
+            let mut peers = vec![alice, bob];
+            peers.sort();
+            peers
+        });
+        assert_eq!(invmgr.upstream.get_data.borrow().len(), 2);
+
+        // The peer no longer counts as having the transaction.
+        let txs = invmgr.received_notfound(alice, &[Inventory::Transaction(txid)]);
+        assert_eq!(txs, vec![tx]);
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 1 }
+        );
+        // Only the first reply counts.
+        assert!(invmgr
+            .received_notfound(alice, &[Inventory::Transaction(txid)])
+            .is_empty());
+
+        // The other peer still has it.
+        invmgr.received_tx(bob, &txid);
+        assert!(invmgr
+            .received_notfound(bob, &[Inventory::Transaction(txid)])
+            .is_empty());
+        assert_eq!(
+            invmgr.status(&txid, &tree),
+            TxStatus::InMempool { peers: 1 }
+        );
+
+        // Peers we didn't announce the transaction to are ignored.
+        assert!(invmgr
+            .received_notfound(carol, &[Inventory::Transaction(txid)])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_notfound_private() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, Recorder::default());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let tx = transaction(1);
+        let txid = tx.txid();
+
+        invmgr.broadcast(tx, vec![alice], LocalTime::now());
+        invmgr.peer_negotiated(alice);
+        invmgr.query(&txid);
+
+        // Private transactions aren't handed back for announcing to other peers.
+        assert!(invmgr
+            .received_notfound(alice, &[Inventory::Transaction(txid)])
+            .is_empty());
+    }
+}
@@ -25,12 +25,13 @@ use nakamoto_common::block::Height;
 use nakamoto_common::collections::HashMap;
 
 use crate::protocol::addrmgr;
+use crate::protocol::feemgr::FeeRate;
 
 use super::{
     channel::{Disconnect, SetTimeout},
     DisconnectReason,
 };
-use super::{Hooks, Link, PeerId, Whitelist};
+use super::{Hooks, Link, Misbehavior, PeerId, Whitelist};
 
 /// Time to wait for response during peer handshake before disconnecting the peer.
 pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
@@ -38,6 +39,12 @@ pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
 
+/// Number of blocks from the tip that peers signaling `NETWORK_LIMITED` are able to serve.
+pub const LIMITED_BLOCK_DEPTH: Height = 288;
+
+/// Minimum protocol version of peers that understand `feefilter` (BIP 133).
+pub const FEEFILTER_VERSION: u32 = 70013;
+
 /// A time offset, in seconds.
 type TimeOffset = i64;
 
@@ -58,6 +65,29 @@ pub enum Event {
         /// Services offered by negotiated peer.
         services: ServiceFlags,
     },
+    /// A peer connected and negotiated. Emitted along with [`Event::PeerNegotiated`], with
+    /// everything known about the peer.
+    PeerConnected {
+        /// The peer's id.
+        addr: PeerId,
+        /// Link direction.
+        link: Link,
+        /// Services offered by the peer.
+        services: ServiceFlags,
+        /// The peer's user agent.
+        user_agent: String,
+        /// The peer's best height, as of the handshake.
+        height: Height,
+        /// The protocol version negotiated with the peer.
+        version: u32,
+    },
+    /// A negotiated peer disconnected.
+    PeerDisconnected {
+        /// The peer's id.
+        addr: PeerId,
+        /// Why the peer disconnected.
+        reason: DisconnectReason,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -73,6 +103,21 @@ impl std::fmt::Display for Event {
                 "{}: Peer negotiated with services {}..",
                 addr, services
             ),
+            Self::PeerConnected {
+                addr,
+                link,
+                services,
+                user_agent,
+                height,
+                version,
+            } => write!(
+                fmt,
+                "{}: Peer connected ({:?}), version = {}, height = {}, agent = {}, services = {}",
+                addr, link, version, height, user_agent, services
+            ),
+            Self::PeerDisconnected { addr, reason } => {
+                write!(fmt, "{}: Peer disconnected: {}", addr, reason)
+            }
         }
     }
 }
@@ -83,6 +128,8 @@ pub trait Handshake {
     fn version(&self, addr: PeerId, msg: VersionMessage) -> &Self;
     /// Send a `verack` message.
     fn verack(&self, addr: PeerId) -> &Self;
+    /// Send a `feefilter` message.
+    fn feefilter(&self, addr: PeerId, rate: FeeRate) -> &Self;
 }
 
 /// The ability to emit peer related events.
@@ -104,6 +151,9 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Our user agent.
     pub user_agent: &'static str,
+    /// Minimum fee rate of the transactions we want announced to us, sent to peers once
+    /// negotiated. Peers that don't understand `feefilter` are not sent anything.
+    pub feefilter: Option<FeeRate>,
 }
 
 /// Peer states.
@@ -140,6 +190,8 @@ pub struct Peer {
     // last_active: LocalTime,
     /// The peer's best height.
     pub height: Height,
+    /// The peer's protocol version.
+    pub version: u32,
     /// The peer's services.
     pub services: ServiceFlags,
     /// Peer user agent string.
@@ -171,6 +223,32 @@ impl Peer {
     pub fn is_outbound(&self) -> bool {
         self.conn.link.is_outbound()
     }
+
+    /// Check whether the peer only serves recent blocks, ie. it signals `NETWORK_LIMITED`
+    /// without `NETWORK`. See BIP 159.
+    pub fn is_limited(&self) -> bool {
+        !self.services.has(ServiceFlags::NETWORK)
+            && self.services.has(ServiceFlags::NETWORK_LIMITED)
+    }
+
+    /// Check whether the peer can serve a block at the given depth, where a depth of zero
+    /// is the tip of the active chain.
+    pub fn can_serve_block(&self, depth: Height) -> bool {
+        if self.services.has(ServiceFlags::NETWORK) {
+            return true;
+        }
+        self.services.has(ServiceFlags::NETWORK_LIMITED) && depth < LIMITED_BLOCK_DEPTH
+    }
+}
+
+/// Check whether the advertised services satisfy the required services. Since peers
+/// signaling `NETWORK_LIMITED` only differ from full nodes in the blocks they are able to
+/// serve, they are accepted in place of `NETWORK`.
+pub fn has_services(services: ServiceFlags, required: ServiceFlags) -> bool {
+    if services.has(ServiceFlags::NETWORK_LIMITED) {
+        return (services | ServiceFlags::NETWORK).has(required);
+    }
+    services.has(required)
 }
 
 /// Manages peers and peer negotiation.
@@ -248,9 +326,16 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
     }
 
     /// Called when a peer disconnected.
-    pub fn peer_disconnected(&mut self, addr: &net::SocketAddr) {
-        self.peers.remove(&addr);
-        self.connections.remove(&addr);
+    pub fn peer_disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason) {
+        if let Some(peer) = self.peers.remove(addr) {
+            if peer.is_negotiated() {
+                self.upstream.event(Event::PeerDisconnected {
+                    addr: *addr,
+                    reason,
+                });
+            }
+        }
+        self.connections.remove(addr);
     }
 
     /// Called when a `version` message was received.
@@ -302,7 +387,10 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             // Peers that don't advertise the `NETWORK` service are not full nodes.
             // It's not so useful for us to connect to them, because they're likely
             // to be less secure.
-            if conn.link.is_outbound() && !services.has(self.config.required_services) && !trusted {
+            if conn.link.is_outbound()
+                && !self::has_services(services, self.config.required_services)
+                && !trusted
+            {
                 return self
                     .upstream
                     .disconnect(*addr, DisconnectReason::PeerServices(services));
@@ -362,6 +450,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                     nonce,
                     conn,
                     height: start_height as Height,
+                    version,
                     time_offset: timestamp - now.block_time() as i64,
                     services,
                     user_agent,
@@ -373,25 +462,41 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
     }
 
     /// Called when a `verack` message was received.
-    pub fn received_verack(&mut self, addr: &PeerId, local_time: LocalTime) -> Option<&Peer> {
+    pub fn received_verack(
+        &mut self,
+        addr: &PeerId,
+        local_time: LocalTime,
+    ) -> Result<&Peer, Misbehavior> {
         if let Some(peer) = self.peers.get_mut(addr) {
             if let PeerState::AwaitingVerack { .. } = peer.state {
                 self.upstream.event(Event::PeerNegotiated {
                     addr: *addr,
                     services: peer.services,
                 });
+                self.upstream.event(Event::PeerConnected {
+                    addr: *addr,
+                    link: peer.conn.link,
+                    services: peer.services,
+                    user_agent: peer.user_agent.clone(),
+                    height: peer.height,
+                    version: peer.version.min(self.config.protocol_version),
+                });
 
                 peer.state = PeerState::Negotiated { since: local_time };
 
-                return Some(peer);
-            } else {
-                self.upstream.disconnect(
-                    *addr,
-                    DisconnectReason::PeerMisbehaving("unexpected `verack` message received"),
-                );
+                if let Some(rate) = self.config.feefilter {
+                    // Only peers that understand `feefilter` will honor it.
+                    if peer.version.min(self.config.protocol_version) >= FEEFILTER_VERSION {
+                        self.upstream.feefilter(*addr, rate);
+                    }
+                }
+
+                return Ok(peer);
             }
         }
-        None
+        Err(Misbehavior::InvalidMessage(
+            "unexpected `verack` message received",
+        ))
     }
 
     /// Called when a tick was received.
@@ -9,13 +9,18 @@ use crate::protocol::PeerId;
 
 use super::{
     channel::{Disconnect, SetTimeout},
-    DisconnectReason,
+    DisconnectReason, Link,
 };
 
 /// Time interval to wait between sent pings.
 pub const PING_INTERVAL: LocalDuration = LocalDuration::from_mins(2);
 /// Time to wait to receive a pong when sending a ping.
 pub const PING_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+/// Time an inbound peer may stay silent before we ping it.
+pub const INBOUND_PING_INTERVAL: LocalDuration = LocalDuration::from_mins(10);
+/// Time to wait for an inbound peer to respond to a ping. Inbound peers may be on
+/// slow or intermittent connections, so we're more lenient with them.
+pub const INBOUND_PING_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
 
 /// Maximum number of latencies recorded per peer.
 const MAX_RECORDED_LATENCIES: usize = 64;
@@ -25,6 +30,44 @@ pub trait Ping {
     fn pong(&self, addr: net::SocketAddr, nonce: u64) -> &Self;
 }
 
+/// Round-trip latency statistics, over the most recent pings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Latency {
+    /// Lowest observed latency.
+    pub min: LocalDuration,
+    /// Average latency.
+    pub avg: LocalDuration,
+    /// Latency of the last ping.
+    pub last: LocalDuration,
+}
+
+/// Messages exchanged with a peer, in one direction.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Traffic {
+    /// Number of messages.
+    pub messages: u64,
+    /// Number of bytes, including message headers.
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Statistics on a negotiated peer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Round-trip latency, if the peer answered any of our pings.
+    pub latency: Option<Latency>,
+    /// Messages sent to the peer, since it was negotiated.
+    pub sent: Traffic,
+    /// Messages received from the peer, since it was negotiated.
+    pub received: Traffic,
+}
+
 #[derive(Debug)]
 enum State {
     AwaitingPong { nonce: u64, since: LocalTime },
@@ -34,18 +77,28 @@ enum State {
 #[derive(Debug)]
 struct Peer {
     address: net::SocketAddr,
+    link: Link,
     state: State,
-    /// Observed round-trip latencies for this peer.
+    /// Observed round-trip latencies for this peer, most recent first.
     latencies: VecDeque<LocalDuration>,
+    /// Messages sent to this peer.
+    sent: Traffic,
+    /// Messages received from this peer.
+    received: Traffic,
 }
 
 impl Peer {
-    /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
-    fn latency(&self) -> LocalDuration {
+    /// Calculate the latency statistics of this peer.
+    fn latency(&self) -> Option<Latency> {
+        let last = *self.latencies.front()?;
+        let min = self.latencies.iter().min().copied()?;
         let sum: LocalDuration = self.latencies.iter().sum();
 
-        sum / self.latencies.len() as u32
+        Some(Latency {
+            min,
+            avg: sum / self.latencies.len() as u32,
+            last,
+        })
     }
 
     fn record_latency(&mut self, sample: LocalDuration) {
@@ -58,6 +111,8 @@ impl Peer {
 pub struct PingManager<U> {
     peers: HashMap<PeerId, Peer>,
     ping_timeout: LocalDuration,
+    /// Time interval to wait between sent pings. Defaults to [`PING_INTERVAL`].
+    ping_interval: LocalDuration,
     /// Random number generator.
     rng: fastrand::Rng,
     upstream: U,
@@ -70,37 +125,91 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
         Self {
             peers,
             ping_timeout,
+            ping_interval: PING_INTERVAL,
             rng,
             upstream,
         }
     }
 
-    pub fn peer_negotiated(&mut self, address: PeerId, now: LocalTime) {
-        let nonce = self.rng.u64(..);
+    pub fn peer_negotiated(&mut self, address: PeerId, link: Link, now: LocalTime) {
+        let state = if link.is_outbound() {
+            let nonce = self.rng.u64(..);
+            self.upstream.ping(address, nonce);
+
+            State::AwaitingPong { nonce, since: now }
+        } else {
+            // Inbound peers are only pinged once they go quiet.
+            self.upstream.set_timeout(INBOUND_PING_INTERVAL);
+
+            State::Idle { since: now }
+        };
 
-        self.upstream.ping(address, nonce);
         self.peers.insert(
             address,
             Peer {
                 address,
-                state: State::AwaitingPong { nonce, since: now },
+                link,
+                state,
                 latencies: VecDeque::new(),
+                sent: Traffic::default(),
+                received: Traffic::default(),
             },
         );
     }
 
+    /// Called when we receive any message from a peer, of the given size. For inbound peers,
+    /// this counts as a sign of life, and delays the next ping.
+    pub fn peer_active(&mut self, addr: PeerId, size: usize, now: LocalTime) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.received.record(size);
+
+            // Nb. A ping we're awaiting the `pong` of is left alone, so that its timeout
+            // still applies.
+            if let (true, State::Idle { since }) = (peer.link.is_inbound(), &mut peer.state) {
+                *since = now;
+            }
+        }
+    }
+
+    /// Called when a message of the given size was sent to a peer.
+    pub fn message_sent(&mut self, addr: PeerId, size: usize) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.sent.record(size);
+        }
+    }
+
     pub fn peer_disconnected(&mut self, addr: &PeerId) {
         self.peers.remove(addr);
     }
 
+    /// Get the statistics of a negotiated peer.
+    pub fn stats(&self, addr: &PeerId) -> Option<Stats> {
+        self.peers.get(addr).map(|peer| Stats {
+            latency: peer.latency(),
+            sent: peer.sent,
+            received: peer.received,
+        })
+    }
+
+    /// Set the interval between pings, eg. based on the current power profile.
+    pub fn set_ping_interval(&mut self, interval: LocalDuration) {
+        self.ping_interval = interval;
+    }
+
     pub fn received_tick(&mut self, now: LocalTime) {
         for peer in self.peers.values_mut() {
+            let (ping_timeout, ping_interval) = if peer.link.is_inbound() {
+                (INBOUND_PING_TIMEOUT, INBOUND_PING_INTERVAL)
+            } else {
+                (self.ping_timeout, self.ping_interval)
+            };
+
             match peer.state {
                 State::AwaitingPong { since, .. } => {
                     // A ping was sent and we're waiting for a `pong`. If too much
                     // time has passed, we consider this peer dead, and disconnect
                     // from them.
-                    if now - since >= self.ping_timeout {
+                    if now - since >= ping_timeout {
                         self.upstream
                             .disconnect(peer.address, DisconnectReason::PeerTimeout("ping"));
                     }
@@ -108,13 +217,13 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
                 State::Idle { since } => {
                     // We aren't waiting for any `pong`. Check whether enough time has passed since we
                     // received the last `pong`, and if so, send a new `ping`.
-                    if now - since >= PING_INTERVAL {
+                    if now - since >= ping_interval {
                         let nonce = self.rng.u64(..);
 
                         self.upstream
                             .ping(peer.address, nonce)
-                            .set_timeout(self.ping_timeout)
-                            .set_timeout(PING_INTERVAL);
+                            .set_timeout(ping_timeout)
+                            .set_timeout(ping_interval);
 
                         peer.state = State::AwaitingPong { nonce, since: now };
                     }
@@ -0,0 +1,184 @@
+//! Power profile policy.
+//!
+//! On mobile devices, every network round-trip can wake up the radio. When there is nothing
+//! going on, ie. we're synced to the tip and the user hasn't interacted with the client in
+//! a while, periodic network activity such as pings and address gossip is stretched out.
+//! Conversely, it's tightened when there is pending work, eg. during a rescan or when a
+//! transaction was just submitted.
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+
+/// How long without user activity before we switch to the low power profile.
+pub const IDLE_AFTER: LocalDuration = LocalDuration::from_mins(10);
+
+/// How long after a transaction is submitted that we consider it pending.
+pub const PENDING_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
+
+/// A power profile, determining the cadence of periodic network activity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// There is pending work. Intervals are shortened.
+    Active,
+    /// Default intervals are used.
+    Balanced,
+    /// Synced and idle. Intervals are stretched out.
+    LowPower,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::Balanced
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Active => write!(fmt, "active"),
+            Self::Balanced => write!(fmt, "balanced"),
+            Self::LowPower => write!(fmt, "low-power"),
+        }
+    }
+}
+
+/// Power policy configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Whether the policy is enabled. If disabled, the balanced profile is always used.
+    pub enabled: bool,
+    /// How long without user activity before we switch to the low power profile.
+    pub idle_after: LocalDuration,
+    /// Factor by which intervals are stretched in the low power profile.
+    pub stretch: u32,
+    /// Factor by which intervals are shortened in the active profile.
+    pub tighten: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_after: IDLE_AFTER,
+            stretch: 5,
+            tighten: 2,
+        }
+    }
+}
+
+impl Config {
+    /// Scale a base interval according to the given profile.
+    pub fn interval(&self, base: LocalDuration, profile: Profile) -> LocalDuration {
+        match profile {
+            Profile::Active => base / self.tighten.max(1),
+            Profile::Balanced => base,
+            Profile::LowPower => base * self.stretch.max(1) as u64,
+        }
+    }
+}
+
+/// Decides which power profile to use, based on what the client is doing.
+#[derive(Debug)]
+pub struct PowerPolicy {
+    /// Policy configuration.
+    pub config: Config,
+    /// Current profile.
+    profile: Profile,
+    /// Last time the user interacted with the client.
+    last_activity: LocalTime,
+    /// Time until which we have pending work that doesn't otherwise show up in the state
+    /// of the protocol, eg. a recently submitted transaction.
+    pending_until: Option<LocalTime>,
+}
+
+impl PowerPolicy {
+    /// Create a new policy.
+    pub fn new(config: Config, now: LocalTime) -> Self {
+        Self {
+            config,
+            profile: Profile::default(),
+            last_activity: now,
+            pending_until: None,
+        }
+    }
+
+    /// Get the current profile.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Scale a base interval according to the current profile.
+    pub fn interval(&self, base: LocalDuration) -> LocalDuration {
+        self.config.interval(base, self.profile)
+    }
+
+    /// Called when the user interacts with the client.
+    pub fn user_activity(&mut self, now: LocalTime) {
+        self.last_activity = now;
+    }
+
+    /// Called when a transaction was submitted to the network.
+    pub fn transaction_submitted(&mut self, now: LocalTime) {
+        self.last_activity = now;
+        self.pending_until = Some(now + PENDING_TIMEOUT);
+    }
+
+    /// Re-evaluate the profile. Returns the new profile if it changed.
+    ///
+    /// `synced` should be `true` when we're caught up with the network, and `busy` when
+    /// there is ongoing work the user is waiting on, eg. a rescan.
+    pub fn update(&mut self, now: LocalTime, synced: bool, busy: bool) -> Option<Profile> {
+        let pending = matches!(self.pending_until, Some(t) if now < t);
+        let profile = if !self.config.enabled {
+            Profile::Balanced
+        } else if busy || pending {
+            Profile::Active
+        } else if synced && now - self.last_activity >= self.config.idle_after {
+            Profile::LowPower
+        } else {
+            Profile::Balanced
+        };
+
+        if profile != self.profile {
+            self.profile = profile;
+            return Some(profile);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let mut time = LocalTime::now();
+        let mut policy = PowerPolicy::new(Config::default(), time);
+        let base = LocalDuration::from_mins(2);
+
+        assert_eq!(policy.update(time, true, false), None);
+        assert_eq!(policy.interval(base), base);
+
+        time.elapse(IDLE_AFTER);
+        assert_eq!(policy.update(time, false, false), None, "not synced");
+        assert_eq!(
+            policy.update(time, true, false),
+            Some(Profile::LowPower),
+            "synced and idle"
+        );
+        assert_eq!(policy.interval(base), LocalDuration::from_mins(10));
+
+        assert_eq!(policy.update(time, true, true), Some(Profile::Active));
+        assert_eq!(policy.interval(base), LocalDuration::from_mins(1));
+
+        policy.user_activity(time);
+        assert_eq!(policy.update(time, true, false), Some(Profile::Balanced));
+
+        policy.transaction_submitted(time);
+        assert_eq!(policy.update(time, true, false), Some(Profile::Active));
+
+        // Once the transaction is no longer pending, and the user has been inactive
+        // for long enough, we go straight to low power.
+        time.elapse(PENDING_TIMEOUT);
+        assert_eq!(policy.update(time, true, false), Some(Profile::LowPower));
+    }
+}
@@ -0,0 +1,376 @@
+//! Per-peer message rate limiting.
+//!
+//! Every message a peer sends costs us some work, and some messages, eg. `inv` and `addr`,
+//! carry many items which each cost us more. To keep a single peer from hogging the node,
+//! the messages, bytes and items we receive from each peer are metered with token buckets.
+//! Messages over the limits are dropped, and peers that keep sending them past a tolerance
+//! are disconnected. Replies to our own requests, eg. the blocks we asked for, aren't
+//! limited, since the peer is only sending what we asked for.
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// A rate limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limit {
+    /// Units allowed per second, on average.
+    pub rate: u64,
+    /// Units allowed at once, after a period of inactivity.
+    pub burst: u64,
+}
+
+impl Limit {
+    /// Create a new limit.
+    pub const fn new(rate: u64, burst: u64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// Rate limiter configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Messages received.
+    pub messages: Limit,
+    /// Bytes received.
+    pub bytes: Limit,
+    /// Inventory items received in `inv` messages.
+    pub inv: Limit,
+    /// Addresses received in `addr` messages.
+    pub addr: Limit,
+    /// Messages over the limits a peer may send before being disconnected.
+    pub tolerance: Limit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            messages: Limit::new(1_000, 10_000),
+            bytes: Limit::new(8 * 1024 * 1024, 32 * 1024 * 1024),
+            inv: Limit::new(100, 50_000),
+            addr: Limit::new(1, 1_000),
+            tolerance: Limit::new(1, 100),
+        }
+    }
+}
+
+/// What to do with a received message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message is within the limits.
+    Accept,
+    /// The message is over the limits, and should be dropped.
+    Drop(&'static str),
+    /// The peer is flooding us, and should be disconnected.
+    Disconnect(&'static str),
+}
+
+/// A token bucket. Tokens are counted in thousandths, so that slow rates refill smoothly.
+#[derive(Debug)]
+struct Bucket {
+    limit: Limit,
+    tokens: u128,
+    last: LocalTime,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: LocalTime) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as u128 * 1000,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: LocalTime) {
+        if now > self.last {
+            let elapsed = (now - self.last).as_millis();
+            let max = self.limit.burst as u128 * 1000;
+
+            self.tokens = (self.tokens + elapsed * self.limit.rate as u128).min(max);
+            self.last = now;
+        }
+    }
+
+    fn has(&self, cost: u64) -> bool {
+        self.tokens >= cost as u128 * 1000
+    }
+
+    fn take(&mut self, cost: u64) {
+        self.tokens = self.tokens.saturating_sub(cost as u128 * 1000);
+    }
+}
+
+/// Buckets of a single peer.
+#[derive(Debug)]
+struct Peer {
+    messages: Bucket,
+    bytes: Bucket,
+    inv: Bucket,
+    addr: Bucket,
+    tolerance: Bucket,
+}
+
+/// Meters the messages received from peers.
+#[derive(Debug)]
+pub struct RateManager {
+    config: Config,
+    peers: HashMap<PeerId, Peer>,
+}
+
+impl RateManager {
+    /// Create a new rate manager.
+    pub fn new(config: Config, rng: fastrand::Rng) -> Self {
+        Self {
+            config,
+            peers: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Called when a message of the given encoded size was received from a peer. Returns
+    /// whether the message should be processed. Messages solicited by one of our requests
+    /// are always accepted, and don't count towards the limits.
+    pub fn received(
+        &mut self,
+        addr: PeerId,
+        msg: &RawNetworkMessage,
+        size: usize,
+        solicited: bool,
+        now: LocalTime,
+    ) -> Verdict {
+        if solicited {
+            return Verdict::Accept;
+        }
+        let config = &self.config;
+        let peer = self.peers.entry(addr).or_insert_with(|| Peer {
+            messages: Bucket::new(config.messages, now),
+            bytes: Bucket::new(config.bytes, now),
+            inv: Bucket::new(config.inv, now),
+            addr: Bucket::new(config.addr, now),
+            tolerance: Bucket::new(config.tolerance, now),
+        });
+        let size = size as u64;
+        let (items, cost) = match &msg.payload {
+            NetworkMessage::Inv(inv) => (Some(&mut peer.inv), inv.len() as u64),
+            NetworkMessage::Addr(addrs) => (Some(&mut peer.addr), addrs.len() as u64),
+            _ => (None, 0),
+        };
+
+        for bucket in [&mut peer.messages, &mut peer.bytes, &mut peer.tolerance] {
+            bucket.refill(now);
+        }
+        let exceeded = if !peer.messages.has(1) {
+            Some("messages")
+        } else if !peer.bytes.has(size) {
+            Some("bandwidth")
+        } else {
+            match items {
+                Some(bucket) => {
+                    bucket.refill(now);
+
+                    if bucket.has(cost) {
+                        bucket.take(cost);
+                        None
+                    } else {
+                        Some(msg.cmd())
+                    }
+                }
+                None => None,
+            }
+        };
+
+        if let Some(reason) = exceeded {
+            if !peer.tolerance.has(1) {
+                return Verdict::Disconnect(reason);
+            }
+            peer.tolerance.take(1);
+
+            return Verdict::Drop(reason);
+        }
+        peer.messages.take(1);
+        peer.bytes.take(size);
+
+        Verdict::Accept
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.peers.remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::consensus::Encodable;
+    use bitcoin::network::address::Address;
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::network::message_blockdata::Inventory;
+    use nakamoto_common::block::time::LocalDuration;
+
+    fn raw(payload: NetworkMessage) -> RawNetworkMessage {
+        RawNetworkMessage { magic: 0, payload }
+    }
+
+    fn size(msg: &RawNetworkMessage) -> usize {
+        msg.consensus_encode(std::io::sink()).unwrap()
+    }
+
+    #[test]
+    fn test_message_rate() {
+        let config = Config {
+            messages: Limit::new(10, 20),
+            tolerance: Limit::new(1, 5),
+            ..Config::default()
+        };
+        let mut ratemgr = RateManager::new(config, fastrand::Rng::with_seed(1));
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut now = LocalTime::now();
+        let ping = raw(NetworkMessage::Ping(0));
+
+        for _ in 0..20 {
+            assert_eq!(
+                ratemgr.received(peer, &ping, size(&ping), false, now),
+                Verdict::Accept
+            );
+        }
+        // Over the burst, messages are dropped...
+        for _ in 0..5 {
+            assert_eq!(
+                ratemgr.received(peer, &ping, size(&ping), false, now),
+                Verdict::Drop("messages")
+            );
+        }
+        // ... until the peer runs out of tolerance.
+        assert_eq!(
+            ratemgr.received(peer, &ping, size(&ping), false, now),
+            Verdict::Disconnect("messages")
+        );
+
+        // Tokens are refilled over time.
+        now = now + LocalDuration::from_secs(1);
+        for _ in 0..10 {
+            assert_eq!(
+                ratemgr.received(peer, &ping, size(&ping), false, now),
+                Verdict::Accept
+            );
+        }
+        assert_eq!(
+            ratemgr.received(peer, &ping, size(&ping), false, now),
+            Verdict::Drop("messages")
+        );
+
+        // Other peers aren't affected.
+        let other: PeerId = ([99, 99, 99, 99], 8333).into();
+        assert_eq!(
+            ratemgr.received(other, &ping, size(&ping), false, now),
+            Verdict::Accept
+        );
+    }
+
+    #[test]
+    fn test_item_rate() {
+        let config = Config {
+            inv: Limit::new(10, 100),
+            addr: Limit::new(1, 10),
+            ..Config::default()
+        };
+        let mut ratemgr = RateManager::new(config, fastrand::Rng::with_seed(1));
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+        let now = LocalTime::now();
+
+        let inv = raw(NetworkMessage::Inv(vec![
+            Inventory::Block(Default::default());
+            60
+        ]));
+        assert_eq!(
+            ratemgr.received(peer, &inv, size(&inv), false, now),
+            Verdict::Accept
+        );
+        assert_eq!(
+            ratemgr.received(peer, &inv, size(&inv), false, now),
+            Verdict::Drop("inv")
+        );
+
+        let addr = Address::new(&([77, 77, 77, 77], 8333).into(), ServiceFlags::NETWORK);
+        let addrs = raw(NetworkMessage::Addr(vec![(0, addr); 11]));
+        assert_eq!(
+            ratemgr.received(peer, &addrs, size(&addrs), false, now),
+            Verdict::Drop("addr")
+        );
+
+        // Messages without items are still accepted.
+        let ping = raw(NetworkMessage::Ping(0));
+        assert_eq!(
+            ratemgr.received(peer, &ping, size(&ping), false, now),
+            Verdict::Accept
+        );
+
+        ratemgr.peer_disconnected(&peer);
+        assert_eq!(
+            ratemgr.received(peer, &inv, size(&inv), false, now),
+            Verdict::Accept
+        );
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let config = Config {
+            bytes: Limit::new(1024, 4096),
+            ..Config::default()
+        };
+        let mut ratemgr = RateManager::new(config, fastrand::Rng::with_seed(1));
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+        let now = LocalTime::now();
+
+        let inv = raw(NetworkMessage::Inv(vec![
+            Inventory::Block(Default::default());
+            100
+        ]));
+        assert_eq!(
+            ratemgr.received(peer, &inv, size(&inv), false, now),
+            Verdict::Accept
+        );
+        assert_eq!(
+            ratemgr.received(peer, &inv, size(&inv), false, now),
+            Verdict::Drop("bandwidth")
+        );
+    }
+
+    #[test]
+    fn test_solicited() {
+        let config = Config {
+            messages: Limit::new(1, 2),
+            tolerance: Limit::new(1, 1),
+            ..Config::default()
+        };
+        let mut ratemgr = RateManager::new(config, fastrand::Rng::with_seed(1));
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+        let now = LocalTime::now();
+        let block = raw(NetworkMessage::Block(bitcoin::Block {
+            header: nakamoto_common::network::Network::Mainnet.genesis(),
+            txdata: vec![],
+        }));
+
+        // Replies to our requests aren't limited, and don't use up the peer's allowance.
+        for _ in 0..10 {
+            assert_eq!(
+                ratemgr.received(peer, &block, size(&block), true, now),
+                Verdict::Accept
+            );
+        }
+        for _ in 0..2 {
+            assert_eq!(
+                ratemgr.received(peer, &block, size(&block), false, now),
+                Verdict::Accept
+            );
+        }
+        assert_eq!(
+            ratemgr.received(peer, &block, size(&block), false, now),
+            Verdict::Drop("messages")
+        );
+    }
+}
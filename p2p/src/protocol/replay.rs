@@ -0,0 +1,941 @@
+//! Deterministic replay of protocol inputs.
+//!
+//! Since the protocol performs no I/O, and is given the time and its random number generator,
+//! its behavior is entirely determined by the inputs it is fed. A [`Recorder`] attached to
+//! the protocol with [`Protocol::with_recorder`] writes every input to a log, along with the
+//! time it was received at and the seed of the random number generator. The log can then be
+//! replayed with a [`Replayer`], eg. to reproduce a bug reported from the field.
+//!
+//! For the replay to be faithful, the protocol must be built with the same version of this
+//! crate, the same configuration, the seed returned by [`Replayer::seed`], and stores in the
+//! same state as when the recording started.
+//!
+//! Commands that carry closures, ie. [`Command::ReadSnapshot`] and [`Command::Broadcast`],
+//! can't be recorded. They are logged as [`Entry::Unrecorded`], and skipped on replay.
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net;
+use std::ops::{Bound, Range};
+use std::path::Path;
+
+use crossbeam_channel as chan;
+use thiserror::Error;
+
+use bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::Address;
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{BlockHeader, OutPoint, Script, Transaction, Txid};
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::p2p::peer;
+
+use super::{Blocks, Command, DerivationScheme, DisconnectReason, Input, Link, Protocol};
+
+/// Identifies replay logs.
+const MAGIC: &[u8; 8] = b"NKREPLAY";
+/// Version of the log format.
+const VERSION: u32 = 1;
+
+/// An error reading a replay log.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// An entry couldn't be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The log is not a replay log, or has an unsupported format.
+    #[error("invalid replay log: {0}")]
+    Invalid(String),
+}
+
+/// An entry of a replay log.
+#[derive(Debug)]
+pub enum Entry {
+    /// The protocol was initialized at the given time.
+    Initialize(LocalTime),
+    /// An input was fed to the protocol at the given time.
+    Step(LocalTime, Input),
+    /// A command that couldn't be recorded was fed to the protocol at the given time.
+    Unrecorded(LocalTime, String),
+}
+
+/// Records protocol inputs to a replay log.
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "Recorder(..)")
+    }
+}
+
+impl Recorder {
+    /// Start a replay log, for a protocol whose random number generator is seeded with
+    /// the given seed.
+    pub fn new(writer: impl Write + Send + 'static, seed: u64) -> io::Result<Self> {
+        let mut recorder = Self {
+            writer: Box::new(writer),
+        };
+        let mut buf = MAGIC.to_vec();
+        VERSION.consensus_encode(&mut buf)?;
+        seed.consensus_encode(&mut buf)?;
+        recorder.write(&buf)?;
+
+        Ok(recorder)
+    }
+
+    /// Create a replay log file, truncating it if it exists.
+    pub fn create(path: impl AsRef<Path>, seed: u64) -> io::Result<Self> {
+        let file = File::create(path)?;
+
+        Self::new(BufWriter::new(file), seed)
+    }
+
+    /// Record the protocol's initialization.
+    pub fn initialize(&mut self, time: LocalTime) -> io::Result<()> {
+        let mut buf = vec![0];
+        encode_time(&mut buf, time)?;
+
+        self.write(&buf)
+    }
+
+    /// Record an input fed to the protocol.
+    pub fn step(&mut self, input: &Input, time: LocalTime) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        match input {
+            Input::Command(cmd) if !is_recordable(cmd) => {
+                buf.push(2);
+                encode_time(&mut buf, time)?;
+                format!("{:?}", cmd).consensus_encode(&mut buf)?;
+            }
+            input => {
+                buf.push(1);
+                encode_time(&mut buf, time)?;
+                encode_input(&mut buf, input)?;
+            }
+        }
+        self.write(&buf)
+    }
+
+    /// Write an entry, and flush it, so that the log is complete if the process crashes.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()
+    }
+}
+
+/// Reads protocol inputs from a replay log, and feeds them to a protocol.
+pub struct Replayer<R> {
+    reader: R,
+    seed: u64,
+    /// Receiving ends of the reply channels of replayed commands. Kept around so that
+    /// replies don't fail.
+    replies: Vec<Box<dyn Any>>,
+}
+
+impl Replayer<BufReader<File>> {
+    /// Open a replay log file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = File::open(path)?;
+
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> Replayer<R> {
+    /// Start reading a replay log.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(Error::Invalid(String::from("bad magic")));
+        }
+        let version = u32::consensus_decode(&mut reader)?;
+        if version != VERSION {
+            return Err(Error::Invalid(format!("unsupported version {}", version)));
+        }
+        let seed = u64::consensus_decode(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            seed,
+            replies: Vec::new(),
+        })
+    }
+
+    /// The seed of the recorded protocol's random number generator.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Replay the remaining entries of the log against the given protocol, which must be
+    /// set up as described in the [module documentation](self). Returns the number of
+    /// inputs replayed.
+    pub fn run<T: BlockTree, F: Filters, P: peer::Store>(
+        &mut self,
+        protocol: &mut Protocol<T, F, P>,
+    ) -> Result<usize, Error> {
+        let mut count = 0;
+
+        while let Some(entry) = self.next_entry()? {
+            match entry {
+                Entry::Initialize(time) => protocol.initialize(time),
+                Entry::Step(time, input) => protocol.step(input, time),
+                Entry::Unrecorded(_, cmd) => {
+                    log::warn!("Skipping unrecorded command {}", cmd);
+                    continue;
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Read the next entry of the log. Returns `None` at the end of the log.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, Error> {
+        let tag = match u8::consensus_decode(&mut self.reader) {
+            Ok(tag) => tag,
+            Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let time = self.time()?;
+
+        let entry = match tag {
+            0 => Entry::Initialize(time),
+            1 => Entry::Step(time, self.input()?),
+            2 => Entry::Unrecorded(time, self.get()?),
+            _ => return Err(invalid("entry", tag)),
+        };
+        Ok(Some(entry))
+    }
+
+    fn get<T: Decodable>(&mut self) -> Result<T, Error> {
+        T::consensus_decode(&mut self.reader).map_err(Error::from)
+    }
+
+    fn list<T>(&mut self, f: impl Fn(&mut Self) -> Result<T, Error>) -> Result<Vec<T>, Error> {
+        let VarInt(len) = self.get()?;
+        (0..len).map(|_| f(self)).collect()
+    }
+
+    fn reply<T: 'static>(&mut self) -> chan::Sender<T> {
+        let (tx, rx) = chan::unbounded();
+        self.replies.push(Box::new(rx));
+
+        tx
+    }
+
+    fn time(&mut self) -> Result<LocalTime, Error> {
+        let millis: u64 = self.get()?;
+
+        Ok(LocalTime::default() + LocalDuration::from_millis(millis as u128))
+    }
+
+    fn duration(&mut self) -> Result<LocalDuration, Error> {
+        let millis: u64 = self.get()?;
+
+        Ok(LocalDuration::from_millis(millis as u128))
+    }
+
+    fn ip(&mut self) -> Result<net::IpAddr, Error> {
+        let ip = match self.get::<u8>()? {
+            4 => self.get::<[u8; 4]>()?.into(),
+            6 => self.get::<[u8; 16]>()?.into(),
+            tag => return Err(invalid("address family", tag)),
+        };
+        Ok(ip)
+    }
+
+    fn addr(&mut self) -> Result<net::SocketAddr, Error> {
+        Ok(net::SocketAddr::new(self.ip()?, self.get()?))
+    }
+
+    fn height(&mut self) -> Result<Height, Error> {
+        self.get()
+    }
+
+    fn bound(&mut self) -> Result<Bound<Height>, Error> {
+        match self.get::<u8>()? {
+            0 => Ok(Bound::Unbounded),
+            1 => Ok(Bound::Included(self.height()?)),
+            2 => Ok(Bound::Excluded(self.height()?)),
+            tag => Err(invalid("bound", tag)),
+        }
+    }
+
+    fn scripts(&mut self) -> Result<Vec<Script>, Error> {
+        self.list(|r| r.get())
+    }
+
+    fn message(&mut self) -> Result<NetworkMessage, Error> {
+        self.get::<RawNetworkMessage>().map(|msg| msg.payload)
+    }
+
+    /// Reasons are static strings in the protocol. Since replay logs are finite, the ones
+    /// read back are simply leaked.
+    fn reason(&mut self) -> Result<&'static str, Error> {
+        Ok(Box::leak(self.get::<String>()?.into_boxed_str()))
+    }
+
+    fn disconnect_reason(&mut self) -> Result<DisconnectReason, Error> {
+        let reason = match self.get::<u8>()? {
+            0 => DisconnectReason::PeerMisbehaving(self.reason()?),
+            1 => DisconnectReason::PeerProtocolVersion(self.get()?),
+            2 => DisconnectReason::PeerServices(self.get()?),
+            3 => DisconnectReason::PeerHeight(self.height()?),
+            4 => DisconnectReason::PeerMagic(self.get()?),
+            5 => DisconnectReason::PeerTimeout(self.reason()?),
+            6 => DisconnectReason::PeerBanned,
+            7 => DisconnectReason::PeerBlacklisted,
+            8 => DisconnectReason::PeerStaleBranch,
+            9 => DisconnectReason::PeerFlooding(self.reason()?),
+            10 => DisconnectReason::SelfConnection,
+            11 => DisconnectReason::ConnectionLimit,
+            12 => DisconnectReason::PeerEvicted,
+            13 => DisconnectReason::ConnectionError(self.get()?),
+            14 => DisconnectReason::Command,
+            15 => DisconnectReason::Other(self.reason()?),
+            tag => return Err(invalid("disconnect reason", tag)),
+        };
+        Ok(reason)
+    }
+
+    fn input(&mut self) -> Result<Input, Error> {
+        let input = match self.get::<u8>()? {
+            0 => Input::Connecting { addr: self.addr()? },
+            1 => Input::Connected {
+                addr: self.addr()?,
+                local_addr: self.addr()?,
+                link: if self.get()? {
+                    Link::Outbound
+                } else {
+                    Link::Inbound
+                },
+            },
+            2 => Input::Disconnected(self.addr()?, self.disconnect_reason()?),
+            3 => Input::Received(self.addr()?, self.get()?),
+            4 => Input::Sent(self.addr()?, self.get::<u64>()? as usize),
+            5 => Input::Command(self.command()?),
+            6 => Input::Tick,
+            tag => return Err(invalid("input", tag)),
+        };
+        Ok(input)
+    }
+
+    fn command(&mut self) -> Result<Command, Error> {
+        let cmd = match self.get::<u8>()? {
+            0 => Command::GetBlockByHeight(self.height()?, self.reply()),
+            1 => Command::GetBlockHeader(self.get()?, self.reply()),
+            2 => Command::GetPeers(self.get()?, self.reply()),
+            3 => Command::PeerInfo(self.reply()),
+            4 => Command::GetTip(self.reply()),
+            5 => Command::GetChainInfo(self.reply()),
+            6 => Command::GetChainWork(self.reply()),
+            7 => Command::GetMedianTimePast(self.height()?, self.reply()),
+            8 => Command::EstimateFeeRate(self.height()?, self.reply()),
+            9 => Command::GetConfig(self.reply()),
+            10 => Command::GetBlock(self.get()?, self.reply()),
+            11 => {
+                let ranges = self.list(|r| {
+                    Ok(Range {
+                        start: r.height()?,
+                        end: r.height()?,
+                    })
+                })?;
+                Command::GetFilters(ranges, self.reply())
+            }
+            12 => Command::Rescan {
+                from: self.bound()?,
+                to: self.bound()?,
+                watch: self.scripts()?,
+                reply: self.reply(),
+            },
+            13 => Command::RescanUntilConfirmed {
+                from: self.bound()?,
+                confirmations: self.height()?,
+                watch: self.scripts()?,
+                reply: self.reply(),
+            },
+            14 => Command::AbortRescan,
+            15 => Command::GetRescanStatus(self.reply()),
+            16 => Command::WatchScripts(self.get()?, self.scripts()?),
+            17 => Command::UnwatchScripts(self.get()?, self.scripts()?),
+            18 => Command::WatchOutpoints(self.list(|r| Ok((r.get()?, r.get()?)))?),
+            19 => {
+                let xpub = self.get::<Vec<u8>>()?;
+                let xpub = ExtendedPubKey::decode(&xpub)
+                    .map_err(|e| Error::Invalid(format!("invalid xpub: {}", e)))?;
+                let scheme = match self.get::<u8>()? {
+                    0 => DerivationScheme::Bip44,
+                    1 => DerivationScheme::Bip49,
+                    2 => DerivationScheme::Bip84,
+                    tag => return Err(invalid("derivation scheme", tag)),
+                };
+                Command::WatchXpub {
+                    xpub,
+                    scheme,
+                    gap_limit: self.get()?,
+                }
+            }
+            20 => Command::Query(self.message()?, self.reply()),
+            21 => Command::Connect(self.addr()?),
+            22 => Command::Disconnect(self.addr()?),
+            23 => Command::ImportHeaders(self.list(|r| r.get())?, self.reply()),
+            24 => Command::ImportAddresses(self.list(|r| r.get())?),
+            25 => Command::QueryAddresses(self.list(|r| r.addr())?, self.reply()),
+            26 => {
+                let tx = self.get()?;
+                let fee = if self.get()? { Some(self.get()?) } else { None };
+                Command::SubmitTransaction(tx, fee, self.get()?)
+            }
+            27 => Command::GetTxStatus(self.get()?, self.reply()),
+            28 => Command::Shutdown,
+            29 => Command::Tagged(self.get()?, Box::new(self.command()?), self.reply()),
+            30 => Command::Ban(self.ip()?, self.duration()?),
+            31 => Command::Unban(self.ip()?),
+            32 => Command::GetBanned(self.reply()),
+            33 => Command::GetAddresses(self.reply()),
+            34 => Command::MarkAddress(self.ip()?, self.get()?, self.reply()),
+            35 => {
+                let blocks = match self.get::<u8>()? {
+                    0 => Blocks::Range(Range {
+                        start: self.height()?,
+                        end: self.height()?,
+                    }),
+                    1 => Blocks::Hashes(self.list(|r| r.get())?),
+                    tag => return Err(invalid("blocks", tag)),
+                };
+                Command::GetBlocks(blocks, self.reply())
+            }
+            tag => return Err(invalid("command", tag)),
+        };
+        Ok(cmd)
+    }
+}
+
+impl<R: Read> Iterator for Replayer<R> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+fn invalid(what: &str, tag: u8) -> Error {
+    Error::Invalid(format!("unknown {} tag {}", what, tag))
+}
+
+/// Whether a command can be recorded. Commands carrying closures can't.
+fn is_recordable(cmd: &Command) -> bool {
+    match cmd {
+        Command::ReadSnapshot(_) | Command::Broadcast(..) => false,
+        Command::Tagged(_, cmd, _) => is_recordable(cmd),
+        _ => true,
+    }
+}
+
+fn encode_time(buf: &mut Vec<u8>, time: LocalTime) -> io::Result<()> {
+    let millis = (time - LocalTime::default()).as_millis() as u64;
+    millis.consensus_encode(buf).map(|_| ())
+}
+
+fn encode_list<T>(
+    buf: &mut Vec<u8>,
+    items: &[T],
+    f: impl Fn(&mut Vec<u8>, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    VarInt(items.len() as u64).consensus_encode(&mut *buf)?;
+    items.iter().try_for_each(|item| f(buf, item))
+}
+
+fn encode<T: Encodable>(buf: &mut Vec<u8>, value: &T) -> io::Result<()> {
+    value.consensus_encode(buf).map(|_| ())
+}
+
+fn encode_str(buf: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    encode(buf, &s.to_owned())
+}
+
+fn encode_duration(buf: &mut Vec<u8>, duration: LocalDuration) -> io::Result<()> {
+    (duration.as_millis() as u64)
+        .consensus_encode(buf)
+        .map(|_| ())
+}
+
+fn encode_ip(buf: &mut Vec<u8>, ip: &net::IpAddr) {
+    match ip {
+        net::IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        net::IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: &net::SocketAddr) -> io::Result<()> {
+    encode_ip(buf, &addr.ip());
+    encode(buf, &addr.port())
+}
+
+fn encode_bound(buf: &mut Vec<u8>, bound: &Bound<Height>) -> io::Result<()> {
+    match bound {
+        Bound::Unbounded => encode(buf, &0u8),
+        Bound::Included(h) => encode(buf, &1u8).and_then(|_| encode(buf, h)),
+        Bound::Excluded(h) => encode(buf, &2u8).and_then(|_| encode(buf, h)),
+    }
+}
+
+fn encode_scripts(buf: &mut Vec<u8>, scripts: &[Script]) -> io::Result<()> {
+    encode_list(buf, scripts, encode)
+}
+
+/// Network messages are only encodable with a network magic, which isn't used here.
+fn encode_message(buf: &mut Vec<u8>, payload: &NetworkMessage) -> io::Result<()> {
+    encode(
+        buf,
+        &RawNetworkMessage {
+            magic: 0,
+            payload: payload.clone(),
+        },
+    )
+}
+
+fn encode_disconnect_reason(buf: &mut Vec<u8>, reason: &DisconnectReason) -> io::Result<()> {
+    match reason {
+        DisconnectReason::PeerMisbehaving(r) => encode(buf, &0u8).and_then(|_| encode_str(buf, r)),
+        DisconnectReason::PeerProtocolVersion(v) => encode(buf, &1u8).and_then(|_| encode(buf, v)),
+        DisconnectReason::PeerServices(s) => encode(buf, &2u8).and_then(|_| encode(buf, s)),
+        DisconnectReason::PeerHeight(h) => encode(buf, &3u8).and_then(|_| encode(buf, h)),
+        DisconnectReason::PeerMagic(m) => encode(buf, &4u8).and_then(|_| encode(buf, m)),
+        DisconnectReason::PeerTimeout(r) => encode(buf, &5u8).and_then(|_| encode_str(buf, r)),
+        DisconnectReason::PeerBanned => encode(buf, &6u8),
+        DisconnectReason::PeerBlacklisted => encode(buf, &7u8),
+        DisconnectReason::PeerStaleBranch => encode(buf, &8u8),
+        DisconnectReason::PeerFlooding(r) => encode(buf, &9u8).and_then(|_| encode_str(buf, r)),
+        DisconnectReason::SelfConnection => encode(buf, &10u8),
+        DisconnectReason::ConnectionLimit => encode(buf, &11u8),
+        DisconnectReason::PeerEvicted => encode(buf, &12u8),
+        DisconnectReason::ConnectionError(e) => encode(buf, &13u8).and_then(|_| encode(buf, e)),
+        DisconnectReason::Command => encode(buf, &14u8),
+        DisconnectReason::Other(r) => encode(buf, &15u8).and_then(|_| encode_str(buf, r)),
+    }
+}
+
+fn encode_input(buf: &mut Vec<u8>, input: &Input) -> io::Result<()> {
+    match input {
+        Input::Connecting { addr } => {
+            buf.push(0);
+            encode_addr(buf, addr)
+        }
+        Input::Connected {
+            addr,
+            local_addr,
+            link,
+        } => {
+            buf.push(1);
+            encode_addr(buf, addr)?;
+            encode_addr(buf, local_addr)?;
+            encode(buf, &link.is_outbound())
+        }
+        Input::Disconnected(addr, reason) => {
+            buf.push(2);
+            encode_addr(buf, addr)?;
+            encode_disconnect_reason(buf, reason)
+        }
+        Input::Received(addr, msg) => {
+            buf.push(3);
+            encode_addr(buf, addr)?;
+            encode(buf, msg)
+        }
+        Input::Sent(addr, n) => {
+            buf.push(4);
+            encode_addr(buf, addr)?;
+            encode(buf, &(*n as u64))
+        }
+        Input::Command(cmd) => {
+            buf.push(5);
+            encode_command(buf, cmd)
+        }
+        Input::Tick => {
+            buf.push(6);
+            Ok(())
+        }
+    }
+}
+
+fn encode_command(buf: &mut Vec<u8>, cmd: &Command) -> io::Result<()> {
+    match cmd {
+        Command::GetBlockByHeight(height, _) => {
+            buf.push(0);
+            encode(buf, height)
+        }
+        Command::GetBlockHeader(hash, _) => {
+            buf.push(1);
+            encode::<BlockHash>(buf, hash)
+        }
+        Command::GetPeers(services, _) => {
+            buf.push(2);
+            encode::<ServiceFlags>(buf, services)
+        }
+        Command::PeerInfo(_) => {
+            buf.push(3);
+            Ok(())
+        }
+        Command::GetTip(_) => {
+            buf.push(4);
+            Ok(())
+        }
+        Command::GetChainInfo(_) => {
+            buf.push(5);
+            Ok(())
+        }
+        Command::GetChainWork(_) => {
+            buf.push(6);
+            Ok(())
+        }
+        Command::GetMedianTimePast(height, _) => {
+            buf.push(7);
+            encode(buf, height)
+        }
+        Command::EstimateFeeRate(height, _) => {
+            buf.push(8);
+            encode(buf, height)
+        }
+        Command::GetConfig(_) => {
+            buf.push(9);
+            Ok(())
+        }
+        Command::GetBlock(hash, _) => {
+            buf.push(10);
+            encode(buf, hash)
+        }
+        Command::GetFilters(ranges, _) => {
+            buf.push(11);
+            encode_list(buf, ranges, |buf, range| {
+                encode(buf, &range.start)?;
+                encode(buf, &range.end)
+            })
+        }
+        Command::Rescan {
+            from, to, watch, ..
+        } => {
+            buf.push(12);
+            encode_bound(buf, from)?;
+            encode_bound(buf, to)?;
+            encode_scripts(buf, watch)
+        }
+        Command::RescanUntilConfirmed {
+            from,
+            confirmations,
+            watch,
+            ..
+        } => {
+            buf.push(13);
+            encode_bound(buf, from)?;
+            encode(buf, confirmations)?;
+            encode_scripts(buf, watch)
+        }
+        Command::AbortRescan => {
+            buf.push(14);
+            Ok(())
+        }
+        Command::GetRescanStatus(_) => {
+            buf.push(15);
+            Ok(())
+        }
+        Command::WatchScripts(id, scripts) => {
+            buf.push(16);
+            encode(buf, id)?;
+            encode_scripts(buf, scripts)
+        }
+        Command::UnwatchScripts(id, scripts) => {
+            buf.push(17);
+            encode(buf, id)?;
+            encode_scripts(buf, scripts)
+        }
+        Command::WatchOutpoints(outpoints) => {
+            buf.push(18);
+            encode_list(buf, outpoints, |buf, (outpoint, script)| {
+                encode::<OutPoint>(buf, outpoint)?;
+                encode(buf, script)
+            })
+        }
+        Command::WatchXpub {
+            xpub,
+            scheme,
+            gap_limit,
+        } => {
+            buf.push(19);
+            encode(buf, &xpub.encode().to_vec())?;
+            buf.push(match scheme {
+                DerivationScheme::Bip44 => 0,
+                DerivationScheme::Bip49 => 1,
+                DerivationScheme::Bip84 => 2,
+            });
+            encode(buf, gap_limit)
+        }
+        Command::Query(msg, _) => {
+            buf.push(20);
+            encode_message(buf, msg)
+        }
+        Command::Connect(addr) => {
+            buf.push(21);
+            encode_addr(buf, addr)
+        }
+        Command::Disconnect(addr) => {
+            buf.push(22);
+            encode_addr(buf, addr)
+        }
+        Command::ImportHeaders(headers, _) => {
+            buf.push(23);
+            encode_list::<BlockHeader>(buf, headers, encode)
+        }
+        Command::ImportAddresses(addrs) => {
+            buf.push(24);
+            encode_list::<Address>(buf, addrs, encode)
+        }
+        Command::QueryAddresses(addrs, _) => {
+            buf.push(25);
+            encode_list(buf, addrs, encode_addr)
+        }
+        Command::SubmitTransaction(tx, fee, private) => {
+            buf.push(26);
+            encode::<Transaction>(buf, tx)?;
+            match fee {
+                Some(fee) => encode(buf, &true).and_then(|_| encode(buf, fee))?,
+                None => encode(buf, &false)?,
+            }
+            encode(buf, private)
+        }
+        Command::GetTxStatus(txid, _) => {
+            buf.push(27);
+            encode::<Txid>(buf, txid)
+        }
+        Command::Shutdown => {
+            buf.push(28);
+            Ok(())
+        }
+        Command::Tagged(epoch, cmd, _) => {
+            buf.push(29);
+            encode(buf, epoch)?;
+            encode_command(buf, cmd)
+        }
+        Command::Ban(ip, duration) => {
+            buf.push(30);
+            encode_ip(buf, ip);
+            encode_duration(buf, *duration)
+        }
+        Command::Unban(ip) => {
+            buf.push(31);
+            encode_ip(buf, ip);
+            Ok(())
+        }
+        Command::GetBanned(_) => {
+            buf.push(32);
+            Ok(())
+        }
+        Command::GetAddresses(_) => {
+            buf.push(33);
+            Ok(())
+        }
+        Command::MarkAddress(ip, good, _) => {
+            buf.push(34);
+            encode_ip(buf, ip);
+            encode(buf, good)
+        }
+        Command::GetBlocks(blocks, _) => {
+            buf.push(35);
+            match blocks {
+                Blocks::Range(range) => {
+                    buf.push(0);
+                    encode(buf, &range.start)?;
+                    encode(buf, &range.end)
+                }
+                Blocks::Hashes(hashes) => {
+                    buf.push(1);
+                    encode_list(buf, hashes, encode)
+                }
+            }
+        }
+        Command::ReadSnapshot(_) | Command::Broadcast(..) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "command can't be recorded",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use nakamoto_chain::block::cache::BlockCache;
+    use nakamoto_chain::block::store;
+    use nakamoto_chain::filter::cache::{FilterCache, StoredHeader};
+    use nakamoto_common::block::time::AdjustedTime;
+    use nakamoto_common::network::Network;
+    use nakamoto_common::p2p::peer::KnownAddress;
+
+    use bitcoin::network::message_network::VersionMessage;
+
+    use crate::protocol::{Config, Out, SnapshotReader};
+
+    /// A writer that can be read back from while it's being written to.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(
+        seed: u64,
+        time: LocalTime,
+    ) -> (
+        Protocol<
+            BlockCache<store::Memory<BlockHeader>>,
+            FilterCache<store::Memory<StoredHeader>>,
+            HashMap<net::IpAddr, KnownAddress>,
+        >,
+        chan::Receiver<Out>,
+    ) {
+        let network = Network::Regtest;
+        let (tx, rx) = chan::unbounded();
+        let tree =
+            BlockCache::from(store::Memory::genesis(network), network.params(), &[]).unwrap();
+        let filters = FilterCache::from(store::Memory::genesis(network)).unwrap();
+        let cfg = Config {
+            network,
+            ..Config::default()
+        };
+        let protocol = Protocol::new(
+            tree,
+            filters,
+            HashMap::new(),
+            AdjustedTime::new(time),
+            fastrand::Rng::with_seed(seed),
+            cfg,
+            tx,
+        );
+        (protocol, rx)
+    }
+
+    fn outputs(rx: &chan::Receiver<Out>) -> Vec<String> {
+        rx.try_iter().map(|o| format!("{:?}", o)).collect()
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let seed = 42;
+        let time = LocalTime::from_secs(1_600_000_000);
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let local: net::SocketAddr = ([127, 0, 0, 1], 8333).into();
+        let log = Shared::default();
+
+        let (protocol, rx) = setup(seed, time);
+        let mut protocol = protocol.with_recorder(Recorder::new(log.clone(), seed).unwrap());
+        let version = NetworkMessage::Version(VersionMessage {
+            version: 70016,
+            services: ServiceFlags::NETWORK,
+            timestamp: time.block_time() as i64,
+            receiver: Address::new(&local, ServiceFlags::NONE),
+            sender: Address::new(&remote, ServiceFlags::NETWORK),
+            nonce: 1,
+            user_agent: String::from("/test/"),
+            start_height: 0,
+            relay: false,
+        });
+        let (reply, _reply) = chan::unbounded();
+
+        protocol.initialize(time);
+        protocol.step(
+            Input::Connected {
+                addr: remote,
+                local_addr: local,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        protocol.step(
+            Input::Received(
+                remote,
+                RawNetworkMessage {
+                    magic: Network::Regtest.magic(),
+                    payload: version,
+                },
+            ),
+            time + LocalDuration::from_secs(1),
+        );
+        protocol.step(
+            Input::Command(Command::ReadSnapshot(SnapshotReader::new(|_| {}))),
+            time + LocalDuration::from_secs(2),
+        );
+        protocol.step(
+            Input::Command(Command::Tagged(
+                0,
+                Box::new(Command::GetTip(reply)),
+                chan::unbounded().0,
+            )),
+            time + LocalDuration::from_secs(3),
+        );
+        protocol.step(
+            Input::Disconnected(remote, DisconnectReason::PeerTimeout("handshake")),
+            time + LocalDuration::from_secs(4),
+        );
+        protocol.step(Input::Tick, time + LocalDuration::from_secs(60));
+
+        let expected = outputs(&rx);
+        let bytes = log.0.lock().unwrap().clone();
+        let mut replayer = Replayer::new(bytes.as_slice()).unwrap();
+        assert_eq!(replayer.seed(), seed);
+
+        let (mut protocol, rx) = setup(replayer.seed(), time);
+        assert_eq!(replayer.run(&mut protocol).unwrap(), 6);
+        assert_eq!(outputs(&rx), expected);
+
+        let entries = Replayer::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(matches!(entries[3], Entry::Unrecorded(..)));
+        assert!(matches!(
+            entries[5],
+            Entry::Step(
+                _,
+                Input::Disconnected(_, DisconnectReason::PeerTimeout("handshake"))
+            )
+        ));
+    }
+
+    #[test]
+    fn test_invalid_log() {
+        assert!(matches!(
+            Replayer::new(&b"NOTALOG\0\x01\0\0\0"[..]),
+            Err(Error::Invalid(_))
+        ));
+    }
+}
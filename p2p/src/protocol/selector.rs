@@ -0,0 +1,157 @@
+//! Peer selection for requests.
+//!
+//! Requests for headers or filters up to a given height are only ever sent to peers known
+//! to be synced up to that height. Out of these, peers are picked at random, weighted by
+//! their past performance: peers that respond faster are picked more often, and peers that
+//! let requests time out are picked less often.
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::block::Height;
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// Latency assumed for peers we haven't measured yet.
+pub const DEFAULT_LATENCY: LocalDuration = LocalDuration::from_secs(1);
+
+/// Weight of a peer with a latency of one millisecond.
+const MAX_WEIGHT: u64 = 1_000_000;
+/// Maximum number of timeouts counted against a peer. Each one halves its weight.
+const MAX_TIMEOUTS: u32 = 8;
+
+/// Past performance of a peer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Performance {
+    /// Moving average of the peer's response time.
+    latency: Option<LocalDuration>,
+    /// Requests that timed out recently.
+    timeouts: u32,
+}
+
+/// Selects peers to send requests to.
+#[derive(Debug)]
+pub struct PeerSelector {
+    peers: HashMap<PeerId, Performance>,
+    rng: fastrand::Rng,
+}
+
+impl PeerSelector {
+    /// Create a new peer selector.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            peers: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// Record the time it took a peer to respond, eg. to a request or a ping. A response
+    /// also makes up for one past timeout.
+    pub fn peer_responded(&mut self, peer: &PeerId, latency: LocalDuration) {
+        let perf = self.peers.entry(*peer).or_default();
+
+        perf.latency = Some(match perf.latency {
+            // Exponential moving average, giving a weight of 1/4 to the latest sample.
+            Some(avg) => {
+                LocalDuration::from_millis((avg.as_millis() * 3 + latency.as_millis()) / 4)
+            }
+            None => latency,
+        });
+        perf.timeouts = perf.timeouts.saturating_sub(1);
+    }
+
+    /// Record that a request to a peer timed out.
+    pub fn peer_timed_out(&mut self, peer: &PeerId) {
+        let perf = self.peers.entry(*peer).or_default();
+
+        perf.timeouts = (perf.timeouts + 1).min(MAX_TIMEOUTS);
+    }
+
+    /// Forget about a peer, eg. when it disconnects.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// The weight of a peer, ie. how likely it is to be selected, relative to other peers.
+    pub fn weight(&self, peer: &PeerId) -> u64 {
+        let perf = self.peers.get(peer).copied().unwrap_or_default();
+        let latency = perf.latency.unwrap_or(DEFAULT_LATENCY).as_millis().max(1) as u64;
+
+        ((MAX_WEIGHT / latency) >> perf.timeouts).max(1)
+    }
+
+    /// Select a peer synced up to the given height, out of the given candidates and their
+    /// heights. Returns `None` if no candidate is synced up to that height.
+    pub fn select<'a>(
+        &self,
+        candidates: impl IntoIterator<Item = (&'a PeerId, Height)>,
+        height: Height,
+    ) -> Option<PeerId> {
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|(_, h)| *h >= height)
+            .map(|(peer, _)| (*peer, self.weight(peer)))
+            .collect::<Vec<_>>();
+        // Keep the selection deterministic for a given random state.
+        candidates.sort_unstable();
+
+        let total = candidates.iter().map(|(_, w)| w).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut n = self.rng.u64(..total);
+
+        for (peer, weight) in candidates {
+            if n < weight {
+                return Some(peer);
+            }
+            n -= weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_height() {
+        let selector = PeerSelector::new(fastrand::Rng::new());
+        let alice = ([127, 0, 0, 1], 8333).into();
+        let bob = ([127, 0, 0, 2], 8333).into();
+        let peers = vec![(&alice, 100), (&bob, 200)];
+
+        for _ in 0..32 {
+            assert_eq!(selector.select(peers.clone(), 150), Some(bob));
+        }
+        assert_eq!(selector.select(peers, 201), None);
+    }
+
+    #[test]
+    fn test_select_performance() {
+        let mut selector = PeerSelector::new(fastrand::Rng::with_seed(1));
+        let fast = ([127, 0, 0, 1], 8333).into();
+        let slow = ([127, 0, 0, 2], 8333).into();
+        let flaky = ([127, 0, 0, 3], 8333).into();
+        let peers = vec![(&fast, 100), (&slow, 100), (&flaky, 100)];
+
+        selector.peer_responded(&fast, LocalDuration::from_millis(50));
+        selector.peer_responded(&slow, LocalDuration::from_millis(500));
+        selector.peer_responded(&flaky, LocalDuration::from_millis(50));
+        selector.peer_timed_out(&flaky);
+        selector.peer_timed_out(&flaky);
+        selector.peer_timed_out(&flaky);
+        selector.peer_timed_out(&flaky);
+
+        assert!(selector.weight(&fast) > selector.weight(&flaky));
+        assert!(selector.weight(&flaky) > 0);
+
+        let mut counts = HashMap::with_hasher(fastrand::Rng::new().into());
+        for _ in 0..1000 {
+            *counts
+                .entry(selector.select(peers.clone(), 0).unwrap())
+                .or_insert(0) += 1;
+        }
+        assert!(counts[&fast] > counts[&slow] * 5);
+        assert!(counts[&fast] > counts[&flaky] * 5);
+    }
+}
@@ -1,4 +1,51 @@
 //! A simple P2P network simulator. Acts as the _reactor_, but without doing any I/O.
+//!
+//! Since the protocol is I/O-free and deterministic, a [`Simulation`] can drive any number
+//! of protocol instances through a simulated network, with latency and I/O failures, from
+//! a single random seed. This makes it suitable for property tests and fuzzers: arbitrary
+//! inputs, eg. messages or commands, can be scheduled with [`Simulation::input`], and
+//! failing runs can always be reproduced.
+//!
+//! Requires the `simulator` feature.
+//!
+//! ```
+//! use nakamoto_chain::block::{cache::BlockCache, store};
+//! use nakamoto_chain::filter::cache::FilterCache;
+//! use nakamoto_common::block::time::LocalTime;
+//! use nakamoto_common::collections::HashMap;
+//! use nakamoto_common::network::Network;
+//! use nakamoto_p2p::protocol::simulator::{Instance, Options, Simulation};
+//! use nakamoto_p2p::event::Event;
+//! use nakamoto_p2p::protocol::{peermgr, Command, Config, Input};
+//!
+//! let network = Network::Regtest;
+//! let rng = fastrand::Rng::with_seed(1);
+//! let time = LocalTime::from_block_time(network.genesis().time);
+//! let node = |name, ip: [u8; 4]| {
+//!     let headers = store::Memory::new((network.genesis(), vec![]).into());
+//!     let tree = BlockCache::from(headers, network.params(), &[]).unwrap();
+//!     let filters = FilterCache::from(store::Memory::default()).unwrap();
+//!     let peers = HashMap::with_hasher(rng.clone().into());
+//!     let cfg = Config::from(name, network, vec![]);
+//!
+//!     Instance::new((ip, network.port()).into(), tree, filters, peers, cfg, rng.clone(), time)
+//! };
+//! let mut alice = node("alice", [48, 48, 48, 48]);
+//! let mut bob = node("bob", [97, 97, 97, 97]);
+//! let mut sim = Simulation::new(time, rng.clone(), Options::default());
+//!
+//! sim.initialize([&mut alice, &mut bob]);
+//! sim.input(alice.addr.ip(), Input::Command(Command::Connect(bob.addr)));
+//!
+//! while sim.step([&mut alice, &mut bob]) {
+//!     if sim.events().any(|(node, e)| {
+//!         node == alice.addr.ip()
+//!             && matches!(e, Event::PeerManager(peermgr::Event::PeerNegotiated { .. }))
+//!     }) {
+//!         break;
+//!     }
+//! }
+//! ```
 use super::*;
 
 use nakamoto_common::collections::HashMap;
@@ -11,7 +58,77 @@ pub const MIN_LATENCY: LocalDuration = LocalDuration::from_millis(1);
 
 /// Identifier for a simulated node/peer.
 /// The simulator requires each peer to have a distinct IP address.
-type NodeId = std::net::IpAddr;
+pub type NodeId = std::net::IpAddr;
+
+/// A simulated node, driving a protocol instance.
+pub trait Node {
+    /// The node's address. Each node must have a distinct IP address.
+    fn addr(&self) -> PeerId;
+    /// Initialize the node's protocol, if it wasn't already.
+    fn initialize(&mut self, time: LocalTime);
+    /// Feed an input to the node's protocol.
+    fn step(&mut self, input: Input, time: LocalTime);
+    /// Take the outputs of the node's protocol, since the last call.
+    fn outputs(&mut self) -> Vec<Out>;
+}
+
+/// A protocol instance that can be simulated.
+#[derive(Debug)]
+pub struct Instance<T, F, P> {
+    /// The node's address.
+    pub addr: PeerId,
+    /// The protocol instance.
+    pub protocol: Protocol<T, F, P>,
+
+    outputs: chan::Receiver<Out>,
+    initialized: bool,
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Instance<T, F, P> {
+    /// Create a new protocol instance to simulate, with its clock set to the given time.
+    pub fn new(
+        addr: PeerId,
+        tree: T,
+        filters: F,
+        peers: P,
+        config: Config,
+        rng: fastrand::Rng,
+        time: LocalTime,
+    ) -> Self {
+        let (tx, rx) = chan::unbounded();
+        let clock = AdjustedTime::new(time);
+        let protocol = Protocol::new(tree, filters, peers, clock, rng, config, tx);
+
+        Self {
+            addr,
+            protocol,
+            outputs: rx,
+            initialized: false,
+        }
+    }
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Node for Instance<T, F, P> {
+    fn addr(&self) -> PeerId {
+        self.addr
+    }
+
+    fn initialize(&mut self, time: LocalTime) {
+        if !self.initialized {
+            self.initialized = true;
+            self.protocol.initialize(time);
+        }
+    }
+
+    fn step(&mut self, input: Input, time: LocalTime) {
+        self.initialize(time);
+        self.protocol.step(input, time);
+    }
+
+    fn outputs(&mut self) -> Vec<Out> {
+        self.outputs.try_iter().collect()
+    }
+}
 
 /// A scheduled protocol input.
 #[derive(Debug, Clone)]
@@ -36,9 +153,7 @@ impl fmt::Display for Scheduled {
                     }
                     NetworkMessage::Verack
                     | NetworkMessage::SendHeaders
-                    | NetworkMessage::GetAddr => {
-                        format!("")
-                    }
+                    | NetworkMessage::GetAddr => String::new(),
                     msg => {
                         format!("{:?}", msg)
                     }
@@ -83,7 +198,6 @@ pub struct Inbox {
 
 impl Inbox {
     /// Iterate over all scheduled inputs.
-    #[allow(dead_code)]
     pub fn iter(&self) -> impl Iterator<Item = &Scheduled> {
         self.messages.values()
     }
@@ -107,6 +221,7 @@ impl Inbox {
 }
 
 /// Simulation options.
+#[derive(Debug, Clone)]
 pub struct Options {
     /// Minimum and maximum latency between nodes, in seconds.
     pub latency: Range<u64>,
@@ -140,6 +255,8 @@ pub struct Simulation {
     start_time: LocalTime,
     /// Current simulation time. Updated when a scheduled message is processed.
     time: LocalTime,
+    /// Events emitted by the nodes, not yet taken with [`Simulation::events`].
+    events: Vec<(NodeId, Event)>,
     /// RNG.
     rng: fastrand::Rng,
 }
@@ -156,6 +273,7 @@ impl Simulation {
             opts,
             start_time: time,
             time,
+            events: Vec::new(),
             rng,
         }
     }
@@ -166,14 +284,12 @@ impl Simulation {
     }
 
     /// Total amount of simulated time elapsed.
-    #[allow(dead_code)]
     pub fn elapsed(&self) -> LocalDuration {
         self.time - self.start_time
     }
 
     /// Check whether the simulation has settled, ie. the only messages left to process
     /// are (periodic) timeouts.
-    #[allow(dead_code)]
     pub fn is_settled(&self) -> bool {
         self.inbox
             .messages
@@ -206,23 +322,54 @@ impl Simulation {
             .unwrap_or_else(|| MIN_LATENCY)
     }
 
+    /// Take the events emitted by the nodes so far, in the order they were emitted.
+    pub fn events(&mut self) -> impl Iterator<Item = (NodeId, Event)> + '_ {
+        self.events.drain(..)
+    }
+
+    /// Current simulation time.
+    pub fn time(&self) -> LocalTime {
+        self.time
+    }
+
     /// Initialize peers.
-    pub fn initialize<'a>(&self, peers: impl IntoIterator<Item = &'a mut super::Peer<Protocol>>) {
+    pub fn initialize<'a, N: Node + 'a>(&self, peers: impl IntoIterator<Item = &'a mut N>) {
         for peer in peers.into_iter() {
-            peer.initialize();
+            peer.initialize(self.time);
         }
     }
 
+    /// Schedule an input to be delivered to a node, eg. a command or a message from a peer.
+    /// The input is delivered after the minimum latency.
+    pub fn input(&mut self, node: NodeId, input: Input) {
+        self.input_after(node, input, MIN_LATENCY);
+    }
+
+    /// Schedule an input to be delivered to a node, after the given delay.
+    pub fn input_after(&mut self, node: NodeId, input: Input, delay: LocalDuration) {
+        let remote = match &input {
+            Input::Connecting { addr } => *addr,
+            Input::Connected { addr, .. } => *addr,
+            Input::Disconnected(addr, _) | Input::Received(addr, _) | Input::Sent(addr, _) => *addr,
+            Input::Command(_) | Input::Tick => ([0, 0, 0, 0], 0).into(),
+        };
+        self.inbox.insert(
+            self.time + delay,
+            Scheduled {
+                node,
+                remote,
+                input,
+            },
+        );
+    }
+
     /// Process one scheduled input from the inbox, using the provided peers.
     /// This function should be called until it returns `false`, or some desired state is reached.
     /// Returns `true` if there are more messages to process.
-    pub fn step<'a>(
-        &mut self,
-        peers: impl IntoIterator<Item = &'a mut super::Peer<Protocol>>,
-    ) -> bool {
+    pub fn step<'a, N: Node + 'a>(&mut self, peers: impl IntoIterator<Item = &'a mut N>) -> bool {
         let mut nodes = HashMap::with_hasher(self.rng.clone().into());
         for peer in peers.into_iter() {
-            nodes.insert(peer.addr.ip(), peer);
+            nodes.insert(peer.addr().ip(), peer);
         }
 
         if !self.opts.latency.is_empty() {
@@ -242,9 +389,11 @@ impl Simulation {
         }
 
         // Schedule any messages in the pipes.
-        for peer in nodes.values() {
-            for o in peer.upstream.try_iter() {
-                self.schedule(&peer.addr.ip(), o);
+        for peer in nodes.values_mut() {
+            let node = peer.addr().ip();
+
+            for o in peer.outputs() {
+                self.schedule(&node, o);
             }
         }
 
@@ -263,8 +412,8 @@ impl Simulation {
             let Scheduled { input, node, .. } = next;
 
             if let Some(ref mut p) = nodes.get_mut(&node) {
-                p.protocol.step(input, self.time);
-                for o in p.upstream.try_iter() {
+                p.step(input, self.time);
+                for o in p.outputs() {
                     self.schedule(&node, o);
                 }
             } else {
@@ -284,7 +433,7 @@ impl Simulation {
         match out {
             Out::Message(receiver, msg) => {
                 // If the other end has disconnected the sender with some latency, there may not be
-                // a connection remaining to use.https://github.com/maateen/battery-monitor
+                // a connection remaining to use.
                 if let Some((sender_addr, _)) = self.connections.get(&(node, receiver.ip())) {
                     // Randomly fail to send a message based on the failure rate.
                     if self.is_fallible() {
@@ -315,7 +464,7 @@ impl Simulation {
                     );
                 }
             }
-            Out::Connect(remote, _timeout) => {
+            Out::Connect(remote, _timeout, _proxy) => {
                 assert!(remote.ip() != node, "self-connections are not allowed");
 
                 // Create an ephemeral sockaddr for the connecting (local) node.
@@ -429,11 +578,11 @@ impl Simulation {
                     },
                 );
             }
-            Out::Event(_) => {
-                // Ignored.
+            Out::Event(event) => {
+                self.events.push((node, event));
             }
             Out::Shutdown => {
-                unimplemented! {}
+                // Ignored. The node keeps receiving the inputs scheduled for it.
             }
         }
     }
@@ -2,37 +2,88 @@
 //!
 //! Manages BIP 157/8 compact block filter sync.
 //!
+pub mod xpub;
 
-use std::ops::Range;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ops::{Bound, Range};
 
-use nonempty::NonEmpty;
 use thiserror::Error;
 
+use bitcoin::consensus::encode;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::network::constants::ServiceFlags;
-use bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders};
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{OutPoint, Script, TxOut, Txid};
 
-use nakamoto_common::block::filter::{self, BlockFilter, Filters};
+use nakamoto_common::block::filter::{
+    self, BlockFilter, FilterHash, FilterHeader, FilterMatcher, Filters,
+};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockTree;
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Block, BlockHash, BlockTime, Height};
 use nakamoto_common::collections::HashMap;
 use nakamoto_common::source;
 
 use super::channel::SetTimeout;
+use super::selector::PeerSelector;
 use super::{Link, PeerId, Timeout};
 
+use xpub::{DerivationScheme, Xpub};
+
 /// Idle timeout.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
 
-/// Services required from peers for SPV functionality.
+/// Default services required from peers for SPV functionality.
 pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::COMPACT_FILTERS;
 
+/// Default minimum number of peers serving compact filters we'd like to be connected to.
+pub const MIN_PEERS: usize = 2;
+
 /// Maximum filter headers to be expected in a message.
 pub const MAX_MESSAGE_CFHEADERS: usize = 2000;
 
 /// Maximum filters to be expected in a message.
 pub const MAX_MESSAGE_CFILTERS: usize = 1000;
 
+/// Interval between the filter headers sent in a `cfcheckpt` message.
+pub const CFCHECKPT_INTERVAL: Height = 1000;
+
+/// Minimum number of heights pruned at once. Pruning may rewrite the filter stores, so
+/// it isn't done on every import.
+pub const PRUNE_INTERVAL: Height = 1000;
+
+/// Number of peers the filter of the newest block is requested from, when redundant
+/// filter requests are enabled.
+pub const REDUNDANT_FILTER_PEERS: usize = 2;
+
+/// Number of request timeouts a peer has to serve all the filters of a `getcfilters`
+/// request, however steadily it's making progress. Peers that trickle filters in are
+/// otherwise never found to stall.
+pub const FILTER_REQUEST_TIMEOUTS: u64 = 6;
+
+/// Default maximum number of filters a rescan buffers while waiting for a missing filter
+/// below them.
+pub const MAX_RESCAN_BUFFER: usize = 2 * MAX_MESSAGE_CFILTERS;
+
+/// Number of filters processed by a rescan between progress reports.
+pub const RESCAN_PROGRESS_INTERVAL: Height = 100;
+
+/// The wallet birth height is the first block with a median time past no earlier than
+/// this many seconds before the birthday. Block timestamps may be earlier than the time
+/// their transactions were made, and the median time past lags behind them.
+pub const BIRTHDAY_WINDOW: BlockTime = 2 * 60 * 60;
+
+/// Identifies a watchlist. Watchlist identifiers are chosen by the client, eg. one
+/// per wallet.
+pub type WatchlistId = u64;
+
+/// Watchlist used for the scripts passed to [`SpvManager::rescan`].
+pub const DEFAULT_WATCHLIST: WatchlistId = 0;
+
 /// An error originating in the SPV manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -70,6 +121,8 @@ pub enum Event {
         height: Height,
         /// Hash of corresponding block.
         block_hash: BlockHash,
+        /// Watchlists with at least one script matching the filter.
+        watchlists: Vec<WatchlistId>,
     },
     /// Filter headers were imported successfully.
     FilterHeadersImported {
@@ -94,14 +147,149 @@ pub enum Event {
     Synced(Height),
     /// A peer has timed out responding to a filter request.
     TimedOut(PeerId),
+    /// A peer stopped sending the filters it was asked for. The missing filters are
+    /// requested from another peer.
+    PeerStalling {
+        /// The stalling peer.
+        peer: PeerId,
+        /// Number of requested filters that were not received.
+        missing: usize,
+    },
     /// Block header chain rollback detected.
     RollbackDetected(Height),
+    /// Peers served conflicting filter headers. The filters of the first block the
+    /// headers disagree on are downloaded from each peer to find out which are valid.
+    FilterHeadersMismatch {
+        /// Height of the first block the headers disagree on.
+        height: Height,
+        /// Peers that served the headers.
+        peers: Vec<PeerId>,
+    },
+    /// The height of the wallet birthday was found, once the chain reached it. Filters
+    /// below it are no longer scanned. See [`Config::birthday`].
+    BirthHeightFound {
+        /// Birth height.
+        height: Height,
+    },
+    /// A rescan has started.
+    RescanStarted {
+        /// Start height.
+        start: Height,
+        /// End height, if known. In confirmation mode, the end height is only known once
+        /// a match is found.
+        end: Option<Height>,
+    },
+    /// A filter was processed as part of a rescan.
+    FilterProcessed {
+        /// Corresponding block hash.
+        block: BlockHash,
+        /// Filter height.
+        height: Height,
+        /// Whether the filter matched one of the watched scripts or outpoints.
+        matched: bool,
+        /// Watchlists with at least one script matching the filter.
+        watchlists: Vec<WatchlistId>,
+        /// Whether the filter matched the script of a watched outpoint, ie. the block may
+        /// spend it. Unlike a watchlist match, this doesn't signal funds received.
+        spends: bool,
+    },
+    /// The state of the active rescan, emitted periodically and on shutdown, from which the
+    /// rescan can be resumed. See [`Config::resume`].
+    RescanCheckpoint(RescanState),
+    /// A rescan was resumed from the state saved by an earlier rescan with the same
+    /// parameters, instead of starting over.
+    RescanResumed {
+        /// Next height to process.
+        current: Height,
+        /// Blocks matching a watchlist below the current height, that were not scanned
+        /// yet. These are scanned once received.
+        matched: Vec<(Height, BlockHash)>,
+    },
+    /// A rescan was completed, up to and including the given height.
+    RescanCompleted {
+        /// Last height scanned.
+        height: Height,
+    },
+    /// Periodic progress report of an active rescan.
+    RescanProgress {
+        /// Next height to process.
+        current: Height,
+        /// Height at which the rescan started.
+        start: Height,
+        /// End height, if known.
+        end: Option<Height>,
+        /// Number of filters requested from peers, that were not yet received.
+        remaining_requests: usize,
+    },
+    /// A rescan was aborted before completing.
+    RescanAborted {
+        /// Last height scanned.
+        height: Height,
+    },
+    /// More scripts were derived for a watched extended public key, since a filter
+    /// matched a script within the gap limit.
+    GapLimitExtended {
+        /// Watchlist the derived scripts were added to.
+        watchlist: WatchlistId,
+        /// Highest child index found in a matching filter.
+        used: u32,
+        /// Total number of scripts derived.
+        derived: u32,
+    },
+    /// A filter requested via [`SpvManager::get_filters`] is available. Within each
+    /// requested range, these are emitted in ascending height order.
+    FilterFetched {
+        /// The filter.
+        filter: BlockFilter,
+        /// Filter height.
+        height: Height,
+        /// Hash of corresponding block.
+        block_hash: BlockHash,
+    },
+    /// An output paying to a watched script was found in a matching block.
+    UtxoAdded {
+        /// Watchlist of the script the output pays to.
+        watchlist: WatchlistId,
+        /// The output's outpoint.
+        outpoint: OutPoint,
+        /// The output.
+        output: TxOut,
+        /// Height of the block the output was created in.
+        height: Height,
+    },
+    /// A watched outpoint was spent. The outpoint is no longer watched.
+    OutpointSpent {
+        /// The spent outpoint.
+        outpoint: OutPoint,
+        /// Transaction spending the outpoint.
+        txid: Txid,
+        /// Height of the block the outpoint was spent in.
+        height: Height,
+    },
+    /// A tracked output was spent in a matching block.
+    UtxoSpent {
+        /// Watchlist the output was tracked in.
+        watchlist: WatchlistId,
+        /// The spent output's outpoint.
+        outpoint: OutPoint,
+        /// The spent output.
+        output: TxOut,
+        /// Transaction spending the output.
+        txid: Txid,
+        /// Height of the block the output was spent in.
+        height: Height,
+    },
 }
 
 impl std::fmt::Display for Event {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Event::TimedOut(addr) => write!(fmt, "Peer {} timed out", addr),
+            Event::PeerStalling { peer, missing } => write!(
+                fmt,
+                "Peer {} stalled with {} filter(s) missing, requesting them from another peer",
+                peer, missing
+            ),
             Event::FilterReceived {
                 from,
                 height,
@@ -129,6 +317,13 @@ impl std::fmt::Display for Event {
                 "Syncing filter headers with {}, start = {}, stop = {}",
                 peer, start_height, stop_hash
             ),
+            Event::FilterHeadersMismatch { height, peers } => {
+                write!(
+                    fmt,
+                    "Filter headers served by {:?} disagree from height {}",
+                    peers, height
+                )
+            }
             Event::RequestCanceled { reason } => {
                 write!(fmt, "Request canceled: {}", reason)
             }
@@ -139,6 +334,122 @@ impl std::fmt::Display for Event {
                     height
                 )
             }
+            Event::BirthHeightFound { height } => {
+                write!(fmt, "Wallet birth height found at {}", height)
+            }
+            Event::RescanStarted {
+                start,
+                end: Some(end),
+            } => {
+                write!(fmt, "Rescan started from height {} to {}", start, end)
+            }
+            Event::RescanStarted { start, end: None } => {
+                write!(fmt, "Rescan started from height {}", start)
+            }
+            Event::FilterProcessed {
+                block,
+                height,
+                watchlists,
+                spends,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Filter {} processed for block {} (matched = {:?}, spends = {})",
+                    height, block, watchlists, spends
+                )
+            }
+            Event::RescanCheckpoint(state) => {
+                write!(fmt, "Rescan checkpoint at height {}", state.current)
+            }
+            Event::RescanResumed { current, matched } => write!(
+                fmt,
+                "Rescan resumed from height {} with {} matching block(s) to scan",
+                current,
+                matched.len()
+            ),
+            Event::RescanCompleted { height } => {
+                write!(fmt, "Rescan completed at height {}", height)
+            }
+            Event::RescanProgress {
+                current,
+                start,
+                end: Some(end),
+                remaining_requests,
+            } => {
+                write!(
+                    fmt,
+                    "Rescan at height {} ({}..={}), {} filter(s) pending",
+                    current, start, end, remaining_requests
+                )
+            }
+            Event::RescanProgress {
+                current,
+                start,
+                end: None,
+                remaining_requests,
+            } => {
+                write!(
+                    fmt,
+                    "Rescan at height {} ({}..), {} filter(s) pending",
+                    current, start, remaining_requests
+                )
+            }
+            Event::RescanAborted { height } => {
+                write!(fmt, "Rescan aborted at height {}", height)
+            }
+            Event::GapLimitExtended {
+                watchlist,
+                used,
+                derived,
+            } => {
+                write!(
+                    fmt,
+                    "Gap limit of watchlist {} extended to {} script(s) (used = {})",
+                    watchlist, derived, used
+                )
+            }
+            Event::FilterFetched {
+                height, block_hash, ..
+            } => {
+                write!(fmt, "Filter {} fetched for block {}", height, block_hash)
+            }
+            Event::UtxoAdded {
+                watchlist,
+                outpoint,
+                output,
+                height,
+            } => {
+                write!(
+                    fmt,
+                    "Output {} of {} sats added to watchlist {} at height {}",
+                    outpoint, output.value, watchlist, height
+                )
+            }
+            Event::OutpointSpent {
+                outpoint,
+                txid,
+                height,
+            } => {
+                write!(
+                    fmt,
+                    "Outpoint {} spent by {} at height {}",
+                    outpoint, txid, height
+                )
+            }
+            Event::UtxoSpent {
+                watchlist,
+                outpoint,
+                txid,
+                height,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Output {} of watchlist {} spent by {} at height {}",
+                    outpoint, watchlist, txid, height
+                )
+            }
         }
     }
 }
@@ -164,8 +475,14 @@ pub trait SyncFilters {
     );
     /// Send compact filter headers to a peer.
     fn send_cfheaders(&self, addr: PeerId, headers: CFHeaders);
+    /// Get compact filter header checkpoints from a peer, up to the stop hash.
+    fn get_cfcheckpt(&self, addr: PeerId, stop_hash: BlockHash, timeout: Timeout);
+    /// Send compact filter header checkpoints to a peer.
+    fn send_cfcheckpt(&self, addr: PeerId, checkpoints: CFCheckpt);
     /// Send a compact filter to a peer.
     fn send_cfilter(&self, addr: PeerId, filter: CFilter);
+    /// Get a block from a peer, to check the filters served for it.
+    fn get_block(&self, addr: PeerId, hash: BlockHash);
 }
 
 /// The ability to emit SPV related events.
@@ -180,9 +497,49 @@ pub enum GetFiltersError {
     /// The specified range is invalid, eg. it is out of bounds.
     #[error("the specified range is invalid")]
     InvalidRange,
-    /// Not connected to any compact filter peer.
+    /// Not connected to any compact filter peer synced up to the end of the range.
     #[error("not connected to any peer with compact filters support")]
     NotConnected,
+    /// A rescan is already in progress.
+    #[error("a rescan is already active")]
+    AlreadyActive,
+    /// The filter headers needed to check the filters in the range were pruned.
+    #[error("the filters in the specified range were pruned")]
+    Pruned,
+}
+
+/// Status of an active rescan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RescanStatus {
+    /// Next height to process.
+    pub current: Height,
+    /// Height at which the rescan started.
+    pub start: Height,
+    /// End height, if known.
+    pub end: Option<Height>,
+    /// Number of filters requested from peers, that were not yet received.
+    pub remaining_requests: usize,
+}
+
+/// State of a rescan, from which it can be resumed, eg. after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RescanState {
+    /// Identifies the rescan, by its bounds and the scripts and outpoints watched when it
+    /// started. Only a rescan with the same identifier is resumed from this state.
+    pub id: sha256::Hash,
+    /// Next height to process. All filters below were processed.
+    pub current: Height,
+    /// Last height to scan, if known.
+    pub end: Option<Height>,
+    /// Blocks matching a watchlist below the current height, that were not scanned yet.
+    pub matched: Vec<(Height, BlockHash)>,
+    /// Outpoints still watched for spends, with the script of the output.
+    pub outpoints: Vec<(OutPoint, Script)>,
+    /// Unspent outputs paying to watched scripts, by watchlist, with the height of the
+    /// block they were created in.
+    pub utxos: Vec<(WatchlistId, OutPoint, TxOut, Height)>,
+    /// Highest child index used, for every watched extended public key that was used.
+    pub xpubs: Vec<(WatchlistId, u32)>,
 }
 
 /// SPV manager configuration.
@@ -190,16 +547,67 @@ pub enum GetFiltersError {
 pub struct Config {
     /// How long to wait for a response from a peer.
     pub request_timeout: Timeout,
+    /// Whether to serve compact filters to peers. If set, all filters are downloaded
+    /// along with their headers, and stored.
+    pub serve_filters: bool,
+    /// Number of heights below the tip to keep filter headers and filters for. Older
+    /// ones are pruned, in steps of [`PRUNE_INTERVAL`], and can no longer be scanned.
+    /// If not set, nothing is pruned.
+    pub filter_window: Option<Height>,
+    /// Whether to request the filter of the newest block from [`REDUNDANT_FILTER_PEERS`]
+    /// peers at once. The first valid filter received is used, and the others are ignored.
+    pub redundant_filters: bool,
+    /// Services required of the peers filters are downloaded from.
+    pub required_services: ServiceFlags,
+    /// Minimum number of outbound peers to download filters from. If we have fewer, the
+    /// connection manager makes room for more.
+    pub min_peers: usize,
+    /// Maximum number of filters a rescan buffers while waiting for a missing filter below
+    /// them. Filters above are requested once the missing filter is received. When the
+    /// buffer is full, the missing filter is requested again from another peer.
+    pub rescan_buffer: usize,
+    /// Rescan state saved on the last shutdown. If the next rescan started has the same
+    /// identifier, it is resumed from there. See [`Event::RescanCheckpoint`].
+    pub resume: Option<RescanState>,
+    /// Wallet birthday. Rescans start no earlier than the birth height, and the filters
+    /// below it are pruned, since they can't match anything. If not set, any height may
+    /// be scanned.
+    pub birthday: Option<Birthday>,
+    /// Trusted filter header checkpoints, eg. [`Network::filter_checkpoints`]. Filter
+    /// headers and checkpoints served by peers that contradict them are rejected.
+    ///
+    /// [`Network::filter_checkpoints`]: nakamoto_common::network::Network::filter_checkpoints
+    pub filter_checkpoints: Vec<(Height, FilterHeader)>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             request_timeout: Timeout::from_secs(30),
+            serve_filters: false,
+            filter_window: None,
+            redundant_filters: false,
+            required_services: REQUIRED_SERVICES,
+            min_peers: MIN_PEERS,
+            rescan_buffer: MAX_RESCAN_BUFFER,
+            resume: None,
+            birthday: None,
+            filter_checkpoints: Vec::new(),
         }
     }
 }
 
+/// Wallet birthday, ie. the earliest point in the chain at which funds may have been
+/// received by the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Birthday {
+    /// Block height.
+    Height(Height),
+    /// Time, eg. when the wallet seed was created. This is converted to a height using the
+    /// block header timestamps, once the chain has reached it.
+    Time(LocalTime),
+}
+
 /// A SPV peer.
 #[derive(Debug)]
 struct Peer {
@@ -207,6 +615,395 @@ struct Peer {
     last_active: LocalTime,
 }
 
+/// Filter (re)scan state.
+#[derive(Debug)]
+struct Rescan {
+    /// Whether a rescan is currently in progress.
+    active: bool,
+    /// Next height to process.
+    current: Height,
+    /// Next height to request filters from.
+    requested: Height,
+    /// Height at which the rescan started.
+    start: Height,
+    /// Whether the rescan waits for the wallet birth height to be known before it scans
+    /// anything, since it can't start before it. See [`Config::birthday`].
+    deferred: bool,
+    /// Identifier of the rescan. See [`RescanState::id`].
+    id: sha256::Hash,
+    /// Last height to scan, inclusive. If `None`, the rescan continues with new blocks
+    /// as they are added to the chain.
+    end: Option<Height>,
+    /// If set, the end of the rescan is not fixed, but is extended every time a filter
+    /// matches, so that the matching block is buried under this many confirmations.
+    confirmations: Option<Height>,
+    /// Scripts being watched, by watchlist. Unlike the rest of the rescan state, these
+    /// are kept from one rescan to the next.
+    watchlists: BTreeMap<WatchlistId, HashSet<Script>>,
+    /// Extended public keys being watched, by the watchlist their scripts are added to.
+    xpubs: BTreeMap<WatchlistId, Xpub>,
+    /// Outpoints being watched for spends, with the script of the output. Filters commit
+    /// to the scripts of the outputs spent by a block, so these are matched on the script.
+    outpoints: BTreeMap<OutPoint, Script>,
+    /// Filters received but not yet processed, since they arrived out of order.
+    received: HashMap<Height, (BlockFilter, BlockHash)>,
+    /// Maximum number of filters kept in `received`. Only the filters that many heights
+    /// above the next height to process are requested and buffered.
+    max_buffered: usize,
+    /// Blocks matching a watchlist, by height, with the block once it's downloaded. Blocks
+    /// are scanned for outputs in height order, so that outputs are tracked before they
+    /// are spent.
+    matched: BTreeMap<Height, (BlockHash, Option<Block>)>,
+    /// Unspent outputs paying to watched scripts, by watchlist, with the height of the
+    /// block they were created in. Like watchlists, these are kept from one rescan to the
+    /// next.
+    utxos: BTreeMap<WatchlistId, BTreeMap<OutPoint, (TxOut, Height)>>,
+}
+
+impl Rescan {
+    fn new(max_buffered: usize, rng: fastrand::Rng) -> Self {
+        Self {
+            active: false,
+            current: 0,
+            requested: 0,
+            start: 0,
+            deferred: false,
+            id: sha256::Hash::default(),
+            end: None,
+            confirmations: None,
+            watchlists: BTreeMap::new(),
+            xpubs: BTreeMap::new(),
+            outpoints: BTreeMap::new(),
+            received: HashMap::with_hasher(rng.into()),
+            max_buffered,
+            matched: BTreeMap::new(),
+            utxos: BTreeMap::new(),
+        }
+    }
+
+    /// Get the rescan status.
+    fn status(&self) -> RescanStatus {
+        // Filters that were received are either processed, or buffered.
+        let pending = self.requested.saturating_sub(self.current) as usize;
+
+        RescanStatus {
+            current: self.current,
+            start: self.start,
+            end: self.end,
+            remaining_requests: pending.saturating_sub(self.received.len()),
+        }
+    }
+
+    /// Compute the identifier of the rescan, from its bounds and what's being watched.
+    fn compute_id(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+
+        engine.input(&self.start.to_le_bytes());
+        for bound in [self.end, self.confirmations] {
+            match bound {
+                Some(h) => {
+                    engine.input(&[1]);
+                    engine.input(&h.to_le_bytes());
+                }
+                None => engine.input(&[0]),
+            }
+        }
+        for (id, scripts) in &self.watchlists {
+            let mut scripts = scripts.iter().collect::<Vec<_>>();
+            scripts.sort();
+
+            engine.input(&id.to_le_bytes());
+            engine.input(&(scripts.len() as u64).to_le_bytes());
+            for script in scripts {
+                engine.input(&encode::serialize(script));
+            }
+        }
+        for (outpoint, script) in &self.outpoints {
+            engine.input(&encode::serialize(outpoint));
+            engine.input(&encode::serialize(script));
+        }
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// Get the state the rescan can be resumed from.
+    fn state(&self) -> RescanState {
+        RescanState {
+            id: self.id,
+            current: self.current,
+            end: self.end,
+            matched: self
+                .matched
+                .iter()
+                .map(|(height, (hash, _))| (*height, *hash))
+                .collect(),
+            outpoints: self
+                .outpoints
+                .iter()
+                .map(|(outpoint, script)| (*outpoint, script.clone()))
+                .collect(),
+            utxos: self
+                .utxos
+                .iter()
+                .flat_map(|(id, utxos)| {
+                    utxos.iter().map(move |(outpoint, (output, height))| {
+                        (*id, *outpoint, output.clone(), *height)
+                    })
+                })
+                .collect(),
+            xpubs: self
+                .xpubs
+                .iter()
+                .filter_map(|(id, xpub)| xpub.used().map(|used| (*id, used)))
+                .collect(),
+        }
+    }
+
+    /// Restore the outputs and derivation state saved with [`Rescan::state`].
+    fn restore(&mut self, state: &RescanState) {
+        self.outpoints = state.outpoints.iter().cloned().collect();
+
+        for (id, outpoint, output, height) in &state.utxos {
+            self.utxos
+                .entry(*id)
+                .or_default()
+                .insert(*outpoint, (output.clone(), *height));
+        }
+        for (id, used) in &state.xpubs {
+            if let Some(xpub) = self.xpubs.get_mut(id) {
+                let scripts = xpub.mark_used(*used);
+                self.watchlists.entry(*id).or_default().extend(scripts);
+            }
+        }
+    }
+
+    /// Check whether all heights up to the end of the rescan were processed.
+    fn is_done(&self) -> bool {
+        matches!(self.end, Some(end) if self.current > end)
+    }
+
+    /// Check whether a filter at the given height is wanted by the rescan.
+    fn is_wanted(&self, height: Height) -> bool {
+        self.active
+            && height >= self.current
+            && height <= self.buffer_end()
+            && !matches!(self.end, Some(end) if height > end)
+    }
+
+    /// Last height that can be buffered.
+    fn buffer_end(&self) -> Height {
+        self.current + self.max_buffered as Height
+    }
+
+    /// Check whether the buffer is full, ie. the next filter to process is the only one
+    /// missing up to the end of the buffer.
+    fn is_blocked(&self) -> bool {
+        self.active && self.received.len() >= self.max_buffered
+    }
+
+    /// Match a filter against the watched scripts. Returns the matching watchlists,
+    /// in ascending order.
+    fn match_filter(&self, filter: &FilterMatcher) -> Vec<WatchlistId> {
+        self.watchlists
+            .iter()
+            .filter(|(_, scripts)| filter.match_any(scripts.iter().map(|s| s.as_bytes())))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Check whether a filter matches the script of any watched outpoint.
+    fn match_outpoints(&self, filter: &FilterMatcher) -> bool {
+        filter.match_any(self.outpoints.values().map(|s| s.as_bytes()))
+    }
+
+    /// Derive more scripts for the extended public keys watched by the given watchlists,
+    /// if the filter matches scripts close to their gap limit. Returns an event for every
+    /// extended watchlist.
+    fn extend_xpubs(&mut self, watchlists: &[WatchlistId], filter: &FilterMatcher) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for id in watchlists {
+            if let Some(xpub) = self.xpubs.get_mut(id) {
+                let scripts = xpub.extend(filter);
+
+                if let Some(used) = xpub.used().filter(|_| !scripts.is_empty()) {
+                    self.watchlists.entry(*id).or_default().extend(scripts);
+
+                    events.push(Event::GapLimitExtended {
+                        watchlist: *id,
+                        used,
+                        derived: xpub.len(),
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Scan a matching block for outputs paying to watched scripts, and inputs spending
+    /// tracked outputs. Returns an event for every output added or spent.
+    fn scan_block(&mut self, block: &Block, height: Height) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+
+            for input in tx.input.iter() {
+                if self.outpoints.remove(&input.previous_output).is_some() {
+                    events.push(Event::OutpointSpent {
+                        outpoint: input.previous_output,
+                        txid,
+                        height,
+                    });
+                }
+                for (watchlist, utxos) in self.utxos.iter_mut() {
+                    if let Some((output, _)) = utxos.remove(&input.previous_output) {
+                        events.push(Event::UtxoSpent {
+                            watchlist: *watchlist,
+                            outpoint: input.previous_output,
+                            output,
+                            txid,
+                            height,
+                        });
+                    }
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+
+                for (watchlist, scripts) in self.watchlists.iter() {
+                    if scripts.contains(&output.script_pubkey) {
+                        self.utxos
+                            .entry(*watchlist)
+                            .or_default()
+                            .insert(outpoint, (output.clone(), height));
+
+                        events.push(Event::UtxoAdded {
+                            watchlist: *watchlist,
+                            outpoint,
+                            output: output.clone(),
+                            height,
+                        });
+                    }
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Filter headers requested from all our peers. Responses are compared before any of them
+/// is imported.
+#[derive(Debug)]
+struct Request {
+    /// Height of the first requested header.
+    start_height: Height,
+    /// Height of the last requested header.
+    stop_height: Height,
+    /// Header the requested headers build on.
+    prev_header: FilterHeader,
+    /// When the request was sent.
+    sent_at: LocalTime,
+    /// Peers that were asked, and didn't respond yet.
+    pending: HashSet<PeerId>,
+    /// Headers received, by peer.
+    responses: BTreeMap<PeerId, Vec<(FilterHash, FilterHeader)>>,
+}
+
+impl Request {
+    fn new(
+        start_height: Height,
+        stop_height: Height,
+        prev_header: FilterHeader,
+        peers: impl IntoIterator<Item = PeerId>,
+        sent_at: LocalTime,
+    ) -> Self {
+        Self {
+            start_height,
+            stop_height,
+            prev_header,
+            sent_at,
+            pending: peers.into_iter().collect(),
+            responses: BTreeMap::new(),
+        }
+    }
+}
+
+/// Filters requested from a peer with a `getcfilters` message.
+#[derive(Debug)]
+struct FilterRequest {
+    /// Peer the filters were requested from.
+    peer: PeerId,
+    /// Heights of the requested filters that were not received yet.
+    pending: BTreeSet<Height>,
+    /// When the request was sent.
+    sent_at: LocalTime,
+    /// When the peer last made progress, ie. when the request was sent, or when one of
+    /// the requested filters was last received.
+    last_progress: LocalTime,
+}
+
+/// Filter header checkpoints requested from all our peers.
+#[derive(Debug)]
+struct CheckpointRequest {
+    /// Hash of the block the last checkpoint is requested for.
+    stop_hash: BlockHash,
+    /// When the request was sent.
+    sent_at: LocalTime,
+    /// Peers that were asked, and didn't respond yet.
+    pending: HashSet<PeerId>,
+    /// Checkpoints received, by peer.
+    responses: BTreeMap<PeerId, Vec<FilterHeader>>,
+}
+
+/// Conflicting filter headers served by different peers.
+///
+/// Since each header commits to a filter, the headers of at most one peer can be valid
+/// from the first height they disagree on. Every peer is asked for the filter at that
+/// height, which must match its own headers, and once all of them are in, the block
+/// itself is downloaded: filters that don't include all of its output scripts are
+/// invalid, and so are the headers committing to them.
+#[derive(Debug)]
+struct Dispute {
+    /// Height of the first header of the interval.
+    start_height: Height,
+    /// Header the interval builds on.
+    prev_header: FilterHeader,
+    /// Height of the first block the headers disagree on.
+    height: Height,
+    /// Hash of the first block the headers disagree on.
+    block_hash: BlockHash,
+    /// Headers served by the peers that are still in the dispute.
+    candidates: BTreeMap<PeerId, Vec<(FilterHash, FilterHeader)>>,
+    /// Filters at the disputed height, by peer. Each matches the headers of its peer.
+    filters: BTreeMap<PeerId, BlockFilter>,
+    /// Whether the disputed block was requested.
+    block_requested: bool,
+    /// When the dispute started.
+    since: LocalTime,
+}
+
+impl Dispute {
+    /// Header preceding the disputed one, which all candidates agree on.
+    fn parent(&self) -> FilterHeader {
+        let ix = (self.height - self.start_height) as usize;
+
+        match self.candidates.values().next() {
+            Some(headers) if ix > 0 => headers[ix - 1].1,
+            _ => self.prev_header,
+        }
+    }
+
+    /// Check whether all candidates served the same headers.
+    fn is_settled(&self) -> bool {
+        let mut candidates = self.candidates.values();
+
+        match candidates.next() {
+            Some(first) => candidates.all(|headers| headers == first),
+            None => true,
+        }
+    }
+}
+
 /// A compact block filter manager.
 #[derive(Debug)]
 pub struct SpvManager<F, U> {
@@ -216,15 +1013,52 @@ pub struct SpvManager<F, U> {
     upstream: U,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
-    /// Inflight requests.
-    inflight: HashMap<BlockHash, LocalTime>,
-    rng: fastrand::Rng,
+    /// Inflight filter header requests, by stop hash.
+    inflight: HashMap<BlockHash, Request>,
+    /// Inflight filter requests, by stop hash and peer. Requests that don't make progress
+    /// within the request timeout, or that aren't complete after
+    /// [`FILTER_REQUEST_TIMEOUTS`] timeouts, are sent to another peer.
+    filter_requests: HashMap<(BlockHash, PeerId), FilterRequest>,
+    /// Conflicting filter headers being looked into, if any.
+    dispute: Option<Dispute>,
+    /// Filter header checkpoints all our peers agreed on, along with the trusted ones,
+    /// by height.
+    checkpoints: BTreeMap<Height, FilterHeader>,
+    /// Inflight checkpoint request, if any.
+    checkpoint_request: Option<CheckpointRequest>,
+    /// Height up to which checkpoints were last requested.
+    checkpoints_requested: Height,
+    /// Rescan state.
+    rescan: Rescan,
+    /// Saved rescan state, to resume the next rescan from, if it has the same identifier.
+    resume: Option<RescanState>,
+    /// Filter ranges requested via [`SpvManager::get_filters`], that haven't been fully
+    /// delivered yet. The start of each range is the next height to deliver.
+    fetches: Vec<Range<Height>>,
+    /// Filters received for pending fetches, but not yet delivered.
+    fetched: HashMap<Height, (BlockFilter, BlockHash)>,
+    /// Height below which filters were last pruned.
+    pruned: Height,
+    /// Selects peers to request filters from.
+    selector: PeerSelector,
+    /// Filters requested from several peers at once, by block hash, with the peers that
+    /// didn't respond yet, and whether a filter was received.
+    redundant: HashMap<BlockHash, (HashSet<PeerId>, bool)>,
+    /// Wallet birth height, once known. See [`Config::birthday`].
+    birth_height: Option<Height>,
 }
 
 impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     /// Create a new filter manager.
-    pub fn new(config: Config, rng: fastrand::Rng, filters: F, upstream: U) -> Self {
+    pub fn new(mut config: Config, rng: fastrand::Rng, filters: F, upstream: U) -> Self {
+        let resume = config.resume.take();
         let peers = HashMap::with_hasher(rng.clone().into());
+        let rescan = Rescan::new(config.rescan_buffer, rng.clone());
+        let checkpoints = config.filter_checkpoints.iter().copied().collect();
+        let birth_height = match config.birthday {
+            Some(Birthday::Height(height)) => Some(height),
+            Some(Birthday::Time(_)) | None => None,
+        };
 
         Self {
             config,
@@ -232,8 +1066,20 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             upstream,
             filters,
             inflight: HashMap::with_hasher(rng.clone().into()),
+            filter_requests: HashMap::with_hasher(rng.clone().into()),
+            dispute: None,
+            checkpoints,
+            checkpoint_request: None,
+            checkpoints_requested: 0,
+            rescan,
+            resume,
+            fetches: Vec::new(),
+            fetched: HashMap::with_hasher(rng.clone().into()),
+            pruned: 0,
             last_idle: None,
-            rng,
+            selector: PeerSelector::new(rng.clone()),
+            redundant: HashMap::with_hasher(rng.into()),
+            birth_height,
         }
     }
 
@@ -244,11 +1090,47 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
 
     /// Called periodically. Triggers syncing if necessary.
     pub fn idle<T: BlockTree>(&mut self, now: LocalTime, tree: &T) {
+        // Compare the headers we have for requests that all peers responded to, or that
+        // timed out. Peers that didn't respond are left out.
+        let timeout = self.config.request_timeout;
+        let ready = self
+            .inflight
+            .iter()
+            .filter(|(_, r)| {
+                r.pending.is_empty() || (now - r.sent_at >= timeout && !r.responses.is_empty())
+            })
+            .map(|(stop_hash, _)| *stop_hash)
+            .collect::<Vec<_>>();
+
+        for stop_hash in ready {
+            span!("getcfheaders", stop_hash = %stop_hash);
+            // Errors with the filter store are reported when handling the next message.
+            self.settle(&stop_hash, tree, now).ok();
+        }
+        if matches!(
+            &self.checkpoint_request,
+            Some(r) if r.pending.is_empty() || now - r.sent_at >= timeout
+        ) {
+            self.settle_checkpoints(tree, now);
+        }
+        // Peers may have disconnected in the middle of a dispute.
+        self.resolve(tree, now).ok();
+        self.check_filter_requests(tree, now);
+
+        if matches!(&self.dispute, Some(d) if now - d.since >= timeout) {
+            self.dispute = None;
+            self.upstream.event(Event::RequestCanceled {
+                reason: "filter header dispute timed out",
+            });
+        }
+
         if now - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+            // Requests still in flight by now won't be answered.
+            self.inflight.clear();
+            self.redundant.clear();
             self.sync(tree, now);
             self.last_idle = Some(now);
             self.upstream.set_timeout(IDLE_TIMEOUT);
-            self.inflight.clear();
         }
     }
 
@@ -257,55 +1139,843 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         self.idle(now, tree);
     }
 
-    /// Rollback filter header chain by a given number of headers.
-    pub fn rollback(&mut self, n: usize) -> Result<(), filter::Error> {
-        self.filters.rollback(n)
+    /// Called when we're shutting down. Cancels all in-flight requests, and makes sure
+    /// the filter headers and filters we imported are durably stored.
+    pub fn shutdown(&mut self) -> Result<(), filter::Error> {
+        self.inflight.clear();
+        self.filter_requests.clear();
+
+        if self.rescan.active {
+            self.upstream
+                .event(Event::RescanCheckpoint(self.rescan.state()));
+        }
+        self.checkpoint_request = None;
+        self.dispute = None;
+
+        self.filters.sync()
     }
 
-    /// Send a `getcfilters` message to a random peer.
+    /// Rollback the filter header chain to the given height, eg. the height of the last
+    /// block in common with a new active chain.
     ///
-    /// If the range is greater than [`MAX_MESSAGE_CFILTERS`], requests filters from multiple
-    /// peers.
-    pub fn get_cfilters<T: BlockTree>(
-        &mut self,
-        range: Range<Height>,
-        tree: &T,
-    ) -> Result<(), GetFiltersError> {
-        // TODO: Consolidate this code with the `get_cfheaders` code.
-        if let Some(peers) = NonEmpty::from_vec(self.peers.keys().collect()) {
-            let iter = HeightIterator {
-                start: range.start,
-                stop: range.end,
-                step: MAX_MESSAGE_CFILTERS as Height,
-            };
-            for r in iter {
-                let ix = self.rng.usize(..peers.len());
-                let peer = *peers.get(ix).unwrap(); // Can't fail.
+    /// Filters for the replacement blocks are requested once their filter headers are
+    /// imported. If the last rescan, or the active one, processed filters above the given
+    /// height, the replacement filters are processed too, so that matches can be
+    /// re-evaluated.
+    pub fn rollback(&mut self, height: Height) -> Result<(), filter::Error> {
+        // Checkpoints above the fork may be for blocks that are no longer in the chain.
+        // Trusted checkpoints are too deep in the chain to be affected.
+        self.checkpoints.split_off(&(height + 1));
+        self.checkpoints
+            .extend(self.config.filter_checkpoints.iter().copied());
+        self.checkpoints_requested = self.checkpoints_requested.min(height);
 
-                let stop_hash = tree
-                    .get_block_by_height(r.end - 1)
-                    .ok_or(GetFiltersError::InvalidRange)?
-                    .block_hash();
-                let timeout = self.config.request_timeout;
+        // Filter headers requested above the fork are for blocks that are no longer in the
+        // chain, and can't be cross-checked anymore.
+        self.inflight.retain(|_, r| r.stop_height <= height);
+        if matches!(&self.dispute, Some(d) if d.height > height) {
+            self.dispute = None;
+            self.upstream.event(Event::RequestCanceled {
+                reason: "filter header dispute rolled back",
+            });
+        }
 
-                self.upstream
-                    .get_cfilters(*peer, r.start, stop_hash, timeout);
-            }
-            Ok(())
+        let n = self.filters.height().saturating_sub(height);
+
+        if n == 0 {
+            return Ok(());
+        }
+        self.filters.rollback(n as usize)?;
+        self.upstream.event(Event::RollbackDetected(height));
+
+        if self.rescan.active {
+            // Filters above the new tip are for blocks that are no longer in the chain.
+            // Rewind so that the replacement filters are requested and processed.
+            self.rescan.current = self.rescan.current.min(height + 1);
+            self.rescan.requested = self.rescan.requested.min(height + 1);
+            self.rescan.received.retain(|h, _| *h <= height);
+        } else if self.rescan.current > height + 1 && !self.rescan.watchlists.is_empty() {
+            // Scan the replacement blocks, up to where the last rescan got to.
+            let start = height + 1;
+            let end = Some(self.rescan.current - 1);
+
+            self.rescan.active = true;
+            self.rescan.start = start;
+            self.rescan.current = start;
+            self.rescan.requested = start;
+            self.rescan.end = end;
+            self.rescan.confirmations = None;
+            self.rescan.received.clear();
+
+            self.upstream.event(Event::RescanStarted { start, end });
+        }
+        self.fetched.retain(|h, _| *h <= height);
+        // Filters above the fork are for blocks that are no longer in the chain.
+        for request in self.filter_requests.values_mut() {
+            request.pending.split_off(&(height + 1));
+        }
+        self.filter_requests.retain(|_, r| !r.pending.is_empty());
+        // Outputs created in blocks that are no longer in the chain are no longer tracked.
+        // Outputs spent in these blocks are found again if the replacement blocks match.
+        self.rescan.matched.split_off(&(height + 1));
+        for utxos in self.rescan.utxos.values_mut() {
+            utxos.retain(|_, (_, h)| *h <= height);
+        }
+
+        Ok(())
+    }
+
+    /// Get the filter header chain.
+    pub fn filters(&self) -> &F {
+        &self.filters
+    }
+
+    /// Check whether a rescan is in progress.
+    pub fn is_rescanning(&self) -> bool {
+        self.rescan.active
+    }
+
+    /// Get the status of the active rescan, if any.
+    pub fn rescan_status(&self) -> Option<RescanStatus> {
+        if self.rescan.active {
+            Some(self.rescan.status())
         } else {
-            Err(GetFiltersError::NotConnected)
+            None
         }
     }
 
-    /// Handle a `cfheaders` message from a peer.
-    pub fn received_cfheaders<T: BlockTree>(
+    /// Rescan the chain for the given scripts, between the given heights. The scripts
+    /// replace the ones in the [`DEFAULT_WATCHLIST`], while other watchlists are kept.
+    ///
+    /// If the start bound is unbounded, the rescan starts at the current tip. If the end
+    /// bound is unbounded, the rescan keeps going as new blocks are added to the chain.
+    ///
+    /// Only one rescan can be active at a time. To rescan with different bounds, abort
+    /// the current rescan first.
+    pub fn rescan<T: BlockTree>(
         &mut self,
-        from: &PeerId,
-        msg: CFHeaders,
+        start: Bound<Height>,
+        end: Bound<Height>,
+        watch: Vec<Script>,
         tree: &T,
         time: LocalTime,
-    ) -> Result<Height, Error> {
-        let from = *from;
+    ) -> Result<(), GetFiltersError> {
+        self.start_rescan(start, end, None, watch, tree, time)
+    }
+
+    /// Rescan the chain for the given scripts, until every block matching one of the
+    /// scripts has at least the given number of confirmations.
+    ///
+    /// Until a first match is found, the rescan keeps going as new blocks are added to
+    /// the chain. Every subsequent match extends the end of the rescan.
+    pub fn rescan_until_confirmed<T: BlockTree>(
+        &mut self,
+        start: Bound<Height>,
+        confirmations: Height,
+        watch: Vec<Script>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), GetFiltersError> {
+        self.start_rescan(
+            start,
+            Bound::Unbounded,
+            Some(confirmations),
+            watch,
+            tree,
+            time,
+        )
+    }
+
+    /// Abort the active rescan, if any. Returns `true` if a rescan was aborted.
+    ///
+    /// Filters that were requested for the aborted rescan may still arrive, but are
+    /// only processed if another rescan wants them.
+    pub fn abort_rescan(&mut self) -> bool {
+        if !self.rescan.active {
+            return false;
+        }
+        let height = self.rescan.current.saturating_sub(1);
+
+        self.rescan.active = false;
+        self.rescan.received.clear();
+        self.upstream.event(Event::RescanAborted { height });
+
+        true
+    }
+
+    fn start_rescan<T: BlockTree>(
+        &mut self,
+        start: Bound<Height>,
+        end: Bound<Height>,
+        confirmations: Option<Height>,
+        watch: Vec<Script>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), GetFiltersError> {
+        if self.rescan.active {
+            return Err(GetFiltersError::AlreadyActive);
+        }
+        let start = match start {
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h + 1,
+            Bound::Unbounded => tree.height(),
+        };
+        // Nothing below the birth height can match. If the birthday is a time that the
+        // chain hasn't reached yet, eg. during the initial sync, the rescan is deferred
+        // until the birth height is known.
+        let birth = self.birth_height(tree);
+        let deferred = birth.is_none() && matches!(self.config.birthday, Some(Birthday::Time(_)));
+        let start = match birth {
+            Some(birth) => start.max(birth),
+            None => start,
+        };
+        let (end, empty) = match end {
+            Bound::Included(h) => (Some(h), h < start),
+            Bound::Excluded(h) => (Some(h.saturating_sub(1)), h <= start),
+            Bound::Unbounded => (None, false),
+        };
+        if !empty && self.is_pruned(start) {
+            return Err(GetFiltersError::Pruned);
+        }
+
+        self.rescan.active = true;
+        self.rescan.start = start;
+        self.rescan.current = start;
+        self.rescan.requested = start;
+        self.rescan.deferred = deferred;
+        self.rescan.end = end;
+        self.rescan.confirmations = confirmations;
+        if watch.is_empty() {
+            self.rescan.watchlists.remove(&DEFAULT_WATCHLIST);
+        } else {
+            self.rescan
+                .watchlists
+                .insert(DEFAULT_WATCHLIST, watch.into_iter().collect());
+        }
+        self.rescan.received.clear();
+        self.rescan.id = self.rescan.compute_id();
+
+        self.upstream.event(Event::RescanStarted { start, end });
+
+        if empty || (!deferred && self.resume_rescan(tree)) {
+            self.complete_rescan();
+            return Ok(());
+        }
+        self.process(tree, time);
+
+        Ok(())
+    }
+
+    /// Start the rescan that was deferred until the birth height is known, if it is.
+    /// Returns `false` if the rescan is still deferred.
+    fn undefer_rescan<T: BlockTree>(&mut self, tree: &T) -> bool {
+        let birth = match self.birth_height(tree) {
+            Some(birth) => birth,
+            None => return false,
+        };
+        let start = self.rescan.start.max(birth);
+
+        self.rescan.deferred = false;
+        self.rescan.start = start;
+        self.rescan.current = start;
+        self.rescan.requested = start;
+        self.rescan.id = self.rescan.compute_id();
+
+        self.upstream.event(Event::RescanProgress {
+            current: start,
+            start,
+            end: self.rescan.end,
+            remaining_requests: 0,
+        });
+        true
+    }
+
+    /// Resume the rescan from the state it was interrupted in, if it's the same rescan.
+    /// Returns `true` if the rescan is done.
+    fn resume_rescan<T: BlockTree>(&mut self, tree: &T) -> bool {
+        if let Some(state) = self.resume.take() {
+            if self.is_resumable(&state, tree) {
+                self.rescan.current = state.current;
+                self.rescan.requested = state.current;
+                self.rescan.end = state.end;
+                self.rescan.restore(&state);
+                self.rescan.matched.extend(
+                    state
+                        .matched
+                        .iter()
+                        .map(|(height, hash)| (*height, (*hash, None))),
+                );
+
+                self.upstream.event(Event::RescanResumed {
+                    current: state.current,
+                    matched: state.matched,
+                });
+                return self.rescan.is_done();
+            }
+        }
+        false
+    }
+
+    /// Add scripts to a watchlist, creating it if necessary. Since filters are matched
+    /// when they are processed, filters that were received but not yet processed are also
+    /// matched against the new scripts.
+    pub fn watch(&mut self, id: WatchlistId, scripts: Vec<Script>) {
+        self.rescan
+            .watchlists
+            .entry(id)
+            .or_default()
+            .extend(scripts);
+    }
+
+    /// Watch the scripts derived from an extended public key, up to the given gap limit.
+    /// Returns the watchlist the scripts are added to, see [`xpub::watchlist`].
+    ///
+    /// Scripts are derived from the key's non-hardened children, according to the given
+    /// scheme. When a filter matches one of these scripts, more are derived so that
+    /// there are always `gap_limit` unused scripts past the last used one, before the
+    /// next filter is processed. If the key is already watched, only its gap limit is
+    /// updated.
+    pub fn watch_xpub(
+        &mut self,
+        xpub: ExtendedPubKey,
+        scheme: DerivationScheme,
+        gap_limit: u32,
+    ) -> WatchlistId {
+        let id = xpub::watchlist(&xpub);
+        let watch = self
+            .rescan
+            .xpubs
+            .entry(id)
+            .or_insert_with(|| Xpub::new(xpub, scheme, gap_limit));
+
+        watch.gap_limit = gap_limit;
+
+        let scripts = watch.derive();
+        self.watch(id, scripts);
+
+        id
+    }
+
+    /// Watch outpoints for spends, given the script of each output. A filter matching one
+    /// of these scripts is reported with [`Event::FilterProcessed`], and once the matching
+    /// block is downloaded, spends are reported with [`Event::OutpointSpent`].
+    pub fn watch_outpoints(&mut self, outpoints: Vec<(OutPoint, Script)>) {
+        self.rescan.outpoints.extend(outpoints);
+    }
+
+    /// Remove scripts from a watchlist. Watchlists left empty are removed.
+    pub fn unwatch(&mut self, id: WatchlistId, scripts: &[Script]) {
+        if let Some(watchlist) = self.rescan.watchlists.get_mut(&id) {
+            for script in scripts {
+                watchlist.remove(script);
+            }
+            if watchlist.is_empty() {
+                self.rescan.watchlists.remove(&id);
+            }
+        }
+    }
+
+    /// Request the filters for the outstanding part of the rescan, up to the height of
+    /// the filter header chain. Filters found in the filter store are loaded from there
+    /// instead of being requested. Returns `true` if any filters were loaded.
+    fn request_rescan_filters<T: BlockTree>(&mut self, tree: &T, time: LocalTime) -> bool {
+        if !self.rescan.active {
+            return false;
+        }
+        let mut stop = self
+            .filters
+            .height()
+            .min(tree.height())
+            .min(self.rescan.buffer_end());
+        if let Some(end) = self.rescan.end {
+            stop = stop.min(end);
+        }
+        let mut loaded = 0;
+        let mut missing: Option<Range<Height>> = None;
+        let mut height = self.rescan.requested;
+
+        // Don't load too many stored filters at once, since they are buffered until
+        // they are processed.
+        while height <= stop && loaded < MAX_MESSAGE_CFILTERS {
+            let block = match tree.get_block_by_height(height) {
+                Some(header) => header.block_hash(),
+                None => break,
+            };
+            if let Some(filter) = self.filters.get_filter(height) {
+                if let Some(range) = missing.take() {
+                    if !self.request_filters(range, tree, time) {
+                        return loaded > 0;
+                    }
+                }
+                self.rescan.received.insert(height, (filter, block));
+                self.rescan.requested = height + 1;
+                loaded += 1;
+            } else {
+                missing = Some(missing.map_or(height..height + 1, |r| r.start..height + 1));
+            }
+            height += 1;
+        }
+        if let Some(range) = missing {
+            self.request_filters(range, tree, time);
+        }
+        loaded > 0
+    }
+
+    /// Request a range of filters for the rescan. Returns `false` if the request failed.
+    fn request_filters<T: BlockTree>(
+        &mut self,
+        range: Range<Height>,
+        tree: &T,
+        time: LocalTime,
+    ) -> bool {
+        match self.get_cfilters(range.clone(), tree, time) {
+            Ok(()) => {
+                self.rescan.requested = range.end;
+                true
+            }
+            Err(err) => {
+                // We'll try again when the next filter headers are imported.
+                self.upstream.event(Event::RequestCanceled {
+                    reason: match err {
+                        GetFiltersError::NotConnected => "no peers with required services",
+                        GetFiltersError::InvalidRange => "rescan range is invalid",
+                        GetFiltersError::AlreadyActive => "a rescan is already active",
+                        GetFiltersError::Pruned => "rescan range was pruned",
+                    },
+                });
+                false
+            }
+        }
+    }
+
+    /// Process the received filters that are next in line. Filters are processed in
+    /// height order, so that the end of a rescan can be extended as matches are found.
+    fn process<T: BlockTree>(&mut self, tree: &T, time: LocalTime) {
+        if self.rescan.active && self.rescan.deferred {
+            if !self.undefer_rescan(tree) {
+                return;
+            }
+            if self.resume_rescan(tree) || self.rescan.is_done() {
+                self.complete_rescan();
+                return;
+            }
+        }
+        loop {
+            while let Some((filter, block)) = self.rescan.received.remove(&self.rescan.current) {
+                let height = self.rescan.current;
+                // Filters are validated against their headers before being processed, so a
+                // decoding error here is treated as if nothing matched.
+                let matcher = FilterMatcher::new(&filter, &block).unwrap_or_default();
+                let watchlists = self.rescan.match_filter(&matcher);
+                let spends = self.rescan.match_outpoints(&matcher);
+                let matched = !watchlists.is_empty() || spends;
+
+                for event in self.rescan.extend_xpubs(&watchlists, &matcher) {
+                    self.upstream.event(event);
+                }
+
+                if matched {
+                    self.rescan.matched.entry(height).or_insert((block, None));
+
+                    if let Some(confirmations) = self.rescan.confirmations {
+                        // A block at height `h` has `n` confirmations when the height of the
+                        // chain is `h + n - 1`.
+                        let end = height + confirmations.saturating_sub(1);
+                        self.rescan.end = Some(self.rescan.end.map_or(end, |e| e.max(end)));
+                    }
+                }
+                self.upstream.event(Event::FilterProcessed {
+                    block,
+                    height,
+                    matched,
+                    watchlists,
+                    spends,
+                });
+                self.rescan.current += 1;
+
+                if self.rescan.is_done() {
+                    self.complete_rescan();
+                    return;
+                }
+                if self.rescan.current.saturating_sub(self.rescan.start) % RESCAN_PROGRESS_INTERVAL
+                    == 0
+                {
+                    let RescanStatus {
+                        current,
+                        start,
+                        end,
+                        remaining_requests,
+                    } = self.rescan.status();
+
+                    self.upstream.event(Event::RescanProgress {
+                        current,
+                        start,
+                        end,
+                        remaining_requests,
+                    });
+                    self.upstream
+                        .event(Event::RescanCheckpoint(self.rescan.state()));
+                }
+            }
+            // If stored filters were loaded, they can be processed right away.
+            if !self.request_rescan_filters(tree, time) {
+                break;
+            }
+        }
+    }
+
+    /// Check whether the active rescan can be resumed from the given state. The state must
+    /// have been saved by a rescan with the same identifier, and the blocks scanned since
+    /// must still be in the chain.
+    fn is_resumable<T: BlockTree>(&self, state: &RescanState, tree: &T) -> bool {
+        state.id == self.rescan.id
+            && state.current >= self.rescan.start
+            && state.current <= tree.height() + 1
+            && !self.is_pruned(state.current)
+            && state.matched.iter().all(|(height, hash)| {
+                *height < state.current
+                    && tree.get_block_by_height(*height).map(|h| h.block_hash()) == Some(*hash)
+            })
+    }
+
+    fn complete_rescan(&mut self) {
+        let height = self.rescan.current.saturating_sub(1);
+
+        self.rescan.active = false;
+        self.rescan.received.clear();
+        self.upstream.event(Event::RescanCompleted { height });
+    }
+
+    /// Get the filters in the given ranges. Filters are delivered via
+    /// [`Event::FilterFetched`], in ascending height order within each range.
+    ///
+    /// Overlapping and adjacent ranges are merged, and only filters that aren't stored,
+    /// or already requested, are requested from peers.
+    pub fn get_filters<T: BlockTree>(
+        &mut self,
+        ranges: Vec<Range<Height>>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), GetFiltersError> {
+        let mut ranges = ranges
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .collect::<Vec<_>>();
+        ranges.sort_unstable_by_key(|r| r.start);
+
+        let mut merged: Vec<Range<Height>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => {
+                    last.end = last.end.max(range.end);
+                }
+                _ => merged.push(range),
+            }
+        }
+        if merged.iter().any(|r| r.end > tree.height() + 1) {
+            return Err(GetFiltersError::InvalidRange);
+        }
+        if merged.iter().any(|r| self.is_pruned(r.start)) {
+            return Err(GetFiltersError::Pruned);
+        }
+
+        // Find the heights we need to request, grouped into contiguous ranges.
+        let mut missing: Vec<Range<Height>> = Vec::new();
+        for range in merged.iter() {
+            for height in range.clone() {
+                if self.filters.has_filter(height) || self.is_requested(height) {
+                    continue;
+                }
+                match missing.last_mut() {
+                    Some(last) if last.end == height => last.end = height + 1,
+                    _ => missing.push(height..height + 1),
+                }
+            }
+        }
+        if !missing.is_empty() && self.peers.is_empty() {
+            return Err(GetFiltersError::NotConnected);
+        }
+        for range in missing {
+            self.get_cfilters(range, tree, time)?;
+        }
+        self.fetches.extend(merged);
+        self.deliver(tree);
+
+        Ok(())
+    }
+
+    /// Check whether the filter at the given height was already requested from a peer.
+    fn is_requested(&self, height: Height) -> bool {
+        let rescan = &self.rescan;
+
+        self.fetches.iter().any(|r| r.contains(&height))
+            || (rescan.active && height >= rescan.current && height < rescan.requested)
+    }
+
+    /// Check whether the filter at the given height can no longer be checked, because
+    /// the header before it was pruned.
+    fn is_pruned(&self, height: Height) -> bool {
+        height > 0
+            && height <= self.filters.height() + 1
+            && self.filters.get_prev_header(height).is_none()
+    }
+
+    /// Get the wallet birth height. If the birthday is a time, the birth height is only
+    /// known once the chain has reached it. See [`BIRTHDAY_WINDOW`].
+    fn birth_height<T: BlockTree>(&mut self, tree: &T) -> Option<Height> {
+        if self.birth_height.is_some() {
+            return self.birth_height;
+        }
+        let birthday = match self.config.birthday {
+            Some(Birthday::Time(time)) => time.block_time(),
+            _ => return None,
+        };
+        let height = tree.find_height_by_time(birthday.saturating_sub(BIRTHDAY_WINDOW))?;
+
+        self.birth_height = Some(height);
+        self.upstream.event(Event::BirthHeightFound { height });
+
+        Some(height)
+    }
+
+    /// Prune the filters below the given height, unless they are still needed by the
+    /// active rescan or a pending fetch. Checking a filter takes the header before it,
+    /// so that header is kept too.
+    fn prune(&mut self, below: Height) -> Result<(), Error> {
+        let mut below = below;
+
+        if self.rescan.active {
+            below = below.min(self.rescan.current.saturating_sub(1));
+        }
+        if let Some(start) = self.fetches.iter().map(|r| r.start).min() {
+            below = below.min(start.saturating_sub(1));
+        }
+        if below >= self.pruned + PRUNE_INTERVAL {
+            self.filters.prune(below)?;
+            self.pruned = below;
+        }
+        Ok(())
+    }
+
+    /// Deliver the filters of pending fetches that are next in line.
+    fn deliver<T: BlockTree>(&mut self, tree: &T) {
+        let fetched = &self.fetched;
+        let received = &self.rescan.received;
+        let filters = &self.filters;
+        let upstream = &self.upstream;
+
+        for range in self.fetches.iter_mut() {
+            while range.start < range.end {
+                let height = range.start;
+                let (filter, block_hash) = if let Some((filter, block_hash)) =
+                    fetched.get(&height).or_else(|| received.get(&height))
+                {
+                    (filter.clone(), *block_hash)
+                } else if let (Some(filter), Some(header)) =
+                    (filters.get_filter(height), tree.get_block_by_height(height))
+                {
+                    (filter, header.block_hash())
+                } else {
+                    break;
+                };
+                upstream.event(Event::FilterFetched {
+                    filter,
+                    height,
+                    block_hash,
+                });
+                range.start += 1;
+            }
+        }
+        self.fetches.retain(|r| !r.is_empty());
+
+        let fetches = &self.fetches;
+        self.fetched
+            .retain(|h, _| fetches.iter().any(|r| r.contains(h)));
+    }
+
+    /// Send a `getcfilters` message to a random peer.
+    ///
+    /// If the range is greater than [`MAX_MESSAGE_CFILTERS`], requests filters from multiple
+    /// peers.
+    pub fn get_cfilters<T: BlockTree>(
+        &mut self,
+        range: Range<Height>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), GetFiltersError> {
+        self.get_cfilters_excluding(range, &[], tree, time)
+    }
+
+    /// Like [`SpvManager::get_cfilters`], but without asking the given peers, unless no
+    /// other peer has the filters.
+    fn get_cfilters_excluding<T: BlockTree>(
+        &mut self,
+        range: Range<Height>,
+        exclude: &[PeerId],
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), GetFiltersError> {
+        let iter = HeightIterator {
+            start: range.start,
+            stop: range.end,
+            step: MAX_MESSAGE_CFILTERS as Height,
+        };
+        for r in iter {
+            let stop_height = r.end - 1;
+            let stop_hash = tree
+                .get_block_by_height(stop_height)
+                .ok_or(GetFiltersError::InvalidRange)?
+                .block_hash();
+            // Only ask peers that have the filters we're requesting.
+            let candidates = self.peers.iter().map(|(addr, peer)| (addr, peer.height));
+            let peer = self
+                .selector
+                .select(
+                    candidates
+                        .clone()
+                        .filter(|(addr, _)| !exclude.contains(addr)),
+                    stop_height,
+                )
+                .or_else(|| self.selector.select(candidates, stop_height))
+                .ok_or(GetFiltersError::NotConnected)?;
+            let timeout = self.config.request_timeout;
+
+            span!(
+                "getcfilters",
+                peer = %peer,
+                start_height = r.start,
+                stop_height
+            );
+            record!("sending request");
+
+            self.upstream
+                .get_cfilters(peer, r.start, stop_hash, timeout);
+            self.upstream.set_timeout(timeout);
+            self.filter_requests.insert(
+                (stop_hash, peer),
+                FilterRequest {
+                    peer,
+                    pending: r.clone().collect(),
+                    sent_at: time,
+                    last_progress: time,
+                },
+            );
+
+            // Also ask other peers for the newest block's filter, so that a slow peer
+            // doesn't hold up matching it.
+            if self.config.redundant_filters && stop_height == tree.height() {
+                let mut peers = vec![peer];
+
+                while peers.len() < REDUNDANT_FILTER_PEERS {
+                    let candidates = self
+                        .peers
+                        .iter()
+                        .filter(|(addr, _)| !peers.contains(addr))
+                        .map(|(addr, p)| (addr, p.height));
+
+                    match self.selector.select(candidates, stop_height) {
+                        Some(other) => {
+                            self.upstream
+                                .get_cfilters(other, stop_height, stop_hash, timeout);
+                            peers.push(other);
+                        }
+                        None => break,
+                    }
+                }
+                if peers.len() > 1 {
+                    self.redundant
+                        .insert(stop_hash, (peers.into_iter().collect(), false));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Request the filter holding up the rescan again, from another peer than the one it
+    /// was requested from.
+    fn unblock_rescan<T: BlockTree>(&mut self, tree: &T, time: LocalTime) {
+        let height = self.rescan.current;
+        let mut exclude = Vec::new();
+
+        for request in self.filter_requests.values_mut() {
+            if request.pending.remove(&height) {
+                exclude.push(request.peer);
+            }
+        }
+        self.filter_requests.retain(|_, r| !r.pending.is_empty());
+
+        if let Some(peer) = exclude.first() {
+            self.selector.peer_timed_out(peer);
+            self.upstream.event(Event::PeerStalling {
+                peer: *peer,
+                missing: 1,
+            });
+        }
+        // If this fails, the filter is requested again when the request times out.
+        self.get_cfilters_excluding(height..height + 1, &exclude, tree, time)
+            .ok();
+    }
+
+    /// Send the filter requests that didn't make progress within the request timeout, or
+    /// that are past their deadline, to other peers. Requests sent to peers that
+    /// disconnected are sent again right away.
+    fn check_filter_requests<T: BlockTree>(&mut self, tree: &T, now: LocalTime) {
+        let timeout = self.config.request_timeout;
+        let deadline = timeout * FILTER_REQUEST_TIMEOUTS;
+        let peers = &self.peers;
+        let stalled = self
+            .filter_requests
+            .iter()
+            .filter(|(_, r)| {
+                !peers.contains_key(&r.peer)
+                    || now - r.last_progress >= timeout
+                    || now - r.sent_at >= deadline
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for (stop_hash, peer) in stalled {
+            let request = match self.filter_requests.remove(&(stop_hash, peer)) {
+                Some(request) => request,
+                None => continue,
+            };
+            span!("getcfilters", peer = %request.peer, stop_hash = %stop_hash);
+            record!(missing = request.pending.len(), "request stalled");
+
+            if self.peers.contains_key(&request.peer) {
+                self.selector.peer_timed_out(&request.peer);
+                self.upstream.event(Event::PeerStalling {
+                    peer: request.peer,
+                    missing: request.pending.len(),
+                });
+            }
+            // Request the missing filters in contiguous ranges.
+            let mut ranges: Vec<Range<Height>> = Vec::new();
+            for height in request.pending {
+                match ranges.last_mut() {
+                    Some(last) if last.end == height => last.end = height + 1,
+                    _ => ranges.push(height..height + 1),
+                }
+            }
+            for range in ranges {
+                // If this fails, the filters are requested again when they're next needed.
+                self.get_cfilters_excluding(range, &[request.peer], tree, now)
+                    .ok();
+            }
+        }
+    }
+
+    /// Handle a `cfheaders` message from a peer.
+    pub fn received_cfheaders<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: CFHeaders,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<Height, Error> {
+        let from = *from;
+
+        span!("cfheaders", peer = %from, stop_hash = %msg.stop_hash);
 
         if msg.filter_type != 0x0 {
             return Err(Error::InvalidMessage {
@@ -324,21 +1994,29 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         }
 
         let start_height = self.filters.height();
-        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
-            height
-        } else {
-            return Err(Error::InvalidMessage {
-                from,
-                reason: "cfheaders: unknown stop hash",
-            });
+        let stop_hash = msg.stop_hash;
+        let request = match self.inflight.get_mut(&stop_hash) {
+            Some(request) if request.pending.contains(&from) => request,
+            _ => {
+                return Err(Error::Ignored {
+                    from,
+                    msg: "cfheaders: unsolicited message",
+                })
+            }
         };
+        request.pending.remove(&from);
 
-        if self.inflight.remove(&msg.stop_hash).is_none() {
+        // The stop hash was ours, but the block is no longer in the chain, eg. after a reorg.
+        // The reply is stale rather than invalid.
+        let stop_height = if let Some((height, _)) = tree.get_block(&stop_hash) {
+            height
+        } else {
             return Err(Error::Ignored {
                 from,
-                msg: "cfheaders: unsolicited message",
+                msg: "cfheaders: stale stop hash",
             });
-        }
+        };
+        self.selector.peer_responded(&from, time - request.sent_at);
 
         let hashes = msg.filter_hashes;
         let count = hashes.len();
@@ -374,36 +2052,154 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             last_header = filter_hash.filter_header(&last_header);
             headers.push((filter_hash, last_header));
         }
-        self.filters
-            .import_headers(headers)
-            .map(|height| {
-                self.upstream.event(Event::FilterHeadersImported { height });
-                assert!(height <= tree.height());
+        for (height, checkpoint) in self.checkpoints.range(start_height + 1..=stop_height) {
+            let (_, header) = headers[(height - start_height - 1) as usize];
 
-                if height == tree.height() {
-                    self.upstream.event(Event::Synced(height));
-                } else {
-                    self.sync(tree, time);
-                }
-                height
-            })
-            .map_err(Error::from)
+            if header != *checkpoint {
+                return Err(Error::InvalidMessage {
+                    from,
+                    reason: "cfheaders: header doesn't match checkpoint",
+                });
+            }
+        }
+        request.responses.insert(from, headers);
+
+        if request.pending.is_empty() {
+            return self.settle(&stop_hash, tree, time);
+        }
+        Ok(self.filters.height())
     }
 
-    /// Handle a `getcfheaders` message from a peer.
-    pub fn received_getcfheaders<T: BlockTree>(
+    /// Compare the filter headers received for a request. If all peers agree, the headers
+    /// are imported, otherwise a dispute is started.
+    fn settle<T: BlockTree>(
         &mut self,
-        from: &PeerId,
-        msg: GetCFHeaders,
+        stop_hash: &BlockHash,
         tree: &T,
-    ) -> Result<(), Error> {
-        let from = *from;
-
-        if msg.filter_type != 0x0 {
-            return Err(Error::InvalidMessage {
-                from,
-                reason: "getcfheaders: invalid filter type",
-            });
+        time: LocalTime,
+    ) -> Result<Height, Error> {
+        let request = match self.inflight.remove(stop_hash) {
+            Some(request) => request,
+            None => return Ok(self.filters.height()),
+        };
+        // Peers that are still pending by now let the request time out.
+        for peer in &request.pending {
+            self.selector.peer_timed_out(peer);
+        }
+        let mut responses = request.responses.values();
+        let first = match responses.next() {
+            Some(first) => first,
+            None => return Ok(self.filters.height()),
+        };
+
+        if responses.all(|headers| headers == first) {
+            let headers = first.clone();
+
+            return self.import(&request.prev_header, headers, tree, time);
+        }
+        // All responses cover the same heights, so they have the same length.
+        let ix = (0..first.len())
+            .find(|i| request.responses.values().any(|h| h[*i] != first[*i]))
+            .unwrap_or_default();
+        let height = request.start_height + ix as Height;
+        let block_hash = match tree.get_block_by_height(height) {
+            Some(header) => header.block_hash(),
+            // The chain was shortened since the request was sent, the headers will be
+            // requested again.
+            None => {
+                self.upstream.event(Event::RequestCanceled {
+                    reason: "disputed block is no longer in the chain",
+                });
+                return Ok(self.filters.height());
+            }
+        };
+        let peers = request.responses.keys().copied().collect::<Vec<_>>();
+
+        for peer in &peers {
+            self.upstream
+                .get_cfilters(*peer, height, block_hash, self.config.request_timeout);
+        }
+        self.upstream
+            .event(Event::FilterHeadersMismatch { height, peers });
+        self.dispute = Some(Dispute {
+            start_height: request.start_height,
+            prev_header: request.prev_header,
+            height,
+            block_hash,
+            candidates: request.responses,
+            filters: BTreeMap::new(),
+            block_requested: false,
+            since: time,
+        });
+
+        Ok(self.filters.height())
+    }
+
+    /// Import filter headers that were agreed on, if they still build on our tip.
+    fn import<T: BlockTree>(
+        &mut self,
+        prev_header: &FilterHeader,
+        headers: Vec<(FilterHash, FilterHeader)>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<Height, Error> {
+        let (_, tip) = self.filters.tip();
+
+        if tip != prev_header {
+            return Ok(self.filters.height());
+        }
+        let start_height = self.filters.height() + 1;
+        let height = self.filters.import_headers(headers)?;
+
+        let birth = self.birth_height(tree);
+
+        if self.config.serve_filters && height >= start_height {
+            // Filters we serve must all be stored, so we get them as soon as we can.
+            let start = birth.map_or(start_height, |birth| start_height.max(birth));
+
+            if start <= height {
+                self.get_cfilters(start..height + 1, tree, time).ok();
+            }
+        }
+        let mut below = self
+            .config
+            .filter_window
+            .map_or(0, |window| height.saturating_sub(window));
+
+        if let Some(birth) = birth {
+            // Checking the filter at the birth height takes the header before it.
+            below = below.max(birth.saturating_sub(1).min(height));
+        }
+        self.prune(below)?;
+
+        self.upstream.event(Event::FilterHeadersImported { height });
+        assert!(height <= tree.height());
+
+        if height == tree.height() {
+            self.upstream.event(Event::Synced(height));
+        } else {
+            self.sync(tree, time);
+        }
+        // New filters may now be available for the rescan.
+        self.process(tree, time);
+
+        Ok(height)
+    }
+
+    /// Handle a `getcfheaders` message from a peer.
+    pub fn received_getcfheaders<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFHeaders,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfheaders: invalid filter type",
+            });
         }
 
         let start_height = msg.start_height as Height;
@@ -418,12 +2214,11 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         };
 
         let headers = self.filters.get_headers(start_height..stop_height);
-        if !headers.is_empty() {
+        // The previous header may have been pruned, in which case we can't serve this.
+        let prev_header = self.filters.get_prev_header(start_height);
+
+        if let (false, Some(prev_header)) = (headers.is_empty(), prev_header) {
             let hashes = headers.iter().map(|(hash, _)| *hash);
-            let prev_header = self
-                .filters
-                .get_prev_header(start_height)
-                .expect("SpvManager::received_getcfheaders: all headers up to the tip must exist");
 
             self.upstream.send_cfheaders(
                 from,
@@ -444,15 +2239,252 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         })
     }
 
+    /// Handle a `getcfcheckpt` message from a peer.
+    pub fn received_getcfcheckpt<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFCheckpt,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfcheckpt: invalid filter type",
+            });
+        }
+
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            // Can't handle this message, we don't have the stop block.
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt",
+                from,
+            });
+        };
+
+        if stop_height - stop_height % CFCHECKPT_INTERVAL > self.filters.height() {
+            // We're still syncing the filter headers the peer is asking for.
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt",
+                from,
+            });
+        }
+        let filter_headers = match (1..=stop_height / CFCHECKPT_INTERVAL)
+            .map(|i| self.filters.get_header(i * CFCHECKPT_INTERVAL))
+            .map(|h| h.map(|(_, header)| header))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(headers) => headers,
+            // Some of the checkpoints were pruned.
+            None => {
+                return Err(Error::Ignored {
+                    msg: "getcfcheckpt",
+                    from,
+                })
+            }
+        };
+
+        self.upstream.send_cfcheckpt(
+            from,
+            CFCheckpt {
+                filter_type: msg.filter_type,
+                stop_hash: msg.stop_hash,
+                filter_headers,
+            },
+        );
+        Ok(())
+    }
+
+    /// Handle a `cfcheckpt` message from a peer.
+    pub fn received_cfcheckpt<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: CFCheckpt,
+        tree: &T,
+        time: LocalTime,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfcheckpt: invalid filter type",
+            });
+        }
+
+        let request = match &mut self.checkpoint_request {
+            Some(request)
+                if request.stop_hash == msg.stop_hash && request.pending.contains(&from) =>
+            {
+                request
+            }
+            _ => {
+                return Err(Error::Ignored {
+                    from,
+                    msg: "cfcheckpt: unsolicited message",
+                })
+            }
+        };
+        request.pending.remove(&from);
+
+        let stop_height = tree
+            .get_block(&msg.stop_hash)
+            .map(|(height, _)| height)
+            .unwrap_or_default();
+
+        if msg.filter_headers.len() != (stop_height / CFCHECKPT_INTERVAL) as usize {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfcheckpt: header count does not match stop height",
+            });
+        }
+        for (height, checkpoint) in self.config.filter_checkpoints.iter() {
+            let contradicts = *height > 0
+                && height % CFCHECKPT_INTERVAL == 0
+                && msg
+                    .filter_headers
+                    .get((height / CFCHECKPT_INTERVAL) as usize - 1)
+                    .map_or(false, |header| header != checkpoint);
+
+            if contradicts {
+                return Err(Error::InvalidMessage {
+                    from,
+                    reason: "cfcheckpt: header doesn't match trusted checkpoint",
+                });
+            }
+        }
+        request.responses.insert(from, msg.filter_headers);
+
+        if request.pending.is_empty() {
+            self.settle_checkpoints(tree, time);
+        }
+        Ok(())
+    }
+
+    /// Keep the checkpoints all peers that responded agree on, and resume syncing.
+    fn settle_checkpoints<T: BlockTree>(&mut self, tree: &T, time: LocalTime) {
+        let request = match self.checkpoint_request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        let mut responses = request.responses.values();
+
+        if let Some(first) = responses.next() {
+            let agreed = responses.fold(first.len(), |agreed, other| {
+                first
+                    .iter()
+                    .zip(other)
+                    .take(agreed)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+            let checkpoints = first
+                .iter()
+                .enumerate()
+                .map(|(i, header)| ((i as Height + 1) * CFCHECKPT_INTERVAL, *header));
+
+            if agreed < first.len() {
+                // Past the divergence, filter headers are compared as they are downloaded.
+                self.upstream.event(Event::FilterHeadersMismatch {
+                    height: (agreed as Height + 1) * CFCHECKPT_INTERVAL,
+                    peers: request.responses.keys().copied().collect(),
+                });
+            }
+            self.checkpoints.extend(checkpoints.take(agreed));
+        }
+        self.sync(tree, time);
+    }
+
+    /// Handle a `getcfilters` message from a peer. Filters are only served if they are
+    /// all stored.
+    pub fn received_getcfilters<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFilters,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if !self.config.serve_filters {
+            return Err(Error::Ignored {
+                msg: "getcfilters",
+                from,
+            });
+        }
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: invalid filter type",
+            });
+        }
+
+        let start_height = msg.start_height as Height;
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            // Can't handle this message, we don't have the stop block.
+            return Err(Error::Ignored {
+                msg: "getcfilters",
+                from,
+            });
+        };
+
+        if start_height > stop_height {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: start height is greater than stop height",
+            });
+        }
+        if (stop_height - start_height) as usize >= MAX_MESSAGE_CFILTERS {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: filter count exceeds maximum",
+            });
+        }
+
+        let heights = start_height..stop_height + 1;
+        if !heights
+            .clone()
+            .all(|height| self.filters.has_filter(height))
+        {
+            // We must be syncing, or the filters were pruned.
+            return Err(Error::Ignored {
+                msg: "getcfilters",
+                from,
+            });
+        }
+        for height in heights {
+            let header = tree.get_block_by_height(height);
+
+            if let (Some(header), Some(filter)) = (header, self.filters.get_filter(height)) {
+                self.upstream.send_cfilter(
+                    from,
+                    CFilter {
+                        filter_type: msg.filter_type,
+                        block_hash: header.block_hash(),
+                        filter: filter.content,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Handle a `cfilter` message.
     pub fn received_cfilter<T: BlockTree>(
         &mut self,
         from: &PeerId,
         msg: CFilter,
         tree: &T,
+        time: LocalTime,
     ) -> Result<(), Error> {
         let from = *from;
 
+        span!("cfilter", peer = %from, block_hash = %msg.block_hash);
+
         if msg.filter_type != 0x0 {
             return Err(Error::Ignored {
                 msg: "cfilter",
@@ -460,6 +2492,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             });
         }
 
+        if matches!(
+            &self.dispute,
+            Some(d) if d.block_hash == msg.block_hash && d.candidates.contains_key(&from)
+        ) {
+            return self.received_disputed_cfilter(from, msg, tree, time);
+        }
+
         let height = if let Some((height, _)) = tree.get_block(&msg.block_hash) {
             height
         } else {
@@ -481,12 +2520,15 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             });
         };
 
-        // Note that in case this fails, we have a bug in our implementation, since filter
-        // headers are supposed to be downloaded in-order.
-        let prev_header = self
-            .filters
-            .get_prev_header(height)
-            .expect("SpvManager::received_cfilter: all headers up to the tip must exist");
+        let prev_header = if let Some(header) = self.filters.get_prev_header(height) {
+            header
+        } else {
+            // The previous header was pruned, so the filter can't be checked.
+            return Err(Error::Ignored {
+                msg: "cfilter",
+                from,
+            });
+        };
         let filter = BlockFilter::new(&msg.filter);
 
         if filter.filter_header(&prev_header) != header {
@@ -495,89 +2537,420 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
                 reason: "cfilter: filter hash doesn't match header",
             });
         }
+        // Keep track of the progress of the requests for this filter.
+        for request in self.filter_requests.values_mut() {
+            if request.pending.remove(&height) && request.peer == from {
+                request.last_progress = time;
+            }
+        }
+        self.filter_requests.retain(|_, r| !r.pending.is_empty());
+
+        // Only the first of the filters requested redundantly is used.
+        if let Some((pending, received)) = self.redundant.get_mut(&msg.block_hash) {
+            let duplicate = *received;
+
+            *received = true;
+            pending.remove(&from);
+
+            if pending.is_empty() {
+                self.redundant.remove(&msg.block_hash);
+            }
+            if duplicate {
+                return Err(Error::Ignored {
+                    msg: "cfilter: duplicate",
+                    from,
+                });
+            }
+        }
+
+        // The same filter may be served by more than one peer, eg. when it was requested
+        // again from another peer.
+        if self.rescan.received.contains_key(&height) {
+            return Err(Error::Ignored {
+                msg: "cfilter: duplicate",
+                from,
+            });
+        }
+        if self.rescan.is_wanted(height) {
+            self.rescan
+                .received
+                .insert(height, (filter.clone(), msg.block_hash));
+        }
+        if self.fetches.iter().any(|r| r.contains(&height)) {
+            self.fetched
+                .insert(height, (filter.clone(), msg.block_hash));
+        }
+        let watchlists = self
+            .rescan
+            .match_filter(&FilterMatcher::new(&filter, &msg.block_hash).unwrap_or_default());
 
         self.upstream.event(Event::FilterReceived {
             from,
             block_hash: msg.block_hash,
             height,
-            filter,
+            filter: filter.clone(),
+            watchlists,
         });
+        self.process(tree, time);
+        self.deliver(tree);
+        self.filters.import_filter(height, filter)?;
 
-        Ok(())
-    }
+        if self.rescan.is_blocked() {
+            self.unblock_rescan(tree, time);
+        }
 
-    /// Called when a peer disconnected.
-    pub fn peer_disconnected(&mut self, id: &PeerId) {
-        self.peers.remove(id);
+        Ok(())
     }
 
-    /// Called when a new peer was negotiated.
-    pub fn peer_negotiated<T: BlockTree>(
+    /// Handle a `cfilter` message for the block of a filter header dispute.
+    fn received_disputed_cfilter<T: BlockTree>(
         &mut self,
-        id: PeerId,
-        height: Height,
-        services: ServiceFlags,
-        link: Link,
-        clock: &impl Clock,
+        from: PeerId,
+        msg: CFilter,
         tree: &T,
-    ) {
-        if !link.is_outbound() {
-            return;
-        }
-        if !services.has(REQUIRED_SERVICES) {
-            return;
+        time: LocalTime,
+    ) -> Result<(), Error> {
+        let dispute = match &mut self.dispute {
+            Some(dispute) => dispute,
+            None => return Ok(()),
+        };
+        let filter = BlockFilter::new(&msg.filter);
+        let ix = (dispute.height - dispute.start_height) as usize;
+        let (_, header) = dispute.candidates[&from][ix];
+
+        if filter.filter_header(&dispute.parent()) != header {
+            dispute.candidates.remove(&from);
+            dispute.filters.remove(&from);
+            self.resolve(tree, time)?;
+
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfilter: filter doesn't match the filter headers served",
+            });
         }
-        let time = clock.local_time();
+        dispute.filters.insert(from, filter);
+        self.resolve(tree, time)?;
 
-        self.peers.insert(
-            id,
-            Peer {
-                last_active: time,
-                height,
-            },
-        );
-        self.sync(tree, time);
+        Ok(())
     }
 
-    /// Send a `getcfheaders` message to a random peer.
-    pub fn send_getcfheaders<T: BlockTree>(
+    /// Handle a block, which may settle a filter header dispute. Returns the peers which
+    /// served filters that don't match the block.
+    ///
+    /// Blocks matching a watchlist during a rescan are also scanned for outputs paying to
+    /// watched scripts, and for inputs spending tracked outputs, emitting
+    /// [`Event::UtxoAdded`] and [`Event::UtxoSpent`]. Since matching blocks are scanned in
+    /// height order, a block is only scanned once all matching blocks below it are
+    /// received.
+    pub fn received_block<T: BlockTree>(
         &mut self,
-        range: Range<Height>,
+        block: &Block,
         tree: &T,
         time: LocalTime,
-    ) -> Option<(PeerId, Height, BlockHash)> {
-        let count = range.end as usize - range.start as usize;
-
-        debug_assert!(range.start < range.end);
-        debug_assert!(!range.is_empty());
+    ) -> Result<Vec<PeerId>, Error> {
+        let block_hash = block.block_hash();
 
-        if range.is_empty() {
-            return None;
-        }
-        let start_height = range.start;
+        self.received_matched_block(block, &block_hash);
 
-        // Cap request to `MAX_MESSAGE_CFHEADERS`.
-        let stop_hash = if count > MAX_MESSAGE_CFHEADERS {
+        let dispute = match &mut self.dispute {
+            Some(dispute) if dispute.block_requested && dispute.block_hash == block_hash => dispute,
+            _ => return Ok(Vec::new()),
+        };
+        // We can't compute the filter without the scripts spent by the block, but a valid
+        // filter must at least include all the scripts the block creates.
+        let scripts = block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .map(|output| &output.script_pubkey)
+            .filter(|script| !script.is_empty() && !script.is_op_return())
+            .map(|script| script.as_bytes())
+            .collect::<Vec<_>>();
+        let invalid = dispute
+            .filters
+            .iter()
+            .filter(|(_, filter)| {
+                !matches!(
+                    filter.match_all(&block_hash, &mut scripts.iter().copied()),
+                    Ok(true)
+                )
+            })
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+
+        for peer in &invalid {
+            dispute.candidates.remove(peer);
+            dispute.filters.remove(peer);
+        }
+        self.resolve(tree, time)?;
+
+        if self.dispute.is_some() {
+            // The block doesn't tell the remaining filters apart. The interval will be
+            // requested again.
+            self.dispute = None;
+            self.upstream.event(Event::RequestCanceled {
+                reason: "conflicting filter headers could not be told apart",
+            });
+        }
+        Ok(invalid)
+    }
+
+    /// Track the outputs of a block matching a watchlist, and of any matching blocks above it
+    /// that were waiting on it.
+    fn received_matched_block(&mut self, block: &Block, block_hash: &BlockHash) {
+        match self
+            .rescan
+            .matched
+            .values_mut()
+            .find(|(hash, _)| hash == block_hash)
+        {
+            Some((_, slot @ None)) => *slot = Some(block.clone()),
+            _ => return,
+        }
+        while let Some((&height, (_, Some(_)))) = self.rescan.matched.iter().next() {
+            let block = match self.rescan.matched.remove(&height) {
+                Some((_, Some(block))) => block,
+                _ => break,
+            };
+            for event in self.rescan.scan_block(&block, height) {
+                self.upstream.event(event);
+            }
+        }
+    }
+
+    /// Make progress on the filter header dispute, given what we received so far.
+    fn resolve<T: BlockTree>(&mut self, tree: &T, time: LocalTime) -> Result<(), Error> {
+        let dispute = match &mut self.dispute {
+            Some(dispute) => dispute,
+            None => return Ok(()),
+        };
+
+        if dispute.is_settled() {
+            let headers = dispute.candidates.values().next().cloned();
+            let prev_header = dispute.prev_header;
+
+            self.dispute = None;
+
+            if let Some(headers) = headers {
+                self.import(&prev_header, headers, tree, time)?;
+            }
+            return Ok(());
+        }
+        // Once all filters are in, get the block to check them against.
+        if !dispute.block_requested
+            && dispute
+                .candidates
+                .keys()
+                .all(|peer| dispute.filters.contains_key(peer))
+        {
+            if let Some(peer) = dispute.candidates.keys().next() {
+                self.upstream.get_block(*peer, dispute.block_hash);
+            }
+            dispute.block_requested = true;
+        }
+        Ok(())
+    }
+
+    /// Get the time a filter header request with the given stop hash was sent, if it's
+    /// still in flight.
+    pub fn requested_at(&self, stop_hash: &BlockHash) -> Option<LocalTime> {
+        self.inflight.get(stop_hash).map(|r| r.sent_at)
+    }
+
+    /// Check whether a message is the reply to one of our requests for filters, filter
+    /// headers, checkpoints or disputed blocks to the given peer. Since the request is
+    /// updated once the reply is processed, only the first matching reply is expected.
+    pub fn is_awaiting<T: BlockTree>(&self, from: &PeerId, msg: &NetworkMessage, tree: &T) -> bool {
+        match msg {
+            NetworkMessage::CFHeaders(msg) => matches!(
+                self.inflight.get(&msg.stop_hash),
+                Some(r) if r.pending.contains(from)
+            ),
+            NetworkMessage::CFCheckpt(msg) => matches!(
+                &self.checkpoint_request,
+                Some(r) if r.stop_hash == msg.stop_hash && r.pending.contains(from)
+            ),
+            NetworkMessage::CFilter(msg) => {
+                let disputed = matches!(
+                    &self.dispute,
+                    Some(d) if d.block_hash == msg.block_hash
+                        && d.candidates.contains_key(from)
+                        && !d.filters.contains_key(from)
+                );
+                let redundant = matches!(
+                    self.redundant.get(&msg.block_hash),
+                    Some((pending, _)) if pending.contains(from)
+                );
+                let requested = match tree.get_block(&msg.block_hash) {
+                    Some((height, _)) => self
+                        .filter_requests
+                        .values()
+                        .any(|r| r.peer == *from && r.pending.contains(&height)),
+                    None => false,
+                };
+                disputed || redundant || requested
+            }
+            NetworkMessage::Block(block) => matches!(
+                &self.dispute,
+                Some(d) if d.block_requested
+                    && d.block_hash == block.block_hash()
+                    && d.candidates.contains_key(from)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Check whether we serve compact filters to peers.
+    pub fn is_serving(&self) -> bool {
+        self.config.serve_filters
+    }
+
+    /// Check whether we're connected to fewer than [`Config::min_peers`] peers serving
+    /// compact filters.
+    pub fn is_short_of_peers(&self) -> bool {
+        self.peers.len() < self.config.min_peers
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, id: &PeerId) {
+        span!("peer", peer = %id);
+
+        self.peers.remove(id);
+        self.selector.peer_disconnected(id);
+
+        for request in self.inflight.values_mut() {
+            request.pending.remove(id);
+        }
+        self.redundant.retain(|_, (pending, _)| {
+            pending.remove(id);
+            !pending.is_empty()
+        });
+        if let Some(dispute) = &mut self.dispute {
+            dispute.candidates.remove(id);
+            dispute.filters.remove(id);
+        }
+        if let Some(request) = &mut self.checkpoint_request {
+            request.pending.remove(id);
+        }
+    }
+
+    /// Called when a peer's best height was updated, eg. after it sent us headers.
+    pub fn peer_height(&mut self, id: &PeerId, height: Height) {
+        if let Some(peer) = self.peers.get_mut(id) {
+            peer.height = peer.height.max(height);
+        }
+    }
+
+    /// Called when a new peer was negotiated.
+    pub fn peer_negotiated<T: BlockTree>(
+        &mut self,
+        id: PeerId,
+        height: Height,
+        services: ServiceFlags,
+        link: Link,
+        clock: &impl Clock,
+        tree: &T,
+    ) {
+        span!("peer", peer = %id);
+
+        if !link.is_outbound() {
+            return;
+        }
+        if !services.has(self.config.required_services) {
+            return;
+        }
+        let time = clock.local_time();
+
+        self.peers.insert(
+            id,
+            Peer {
+                last_active: time,
+                height,
+            },
+        );
+        self.sync(tree, time);
+    }
+
+    /// Send a `getcfheaders` message for the given range.
+    ///
+    /// If the range ends at a checkpoint, the response can be checked against it, and a
+    /// random peer is asked. Otherwise, all peers are asked so that their responses can be
+    /// compared.
+    pub fn send_getcfheaders<T: BlockTree>(
+        &mut self,
+        range: Range<Height>,
+        tree: &T,
+        time: LocalTime,
+    ) -> Option<(Vec<PeerId>, Height, BlockHash)> {
+        let count = range.end as usize - range.start as usize;
+
+        debug_assert!(range.start < range.end);
+        debug_assert!(!range.is_empty());
+
+        if range.is_empty() {
+            return None;
+        }
+        let start_height = range.start;
+        let checkpoint = self
+            .checkpoints
+            .range(range.clone())
+            .next()
+            .map(|(height, _)| *height);
+
+        // Cap request to the next checkpoint, or `MAX_MESSAGE_CFHEADERS`.
+        let (stop_height, stop_hash) = if let Some(stop_height) = checkpoint {
+            let stop_block = tree
+                .get_block_by_height(stop_height)
+                .expect("all headers up to the tip exist");
+
+            (stop_height, stop_block.block_hash())
+        } else if count > MAX_MESSAGE_CFHEADERS {
             let stop_height = range.start + MAX_MESSAGE_CFHEADERS as Height - 1;
             let stop_block = tree
                 .get_block_by_height(stop_height)
                 .expect("all headers up to the tip exist");
 
-            stop_block.block_hash()
+            (stop_height, stop_block.block_hash())
         } else {
             let (hash, _) = tree.tip();
 
-            hash
+            (tree.height(), hash)
         };
         if self.inflight.contains_key(&stop_hash) {
             // Don't request the same thing twice.
             return None;
         }
 
-        // TODO: We should select peers that are caught up to the requested height.
-        if let Some(peers) = NonEmpty::from_vec(self.peers.keys().collect()) {
-            let ix = self.rng.usize(..peers.len());
-            let peer = *peers.get(ix).unwrap(); // Can't fail.
+        let mut peers = self.sync_peers(stop_height);
+        if peers.is_empty() {
+            self.upstream.event(Event::RequestCanceled {
+                reason: "no peers with required services",
+            });
+            return None;
+        }
+        if checkpoint.is_some() {
+            let candidates = self
+                .peers
+                .iter()
+                .filter(|(addr, _)| peers.contains(addr))
+                .map(|(addr, peer)| (addr, peer.height));
+
+            peers = self
+                .selector
+                .select(candidates, stop_height)
+                .into_iter()
+                .collect();
+        }
+
+        for peer in &peers {
+            span!(
+                "getcfheaders",
+                peer = %peer,
+                start_height,
+                stop_height,
+                stop_hash = %stop_hash
+            );
+            record!("sending request");
 
             self.upstream.get_cfheaders(
                 *peer,
@@ -585,15 +2958,60 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
                 stop_hash,
                 self.config.request_timeout,
             );
-            self.inflight.insert(stop_hash, time);
+        }
+        let (_, prev_header) = self.filters.tip();
+        let request = Request::new(
+            start_height,
+            stop_height,
+            *prev_header,
+            peers.iter().copied(),
+            time,
+        );
+
+        self.inflight.insert(stop_hash, request);
+
+        Some((peers, start_height, stop_hash))
+    }
 
-            return Some((*peer, start_height, stop_hash));
+    /// Send a `getcfcheckpt` message to all peers caught up to the given height, for the
+    /// checkpoints up to that height.
+    fn send_getcfcheckpt<T: BlockTree>(&mut self, height: Height, tree: &T, time: LocalTime) {
+        let stop_hash = if let Some(header) = tree.get_block_by_height(height) {
+            header.block_hash()
         } else {
-            self.upstream.event(Event::RequestCanceled {
-                reason: "no peers with required services",
-            });
+            return;
+        };
+        let peers = self.sync_peers(height);
+
+        if peers.is_empty() {
+            return;
+        }
+        for peer in &peers {
+            self.upstream
+                .get_cfcheckpt(*peer, stop_hash, self.config.request_timeout);
         }
-        None
+        self.checkpoints_requested = height;
+        self.checkpoint_request = Some(CheckpointRequest {
+            stop_hash,
+            sent_at: time,
+            pending: peers.into_iter().collect(),
+            responses: BTreeMap::new(),
+        });
+    }
+
+    /// Get the peers to sync up to the given height with. These are the peers known to be
+    /// caught up with that height.
+    fn sync_peers(&self, height: Height) -> Vec<PeerId> {
+        let mut peers = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.height >= height)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        // Keep the order deterministic.
+        peers.sort();
+        peers
     }
 
     /// Attempt to sync the filter header chain.
@@ -601,19 +3019,37 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         let filter_height = self.filters.height();
         let block_height = tree.height();
 
+        if self.dispute.is_some() || self.checkpoint_request.is_some() {
+            // Wait for the dispute to be settled, or the checkpoints to arrive, before
+            // requesting more headers.
+            return;
+        }
         if filter_height < block_height {
+            // Get checkpoints for the filter headers we're missing, to bootstrap the sync.
+            let checkpoint = block_height - block_height % CFCHECKPT_INTERVAL;
+
+            if checkpoint > filter_height && checkpoint > self.checkpoints_requested {
+                self.send_getcfcheckpt(checkpoint, tree, time);
+
+                if self.checkpoint_request.is_some() {
+                    return;
+                }
+            }
+
             // We need to sync the filter header chain.
             let start_height = self.filters.height() + 1;
             let stop_height = tree.height();
 
-            if let Some((peer, start_height, stop_hash)) =
+            if let Some((peers, start_height, stop_hash)) =
                 self.send_getcfheaders(start_height..stop_height + 1, tree, time)
             {
-                self.upstream.event(Event::Syncing {
-                    peer,
-                    start_height,
-                    stop_hash,
-                });
+                for peer in peers {
+                    self.upstream.event(Event::Syncing {
+                        peer,
+                        start_height,
+                        stop_hash,
+                    });
+                }
             }
         } else if filter_height > block_height {
             panic!("{}: filter chain is longer than header chain!", source!());
@@ -634,9 +3070,9 @@ impl Iterator for HeightIterator {
     fn next(&mut self) -> Option<Self::Item> {
         if self.start < self.stop {
             let start = self.start;
-            let stop = self.stop.min(start + self.step - 1);
+            let stop = self.stop.min(start + self.step);
 
-            self.start = stop + 1;
+            self.start = stop;
 
             Some(start..stop)
         } else {
@@ -649,15 +3085,20 @@ impl Iterator for HeightIterator {
 mod tests {
     use bitcoin_hashes::hex::FromHex;
     use crossbeam_channel as chan;
+    use nonempty::NonEmpty;
 
     use nakamoto_chain::block::{cache::BlockCache, store};
-    use nakamoto_chain::filter::cache::FilterCache;
+    use nakamoto_chain::filter::cache::{FilterCache, StoredHeader};
+    use nakamoto_chain::filter::store::bodies;
     use nakamoto_common::block::filter::{FilterHash, FilterHeader};
+    use nakamoto_common::block::BlockHeader;
     use nakamoto_common::network::Network;
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
     use nakamoto_test::BITCOIN_HEADERS;
 
     use crate::protocol::channel::Channel;
-    use crate::protocol::PROTOCOL_VERSION;
+    use crate::protocol::{NetworkMessage, Out, PROTOCOL_VERSION};
 
     use super::*;
 
@@ -693,8 +3134,14 @@ mod tests {
         &[1, 155, 155, 152],
     ];
 
-    #[test]
-    fn test_receive_filters() {
+    type Tree = BlockCache<store::Memory<BlockHeader>>;
+
+    /// Setup a manager with the filter headers of the first blocks imported.
+    fn setup() -> (
+        SpvManager<FilterCache<store::Memory<StoredHeader>>, Channel>,
+        Tree,
+        chan::Receiver<Out>,
+    ) {
         let network = Network::Mainnet;
         let peer = &([0, 0, 0, 0], 0).into();
         let time = LocalTime::now();
@@ -706,11 +3153,13 @@ mod tests {
 
             BlockCache::from(store::Memory::new(BITCOIN_HEADERS.clone()), params, &[]).unwrap()
         };
-        let (sender, _receiver) = chan::unbounded();
+        let (sender, receiver) = chan::unbounded();
 
         let mut spvmgr = {
             let rng = fastrand::Rng::new();
-            let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+            let cache = FilterCache::from(store::memory::Memory::genesis(network))
+                .unwrap()
+                .with_bodies(bodies::Memory::default());
             let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
 
             SpvManager::new(Config::default(), rng, cache, upstream)
@@ -733,26 +3182,1710 @@ mod tests {
                     .map(|h| FilterHash::from_hex(h).unwrap())
                     .collect(),
             };
-            spvmgr.inflight.insert(msg.stop_hash, time);
+            let request = Request::new(1, 15, msg.previous_filter_header, vec![*peer], time);
+
+            spvmgr.inflight.insert(msg.stop_hash, request);
             spvmgr.received_cfheaders(peer, msg, &tree, time).unwrap();
         }
 
         assert_eq!(spvmgr.filters.height(), 15);
         spvmgr.filters.verify(network).unwrap();
 
-        let cfilters = FILTERS
+        (spvmgr, tree, receiver)
+    }
+
+    fn cfilters() -> impl Iterator<Item = CFilter> {
+        FILTERS
             .iter()
             .zip(BITCOIN_HEADERS.iter())
             .map(|(f, h)| CFilter {
                 filter_type: 0x0,
                 block_hash: h.block_hash(),
                 filter: f.to_vec(),
-            });
+            })
+    }
+
+    fn events(receiver: &chan::Receiver<Out>) -> Vec<Event> {
+        receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Event(crate::event::Event::SpvManager(e)) => Some(e),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_receive_filters() {
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, _receiver) = setup();
 
         // Now import the filters.
-        for msg in cfilters {
-            spvmgr.received_cfilter(peer, msg, &tree).unwrap();
+        for msg in cfilters() {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_serve_filters() {
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let getcfilters = |start_height: Height, stop_height: Height| GetCFilters {
+            filter_type: 0x0,
+            start_height: start_height as u32,
+            stop_hash: tree.get_block_by_height(stop_height).unwrap().block_hash(),
+        };
+
+        assert!(matches!(
+            spvmgr.received_getcfilters(peer, getcfilters(2, 5), &tree),
+            Err(Error::Ignored { .. })
+        ));
+        spvmgr.config.serve_filters = true;
+
+        // We can't serve filters we don't have.
+        assert!(matches!(
+            spvmgr.received_getcfilters(peer, getcfilters(2, 5), &tree),
+            Err(Error::Ignored { .. })
+        ));
+        for msg in cfilters() {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        receiver.try_iter().for_each(drop);
+
+        spvmgr
+            .received_getcfilters(peer, getcfilters(2, 5), &tree)
+            .unwrap();
+
+        let served = receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(_, msg) => match msg.payload {
+                    NetworkMessage::CFilter(msg) => Some(msg),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(served, cfilters().skip(2).take(4).collect::<Vec<_>>());
+
+        assert!(matches!(
+            spvmgr.received_getcfilters(peer, getcfilters(5, 2), &tree),
+            Err(Error::InvalidMessage { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rescan() {
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+
+        spvmgr.peers.insert(
+            *peer,
+            Peer {
+                height: tree.height(),
+                last_active: LocalTime::now(),
+            },
+        );
+        spvmgr
+            .rescan(
+                Bound::Included(2),
+                Bound::Excluded(5),
+                vec![],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+        assert_eq!(
+            spvmgr.rescan_status(),
+            Some(RescanStatus {
+                current: 2,
+                start: 2,
+                end: Some(4),
+                remaining_requests: 3,
+            })
+        );
+
+        // Filters arrive out of order, and some aren't part of the rescan.
+        let mut msgs = cfilters().collect::<Vec<_>>();
+        msgs.reverse();
+
+        for msg in msgs {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert_eq!(spvmgr.rescan_status(), None);
+
+        let processed = events(&receiver)
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::FilterProcessed { height, .. } => Some(height),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(processed, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_get_filters() {
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let mut msgs = cfilters().take(8).collect::<Vec<_>>();
+
+        spvmgr.peers.insert(
+            *peer,
+            Peer {
+                height: tree.height(),
+                last_active: LocalTime::now(),
+            },
+        );
+
+        // Store the first few filters.
+        for msg in msgs.drain(..4) {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        events(&receiver);
+
+        assert!(matches!(
+            spvmgr.get_filters(vec![2..4, 5..tree.height() + 2], &tree, LocalTime::now()),
+            Err(GetFiltersError::InvalidRange)
+        ));
+        spvmgr
+            .get_filters(vec![5..8, 2..4, 3..6], &tree, LocalTime::now())
+            .unwrap();
+
+        // The ranges are merged, and only the filters we don't have are requested.
+        // Stored filters are delivered right away.
+        let mut requests = Vec::new();
+        let mut fetched = Vec::new();
+
+        for out in receiver.try_iter() {
+            match out {
+                Out::Message(_, msg) => {
+                    if let NetworkMessage::GetCFilters(msg) = msg.payload {
+                        requests.push(msg.start_height);
+                    }
+                }
+                Out::Event(crate::event::Event::SpvManager(Event::FilterFetched {
+                    height,
+                    ..
+                })) => fetched.push(height),
+                _ => {}
+            }
+        }
+        assert_eq!(requests, vec![4]);
+        assert_eq!(fetched, vec![2, 3]);
+
+        // Filters arrive out of order, but are delivered in order.
+        msgs.reverse();
+        for msg in msgs {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert!(spvmgr.fetches.is_empty());
+        assert!(spvmgr.fetched.is_empty());
+
+        let fetched = events(&receiver)
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::FilterFetched { height, .. } => Some(height),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fetched, vec![4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_redundant_filters() {
+        let alice = &([0, 0, 0, 1], 0).into();
+        let bob = &([0, 0, 0, 2], 0).into();
+        let (mut spvmgr, _, receiver) = setup();
+        // Only keep the blocks we have filters for.
+        let tree = {
+            let headers = BITCOIN_HEADERS
+                .iter()
+                .take(FILTERS.len())
+                .cloned()
+                .collect();
+            let store = store::Memory::new(NonEmpty::from_vec(headers).unwrap());
+
+            BlockCache::from(store, Network::Mainnet.params(), &[]).unwrap()
+        };
+        let height = tree.height();
+
+        spvmgr.config.redundant_filters = true;
+        for peer in [alice, bob].iter() {
+            spvmgr.peers.insert(
+                **peer,
+                Peer {
+                    height,
+                    last_active: LocalTime::now(),
+                },
+            );
+        }
+        let range = height - 2..height + 1;
+        spvmgr
+            .get_filters(vec![range], &tree, LocalTime::now())
+            .unwrap();
+
+        let mut requested = receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(addr, msg) => match msg.payload {
+                    NetworkMessage::GetCFilters(msg) => Some((addr, msg.start_height as Height)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        requested.sort_by_key(|(_, start)| *start);
+
+        assert_eq!(requested.len(), 2, "the newest filter is requested twice");
+        assert_eq!(requested[0].1, height - 2);
+        assert_eq!(requested[1].1, height);
+        assert_ne!(requested[0].0, requested[1].0);
+
+        let msg = cfilters().nth(height as usize).unwrap();
+        let (first, second) = (requested[1].0, requested[0].0);
+
+        spvmgr
+            .received_cfilter(&first, msg.clone(), &tree, LocalTime::now())
+            .unwrap();
+        assert!(matches!(
+            spvmgr.received_cfilter(&second, msg, &tree, LocalTime::now()),
+            Err(Error::Ignored { .. })
+        ));
+        assert!(spvmgr.redundant.is_empty());
+
+        let received = events(&receiver)
+            .into_iter()
+            .filter(|e| matches!(e, Event::FilterReceived { height: h, .. } if *h == height))
+            .count();
+        assert_eq!(received, 1, "only the first filter is used");
+    }
+
+    #[test]
+    fn test_peer_stalling() {
+        let alice = ([0, 0, 0, 1], 0).into();
+        let bob = ([0, 0, 0, 2], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let timeout = spvmgr.config.request_timeout;
+        let mut time = LocalTime::now();
+        let msgs = cfilters().collect::<Vec<_>>();
+
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        // Get the filter requests sent, and the stalling peers reported.
+        let outputs = |receiver: &chan::Receiver<Out>| {
+            let mut requests = Vec::new();
+            let mut stalling = Vec::new();
+
+            for out in receiver.try_iter() {
+                match out {
+                    Out::Message(addr, msg) => {
+                        if let NetworkMessage::GetCFilters(msg) = msg.payload {
+                            requests.push((addr, msg.start_height));
+                        }
+                    }
+                    Out::Event(crate::event::Event::SpvManager(Event::PeerStalling {
+                        peer,
+                        missing,
+                    })) => stalling.push((peer, missing)),
+                    _ => {}
+                }
+            }
+            (requests, stalling)
+        };
+        let range = 2..6;
+        spvmgr.get_filters(vec![range], &tree, time).unwrap();
+
+        let (requests, _) = outputs(&receiver);
+        let (peer, start) = requests[0];
+        let other = if peer == alice { bob } else { alice };
+        assert_eq!(start, 2);
+
+        // The peer only sends the first filter.
+        time.elapse(LocalDuration::from_secs(10));
+        spvmgr
+            .received_cfilter(&peer, msgs[2].clone(), &tree, time)
+            .unwrap();
+
+        // Receiving a filter counts as progress.
+        time.elapse(LocalDuration::from_secs(timeout.as_secs() - 1));
+        spvmgr.received_tick(time, &tree);
+        assert_eq!(outputs(&receiver), (vec![], vec![]));
+
+        // The missing filters are requested from the other peer.
+        time.elapse(LocalDuration::from_secs(1));
+        spvmgr.received_tick(time, &tree);
+        assert_eq!(outputs(&receiver), (vec![(other, 3)], vec![(peer, 3)]));
+
+        // Once all filters are received, nothing is requested anymore.
+        for msg in &msgs[3..6] {
+            spvmgr
+                .received_cfilter(&other, msg.clone(), &tree, time)
+                .unwrap();
+        }
+        assert!(spvmgr.filter_requests.is_empty());
+    }
+
+    #[test]
+    fn test_peer_trickling() {
+        let alice = ([0, 0, 0, 1], 0).into();
+        let bob = ([0, 0, 0, 2], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let timeout = spvmgr.config.request_timeout;
+        let mut time = LocalTime::now();
+        let msgs = cfilters().collect::<Vec<_>>();
+
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
         }
+        let range = 1..21;
+        spvmgr.get_filters(vec![range], &tree, time).unwrap();
+
+        // Get the requests for the filters in the range. Other filters are requested while
+        // syncing.
+        let requested = |receiver: &chan::Receiver<Out>| {
+            receiver
+                .try_iter()
+                .filter_map(|o| match o {
+                    Out::Message(addr, msg) => match msg.payload {
+                        NetworkMessage::GetCFilters(msg) if msg.start_height < 21 => {
+                            Some((addr, msg.start_height))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let (peer, _) = requested(&receiver)[0];
+        let other = if peer == alice { bob } else { alice };
+
+        // The peer sends a filter just before every timeout.
+        let step = LocalDuration::from_secs(timeout.as_secs() - 1);
+        let deadline = time + timeout * FILTER_REQUEST_TIMEOUTS;
+        let mut height = 1;
+
+        while time + step < deadline {
+            time.elapse(step);
+            spvmgr
+                .received_cfilter(&peer, msgs[height].clone(), &tree, time)
+                .unwrap();
+            spvmgr.received_tick(time, &tree);
+            height += 1;
+
+            assert_eq!(requested(&receiver), vec![]);
+        }
+        // Past the deadline, the missing filters are requested from the other peer.
+        time.elapse(step);
+        spvmgr.received_tick(time, &tree);
+
+        assert_eq!(requested(&receiver), vec![(other, height as u32)]);
+    }
+
+    #[test]
+    fn test_duplicate_filter_requests() {
+        let alice = ([0, 0, 0, 1], 0).into();
+        let bob = ([0, 0, 0, 2], 0).into();
+        let (mut spvmgr, tree, _receiver) = setup();
+        let time = LocalTime::now();
+
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        spvmgr
+            .get_cfilters_excluding(1..21, &[alice], &tree, time)
+            .unwrap();
+        spvmgr
+            .get_cfilters_excluding(1..21, &[bob], &tree, time)
+            .unwrap();
+
+        // Both requests are tracked, even though they have the same stop hash.
+        assert_eq!(spvmgr.filter_requests.len(), 2);
+        assert!(spvmgr.filter_requests.values().any(|r| r.peer == alice));
+        assert!(spvmgr.filter_requests.values().any(|r| r.peer == bob));
+    }
+
+    #[test]
+    fn test_rescan_buffer() {
+        let alice = ([0, 0, 0, 1], 0).into();
+        let bob = ([0, 0, 0, 2], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let time = LocalTime::now();
+        let msgs = cfilters().collect::<Vec<_>>();
+
+        spvmgr.rescan.max_buffered = 4;
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        let requests = |receiver: &chan::Receiver<Out>| {
+            receiver
+                .try_iter()
+                .filter_map(|o| match o {
+                    Out::Message(addr, msg) => match msg.payload {
+                        NetworkMessage::GetCFilters(msg) => {
+                            let (stop, _) = tree.get_block(&msg.stop_hash).unwrap();
+                            Some((addr, msg.start_height as Height..stop + 1))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        spvmgr
+            .rescan(Bound::Included(0), Bound::Included(9), vec![], &tree, time)
+            .unwrap();
+
+        // Only the filters that fit in the buffer are requested.
+        let (peer, range) = requests(&receiver).pop().unwrap();
+        let other = if peer == alice { bob } else { alice };
+        assert_eq!(range, 0..5);
+
+        // The first filter is missing.
+        for msg in &msgs[1..4] {
+            spvmgr
+                .received_cfilter(&peer, msg.clone(), &tree, time)
+                .unwrap();
+        }
+        assert!(matches!(
+            spvmgr.received_cfilter(&other, msgs[3].clone(), &tree, time),
+            Err(Error::Ignored { .. })
+        ));
+        assert!(requests(&receiver).is_empty());
+
+        // Once the buffer is full, the missing filter is requested from the other peer.
+        spvmgr
+            .received_cfilter(&peer, msgs[4].clone(), &tree, time)
+            .unwrap();
+        assert_eq!(spvmgr.rescan.received.len(), 4);
+        assert_eq!(requests(&receiver), vec![(other, 0..1)]);
+
+        // Once it's received, the buffered filters are processed, and the next ones are
+        // requested.
+        spvmgr
+            .received_cfilter(&other, msgs[0].clone(), &tree, time)
+            .unwrap();
+        assert!(spvmgr.rescan.received.is_empty());
+        assert_eq!(spvmgr.rescan.current, 5);
+        assert_eq!(requests(&receiver).pop().unwrap().1, 5..10);
+    }
+
+    #[test]
+    fn test_rescan_resume() {
+        let peer = ([0, 0, 0, 1], 0).into();
+        let time = LocalTime::now();
+        let msgs = cfilters().collect::<Vec<_>>();
+        let watch = vec![Script::from(vec![0x6a])];
+        let rescan = |spvmgr: &mut SpvManager<_, _>, tree: &Tree, end: Height| {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+            spvmgr
+                .rescan(
+                    Bound::Included(0),
+                    Bound::Included(end),
+                    watch.clone(),
+                    tree,
+                    time,
+                )
+                .unwrap();
+        };
+        let requested = |receiver: &chan::Receiver<Out>| {
+            receiver.try_iter().find_map(|o| match o {
+                Out::Message(_, msg) => match msg.payload {
+                    NetworkMessage::GetCFilters(msg) => Some(msg.start_height as Height),
+                    _ => None,
+                },
+                _ => None,
+            })
+        };
+
+        let state = {
+            let (mut spvmgr, tree, _receiver) = setup();
+
+            rescan(&mut spvmgr, &tree, 9);
+            for msg in &msgs[..3] {
+                spvmgr
+                    .received_cfilter(&peer, msg.clone(), &tree, time)
+                    .unwrap();
+            }
+            spvmgr.shutdown().unwrap();
+            spvmgr.rescan.state()
+        };
+        assert_eq!(state.current, 3);
+
+        // The same rescan is resumed.
+        let (mut spvmgr, tree, receiver) = setup();
+        spvmgr.resume = Some(state.clone());
+        rescan(&mut spvmgr, &tree, 9);
+
+        assert_eq!(requested(&receiver), Some(3));
+        assert!(spvmgr.resume.is_none());
+
+        // A different rescan starts over.
+        let (mut spvmgr, tree, receiver) = setup();
+        spvmgr.resume = Some(state);
+        rescan(&mut spvmgr, &tree, 8);
+
+        assert_eq!(requested(&receiver), Some(0));
+    }
+
+    #[test]
+    fn test_rescan_state_restore() {
+        use std::str::FromStr;
+
+        let xpub = ExtendedPubKey::from_str(
+            "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+        )
+        .unwrap();
+        let id = xpub::watchlist(&xpub);
+        let spent = OutPoint::new(Txid::hash(b"spent"), 0);
+        let watched = OutPoint::new(Txid::hash(b"watched"), 1);
+        let utxo = OutPoint::new(Txid::hash(b"utxo"), 2);
+        let output = TxOut {
+            value: 1000,
+            script_pubkey: Script::from(vec![0x6a]),
+        };
+        let rescan = || {
+            let mut rescan = Rescan::new(MAX_RESCAN_BUFFER, fastrand::Rng::with_seed(1));
+            let mut watch = Xpub::new(xpub, DerivationScheme::Bip84, 4);
+
+            rescan
+                .watchlists
+                .insert(id, watch.derive().into_iter().collect());
+            rescan.xpubs.insert(id, watch);
+            rescan.outpoints.insert(spent, Script::new());
+            rescan.outpoints.insert(watched, Script::new());
+            rescan
+        };
+
+        // During the rescan, an outpoint is spent, an output is found, and a derived script
+        // is used.
+        let mut original = rescan();
+        original.outpoints.remove(&spent);
+        original
+            .utxos
+            .entry(id)
+            .or_default()
+            .insert(utxo, (output.clone(), 7));
+        let scripts = original.xpubs.get_mut(&id).unwrap().mark_used(5);
+        original.watchlists.entry(id).or_default().extend(scripts);
+
+        let state = original.state();
+        assert_eq!(state.xpubs, vec![(id, 5)]);
+
+        let mut restored = rescan();
+        restored.restore(&state);
+
+        assert_eq!(restored.outpoints, original.outpoints);
+        assert_eq!(restored.utxos, original.utxos);
+        assert_eq!(restored.watchlists, original.watchlists);
+        assert_eq!(restored.xpubs[&id].used(), Some(5));
+        assert_eq!(restored.xpubs[&id].len(), 10);
+    }
+
+    #[test]
+    fn test_rescan_stored_filters() {
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+
+        // Receiving filters stores them.
+        for msg in cfilters() {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert!(spvmgr.filters.get_filter(3).is_some());
+
+        // Since we have all the filters we need, the rescan completes without any
+        // requests to peers.
+        events(&receiver);
+        spvmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Included(9),
+                vec![],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+
+        assert!(!spvmgr.rescan.active);
+        assert!(receiver
+            .try_iter()
+            .all(|o| !matches!(o, Out::Message(_, _))));
+    }
+
+    #[test]
+    fn test_rescan_watch() {
+        let network = Network::Mainnet;
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let script = network.genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+
+        spvmgr.peers.insert(
+            *peer,
+            Peer {
+                height: tree.height(),
+                last_active: LocalTime::now(),
+            },
+        );
+        spvmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Included(4),
+                vec![],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+
+        let mut msgs = cfilters().take(5).collect::<Vec<_>>();
+        let genesis = msgs.remove(0);
+
+        // Filters are buffered, since the genesis filter hasn't arrived yet.
+        for msg in msgs {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        spvmgr.watch(1, vec![script.clone()]);
+        spvmgr.unwatch(1, std::slice::from_ref(&script));
+        assert!(spvmgr.rescan.watchlists.is_empty());
+
+        spvmgr.watch(2, vec![script.clone()]);
+        spvmgr.watch(3, vec![script.clone(), Script::new()]);
+        spvmgr.watch(4, vec![Script::new()]);
+        spvmgr
+            .received_cfilter(peer, genesis, &tree, LocalTime::now())
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut processed = Vec::new();
+
+        for event in events(&receiver) {
+            match event {
+                Event::FilterReceived { watchlists, .. } => received.push(watchlists),
+                Event::FilterProcessed {
+                    height,
+                    matched,
+                    watchlists,
+                    ..
+                } => processed.push((height, matched, watchlists)),
+                _ => {}
+            }
+        }
+        assert_eq!(received.last(), Some(&vec![2, 3]));
+        assert_eq!(
+            processed,
+            vec![
+                (0, true, vec![2, 3]),
+                (1, false, vec![]),
+                (2, false, vec![]),
+                (3, false, vec![]),
+                (4, false, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_track_utxos() {
+        use bitcoin::{Transaction, TxIn};
+
+        let network = Network::Mainnet;
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let genesis = network.genesis_block();
+        let script = genesis.txdata[0].output[0].script_pubkey.clone();
+        let outpoint = OutPoint::new(genesis.txdata[0].txid(), 0);
+
+        spvmgr.watch(7, vec![script.clone()]);
+        spvmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Included(1),
+                vec![],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+        for msg in cfilters().take(2) {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert_eq!(spvmgr.rescan.matched.keys().collect::<Vec<_>>(), vec![&0]);
+        events(&receiver);
+
+        spvmgr
+            .received_block(&genesis, &tree, LocalTime::now())
+            .unwrap();
+        assert!(spvmgr.rescan.matched.is_empty());
+        assert!(matches!(
+            events(&receiver).as_slice(),
+            [Event::UtxoAdded {
+                watchlist: 7,
+                outpoint: o,
+                height: 0,
+                ..
+            }] if *o == outpoint
+        ));
+
+        // A block spending the output.
+        let mut block = genesis.clone();
+        let spend = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![],
+        };
+        block.txdata.push(spend.clone());
+
+        let events = spvmgr.rescan.scan_block(&block, 3);
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::UtxoSpent {
+                watchlist: 7,
+                outpoint: o,
+                txid,
+                height: 3,
+                ..
+            } if *o == outpoint && *txid == spend.txid()
+        )));
+        assert!(spvmgr.rescan.utxos[&7].is_empty());
+    }
+
+    #[test]
+    fn test_watch_outpoints() {
+        use bitcoin::{Transaction, TxIn};
+
+        let network = Network::Mainnet;
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let genesis = network.genesis_block();
+        let script = genesis.txdata[0].output[0].script_pubkey.clone();
+        let outpoint = OutPoint::new(genesis.txdata[0].txid(), 0);
+
+        spvmgr.watch_outpoints(vec![(outpoint, script)]);
+        spvmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Included(1),
+                vec![],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+        for msg in cfilters().take(2) {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        let processed = events(&receiver)
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::FilterProcessed {
+                    height,
+                    matched,
+                    watchlists,
+                    spends,
+                    ..
+                } => Some((height, matched, watchlists, spends)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            processed,
+            vec![(0, true, vec![], true), (1, false, vec![], false)]
+        );
+
+        let mut block = genesis.clone();
+        let spend = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![],
+        };
+        block.txdata.push(spend.clone());
+
+        let events = spvmgr.rescan.scan_block(&block, 1);
+        assert!(matches!(
+            events.as_slice(),
+            [Event::OutpointSpent { outpoint: o, txid, height: 1 }]
+                if *o == outpoint && *txid == spend.txid()
+        ));
+        assert!(spvmgr.rescan.outpoints.is_empty());
+    }
+
+    #[test]
+    fn test_rollback_rescan() {
+        let network = Network::Mainnet;
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let script = network.genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+
+        spvmgr.peers.insert(
+            *peer,
+            Peer {
+                height: tree.height(),
+                last_active: LocalTime::now(),
+            },
+        );
+        spvmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Included(4),
+                vec![script],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+
+        for msg in cfilters().take(5) {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert!(!spvmgr.is_rescanning());
+        events(&receiver);
+
+        // Rolling back above the scanned blocks has no effect on the rescan.
+        spvmgr.rollback(12).unwrap();
+        assert_eq!(spvmgr.filters.height(), 12);
+        assert!(!spvmgr.is_rescanning());
+        assert_eq!(events(&receiver).len(), 1);
+
+        // Rolling back into the scanned blocks rescans their replacements.
+        spvmgr.rollback(2).unwrap();
+        assert_eq!(spvmgr.filters.height(), 2);
+        assert_eq!(
+            spvmgr.rescan_status(),
+            Some(RescanStatus {
+                current: 3,
+                start: 3,
+                end: Some(4),
+                remaining_requests: 0,
+            })
+        );
+
+        let events = events(&receiver);
+        assert!(matches!(events[0], Event::RollbackDetected(2)));
+        assert!(matches!(
+            events[1],
+            Event::RescanStarted {
+                start: 3,
+                end: Some(4)
+            }
+        ));
+
+        // Nothing to roll back.
+        spvmgr.rollback(2).unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rescan_until_confirmed() {
+        let network = Network::Mainnet;
+        let peer = &([0, 0, 0, 0], 0).into();
+        let (mut spvmgr, tree, receiver) = setup();
+        let watch = network.genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+
+        spvmgr.peers.insert(
+            *peer,
+            Peer {
+                height: tree.height(),
+                last_active: LocalTime::now(),
+            },
+        );
+        spvmgr
+            .rescan_until_confirmed(
+                Bound::Unbounded,
+                3,
+                vec![watch.clone()],
+                &tree,
+                LocalTime::now(),
+            )
+            .unwrap();
+
+        // Since the rescan started at the tip, and there was no match, it doesn't end.
+        assert!(spvmgr.rescan.active);
+        assert_eq!(spvmgr.rescan.end, None);
+
+        // Scan again, this time from the genesis block, which matches.
+        assert!(matches!(
+            spvmgr.rescan_until_confirmed(
+                Bound::Included(0),
+                3,
+                vec![watch.clone()],
+                &tree,
+                LocalTime::now()
+            ),
+            Err(GetFiltersError::AlreadyActive)
+        ));
+        assert!(spvmgr.abort_rescan());
+        assert!(!spvmgr.abort_rescan());
+
+        spvmgr
+            .rescan_until_confirmed(Bound::Included(0), 3, vec![watch], &tree, LocalTime::now())
+            .unwrap();
+        events(&receiver);
+
+        for msg in cfilters() {
+            spvmgr
+                .received_cfilter(peer, msg, &tree, LocalTime::now())
+                .unwrap();
+        }
+        assert!(!spvmgr.rescan.active);
+
+        let events = events(&receiver)
+            .into_iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    Event::FilterProcessed { .. } | Event::RescanCompleted { .. }
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Event::FilterProcessed {
+                    height: 0,
+                    matched: true,
+                    ..
+                },
+                Event::FilterProcessed {
+                    height: 1,
+                    matched: false,
+                    ..
+                },
+                Event::FilterProcessed {
+                    height: 2,
+                    matched: false,
+                    ..
+                },
+                Event::RescanCompleted { height: 2 },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_filter_header_dispute() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let genesis = gen::genesis(&mut rng);
+        let blocks = Vec::from(gen::blockchain(genesis, 8..9, &mut rng));
+        let tree = model::Cache::from(
+            NonEmpty::from_vec(blocks.iter().map(|b| b.header).collect()).unwrap(),
+        );
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let mut spvmgr = SpvManager::new(Config::default(), rng.clone(), filters, upstream);
+
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let carol: PeerId = ([77, 77, 77, 77], 8333).into();
+
+        for peer in [alice, bob, carol] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        spvmgr.sync(&tree, time);
+
+        let requested = receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(addr, msg) => match msg.payload {
+                    NetworkMessage::GetCFHeaders(_) => Some(addr),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(requested, vec![carol, alice, bob]);
+
+        // Bob commits to a filter that doesn't match block 5, and Carol to a filter that
+        // she can't produce.
+        let parent = *spvmgr.filters.tip().1;
+        let honest = blocks.iter().skip(1).map(gen::cfilter).collect::<Vec<_>>();
+        let mut bogus = honest.clone();
+        bogus[4] = BlockFilter::new(&[]);
+
+        let cfheaders = |filters: &[BlockFilter]| {
+            let mut prev = parent;
+
+            filters
+                .iter()
+                .map(|f| {
+                    let (hash, header) = gen::cfheader(&prev, f);
+                    prev = header;
+                    hash
+                })
+                .collect::<Vec<_>>()
+        };
+        let honest_hashes = cfheaders(&honest);
+        let mut lying = cfheaders(&bogus);
+        lying[6] = honest_hashes[6];
+
+        let responses = [
+            (alice, honest_hashes.clone()),
+            (bob, cfheaders(&bogus)),
+            (carol, lying),
+        ];
+
+        for (peer, filter_hashes) in responses.iter().cloned() {
+            let msg = CFHeaders {
+                filter_type: 0,
+                stop_hash: tree.tip().0,
+                previous_filter_header: parent,
+                filter_hashes,
+            };
+            spvmgr.received_cfheaders(&peer, msg, &tree, time).unwrap();
+        }
+        // Nothing is imported until the dispute is settled.
+        assert_eq!(spvmgr.filters.height(), 0);
+        assert!(events(&receiver).iter().any(|e| matches!(
+            e,
+            Event::FilterHeadersMismatch { height: 5, peers } if peers.len() == 3
+        )));
+
+        let cfilter = |filter: &BlockFilter| CFilter {
+            filter_type: 0,
+            block_hash: blocks[5].block_hash(),
+            filter: filter.content.clone(),
+        };
+        spvmgr
+            .received_cfilter(&alice, cfilter(&honest[4]), &tree, time)
+            .unwrap();
+        spvmgr
+            .received_cfilter(&bob, cfilter(&bogus[4]), &tree, time)
+            .unwrap();
+        // Carol's filter doesn't match her own headers.
+        assert!(matches!(
+            spvmgr.received_cfilter(&carol, cfilter(&honest[4]), &tree, time),
+            Err(Error::InvalidMessage { from, .. }) if from == carol
+        ));
+
+        // Once the filters are in, the block is requested.
+        let requested = receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(addr, msg) => match msg.payload {
+                    NetworkMessage::GetData(_) => Some(addr),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(requested, vec![alice]);
+
+        // The block settles the dispute, in favor of Alice.
+        assert_eq!(
+            spvmgr.received_block(&blocks[5], &tree, time).unwrap(),
+            vec![bob]
+        );
+        assert_eq!(spvmgr.filters.height(), tree.height());
+        assert_eq!(
+            spvmgr.filters.get_header(5).map(|(hash, _)| hash),
+            Some(honest_hashes[4])
+        );
+        assert!(spvmgr.dispute.is_none());
+    }
+
+    #[test]
+    fn test_filter_header_dispute_rollback() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let genesis = gen::genesis(&mut rng);
+        let blocks = Vec::from(gen::blockchain(genesis, 8..9, &mut rng));
+        let headers = blocks.iter().map(|b| b.header).collect::<Vec<_>>();
+        let tree = model::Cache::from(NonEmpty::from_vec(headers.clone()).unwrap());
+        // The chain after a reorg to a shorter chain with more work.
+        let shortened = model::Cache::from(NonEmpty::from_vec(headers[..4].to_vec()).unwrap());
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender.clone());
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let mut spvmgr = SpvManager::new(Config::default(), rng.clone(), filters, upstream);
+
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let carol: PeerId = ([77, 77, 77, 77], 8333).into();
+
+        for peer in [alice, bob, carol] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        let parent = *spvmgr.filters.tip().1;
+        let honest = blocks.iter().skip(1).map(gen::cfilter).collect::<Vec<_>>();
+        let mut bogus = honest.clone();
+        bogus[4] = BlockFilter::new(&[]);
+
+        let cfheaders = |filters: &[BlockFilter]| CFHeaders {
+            filter_type: 0,
+            stop_hash: tree.tip().0,
+            previous_filter_header: parent,
+            filter_hashes: filters
+                .iter()
+                .scan(parent, |prev, f| {
+                    let (hash, header) = gen::cfheader(prev, f);
+                    *prev = header;
+                    Some(hash)
+                })
+                .collect(),
+        };
+
+        // Alice and Bob disagree on block 5, and Carol doesn't respond. By the time the
+        // request times out, block 5 is no longer in the chain.
+        spvmgr.sync(&tree, time);
+        spvmgr
+            .received_cfheaders(&alice, cfheaders(&honest), &tree, time)
+            .unwrap();
+        spvmgr
+            .received_cfheaders(&bob, cfheaders(&bogus), &tree, time)
+            .unwrap();
+        receiver.try_iter().for_each(drop);
+
+        spvmgr.idle(time + spvmgr.config.request_timeout, &shortened);
+        assert!(spvmgr.dispute.is_none());
+        assert!(events(&receiver)
+            .iter()
+            .any(|e| matches!(e, Event::RequestCanceled { .. })));
+
+        // Rolling back cancels the cross-checks and disputes above the fork.
+        let mut spvmgr = SpvManager::new(
+            Config::default(),
+            rng,
+            model::FilterCache::new(FilterHeader::default()),
+            Channel::new(network, PROTOCOL_VERSION, "test", sender),
+        );
+        for peer in [alice, bob, carol] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        spvmgr.sync(&tree, time);
+        spvmgr
+            .received_cfheaders(&alice, cfheaders(&honest), &tree, time)
+            .unwrap();
+        assert_eq!(spvmgr.inflight.len(), 1);
+
+        spvmgr.rollback(3).unwrap();
+        assert!(spvmgr.inflight.is_empty());
+        receiver.try_iter().for_each(drop);
+
+        // Bob's late reply is for a block that is no longer ours to ask about. It is ignored,
+        // rather than treated as invalid, which would get Bob banned.
+        assert!(matches!(
+            spvmgr.received_cfheaders(&bob, cfheaders(&bogus), &shortened, time),
+            Err(Error::Ignored { .. })
+        ));
+        assert!(spvmgr.dispute.is_none());
+        assert!(spvmgr.peers.contains_key(&bob));
+        assert!(!events(&receiver)
+            .iter()
+            .any(|e| matches!(e, Event::FilterHeadersMismatch { .. })));
+    }
+
+    #[test]
+    fn test_filter_window() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let mut headers = NonEmpty::new(gen::genesis(&mut rng).header);
+
+        for _ in 0..2500 {
+            let prev = *headers.last();
+            headers.push(gen::header(&prev, Default::default(), &mut rng));
+        }
+        let tree = model::Cache::from(headers);
+        let cfheaders = gen::cfheaders(FilterHeader::default(), rng.clone())
+            .take(2500)
+            .collect::<Vec<_>>();
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+
+        let (sender, _receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let config = Config {
+            filter_window: Some(1000),
+            ..Config::default()
+        };
+        let mut spvmgr = SpvManager::new(config, rng.clone(), filters, upstream);
+
+        spvmgr
+            .import(
+                &FilterHeader::default(),
+                cfheaders[..2000].to_vec(),
+                &tree,
+                time,
+            )
+            .unwrap();
+
+        assert_eq!(spvmgr.filters.height(), 2000);
+        assert_eq!(spvmgr.filters.get_header(999), None);
+        assert_eq!(spvmgr.filters.get_header(1000), Some(cfheaders[999]));
+
+        // Pruned filters can't be scanned or served.
+        assert!(matches!(
+            spvmgr.rescan(
+                Bound::Included(500),
+                Bound::Unbounded,
+                vec![],
+                &tree,
+                LocalTime::now()
+            ),
+            Err(GetFiltersError::Pruned)
+        ));
+        assert!(matches!(
+            spvmgr.get_filters(vec![1500..1600, 900..1000], &tree, LocalTime::now()),
+            Err(GetFiltersError::Pruned)
+        ));
+        let msg = GetCFHeaders {
+            filter_type: 0,
+            start_height: 500,
+            stop_hash: tree.get_block_by_height(1500).unwrap().block_hash(),
+        };
+        assert!(matches!(
+            spvmgr.received_getcfheaders(&peer, msg, &tree),
+            Err(Error::Ignored { .. })
+        ));
+
+        // Pruning is done in steps.
+        let (_, prev_header) = cfheaders[1999];
+        spvmgr
+            .import(&prev_header, cfheaders[2000..].to_vec(), &tree, time)
+            .unwrap();
+
+        assert_eq!(spvmgr.filters.height(), 2500);
+        assert_eq!(spvmgr.filters.get_header(1000), Some(cfheaders[999]));
+    }
+
+    #[test]
+    fn test_birthday() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let mut headers = NonEmpty::new(gen::genesis(&mut rng).header);
+
+        for _ in 0..2500 {
+            let prev = *headers.last();
+            headers.push(gen::header(&prev, Default::default(), &mut rng));
+        }
+        let birthday = headers.get(2000).unwrap().time;
+        let tree = model::Cache::from(headers);
+        let birth = (1..tree.height())
+            .find(|h| tree.median_time_past(h + 1) >= birthday - BIRTHDAY_WINDOW)
+            .unwrap();
+        let cfheaders = gen::cfheaders(FilterHeader::default(), rng.clone())
+            .take(2500)
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let config = Config {
+            birthday: Some(Birthday::Time(LocalTime::from_secs(birthday as u64))),
+            ..Config::default()
+        };
+        let mut spvmgr = SpvManager::new(config, rng.clone(), filters, upstream);
+
+        spvmgr
+            .import(&FilterHeader::default(), cfheaders.clone(), &tree, time)
+            .unwrap();
+
+        assert!(birth < 2000);
+        assert!(events(&receiver)
+            .iter()
+            .any(|e| matches!(e, Event::BirthHeightFound { height } if *height == birth)));
+
+        // Filters below the birth height are pruned.
+        assert_eq!(spvmgr.filters.get_header(birth - 2), None);
+        assert_eq!(
+            spvmgr.filters.get_header(birth - 1),
+            Some(cfheaders[birth as usize - 2])
+        );
+
+        // Rescans start no earlier than the birth height.
+        spvmgr
+            .rescan(Bound::Included(0), Bound::Unbounded, vec![], &tree, time)
+            .unwrap();
+
+        assert!(events(&receiver)
+            .iter()
+            .any(|e| matches!(e, Event::RescanStarted { start, end: None } if *start == birth)));
+    }
+
+    #[test]
+    fn test_birthday_deferred() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let mut headers = NonEmpty::new(gen::genesis(&mut rng).header);
+
+        for _ in 0..2500 {
+            let prev = *headers.last();
+            headers.push(gen::header(&prev, Default::default(), &mut rng));
+        }
+        let birthday = headers.get(2000).unwrap().time;
+        // The chain during the initial sync, before it reaches the birthday.
+        let syncing = model::Cache::from(
+            NonEmpty::from_vec(headers.iter().take(1000).cloned().collect()).unwrap(),
+        );
+        let tree = model::Cache::from(headers);
+        let birth = (1..tree.height())
+            .find(|h| tree.median_time_past(h + 1) >= birthday - BIRTHDAY_WINDOW)
+            .unwrap();
+        let cfheaders = gen::cfheaders(FilterHeader::default(), rng.clone())
+            .take(2500)
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let config = Config {
+            birthday: Some(Birthday::Time(LocalTime::from_secs(birthday as u64))),
+            ..Config::default()
+        };
+        let mut spvmgr = SpvManager::new(config, rng.clone(), filters, upstream);
+        let requested = |receiver: &chan::Receiver<Out>| {
+            receiver
+                .try_iter()
+                .filter_map(|o| match o {
+                    Out::Message(_, msg) => match msg.payload {
+                        NetworkMessage::GetCFilters(msg) => Some(msg.start_height as Height),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        spvmgr.peers.insert(
+            ([0, 0, 0, 1], 0).into(),
+            Peer {
+                height: tree.height(),
+                last_active: time,
+            },
+        );
+
+        // The birth height isn't known yet, so the rescan waits for it.
+        spvmgr
+            .rescan(Bound::Included(0), Bound::Unbounded, vec![], &syncing, time)
+            .unwrap();
+        spvmgr
+            .import(
+                &FilterHeader::default(),
+                cfheaders[..999].to_vec(),
+                &syncing,
+                time,
+            )
+            .unwrap();
+
+        assert!(spvmgr.rescan_status().is_some());
+        assert_eq!(requested(&receiver), vec![]);
+
+        // Once it's known, the rescan starts from the birth height.
+        spvmgr
+            .import(&cfheaders[998].1, cfheaders[999..].to_vec(), &tree, time)
+            .unwrap();
+
+        let requested = requested(&receiver);
+        assert!(!requested.is_empty());
+        assert!(requested.iter().all(|h| *h >= birth));
+        assert_eq!(spvmgr.rescan_status().map(|s| s.start), Some(birth));
+    }
+
+    #[test]
+    fn test_cfcheckpt() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let mut headers = NonEmpty::new(gen::genesis(&mut rng).header);
+
+        for _ in 0..2500 {
+            let prev = *headers.last();
+            headers.push(gen::header(&prev, Default::default(), &mut rng));
+        }
+        let tree = model::Cache::from(headers);
+        let cfheaders = gen::cfheaders(FilterHeader::default(), rng.clone())
+            .take(2500)
+            .collect::<Vec<_>>();
+        let checkpoints = vec![cfheaders[999].1, cfheaders[1999].1];
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+
+        let messages = |receiver: &chan::Receiver<Out>| {
+            receiver
+                .try_iter()
+                .filter_map(|o| match o {
+                    Out::Message(addr, msg) => Some((addr, msg.payload)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Checkpoints are served from the filter header chain.
+        {
+            let (sender, receiver) = chan::unbounded();
+            let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+            let filters = model::FilterCache::from(NonEmpty {
+                head: (FilterHash::default(), FilterHeader::default()),
+                tail: cfheaders.clone(),
+            });
+            let mut spvmgr = SpvManager::new(Config::default(), rng.clone(), filters, upstream);
+            let msg = GetCFCheckpt {
+                filter_type: 0,
+                stop_hash: tree.tip().0,
+            };
+
+            spvmgr.received_getcfcheckpt(&alice, msg, &tree).unwrap();
+            assert!(matches!(
+                messages(&receiver).as_slice(),
+                [(_, NetworkMessage::CFCheckpt(msg))] if msg.filter_headers == checkpoints
+            ));
+        }
+
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let mut spvmgr = SpvManager::new(Config::default(), rng.clone(), filters, upstream);
+
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+
+        // Syncing starts with getting checkpoints from all peers.
+        spvmgr.sync(&tree, time);
+
+        let stop_hash = tree.get_block_by_height(2000).unwrap().block_hash();
+        let requested = messages(&receiver)
+            .into_iter()
+            .filter_map(|(addr, msg)| match msg {
+                NetworkMessage::GetCFCheckpt(msg) if msg.stop_hash == stop_hash => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(requested, vec![alice, bob]);
+
+        // Only the checkpoints all peers agree on are kept.
+        let responses = [
+            (alice, checkpoints.clone()),
+            (bob, vec![checkpoints[0], FilterHeader::default()]),
+        ];
+        for (peer, filter_headers) in responses.iter().cloned() {
+            let msg = CFCheckpt {
+                filter_type: 0,
+                stop_hash,
+                filter_headers,
+            };
+            spvmgr.received_cfcheckpt(&peer, msg, &tree, time).unwrap();
+        }
+        assert_eq!(
+            spvmgr.checkpoints.iter().collect::<Vec<_>>(),
+            vec![(&1000, &checkpoints[0])]
+        );
+
+        // Headers up to the checkpoint are requested from a single peer.
+        let requests = |receiver: &chan::Receiver<Out>| {
+            messages(receiver)
+                .into_iter()
+                .filter_map(|(addr, msg)| match msg {
+                    NetworkMessage::GetCFHeaders(msg) => {
+                        Some((addr, msg.start_height, msg.stop_hash))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let checkpoint_hash = tree.get_block_by_height(1000).unwrap().block_hash();
+        let (peer, start_height, _) = match requests(&receiver).as_slice() {
+            [request] if request.2 == checkpoint_hash => *request,
+            other => panic!("unexpected requests: {:?}", other),
+        };
+        assert_eq!(start_height, 1);
+
+        let cfheaders_msg = |hashes: Vec<FilterHash>| CFHeaders {
+            filter_type: 0,
+            stop_hash: checkpoint_hash,
+            previous_filter_header: FilterHeader::default(),
+            filter_hashes: hashes,
+        };
+        let hashes = cfheaders[..1000]
+            .iter()
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+
+        // Headers that don't lead to the checkpoint are rejected.
+        let mut bogus = hashes.clone();
+        bogus.swap(500, 501);
+        assert!(matches!(
+            spvmgr.received_cfheaders(&peer, cfheaders_msg(bogus), &tree, time),
+            Err(Error::InvalidMessage { .. })
+        ));
+
+        spvmgr.idle(time, &tree);
+        let (peer, _, _) = requests(&receiver)[0];
+        spvmgr
+            .received_cfheaders(&peer, cfheaders_msg(hashes), &tree, time)
+            .unwrap();
+        assert_eq!(spvmgr.filters.height(), 1000);
+
+        // Past the agreed checkpoints, headers are requested from all peers.
+        let requested = requests(&receiver)
+            .into_iter()
+            .map(|(addr, start_height, _)| (addr, start_height))
+            .collect::<Vec<_>>();
+        assert_eq!(requested, vec![(alice, 1001), (bob, 1001)]);
+    }
+
+    #[test]
+    fn test_trusted_checkpoints() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let network = Network::Mainnet;
+        let time = LocalTime::now();
+        let mut headers = NonEmpty::new(gen::genesis(&mut rng).header);
+
+        for _ in 0..2500 {
+            let prev = *headers.last();
+            headers.push(gen::header(&prev, Default::default(), &mut rng));
+        }
+        let tree = model::Cache::from(headers);
+        let cfheaders = gen::cfheaders(FilterHeader::default(), rng.clone())
+            .take(2500)
+            .collect::<Vec<_>>();
+        let checkpoints = vec![cfheaders[999].1, cfheaders[1999].1];
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+
+        let (sender, _receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let filters = model::FilterCache::new(FilterHeader::default());
+        let config = Config {
+            filter_checkpoints: vec![(1000, checkpoints[0])],
+            ..Config::default()
+        };
+        let mut spvmgr = SpvManager::new(config, rng.clone(), filters, upstream);
+
+        for peer in [alice, bob] {
+            spvmgr.peers.insert(
+                peer,
+                Peer {
+                    height: tree.height(),
+                    last_active: time,
+                },
+            );
+        }
+        spvmgr.sync(&tree, time);
+
+        // Checkpoints contradicting the trusted ones are rejected.
+        let stop_hash = tree.get_block_by_height(2000).unwrap().block_hash();
+        let msg = |filter_headers| CFCheckpt {
+            filter_type: 0,
+            stop_hash,
+            filter_headers,
+        };
+        assert!(matches!(
+            spvmgr.received_cfcheckpt(
+                &alice,
+                msg(vec![FilterHeader::default(), checkpoints[1]]),
+                &tree,
+                time
+            ),
+            Err(Error::InvalidMessage { .. })
+        ));
+        spvmgr
+            .received_cfcheckpt(&bob, msg(checkpoints.clone()), &tree, time)
+            .unwrap();
+
+        assert_eq!(
+            spvmgr.checkpoints.iter().collect::<Vec<_>>(),
+            vec![(&1000, &checkpoints[0]), (&2000, &checkpoints[1])]
+        );
+
+        // Trusted checkpoints are kept on rollback.
+        spvmgr.rollback(500).unwrap();
+        assert_eq!(
+            spvmgr.checkpoints.iter().collect::<Vec<_>>(),
+            vec![(&1000, &checkpoints[0])]
+        );
     }
 
     #[test]
@@ -762,9 +4895,9 @@ mod tests {
             stop: 19,
             step: 5,
         };
-        assert_eq!(it.next(), Some(3..7));
-        assert_eq!(it.next(), Some(8..12));
-        assert_eq!(it.next(), Some(13..17));
+        assert_eq!(it.next(), Some(3..8));
+        assert_eq!(it.next(), Some(8..13));
+        assert_eq!(it.next(), Some(13..18));
         assert_eq!(it.next(), Some(18..19));
         assert_eq!(it.next(), None);
     }
@@ -0,0 +1,297 @@
+//! Extended public key watching.
+//!
+//! Wallets following BIP 32 hand out addresses in order, and stop looking for funds after
+//! a run of unused addresses, the *gap limit*. To scan for such a wallet, we derive scripts
+//! up to the gap limit, and derive more every time one of the higher-index scripts is used.
+//!
+//! BIP 44 style wallets have two such chains of addresses under each account: one for
+//! receiving, and one for change. Each chain has its own gap limit.
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::bip32::{self, ChildNumber, ExtendedPubKey};
+use bitcoin::{PublicKey, Script};
+
+use nakamoto_common::block::filter::FilterMatcher;
+
+use super::WatchlistId;
+
+/// Default number of unused scripts to watch past the last used one.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Index of the receive chain under a BIP 44 account.
+pub const RECEIVE_CHAIN: u32 = 0;
+
+/// Index of the change chain under a BIP 44 account.
+pub const CHANGE_CHAIN: u32 = 1;
+
+/// How scripts are derived from public keys, following the BIP that defines the
+/// derivation path of the account.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DerivationScheme {
+    /// Pay-to-pubkey-hash, ie. `m/44'/..`.
+    Bip44,
+    /// Pay-to-witness-pubkey-hash nested in pay-to-script-hash, ie. `m/49'/..`.
+    Bip49,
+    /// Pay-to-witness-pubkey-hash, ie. `m/84'/..`.
+    Bip84,
+}
+
+impl Default for DerivationScheme {
+    fn default() -> Self {
+        Self::Bip84
+    }
+}
+
+impl DerivationScheme {
+    /// Get the output script paying to the given key. Returns `None` if the key
+    /// can't be used with this scheme, ie. uncompressed keys with segwit schemes.
+    pub fn script(&self, key: &PublicKey) -> Option<Script> {
+        match self {
+            Self::Bip44 => Some(Script::new_p2pkh(&key.pubkey_hash())),
+            Self::Bip49 => key
+                .wpubkey_hash()
+                .map(|hash| Script::new_p2sh(&Script::new_v0_wpkh(&hash).script_hash())),
+            Self::Bip84 => key.wpubkey_hash().map(|hash| Script::new_v0_wpkh(&hash)),
+        }
+    }
+}
+
+impl std::fmt::Display for DerivationScheme {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bip44 => write!(fmt, "bip44"),
+            Self::Bip49 => write!(fmt, "bip49"),
+            Self::Bip84 => write!(fmt, "bip84"),
+        }
+    }
+}
+
+/// Derive the receive and change chain keys of an account-level extended public key.
+pub fn chains(account: &ExtendedPubKey) -> Result<[ExtendedPubKey; 2], bip32::Error> {
+    let secp = Secp256k1::verification_only();
+    let receive = account.ckd_pub(&secp, ChildNumber::from_normal_idx(RECEIVE_CHAIN)?)?;
+    let change = account.ckd_pub(&secp, ChildNumber::from_normal_idx(CHANGE_CHAIN)?)?;
+
+    Ok([receive, change])
+}
+
+/// Get the watchlist in which the scripts of an extended public key are watched.
+pub fn watchlist(xpub: &ExtendedPubKey) -> WatchlistId {
+    let id = xpub.identifier();
+
+    WatchlistId::from_be_bytes(id[..8].try_into().expect("identifiers are 20 bytes long"))
+}
+
+/// Scripts derived from an extended public key.
+#[derive(Debug)]
+pub struct Xpub {
+    /// The extended public key scripts are derived from.
+    pub xpub: ExtendedPubKey,
+    /// How scripts are derived from the child keys.
+    pub scheme: DerivationScheme,
+    /// Number of unused scripts to watch past the last used one.
+    pub gap_limit: u32,
+    /// Derived scripts, with their child index.
+    scripts: HashMap<Script, u32>,
+    /// Next child index to derive.
+    next: u32,
+    /// Highest child index found in a matching filter.
+    used: Option<u32>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl Xpub {
+    /// Create a new set of scripts for an extended public key. Scripts are derived from
+    /// the key's non-hardened children, but not until [`Xpub::derive`] is called.
+    pub fn new(xpub: ExtendedPubKey, scheme: DerivationScheme, gap_limit: u32) -> Self {
+        Self {
+            xpub,
+            scheme,
+            gap_limit,
+            scripts: HashMap::new(),
+            next: 0,
+            used: None,
+            secp: Secp256k1::verification_only(),
+        }
+    }
+
+    /// Number of scripts derived so far.
+    pub fn len(&self) -> u32 {
+        self.next
+    }
+
+    /// Check whether no scripts were derived yet.
+    pub fn is_empty(&self) -> bool {
+        self.next == 0
+    }
+
+    /// Highest child index found in a matching filter, if any.
+    pub fn used(&self) -> Option<u32> {
+        self.used
+    }
+
+    /// Derive scripts until there are `gap_limit` scripts past the last used one.
+    /// Returns the newly derived scripts.
+    pub fn derive(&mut self) -> Vec<Script> {
+        let target = self
+            .used
+            .map_or(0, |i| i + 1)
+            .saturating_add(self.gap_limit);
+        let mut derived = Vec::new();
+
+        while self.next < target {
+            let child = match ChildNumber::from_normal_idx(self.next) {
+                Ok(child) => child,
+                // We've run out of non-hardened indexes.
+                Err(_) => break,
+            };
+            // Derivation only fails with negligible probability, in which case wallets
+            // skip the index.
+            if let Ok(key) = self.xpub.ckd_pub(&self.secp, child) {
+                if let Some(script) = self.scheme.script(&key.public_key) {
+                    self.scripts.insert(script.clone(), self.next);
+                    derived.push(script);
+                }
+            }
+            self.next += 1;
+        }
+        derived
+    }
+
+    /// Record the use of the script at the given child index, eg. when restoring a wallet's
+    /// state, and derive more scripts as needed to maintain the gap limit. Returns the newly
+    /// derived scripts.
+    pub fn mark_used(&mut self, index: u32) -> Vec<Script> {
+        if self.used.map_or(true, |used| index > used) {
+            self.used = Some(index);
+        }
+        self.derive()
+    }
+
+    /// Get the child index of a derived script.
+    pub fn index(&self, script: &Script) -> Option<u32> {
+        self.scripts.get(script).copied()
+    }
+
+    /// Record the use of any derived script matching the filter, and derive more scripts
+    /// as needed to maintain the gap limit. Since the newly derived scripts may also be
+    /// used in the same block, they are matched against the filter too. Returns all the
+    /// newly derived scripts.
+    pub fn extend(&mut self, filter: &FilterMatcher) -> Vec<Script> {
+        let mut derived = Vec::new();
+        let mut candidates = self.scripts.iter().collect::<Vec<_>>();
+
+        loop {
+            let used = candidates
+                .into_iter()
+                .filter(|(script, _)| filter.contains(script.as_bytes()))
+                .map(|(_, ix)| *ix)
+                .max();
+
+            match (used, self.used) {
+                (Some(ix), Some(prev)) if ix <= prev => break,
+                (None, _) => break,
+                (Some(ix), _) => self.used = Some(ix),
+            }
+            let scripts = self.derive();
+
+            if scripts.is_empty() {
+                break;
+            }
+            derived.extend(scripts);
+            candidates = self
+                .scripts
+                .iter()
+                .filter(|(_, ix)| matches!(self.used, Some(used) if **ix > used))
+                .collect();
+        }
+        derived
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use bitcoin::TxOut;
+    use nakamoto_test::block::gen;
+
+    const XPUB: &str = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+
+    /// Build a block paying to the given scripts, and a matcher for its filter.
+    fn block(scripts: &[&Script], rng: &mut fastrand::Rng) -> FilterMatcher {
+        let mut block = gen::genesis(rng);
+
+        block.txdata[0].output = scripts
+            .iter()
+            .map(|script| TxOut {
+                value: 1,
+                script_pubkey: (*script).clone(),
+            })
+            .collect();
+
+        let filter = gen::cfilter(&block);
+
+        FilterMatcher::new(&filter, &block.block_hash()).unwrap()
+    }
+
+    #[test]
+    fn test_gap_limit() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let xpub = ExtendedPubKey::from_str(XPUB).unwrap();
+        let expected = Xpub::new(xpub, DerivationScheme::Bip84, 16).derive();
+        let mut watch = Xpub::new(xpub, DerivationScheme::Bip84, 5);
+
+        assert_eq!(watch.derive(), &expected[..5]);
+        assert!(watch.derive().is_empty());
+
+        // Nothing used.
+        let filter = block(&[], &mut rng);
+        assert!(watch.extend(&filter).is_empty());
+
+        // Using a script moves the gap along.
+        let filter = block(&[&expected[0]], &mut rng);
+        assert_eq!(watch.extend(&filter), &expected[5..6]);
+        assert_eq!(watch.len(), 6);
+
+        // Scripts derived because of a match are themselves matched against the filter.
+        let filter = block(&[&expected[5], &expected[10]], &mut rng);
+        assert_eq!(watch.extend(&filter), &expected[6..16]);
+        assert_eq!(watch.len(), 16);
+
+        // Using an earlier script again has no effect.
+        let filter = block(&[&expected[3]], &mut rng);
+        assert!(watch.extend(&filter).is_empty());
+        assert_eq!(watch.used(), Some(10));
+    }
+
+    #[test]
+    fn test_derivation_schemes() {
+        let xpub = ExtendedPubKey::from_str(XPUB).unwrap();
+        let [receive, change] = chains(&xpub).unwrap();
+        let network = bitcoin::Network::Bitcoin;
+
+        for scheme in [
+            DerivationScheme::Bip44,
+            DerivationScheme::Bip49,
+            DerivationScheme::Bip84,
+        ] {
+            let receive = Xpub::new(receive, scheme, 1).derive();
+            let change = Xpub::new(change, scheme, 1).derive();
+
+            assert_ne!(receive, change);
+
+            let address = bitcoin::Address::from_script(&receive[0], network).unwrap();
+            let prefix = match scheme {
+                DerivationScheme::Bip44 => "1",
+                DerivationScheme::Bip49 => "3",
+                DerivationScheme::Bip84 => "bc1q",
+            };
+            assert!(address.to_string().starts_with(prefix), "{}", address);
+        }
+    }
+}
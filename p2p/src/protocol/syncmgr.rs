@@ -2,8 +2,12 @@
 //! Manages header synchronization with peers.
 //!
 #![warn(missing_docs)]
+pub mod fork;
+pub mod orphan;
+pub mod segment;
+
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 use nonempty::NonEmpty;
 
@@ -14,12 +18,20 @@ use bitcoin::Block;
 
 use nakamoto_common::block::store;
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
-use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height, Work};
 use nakamoto_common::collections::HashMap;
 
+use thiserror::Error;
+
 use super::channel::{Disconnect, SetTimeout};
-use super::{DisconnectReason, Link, Locators, PeerId, Timeout};
+use super::selector::PeerSelector;
+use super::tracker::{RequestTracker, RetryPolicy};
+use super::{DisconnectReason, Link, Locators, Misbehavior, PeerId, Timeout};
+
+use fork::{Claim, ForkResolver};
+use orphan::OrphanPool;
+use segment::Segments;
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
@@ -37,6 +49,8 @@ pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
 const MAX_HEADERS_ANNOUNCED: usize = 8;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
+/// Maximum number of block hashes remembered as known to a peer.
+const MAX_KNOWN_BLOCKS: usize = 64;
 
 /// The ability to get and send headers.
 pub trait SyncHeaders {
@@ -44,12 +58,30 @@ pub trait SyncHeaders {
     fn get_headers(&self, addr: PeerId, locators: Locators);
     /// Send headers to a peer.
     fn send_headers(&self, addr: PeerId, headers: Vec<BlockHeader>);
+    /// Announce blocks to a peer with an `inv` message.
+    fn send_inv(&self, addr: PeerId, blocks: Vec<BlockHash>);
     /// Send initial post-negotiation messages, eg. `sendheaders`.
     fn negotiate(&self, addr: PeerId);
     /// Emit a sync-related event.
     fn event(&self, event: Event);
 }
 
+/// An error originating in the sync manager.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The peer misbehaved.
+    #[error("peer {from} misbehaved: {misbehavior}")]
+    Misbehaving {
+        /// Message sender.
+        from: PeerId,
+        /// The misbehavior.
+        misbehavior: Misbehavior,
+    },
+    /// Error with the underlying block store.
+    #[error("store error: {0}")]
+    Store(#[from] store::Error),
+}
+
 /// What to do if a timeout for a peer is received.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum OnTimeout {
@@ -68,6 +100,30 @@ struct PeerState {
     link: Link,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Whether the peer prefers new blocks to be announced with `headers` messages,
+    /// as signaled with `sendheaders`.
+    prefers_headers: bool,
+    /// Most recent blocks we know the peer has, because it announced them to us or
+    /// we announced them to it.
+    known_blocks: VecDeque<BlockHash>,
+}
+
+impl PeerState {
+    /// Check whether the peer is known to have the given block.
+    fn knows(&self, hash: &BlockHash) -> bool {
+        self.known_blocks.contains(hash)
+    }
+
+    /// Remember that the peer has the given block.
+    fn learn(&mut self, hash: BlockHash) {
+        if self.knows(&hash) {
+            return;
+        }
+        if self.known_blocks.len() == MAX_KNOWN_BLOCKS {
+            self.known_blocks.pop_front();
+        }
+        self.known_blocks.push_back(hash);
+    }
 }
 
 /// Sync manager configuration.
@@ -79,6 +135,9 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
+    /// Block checkpoints. Headers up to the last checkpoint are downloaded from several
+    /// peers in parallel.
+    pub checkpoints: Vec<(Height, BlockHash)>,
 }
 
 /// The sync manager state.
@@ -94,10 +153,16 @@ pub struct SyncManager<U> {
     last_peer_sample: Option<LocalTime>,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
-    /// Random number generator.
-    rng: fastrand::Rng,
     /// In-flight requests to peers.
-    inflight: HashMap<PeerId, GetHeaders>,
+    inflight: RequestTracker<PeerId, GetHeaders>,
+    /// Branches claimed by our peers.
+    forks: ForkResolver,
+    /// Header chain segments left to download in parallel.
+    segments: Segments,
+    /// Announced headers whose parent we don't have yet.
+    orphans: OrphanPool,
+    /// Selects peers to sync with.
+    selector: PeerSelector,
     /// Upstream protocol channel.
     upstream: U,
 }
@@ -108,15 +173,48 @@ pub enum Event {
     /// Headers received from a peer.
     HeadersReceived(PeerId, usize),
     /// Invalid headers received from a peer.
-    InvalidHeadersReceived(PeerId, Arc<Error>),
+    InvalidHeadersReceived(PeerId, Arc<tree::Error>),
     /// Unsolicited headers received.
     UnsolicitedHeadersReceived(PeerId, usize),
+    /// Our active chain was extended by a header announcement from a peer.
+    HeadersAnnounced {
+        /// Peer who announced the headers.
+        from: PeerId,
+        /// New tip.
+        tip: BlockHash,
+        /// New tip height.
+        height: Height,
+    },
     /// Block received.
     BlockReceived(PeerId, Block, Height),
     /// A new block was discovered via a peer.
     BlockDiscovered(PeerId, BlockHash),
     /// Headers were imported successfully.
     HeadersImported(ImportResult),
+    /// A block was disconnected from the active chain, due to a re-org. Blocks are
+    /// disconnected from the tip downwards.
+    BlockDisconnected {
+        /// Hash of the disconnected block.
+        hash: BlockHash,
+        /// Height the block was at.
+        height: Height,
+    },
+    /// A block was connected to the active chain. Blocks are connected in ascending height
+    /// order, after any blocks disconnected by the same import.
+    BlockConnected {
+        /// Header of the connected block.
+        header: BlockHeader,
+        /// Height of the block.
+        height: Height,
+    },
+    /// The active chain was re-organized. Emitted after the [`Event::BlockDisconnected`] and
+    /// [`Event::BlockConnected`] events of the import, with the same blocks.
+    ChainReorged {
+        /// Blocks disconnected from the active chain, from the old tip downwards.
+        disconnected: Vec<(Height, BlockHash)>,
+        /// Blocks connected to the active chain, in ascending height order, up to the new tip.
+        connected: Vec<(Height, BlockHeader)>,
+    },
     /// Started syncing with a peer.
     Syncing(PeerId),
     /// Finished syncing up to the specified hash and height.
@@ -125,6 +223,8 @@ pub enum Event {
     TimedOut(PeerId),
     /// Potential stale tip detected on the active chain.
     StaleTipDetected(LocalTime),
+    /// A peer was found to be on a branch with less work than our active chain.
+    StaleBranch(PeerId, BlockHash),
 }
 
 impl std::fmt::Display for Event {
@@ -143,9 +243,38 @@ impl std::fmt::Display for Event {
             Event::UnsolicitedHeadersReceived(from, count) => {
                 write!(fmt, "Received {} unsolicited headers from {}", count, from)
             }
+            Event::HeadersAnnounced { from, tip, height } => {
+                write!(
+                    fmt,
+                    "{}: Chain extended by announcement to {} at height {}",
+                    from, tip, height
+                )
+            }
             Event::HeadersImported(import_result) => {
                 write!(fmt, "Headers imported: {:?}", &import_result)
             }
+            Event::BlockDisconnected { hash, height } => {
+                write!(fmt, "Block {} disconnected at height {}", hash, height)
+            }
+            Event::BlockConnected { header, height } => {
+                write!(
+                    fmt,
+                    "Block {} connected at height {}",
+                    header.block_hash(),
+                    height
+                )
+            }
+            Event::ChainReorged {
+                disconnected,
+                connected,
+            } => {
+                write!(
+                    fmt,
+                    "Chain re-organized: {} block(s) disconnected, {} block(s) connected",
+                    disconnected.len(),
+                    connected.len()
+                )
+            }
             Event::Synced(hash, height) => {
                 write!(fmt, "Headers synced up to hash={} height={}", hash, height)
             }
@@ -154,14 +283,15 @@ impl std::fmt::Display for Event {
                 write!(fmt, "{}: Discovered new block: {}", from, &hash)
             }
             Event::StaleTipDetected(last_update) => {
-                let elapsed = LocalTime::from(SystemTime::now()) - *last_update;
-
                 write!(
                     fmt,
-                    "Potential stale tip detected (last update is {} ago)",
-                    elapsed
+                    "Potential stale tip detected (last update was at {})",
+                    last_update
                 )
             }
+            Event::StaleBranch(addr, tip) => {
+                write!(fmt, "{}: Peer is on a stale branch with tip {}", addr, tip)
+            }
         }
     }
 }
@@ -176,8 +306,6 @@ pub struct GetHeaders {
     /// Request timeout.
     pub timeout: LocalDuration,
 
-    /// Time at which the request was sent.
-    sent_at: LocalTime,
     /// What to do if this request times out.
     on_timeout: OnTimeout,
 }
@@ -198,7 +326,21 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         let last_tip_update = None;
         let last_peer_sample = None;
         let last_idle = None;
-        let inflight = HashMap::with_hasher(rng.clone().into());
+        // Headers are requested from one peer at a time, and requests that time out are
+        // not retried as such: new locators are computed instead.
+        let inflight = RequestTracker::new(
+            RetryPolicy {
+                timeout: config.request_timeout,
+                max_timeout: config.request_timeout,
+                max_attempts: 1,
+                max_outstanding: 1,
+            },
+            rng.clone(),
+        );
+        let forks = ForkResolver::new(rng.clone());
+        let selector = PeerSelector::new(rng.clone());
+        let segments = Segments::new((0, BlockHash::default()), vec![], config.params.pow_limit);
+        let orphans = OrphanPool::new(&config.params, rng.clone());
 
         Self {
             peers,
@@ -206,14 +348,22 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             last_tip_update,
             last_peer_sample,
             last_idle,
-            rng,
             inflight,
+            forks,
+            segments,
+            orphans,
+            selector,
             upstream,
         }
     }
 
     /// Initialize the sync manager. Should only be called once.
     pub fn initialize<T: BlockTree>(&mut self, time: LocalTime, tree: &T) {
+        self.segments = Segments::new(
+            (tree.height(), tree.tip().0),
+            self.config.checkpoints.iter().copied(),
+            self.config.params.pow_limit,
+        );
         self.idle(time, tree);
     }
 
@@ -236,6 +386,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         clock: &impl Clock,
         tree: &T,
     ) {
+        span!("peer", peer = %id);
+
         if link.is_outbound() && !services.has(REQUIRED_SERVICES) {
             return;
         }
@@ -246,9 +398,25 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
+        span!("peer", peer = %id);
         self.unregister(id);
     }
 
+    /// Called when we receive a `sendheaders` message from a peer.
+    pub fn received_sendheaders(&mut self, addr: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.prefers_headers = true;
+        }
+    }
+
+    /// Check whether the given headers from a peer are a header announcement, ie. they
+    /// come from a peer that announces blocks with `headers` messages, and are few enough.
+    pub fn is_announcement(&self, addr: &PeerId, headers: &[BlockHeader]) -> bool {
+        !headers.is_empty()
+            && headers.len() <= MAX_HEADERS_ANNOUNCED
+            && self.peers.get(addr).map_or(false, |p| p.prefers_headers)
+    }
+
     /// Called when we received a `getheaders` message from a peer.
     pub fn received_getheaders<T: BlockTree>(
         &self,
@@ -275,11 +443,14 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         blocks: I,
         context: &C,
         tree: &mut T,
-    ) -> Result<ImportResult, Error> {
-        match tree.import_blocks(blocks, context) {
+    ) -> Result<ImportResult, tree::Error> {
+        let current = tree.height();
+
+        let result = match tree.import_blocks(blocks, context) {
             Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
                 let result = ImportResult::TipChanged(header, tip, height, reverted);
 
+                self.imported(&result, current, tree);
                 self.upstream.event(Event::HeadersImported(result.clone()));
                 self.upstream.event(Event::Synced(tip, height));
                 self.broadcast_tip(&tip, tree);
@@ -292,7 +463,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 Ok(result)
             }
             Err(err) => Err(err),
+        };
+        if result.is_ok() {
+            self.connect_orphans(context, tree);
         }
+        result
     }
 
     /// Called when a block is received from a peer.
@@ -305,25 +480,63 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
-    /// Called when we receive headers from a peer.
+    /// Called when we receive headers from a peer. Announced headers whose parent we don't
+    /// have are kept until they can be connected, see [`orphan`].
     pub fn received_headers<T: BlockTree>(
         &mut self,
         from: &PeerId,
         headers: Vec<BlockHeader>,
         clock: &impl Clock,
         tree: &mut T,
-    ) -> Result<ImportResult, store::Error> {
+    ) -> Result<ImportResult, Error> {
+        span!("headers", peer = %from, count = headers.len());
+        let result = self.process_headers(from, headers, clock, tree);
+
+        if result.is_ok() {
+            self.connect_orphans(clock, tree);
+        }
+        result
+    }
+
+    fn process_headers<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        headers: Vec<BlockHeader>,
+        clock: &impl Clock,
+        tree: &mut T,
+    ) -> Result<ImportResult, Error> {
+        if self.inflight.contains(from) && self.segments.expects(from, &headers) {
+            return self.received_segment(from, headers, clock, tree);
+        }
         let headers = if let Some(headers) = NonEmpty::from_vec(headers) {
             headers
         } else {
+            // An empty response to our request means the peer has nothing past our tip.
+            if self.peers.contains_key(from) && self.fulfill(from, clock.local_time()).is_some() {
+                let (tip, _) = tree.tip();
+                let height = tree.height();
+
+                self.forks.claim(
+                    *from,
+                    Claim {
+                        tip,
+                        height,
+                        fork_height: height,
+                        work: Work::default(),
+                    },
+                );
+                self.resolve_forks(tree);
+            }
             return Ok(ImportResult::TipUnchanged);
         };
 
         let length = headers.len();
         let best = headers.last().block_hash();
+        let current = tree.height();
 
         if let Some(peer) = self.peers.get_mut(from) {
             peer.last_active = Some(clock.local_time());
+            peer.learn(best);
         } else {
             return Ok(ImportResult::TipUnchanged);
         }
@@ -334,19 +547,43 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             return Ok(ImportResult::TipUnchanged);
         }
 
-        match self.inflight.remove(from) {
-            Some(GetHeaders { locators, .. })
-                if headers
-                    .iter()
-                    .any(|h| locators.0.contains(&h.prev_blockhash)) =>
-            {
-                // Requested headers. These should extend our main chain.
-                // Check whether the start of the header chain matches one of the locators we
-                // supplied to the peer. Otherwise, we consider them unsolicited.
+        // Check whether the start of the header chain matches one of the locators we
+        // supplied to the peer. Otherwise, we consider them unsolicited, and the request
+        // stays in flight.
+        let solicited = matches!(
+            self.inflight.get(from),
+            Some(req) if headers.iter().any(|h| req.data.locators.0.contains(&h.prev_blockhash))
+        );
 
-                let result = self.extend_chain(headers, clock, tree);
+        match solicited.then(|| self.fulfill(from, clock.local_time())) {
+            Some(_) => {
+                // Requested headers. These should extend our main chain, unless the peer
+                // is on a different branch.
+                let root = headers.first().prev_blockhash;
+                let is_fork = root != tree.tip().0;
+                let claim = if length < self.config.max_message_headers {
+                    // Since the peer sent us less than the maximum, this is its entire
+                    // branch starting from the fork point.
+                    let branch = headers.iter().cloned().collect::<Vec<_>>();
+
+                    tree.get_block(&root).map(|(fork_height, _)| Claim {
+                        tip: best,
+                        height: fork_height + length as Height,
+                        fork_height,
+                        work: tree::Branch(&branch).work(),
+                    })
+                } else {
+                    None
+                };
+
+                let result = if is_fork {
+                    tree.import_blocks(headers.into_iter(), clock)
+                } else {
+                    self.extend_chain(headers, clock, tree)
+                };
 
                 if let Ok(ref imported) = result {
+                    self.imported(imported, current, tree);
                     self.upstream
                         .event(Event::HeadersImported(imported.clone()));
                 }
@@ -360,6 +597,17 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                     }
                 }
 
+                if let (Ok(_), Some(claim)) = (&result, claim) {
+                    self.forks.claim(*from, claim);
+
+                    if is_fork {
+                        // Our peers disagree on the best chain. Find out which branch
+                        // each of them is on.
+                        self.sample_peers(clock.local_time(), tree);
+                    }
+                    self.resolve_forks(tree);
+                }
+
                 match result {
                     Ok(ImportResult::TipUnchanged) => Ok(ImportResult::TipUnchanged),
                     Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
@@ -401,6 +649,30 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             // Header announcement.
             _ if length <= MAX_HEADERS_ANNOUNCED => {
                 let root = headers.first().block_hash();
+                let parent = headers.first().prev_blockhash;
+
+                if !tree.is_known(&parent) {
+                    // Keep the headers until we have their parent, and ask for the headers
+                    // leading up to it, unless we're already waiting on this peer.
+                    let target = tree.tip().1.target();
+
+                    for header in headers {
+                        self.orphans.insert(*from, header, target);
+                    }
+                    if !self.inflight.contains(from) {
+                        let locators = (tree.locator_hashes(tree.height()), parent);
+                        let timeout = self.config.request_timeout;
+
+                        self.request(
+                            *from,
+                            locators,
+                            clock.local_time(),
+                            timeout,
+                            OnTimeout::Ignore,
+                        );
+                    }
+                    return Ok(ImportResult::TipUnchanged);
+                }
 
                 match tree.import_blocks(headers.into_iter(), clock) {
                     Ok(import_result @ ImportResult::TipUnchanged) => {
@@ -428,16 +700,19 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                             peer.tip = tip;
                             peer.height = height;
                         }
+                        let result = ImportResult::TipChanged(header, tip, height, reverted);
 
-                        self.upstream
-                            .event(Event::HeadersImported(ImportResult::TipChanged(
-                                header,
-                                tip,
-                                height,
-                                reverted.clone(),
-                            )));
-
-                        Ok(ImportResult::TipChanged(header, tip, height, reverted))
+                        self.last_tip_update = Some(clock.local_time());
+                        self.imported(&result, current, tree);
+                        self.upstream.event(Event::HeadersImported(result.clone()));
+                        self.upstream.event(Event::HeadersAnnounced {
+                            from: *from,
+                            tip,
+                            height,
+                        });
+                        self.broadcast_tip(&tip, tree);
+
+                        Ok(result)
                     }
                     Err(err) => self
                         .handle_error(from, err)
@@ -451,9 +726,73 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 self.upstream
                     .event(Event::UnsolicitedHeadersReceived(*from, length));
 
-                Ok(ImportResult::TipUnchanged)
+                Err(Error::Misbehaving {
+                    from: *from,
+                    misbehavior: Misbehavior::UnsolicitedMessage("headers"),
+                })
+            }
+        }
+    }
+
+    /// Called when we receive headers for a segment of the header chain.
+    fn received_segment<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        headers: Vec<BlockHeader>,
+        clock: &impl Clock,
+        tree: &mut T,
+    ) -> Result<ImportResult, Error> {
+        let now = clock.local_time();
+
+        self.fulfill(from, now);
+
+        if let Some(peer) = self.peers.get_mut(from) {
+            peer.last_active = Some(now);
+        }
+        self.upstream
+            .event(Event::HeadersReceived(*from, headers.len()));
+
+        if headers.is_empty() {
+            // The peer doesn't have the segment, let another peer handle it.
+            self.segments.release(from, true);
+            self.sync(now, tree);
+
+            return Ok(ImportResult::TipUnchanged);
+        }
+
+        match self.segments.received(from, &headers) {
+            Ok(Some(locators)) => {
+                let timeout = self.config.request_timeout;
+
+                self.request(*from, locators, now, timeout, OnTimeout::Ignore);
+            }
+            Ok(None) => {}
+            Err(_) => {
+                return Err(Error::Misbehaving {
+                    from: *from,
+                    misbehavior: Misbehavior::InvalidHeaders,
+                });
             }
         }
+
+        let ready = self.segments.ready();
+        let result = if ready.is_empty() {
+            ImportResult::TipUnchanged
+        } else {
+            match self.import_blocks(ready.into_iter(), clock, tree) {
+                Ok(result) => {
+                    self.last_tip_update = Some(now);
+                    result
+                }
+                Err(err) => {
+                    self.handle_error(from, err)?;
+                    ImportResult::TipUnchanged
+                }
+            }
+        };
+        self.sync(now, tree);
+
+        Ok(result)
     }
 
     fn request(
@@ -464,6 +803,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         timeout: Timeout,
         on_timeout: OnTimeout,
     ) {
+        span!("getheaders", peer = %addr, stop_hash = %locators.1, timeout = ?timeout);
+
         if let Some(peer) = self.peers.get_mut(&addr) {
             debug_assert!(peer.last_asked.as_ref() != Some(&locators));
 
@@ -473,13 +814,14 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 addr,
                 locators,
                 timeout,
-                sent_at,
                 on_timeout,
             };
 
-            self.inflight.insert(addr, req.clone());
+            self.inflight.request(addr, addr, req.clone(), sent_at);
             self.upstream.get_headers(req.addr, req.locators);
             self.upstream.set_timeout(req.timeout);
+
+            record!("sending request");
         }
     }
 
@@ -488,7 +830,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         headers: NonEmpty<BlockHeader>,
         clock: &impl Clock,
         tree: &mut T,
-    ) -> Result<ImportResult, Error> {
+    ) -> Result<ImportResult, tree::Error> {
         let mut import_result = ImportResult::TipUnchanged;
 
         for header in headers.into_iter() {
@@ -526,6 +868,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     ) where
         C: Clock,
     {
+        span!("inv", peer = %addr);
+
         if !self.peers.contains_key(&addr) {
             return;
         }
@@ -533,6 +877,9 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
         for i in &inv {
             if let Inventory::Block(hash) = i {
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.learn(*hash);
+                }
                 // TODO: Update block availability for this peer.
                 if !tree.is_known(hash) {
                     self.upstream.event(Event::BlockDiscovered(addr, *hash));
@@ -561,21 +908,19 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
     /// Called when we received a tick.
     pub fn received_tick<T: BlockTree>(&mut self, local_time: LocalTime, tree: &T) {
-        let timeout = self.config.request_timeout;
         let timed_out = self
             .inflight
-            .iter()
-            .filter_map(|(peer, req)| {
-                if local_time - req.sent_at >= timeout {
-                    Some((*peer, req.on_timeout))
-                } else {
-                    None
-                }
-            })
+            .timed_out(local_time)
+            .into_iter()
+            .map(|expired| (expired.id, expired.request.data.on_timeout))
             .collect::<Vec<_>>();
 
         for (peer, on_timeout) in &timed_out {
-            self.inflight.remove(&peer);
+            span!("getheaders", peer = %peer);
+            record!(on_timeout = ?on_timeout, "request timed out");
+
+            self.segments.release(peer, true);
+            self.selector.peer_timed_out(peer);
 
             match on_timeout {
                 OnTimeout::Disconnect => {
@@ -598,11 +943,45 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
+    /// Get the best known height of a peer.
+    pub fn peer_height(&self, id: &PeerId) -> Option<Height> {
+        self.peers.get(id).map(|p| p.height)
+    }
+
     /// Get the best known height out of all our peers.
     pub fn best_height(&self) -> Option<Height> {
         self.peers.iter().map(|(_, p)| p.height).max()
     }
 
+    /// Called when we're shutting down. Cancels all in-flight requests.
+    pub fn shutdown(&mut self) {
+        for peer in self
+            .inflight
+            .iter()
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>()
+        {
+            self.segments.release(&peer, false);
+        }
+        self.inflight.clear();
+    }
+
+    /// Check whether the given headers are a reply to our request to the peer, ie. whether
+    /// they start from one of the locators we sent, or from the segment the peer is
+    /// downloading. An empty reply matches any request.
+    pub fn is_awaiting(&self, from: &PeerId, headers: &[BlockHeader]) -> bool {
+        match self.inflight.get(from) {
+            Some(req) => {
+                headers.is_empty()
+                    || self.segments.expects(from, headers)
+                    || headers
+                        .iter()
+                        .any(|h| req.data.locators.0.contains(&h.prev_blockhash))
+            }
+            None => false,
+        }
+    }
+
     /// Are we currently syncing?
     pub fn is_syncing(&self) -> bool {
         !self.inflight.is_empty()
@@ -610,35 +989,90 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
     ///////////////////////////////////////////////////////////////////////////
 
-    fn handle_error(&mut self, from: &PeerId, err: Error) -> Result<(), store::Error> {
+    fn handle_error(&mut self, from: &PeerId, err: tree::Error) -> Result<(), Error> {
         match err {
             // If this is an error with the underlying store, we have to propagate
             // this up, because we can't handle it here.
-            Error::Store(e) => Err(e),
+            tree::Error::Store(e) => Err(Error::Store(e)),
 
             // If we got a bad block from the peer, we can handle it here.
-            Error::InvalidBlockPoW
-            | Error::InvalidBlockTarget(_, _)
-            | Error::InvalidBlockHash(_, _)
-            | Error::InvalidBlockHeight(_)
-            | Error::InvalidBlockTime(_, _) => {
-                self.record_misbehavior(from);
+            tree::Error::InvalidBlockPoW
+            | tree::Error::InvalidBlockTarget(_, _)
+            | tree::Error::InvalidBlockHash(_, _)
+            | tree::Error::InvalidBlockHeight(_)
+            | tree::Error::InvalidBlockTime(_, _) => {
                 self.upstream
                     .event(Event::InvalidHeadersReceived(*from, Arc::new(err)));
 
-                Ok(())
+                Err(Error::Misbehaving {
+                    from: *from,
+                    misbehavior: Misbehavior::InvalidHeaders,
+                })
             }
 
             // Harmless errors can be ignored.
-            Error::DuplicateBlock(_) | Error::BlockMissing(_) => Ok(()),
+            tree::Error::DuplicateBlock(_) | tree::Error::BlockMissing(_) => Ok(()),
 
             // TODO: This will be removed.
-            Error::BlockImportAborted(_, _, _) => Ok(()),
+            tree::Error::BlockImportAborted(_, _, _) => Ok(()),
         }
     }
 
-    fn record_misbehavior(&mut self, _peer: &PeerId) {
-        // TODO
+    /// Emit an event for every block disconnected and connected by an import, given the
+    /// height of the active chain before the import. If blocks were disconnected, the
+    /// re-org as a whole is also reported, with [`Event::ChainReorged`], and the fork claims
+    /// made against the disconnected blocks are dropped.
+    fn imported<T: BlockTree>(&mut self, result: &ImportResult, height: Height, tree: &T) {
+        if let ImportResult::TipChanged(_, _, tip, reverted) = result {
+            // Reverted blocks are listed in ascending height order, up to the previous tip.
+            let fork = height.saturating_sub(reverted.len() as Height);
+            let disconnected = reverted
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, hash)| (fork + i as Height + 1, *hash))
+                .collect::<Vec<_>>();
+            let connected = (fork + 1..=*tip)
+                .zip(tree.range(fork + 1..*tip + 1))
+                .collect::<Vec<_>>();
+
+            for (height, hash) in disconnected.iter().copied() {
+                self.upstream
+                    .event(Event::BlockDisconnected { hash, height });
+            }
+            for (height, header) in connected.iter().copied() {
+                self.upstream
+                    .event(Event::BlockConnected { header, height });
+            }
+            if !disconnected.is_empty() {
+                self.forks.reverted(fork);
+                self.upstream.event(Event::ChainReorged {
+                    disconnected,
+                    connected,
+                });
+            }
+        }
+    }
+
+    /// Import the orphan headers that can be connected to the block tree, now that their
+    /// parent was imported. Orphans can't be blamed on a peer, so invalid ones are dropped.
+    fn connect_orphans<T: BlockTree>(&mut self, clock: &impl Clock, tree: &mut T) {
+        let headers = match NonEmpty::from_vec(self.orphans.connect(tree)) {
+            Some(headers) => headers,
+            None => return,
+        };
+        let current = tree.height();
+
+        if let Ok(ImportResult::TipChanged(header, tip, height, reverted)) =
+            tree.import_blocks(headers.into_iter(), clock)
+        {
+            let result = ImportResult::TipChanged(header, tip, height, reverted);
+
+            self.last_tip_update = Some(clock.local_time());
+            self.imported(&result, current, tree);
+            self.upstream.event(Event::HeadersImported(result));
+            self.broadcast_tip(&tip, tree);
+        }
     }
 
     /// Check whether our current tip is stale.
@@ -680,17 +1114,43 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 link,
                 last_active,
                 last_asked,
+                prefers_headers: false,
+                known_blocks: VecDeque::new(),
             },
         );
     }
 
+    /// Stop tracking the request in flight with a peer, since it responded.
+    fn fulfill(&mut self, from: &PeerId, now: LocalTime) -> Option<GetHeaders> {
+        let request = self.inflight.fulfill(from)?;
+        self.selector.peer_responded(from, now - request.sent_at);
+
+        Some(request.data)
+    }
+
     /// Unregister a peer.
     fn unregister(&mut self, id: &PeerId) {
-        self.inflight.remove(id);
+        self.segments.release(id, false);
+        self.inflight.cancel(id);
+        self.selector.peer_disconnected(id);
         self.peers.remove(id);
+        self.forks.remove(id);
     }
 
-    /// Pick a random peer we could sync with using the given locators.
+    /// Disconnect peers that are provably on a branch with less work than ours.
+    fn resolve_forks<T: BlockTree>(&mut self, tree: &T) {
+        for peer in self.forks.resolve(tree) {
+            if let Some(Claim { tip, .. }) = self.forks.get(&peer).cloned() {
+                self.upstream.event(Event::StaleBranch(peer, tip));
+            }
+            self.unregister(&peer);
+            self.upstream
+                .disconnect(peer, DisconnectReason::PeerStaleBranch);
+        }
+    }
+
+    /// Pick a random peer we could sync with using the given locators, favoring peers
+    /// that responded quickly in the past.
     fn random_sync_candidate<T: BlockTree>(
         &self,
         locators: &[BlockHash],
@@ -699,15 +1159,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         let candidates = self
             .peers
             .values()
-            .filter(|p| self.is_sync_candidate(p, locators, tree));
-
-        if let Some(peers) = NonEmpty::from_vec(candidates.collect()) {
-            let ix = self.rng.usize(..peers.len());
-
-            return peers.get(ix).cloned();
-        }
+            .filter(|p| self.is_sync_candidate(p, locators, tree))
+            .map(|p| (&p.id, p.height));
+        let id = self.selector.select(candidates, tree.height() + 1)?;
 
-        None
+        self.peers.get(&id)
     }
 
     /// Check whether a peer can be synced with using the given locators.
@@ -719,7 +1175,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     ) -> bool {
         peer.link.is_outbound()
             && peer.height > tree.height()
-            && !self.inflight.contains_key(&peer.id)
+            && !self.inflight.contains(&peer.id)
             && peer.last_asked.as_ref().map_or(true, |l| l.0 != locators)
     }
 
@@ -743,7 +1199,9 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
     /// Check if we're currently syncing with these locators.
     fn syncing(&self, locators: &Locators) -> bool {
-        self.inflight.values().any(|r| &r.locators == locators)
+        self.inflight
+            .iter()
+            .any(|(_, r)| &r.data.locators == locators)
     }
 
     /// Start syncing if we're out of sync.
@@ -772,6 +1230,15 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
         // It looks like we're out of sync...
 
+        // Up to the last checkpoint, download headers from as many peers as we can. If
+        // none of our peers can serve the segments, fall back to syncing with one peer.
+        if !self.segments.is_empty() {
+            if self.sync_segments(now) {
+                return;
+            }
+            self.segments.clear();
+        }
+
         let locators = (tree.locator_hashes(tree.height()), BlockHash::default());
 
         // If we're already fetching these headers, just wait.
@@ -790,25 +1257,67 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
-    /// Broadcast our best block header to connected peers who don't have it.
+    /// Assign segments of the header chain to idle peers. Returns whether any segment is
+    /// being downloaded.
+    fn sync_segments(&mut self, now: LocalTime) -> bool {
+        let idle = self
+            .peers
+            .values()
+            .filter(|p| p.link.is_outbound() && !self.inflight.contains(&p.id))
+            .map(|p| (p.id, p.height, p.last_asked.clone()))
+            .collect::<Vec<_>>();
+
+        for (addr, height, last_asked) in idle {
+            // Peers without a request in flight aren't downloading anything.
+            self.segments.release(&addr, false);
+
+            if let Some(locators) = self.segments.assign(addr, height) {
+                if last_asked.as_ref() == Some(&locators) {
+                    self.segments.release(&addr, true);
+                    continue;
+                }
+                let timeout = self.config.request_timeout;
+
+                self.request(addr, locators, now, timeout, OnTimeout::Ignore);
+                self.upstream.event(Event::Syncing(addr));
+            }
+        }
+        self.segments.is_downloading()
+    }
+
+    /// Announce our best block to connected peers who don't have it, with a `headers`
+    /// message to peers that asked for `sendheaders`, and an `inv` to the others.
     fn broadcast_tip<T: BlockTree>(&mut self, hash: &BlockHash, tree: &T) {
         if let Some((height, best)) = tree.get_block(hash) {
-            for (addr, peer) in &self.peers {
+            for (addr, peer) in self.peers.iter_mut() {
                 // TODO: Don't broadcast to peer that is currently syncing?
-                if peer.link == Link::Inbound && height > peer.height {
+                if height <= peer.height || peer.knows(hash) {
+                    continue;
+                }
+                if peer.prefers_headers {
                     self.upstream.send_headers(*addr, vec![*best]);
+                } else {
+                    self.upstream.send_inv(*addr, vec![*hash]);
                 }
+                peer.learn(*hash);
             }
         }
     }
 
     /// Ask all our outbound peers whether they have better block headers.
+    ///
+    /// Unlike when syncing, peers that seem to be behind are also asked, so that we learn
+    /// which branch they are on.
     fn sample_peers<T: BlockTree>(&mut self, now: LocalTime, tree: &T) {
         let locators = tree.locator_hashes(tree.height());
         let addrs = self
             .peers
             .values()
-            .filter(|p| self.is_sync_candidate(p, &locators, tree))
+            .filter(|p| {
+                p.link.is_outbound()
+                    && !self.inflight.contains(&p.id)
+                    && !matches!(&p.last_asked, Some(l) if l.0 == locators)
+            })
             .map(|p| p.id)
             .collect::<Vec<_>>();
 
@@ -0,0 +1,284 @@
+//! Fork resolution.
+//!
+//! When our peers disagree on the best chain, we learn about each peer's branch from the
+//! headers it sends us in response to our locators. Since these headers carry proof-of-work,
+//! we can tell which peers are on a branch with less work than our active chain.
+//!
+//! Disconnecting such peers is only safe if we aren't alone on our branch: an attacker
+//! controlling many addresses in a single network range could otherwise isolate us. Hence,
+//! we only act once the active chain is corroborated by peers in enough independent
+//! netgroups.
+use std::net;
+
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, Height, Work};
+use nakamoto_common::collections::HashMap;
+
+use crate::protocol::PeerId;
+
+/// Minimum number of netgroups the active chain must be seen from, before peers on
+/// less-work branches are disconnected.
+pub const MIN_NETGROUPS: usize = 2;
+
+/// A network range. Addresses in the same range are likely to be controlled by the
+/// same entity.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Netgroup {
+    /// The `/16` range of an IPv4 address.
+    V4([u8; 2]),
+    /// The `/32` range of an IPv6 address.
+    V6([u16; 2]),
+}
+
+impl From<&net::IpAddr> for Netgroup {
+    fn from(ip: &net::IpAddr) -> Self {
+        match ip {
+            net::IpAddr::V4(ip) => {
+                let [a, b, _, _] = ip.octets();
+                Self::V4([a, b])
+            }
+            net::IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                Self::V6([segments[0], segments[1]])
+            }
+        }
+    }
+}
+
+/// A peer's branch, as learned from the headers it sent us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claim {
+    /// Tip of the branch.
+    pub tip: BlockHash,
+    /// Height of the tip.
+    pub height: Height,
+    /// Height of the last block the branch has in common with our active chain.
+    pub fork_height: Height,
+    /// Proof-of-work of the branch, counting from the block after the fork.
+    pub work: Work,
+}
+
+/// A branch claimed by one or more peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branch {
+    /// Tip of the branch.
+    pub tip: BlockHash,
+    /// Height of the tip.
+    pub height: Height,
+    /// Peers on this branch.
+    pub peers: Vec<PeerId>,
+    /// Number of distinct netgroups the branch was seen from.
+    pub netgroups: usize,
+}
+
+/// Keeps track of the branches our peers are on.
+#[derive(Debug)]
+pub struct ForkResolver {
+    claims: HashMap<PeerId, Claim>,
+}
+
+impl ForkResolver {
+    /// Create a new fork resolver.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            claims: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Record a peer's branch, replacing any earlier claim by the same peer.
+    pub fn claim(&mut self, peer: PeerId, claim: Claim) {
+        self.claims.insert(peer, claim);
+    }
+
+    /// Get a peer's claimed branch.
+    pub fn get(&self, peer: &PeerId) -> Option<&Claim> {
+        self.claims.get(peer)
+    }
+
+    /// Forget about a peer, eg. because it disconnected.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.claims.remove(peer);
+    }
+
+    /// Called when blocks above the given height were reverted from the active chain. Claims
+    /// forking off above that height were measured against blocks that are no longer active,
+    /// and are dropped, until the peers send us their branch again.
+    pub fn reverted(&mut self, fork_height: Height) {
+        self.claims
+            .retain(|_, claim| claim.fork_height <= fork_height);
+    }
+
+    /// Get the claimed branches, grouped by tip. Branches seen from more netgroups
+    /// come first.
+    pub fn branches(&self) -> Vec<Branch> {
+        let mut branches: Vec<(Branch, Vec<Netgroup>)> = Vec::new();
+
+        for (peer, claim) in self.claims.iter() {
+            let netgroup = Netgroup::from(&peer.ip());
+
+            if let Some((branch, netgroups)) = branches.iter_mut().find(|(b, _)| b.tip == claim.tip)
+            {
+                branch.peers.push(*peer);
+                netgroups.push(netgroup);
+            } else {
+                branches.push((
+                    Branch {
+                        tip: claim.tip,
+                        height: claim.height,
+                        peers: vec![*peer],
+                        netgroups: 0,
+                    },
+                    vec![netgroup],
+                ));
+            }
+        }
+
+        let mut branches = branches
+            .into_iter()
+            .map(|(mut branch, mut netgroups)| {
+                netgroups.sort_unstable();
+                netgroups.dedup();
+
+                branch.peers.sort_unstable();
+                branch.netgroups = netgroups.len();
+                branch
+            })
+            .collect::<Vec<_>>();
+
+        branches.sort_by(|a, b| {
+            b.netgroups
+                .cmp(&a.netgroups)
+                .then_with(|| b.height.cmp(&a.height))
+        });
+        branches
+    }
+
+    /// Get the peers that are provably on a branch with less work than our active chain.
+    ///
+    /// Returns nothing unless the active chain is seen from at least [`MIN_NETGROUPS`]
+    /// independent netgroups.
+    pub fn resolve<T: BlockTree>(&self, tree: &T) -> Vec<PeerId> {
+        let work = match tree.chain_work(tree.height()) {
+            Some(work) => work,
+            None => return vec![],
+        };
+        let mut netgroups = self
+            .claims
+            .iter()
+            .filter(|(_, claim)| tree.contains(&claim.tip))
+            .map(|(peer, _)| Netgroup::from(&peer.ip()))
+            .collect::<Vec<_>>();
+
+        netgroups.sort_unstable();
+        netgroups.dedup();
+
+        if netgroups.len() < MIN_NETGROUPS {
+            return vec![];
+        }
+
+        let mut peers = self
+            .claims
+            .iter()
+            .filter(|(_, claim)| !tree.contains(&claim.tip))
+            .filter(|(_, claim)| match tree.chain_work(claim.fork_height) {
+                Some(fork) => claim.work < work - fork,
+                None => false,
+            })
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+
+        peers.sort_unstable();
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_common::block::tree;
+
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_netgroup() {
+        let a: net::IpAddr = [88, 12, 1, 1].into();
+        let b: net::IpAddr = [88, 12, 200, 7].into();
+        let c: net::IpAddr = [88, 13, 1, 1].into();
+
+        assert_eq!(Netgroup::from(&a), Netgroup::from(&b));
+        assert_ne!(Netgroup::from(&a), Netgroup::from(&c));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 8..9, &mut rng);
+        let headers = chain.iter().map(|b| b.header).collect::<Vec<_>>();
+        let tree = model::Cache::from(nonempty::NonEmpty::from_vec(headers.clone()).unwrap());
+        let (tip, _) = tree.tip();
+
+        // A two-block branch forking off at height 5, with less work than the three
+        // blocks on our active chain.
+        let stale = Claim {
+            tip: BlockHash::default(),
+            height: 7,
+            fork_height: 5,
+            work: tree::Branch(&headers[6..8]).work(),
+        };
+        let agree = |height: Height| Claim {
+            tip: headers[height as usize].block_hash(),
+            height,
+            fork_height: height,
+            work: Work::default(),
+        };
+        let sybil = ([44, 1, 0, 1], 8333).into();
+        let honest = ([55, 1, 0, 1], 8333).into();
+        let other = ([66, 1, 0, 1], 8333).into();
+
+        let mut resolver = ForkResolver::new(rng);
+        resolver.claim(sybil, stale.clone());
+        resolver.claim(honest, agree(8));
+        resolver.claim(([55, 1, 0, 2], 8333).into(), agree(7));
+        assert_eq!(tree.height(), 8);
+        assert_eq!(tip, headers[8].block_hash());
+
+        // Our active chain was only seen from a single netgroup.
+        assert!(resolver.resolve(&tree).is_empty());
+
+        resolver.claim(other, agree(8));
+        assert_eq!(resolver.resolve(&tree), vec![sybil]);
+
+        let branches = resolver.branches();
+        assert_eq!(branches[0].tip, tip);
+        assert_eq!(branches[0].peers, {
+            let mut peers = vec![honest, other];
+            peers.sort_unstable();
+            peers
+        });
+        assert_eq!(branches[0].netgroups, 2);
+
+        // A branch with more work isn't provably stale.
+        resolver.claim(
+            sybil,
+            Claim {
+                work: tree::Branch(&[headers[6], headers[7], headers[8], headers[8]]).work(),
+                ..stale
+            },
+        );
+        assert!(resolver.resolve(&tree).is_empty());
+
+        resolver.remove(&sybil);
+        assert_eq!(resolver.branches().len(), 2);
+
+        // Reverting blocks drops the claims made against them.
+        resolver.claim(sybil, stale);
+        resolver.reverted(6);
+        assert_eq!(resolver.get(&sybil).map(|c| c.fork_height), Some(5));
+        assert!(resolver.get(&honest).is_none());
+        assert!(resolver.get(&other).is_none());
+        assert!(resolver.resolve(&tree).is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+//! Orphan headers.
+//!
+//! Headers may be announced out of order, in which case we don't have their parent yet.
+//! Instead of discarding them, and downloading them again once we have the parent, they are
+//! kept in a pool until they can be connected. The pool is bounded, since orphans can't be
+//! fully validated: when it's full, the oldest orphans are evicted first.
+//!
+//! Since an orphan's difficulty can't be checked against its parent, orphans are only checked
+//! to be roughly as hard to produce as our tip, and no peer can take up more than a few slots
+//! of the pool, so that cheap headers can't be used to flush out the others.
+use std::collections::VecDeque;
+
+use bitcoin::consensus::params::Params;
+
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, BlockHeader, Target};
+use nakamoto_common::collections::HashMap;
+
+use crate::protocol::PeerId;
+
+/// Maximum number of orphan headers kept.
+pub const MAX_ORPHANS: usize = 64;
+/// Maximum number of orphan headers kept from a single peer.
+pub const MAX_ORPHANS_PER_PEER: usize = 8;
+/// Maximum factor by which an orphan's target may exceed the target of our tip, as a power
+/// of two. Difficulty can't drop by more than a factor of four in a retarget period.
+pub const MAX_TARGET_SHIFT: usize = 2;
+
+/// A bounded pool of orphan headers.
+#[derive(Debug)]
+pub struct OrphanPool {
+    /// Orphan headers, by block hash, with the peer that sent them.
+    headers: HashMap<BlockHash, (BlockHeader, PeerId)>,
+    /// Orphans in the pool, oldest first.
+    order: VecDeque<BlockHash>,
+    /// Highest target allowed.
+    pow_limit: Target,
+    /// Whether blocks may have the highest target allowed, regardless of the difficulty.
+    allow_min_difficulty_blocks: bool,
+}
+
+impl OrphanPool {
+    /// Create a new, empty orphan pool.
+    pub fn new(params: &Params, rng: fastrand::Rng) -> Self {
+        Self {
+            headers: HashMap::with_hasher(rng.into()),
+            order: VecDeque::new(),
+            pow_limit: params.pow_limit,
+            allow_min_difficulty_blocks: params.allow_min_difficulty_blocks,
+        }
+    }
+
+    /// Number of orphans in the pool.
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// Check whether the pool is empty.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Check whether a header is in the pool.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Add an orphan header sent by the given peer to the pool. Headers with invalid
+    /// proof-of-work, or a target much higher than the target of our tip, are ignored, and so
+    /// are headers from peers that have too many orphans in the pool already. Returns whether
+    /// the header was added.
+    pub fn insert(&mut self, from: PeerId, header: BlockHeader, tip_target: Target) -> bool {
+        let hash = header.block_hash();
+        let target = header.target();
+
+        if self.headers.contains_key(&hash)
+            || target > self.pow_limit
+            || header.validate_pow(&target).is_err()
+        {
+            return false;
+        }
+        if !self.allow_min_difficulty_blocks && target >> MAX_TARGET_SHIFT > tip_target {
+            return false;
+        }
+        if self.headers.values().filter(|(_, p)| *p == from).count() >= MAX_ORPHANS_PER_PEER {
+            return false;
+        }
+        self.headers.insert(hash, (header, from));
+        self.order.push_back(hash);
+
+        while self.order.len() > MAX_ORPHANS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.headers.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Remove the orphans that connect to the block tree, directly or through other
+    /// orphans, and return them, parents first, ready to be imported.
+    pub fn connect<T: BlockTree>(&mut self, tree: &T) -> Vec<BlockHeader> {
+        let mut connected = Vec::new();
+        let mut parents = self
+            .headers
+            .values()
+            .map(|(h, _)| h)
+            .filter(|h| tree.is_known(&h.prev_blockhash))
+            .map(|h| h.prev_blockhash)
+            .collect::<VecDeque<_>>();
+
+        while let Some(parent) = parents.pop_front() {
+            let children = self
+                .headers
+                .values()
+                .map(|(h, _)| h)
+                .filter(|h| h.prev_blockhash == parent)
+                .map(|h| h.block_hash())
+                .collect::<Vec<_>>();
+
+            for hash in children {
+                if let Some((header, _)) = self.headers.remove(&hash) {
+                    // Orphans imported in the meantime, eg. from another peer, are dropped.
+                    if !tree.is_known(&hash) {
+                        connected.push(header);
+                    }
+                    parents.push_back(hash);
+                }
+            }
+        }
+        let headers = &self.headers;
+        self.order.retain(|hash| headers.contains_key(hash));
+
+        connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    fn params() -> Params {
+        Params {
+            allow_min_difficulty_blocks: false,
+            ..Params::new(bitcoin::Network::Regtest)
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 6..7, &mut rng);
+        let headers = chain.iter().map(|b| b.header).collect::<Vec<_>>();
+        let tree = model::Cache::from(nonempty::NonEmpty::from((
+            headers[0],
+            headers[1..3].to_vec(),
+        )));
+        let mut pool = OrphanPool::new(&params(), rng);
+        let peer: PeerId = ([88, 88, 88, 88], 8333).into();
+        let target = headers[2].target();
+
+        // Orphans are received out of order.
+        assert!(pool.insert(peer, headers[5], target));
+        assert!(pool.insert(peer, headers[4], target));
+        assert!(!pool.insert(peer, headers[4], target));
+        assert_eq!(pool.len(), 2);
+
+        // The parent of the orphans is missing.
+        assert!(pool.connect(&tree).is_empty());
+        assert_eq!(pool.len(), 2);
+
+        // The parent is imported.
+        let tree = model::Cache::from(nonempty::NonEmpty::from((
+            headers[0],
+            headers[1..4].to_vec(),
+        )));
+        assert_eq!(pool.connect(&tree), vec![headers[4], headers[5]]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let mut pool = OrphanPool::new(&params(), rng.clone());
+        let mut orphans = Vec::new();
+
+        for i in 0..MAX_ORPHANS + 1 {
+            let parent = gen::header(&genesis.header, genesis.header.merkle_root, &mut rng);
+            let orphan = gen::header(&parent, parent.merkle_root, &mut rng);
+            let peer: PeerId = ([88, 88, 88, i as u8], 8333).into();
+
+            assert!(pool.insert(peer, orphan, genesis.header.target()));
+            orphans.push(orphan);
+        }
+        // The oldest orphan was evicted.
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&orphans[0].block_hash()));
+        assert!(pool.contains(&orphans[MAX_ORPHANS].block_hash()));
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let mut pool = OrphanPool::new(&params(), rng.clone());
+        let mut mainnet = OrphanPool::new(&Params::new(bitcoin::Network::Bitcoin), rng.clone());
+        let mut orphan = || {
+            let parent = gen::header(&genesis.header, genesis.header.merkle_root, &mut rng);
+            gen::header(&parent, parent.merkle_root, &mut rng)
+        };
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let target = genesis.header.target();
+
+        // Orphans much easier to produce than our tip are rejected.
+        assert!(!pool.insert(alice, orphan(), target >> (MAX_TARGET_SHIFT + 1)));
+        assert!(pool.insert(alice, orphan(), target >> MAX_TARGET_SHIFT));
+
+        // So are orphans above the network's highest target.
+        assert!(!mainnet.insert(alice, orphan(), target));
+
+        // A single peer can't fill the pool.
+        for _ in 1..MAX_ORPHANS_PER_PEER {
+            assert!(pool.insert(alice, orphan(), target));
+        }
+        assert!(!pool.insert(alice, orphan(), target));
+        assert!(pool.insert(bob, orphan(), target));
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER + 1);
+    }
+}
@@ -0,0 +1,378 @@
+//! Parallel header download.
+//!
+//! Up to the last checkpoint, the headers we are missing are split into segments ending at
+//! checkpoints. Since the hashes at both ends of a segment are known in advance, segments
+//! can be requested from different peers at the same time, with `getheaders` locators that
+//! don't overlap. A segment is only imported once it links up from its start all the way to
+//! its checkpoint, and only after the segments before it are imported, so headers from a
+//! peer serving a bogus chain never make it into the block tree.
+//!
+//! Past the last checkpoint, headers are downloaded from one peer at a time, and peers
+//! disagreeing on the best chain are told apart by the work of their branches.
+use std::collections::{HashSet, VecDeque};
+
+use bitcoin::util::uint::Uint256;
+
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+
+use thiserror::Error;
+
+use crate::protocol::{Locators, PeerId};
+
+/// An error with the headers received for a segment.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The headers don't form a chain.
+    #[error("headers don't connect")]
+    Disconnected,
+    /// A header doesn't have valid proof-of-work.
+    #[error("header {0} has invalid proof-of-work")]
+    InvalidPoW(BlockHash),
+    /// The header at a checkpoint height doesn't match the checkpoint.
+    #[error("header at height {0} doesn't match checkpoint")]
+    CheckpointMismatch(Height),
+}
+
+/// A range of headers ending at a checkpoint.
+#[derive(Debug)]
+struct Segment {
+    /// Hash of the block the segment starts after.
+    start: BlockHash,
+    /// Height of the block the segment starts after.
+    start_height: Height,
+    /// Height and hash of the last block of the segment.
+    stop: (Height, BlockHash),
+    /// Headers received so far.
+    headers: Vec<BlockHeader>,
+    /// Peer the segment is being downloaded from.
+    peer: Option<PeerId>,
+    /// Peers that failed to serve this segment.
+    failed: HashSet<PeerId>,
+}
+
+impl Segment {
+    /// Hash of the last header we have in this segment.
+    fn cursor(&self) -> BlockHash {
+        self.headers
+            .last()
+            .map_or(self.start, |header| header.block_hash())
+    }
+
+    /// Height of the last header we have in this segment.
+    fn height(&self) -> Height {
+        self.start_height + self.headers.len() as Height
+    }
+
+    fn is_complete(&self) -> bool {
+        self.height() == self.stop.0
+    }
+
+    fn locators(&self) -> Locators {
+        (vec![self.cursor()], self.stop.1)
+    }
+}
+
+/// Segments of the header chain left to download.
+#[derive(Debug)]
+pub struct Segments {
+    segments: VecDeque<Segment>,
+    /// Easiest proof-of-work target allowed.
+    pow_limit: Uint256,
+}
+
+impl Segments {
+    /// Split the headers from our tip up to the last checkpoint into segments.
+    pub fn new(
+        tip: (Height, BlockHash),
+        checkpoints: impl IntoIterator<Item = (Height, BlockHash)>,
+        pow_limit: Uint256,
+    ) -> Self {
+        let mut segments = VecDeque::new();
+        let (mut start_height, mut start) = tip;
+
+        for (height, hash) in checkpoints {
+            if height <= start_height {
+                continue;
+            }
+            segments.push_back(Segment {
+                start,
+                start_height,
+                stop: (height, hash),
+                headers: Vec::new(),
+                peer: None,
+                failed: HashSet::new(),
+            });
+            start = hash;
+            start_height = height;
+        }
+
+        Self {
+            segments,
+            pow_limit,
+        }
+    }
+
+    /// Check whether all segments were downloaded and taken.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Check whether any segment is being downloaded.
+    pub fn is_downloading(&self) -> bool {
+        self.segments.iter().any(|s| s.peer.is_some())
+    }
+
+    /// Forget all segments, eg. to fall back to downloading headers one peer at a time.
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+
+    /// Check whether the given headers are the response we expect from a peer, ie. whether
+    /// they pick up where the peer's segment left off.
+    pub fn expects(&self, peer: &PeerId, headers: &[BlockHeader]) -> bool {
+        match self.segments.iter().find(|s| s.peer.as_ref() == Some(peer)) {
+            Some(segment) => match headers.first() {
+                Some(header) => header.prev_blockhash == segment.cursor(),
+                None => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Assign the first segment that isn't being downloaded to a peer at the given height.
+    /// Returns the locators to request the segment with.
+    pub fn assign(&mut self, peer: PeerId, height: Height) -> Option<Locators> {
+        if self.segments.iter().any(|s| s.peer == Some(peer)) {
+            return None;
+        }
+        let segment = self.segments.iter_mut().find(|s| {
+            s.peer.is_none() && !s.is_complete() && s.stop.0 <= height && !s.failed.contains(&peer)
+        })?;
+        segment.peer = Some(peer);
+
+        Some(segment.locators())
+    }
+
+    /// Stop downloading from a peer, eg. because it disconnected. If the peer failed to
+    /// serve its segment, it won't be assigned the segment again.
+    pub fn release(&mut self, peer: &PeerId, failed: bool) {
+        for segment in self.segments.iter_mut() {
+            if segment.peer.as_ref() == Some(peer) {
+                segment.peer = None;
+
+                if failed {
+                    segment.failed.insert(*peer);
+                }
+            }
+        }
+    }
+
+    /// Called when headers were received for the segment assigned to a peer. Returns the
+    /// locators to request the rest of the segment with, if it isn't complete.
+    ///
+    /// If the headers are invalid, the peer's segment is emptied and released, since
+    /// the headers it sent before can't be trusted either.
+    pub fn received(
+        &mut self,
+        peer: &PeerId,
+        headers: &[BlockHeader],
+    ) -> Result<Option<Locators>, Error> {
+        let pow_limit = self.pow_limit;
+        let segment = match self
+            .segments
+            .iter_mut()
+            .find(|s| s.peer.as_ref() == Some(peer))
+        {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+
+        if let Err(err) = Self::validate(segment, headers, pow_limit) {
+            segment.headers.clear();
+            segment.peer = None;
+            segment.failed.insert(*peer);
+
+            return Err(err);
+        }
+        segment.headers.extend_from_slice(headers);
+
+        if segment.is_complete() {
+            segment.peer = None;
+
+            return Ok(None);
+        }
+        Ok(Some(segment.locators()))
+    }
+
+    /// Take the headers of the complete segments that follow our tip, in order.
+    pub fn ready(&mut self) -> Vec<BlockHeader> {
+        let mut headers = Vec::new();
+
+        while matches!(self.segments.front(), Some(s) if s.is_complete()) {
+            if let Some(segment) = self.segments.pop_front() {
+                headers.extend(segment.headers);
+            }
+        }
+        headers
+    }
+
+    fn validate(
+        segment: &Segment,
+        headers: &[BlockHeader],
+        pow_limit: Uint256,
+    ) -> Result<(), Error> {
+        let mut prev = segment.cursor();
+        let mut height = segment.height();
+
+        for header in headers {
+            let hash = header.block_hash();
+            let target = header.target();
+
+            if header.prev_blockhash != prev {
+                return Err(Error::Disconnected);
+            }
+            if target > pow_limit || header.validate_pow(&target).is_err() {
+                return Err(Error::InvalidPoW(hash));
+            }
+            height += 1;
+
+            if height == segment.stop.0 && hash != segment.stop.1 || height > segment.stop.0 {
+                return Err(Error::CheckpointMismatch(segment.stop.0));
+            }
+            prev = hash;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_test::block::gen;
+
+    /// Generate a chain of headers, and checkpoints every `interval` headers.
+    fn chain(
+        length: usize,
+        interval: usize,
+        rng: &mut fastrand::Rng,
+    ) -> (Vec<BlockHeader>, Vec<(Height, BlockHash)>) {
+        let genesis = gen::genesis(rng).header;
+        let mut headers = vec![genesis];
+
+        for _ in 0..length {
+            let prev = *headers.last().unwrap();
+            headers.push(gen::header(&prev, Default::default(), rng));
+        }
+        let checkpoints = headers
+            .iter()
+            .enumerate()
+            .skip(interval)
+            .step_by(interval)
+            .map(|(height, h)| (height as Height, h.block_hash()))
+            .collect();
+
+        (headers, checkpoints)
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let (headers, checkpoints) = chain(30, 10, &mut rng);
+        let pow_limit = headers[0].target();
+        let tip = (5, headers[5].block_hash());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+
+        let mut segments = Segments::new(tip, checkpoints.clone(), pow_limit);
+        assert_eq!(segments.segments.len(), 3);
+
+        // Peers are assigned different segments, each within their reach.
+        assert_eq!(
+            segments.assign(alice, 30),
+            Some((vec![tip.1], checkpoints[0].1))
+        );
+        assert_eq!(segments.assign(alice, 30), None);
+        assert_eq!(
+            segments.assign(bob, 20),
+            Some((vec![checkpoints[0].1], checkpoints[1].1))
+        );
+
+        // Segments ahead of our tip aren't ready until the ones before them are.
+        assert!(segments.expects(&bob, &headers[11..]));
+        assert!(!segments.expects(&bob, &headers[6..]));
+        assert_eq!(segments.received(&bob, &headers[11..=20]), Ok(None));
+        assert!(segments.ready().is_empty());
+
+        // A segment may take more than one request.
+        assert_eq!(
+            segments.received(&alice, &headers[6..8]),
+            Ok(Some((vec![headers[7].block_hash()], checkpoints[0].1)))
+        );
+        assert_eq!(segments.received(&alice, &headers[8..=10]), Ok(None));
+        assert_eq!(segments.ready(), headers[6..=20].to_vec());
+
+        assert_eq!(
+            segments.assign(alice, 30),
+            Some((vec![checkpoints[1].1], checkpoints[2].1))
+        );
+        assert!(segments.is_downloading());
+        assert_eq!(segments.received(&alice, &headers[21..=30]), Ok(None));
+        assert_eq!(segments.ready(), headers[21..=30].to_vec());
+        assert!(segments.is_empty());
+    }
+
+    #[test]
+    fn test_bogus_segment() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let (headers, checkpoints) = chain(20, 10, &mut rng);
+        let pow_limit = headers[0].target();
+        let tip = (0, headers[0].block_hash());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+
+        let mut segments = Segments::new(tip, checkpoints.clone(), pow_limit);
+
+        // A branch that doesn't lead to the checkpoint.
+        let mut bogus = headers[1..10].to_vec();
+        bogus.push(gen::header(&headers[9], Default::default(), &mut rng));
+
+        segments.assign(alice, 20);
+        assert!(segments.received(&alice, &bogus[..5]).unwrap().is_some());
+        assert_eq!(
+            segments.received(&alice, &bogus[5..]),
+            Err(Error::CheckpointMismatch(10))
+        );
+
+        // The segment starts over with another peer, and isn't given back to the first.
+        assert_eq!(
+            segments.assign(alice, 20),
+            Some((vec![checkpoints[0].1], checkpoints[1].1))
+        );
+        segments.release(&alice, false);
+        assert_eq!(
+            segments.assign(bob, 20),
+            Some((vec![tip.1], checkpoints[0].1))
+        );
+
+        // Headers that don't connect.
+        assert_eq!(
+            segments.received(&bob, &[headers[1], headers[3]]),
+            Err(Error::Disconnected)
+        );
+
+        // Headers without proof-of-work.
+        let mut header = headers[1];
+        header.nonce = header.nonce.wrapping_add(1);
+        while header.validate_pow(&header.target()).is_ok() {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        let carol: PeerId = ([77, 77, 77, 77], 8333).into();
+        segments.assign(carol, 20);
+        assert_eq!(
+            segments.received(&carol, &[header]),
+            Err(Error::InvalidPoW(header.block_hash()))
+        );
+        assert!(!segments.is_downloading());
+    }
+}
@@ -1,28 +1,29 @@
 #![cfg(test)]
 pub mod peer;
-pub mod simulator;
 
 use std::iter;
 use std::net;
-use std::ops::Range;
 use std::sync::Arc;
 
 use log::*;
 
-use super::{addrmgr, connmgr, peermgr, pingmgr, spvmgr, syncmgr};
+use super::{addrmgr, blockmgr, connmgr, feemgr, invmgr, peermgr, pingmgr, ratemgr};
 use super::{
-    chan, message, AdjustedTime, BlockHash, BlockHeader, BlockTree as _, Command, Config,
-    DisconnectReason, Event, HashSet, Height, Input, Link, LocalDuration, LocalTime, Network,
-    NetworkMessage, Out, PeerId, RawNetworkMessage, ServiceFlags, VersionMessage,
+    chan, message, AdjustedTime, BlockHash, BlockHeader, BlockTime, BlockTree as _, Blocks,
+    Command, Config, DisconnectReason, Event, HashSet, Height, ImportResult, Input, Link,
+    LocalDuration, LocalTime, Network, NetworkMessage, Out, PeerId, RawNetworkMessage,
+    ServiceFlags, SnapshotReader, VersionMessage,
 };
+use super::{spvmgr, syncmgr};
 use super::{PROTOCOL_VERSION, USER_AGENT};
 
+use super::simulator::{Options, Simulation};
 use peer::{Peer, PeerDummy};
-use simulator::{Options, Simulation};
 
 use bitcoin::network::message_blockdata::Inventory;
 use bitcoin::network::Address;
 use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::Hash as _;
 
 use quickcheck_macros::quickcheck;
 
@@ -34,6 +35,7 @@ use nakamoto_common::p2p::peer::KnownAddress;
 use nakamoto_common::p2p::peer::Source;
 
 use nakamoto_test::block::cache::model;
+use nakamoto_test::block::gen;
 use nakamoto_test::BITCOIN_HEADERS;
 
 #[allow(unused_imports)]
@@ -133,6 +135,33 @@ fn test_initial_sync() {
     }
 }
 
+/// Test that inputs can be scheduled and events observed through the simulator.
+#[test]
+fn test_simulator_input() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let time = LocalTime::from_block_time(network.genesis().time);
+
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let mut bob = Peer::genesis("bob", [97, 97, 97, 97], network, vec![], rng.clone());
+    let mut simulation = Simulation::new(time, rng, Options::default());
+
+    simulation.initialize([&mut alice, &mut bob]);
+    simulation.input(alice.addr.ip(), Input::Command(Command::Connect(bob.addr)));
+
+    let mut negotiated = false;
+    while simulation.step([&mut alice, &mut bob]) && !negotiated {
+        negotiated = simulation.events().any(|(node, e)| {
+            node == alice.addr.ip()
+                && matches!(
+                    e,
+                    Event::PeerManager(peermgr::Event::PeerNegotiated { addr, .. }) if addr == bob.addr
+                )
+        });
+    }
+    assert!(negotiated, "alice negotiated with bob");
+}
+
 /// Test what happens when a peer is idle for too long.
 #[test]
 fn test_idle_disconnect() {
@@ -175,6 +204,114 @@ fn test_idle_disconnect() {
         .expect("peer disconnects remote");
 }
 
+/// Test that peer connection and disconnection events carry the peer's metadata.
+#[test]
+fn test_peer_connected_disconnected_events() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+    peer.upstream
+        .try_iter()
+        .find(|o| {
+            matches!(
+                o,
+                Out::Event(Event::PeerManager(peermgr::Event::PeerConnected {
+                    addr,
+                    link: Link::Outbound,
+                    height: 144,
+                    version,
+                    ..
+                })) if addr == &remote && *version == PROTOCOL_VERSION
+            )
+        })
+        .expect("peer connected event is emitted");
+
+    peer.step(Input::Disconnected(remote, DisconnectReason::Command));
+    peer.upstream
+        .try_iter()
+        .find(|o| {
+            matches!(
+                o,
+                Out::Event(Event::PeerManager(peermgr::Event::PeerDisconnected {
+                    addr,
+                    reason: DisconnectReason::Command,
+                })) if addr == &remote
+            )
+        })
+        .expect("peer disconnected event is emitted");
+}
+
+/// Test that inbound peers are pinged only when they go quiet, and given more time to respond.
+#[test]
+fn test_idle_inbound_disconnect() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+    let msg = message::Builder::new(network);
+    let pinged = |upstream: &chan::Receiver<Out>| {
+        upstream.try_iter().any(|o| {
+            matches!(o, Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::Ping(_), ..
+                },
+            ) if addr == remote)
+        })
+    };
+    let disconnected = |upstream: &chan::Receiver<Out>| {
+        upstream.try_iter().any(|o| {
+            matches!(o, Out::Disconnect(
+                addr,
+                DisconnectReason::PeerTimeout("ping")
+            ) if addr == remote)
+        })
+    };
+
+    peer.connect_addr(&remote, Link::Inbound);
+
+    // The usual ping interval doesn't apply to inbound peers.
+    peer.time.elapse(pingmgr::PING_INTERVAL);
+    peer.tick();
+    assert!(!pinged(&peer.upstream));
+
+    // Activity from the remote delays the ping.
+    peer.time.elapse(pingmgr::INBOUND_PING_INTERVAL / 2);
+    peer.step(Input::Received(
+        remote,
+        msg.raw(NetworkMessage::SendHeaders),
+    ));
+    peer.time.elapse(pingmgr::INBOUND_PING_INTERVAL / 2);
+    peer.tick();
+    assert!(!pinged(&peer.upstream));
+
+    peer.time.elapse(pingmgr::INBOUND_PING_INTERVAL / 2);
+    peer.tick();
+    assert!(
+        pinged(&peer.upstream),
+        "`ping` is sent once the remote is quiet"
+    );
+
+    peer.time.elapse(pingmgr::PING_TIMEOUT);
+    peer.tick();
+    assert!(!disconnected(&peer.upstream));
+
+    // Activity other than a `pong` doesn't stop the ping from timing out.
+    peer.step(Input::Received(
+        remote,
+        msg.raw(NetworkMessage::SendHeaders),
+    ));
+    peer.time.elapse(pingmgr::INBOUND_PING_TIMEOUT);
+    peer.tick();
+    assert!(
+        disconnected(&peer.upstream),
+        "peer disconnects unresponsive remote"
+    );
+}
+
 #[test]
 fn test_inv_getheaders() {
     let rng = fastrand::Rng::new();
@@ -274,7 +411,7 @@ fn test_maintain_connections() {
             .upstream
             .try_iter()
             .find_map(|o| match o {
-                Out::Connect(addr, _) => Some(addr),
+                Out::Connect(addr, _, _) => Some(addr),
                 _ => None,
             })
             .expect("Alice connects to a new peer");
@@ -540,7 +677,7 @@ fn test_connection_error() {
     peer.step(Input::Command(Command::Connect(remote.addr)));
     peer.upstream
         .try_iter()
-        .find(|o| matches!(o, Out::Connect(addr, _) if *addr == remote.addr))
+        .find(|o| matches!(o, Out::Connect(addr, _, _) if *addr == remote.addr))
         .expect("Alice should try to connect to remote");
     peer.step(Input::Connecting { addr: remote.addr });
     // Make sure we can handle a disconnection before an established connection.
@@ -603,10 +740,101 @@ fn test_getaddr() {
     alice
         .upstream
         .try_iter()
-        .find(|o| matches!(o, Out::Connect(addr, _) if addr == &toto))
+        .find(|o| matches!(o, Out::Connect(addr, _, _) if addr == &toto))
         .expect("Alice tries to connect to Toto");
 }
 
+#[test]
+fn test_addr_relay() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob: PeerId = ([241, 19, 44, 18], 8333).into();
+    let eve: PeerId = ([241, 19, 44, 19], 8333).into();
+    let toto: net::SocketAddr = ([14, 45, 16, 57], 8333).into();
+
+    alice.connect_addr(&bob, Link::Outbound);
+    alice.connect_addr(&eve, Link::Inbound);
+
+    alice.step(Input::Received(
+        bob,
+        msg.raw(NetworkMessage::Addr(vec![(
+            alice.time.block_time(),
+            Address::new(&toto, ServiceFlags::NETWORK),
+        )])),
+    ));
+    alice.upstream.try_iter().for_each(drop);
+
+    alice.time.elapse(addrmgr::RELAY_INTERVAL);
+    alice.tick();
+
+    let relayed = alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .filter_map(|(addr, msg)| match msg {
+            NetworkMessage::Addr(addrs) => Some((addr, addrs)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Toto is only relayed to Eve, since Bob told us about it.
+    assert_eq!(relayed.len(), 1);
+    assert_eq!(relayed[0].0, eve);
+    assert_eq!(relayed[0].1.len(), 1);
+    assert_eq!(relayed[0].1[0].1.socket_addr().unwrap(), toto);
+
+    // Addresses are only relayed once.
+    alice.time.elapse(addrmgr::RELAY_INTERVAL);
+    alice.tick();
+    assert!(!alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .any(|(_, msg)| matches!(msg, NetworkMessage::Addr(_))));
+}
+
+#[test]
+fn test_query_addresses() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob: PeerId = ([241, 19, 44, 18], 8333).into();
+    let eve: PeerId = ([241, 19, 44, 19], 8333).into();
+    let seed: net::SocketAddr = ([14, 45, 16, 57], 8333).into();
+
+    alice.connect_addr(&bob, Link::Outbound);
+    alice.connect_addr(&eve, Link::Outbound);
+    alice.upstream.try_iter().for_each(drop);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::QueryAddresses(vec![seed, seed], reply));
+
+    let (count, mut peers) = result.try_recv().unwrap();
+    peers.sort();
+
+    assert_eq!(count, 1, "The seed address is only added once");
+    assert_eq!(peers, {
+        let mut peers = vec![bob, eve];
+        peers.sort();
+        peers
+    });
+
+    // Both peers are asked for addresses, even though we just asked them during the handshake.
+    let asked = alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .filter(|(_, msg)| matches!(msg, NetworkMessage::GetAddr))
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>();
+
+    assert_eq!(asked.len(), 2);
+    assert!(asked.contains(&bob));
+    assert!(asked.contains(&eve));
+}
+
 #[test]
 fn test_stale_tip() {
     let rng = fastrand::Rng::new();
@@ -751,7 +979,7 @@ fn prop_connect_timeout(seed: u64) {
     let result = alice
         .upstream
         .try_iter()
-        .filter(|o| matches!(o, Out::Connect(_, _)))
+        .filter(|o| matches!(o, Out::Connect(_, _, _)))
         .collect::<Vec<_>>();
 
     assert_eq!(
@@ -762,7 +990,7 @@ fn prop_connect_timeout(seed: u64) {
     let mut attempted: Vec<net::SocketAddr> = result
         .into_iter()
         .map(|r| match r {
-            Out::Connect(addr, _) => addr,
+            Out::Connect(addr, _, _) => addr,
             _ => panic!(),
         })
         .collect();
@@ -777,50 +1005,1884 @@ fn prop_connect_timeout(seed: u64) {
     alice.tick();
 
     assert!(alice.upstream.try_iter().all(|o| match o {
-        Out::Connect(addr, _) => !attempted.contains(&addr),
+        Out::Connect(addr, _, _) => !attempted.contains(&addr),
         _ => true,
     }));
 }
 
-/// Test that we can find and connect to peers amidst network errors.
+/// Test that connections through a proxy use credentials unique to each connection.
 #[test]
-fn sim_connect_to_peers() {
-    logger::init(log::Level::Debug);
+fn test_connect_proxy() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let proxy: net::SocketAddr = ([127, 0, 0, 1], 9050).into();
+    let config = Config {
+        target: "alice",
+        target_outbound_peers: 2,
+        connect: vec![
+            ([77, 77, 77, 77], network.port()).into(),
+            ([88, 88, 88, 88], network.port()).into(),
+        ],
+        network,
+        proxy: Some(proxy),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], config, rng);
+
+    alice.protocol.initialize(alice.time);
+
+    let proxies = alice
+        .upstream
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Connect(_, _, proxy) => proxy,
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(proxies.len(), 2);
+    assert!(proxies.iter().all(|p| p.addr == proxy));
+
+    let credentials = proxies
+        .into_iter()
+        .map(|p| p.credentials.expect("streams are isolated by default"))
+        .collect::<Vec<_>>();
+    assert_ne!(credentials[0], credentials[1]);
+}
+
+/// Test that blocks deeper than what `NETWORK_LIMITED` peers serve are only requested from
+/// full nodes.
+#[test]
+fn test_getblock_limited_peers() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let limited = PeerDummy::new(
+        [131, 31, 11, 33],
+        network,
+        height,
+        ServiceFlags::NETWORK_LIMITED,
+    );
+    let full = PeerDummy::new([131, 31, 11, 66], network, height, ServiceFlags::NETWORK);
 
+    alice.connect(&limited, Link::Outbound);
+
+    // The limited peer can't serve deep blocks.
+    let deep = headers[0].block_hash();
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(deep, reply));
+    assert!(result.try_recv().unwrap().is_err());
+
+    // ... but recent blocks are fine.
+    let recent = headers.last().unwrap().block_hash();
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(recent, reply));
+    assert_eq!(result.try_recv().unwrap().unwrap(), limited.addr);
+
+    alice.connect(&full, Link::Outbound);
+
+    for _ in 0..16 {
+        let (reply, result) = chan::bounded(1);
+        alice.command(Command::GetBlock(deep, reply));
+        assert_eq!(result.try_recv().unwrap().unwrap(), full.addr);
+    }
+}
+
+/// Test that blocks are requested along with decoys, which aren't passed on to the user.
+#[test]
+fn test_getblock_decoys() {
     let rng = fastrand::Rng::with_seed(1);
     let network = Network::Mainnet;
     let headers = BITCOIN_HEADERS.tail.to_vec();
-    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+    let height = headers.len() as Height;
+    let msg = message::Builder::new(network);
+    let config = Config {
+        target: "alice",
+        network,
+        decoy_blocks: 2.,
+        ..Config::default()
+    };
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        headers.clone(),
+        vec![],
+        vec![],
+        config,
+        rng,
+    );
+    let peers = (1..=3)
+        .map(|i| PeerDummy::new([131, 31, 11, i], network, height, ServiceFlags::NETWORK))
+        .collect::<Vec<_>>();
 
-    // Alice will try to connect to enough outbound peers.
-    let mut peers = peer::network(network, connmgr::TARGET_OUTBOUND_PEERS + 1, rng.clone());
-    let addrs = peers
-        .iter()
-        .map(|p| (p.addr, Source::Dns, p.cfg.services))
+    for peer in &peers {
+        alice.connect(peer, Link::Outbound);
+    }
+    alice.upstream.try_iter().for_each(drop);
+
+    let hash = headers[height as usize / 2].block_hash();
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(hash, reply));
+    let peer = result.try_recv().unwrap().unwrap();
+
+    // The block and its decoys are each requested from a different peer.
+    let requests = alice
+        .upstream
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::GetData(inv),
+                    ..
+                },
+            ) => match inv.as_slice() {
+                [Inventory::Block(hash)] => Some((addr, *hash)),
+                _ => None,
+            },
+            _ => None,
+        })
         .collect::<Vec<_>>();
-    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, addrs, rng.clone());
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0], (peer, hash));
+    assert_eq!(
+        requests
+            .iter()
+            .map(|(addr, _)| addr)
+            .collect::<HashSet<_>>()
+            .len(),
+        3
+    );
 
-    let mut simulator = Simulation::new(
-        time,
+    // Only the requested block is passed on to the user.
+    for (addr, hash) in requests {
+        let (_, header) = alice.protocol.tree.get_block(&hash).unwrap();
+        let block = bitcoin::Block {
+            header: *header,
+            txdata: vec![],
+        };
+        alice.step(Input::Received(addr, msg.raw(NetworkMessage::Block(block))));
+    }
+    let received = alice
+        .upstream
+        .try_iter()
+        .filter_map(event)
+        .filter_map(|e| match e {
+            Event::SyncManager(syncmgr::Event::BlockReceived(_, block, _)) => {
+                Some(block.block_hash())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(received, vec![hash]);
+}
+
+/// Test that blocks aren't requested from the peer that served us their filter.
+#[test]
+fn test_getblock_filter_peer() {
+    use bitcoin::network::message_filter::CFilter;
+
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let msg = message::Builder::new(network);
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
         rng,
-        Options {
-            latency: 1..5,      // 1 - 5 seconds
-            failure_rate: 0.04, // 4%
-        },
     );
-    alice.initialize();
-    simulator.initialize(&mut peers);
+    let filter_peer = PeerDummy::new([131, 31, 11, 33], network, height, ServiceFlags::NETWORK);
+    let other = PeerDummy::new([131, 31, 11, 66], network, height, ServiceFlags::NETWORK);
+    let hash = headers.last().unwrap().block_hash();
 
-    // TODO: Node needs to try to reconnect after disconnect!
+    alice.connect(&filter_peer, Link::Outbound);
+    alice.step(Input::Received(
+        filter_peer.addr,
+        msg.raw(NetworkMessage::CFilter(CFilter {
+            filter_type: 0x0,
+            block_hash: hash,
+            filter: vec![0x0],
+        })),
+    ));
 
-    while simulator.step(iter::once(&mut alice).chain(&mut peers)) {
-        if alice.protocol.connmgr.outbound_peers().count() >= connmgr::TARGET_OUTBOUND_PEERS {
-            break;
-        }
+    // With no other peer, the filter peer is used.
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(hash, reply));
+    assert_eq!(result.try_recv().unwrap().unwrap(), filter_peer.addr);
+
+    alice.connect(&other, Link::Outbound);
+    // Let the first request time out, so that the block is requested again.
+    alice.time.elapse(blockmgr::REQUEST_TIMEOUT);
+
+    for _ in 0..4 {
+        let (reply, result) = chan::bounded(1);
+        alice.command(Command::GetBlock(hash, reply));
+        assert_eq!(result.try_recv().unwrap().unwrap(), other.addr);
+    }
+}
+
+/// Test that blocks fetched in a batch are requested once, and delivered in height order.
+#[test]
+fn test_getblocks() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let msg = message::Builder::new(network);
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers,
+        vec![],
+        vec![],
+        rng,
+    );
+    let peers = (1..=2)
+        .map(|i| PeerDummy::new([131, 31, 11, i], network, height, ServiceFlags::NETWORK))
+        .collect::<Vec<_>>();
+    let block_requests = |alice: &mut Peer<Protocol>| {
+        alice
+            .upstream
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(
+                    addr,
+                    RawNetworkMessage {
+                        payload: NetworkMessage::GetData(inv),
+                        ..
+                    },
+                ) => match inv.as_slice() {
+                    [Inventory::Block(hash)] => Some((addr, *hash)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for peer in &peers {
+        alice.connect(peer, Link::Outbound);
+    }
+    alice.upstream.try_iter().for_each(drop);
+
+    // Blocks past the tip can't be fetched.
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlocks(
+        Blocks::Range(height - 1..height + 2),
+        reply,
+    ));
+    assert!(result.try_recv().unwrap().is_err());
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlocks(
+        Blocks::Range(height - 3..height + 1),
+        reply,
+    ));
+    let blocks = result.try_recv().unwrap().unwrap();
+    let requests = block_requests(&mut alice);
+    assert_eq!(requests.len(), 4);
+
+    // Blocks in flight aren't requested again.
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(requests[0].1, reply));
+    assert_eq!(result.try_recv().unwrap().unwrap(), requests[0].0);
+    assert!(block_requests(&mut alice).is_empty());
+
+    // Blocks are received out of order, and one of them never arrives.
+    for (addr, hash) in requests.iter().skip(1).rev() {
+        let (_, header) = alice.protocol.tree.get_block(hash).unwrap();
+        let block = bitcoin::Block {
+            header: *header,
+            txdata: vec![],
+        };
+        alice.step(Input::Received(
+            *addr,
+            msg.raw(NetworkMessage::Block(block)),
+        ));
+    }
+    assert!(blocks.try_recv().is_err());
+
+    // The missing block is requested again once the request times out.
+    alice.time.elapse(blockmgr::REQUEST_TIMEOUT);
+    alice.tick();
+    let retries = block_requests(&mut alice);
+    assert_eq!(retries.len(), 1);
+    assert_eq!(retries[0].1, requests[0].1);
+
+    let (_, header) = alice.protocol.tree.get_block(&requests[0].1).unwrap();
+    let block = bitcoin::Block {
+        header: *header,
+        txdata: vec![],
+    };
+    alice.step(Input::Received(
+        retries[0].0,
+        msg.raw(NetworkMessage::Block(block)),
+    ));
+
+    assert_eq!(
+        blocks.try_iter().map(|(_, h)| h).collect::<Vec<_>>(),
+        vec![height - 3, height - 2, height - 1, height]
+    );
+    assert!(blocks.recv().is_err());
+}
+
+/// Test that blocks a peer doesn't have are requested from another peer right away.
+#[test]
+fn test_getblock_notfound() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let msg = message::Builder::new(network);
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let peers = (1..=2)
+        .map(|i| PeerDummy::new([131, 31, 11, i], network, height, ServiceFlags::NETWORK))
+        .collect::<Vec<_>>();
+    let hash = headers.last().unwrap().block_hash();
+
+    for peer in &peers {
+        alice.connect(peer, Link::Outbound);
+    }
+    alice.upstream.try_iter().for_each(drop);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(hash, reply));
+    let first = result.try_recv().unwrap().unwrap();
+    alice.upstream.try_iter().for_each(drop);
+
+    alice.step(Input::Received(
+        first,
+        msg.raw(NetworkMessage::NotFound(vec![Inventory::Block(hash)])),
+    ));
+    let requests = alice
+        .upstream
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::GetData(inv),
+                    ..
+                },
+            ) => Some((addr, inv)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let second = peers.iter().find(|p| p.addr != first).unwrap().addr;
+
+    assert_eq!(requests, vec![(second, vec![Inventory::Block(hash)])]);
+
+    // Once no peer has the block, it isn't requested anymore.
+    alice.step(Input::Received(
+        second,
+        msg.raw(NetworkMessage::NotFound(vec![Inventory::Block(hash)])),
+    ));
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlock(hash, reply));
+    assert!(result.try_recv().unwrap().is_err());
+}
+
+#[test]
+fn test_rescan_already_active() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers,
+        vec![],
+        vec![],
+        rng,
+    );
+    let rescan = |reply| Command::Rescan {
+        from: std::ops::Bound::Included(0),
+        to: std::ops::Bound::Unbounded,
+        watch: vec![],
+        reply,
+    };
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(rescan(reply));
+    assert!(result.try_recv().unwrap().is_ok());
+
+    // A second rescan is refused while the first one is active.
+    let (reply, result) = chan::bounded(1);
+    alice.command(rescan(reply));
+    assert!(matches!(
+        result.try_recv().unwrap(),
+        Err(spvmgr::GetFiltersError::AlreadyActive)
+    ));
+
+    // Once the active rescan is aborted, a new one can be started.
+    alice.command(Command::AbortRescan);
+    let (reply, result) = chan::bounded(1);
+    alice.command(rescan(reply));
+    assert!(result.try_recv().unwrap().is_ok());
+}
+
+#[test]
+fn test_get_chain_info() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let tip = headers.last().unwrap();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetChainInfo(reply));
+
+    let info = result.try_recv().unwrap();
+    let mut times = headers[headers.len() - 11..]
+        .iter()
+        .map(|h| h.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+
+    assert_eq!(info.tip, tip.block_hash());
+    assert_eq!(info.height, height);
+    assert_eq!(info.time, tip.time);
+    assert_eq!(info.median_time_past, times[5]);
+    assert_eq!(info.bits, tip.bits);
+    assert_eq!(info.difficulty, 1);
+    assert_eq!(info.next_retarget, 2016);
+    assert_eq!(
+        info.next_retarget_time,
+        tip.time + (2016 - height as BlockTime) * 600
+    );
+}
+
+#[test]
+fn test_get_block_header() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let header = headers[41];
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlockHeader(header.block_hash(), reply));
+    assert_eq!(result.try_recv().unwrap(), Some((42, header)));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlockByHeight(42, reply));
+    assert_eq!(result.try_recv().unwrap(), Some(header));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlockHeader(BlockHash::default(), reply));
+    assert_eq!(result.try_recv().unwrap(), None);
+}
+
+#[test]
+fn test_chain_work_and_median_time_past() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let height = headers.len() as Height;
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetChainWork(reply));
+    assert_eq!(
+        result.try_recv().unwrap(),
+        BITCOIN_HEADERS
+            .iter()
+            .fold(Default::default(), |w, h| w + h.work())
+    );
+
+    // The median of the eleven timestamps leading up to and including block `20`.
+    let mut times = BITCOIN_HEADERS
+        .iter()
+        .take(21)
+        .skip(10)
+        .map(|h| h.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetMedianTimePast(20, reply));
+    assert_eq!(result.try_recv().unwrap(), Some(times[5]));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetMedianTimePast(0, reply));
+    assert_eq!(result.try_recv().unwrap(), Some(BITCOIN_HEADERS.head.time));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetMedianTimePast(height + 1, reply));
+    assert_eq!(result.try_recv().unwrap(), None);
+}
+
+#[test]
+fn test_estimate_feerate() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+    let msg = message::Builder::new(network);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::EstimateFeeRate(6, reply));
+    assert_eq!(result.try_recv().unwrap(), None);
+
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.step(Input::Received(
+        remote,
+        msg.raw(NetworkMessage::FeeFilter(5000)),
+    ));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::EstimateFeeRate(6, reply));
+    assert_eq!(result.try_recv().unwrap(), Some(5000));
+
+    alice.step(Input::Disconnected(
+        remote,
+        DisconnectReason::PeerTimeout("timeout"),
+    ));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::EstimateFeeRate(6, reply));
+    assert_eq!(result.try_recv().unwrap(), None);
+}
+
+#[test]
+fn test_submit_transaction() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let msg = message::Builder::new(network);
+    let relay = PeerDummy {
+        relay: true,
+        ..PeerDummy::new([241, 19, 44, 18], network, 144, ServiceFlags::NETWORK)
+    };
+    let quiet: PeerId = ([241, 19, 44, 19], 8333).into();
+    let tx = gen::transaction(&mut fastrand::Rng::new());
+    let txid = tx.txid();
+
+    alice.connect(&relay, Link::Outbound);
+    alice.connect_addr(&quiet, Link::Outbound);
+    alice.command(Command::SubmitTransaction(tx, None, false));
+
+    // The transaction is only announced to peers that relay transactions.
+    let invs = alice
+        .upstream
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::Inv(inv),
+                    ..
+                },
+            ) => Some((addr, inv)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(invs, vec![(relay.addr, vec![Inventory::Transaction(txid)])]);
+
+    let getdata = |alice: &mut Peer<Protocol>, addr: PeerId| {
+        alice.step(Input::Received(
+            addr,
+            msg.raw(NetworkMessage::GetData(vec![Inventory::Transaction(txid)])),
+        ));
+        alice
+            .upstream
+            .try_iter()
+            .find_map(|o| match o {
+                Out::Message(a, RawNetworkMessage { payload, .. }) if a == addr => match payload {
+                    NetworkMessage::Tx(_) | NetworkMessage::NotFound(_) => Some(payload),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap()
+    };
+
+    // Repeated requests are answered.
+    for _ in 0..2 {
+        assert!(matches!(
+            getdata(&mut alice, relay.addr),
+            NetworkMessage::Tx(tx) if tx.txid() == txid
+        ));
+    }
+    assert!(matches!(
+        getdata(&mut alice, quiet),
+        NetworkMessage::NotFound(inv) if inv == vec![Inventory::Transaction(txid)]
+    ));
+
+    // Once the transaction expires, it's no longer served.
+    alice.time.elapse(invmgr::DEFAULT_TTL);
+    alice.tick();
+
+    assert!(matches!(
+        getdata(&mut alice, relay.addr),
+        NetworkMessage::NotFound(_)
+    ));
+}
+
+/// Test that private transaction broadcasts go over short-lived connections to peers outside
+/// of our outbound peer set.
+#[test]
+fn test_submit_transaction_private() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let config = Config {
+        target: "alice",
+        target_outbound_peers: 1,
+        network,
+        ..Config::default()
+    };
+    let addrs = (1..=4)
+        .map(|i| {
+            (
+                ([241, i * 10, 44, 19], network.port()).into(),
+                Source::Dns,
+                ServiceFlags::NETWORK,
+            )
+        })
+        .collect();
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], addrs, config, rng);
+    let tx = gen::transaction(&mut fastrand::Rng::with_seed(1));
+    let txid = tx.txid();
+    let dummy = |addr: PeerId| PeerDummy {
+        addr,
+        relay: true,
+        ..PeerDummy::new(addr.ip(), network, 144, ServiceFlags::NETWORK)
+    };
+    let connects = |alice: &mut Peer<Protocol>| {
+        alice
+            .upstream
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Connect(addr, _, _) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    alice.initialize();
+
+    let outbound = connects(&mut alice);
+    assert_eq!(outbound.len(), 1);
+    alice.connect(&dummy(outbound[0]), Link::Outbound);
+
+    alice.command(Command::SubmitTransaction(tx, None, true));
+
+    // New connections are opened, and the transaction isn't announced to our peer.
+    let broadcast = connects(&mut alice);
+    assert_eq!(broadcast.len(), connmgr::BROADCAST_PEERS);
+    assert!(!broadcast.contains(&outbound[0]));
+    assert!(alice.upstream.try_iter().all(|o| !matches!(
+        o,
+        Out::Message(
+            _,
+            RawNetworkMessage {
+                payload: NetworkMessage::Inv(_),
+                ..
+            }
+        )
+    )));
+
+    // The transaction is announced to the broadcast peers once negotiated.
+    for addr in &broadcast {
+        alice.connect(&dummy(*addr), Link::Outbound);
+
+        let invs = alice
+            .upstream
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(
+                    addr,
+                    RawNetworkMessage {
+                        payload: NetworkMessage::Inv(inv),
+                        ..
+                    },
+                ) => Some((addr, inv)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(invs, vec![(*addr, vec![Inventory::Transaction(txid)])]);
+    }
+
+    // Broadcast connections don't count as outbound peers.
+    assert_eq!(
+        alice.protocol.connmgr.outbound_peers().collect::<Vec<_>>(),
+        vec![&outbound[0]]
+    );
+
+    // The connection is closed shortly after the transaction was served.
+    alice.step(Input::Received(
+        broadcast[0],
+        msg.raw(NetworkMessage::GetData(vec![Inventory::Transaction(txid)])),
+    ));
+    alice.time.elapse(connmgr::BROADCAST_LINGER);
+    alice.tick();
+
+    let disconnects = alice
+        .upstream
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Disconnect(addr, _) if broadcast.contains(&addr) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(disconnects, vec![broadcast[0]]);
+
+    // The other connection is closed once it times out.
+    alice.time.elapse(connmgr::BROADCAST_TIMEOUT);
+    alice.tick();
+
+    assert!(alice
+        .upstream
+        .try_iter()
+        .any(|o| matches!(o, Out::Disconnect(addr, _) if addr == broadcast[1])));
+}
+
+#[test]
+fn test_parallel_header_sync() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = gen::genesis(&mut rng).header;
+    let mut headers = vec![genesis];
+
+    for _ in 0..30 {
+        let prev = *headers.last().unwrap();
+        headers.push(gen::header(&prev, Default::default(), &mut rng));
+    }
+    let checkpoints = vec![
+        (10, headers[10].block_hash()),
+        (20, headers[20].block_hash()),
+    ];
+    let (sender, outputs) = chan::unbounded();
+    let upstream = super::channel::Channel::new(network, PROTOCOL_VERSION, "test", sender);
+    let clock = AdjustedTime::<PeerId>::new(LocalTime::now());
+    let mut tree = model::Cache::new(genesis);
+    let mut syncmgr = syncmgr::SyncManager::new(
+        syncmgr::Config {
+            max_message_headers: 4,
+            request_timeout: syncmgr::REQUEST_TIMEOUT,
+            params: network.params(),
+            checkpoints: checkpoints.clone(),
+        },
+        rng.clone(),
+        upstream,
+    );
+    let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+    let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+
+    syncmgr.initialize(clock.local_time(), &tree);
+    for peer in [alice, bob] {
+        syncmgr.peer_negotiated(
+            peer,
+            30,
+            ServiceFlags::NETWORK,
+            Link::Outbound,
+            &clock,
+            &tree,
+        );
+    }
+
+    let mut requests = Vec::new();
+    while let Ok(out) = outputs.try_recv() {
+        if let Out::Message(
+            addr,
+            RawNetworkMessage {
+                payload: NetworkMessage::GetHeaders(msg),
+                ..
+            },
+        ) = out
+        {
+            requests.push((addr, msg.locator_hashes, msg.stop_hash));
+        }
+    }
+    // Each peer is asked for a different segment.
+    assert_eq!(
+        requests,
+        vec![
+            (alice, vec![genesis.block_hash()], checkpoints[0].1),
+            (bob, vec![checkpoints[0].1], checkpoints[1].1)
+        ]
+    );
+
+    // Serve requests until there are none left.
+    while let Some((addr, locators, stop)) = requests.pop() {
+        let start = headers
+            .iter()
+            .position(|h| locators.contains(&h.block_hash()))
+            .unwrap();
+        let batch = headers[start + 1..]
+            .iter()
+            .take(4)
+            .scan(false, |stopped, h| {
+                if *stopped {
+                    return None;
+                }
+                *stopped = h.block_hash() == stop;
+                Some(*h)
+            })
+            .collect::<Vec<_>>();
+
+        syncmgr
+            .received_headers(&addr, batch, &clock, &mut tree)
+            .unwrap();
+
+        while let Ok(out) = outputs.try_recv() {
+            if let Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::GetHeaders(msg),
+                    ..
+                },
+            ) = out
+            {
+                requests.push((addr, msg.locator_hashes, msg.stop_hash));
+            }
+        }
+    }
+    assert_eq!(tree.height(), 30);
+    assert_eq!(tree.tip().0, headers[30].block_hash());
+}
+
+#[test]
+fn test_feefilter() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let msg = message::Builder::new(network);
+    let cheap = PeerDummy {
+        relay: true,
+        ..PeerDummy::new([241, 19, 44, 18], network, 144, ServiceFlags::NETWORK)
+    };
+    let picky = PeerDummy {
+        relay: true,
+        ..PeerDummy::new([241, 19, 44, 19], network, 144, ServiceFlags::NETWORK)
+    };
+    let tx = gen::transaction(&mut fastrand::Rng::new());
+    let txid = tx.txid();
+    let fee = tx.get_weight() as u64; // About 4 sat/vB.
+    let rate = feemgr::feerate(&tx, fee);
+
+    alice.connect(&cheap, Link::Outbound);
+
+    // Our fee filter is sent once the peer is negotiated.
+    assert!(alice.upstream.try_iter().any(|o| matches!(
+        o,
+        Out::Message(addr, RawNetworkMessage { payload: NetworkMessage::FeeFilter(rate), .. })
+            if addr == cheap.addr && rate == feemgr::DEFAULT_FEEFILTER as i64
+    )));
+
+    alice.connect(&picky, Link::Outbound);
+    alice.step(Input::Received(
+        picky.addr,
+        msg.raw(NetworkMessage::FeeFilter(rate as i64 + 1)),
+    ));
+
+    let announced = |alice: &mut Peer<Protocol>| {
+        alice
+            .upstream
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Message(
+                    addr,
+                    RawNetworkMessage {
+                        payload: NetworkMessage::Inv(_),
+                        ..
+                    },
+                ) => Some(addr),
+                _ => None,
+            })
+            .collect::<HashSet<_>>()
+    };
+
+    // The transaction doesn't pay enough for one of the peers.
+    alice.command(Command::SubmitTransaction(tx.clone(), Some(fee), false));
+    assert_eq!(announced(&mut alice), iter::once(cheap.addr).collect());
+
+    // If we don't know the fee, we announce to everyone.
+    alice.command(Command::SubmitTransaction(tx, None, false));
+    assert_eq!(announced(&mut alice), iter::once(picky.addr).collect());
+    assert!(alice.protocol.invmgr.contains(&txid));
+}
+
+#[test]
+fn test_peer_info() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let msg = message::Builder::new(network);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let latency = LocalDuration::from_millis(120);
+
+    alice.connect_addr(&remote, Link::Outbound);
+
+    let nonce = alice
+        .upstream
+        .try_iter()
+        .find_map(|o| match o {
+            Out::Message(
+                addr,
+                RawNetworkMessage {
+                    payload: NetworkMessage::Ping(nonce),
+                    ..
+                },
+            ) if addr == remote => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent");
+
+    alice.step(Input::Sent(remote, 32));
+    alice.time.elapse(latency);
+    alice.step(Input::Received(
+        remote,
+        msg.raw(NetworkMessage::Pong(nonce)),
+    ));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::PeerInfo(reply));
+
+    let peers = result.try_recv().unwrap();
+    assert_eq!(peers.len(), 1);
+
+    let info = &peers[0];
+    assert_eq!(info.addr, remote);
+    assert_eq!(info.link, Link::Outbound);
+    assert_eq!(
+        info.stats.latency,
+        Some(pingmgr::Latency {
+            min: latency,
+            avg: latency,
+            last: latency,
+        })
+    );
+    assert_eq!(
+        info.stats.sent,
+        pingmgr::Traffic {
+            messages: 1,
+            bytes: 32
+        }
+    );
+    // Only the `pong` was received after the handshake.
+    assert_eq!(
+        info.stats.received,
+        pingmgr::Traffic {
+            messages: 1,
+            bytes: 32
+        }
+    );
+}
+
+#[test]
+fn test_stale_commands() {
+    let rng = fastrand::Rng::new();
+    let cfg = Config {
+        epoch: 2,
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+
+    // A command from a previous epoch is dropped, along with its reply channel. The
+    // current epoch is sent back either way.
+    let (reply, result) = chan::bounded(1);
+    let (ack, epoch) = chan::bounded(1);
+    alice.command(Command::Tagged(1, Box::new(Command::GetTip(reply)), ack));
+    assert_eq!(result.try_recv(), Err(chan::TryRecvError::Disconnected));
+    assert_eq!(epoch.try_recv(), Ok(2));
+
+    let (reply, result) = chan::bounded(1);
+    let (ack, epoch) = chan::bounded(1);
+    alice.command(Command::Tagged(2, Box::new(Command::GetTip(reply)), ack));
+    assert_eq!(result.try_recv().unwrap().0, 0);
+    assert_eq!(epoch.try_recv(), Ok(2));
+}
+
+#[test]
+fn test_read_snapshot() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ReadSnapshot(SnapshotReader::new(
+        move |snapshot| {
+            let (tip, _) = snapshot.tip();
+            let height = snapshot.height();
+            let blocks = snapshot.get_blocks(height - 1..height + 1);
+            let (_, filter_tip) = snapshot.filter_tip();
+
+            reply
+                .send((
+                    tip,
+                    height,
+                    blocks,
+                    snapshot.filter_height(),
+                    snapshot.get_filter_header(0).map(|(_, h)| h) == Some(filter_tip),
+                ))
+                .ok();
+        },
+    )));
+
+    let (tip, height, blocks, filter_height, genesis) = result.try_recv().unwrap();
+
+    assert_eq!(tip, headers.last().unwrap().block_hash());
+    assert_eq!(height, headers.len() as Height);
+    assert_eq!(blocks, headers[headers.len() - 2..].to_vec());
+    assert_eq!(filter_height, 0);
+    assert!(genesis);
+}
+
+#[test]
+fn test_block_disconnected() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let merkle_root = genesis.merkle_root;
+
+    let mut headers = vec![genesis];
+    for _ in 0..5 {
+        let header = gen::header(headers.last().unwrap(), merkle_root, &mut rng);
+        headers.push(header);
+    }
+    // A fork off the block at height 2, with one block more than the active chain.
+    let mut fork = vec![headers[2]];
+    for _ in 0..4 {
+        let header = gen::header(fork.last().unwrap(), merkle_root, &mut rng);
+        fork.push(header);
+    }
+    let config = Config {
+        target: "alice",
+        network,
+        params: network.params(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        headers[1..].to_vec(),
+        vec![],
+        vec![],
+        config,
+        rng,
+    );
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(fork[1..].to_vec(), reply));
+
+    assert!(matches!(
+        result.try_recv().unwrap(),
+        Ok(ImportResult::TipChanged(_, _, 6, _))
+    ));
+    let disconnected = alice
+        .upstream
+        .try_iter()
+        .filter_map(event)
+        .filter_map(|e| match e {
+            Event::SyncManager(syncmgr::Event::BlockDisconnected { hash, height }) => {
+                Some((hash, height))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        disconnected,
+        vec![
+            (headers[5].block_hash(), 5),
+            (headers[4].block_hash(), 4),
+            (headers[3].block_hash(), 3)
+        ]
+    );
+}
+
+#[test]
+fn test_block_connected() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let merkle_root = genesis.merkle_root;
+
+    let mut headers = vec![genesis];
+    for _ in 0..3 {
+        let header = gen::header(headers.last().unwrap(), merkle_root, &mut rng);
+        headers.push(header);
+    }
+    // A fork off the block at height 1, with one block more than the active chain.
+    let mut fork = vec![headers[1]];
+    for _ in 0..3 {
+        let header = gen::header(fork.last().unwrap(), merkle_root, &mut rng);
+        fork.push(header);
+    }
+    let config = Config {
+        target: "alice",
+        network,
+        params: network.params(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], config, rng);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(headers[1..].to_vec(), reply));
+    assert!(result.try_recv().unwrap().is_ok());
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(fork[1..].to_vec(), reply));
+    assert!(result.try_recv().unwrap().is_ok());
+
+    let events = alice
+        .upstream
+        .try_iter()
+        .filter_map(event)
+        .collect::<Vec<_>>();
+    let reorgs = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::SyncManager(syncmgr::Event::ChainReorged {
+                disconnected,
+                connected,
+            }) => Some((disconnected.clone(), connected.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let events = events
+        .into_iter()
+        .filter_map(|e| match e {
+            Event::SyncManager(syncmgr::Event::BlockConnected { header, height }) => {
+                Some((true, header.block_hash(), height))
+            }
+            Event::SyncManager(syncmgr::Event::BlockDisconnected { hash, height }) => {
+                Some((false, hash, height))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        events,
+        vec![
+            (true, headers[1].block_hash(), 1),
+            (true, headers[2].block_hash(), 2),
+            (true, headers[3].block_hash(), 3),
+            (false, headers[3].block_hash(), 3),
+            (false, headers[2].block_hash(), 2),
+            (true, fork[1].block_hash(), 2),
+            (true, fork[2].block_hash(), 3),
+            (true, fork[3].block_hash(), 4),
+        ]
+    );
+
+    // The re-org is also reported as a whole, while extending the chain isn't.
+    assert_eq!(
+        reorgs,
+        vec![(
+            vec![(3, headers[3].block_hash()), (2, headers[2].block_hash())],
+            vec![(2, fork[1]), (3, fork[2]), (4, fork[3])]
+        )]
+    );
+}
+
+#[test]
+fn test_header_announcement() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let msg = message::Builder::new(network);
+    let header = gen::header(&genesis, genesis.merkle_root, &mut rng);
+    let config = Config {
+        target: "alice",
+        network,
+        params: network.params(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], config, rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 18],
+        network,
+        0,
+        ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+    );
+
+    alice.connect(&bob, Link::Outbound);
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::SendHeaders),
+    ));
+    // Bob has nothing past our tip.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![])),
+    ));
+    alice.upstream.try_iter().for_each(drop);
+
+    // Bob announces a new block with a `headers` message.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![header])),
+    ));
+    assert_eq!(alice.protocol.tree.height(), 1);
+
+    let (events, msgs): (Vec<_>, Vec<_>) = alice
+        .upstream
+        .try_iter()
+        .partition(|o| matches!(o, Out::Event(_)));
+
+    events
+        .into_iter()
+        .filter_map(event)
+        .find(|e| {
+            matches!(
+                e,
+                Event::SyncManager(syncmgr::Event::HeadersAnnounced { from, tip, height: 1 })
+                if from == &bob.addr && tip == &header.block_hash()
+            )
+        })
+        .expect("Alice emits an announcement event");
+
+    msgs.into_iter()
+        .filter_map(payload)
+        .find(|(addr, msg)| *addr == bob.addr && matches!(msg, NetworkMessage::GetCFHeaders(_)))
+        .expect("Alice syncs filter headers up to the announced block");
+}
+
+#[test]
+fn test_orphan_headers() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let msg = message::Builder::new(network);
+    let parent = gen::header(&genesis, genesis.merkle_root, &mut rng);
+    let orphan = gen::header(&parent, parent.merkle_root, &mut rng);
+    let child = gen::header(&orphan, orphan.merkle_root, &mut rng);
+    let config = Config {
+        target: "alice",
+        network,
+        params: network.params(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], config, rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 18],
+        network,
+        0,
+        ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+    );
+
+    alice.connect(&bob, Link::Outbound);
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::SendHeaders),
+    ));
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![])),
+    ));
+    alice.upstream.try_iter().for_each(drop);
+
+    // Bob announces a block before its parent.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![orphan])),
+    ));
+    assert_eq!(alice.protocol.tree.height(), 0);
+
+    alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .find(|(addr, msg)| {
+            matches!(
+                msg,
+                NetworkMessage::GetHeaders(super::GetHeadersMessage { stop_hash, .. })
+                if *addr == bob.addr && *stop_hash == parent.block_hash()
+            )
+        })
+        .expect("Alice asks for the missing parent");
+
+    // Another orphan doesn't trigger another request while the first is outstanding.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![child])),
+    ));
+    assert!(!alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .any(|(_, msg)| matches!(msg, NetworkMessage::GetHeaders(_))));
+
+    // Once the parent arrives, the orphans are connected without being downloaded again.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Headers(vec![parent])),
+    ));
+    assert_eq!(alice.protocol.tree.height(), 3);
+    assert_eq!(alice.protocol.tree.tip().0, child.block_hash());
+    assert!(!alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .any(|(_, msg)| matches!(msg, NetworkMessage::GetHeaders(_))));
+}
+
+#[test]
+fn test_block_announcement() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let msg = message::Builder::new(network);
+    let mut headers = vec![genesis];
+    for _ in 0..2 {
+        let header = gen::header(headers.last().unwrap(), genesis.merkle_root, &mut rng);
+        headers.push(header);
+    }
+    let config = Config {
+        target: "alice",
+        network,
+        params: network.params(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], config, rng);
+    let bob = PeerDummy::new([241, 19, 44, 18], network, 0, ServiceFlags::NETWORK);
+    let carol = PeerDummy::new([241, 19, 44, 19], network, 0, ServiceFlags::NETWORK);
+
+    alice.connect(&bob, Link::Inbound);
+    alice.connect(&carol, Link::Inbound);
+    alice.step(Input::Received(
+        carol.addr,
+        msg.raw(NetworkMessage::SendHeaders),
+    ));
+    alice.upstream.try_iter().for_each(drop);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(vec![headers[1]], reply));
+    assert!(result.try_recv().unwrap().is_ok());
+
+    let msgs = alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .collect::<Vec<_>>();
+
+    // Bob gets an `inv`, while Carol, who asked for `sendheaders`, gets the header.
+    assert!(msgs.contains(&(
+        bob.addr,
+        NetworkMessage::Inv(vec![Inventory::Block(headers[1].block_hash())])
+    )));
+    assert!(msgs.contains(&(carol.addr, NetworkMessage::Headers(vec![headers[1]]))));
+
+    // Bob announces the next block before we do.
+    alice.step(Input::Received(
+        bob.addr,
+        msg.raw(NetworkMessage::Inv(vec![Inventory::Block(
+            headers[2].block_hash(),
+        )])),
+    ));
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(vec![headers[2]], reply));
+    assert!(result.try_recv().unwrap().is_ok());
+
+    let announced = alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .filter(|(_, msg)| matches!(msg, NetworkMessage::Inv(_) | NetworkMessage::Headers(_)))
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        announced,
+        vec![carol.addr],
+        "Blocks known to a peer are not announced to it"
+    );
+}
+
+/// Test that we can find and connect to peers amidst network errors.
+#[test]
+fn sim_connect_to_peers() {
+    logger::init(log::Level::Debug);
+
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+
+    // Alice will try to connect to enough outbound peers.
+    let mut peers = peer::network(network, connmgr::TARGET_OUTBOUND_PEERS + 1, rng.clone());
+    let addrs = peers
+        .iter()
+        .map(|p| (p.addr, Source::Dns, p.cfg.services))
+        .collect::<Vec<_>>();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, addrs, rng.clone());
+
+    let mut simulator = Simulation::new(
+        time,
+        rng,
+        Options {
+            latency: 1..5,      // 1 - 5 seconds
+            failure_rate: 0.04, // 4%
+        },
+    );
+    alice.initialize();
+    simulator.initialize(&mut peers);
+
+    // TODO: Node needs to try to reconnect after disconnect!
+
+    while simulator.step(iter::once(&mut alice).chain(&mut peers)) {
+        if alice.protocol.connmgr.outbound_peers().count() >= connmgr::TARGET_OUTBOUND_PEERS {
+            break;
+        }
+    }
+    assert!(
+        simulator.elapsed() < LocalDuration::from_secs(20),
+        "Desired state took too long to reach: {}",
+        simulator.elapsed()
+    );
+}
+
+#[test]
+fn test_effective_config() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([241, 19, 44, 18], 18444).into();
+    let cfg = Config {
+        storage: Some("/tmp/nakamoto".into()),
+        ..Config::from("alice", network, vec![remote])
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+
+    alice.initialize();
+    alice
+        .upstream
+        .try_iter()
+        .filter_map(event)
+        .find(|e| matches!(e, Event::NodeStarted(c) if c.connect == 1))
+        .expect("the node reports its configuration when starting");
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetConfig(reply));
+
+    let config = result.try_recv().unwrap();
+    let json = microserde::json::to_string(&config.to_json());
+
+    assert_eq!(config.connect, 1);
+    assert!(json.contains(r#""network":"regtest""#));
+    assert!(json.contains(r#""storage":"/tmp/nakamoto""#));
+    assert!(
+        !json.contains(&remote.ip().to_string()),
+        "peer addresses are redacted"
+    );
+}
+
+/// Test that inbound peers syncing from us are served headers.
+#[test]
+fn test_serve_headers_inbound() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let msg = message::Builder::new(network);
+    let remote = PeerDummy::new([131, 31, 11, 33], network, 0, ServiceFlags::NETWORK);
+
+    alice.connect(&remote, Link::Inbound);
+    alice.upstream.try_iter().for_each(drop);
+    alice.step(Input::Received(
+        remote.addr,
+        msg.raw(NetworkMessage::GetHeaders(super::GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: vec![network.genesis_hash()],
+            stop_hash: BlockHash::default(),
+        })),
+    ));
+
+    let served = alice
+        .upstream
+        .try_iter()
+        .filter_map(payload)
+        .find_map(|(addr, m)| match m {
+            NetworkMessage::Headers(hs) if addr == remote.addr => Some(hs),
+            _ => None,
+        })
+        .expect("alice serves headers to the inbound peer");
+
+    assert_eq!(served.first(), headers.first());
+    assert_eq!(served.len(), headers.len());
+}
+
+#[test]
+fn test_flooding_peer_disconnected() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let cfg = Config {
+        limits: ratemgr::Config {
+            messages: ratemgr::Limit::new(1, 8),
+            tolerance: ratemgr::Limit::new(1, 4),
+            ..ratemgr::Config::default()
+        },
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+    let msg = message::Builder::new(network);
+
+    alice.connect_addr(&remote, Link::Outbound);
+
+    for nonce in 0..16 {
+        alice.step(Input::Received(
+            remote,
+            msg.raw(NetworkMessage::Ping(nonce)),
+        ));
+    }
+    let outputs = alice.upstream.try_iter().collect::<Vec<_>>();
+    let pongs = outputs
+        .iter()
+        .filter(|o| matches!(o, Out::Message(_, m) if matches!(m.payload, NetworkMessage::Pong(_))))
+        .count();
+
+    assert!(pongs < 16, "excess messages are dropped");
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerFlooding("messages")) if *addr == remote
+    )));
+}
+
+/// Test that a peer sending the blocks we asked for isn't rate limited.
+#[test]
+fn test_solicited_blocks_not_rate_limited() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let cfg = Config {
+        limits: ratemgr::Config {
+            messages: ratemgr::Limit::new(1, 8),
+            tolerance: ratemgr::Limit::new(1, 2),
+            ..ratemgr::Config::default()
+        },
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], headers, vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([131, 31, 11, 1], network, height, ServiceFlags::NETWORK);
+    let msg = message::Builder::new(network);
+
+    alice.connect(&bob, Link::Outbound);
+    alice.upstream.try_iter().for_each(drop);
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetBlocks(
+        Blocks::Range(height - 15..height + 1),
+        reply,
+    ));
+    let blocks = result.try_recv().unwrap().unwrap();
+
+    for h in height - 15..=height {
+        let header = *alice.protocol.tree.get_block_by_height(h).unwrap();
+        alice.step(Input::Received(
+            bob.addr,
+            msg.raw(NetworkMessage::Block(bitcoin::Block {
+                header,
+                txdata: vec![],
+            })),
+        ));
+    }
+    assert_eq!(blocks.try_iter().count(), 16);
+    assert!(!alice
+        .upstream
+        .try_iter()
+        .any(|o| matches!(o, Out::Disconnect(addr, _) if addr == bob.addr)));
+}
+
+/// Test that a peer we're waiting on isn't exempt from rate limiting for messages that
+/// don't match our request.
+#[test]
+fn test_unsolicited_headers_rate_limited() {
+    let rng = fastrand::Rng::with_seed(1);
+    let network = Network::Mainnet;
+    let cfg = Config {
+        limits: ratemgr::Config {
+            messages: ratemgr::Limit::new(1, 8),
+            tolerance: ratemgr::Limit::new(1, 2),
+            ..ratemgr::Config::default()
+        },
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([131, 31, 11, 1], network, 144, ServiceFlags::NETWORK);
+    let msg = message::Builder::new(network);
+
+    alice.connect(&bob, Link::Outbound);
+    alice.upstream.try_iter().for_each(drop);
+
+    assert!(alice.protocol.syncmgr.is_syncing());
+
+    let genesis = network.genesis();
+    for nonce in 0..16 {
+        let header = BlockHeader {
+            prev_blockhash: BlockHash::hash(&[nonce]),
+            nonce: nonce as u32,
+            ..genesis
+        };
+        assert!(!alice.protocol.syncmgr.is_awaiting(&bob.addr, &[header]));
+
+        alice.step(Input::Received(
+            bob.addr,
+            msg.raw(NetworkMessage::Headers(vec![header])),
+        ));
+    }
+    assert!(alice.upstream.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerFlooding(_)) if addr == bob.addr
+    )));
+}
+
+#[test]
+fn test_shutdown() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut cfg = Config::from("alice", network, vec![]);
+    cfg.hooks.on_shutdown = Arc::new(|addr, upstream| {
+        upstream.message(addr, NetworkMessage::Alert(b"bye".to_vec()));
+    });
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.upstream.try_iter().for_each(drop);
+    alice.command(Command::Shutdown);
+
+    let outputs = alice.upstream.try_iter().collect::<Vec<_>>();
+    let farewell = outputs
+        .iter()
+        .position(|o| matches!(o, Out::Message(a, m) if *a == remote && matches!(m.payload, NetworkMessage::Alert(_))))
+        .expect("the shutdown hook is called for negotiated peers");
+    let shutdown = outputs
+        .iter()
+        .position(|o| matches!(o, Out::Shutdown))
+        .expect("the reactor is told to shut down");
+
+    assert!(
+        farewell < shutdown,
+        "final messages are sent before shutting down"
+    );
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ConnManager(connmgr::Event::Anchors(_)))
+    )));
+}
+
+#[test]
+fn test_wire_hook() {
+    use super::Direction;
+    use std::sync::Mutex;
+
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let wire = Arc::new(Mutex::new(Vec::new()));
+    let mut cfg = Config::from("alice", network, vec![]);
+    cfg.hooks.on_wire = Arc::new({
+        let wire = wire.clone();
+        move |addr, dir, msg, size| {
+            wire.lock()
+                .unwrap()
+                .push((addr, dir, msg.cmd().to_owned(), size));
+        }
+    });
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    alice.connect_addr(&remote, Link::Outbound);
+
+    let wire = wire.lock().unwrap();
+    for cmd in ["version", "verack"].iter() {
+        for dir in [Direction::Sent, Direction::Received].iter() {
+            let (_, _, _, size) = wire
+                .iter()
+                .find(|(a, d, c, _)| *a == remote && d == dir && c == cmd)
+                .expect("messages are passed to the hook in both directions");
+            // Message header, plus payload.
+            assert!(*size >= 24);
+        }
+    }
+}
+
+/// Test that requests and the events they lead to are traced in per-peer request spans.
+#[cfg(feature = "tracing")]
+mod spans {
+    use std::fmt::{self, Write as _};
+    use std::sync::Mutex;
+
+    use tracing::field::{Field, Visit};
+    use tracing::{span, Metadata, Subscriber};
+
+    use super::*;
+
+    /// Records events, along with the span they were recorded in.
+    #[derive(Default)]
+    struct Recorder {
+        /// Spans, formatted with their fields, by id.
+        spans: Mutex<Vec<String>>,
+        /// Entered spans.
+        stack: Mutex<Vec<u64>>,
+        /// Events, formatted with their fields, with the current span.
+        events: Arc<Mutex<Vec<(Option<String>, String)>>>,
+    }
+
+    /// Formats the fields of a span or event.
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            write!(self.0, " {}={:?}", field.name(), value).ok();
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut fields = Fields(span.metadata().name().to_owned());
+            let mut spans = self.spans.lock().unwrap();
+
+            span.record(&mut fields);
+            spans.push(fields.0);
+
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields(String::new());
+            let current = self
+                .stack
+                .lock()
+                .unwrap()
+                .last()
+                .map(|id| self.spans.lock().unwrap()[*id as usize - 1].clone());
+
+            event.record(&mut fields);
+            self.events.lock().unwrap().push((current, fields.0));
+        }
+
+        fn enter(&self, id: &span::Id) {
+            self.stack.lock().unwrap().push(id.into_u64());
+        }
+
+        fn exit(&self, _: &span::Id) {
+            self.stack.lock().unwrap().pop();
+        }
+    }
+
+    #[test]
+    fn test_request_spans() {
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let msg = message::Builder::new(network);
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+        let hash =
+            BlockHash::from_hex("0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+                .unwrap();
+
+        tracing::subscriber::with_default(recorder, || {
+            let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+
+            peer.connect_addr(&remote, Link::Outbound);
+            peer.step(Input::Received(
+                remote,
+                msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
+            ));
+            peer.time.elapse(syncmgr::REQUEST_TIMEOUT);
+            peer.tick();
+        });
+
+        let events = events.lock().unwrap();
+        let traced = |span: &str, event: &str| {
+            events.iter().any(|(s, e)| {
+                matches!(s.as_deref(), Some(s) if s.starts_with(span)) && e.contains(event)
+            })
+        };
+        let span = format!("getheaders peer={} stop_hash={}", remote, hash);
+
+        assert!(traced(&span, "sending request"));
+        assert!(traced(
+            &format!("getheaders peer={}", remote),
+            "request timed out"
+        ));
+        // Manager events are recorded in the span they are emitted in.
+        assert!(traced(
+            &format!("getheaders peer={}", remote),
+            &format!("{}", syncmgr::Event::TimedOut(remote))
+        ));
+        assert!(traced(
+            &format!("peer peer={}", remote),
+            &format!("{}", connmgr::Event::Connected(remote, Link::Outbound))
+        ));
     }
-    assert!(
-        simulator.elapsed() < LocalDuration::from_secs(20),
-        "Desired state took too long to reach: {}",
-        simulator.elapsed()
-    );
 }
@@ -1,4 +1,5 @@
 use super::*;
+use crate::protocol::simulator;
 
 use bitcoin::network::message_network::VersionMessage;
 use bitcoin::network::Address;
@@ -21,6 +22,7 @@ pub struct PeerDummy {
     pub services: ServiceFlags,
     pub protocol_version: u32,
     pub time: LocalTime,
+    pub relay: bool,
 }
 
 impl PeerDummy {
@@ -40,6 +42,7 @@ impl PeerDummy {
             services,
             protocol_version: PROTOCOL_VERSION,
             time,
+            relay: false,
         }
     }
 
@@ -53,7 +56,7 @@ impl PeerDummy {
             nonce,
             user_agent: USER_AGENT.to_owned(),
             start_height: self.height as i32,
-            relay: false,
+            relay: self.relay,
         }
     }
 }
@@ -89,6 +92,24 @@ impl Peer<Protocol> {
     }
 }
 
+impl simulator::Node for Peer<Protocol> {
+    fn addr(&self) -> PeerId {
+        self.addr
+    }
+
+    fn initialize(&mut self, _time: LocalTime) {
+        Peer::initialize(self);
+    }
+
+    fn step(&mut self, input: Input, time: LocalTime) {
+        self.protocol.step(input, time);
+    }
+
+    fn outputs(&mut self) -> Vec<Out> {
+        self.upstream.try_iter().collect()
+    }
+}
+
 impl Peer<Protocol> {
     pub fn new(
         name: &'static str,
@@ -173,6 +194,7 @@ impl Peer<Protocol> {
                 protocol_version: self.protocol.protocol_version,
                 services: self.protocol.peermgr.config.required_services,
                 time: self.time,
+                relay: false,
             },
             link,
         );
@@ -257,7 +279,7 @@ impl Peer<Protocol> {
                     o,
                     Out::Event(
                         Event::PeerManager(peermgr::Event::PeerNegotiated { addr, services })
-                    ) if addr == &remote.addr && services.has(ServiceFlags::NETWORK)
+                    ) if addr == &remote.addr && peermgr::has_services(*services, ServiceFlags::NETWORK)
                 )
             })
             .expect("peer handshake is successful");
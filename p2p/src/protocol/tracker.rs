@@ -0,0 +1,336 @@
+//! Tracking of requests sent to peers.
+//!
+//! Managers send requests, eg. `getheaders` or `getcfilters`, and wait for peers to
+//! fulfill them. A [`RequestTracker`] keeps track of these requests until they are
+//! fulfilled or time out, according to a [`RetryPolicy`]: requests that time out can be
+//! retried with a different peer, with a timeout that doubles with every attempt, and peers
+//! are never asked for more than a given number of requests at once.
+use std::hash::Hash;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::collections::{HashMap, HashSet};
+
+use super::PeerId;
+
+/// How requests are timed out and retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How long to wait for the first attempt at a request to be fulfilled.
+    pub timeout: LocalDuration,
+    /// Maximum time to wait for any attempt. Timeouts double with every attempt, up to this
+    /// duration.
+    pub max_timeout: LocalDuration,
+    /// Maximum number of times a request is sent before giving up on it.
+    pub max_attempts: usize,
+    /// Maximum number of requests outstanding with a single peer.
+    pub max_outstanding: usize,
+}
+
+impl RetryPolicy {
+    /// The time to wait for the given attempt at a request. Attempts are counted from one.
+    pub fn timeout(&self, attempt: usize) -> LocalDuration {
+        let factor = 1 << attempt.saturating_sub(1).min(16);
+
+        (self.timeout * factor).min(self.max_timeout)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: LocalDuration::from_secs(30),
+            max_timeout: LocalDuration::from_mins(4),
+            max_attempts: 3,
+            max_outstanding: 1,
+        }
+    }
+}
+
+/// A request sent to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request<T> {
+    /// The peer the request was sent to.
+    pub peer: PeerId,
+    /// Request data.
+    pub data: T,
+    /// When the request was sent.
+    pub sent_at: LocalTime,
+    /// When the request times out.
+    pub deadline: LocalTime,
+    /// Number of times the request was sent, including this one.
+    pub attempts: usize,
+}
+
+/// A request that timed out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expired<K, T> {
+    /// Request identifier.
+    pub id: K,
+    /// The request.
+    pub request: Request<T>,
+    /// Whether the request can be retried. This is the case as long as the maximum number
+    /// of attempts was not reached.
+    pub retry: bool,
+}
+
+/// Keeps track of requests sent to peers, by request identifier.
+#[derive(Debug)]
+pub struct RequestTracker<K, T> {
+    policy: RetryPolicy,
+    requests: HashMap<K, Request<T>>,
+    /// Peers that didn't fulfill a request, by request identifier. Only kept for requests
+    /// that can still be retried.
+    failed: HashMap<K, HashSet<PeerId>>,
+    rng: fastrand::Rng,
+}
+
+impl<K: Hash + Eq + Clone, T> RequestTracker<K, T> {
+    /// Create a new request tracker.
+    pub fn new(policy: RetryPolicy, rng: fastrand::Rng) -> Self {
+        Self {
+            policy,
+            requests: HashMap::with_hasher(rng.clone().into()),
+            failed: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// The retry policy.
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Track a request sent to a peer. If a request with the same identifier timed out
+    /// earlier, this counts as a new attempt at it. Returns the time to wait for the
+    /// request to be fulfilled.
+    pub fn request(&mut self, id: K, peer: PeerId, data: T, now: LocalTime) -> LocalDuration {
+        let attempts = self.failed.get(&id).map_or(0, |peers| peers.len()) + 1;
+        let timeout = self.policy.timeout(attempts);
+
+        self.requests.insert(
+            id,
+            Request {
+                peer,
+                data,
+                sent_at: now,
+                deadline: now + timeout,
+                attempts,
+            },
+        );
+        timeout
+    }
+
+    /// Mark a request as fulfilled, and stop tracking it.
+    pub fn fulfill(&mut self, id: &K) -> Option<Request<T>> {
+        self.failed.remove(id);
+        self.requests.remove(id)
+    }
+
+    /// Cancel a request, eg. because we're no longer interested in the response.
+    pub fn cancel(&mut self, id: &K) -> Option<Request<T>> {
+        self.fulfill(id)
+    }
+
+    /// Get an outstanding request.
+    pub fn get(&self, id: &K) -> Option<&Request<T>> {
+        self.requests.get(id)
+    }
+
+    /// Check whether a request is outstanding.
+    pub fn contains(&self, id: &K) -> bool {
+        self.requests.contains_key(id)
+    }
+
+    /// Iterate over the outstanding requests.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Request<T>)> {
+        self.requests.iter()
+    }
+
+    /// Number of outstanding requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Check whether there are no outstanding requests.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Number of requests outstanding with the given peer.
+    pub fn outstanding(&self, peer: &PeerId) -> usize {
+        self.requests.values().filter(|r| &r.peer == peer).count()
+    }
+
+    /// Check whether a peer can be sent another request, ie. whether it has fewer requests
+    /// outstanding than allowed.
+    pub fn is_available(&self, peer: &PeerId) -> bool {
+        self.outstanding(peer) < self.policy.max_outstanding
+    }
+
+    /// Select a peer to send a request to, out of the given candidates. Peers which have
+    /// reached their limit of outstanding requests, or that already failed to fulfill this
+    /// request, are skipped. Out of the remaining peers, the ones with the fewest outstanding
+    /// requests are preferred, at random.
+    pub fn select<'a>(
+        &mut self,
+        id: &K,
+        candidates: impl IntoIterator<Item = &'a PeerId>,
+    ) -> Option<PeerId> {
+        let failed = self.failed.get(id);
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|p| !failed.map_or(false, |f| f.contains(p)))
+            .map(|p| (*p, self.outstanding(p)))
+            .filter(|(_, n)| *n < self.policy.max_outstanding)
+            .collect::<Vec<_>>();
+        let fewest = candidates.iter().map(|(_, n)| *n).min()?;
+
+        candidates.retain(|(_, n)| *n == fewest);
+        let (peer, _) = candidates[self.rng.usize(..candidates.len())];
+
+        Some(peer)
+    }
+
+    /// Stop tracking the requests that have timed out, and return them. Requests that can be
+    /// retried should be sent again with [`RequestTracker::request`], otherwise they should
+    /// be canceled with [`RequestTracker::cancel`].
+    pub fn timed_out(&mut self, now: LocalTime) -> Vec<Expired<K, T>> {
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, r)| now >= r.deadline)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.expire(id))
+            .collect()
+    }
+
+    /// Stop tracking the requests outstanding with a peer that disconnected, and return them.
+    /// These are considered to have timed out.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) -> Vec<Expired<K, T>> {
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, r)| &r.peer == peer)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.expire(id))
+            .collect()
+    }
+
+    /// Stop tracking all requests.
+    pub fn clear(&mut self) {
+        self.requests.clear();
+        self.failed.clear();
+    }
+
+    fn expire(&mut self, id: K) -> Option<Expired<K, T>> {
+        let request = self.requests.remove(&id)?;
+        let retry = request.attempts < self.policy.max_attempts;
+
+        if retry {
+            let rng = &self.rng;
+
+            self.failed
+                .entry(id.clone())
+                .or_insert_with(|| HashSet::with_hasher(rng.clone().into()))
+                .insert(request.peer);
+        } else {
+            self.failed.remove(&id);
+        }
+        Some(Expired { id, request, retry })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> PeerId {
+        ([127, 0, 0, n], 8333).into()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            timeout: LocalDuration::from_secs(10),
+            max_timeout: LocalDuration::from_secs(30),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.timeout(1), LocalDuration::from_secs(10));
+        assert_eq!(policy.timeout(2), LocalDuration::from_secs(20));
+        assert_eq!(policy.timeout(3), LocalDuration::from_secs(30));
+        assert_eq!(policy.timeout(64), LocalDuration::from_secs(30));
+    }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy {
+            timeout: LocalDuration::from_secs(10),
+            max_attempts: 2,
+            max_outstanding: 1,
+            ..RetryPolicy::default()
+        };
+        let mut tracker = RequestTracker::new(policy, fastrand::Rng::with_seed(1));
+        let mut now = LocalTime::now();
+        let peers = vec![peer(1), peer(2)];
+
+        let first = tracker.select(&"a", &peers).unwrap();
+        assert_eq!(
+            tracker.request("a", first, (), now),
+            LocalDuration::from_secs(10)
+        );
+        assert!(!tracker.is_available(&first));
+        assert_eq!(
+            tracker.select(&"b", &peers),
+            peers.iter().copied().find(|p| *p != first),
+            "peers with outstanding requests are skipped"
+        );
+
+        now = now + LocalDuration::from_secs(9);
+        assert!(tracker.timed_out(now).is_empty());
+
+        now = now + LocalDuration::from_secs(1);
+        let expired = tracker.timed_out(now);
+        assert_eq!(expired.len(), 1);
+        assert!(expired[0].retry);
+        assert!(tracker.is_empty());
+
+        let second = tracker.select(&"a", &peers).unwrap();
+        assert_ne!(first, second, "peers that failed a request are skipped");
+        assert_eq!(
+            tracker.request("a", second, (), now),
+            LocalDuration::from_secs(20),
+            "timeouts double with every attempt"
+        );
+        assert_eq!(tracker.get(&"a").unwrap().attempts, 2);
+
+        let expired = tracker.peer_disconnected(&second);
+        assert_eq!(expired.len(), 1);
+        assert!(
+            !expired[0].retry,
+            "the maximum number of attempts was reached"
+        );
+        assert!(tracker.select(&"a", &peers).is_some());
+    }
+
+    #[test]
+    fn test_fulfill() {
+        let mut tracker = RequestTracker::new(RetryPolicy::default(), fastrand::Rng::new());
+        let now = LocalTime::now();
+
+        tracker.request(1, peer(1), "data", now);
+        assert!(tracker.contains(&1));
+        assert_eq!(tracker.fulfill(&1).map(|r| r.data), Some("data"));
+        assert!(tracker.fulfill(&1).is_none());
+        assert!(tracker
+            .timed_out(now + LocalDuration::from_mins(60))
+            .is_empty());
+    }
+}
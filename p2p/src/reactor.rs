@@ -23,7 +23,8 @@ pub trait Reactor<E: Publisher> {
         E: Publisher,
         Self: Sized;
 
-    /// Run the given protocol state machine with the reactor.
+    /// Run the given protocol state machine with the reactor. Once this returns, the
+    /// reactor can be run again, with a new protocol instance.
     ///
     /// The protocol is supplied via a "builder" function that takes the protocol output
     /// channel as its only parameter.
@@ -0,0 +1,25 @@
+//! Structured tracing of the protocol managers, enabled with the `tracing` feature.
+//!
+//! The managers enter a span for the peer or request they are handling, eg. a `getheaders`
+//! request to a given peer, so that what happens while handling it, such as the request timing
+//! out or the peer being disconnected, is attributed to that peer or request. Manager events
+//! are recorded in the span that is current when they are emitted.
+//!
+//! Without the `tracing` feature, these macros expand to nothing.
+
+/// Enter a span for the rest of the enclosing block. Takes the same arguments as
+/// `tracing::debug_span!`.
+macro_rules! span {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!($($arg)+).entered();
+    };
+}
+
+/// Record an event in the current span. Takes the same arguments as `tracing::debug!`.
+macro_rules! record {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)+);
+    };
+}
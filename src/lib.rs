@@ -27,11 +27,11 @@
 //!         ..Config::default()
 //!     };
 //!     // Create a client using the above network reactor.
-//!     let client = Client::<Reactor>::new(cfg)?;
+//!     let mut client = Client::<Reactor>::new(cfg)?;
 //!     let handle = client.handle();
 //!
 //!     // Run the client on a different thread, to not block the main thread.
-//!     thread::spawn(|| client.run().unwrap());
+//!     thread::spawn(move || client.run().unwrap());
 //!
 //!     // Wait for the client to be in-sync with the blockchain.
 //!     handle.wait_for_ready()?;
@@ -205,6 +205,7 @@ impl BlockTree for Cache {
 pub struct FilterCache {
     headers: NonEmpty<(FilterHash, FilterHeader)>,
     filters: BTreeMap<Height, BlockFilter>,
+    pruned: Height,
 }
 
 impl FilterCache {
@@ -212,6 +213,7 @@ impl FilterCache {
         Self {
             headers: NonEmpty::new((FilterHash::default(), genesis)),
             filters: BTreeMap::new(),
+            pruned: 0,
         }
     }
 
@@ -219,18 +221,26 @@ impl FilterCache {
         Self {
             headers,
             filters: BTreeMap::new(),
+            pruned: 0,
         }
     }
 }
 
 impl Filters for FilterCache {
     fn get_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)> {
+        if height > 0 && height <= self.pruned {
+            return None;
+        }
         self.headers.get(height as usize).copied()
     }
 
     fn get_headers(&self, range: Range<Height>) -> Vec<(FilterHash, FilterHeader)> {
         assert!(range.start < range.end);
 
+        if range.start > 0 && range.start <= self.pruned {
+            return Vec::new();
+        }
+
         self.headers
             .iter()
             .cloned()
@@ -274,4 +284,24 @@ impl Filters for FilterCache {
         }
         Ok(())
     }
+
+    fn get_filter(&self, height: Height) -> Option<BlockFilter> {
+        self.filters.get(&height).cloned()
+    }
+
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), filter::Error> {
+        self.filters.insert(height, filter);
+        Ok(())
+    }
+
+    fn prune(&mut self, below: Height) -> Result<(), filter::Error> {
+        let below = below.min(self.height());
+
+        if below > self.pruned + 1 {
+            // Pruned headers are kept, but are no longer returned.
+            self.pruned = below - 1;
+            self.filters = self.filters.split_off(&below);
+        }
+        Ok(())
+    }
 }
@@ -1,21 +1,26 @@
 //! A watch-only wallet.
 pub mod logger;
+pub mod state;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::{fmt, net, thread};
 
 use crossbeam_channel as chan;
 
-use bitcoin::blockdata::script::Script;
-use bitcoin::blockdata::transaction::{OutPoint, TxOut};
-use bitcoin::Address;
+use bitcoin::blockdata::transaction::OutPoint;
+use bitcoin::{Address, Block, BlockHash};
 
 use nakamoto_client::error::Error;
 use nakamoto_client::handle::Handle;
 use nakamoto_client::Network;
-use nakamoto_client::{client, Client, Config};
+use nakamoto_client::{client, Client, Config, Event};
 use nakamoto_common::block::Height;
 use nakamoto_common::network::Services;
+use nakamoto_p2p::protocol::connmgr;
+use nakamoto_p2p::protocol::spvmgr::xpub::Xpub;
+
+pub use state::{State, TxRecord, Utxo};
 
 /// Re-scan parameters.
 pub struct Rescan {
@@ -25,38 +30,32 @@ pub struct Rescan {
 /// A Bitcoin wallet.
 pub struct Wallet<H> {
     client: H,
-    addresses: HashSet<Address>,
-    utxos: HashMap<OutPoint, TxOut>,
+    state: State,
 }
 
 impl<H: Handle> Wallet<H> {
-    /// Create a new wallet, given a client handle and a list of watch addresses.
-    pub fn new(client: H, addresses: Vec<Address>) -> Self {
-        Self {
-            client,
-            addresses: addresses.into_iter().collect(),
-            utxos: HashMap::new(),
-        }
+    /// Create a new wallet, given a client handle and the wallet state.
+    pub fn new(client: H, state: State) -> Self {
+        Self { client, state }
     }
 
-    /// Rescan the blockchain for matching transactions.
+    /// Rescan the blockchain for matching transactions. Blocks that were already scanned,
+    /// according to the wallet state, are skipped.
     pub fn rescan(&mut self, options: Rescan) -> Result<(), Error> {
         // 1. Download block filters between `genesis` and `height` Filters can be downloaded in
         //    parallel, but should be processed in-order.
         // 2. As they are downloaded, check if there's a match. If so, add the block hash
         //    to `blocks_remaining`.
-        // 3. Once all filters in the range are downloaded, check each for matching addresses, with
-        //    `addresses`. For each matching filter, download the corresponding block.
+        // 3. Once all filters in the range are downloaded, check each for matching scripts.
+        //    For each matching filter, download the corresponding block.
         // 4. As blocks are downloaded and checked for txs, remove them from the block queue,
-        //    and update the UTXO set.
+        //    and update the wallet state, in order.
         // 5. Once there are no more blocks in the queue and filters to check, exit.
         //
-        let addresses: HashSet<Script> = self.addresses.iter().map(|a| a.script_pubkey()).collect();
-        let query = self
-            .addresses
-            .iter()
-            .map(|a| a.script_pubkey())
-            .collect::<Vec<_>>();
+        let genesis = match self.state.height() {
+            Some(height) => options.genesis.max(height + 1),
+            None => options.genesis,
+        };
 
         log::info!("Waiting for peers..");
 
@@ -65,27 +64,31 @@ impl<H: Handle> Wallet<H> {
 
         let (height, _) = self.client.get_tip()?;
 
-        let range = if options.genesis > height {
+        let range = if genesis > height {
             // If the wallet genesis is higher than the current block height, we need to wait
             // until we reach that height.
-            log::info!("Waiting for height {}", options.genesis);
+            log::info!("Waiting for height {}", genesis);
 
-            self.client.wait_for_height(options.genesis)?;
-            options.genesis..options.genesis + 1
+            self.client.wait_for_height(genesis)?;
+            genesis..genesis + 1
         } else {
-            options.genesis..height + 1
+            genesis..height + 1
         };
         let count = (range.end - range.start) as usize;
 
         let blocks_recv = self.client.blocks();
         let filters_recv = self.client.filters();
+        let events_recv = self.client.events_filtered(|e: &Event| {
+            matches!(e, Event::ConnManager(connmgr::Event::NoFilterPeers(_)))
+        });
 
         log::info!("Fetching filters in range {}..{}", range.start, range.end);
-        self.client.get_filters(range)?;
+        self.client.get_filters(vec![range])?;
 
-        let mut filter_height = options.genesis;
-        let mut blocks_remaining = HashSet::new();
+        let mut filter_height = genesis;
         let mut filters_remaining = count;
+        // Matching blocks, in order, with the block itself once it's received.
+        let mut blocks_remaining: BTreeMap<Height, (BlockHash, Option<Block>)> = BTreeMap::new();
 
         while !blocks_remaining.is_empty() || filters_remaining > 0 {
             chan::select! {
@@ -96,17 +99,12 @@ impl<H: Handle> Wallet<H> {
                             filter_height = height + 1;
                             filters_remaining -= 1;
 
-                            if let Ok(true) =
-                                filter.match_any(&block_hash, &mut query.iter().map(|s| s.as_bytes()))
-                            {
+                            if self.state.matches(&filter, &block_hash) {
                                 log::info!("Filter matched at height {}", height);
                                 log::info!("Fetching block {}", block_hash);
 
-                                // TODO: For BIP32 wallets, add one more address to check, if the
-                                // matching one was the highest-index one.
-                                blocks_remaining.insert(block_hash);
+                                blocks_remaining.insert(height, (block_hash, None));
                                 self.client.get_block(&block_hash)?;
-
                             }
                         } else {
                             // TODO: If this condition triggers, we should just queue the filters
@@ -118,58 +116,88 @@ impl<H: Handle> Wallet<H> {
                         }
                     }
                 }
+                recv(events_recv) -> msg => {
+                    if let Ok(Event::ConnManager(event)) = msg {
+                        log::warn!("{}, the rescan may not make progress", event);
+                    }
+                }
                 recv(blocks_recv) -> msg => {
                     if let Ok((block, height)) = msg {
-                        blocks_remaining.remove(&block.block_hash());
-
+                        match blocks_remaining.get_mut(&height) {
+                            Some((hash, slot)) if *hash == block.block_hash() => {
+                                *slot = Some(block);
+                            }
+                            _ => continue,
+                        }
                         log::info!(
                             "Received block {} (remaining={})",
                             height,
-                            blocks_remaining.len()
+                            blocks_remaining.len() - 1
                         );
+                    }
+                }
+            }
 
-                        for tx in block.txdata.iter() {
-                            // Look for outputs.
-                            for (vout, output) in tx.output.iter().enumerate() {
-                                // Received coin.
-                                if addresses.contains(&output.script_pubkey) {
-                                    let outpoint = OutPoint {
-                                        txid: tx.txid(),
-                                        vout: vout as u32,
-                                    };
-                                    self.utxos.insert(outpoint, output.clone());
-                                    log::info!("Unspent output found (balance={})", self.balance());
-                                }
-                            }
-                            // Look for inputs.
-                            for input in tx.input.iter() {
-                                // Spent coin.
-                                if self.utxos.remove(&input.previous_output).is_some() {
-                                    log::info!("Spent output found (balance={})", self.balance())
-                                }
-                            }
-                        }
+            // Apply the received blocks, in order. Filters are processed in order, so
+            // blocks can only be applied once all filters up to their height are processed.
+            while let Some((&height, (_, Some(_)))) = blocks_remaining.iter().next() {
+                if let Some((_, Some(block))) = blocks_remaining.remove(&height) {
+                    let balance = self.state.balance();
+
+                    self.state.apply_block(&block, height);
+
+                    if self.state.balance() != balance {
+                        log::info!("Balance updated (balance={})", self.state.balance());
                     }
                 }
             }
+            // Everything below the lowest pending block and the next filter was scanned.
+            let scanned = blocks_remaining
+                .keys()
+                .next()
+                .copied()
+                .unwrap_or(filter_height)
+                .min(filter_height);
+            if scanned > genesis {
+                self.state.scanned(scanned - 1);
+            }
         }
 
         Ok(())
     }
 
-    fn balance(&self) -> u64 {
-        self.utxos.values().map(|u| u.value).sum()
+    /// The wallet balance, in satoshis.
+    pub fn balance(&self) -> u64 {
+        self.state.balance()
+    }
+
+    /// The transaction history of the wallet, in block order.
+    pub fn history(&self) -> &[TxRecord] {
+        self.state.history()
+    }
+
+    /// The unspent outputs of the wallet.
+    pub fn utxos(&self) -> impl Iterator<Item = (&OutPoint, &Utxo)> {
+        self.state.utxos()
+    }
+
+    /// The wallet state.
+    pub fn state(&self) -> &State {
+        &self.state
     }
 }
 
 /// The network reactor we're going to use.
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
 
-/// Entry point for running the wallet.
+/// Entry point for running the wallet. If a wallet file is given, the wallet state is
+/// loaded from it, and saved back to it once the rescan is complete.
 pub fn run<S: net::ToSocketAddrs + fmt::Debug>(
     seed: S,
     addresses: Vec<Address>,
+    xpubs: Vec<Xpub>,
     genesis: Height,
+    path: Option<&Path>,
 ) -> Result<(), Error> {
     let mut cfg = Config {
         listen: vec![], // Don't listen for incoming connections.
@@ -182,18 +210,35 @@ pub fn run<S: net::ToSocketAddrs + fmt::Debug>(
     cfg.target_outbound_peers = cfg.connect.len().min(8);
 
     // Create a new client using `Reactor` for networking.
-    let client = Client::<Reactor>::new(cfg)?;
+    let mut client = Client::<Reactor>::new(cfg)?;
     let handle = client.handle();
 
     // Start the network client in the background.
-    thread::spawn(|| client.run().unwrap());
+    thread::spawn(move || client.run().unwrap());
+
+    let mut state = match path {
+        Some(path) => State::load(path)?,
+        None => State::new(),
+    };
+    for addr in addresses {
+        state.watch(addr.script_pubkey());
+    }
+    for xpub in xpubs {
+        state.watch_xpub(xpub);
+    }
 
     // Create a new wallet and rescan the chain from the provided `genesis` height for
-    // matching addresses.
-    let mut wallet = Wallet::new(handle, addresses);
+    // matching scripts.
+    let mut wallet = Wallet::new(handle, state);
 
     wallet.rescan(Rescan { genesis })?;
 
+    if let Some(path) = path {
+        wallet.state().save(path)?;
+    }
+    for tx in wallet.history() {
+        log::info!("{} {:+} sats (height={})", tx.txid, tx.net(), tx.height);
+    }
     log::info!("Balance is {} sats", wallet.balance());
     log::info!("Rescan complete.");
 
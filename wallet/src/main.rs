@@ -1,8 +1,12 @@
+use std::path::PathBuf;
+
 use argh::FromArgs;
 
+use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::Address;
 
 use nakamoto_common::block::Height;
+use nakamoto_p2p::protocol::spvmgr::xpub::{self, DerivationScheme, Xpub, DEFAULT_GAP_LIMIT};
 use nakamoto_wallet::logger;
 
 /// A Bitcoin wallet.
@@ -14,9 +18,16 @@ pub struct Options {
     /// watch the following addresses
     #[argh(option)]
     pub addresses: Vec<Address>,
+    /// watch the addresses derived from the following account-level extended public
+    /// keys, as BIP 84 receive and change chains
+    #[argh(option)]
+    pub xpubs: Vec<ExtendedPubKey>,
     /// wallet genesis height, from which to start scanning
     #[argh(option)]
     pub genesis: Height,
+    /// load and save the wallet state from the specified file
+    #[argh(option)]
+    pub wallet: Option<PathBuf>,
     /// enable debug logging
     #[argh(switch)]
     pub debug: bool,
@@ -38,12 +49,35 @@ fn main() {
     };
     logger::init(level).expect("initializing logger for the first time");
 
-    if opts.addresses.is_empty() {
-        log::error!("Fatal: at least one address must be specified with `--addresses`");
+    if opts.addresses.is_empty() && opts.xpubs.is_empty() && opts.wallet.is_none() {
+        log::error!(
+            "Fatal: at least one address or key must be specified with `--addresses` or `--xpubs`"
+        );
         std::process::exit(1);
     }
 
-    if let Err(err) = nakamoto_wallet::run(&opts.connect, opts.addresses, opts.genesis) {
+    let mut xpubs = Vec::new();
+    for account in opts.xpubs.iter() {
+        match xpub::chains(account) {
+            Ok(chains) => xpubs.extend(
+                chains
+                    .iter()
+                    .map(|chain| Xpub::new(*chain, DerivationScheme::Bip84, DEFAULT_GAP_LIMIT)),
+            ),
+            Err(err) => {
+                log::error!("Fatal: invalid extended public key {}: {}", account, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Err(err) = nakamoto_wallet::run(
+        &opts.connect,
+        opts.addresses,
+        xpubs,
+        opts.genesis,
+        opts.wallet.as_deref(),
+    ) {
         log::error!("Fatal: {}", err);
         std::process::exit(1);
     }
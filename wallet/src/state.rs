@@ -0,0 +1,520 @@
+//! Watch-only wallet state.
+//!
+//! The state tracks the set of watched scripts, including those derived from extended
+//! public keys, along with the unspent outputs and transaction history found in the blocks
+//! scanned so far. It is persisted as a single JSON file.
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs, io};
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, TxOut};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{Block, BlockHash, Txid};
+
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_common::block::filter::{BlockFilter, FilterMatcher};
+use nakamoto_common::block::Height;
+use nakamoto_p2p::protocol::spvmgr::xpub::{DerivationScheme, Xpub};
+
+/// An output paying to one of the watched scripts, that hasn't been spent yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    /// The output.
+    pub txout: TxOut,
+    /// Height of the block the output was created in.
+    pub height: Height,
+}
+
+/// A transaction spending from or paying to the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxRecord {
+    /// Transaction id.
+    pub txid: Txid,
+    /// Hash of the block the transaction was confirmed in.
+    pub block_hash: BlockHash,
+    /// Height of the block the transaction was confirmed in.
+    pub height: Height,
+    /// Amount received by the wallet, in satoshis.
+    pub received: u64,
+    /// Amount spent by the wallet, in satoshis.
+    pub sent: u64,
+}
+
+impl TxRecord {
+    /// Net effect of the transaction on the wallet balance, in satoshis.
+    pub fn net(&self) -> i64 {
+        self.received as i64 - self.sent as i64
+    }
+}
+
+/// Wallet state.
+#[derive(Debug)]
+pub struct State {
+    /// Watched scripts, including the scripts derived from `xpubs`.
+    scripts: HashSet<Script>,
+    /// Watched extended public keys.
+    xpubs: Vec<Xpub>,
+    /// Unspent outputs paying to watched scripts.
+    utxos: HashMap<OutPoint, Utxo>,
+    /// Transactions spending from or paying to watched scripts, in block order.
+    history: Vec<TxRecord>,
+    /// Height of the last block scanned.
+    height: Option<Height>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Create a new, empty wallet state.
+    pub fn new() -> Self {
+        Self {
+            scripts: HashSet::new(),
+            xpubs: Vec::new(),
+            utxos: HashMap::new(),
+            history: Vec::new(),
+            height: None,
+        }
+    }
+
+    /// Watch a script. Returns `false` if the script was already watched.
+    ///
+    /// Since past blocks weren't scanned for the script, the scanned height is reset when
+    /// a new script is watched.
+    pub fn watch(&mut self, script: Script) -> bool {
+        if self.scripts.insert(script) {
+            self.height = None;
+            return true;
+        }
+        false
+    }
+
+    /// Watch the scripts derived from an extended public key. Returns `false` if the key was
+    /// already watched.
+    ///
+    /// Since past blocks weren't scanned for the key, the scanned height is reset when
+    /// a new key is watched.
+    pub fn watch_xpub(&mut self, mut xpub: Xpub) -> bool {
+        if self.xpubs.iter().any(|x| x.xpub == xpub.xpub) {
+            return false;
+        }
+        self.scripts.extend(xpub.derive());
+        self.xpubs.push(xpub);
+        self.height = None;
+
+        true
+    }
+
+    /// The watched scripts.
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.scripts.iter()
+    }
+
+    /// Height of the last block scanned, if any.
+    pub fn height(&self) -> Option<Height> {
+        self.height
+    }
+
+    /// Record that the blocks up to the given height were scanned.
+    pub fn scanned(&mut self, height: Height) {
+        self.height = Some(self.height.map_or(height, |h| h.max(height)));
+    }
+
+    /// Check whether a block filter matches any of the watched scripts. Scripts derived from
+    /// extended public keys are extended as needed to maintain the gap limit.
+    pub fn matches(&mut self, filter: &BlockFilter, block_hash: &BlockHash) -> bool {
+        let filter = match FilterMatcher::new(filter, block_hash) {
+            Ok(filter) => filter,
+            Err(_) => return false,
+        };
+        for xpub in self.xpubs.iter_mut() {
+            self.scripts.extend(xpub.extend(&filter));
+        }
+        filter.match_any(self.scripts.iter().map(|s| s.as_bytes()))
+    }
+
+    /// Update the unspent outputs and history with the transactions of a block. Blocks
+    /// should be applied in order, since outputs spent in a block must have been seen first.
+    pub fn apply_block(&mut self, block: &Block, height: Height) {
+        let block_hash = block.block_hash();
+
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            let mut received = 0;
+            let mut sent = 0;
+
+            // Look for inputs, ie. spent coins.
+            for input in tx.input.iter() {
+                if let Some(utxo) = self.utxos.remove(&input.previous_output) {
+                    sent += utxo.txout.value;
+                }
+            }
+            // Look for outputs, ie. received coins.
+            for (vout, output) in tx.output.iter().enumerate() {
+                if !self.scripts.contains(&output.script_pubkey) {
+                    continue;
+                }
+                for xpub in self.xpubs.iter_mut() {
+                    if let Some(index) = xpub.index(&output.script_pubkey) {
+                        self.scripts.extend(xpub.mark_used(index));
+                    }
+                }
+                received += output.value;
+
+                self.utxos.insert(
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    Utxo {
+                        txout: output.clone(),
+                        height,
+                    },
+                );
+            }
+
+            if (received > 0 || sent > 0) && !self.history.iter().any(|r| r.txid == txid) {
+                self.history.push(TxRecord {
+                    txid,
+                    block_hash,
+                    height,
+                    received,
+                    sent,
+                });
+            }
+        }
+        self.scanned(height);
+    }
+
+    /// The wallet balance, ie. the sum of the unspent outputs, in satoshis.
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(|u| u.txout.value).sum()
+    }
+
+    /// The unspent outputs.
+    pub fn utxos(&self) -> impl Iterator<Item = (&OutPoint, &Utxo)> {
+        self.utxos.iter()
+    }
+
+    /// The transaction history, in block order.
+    pub fn history(&self) -> &[TxRecord] {
+        &self.history
+    }
+
+    /// Load the state persisted in the given file. Returns an empty state if the file doesn't
+    /// exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e),
+        };
+        let val = json::from_str(&s).map_err(|_| invalid())?;
+
+        Self::from_json(val).ok_or_else(invalid)
+    }
+
+    /// Persist the state to the given file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let s = json::to_string(&self.to_json());
+
+        fs::write(path, s + "\n")
+    }
+
+    fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        let scripts = self
+            .scripts
+            .iter()
+            // Derived scripts are restored from their keys.
+            .filter(|s| self.xpubs.iter().all(|x| x.index(s).is_none()))
+            .map(|s| Value::String(s.as_bytes().to_hex()))
+            .collect();
+        let xpubs = self
+            .xpubs
+            .iter()
+            .map(|x| {
+                let mut obj = Object::new();
+
+                obj.insert("xpub".to_owned(), Value::String(x.xpub.to_string()));
+                obj.insert("scheme".to_owned(), Value::String(x.scheme.to_string()));
+                obj.insert("gap".to_owned(), number(x.gap_limit as u64));
+                if let Some(used) = x.used() {
+                    obj.insert("used".to_owned(), number(used as u64));
+                }
+                Value::Object(obj)
+            })
+            .collect();
+        let utxos = self
+            .utxos
+            .iter()
+            .map(|(outpoint, utxo)| {
+                let mut obj = Object::new();
+
+                obj.insert(
+                    "script".to_owned(),
+                    Value::String(utxo.txout.script_pubkey.as_bytes().to_hex()),
+                );
+                obj.insert("value".to_owned(), number(utxo.txout.value));
+                obj.insert("height".to_owned(), number(utxo.height));
+
+                (outpoint.to_string(), Value::Object(obj))
+            })
+            .collect();
+        let history = self
+            .history
+            .iter()
+            .map(|r| {
+                let mut obj = Object::new();
+
+                obj.insert("txid".to_owned(), Value::String(r.txid.to_string()));
+                obj.insert("block".to_owned(), Value::String(r.block_hash.to_string()));
+                obj.insert("height".to_owned(), number(r.height));
+                obj.insert("received".to_owned(), number(r.received));
+                obj.insert("sent".to_owned(), number(r.sent));
+
+                Value::Object(obj)
+            })
+            .collect();
+
+        obj.insert("scripts".to_owned(), Value::Array(scripts));
+        obj.insert("xpubs".to_owned(), Value::Array(xpubs));
+        obj.insert("utxos".to_owned(), Value::Object(utxos));
+        obj.insert("history".to_owned(), Value::Array(history));
+        if let Some(height) = self.height {
+            obj.insert("height".to_owned(), number(height));
+        }
+        Value::Object(obj)
+    }
+
+    fn from_json(val: Value) -> Option<Self> {
+        let mut obj = match val {
+            Value::Object(obj) => obj,
+            _ => return None,
+        };
+        let mut state = Self::new();
+
+        for script in array(obj.remove("scripts")?)? {
+            state.scripts.insert(script_from_hex(&string(script)?)?);
+        }
+        for xpub in array(obj.remove("xpubs")?)? {
+            let mut xpub = match xpub {
+                Value::Object(obj) => obj,
+                _ => return None,
+            };
+            let key = ExtendedPubKey::from_str(&string(xpub.remove("xpub")?)?).ok()?;
+            let scheme = match string(xpub.remove("scheme")?)?.as_str() {
+                "bip44" => DerivationScheme::Bip44,
+                "bip49" => DerivationScheme::Bip49,
+                "bip84" => DerivationScheme::Bip84,
+                _ => return None,
+            };
+            let gap = u64(xpub.remove("gap")?)? as u32;
+            let mut xpub_ = Xpub::new(key, scheme, gap);
+
+            state.scripts.extend(xpub_.derive());
+            if let Some(used) = xpub.remove("used") {
+                state.scripts.extend(xpub_.mark_used(u64(used)? as u32));
+            }
+            state.xpubs.push(xpub_);
+        }
+        let utxos = match obj.remove("utxos")? {
+            Value::Object(obj) => obj,
+            _ => return None,
+        };
+        for (outpoint, utxo) in utxos.into_iter() {
+            let mut utxo = match utxo {
+                Value::Object(obj) => obj,
+                _ => return None,
+            };
+            let outpoint = OutPoint::from_str(&outpoint).ok()?;
+            let txout = TxOut {
+                script_pubkey: script_from_hex(&string(utxo.remove("script")?)?)?,
+                value: u64(utxo.remove("value")?)?,
+            };
+            let height = u64(utxo.remove("height")?)?;
+
+            state.utxos.insert(outpoint, Utxo { txout, height });
+        }
+        for record in array(obj.remove("history")?)? {
+            let mut record = match record {
+                Value::Object(obj) => obj,
+                _ => return None,
+            };
+            state.history.push(TxRecord {
+                txid: Txid::from_str(&string(record.remove("txid")?)?).ok()?,
+                block_hash: BlockHash::from_str(&string(record.remove("block")?)?).ok()?,
+                height: u64(record.remove("height")?)?,
+                received: u64(record.remove("received")?)?,
+                sent: u64(record.remove("sent")?)?,
+            });
+        }
+        state.height = match obj.remove("height") {
+            Some(height) => Some(u64(height)?),
+            None => None,
+        };
+
+        Some(state)
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
+}
+
+fn number(n: u64) -> Value {
+    Value::Number(Number::U64(n))
+}
+
+fn u64(val: Value) -> Option<u64> {
+    match val {
+        Value::Number(Number::U64(n)) => Some(n),
+        _ => None,
+    }
+}
+
+fn string(val: Value) -> Option<String> {
+    match val {
+        Value::String(s) => Some(s),
+        _ => None,
+    }
+}
+
+fn array(val: Value) -> Option<Vec<Value>> {
+    match val {
+        Value::Array(ary) => Some(ary.into_iter().collect()),
+        _ => None,
+    }
+}
+
+fn script_from_hex(s: &str) -> Option<Script> {
+    Vec::<u8>::from_hex(s).ok().map(Script::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn};
+    use bitcoin::network::constants::Network;
+
+    const XPUB: &str = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+
+    fn tx(inputs: &[OutPoint], outputs: &[(&Script, u64)]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script, value)| TxOut {
+                    script_pubkey: (*script).clone(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    fn block(txdata: Vec<Transaction>) -> Block {
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata = txdata;
+        block
+    }
+
+    #[test]
+    fn test_apply_block() {
+        let ours = Script::from(vec![0x51]);
+        let theirs = Script::from(vec![0x52]);
+        let mut state = State::new();
+
+        state.watch(ours.clone());
+
+        let funding = tx(&[], &[(&ours, 1000), (&theirs, 500), (&ours, 200)]);
+        let funding_txid = funding.txid();
+        state.apply_block(&block(vec![funding]), 1);
+
+        assert_eq!(state.balance(), 1200);
+        assert_eq!(state.height(), Some(1));
+        assert_eq!(state.history().len(), 1);
+        assert_eq!(state.history()[0].net(), 1200);
+
+        let spend = tx(
+            &[OutPoint::new(funding_txid, 0)],
+            &[(&theirs, 700), (&ours, 250)],
+        );
+        let unrelated = tx(&[OutPoint::new(funding_txid, 1)], &[(&theirs, 500)]);
+        state.apply_block(&block(vec![spend, unrelated]), 2);
+
+        assert_eq!(state.balance(), 450);
+        assert_eq!(state.utxos().count(), 2);
+        assert_eq!(state.history().len(), 2);
+        assert_eq!(state.history()[1].sent, 1000);
+        assert_eq!(state.history()[1].received, 250);
+        assert_eq!(state.history()[1].net(), -750);
+    }
+
+    #[test]
+    fn test_xpub_gap() {
+        let key = ExtendedPubKey::from_str(XPUB).unwrap();
+        let mut state = State::new();
+        let mut xpub = Xpub::new(key, DerivationScheme::Bip84, 4);
+        let scripts = xpub.derive();
+
+        state.watch_xpub(Xpub::new(key, DerivationScheme::Bip84, 4));
+        assert_eq!(state.scripts().count(), 4);
+
+        // Paying to the last script derives more.
+        state.apply_block(&block(vec![tx(&[], &[(&scripts[3], 100)])]), 1);
+        assert_eq!(state.scripts().count(), 8);
+        assert_eq!(state.balance(), 100);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let key = ExtendedPubKey::from_str(XPUB).unwrap();
+        let ours = Script::from(vec![0x51]);
+        let mut state = State::new();
+        let mut xpub = Xpub::new(key, DerivationScheme::Bip49, 2);
+        let derived = xpub.derive();
+
+        state.watch(ours.clone());
+        state.watch_xpub(Xpub::new(key, DerivationScheme::Bip49, 2));
+        state.apply_block(
+            &block(vec![tx(&[], &[(&ours, 1000), (&derived[1], 300)])]),
+            7,
+        );
+        state.scanned(9);
+
+        let path =
+            std::env::temp_dir().join(format!("nakamoto-wallet-{}.json", std::process::id()));
+        state.save(&path).unwrap();
+        let loaded = State::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.height(), Some(9));
+        assert_eq!(loaded.balance(), 1300);
+        assert_eq!(loaded.history(), state.history());
+        assert_eq!(
+            loaded.scripts().collect::<HashSet<_>>(),
+            state.scripts().collect::<HashSet<_>>()
+        );
+        assert_eq!(loaded.xpubs[0].used(), Some(1));
+    }
+}